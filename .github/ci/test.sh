@@ -14,6 +14,7 @@ export CARGO_NET_GIT_FETCH_WITH_CLI=true
 
 cargo test --manifest-path ./embassy-executor/Cargo.toml --features metadata-name
 cargo test --manifest-path ./embassy-futures/Cargo.toml
+cargo test --manifest-path ./embassy-fs/Cargo.toml --features std
 cargo test --manifest-path ./embassy-sync/Cargo.toml
 cargo test --manifest-path ./embassy-embedded-hal/Cargo.toml
 cargo test --manifest-path ./embassy-hal-internal/Cargo.toml
@@ -23,6 +24,11 @@ cargo test --manifest-path ./embassy-time-driver/Cargo.toml
 cargo test --manifest-path ./embassy-boot/Cargo.toml
 cargo test --manifest-path ./embassy-boot/Cargo.toml --features ed25519-dalek
 cargo test --manifest-path ./embassy-boot/Cargo.toml --features ed25519-salty
+cargo test --manifest-path ./embassy-boot/Cargo.toml --features ecdsa-p256
+cargo test --manifest-path ./embassy-boot/Cargo.toml --features rsa
+cargo test --manifest-path ./embassy-boot/Cargo.toml --features encryption
+cargo test --manifest-path ./embassy-boot/Cargo.toml --features ota
+cargo test --manifest-path ./embassy-boot/Cargo.toml --features security-counter
 
 cargo test --manifest-path ./embassy-nrf/Cargo.toml --no-default-features --features nrf52840,time-driver-rtc1,gpiote
 
@@ -38,4 +44,5 @@ cargo test --manifest-path ./embassy-stm32/Cargo.toml --no-default-features --fe
 cargo test --manifest-path ./embassy-net-adin1110/Cargo.toml
 cargo test --manifest-path ./embassy-usb-dfu/Cargo.toml --features dfu
 cargo test --manifest-path ./embassy-usb-host/Cargo.toml
+cargo test --manifest-path ./embassy-usb-synopsys-otg/Cargo.toml --features host
 cargo test --manifest-path ./embassy-net/Cargo.toml --features tcp,dhcpv4,medium-ethernet,proto-ipv6
\ No newline at end of file
@@ -0,0 +1,1085 @@
+use core::cell::RefCell;
+
+use embassy_embedded_hal::flash::partition::BlockingPartition;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embedded_storage::nor_flash::{NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash};
+
+#[cfg(feature = "encryption")]
+use crate::encryption::ImageCipher;
+use crate::image::{
+    FLAG_ENCRYPTED, HEADER_LEN, ImageFormat, ImageHeader, TLV_HEADER_LEN, TLV_PROT_INFO_MAGIC, TLV_SEC_CNT,
+    parse_tlv_header,
+};
+use crate::security_counter::{self, MAX_RECORD_SIZE};
+use crate::{
+    AlignedBuffer, BOOT_MAGIC, BootEvent, BootRecord, DFU_DETACH_MAGIC, RECOVERY_MAGIC, REVERT_MAGIC,
+    STATE_ERASE_VALUE, SWAP_MAGIC, State,
+};
+
+mod multi;
+mod scratch;
+mod xip;
+
+pub use multi::{ImagePartitions, MultiBootLoader, MultiBootLoaderConfig};
+use xip::read_unaligned;
+
+/// Errors returned by bootloader
+#[derive(PartialEq, Eq, Debug)]
+pub enum BootError {
+    /// Error from flash.
+    Flash(NorFlashErrorKind),
+    /// Invalid bootloader magic
+    BadMagic,
+    /// An encrypted image being swapped or reverted can no longer be decrypted.
+    Decryption,
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for BootError {
+    fn format(&self, fmt: defmt::Formatter) {
+        match self {
+            BootError::Flash(_) => defmt::write!(fmt, "BootError::Flash(_)"),
+            BootError::BadMagic => defmt::write!(fmt, "BootError::BadMagic"),
+            BootError::Decryption => defmt::write!(fmt, "BootError::Decryption"),
+        }
+    }
+}
+
+impl<E> From<E> for BootError
+where
+    E: NorFlashError,
+{
+    fn from(error: E) -> Self {
+        BootError::Flash(error.kind())
+    }
+}
+
+/// Bootloader flash configuration holding the three flashes used by the bootloader
+///
+/// If only a single flash is actually used, then that flash should be partitioned into three partitions before use.
+/// The easiest way to do this is to use [`BootLoaderConfig::from_linkerfile_blocking`] which will partition
+/// the provided flash according to symbols defined in the linkerfile.
+pub struct BootLoaderConfig<ACTIVE, DFU, STATE> {
+    /// Flash type used for the active partition - the partition which will be booted from.
+    pub active: ACTIVE,
+    /// Flash type used for the dfu partition - the partition which will be swapped in when requested.
+    pub dfu: DFU,
+    /// Flash type used for the state partition.
+    pub state: STATE,
+}
+
+impl<'a, ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash>
+    BootLoaderConfig<
+        BlockingPartition<'a, NoopRawMutex, ACTIVE>,
+        BlockingPartition<'a, NoopRawMutex, DFU>,
+        BlockingPartition<'a, NoopRawMutex, STATE>,
+    >
+{
+    /// Constructs a `BootLoaderConfig` instance from flash memory and address symbols defined in the linker file.
+    ///
+    /// This method initializes `BlockingPartition` instances for the active, DFU (Device Firmware Update),
+    /// and state partitions, leveraging start and end addresses specified by the linker. These partitions
+    /// are critical for managing firmware updates, application state, and boot operations within the bootloader.
+    ///
+    /// # Parameters
+    /// - `active_flash`: A reference to a mutex-protected `RefCell` for the active partition's flash interface.
+    /// - `dfu_flash`: A reference to a mutex-protected `RefCell` for the DFU partition's flash interface.
+    /// - `state_flash`: A reference to a mutex-protected `RefCell` for the state partition's flash interface.
+    ///
+    /// # Safety
+    /// The method contains `unsafe` blocks for dereferencing raw pointers that represent the start and end addresses
+    /// of the bootloader's partitions in flash memory. It is crucial that these addresses are accurately defined
+    /// in the memory.x file to prevent undefined behavior.
+    ///
+    /// The caller must ensure that the memory regions defined by these symbols are valid and that the flash memory
+    /// interfaces provided are compatible with these regions.
+    ///
+    /// # Returns
+    /// A `BootLoaderConfig` instance with `BlockingPartition` instances for the active, DFU, and state partitions.
+    ///
+    /// # Example
+    /// ```ignore
+    /// // Assume `active_flash`, `dfu_flash`, and `state_flash` all share the same flash memory interface.
+    /// let layout = Flash::new_blocking(p.FLASH).into_blocking_regions();
+    /// let flash = Mutex::new(RefCell::new(layout.bank1_region));
+    ///
+    /// let config = BootLoaderConfig::from_linkerfile_blocking(&flash, &flash, &flash);
+    /// // `config` can now be used to create a `BootLoader` instance for managing boot operations.
+    /// ```
+    /// Working examples can be found in the bootloader examples folder.
+    // #[cfg(target_os = "none")]
+    pub fn from_linkerfile_blocking(
+        active_flash: &'a Mutex<NoopRawMutex, RefCell<ACTIVE>>,
+        dfu_flash: &'a Mutex<NoopRawMutex, RefCell<DFU>>,
+        state_flash: &'a Mutex<NoopRawMutex, RefCell<STATE>>,
+    ) -> Self {
+        unsafe extern "C" {
+            static __bootloader_state_start: u32;
+            static __bootloader_state_end: u32;
+            static __bootloader_active_start: u32;
+            static __bootloader_active_end: u32;
+            static __bootloader_dfu_start: u32;
+            static __bootloader_dfu_end: u32;
+        }
+
+        let active = unsafe {
+            let start = &__bootloader_active_start as *const u32 as u32;
+            let end = &__bootloader_active_end as *const u32 as u32;
+            trace!("ACTIVE: 0x{:x} - 0x{:x}", start, end);
+
+            BlockingPartition::new(active_flash, start, end - start)
+        };
+        let dfu = unsafe {
+            let start = &__bootloader_dfu_start as *const u32 as u32;
+            let end = &__bootloader_dfu_end as *const u32 as u32;
+            trace!("DFU: 0x{:x} - 0x{:x}", start, end);
+
+            BlockingPartition::new(dfu_flash, start, end - start)
+        };
+        let state = unsafe {
+            let start = &__bootloader_state_start as *const u32 as u32;
+            let end = &__bootloader_state_end as *const u32 as u32;
+            trace!("STATE: 0x{:x} - 0x{:x}", start, end);
+
+            BlockingPartition::new(state_flash, start, end - start)
+        };
+
+        Self { active, dfu, state }
+    }
+}
+
+/// Algorithm used by [`BootLoader::prepare_boot`] to install an update.
+///
+/// All strategies resume where they left off after a power failure.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Strategy {
+    /// Swap the active and DFU partitions page by page, using one extra page at the end of the
+    /// DFU partition. The DFU partition must be at least one page bigger than the active one.
+    /// The update is reverted if the application does not mark it booted.
+    #[default]
+    Swap,
+    /// Copy the DFU partition over the active partition. This is the fastest strategy and wears
+    /// the active partition the least, but the update cannot be reverted.
+    Overwrite,
+    /// Swap the active and DFU partitions page by page through a scratch page, for partitions
+    /// of equal size. The scratch page is the last page of the state partition in front of the
+    /// boot history page, if any, see [`BootLoader`]. Encrypted updates are rejected.
+    SwapScratch,
+    /// Execute in place from whichever of the active and DFU partitions holds the newest valid
+    /// image, see [`BootLoader::boot_slot`]. Nothing is copied.
+    ///
+    /// Images must have a header and a SHA-256 TLV, see [`crate::image`], and must be linked
+    /// for the partition they are written to. An image marked updated boots on trial, and its
+    /// header page is erased if the application does not mark it booted, unless the other
+    /// partition holds no valid image. Encrypted images are never booted.
+    DirectXip,
+}
+
+/// Partition holding the image to boot.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Slot {
+    /// The active partition.
+    Active,
+    /// The DFU partition.
+    Dfu,
+}
+
+/// BootLoader works with any flash implementing embedded_storage.
+pub struct BootLoader<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> {
+    active: ACTIVE,
+    dfu: DFU,
+    /// The state partition has the following format:
+    /// All ranges are in multiples of WRITE_SIZE bytes.
+    /// N = Active partition size divided by WRITE_SIZE.
+    /// | Range              | Description                                                                      |
+    /// | 0..1               | Magic indicating bootloader state. BOOT_MAGIC means boot, SWAP_MAGIC means swap. |
+    /// | 1..2               | Progress validity. ERASE_VALUE means valid, !ERASE_VALUE means invalid.          |
+    /// | 2..(2 + 2N)        | Progress index used while swapping                                               |
+    /// | (2 + 2N)..(2 + 4N) | Progress index used while reverting
+    ///
+    /// The other strategies use the same layout with a different number of progress indices:
+    /// N for [`Strategy::Overwrite`], 3N to swap and 3N to revert for [`Strategy::SwapScratch`],
+    /// and one per partition marking the image on trial for [`Strategy::DirectXip`]. They are
+    /// followed by one word per boot attempt after the first, see [`Self::set_boot_attempts`].
+    ///
+    /// With the `security-counter` feature, if the state partition spans at least four erase
+    /// pages, its last three erase pages are kept apart: a page holding the boot history, see
+    /// [`Self::boot_history`], and two pages holding the security counter of the last confirmed
+    /// image, written alternately. With [`Strategy::SwapScratch`], the page in front of them is
+    /// the scratch page. The ranges above must then fit in front of them.
+    state: STATE,
+    strategy: Strategy,
+    slot: Slot,
+    max_attempts: u8,
+    reset_reason: u8,
+    #[cfg(feature = "encryption")]
+    decryption_key: Option<[u8; 32]>,
+    #[cfg(feature = "encryption")]
+    cipher: Option<ImageCipher>,
+}
+
+/// Which image the pages being copied belong to.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Payload {
+    /// The update being swapped in, or reverted.
+    Update,
+    /// The image it replaces.
+    Previous,
+}
+
+impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, STATE> {
+    /// Get the page size which is the "unit of operation" within the bootloader.
+    const PAGE_SIZE: u32 = if ACTIVE::ERASE_SIZE > DFU::ERASE_SIZE {
+        ACTIVE::ERASE_SIZE as u32
+    } else {
+        DFU::ERASE_SIZE as u32
+    };
+
+    /// Create a new instance of a bootloader with the flash partitions.
+    ///
+    /// - All partitions must be aligned with the PAGE_SIZE const generic parameter.
+    /// - The dfu partition must be at least PAGE_SIZE bigger than the active partition.
+    pub fn new(config: BootLoaderConfig<ACTIVE, DFU, STATE>) -> Self {
+        Self {
+            active: config.active,
+            dfu: config.dfu,
+            state: config.state,
+            strategy: Strategy::Swap,
+            slot: Slot::Active,
+            max_attempts: 1,
+            reset_reason: 0,
+            #[cfg(feature = "encryption")]
+            decryption_key: None,
+            #[cfg(feature = "encryption")]
+            cipher: None,
+        }
+    }
+
+    /// Set the algorithm used to install updates, [`Strategy::Swap`] by default.
+    ///
+    /// The strategy must not change while an update is being installed or reverted.
+    pub fn set_strategy(&mut self, strategy: Strategy) {
+        self.strategy = strategy;
+    }
+
+    /// Set how many times an update on trial, or the image it was reverted to, boots without
+    /// being marked booted before the bootloader gives up on it, 1 by default. A watchdog reset
+    /// then counts as a failed attempt.
+    ///
+    /// An update is given up on early when the application marks it unhealthy, see
+    /// [`crate::FirmwareState::mark_unhealthy`]. Given up updates are reverted, except with
+    /// [`Strategy::Overwrite`], and given up reverted images are replaced with the recovery
+    /// image passed to [`Self::prepare_boot_with_recovery`], if any. Each attempt after the
+    /// first uses one more word of the state partition.
+    pub fn set_boot_attempts(&mut self, attempts: u8) {
+        assert!(attempts > 0);
+        self.max_attempts = attempts;
+    }
+
+    /// Set the reset reason stored in the boot history by [`Self::prepare_boot`], such as the
+    /// reset cause reported by the chip. Zero by default.
+    pub fn set_reset_reason(&mut self, reason: u8) {
+        self.reset_reason = reason;
+    }
+
+    /// Partition holding the image to boot, as selected by [`Self::prepare_boot`]. This is
+    /// always [`Slot::Active`] unless the strategy is [`Strategy::DirectXip`].
+    pub fn boot_slot(&self) -> Slot {
+        self.slot
+    }
+
+    /// Set the device private key used to decrypt encrypted updates, see
+    /// [`crate::encryption`]. Without it, encrypted updates are rejected.
+    #[cfg(feature = "encryption")]
+    pub fn set_decryption_key(&mut self, private_key: [u8; 32]) {
+        self.decryption_key = Some(private_key);
+    }
+
+    /// Perform necessary boot preparations like swapping images.
+    ///
+    /// The DFU partition is assumed to be 1 page bigger than the active partition for the swap
+    /// algorithm to work correctly. The other strategies are described in [`Strategy`]; the
+    /// following describes [`Strategy::Swap`].
+    ///
+    /// The provided aligned_buf argument must satisfy any alignment requirements
+    /// given by the partition flashes. All flash operations will use this buffer.
+    ///
+    /// ## SWAPPING
+    ///
+    /// Assume a flash size of 3 pages for the active partition, and 4 pages for the DFU partition.
+    /// The swap index contains the copy progress, as to allow continuation of the copy process on
+    /// power failure. The index counter is represented within 1 or more pages (depending on total
+    /// flash size), where a page X is considered swapped if index at location (`X + WRITE_SIZE`)
+    /// contains a zero value. This ensures that index updates can be performed atomically and
+    /// avoid a situation where the wrong index value is set (page write size is "atomic").
+    ///
+    ///
+    /// | Partition | Swap Index | Page 0 | Page 1 | Page 3 | Page 4 |
+    /// |-----------|------------|--------|--------|--------|--------|
+    /// |    Active |          0 |      1 |      2 |      3 |      - |
+    /// |       DFU |          0 |      4 |      5 |      6 |      X |
+    ///
+    /// The algorithm starts by copying 'backwards', and after the first step, the layout is
+    /// as follows:
+    ///
+    /// | Partition | Swap Index | Page 0 | Page 1 | Page 3 | Page 4 |
+    /// |-----------|------------|--------|--------|--------|--------|
+    /// |    Active |          1 |      1 |      2 |      6 |      - |
+    /// |       DFU |          1 |      4 |      5 |      6 |      3 |
+    ///
+    /// The next iteration performs the same steps
+    ///
+    /// | Partition | Swap Index | Page 0 | Page 1 | Page 3 | Page 4 |
+    /// |-----------|------------|--------|--------|--------|--------|
+    /// |    Active |          2 |      1 |      5 |      6 |      - |
+    /// |       DFU |          2 |      4 |      5 |      2 |      3 |
+    ///
+    /// And again until we're done
+    ///
+    /// | Partition | Swap Index | Page 0 | Page 1 | Page 3 | Page 4 |
+    /// |-----------|------------|--------|--------|--------|--------|
+    /// |    Active |          3 |      4 |      5 |      6 |      - |
+    /// |       DFU |          3 |      4 |      1 |      2 |      3 |
+    ///
+    /// ## REVERTING
+    ///
+    /// The reverting algorithm uses the swap index to discover that images were swapped, but that
+    /// the application failed to mark the boot successful. In this case, the revert algorithm will
+    /// run.
+    ///
+    /// The revert index is located separately from the swap index, to ensure that revert can continue
+    /// on power failure.
+    ///
+    /// The revert algorithm works forwards, by starting copying into the 'unused' DFU page at the start.
+    ///
+    /// | Partition | Revert Index | Page 0 | Page 1 | Page 3 | Page 4 |
+    /// |-----------|--------------|--------|--------|--------|--------|
+    /// |    Active |            3 |      1 |      5 |      6 |      - |
+    /// |       DFU |            3 |      4 |      1 |      2 |      3 |
+    ///
+    ///
+    /// | Partition | Revert Index | Page 0 | Page 1 | Page 3 | Page 4 |
+    /// |-----------|--------------|--------|--------|--------|--------|
+    /// |    Active |            3 |      1 |      2 |      6 |      - |
+    /// |       DFU |            3 |      4 |      5 |      2 |      3 |
+    ///
+    /// | Partition | Revert Index | Page 0 | Page 1 | Page 3 | Page 4 |
+    /// |-----------|--------------|--------|--------|--------|--------|
+    /// |    Active |            3 |      1 |      2 |      3 |      - |
+    /// |       DFU |            3 |      4 |      5 |      6 |      3 |
+    ///
+    pub fn prepare_boot(&mut self, aligned_buf: &mut [u8]) -> Result<State, BootError> {
+        self.prepare(None::<&mut ACTIVE>, aligned_buf)
+    }
+
+    /// Perform boot preparations like [`Self::prepare_boot`], falling back to the image in
+    /// `recovery` once the boot attempts of both an update and the image it was reverted to
+    /// are exhausted, see [`Self::set_boot_attempts`].
+    ///
+    /// The recovery image is copied over the start of the active partition and booted in the
+    /// [`State::Recovery`] state until the application marks it booted, or a new update is
+    /// installed. `recovery` must be a multiple of the page size and no larger than the active
+    /// partition. With [`Strategy::Overwrite`], the recovery image replaces an update that was
+    /// not marked booted.
+    ///
+    /// Starting the recovery is resumed after a power failure from the boot history, so it
+    /// needs the `security-counter` feature.
+    pub fn prepare_boot_with_recovery<R: ReadNorFlash>(
+        &mut self,
+        recovery: &mut R,
+        aligned_buf: &mut [u8],
+    ) -> Result<State, BootError> {
+        assert_eq!(0, recovery.capacity() as u32 % Self::PAGE_SIZE);
+        assert!(recovery.capacity() <= self.active.capacity());
+        assert_eq!(0, aligned_buf.len() % R::READ_SIZE);
+        self.prepare(Some(recovery), aligned_buf)
+    }
+
+    fn prepare<R: ReadNorFlash>(
+        &mut self,
+        mut recovery: Option<&mut R>,
+        aligned_buf: &mut [u8],
+    ) -> Result<State, BootError> {
+        const {
+            core::assert!(Self::PAGE_SIZE % ACTIVE::WRITE_SIZE as u32 == 0);
+            core::assert!(Self::PAGE_SIZE % ACTIVE::ERASE_SIZE as u32 == 0);
+            core::assert!(Self::PAGE_SIZE % DFU::WRITE_SIZE as u32 == 0);
+            core::assert!(Self::PAGE_SIZE % DFU::ERASE_SIZE as u32 == 0);
+        }
+
+        // Ensure we have enough progress pages to store copy progress
+        assert_eq!(0, Self::PAGE_SIZE % aligned_buf.len() as u32);
+        assert!(aligned_buf.len() >= STATE::WRITE_SIZE);
+        assert_eq!(0, aligned_buf.len() % ACTIVE::WRITE_SIZE);
+        assert_eq!(0, aligned_buf.len() % DFU::WRITE_SIZE);
+
+        // Ensure our partitions are able to handle boot operations
+        assert_partitions(
+            &self.active,
+            &self.dfu,
+            &self.state,
+            Self::PAGE_SIZE,
+            self.strategy,
+            self.max_attempts,
+        );
+        if self.strategy == Strategy::SwapScratch {
+            assert_eq!(0, aligned_buf.len() % STATE::WRITE_SIZE);
+        }
+
+        let state = self.read_state(aligned_buf)?;
+        if state == State::Boot
+            && let Some(recovery) = recovery.as_mut()
+            && self.last_event()? == Some(BootEvent::Recovery)
+        {
+            // The power failed while the recovery was being started.
+            return self.start_recovery(*recovery, aligned_buf);
+        }
+        if self.strategy == Strategy::DirectXip {
+            return self.prepare_xip(state, recovery, aligned_buf);
+        }
+
+        match state {
+            // Copy contents from partition N to active
+            State::Swap => {
+                //
+                // Check if we already swapped. If we're in the swap state, this means we should revert
+                // since the app has failed to mark boot as successful
+                //
+                let progress = self.current_progress(aligned_buf)?;
+                let installed = self.installed_progress();
+                if progress < installed {
+                    // The update may be partially copied over when resuming, only check it up front.
+                    if progress == 0 && !self.update_allowed()? {
+                        warn!("Update rejected, its security counter is below the installed one");
+                        return self.reject_update(aligned_buf);
+                    }
+                    if !self.prepare_cipher(false, aligned_buf)? {
+                        warn!("Update rejected, it is encrypted and cannot be decrypted");
+                        return self.reject_update(aligned_buf);
+                    }
+                    self.record(BootEvent::Swap, 1, 0)?;
+                    trace!("Swapping");
+                    match self.strategy {
+                        Strategy::Swap => self.swap(aligned_buf)?,
+                        Strategy::SwapScratch => self.swap_scratch(0, aligned_buf)?,
+                        Strategy::Overwrite => self.overwrite(aligned_buf)?,
+                        Strategy::DirectXip => unreachable!(),
+                    }
+                    trace!("Swapping done");
+                    return Ok(State::Swap);
+                }
+
+                if progress == installed
+                    && let Some(attempt) = self.retry(aligned_buf)?
+                {
+                    trace!("Booting the update again, attempt {}", attempt);
+                    self.record(BootEvent::Swap, attempt, 0)?;
+                    return Ok(State::Swap);
+                }
+
+                if self.strategy == Strategy::Overwrite {
+                    if let Some(recovery) = recovery {
+                        return self.start_recovery(recovery, aligned_buf);
+                    }
+                    // There is nothing to revert to.
+                    self.set_magic(BOOT_MAGIC, aligned_buf)?;
+                    self.record_boot()?;
+                    return Ok(State::Boot);
+                }
+
+                if !self.prepare_cipher(true, aligned_buf)? {
+                    return Err(BootError::Decryption);
+                }
+                self.record(BootEvent::Revert, 1, 0)?;
+                trace!("Reverting");
+                match self.strategy {
+                    Strategy::SwapScratch => {
+                        let page_count = self.active.capacity() / Self::PAGE_SIZE as usize;
+                        self.swap_scratch(page_count * 3, aligned_buf)?;
+                    }
+                    _ => self.revert(aligned_buf)?,
+                }
+                self.set_magic(REVERT_MAGIC, aligned_buf)?;
+                Ok(State::Swap)
+            }
+            State::Revert => {
+                if let Some(attempt) = self.retry(aligned_buf)? {
+                    self.record(BootEvent::Revert, attempt, 0)?;
+                } else if let Some(recovery) = recovery {
+                    return self.start_recovery(recovery, aligned_buf);
+                } else {
+                    self.record(BootEvent::Revert, 0, 0)?;
+                }
+                Ok(State::Revert)
+            }
+            State::Recovery => {
+                self.record(BootEvent::Recovery, 0, 0)?;
+                self.recover(recovery, aligned_buf)
+            }
+            State::Boot => {
+                self.record_boot()?;
+                Ok(State::Boot)
+            }
+            State::DfuDetach => {
+                self.record(BootEvent::DfuDetach, 0, 0)?;
+                Ok(State::DfuDetach)
+            }
+        }
+    }
+
+    /// Keep booting the active image when an update is rejected before it is installed.
+    fn reject_update(&mut self, aligned_buf: &mut [u8]) -> Result<State, BootError> {
+        self.set_magic(BOOT_MAGIC, aligned_buf)?;
+        self.record_boot()?;
+        Ok(State::Boot)
+    }
+
+    /// Count one more boot attempt of the image on trial, or reverted to. Returns the attempt
+    /// number, or `None` if the attempts are exhausted or the application marked the image
+    /// unhealthy.
+    fn retry(&mut self, aligned_buf: &mut [u8]) -> Result<Option<u8>, BootError> {
+        if !self.progress_valid(aligned_buf)? || matches!(self.last_event()?, Some(BootEvent::Unhealthy(_))) {
+            return Ok(None);
+        }
+        let base = progress_count(self.strategy, self.active.capacity() as u32 / Self::PAGE_SIZE) as usize;
+        for attempt in 1..self.max_attempts {
+            let index = base + attempt as usize - 1;
+            let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+            self.state
+                .read((2 + index) as u32 * STATE::WRITE_SIZE as u32, state_word)?;
+            // A partially written word counts as an attempt.
+            if state_word.iter().all(|&b| b == STATE_ERASE_VALUE) {
+                self.update_progress(index, aligned_buf)?;
+                return Ok(Some(attempt + 1));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The newest event of the boot history.
+    fn last_event(&mut self) -> Result<Option<BootEvent>, BootError> {
+        let mut last = [BootRecord::default()];
+        let count = self.boot_history(&mut last)?;
+        Ok((count == 1).then_some(last[0].event))
+    }
+
+    /// Start replacing the active image with the recovery image. The event is recorded first,
+    /// so that the recovery is resumed if the power fails before the magic is written.
+    fn start_recovery<R: ReadNorFlash>(
+        &mut self,
+        recovery: &mut R,
+        aligned_buf: &mut [u8],
+    ) -> Result<State, BootError> {
+        warn!("Boot attempts exhausted, installing the recovery image");
+        self.record(BootEvent::Recovery, 0, 0)?;
+        self.set_magic(RECOVERY_MAGIC, aligned_buf)?;
+        self.recover(Some(recovery), aligned_buf)
+    }
+
+    /// Copy the recovery image over the active partition, resuming an interrupted copy. The
+    /// progress indices mark the copied pages.
+    fn recover<R: ReadNorFlash>(
+        &mut self,
+        recovery: Option<&mut R>,
+        aligned_buf: &mut [u8],
+    ) -> Result<State, BootError> {
+        self.slot = Slot::Active;
+        let Some(recovery) = recovery else {
+            return Ok(State::Recovery);
+        };
+
+        let page_count = recovery.capacity() as u32 / Self::PAGE_SIZE;
+        for page_num in 0..page_count {
+            if self.current_progress(aligned_buf)? <= page_num as usize {
+                let offset = page_num * Self::PAGE_SIZE;
+                self.active.erase(offset, offset + Self::PAGE_SIZE)?;
+                for offset_in_page in (0..Self::PAGE_SIZE).step_by(aligned_buf.len()) {
+                    recovery.read(offset + offset_in_page, aligned_buf)?;
+                    self.active.write(offset + offset_in_page, aligned_buf)?;
+                }
+                self.update_progress(page_num as usize, aligned_buf)?;
+            }
+        }
+        Ok(State::Recovery)
+    }
+
+    /// Record a boot of the confirmed active image, whose security counter is now the lowest
+    /// one accepted.
+    fn record_boot(&mut self) -> Result<(), BootError> {
+        let counter = self.read_header_active()?.map_or(0, |header| header.security_counter);
+        self.record_confirmed(counter)
+    }
+
+    /// Raise the security counter to the one of the confirmed image being booted, and record
+    /// the boot.
+    fn record_confirmed(&mut self, security_counter: u32) -> Result<(), BootError> {
+        let mut buf = AlignedBuffer([0; MAX_RECORD_SIZE]);
+        security_counter::raise(&mut self.state, security_counter, &mut buf.0)?;
+        self.record(BootEvent::Boot, 0, security_counter)
+    }
+
+    /// Append an event to the boot history.
+    fn record(&mut self, event: BootEvent, attempt: u8, security_counter: u32) -> Result<(), BootError> {
+        let record = BootRecord {
+            event,
+            attempt,
+            reset_reason: self.reset_reason,
+            security_counter,
+        };
+        let mut buf = AlignedBuffer([0; MAX_RECORD_SIZE]);
+        security_counter::append(&mut self.state, record, &mut buf.0)?;
+        Ok(())
+    }
+
+    /// Read the newest entries of the boot history into `out`, newest first, and return how
+    /// many were read. The history is empty without the `security-counter` feature or if the
+    /// state partition has no room for it, and starts over whenever its page fills up.
+    pub fn boot_history(&mut self, out: &mut [BootRecord]) -> Result<usize, BootError> {
+        let mut record = AlignedBuffer([0; MAX_RECORD_SIZE]);
+        Ok(security_counter::history(&mut self.state, out, &mut record.0)?)
+    }
+
+    /// Read the security counter stored in the state partition, or `None` without the
+    /// `security-counter` feature or if the state partition has no room for it. Counter pages
+    /// that cannot be read hold `u32::MAX`, refusing every update until a confirmed image is
+    /// booted.
+    pub fn security_counter(&mut self) -> Result<Option<u32>, BootError> {
+        let mut record = AlignedBuffer([0; MAX_RECORD_SIZE]);
+        Ok(security_counter::read(&mut self.state, &mut record.0)?)
+    }
+
+    /// Whether the image in the DFU partition may replace the active one. Images without a
+    /// header have a security counter of zero.
+    fn update_allowed(&mut self) -> Result<bool, BootError> {
+        let Some(stored) = self.security_counter()? else {
+            return Ok(true);
+        };
+        let counter = read_header(&mut self.dfu)?.map_or(0, |header| header.security_counter);
+        Ok(counter >= stored)
+    }
+
+    /// Prepare decryption of the update if it is encrypted. Returns false if it cannot be
+    /// decrypted.
+    #[cfg(feature = "encryption")]
+    fn prepare_cipher(&mut self, reverting: bool, aligned_buf: &mut [u8]) -> Result<bool, BootError> {
+        use crate::encryption::wrapped_key;
+
+        self.cipher = None;
+        if self.strategy == Strategy::SwapScratch {
+            return self.scratch_cipher_allowed(reverting, aligned_buf);
+        }
+
+        // The header page of the update is never written while swapping, and is the first
+        // page moved back to DFU when reverting.
+        let page_count = self.active.capacity() / Self::PAGE_SIZE as usize;
+        let mut header_buf = AlignedBuffer([0; 128]);
+        if reverting && self.current_progress(aligned_buf)? <= page_count * 2 {
+            self.active.read(0, &mut header_buf.0)?;
+        } else {
+            self.dfu.read(0, &mut header_buf.0)?;
+        }
+
+        let header = match ImageHeader::parse(&header_buf.0) {
+            Ok(header) if header.flags & FLAG_ENCRYPTED != 0 => header,
+            _ => return Ok(true),
+        };
+        let (Some(secret), Some(wrapped)) = (&self.decryption_key, wrapped_key(&header_buf.0)) else {
+            return Ok(false);
+        };
+        match ImageCipher::new(&header, wrapped, secret) {
+            Ok(cipher) => {
+                self.cipher = Some(cipher);
+                Ok(true)
+            }
+            Err(_) => Ok(false),
+        }
+    }
+
+    /// Encrypted updates cannot be decrypted without the `encryption` feature.
+    #[cfg(not(feature = "encryption"))]
+    fn prepare_cipher(&mut self, reverting: bool, aligned_buf: &mut [u8]) -> Result<bool, BootError> {
+        if self.strategy == Strategy::SwapScratch {
+            return self.scratch_cipher_allowed(reverting, aligned_buf);
+        }
+        Ok(reverting || !self.update_encrypted()?)
+    }
+
+    /// [`Strategy::SwapScratch`] overwrites the header page of the update, so encrypted updates
+    /// are rejected before the swap starts.
+    fn scratch_cipher_allowed(&mut self, reverting: bool, aligned_buf: &mut [u8]) -> Result<bool, BootError> {
+        Ok(reverting || self.current_progress(aligned_buf)? > 0 || !self.update_encrypted()?)
+    }
+
+    fn update_encrypted(&mut self) -> Result<bool, BootError> {
+        let mut header = AlignedBuffer([0; HEADER_LEN]);
+        self.dfu.read(0, &mut header.0)?;
+        Ok(ImageHeader::parse(&header.0).is_ok_and(|header| header.flags & FLAG_ENCRYPTED != 0))
+    }
+
+    /// Encrypt or decrypt a chunk of a page found at `position` in the active partition.
+    fn crypt(&self, _payload: Payload, _position: u32, _buf: &mut [u8]) {
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.cipher {
+            cipher.apply(_payload, _position, _buf);
+        }
+    }
+
+    fn read_header_active(&mut self) -> Result<Option<ImageHeader>, BootError> {
+        read_header(&mut self.active)
+    }
+
+    /// Invalidate the progress, clear the state and write a new magic.
+    fn set_magic(&mut self, magic: u8, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        let area = self.state_area();
+        write_magic(&mut self.state, area, magic, aligned_buf)
+    }
+
+    /// Size of the state partition used for the magic and progress.
+    fn state_area(&self) -> u32 {
+        state_area(&self.state, Self::PAGE_SIZE, self.strategy)
+    }
+
+    /// Read the magic state from flash
+    pub fn read_state(&mut self, aligned_buf: &mut [u8]) -> Result<State, BootError> {
+        read_magic(&mut self.state, aligned_buf)
+    }
+
+    /// Progress once the update is installed, and before it is reverted.
+    fn installed_progress(&self) -> usize {
+        let page_count = self.active.capacity() / Self::PAGE_SIZE as usize;
+        match self.strategy {
+            Strategy::Swap => page_count * 2,
+            Strategy::SwapScratch => page_count * 3,
+            Strategy::Overwrite => page_count,
+            Strategy::DirectXip => 0,
+        }
+    }
+
+    fn progress_valid(&mut self, aligned_buf: &mut [u8]) -> Result<bool, BootError> {
+        let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+        self.state.read(STATE::WRITE_SIZE as u32, state_word)?;
+        Ok(state_word.iter().all(|&b| b == STATE_ERASE_VALUE))
+    }
+
+    fn current_progress(&mut self, aligned_buf: &mut [u8]) -> Result<usize, BootError> {
+        let write_size = STATE::WRITE_SIZE as u32;
+        let max_index = ((self.state_area() as usize - STATE::WRITE_SIZE) / STATE::WRITE_SIZE) - 2;
+
+        if !self.progress_valid(aligned_buf)? {
+            // Progress is invalid
+            return Ok(max_index);
+        }
+
+        let state_word = &mut aligned_buf[..write_size as usize];
+
+        for index in 0..max_index {
+            self.state.read((2 + index) as u32 * write_size, state_word)?;
+
+            if state_word.iter().any(|&b| b == STATE_ERASE_VALUE) {
+                return Ok(index);
+            }
+        }
+        Ok(max_index)
+    }
+
+    fn update_progress(&mut self, progress_index: usize, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        update_progress(&mut self.state, progress_index, aligned_buf)
+    }
+
+    fn copy_page_once_to_active(
+        &mut self,
+        progress_index: usize,
+        from_offset: u32,
+        to_offset: u32,
+        payload: Payload,
+        aligned_buf: &mut [u8],
+    ) -> Result<(), BootError> {
+        if self.current_progress(aligned_buf)? <= progress_index {
+            let page_size = Self::PAGE_SIZE as u32;
+
+            self.active.erase(to_offset, to_offset + page_size)?;
+
+            for offset_in_page in (0..page_size).step_by(aligned_buf.len()) {
+                self.dfu.read(from_offset + offset_in_page as u32, aligned_buf)?;
+                self.crypt(payload, to_offset + offset_in_page, aligned_buf);
+                self.active.write(to_offset + offset_in_page as u32, aligned_buf)?;
+            }
+
+            self.update_progress(progress_index, aligned_buf)?;
+        }
+        Ok(())
+    }
+
+    fn copy_page_once_to_dfu(
+        &mut self,
+        progress_index: usize,
+        from_offset: u32,
+        to_offset: u32,
+        payload: Payload,
+        aligned_buf: &mut [u8],
+    ) -> Result<(), BootError> {
+        if self.current_progress(aligned_buf)? <= progress_index {
+            let page_size = Self::PAGE_SIZE as u32;
+
+            self.dfu.erase(to_offset as u32, to_offset + page_size)?;
+
+            for offset_in_page in (0..page_size).step_by(aligned_buf.len()) {
+                self.active.read(from_offset + offset_in_page as u32, aligned_buf)?;
+                self.crypt(payload, from_offset + offset_in_page, aligned_buf);
+                self.dfu.write(to_offset + offset_in_page as u32, aligned_buf)?;
+            }
+
+            self.update_progress(progress_index, aligned_buf)?;
+        }
+        Ok(())
+    }
+
+    fn swap(&mut self, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        let page_count = self.active.capacity() as u32 / Self::PAGE_SIZE;
+        for page_num in 0..page_count {
+            let progress_index = (page_num * 2) as usize;
+
+            // Copy active page to the 'next' DFU page.
+            let active_from_offset = (page_count - 1 - page_num) * Self::PAGE_SIZE;
+            let dfu_to_offset = (page_count - page_num) * Self::PAGE_SIZE;
+            //trace!("Copy active {} to dfu {}", active_from_offset, dfu_to_offset);
+            self.copy_page_once_to_dfu(
+                progress_index,
+                active_from_offset,
+                dfu_to_offset,
+                Payload::Previous,
+                aligned_buf,
+            )?;
+
+            // Copy DFU page to the active page
+            let active_to_offset = (page_count - 1 - page_num) * Self::PAGE_SIZE;
+            let dfu_from_offset = (page_count - 1 - page_num) * Self::PAGE_SIZE;
+            //trace!("Copy dfy {} to active {}", dfu_from_offset, active_to_offset);
+            self.copy_page_once_to_active(
+                progress_index + 1,
+                dfu_from_offset,
+                active_to_offset,
+                Payload::Update,
+                aligned_buf,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn overwrite(&mut self, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        let page_count = self.active.capacity() as u32 / Self::PAGE_SIZE;
+        for page_num in 0..page_count {
+            let offset = page_num * Self::PAGE_SIZE;
+            self.copy_page_once_to_active(page_num as usize, offset, offset, Payload::Update, aligned_buf)?;
+        }
+
+        Ok(())
+    }
+
+    fn revert(&mut self, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        let page_count = self.active.capacity() as u32 / Self::PAGE_SIZE;
+        for page_num in 0..page_count {
+            let progress_index = (page_count * 2 + page_num * 2) as usize;
+
+            // Copy the bad active page to the DFU page
+            let active_from_offset = page_num * Self::PAGE_SIZE;
+            let dfu_to_offset = page_num * Self::PAGE_SIZE;
+            self.copy_page_once_to_dfu(
+                progress_index,
+                active_from_offset,
+                dfu_to_offset,
+                Payload::Update,
+                aligned_buf,
+            )?;
+
+            // Copy the DFU page back to the active page
+            let active_to_offset = page_num * Self::PAGE_SIZE;
+            let dfu_from_offset = (page_num + 1) * Self::PAGE_SIZE;
+            self.copy_page_once_to_active(
+                progress_index + 1,
+                dfu_from_offset,
+                active_to_offset,
+                Payload::Previous,
+                aligned_buf,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Read the magic state from the start of the state partition.
+fn read_magic<STATE: NorFlash>(state: &mut STATE, aligned_buf: &mut [u8]) -> Result<State, BootError> {
+    let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+    state.read(0, state_word)?;
+
+    if !state_word.iter().any(|&b| b != SWAP_MAGIC) {
+        Ok(State::Swap)
+    } else if !state_word.iter().any(|&b| b != DFU_DETACH_MAGIC) {
+        Ok(State::DfuDetach)
+    } else if !state_word.iter().any(|&b| b != REVERT_MAGIC) {
+        Ok(State::Revert)
+    } else if !state_word.iter().any(|&b| b != RECOVERY_MAGIC) {
+        Ok(State::Recovery)
+    } else {
+        Ok(State::Boot)
+    }
+}
+
+/// Invalidate the progress, clear the first `area` bytes of the state partition and write a
+/// new magic.
+fn write_magic<STATE: NorFlash>(
+    state: &mut STATE,
+    area: u32,
+    magic: u8,
+    aligned_buf: &mut [u8],
+) -> Result<(), BootError> {
+    let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+
+    // Invalidate progress, unless an interrupted call already did
+    state.read(STATE::WRITE_SIZE as u32, state_word)?;
+    if state_word.iter().all(|&b| b == STATE_ERASE_VALUE) {
+        state_word.fill(!STATE_ERASE_VALUE);
+        state.write(STATE::WRITE_SIZE as u32, state_word)?;
+    }
+
+    // Clear magic and progress
+    state.erase(0, area)?;
+
+    // Set magic
+    state_word.fill(magic);
+    state.write(0, state_word)?;
+    Ok(())
+}
+
+/// Mark the progress index `progress_index` as done.
+fn update_progress<STATE: NorFlash>(
+    state: &mut STATE,
+    progress_index: usize,
+    aligned_buf: &mut [u8],
+) -> Result<(), BootError> {
+    let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+    state_word.fill(!STATE_ERASE_VALUE);
+    state.write((2 + progress_index) as u32 * STATE::WRITE_SIZE as u32, state_word)?;
+    Ok(())
+}
+
+/// Header of the image at the start of `flash`, if any. The security counter of MCUboot images
+/// is read from their protected trailer, and images whose protected trailer is malformed have
+/// no header.
+fn read_header<F: NorFlash>(flash: &mut F) -> Result<Option<ImageHeader>, BootError> {
+    let mut buf = AlignedBuffer([0; HEADER_LEN]);
+    flash.read(0, &mut buf.0)?;
+    let Ok(mut header) = ImageHeader::parse(&buf.0) else {
+        return Ok(None);
+    };
+    if header.format != ImageFormat::McuBoot || header.protected_tlv_size == 0 {
+        return Ok(Some(header));
+    }
+
+    let mut offset = header.protected_tlv_offset();
+    let end = header.tlv_offset();
+    let mut tlv = [0; TLV_HEADER_LEN];
+    if end as usize > flash.capacity() {
+        return Ok(None);
+    }
+    read_unaligned(flash, offset, &mut tlv, &mut buf.0)?;
+    let (magic, len) = parse_tlv_header(&tlv);
+    if magic != TLV_PROT_INFO_MAGIC || len != header.protected_tlv_size {
+        return Ok(None);
+    }
+
+    offset += TLV_HEADER_LEN as u32;
+    while offset + TLV_HEADER_LEN as u32 <= end {
+        read_unaligned(flash, offset, &mut tlv, &mut buf.0)?;
+        let (ty, len) = parse_tlv_header(&tlv);
+        offset += TLV_HEADER_LEN as u32;
+        if ty == TLV_SEC_CNT {
+            let mut counter = [0; 4];
+            if len != 4 || offset + 4 > end {
+                return Ok(None);
+            }
+            read_unaligned(flash, offset, &mut counter, &mut buf.0)?;
+            header.security_counter = u32::from_le_bytes(counter);
+            break;
+        }
+        offset += len as u32;
+    }
+    Ok(Some(header))
+}
+
+/// Size of the state partition used for the magic and progress with `strategy`.
+fn state_area<STATE: NorFlash>(state: &STATE, page_size: u32, strategy: Strategy) -> u32 {
+    let area = security_counter::state_area(state.capacity(), STATE::ERASE_SIZE, STATE::WRITE_SIZE);
+    match strategy {
+        Strategy::SwapScratch => area.saturating_sub(page_size),
+        _ => area,
+    }
+}
+
+/// Number of progress indices used to install and revert an update with `strategy`, in front
+/// of the boot attempts.
+fn progress_count(strategy: Strategy, page_count: u32) -> u32 {
+    match strategy {
+        Strategy::Swap => 4 * page_count,
+        Strategy::Overwrite => page_count,
+        Strategy::SwapScratch => 6 * page_count,
+        Strategy::DirectXip => 2,
+    }
+}
+
+fn assert_partitions<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash>(
+    active: &ACTIVE,
+    dfu: &DFU,
+    state: &STATE,
+    page_size: u32,
+    strategy: Strategy,
+    max_attempts: u8,
+) {
+    assert_eq!(active.capacity() as u32 % page_size, 0);
+    assert_eq!(dfu.capacity() as u32 % page_size, 0);
+    match strategy {
+        Strategy::Swap => {
+            // DFU partition has to be bigger than ACTIVE partition to handle swap algorithm
+            assert!(dfu.capacity() as u32 - active.capacity() as u32 >= page_size);
+        }
+        Strategy::Overwrite => {
+            assert!(dfu.capacity() >= active.capacity());
+        }
+        Strategy::SwapScratch => {
+            assert!(dfu.capacity() >= active.capacity());
+            // The scratch page is erased and written through the state partition.
+            assert_eq!(page_size % STATE::ERASE_SIZE as u32, 0);
+            assert_eq!(page_size % STATE::WRITE_SIZE as u32, 0);
+            let area = security_counter::state_area(state.capacity(), STATE::ERASE_SIZE, STATE::WRITE_SIZE);
+            assert!(area >= page_size);
+        }
+        Strategy::DirectXip => {}
+    }
+    let page_count = active.capacity() as u32 / page_size;
+    let progress_count = progress_count(strategy, page_count) + max_attempts as u32 - 1;
+    let state_area = state_area(state, page_size, strategy);
+    assert!(2 + progress_count <= state_area / STATE::WRITE_SIZE as u32);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem_flash::MemFlash;
+
+    #[test]
+    #[should_panic]
+    fn test_range_asserts() {
+        const ACTIVE_SIZE: usize = 4194304 - 4096;
+        const DFU_SIZE: usize = 4194304;
+        const STATE_SIZE: usize = 4096;
+        static ACTIVE: MemFlash<ACTIVE_SIZE, 4, 4> = MemFlash::new(0xFF);
+        static DFU: MemFlash<DFU_SIZE, 4, 4> = MemFlash::new(0xFF);
+        static STATE: MemFlash<STATE_SIZE, 4, 4> = MemFlash::new(0xFF);
+        assert_partitions(&ACTIVE, &DFU, &STATE, 4096, Strategy::Swap, 1);
+    }
+}
@@ -0,0 +1,441 @@
+use embedded_storage::nor_flash::NorFlash;
+
+use super::xip::read_unaligned;
+use super::{BootError, read_header, read_magic, update_progress, write_magic};
+use crate::image::{
+    DEPENDENCY_LEN, ImageDependency, ImageHeader, ImageVersion, TLV_DEPENDENCY, TLV_HEADER_LEN, TLV_PROT_INFO_MAGIC,
+    parse_tlv_header,
+};
+use crate::security_counter::{self, MAX_RECORD_SIZE};
+use crate::{AlignedBuffer, BOOT_MAGIC, BootEvent, BootRecord, REVERT_MAGIC, STATE_ERASE_VALUE, State};
+
+/// Active and DFU partitions of one image of a [`MultiBootLoader`].
+pub struct ImagePartitions<ACTIVE, DFU> {
+    /// Partition the image runs from.
+    pub active: ACTIVE,
+    /// Partition its updates are written to, at least one page bigger than `active`.
+    pub dfu: DFU,
+}
+
+/// Flash configuration of a [`MultiBootLoader`].
+pub struct MultiBootLoaderConfig<ACTIVE, DFU, STATE, const N: usize> {
+    /// Partitions of each image, in image index order.
+    pub images: [ImagePartitions<ACTIVE, DFU>; N],
+    /// State partition shared by all images.
+    pub state: STATE,
+}
+
+/// Bootloader updating several images together, such as the application and network core
+/// images of a dual-core chip.
+///
+/// The application writes the updates of some of the images to their DFU partitions and marks
+/// them updated together with [`crate::FirmwareState::mark_images_updated`]. On the next boot
+/// the bootloader checks the [`ImageDependency`] entries of the resulting set of images, and
+/// swaps each updated image with the algorithm of [`Strategy::Swap`](super::Strategy::Swap).
+/// The set is then on trial as a whole: if the application does not mark it booted, all the
+/// updated images are reverted, so that images that do not work together never run after a
+/// failed update or a power failure.
+///
+/// The state partition holds one word per image after the magic and validity words, marking
+/// the images to update, followed by the progress of the swap and of the revert of each updated
+/// image in order. A single security counter covers all the images: it is raised to the counter
+/// of image 0 when the images are confirmed, and every updated image must carry at least the
+/// stored counter. Encrypted images, boot attempts and recovery images are not supported.
+pub struct MultiBootLoader<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash, const N: usize> {
+    images: [ImagePartitions<ACTIVE, DFU>; N],
+    state: STATE,
+}
+
+impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash, const N: usize> MultiBootLoader<ACTIVE, DFU, STATE, N> {
+    /// Get the page size which is the "unit of operation" within the bootloader.
+    const PAGE_SIZE: u32 = if ACTIVE::ERASE_SIZE > DFU::ERASE_SIZE {
+        ACTIVE::ERASE_SIZE as u32
+    } else {
+        DFU::ERASE_SIZE as u32
+    };
+
+    /// Create a new instance of a bootloader with the flash partitions of each image.
+    ///
+    /// - All partitions must be aligned with the page size.
+    /// - Each DFU partition must be at least one page bigger than its active partition.
+    /// - At most 32 images are supported.
+    pub fn new(config: MultiBootLoaderConfig<ACTIVE, DFU, STATE, N>) -> Self {
+        Self {
+            images: config.images,
+            state: config.state,
+        }
+    }
+
+    /// Perform necessary boot preparations like swapping images.
+    ///
+    /// The provided aligned_buf argument must satisfy the same requirements as for
+    /// [`BootLoader::prepare_boot`](super::BootLoader::prepare_boot).
+    ///
+    /// An update whose dependencies are not met, or holding an image with a security counter
+    /// lower than the stored one, is rejected as a whole and the current images boot in the [`State::Boot`]
+    /// state.
+    pub fn prepare_boot(&mut self, aligned_buf: &mut [u8]) -> Result<State, BootError> {
+        const {
+            core::assert!(N > 0 && N <= 32);
+            core::assert!(Self::PAGE_SIZE % ACTIVE::WRITE_SIZE as u32 == 0);
+            core::assert!(Self::PAGE_SIZE % ACTIVE::ERASE_SIZE as u32 == 0);
+            core::assert!(Self::PAGE_SIZE % DFU::WRITE_SIZE as u32 == 0);
+            core::assert!(Self::PAGE_SIZE % DFU::ERASE_SIZE as u32 == 0);
+        }
+
+        assert!(aligned_buf.len() >= STATE::WRITE_SIZE);
+        assert_eq!(0, Self::PAGE_SIZE % aligned_buf.len() as u32);
+        assert_eq!(0, aligned_buf.len() % ACTIVE::WRITE_SIZE);
+        assert_eq!(0, aligned_buf.len() % DFU::WRITE_SIZE);
+        self.assert_partitions();
+
+        match self.read_state(aligned_buf)? {
+            State::Swap => {
+                let updated = self.updated_images(aligned_buf)?;
+                let swapped = N + 2 * self.page_count(updated);
+                let progress = self.current_progress(aligned_buf)?;
+                if progress < swapped {
+                    // Nothing was copied yet, so the update can still be rejected.
+                    if progress == N && !self.update_allowed(updated, aligned_buf)? {
+                        self.set_magic(BOOT_MAGIC, aligned_buf)?;
+                        self.record_boot()?;
+                        return Ok(State::Boot);
+                    }
+
+                    trace!("Swapping images {:x}", updated);
+                    self.record(BootEvent::Swap, 0)?;
+                    let mut base = N;
+                    for image in (0..N).filter(|&image| updated & (1 << image) != 0) {
+                        self.swap(image, base, aligned_buf)?;
+                        base += 2 * self.images[image].active.capacity() / Self::PAGE_SIZE as usize;
+                    }
+                } else {
+                    trace!("Reverting images {:x}", updated);
+                    self.record(BootEvent::Revert, 0)?;
+                    let mut base = swapped;
+                    for image in (0..N).filter(|&image| updated & (1 << image) != 0) {
+                        self.revert(image, base, aligned_buf)?;
+                        base += 2 * self.images[image].active.capacity() / Self::PAGE_SIZE as usize;
+                    }
+                    self.set_magic(REVERT_MAGIC, aligned_buf)?;
+                }
+                Ok(State::Swap)
+            }
+            State::Boot => {
+                self.record_boot()?;
+                Ok(State::Boot)
+            }
+            State::Revert => {
+                self.record(BootEvent::Revert, 0)?;
+                Ok(State::Revert)
+            }
+            State::DfuDetach => {
+                self.record(BootEvent::DfuDetach, 0)?;
+                Ok(State::DfuDetach)
+            }
+            State::Recovery => {
+                self.record(BootEvent::Recovery, 0)?;
+                Ok(State::Recovery)
+            }
+        }
+    }
+
+    /// Read the magic state from flash
+    pub fn read_state(&mut self, aligned_buf: &mut [u8]) -> Result<State, BootError> {
+        read_magic(&mut self.state, aligned_buf)
+    }
+
+    /// Read the security counter stored in the state partition, raised to the counter of image 0
+    /// when the images are confirmed. See
+    /// [`BootLoader::security_counter`](super::BootLoader::security_counter).
+    pub fn security_counter(&mut self) -> Result<Option<u32>, BootError> {
+        let mut record = AlignedBuffer([0; MAX_RECORD_SIZE]);
+        Ok(security_counter::read(&mut self.state, &mut record.0)?)
+    }
+
+    /// Read the newest entries of the boot history into `out`, newest first, and return how
+    /// many were read. See [`BootLoader::boot_history`](super::BootLoader::boot_history).
+    pub fn boot_history(&mut self, out: &mut [BootRecord]) -> Result<usize, BootError> {
+        let mut record = AlignedBuffer([0; MAX_RECORD_SIZE]);
+        Ok(security_counter::history(&mut self.state, out, &mut record.0)?)
+    }
+
+    /// Whether the update of the `updated` images may be installed.
+    fn update_allowed(&mut self, updated: u32, aligned_buf: &mut [u8]) -> Result<bool, BootError> {
+        if updated == 0 {
+            warn!("No image marked updated");
+            return Ok(false);
+        }
+
+        let mut headers = [None; N];
+        for (image, header) in headers.iter_mut().enumerate() {
+            *header = self.header(image, updated & (1 << image) != 0)?;
+        }
+
+        if let Some(stored) = self.security_counter()? {
+            for (image, header) in headers.iter().enumerate() {
+                if updated & (1 << image) != 0 && header.map_or(0, |header| header.security_counter) < stored {
+                    warn!("Image {} would lower the security counter", image);
+                    return Ok(false);
+                }
+            }
+        }
+
+        let mut versions = [None; N];
+        for (version, header) in versions.iter_mut().zip(&headers) {
+            *version = header.map(|header| header.version);
+        }
+        for (image, header) in headers.iter().enumerate() {
+            if let Some(header) = header
+                && !self.dependencies_met(image, updated & (1 << image) != 0, header, &versions, aligned_buf)?
+            {
+                warn!("Dependencies of image {} are not met", image);
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Whether every [`TLV_DEPENDENCY`] entry in the protected trailer of `image` names an
+    /// image whose version is at least the required one.
+    fn dependencies_met(
+        &mut self,
+        image: usize,
+        from_dfu: bool,
+        header: &ImageHeader,
+        versions: &[Option<ImageVersion>; N],
+        aligned_buf: &mut [u8],
+    ) -> Result<bool, BootError> {
+        if header.protected_tlv_size == 0 {
+            return Ok(true);
+        }
+
+        let mut offset = header.protected_tlv_offset();
+        let end = header.tlv_offset();
+        let mut tlv = [0; TLV_HEADER_LEN];
+        self.read(image, from_dfu, offset, &mut tlv, aligned_buf)?;
+        let (magic, len) = parse_tlv_header(&tlv);
+        if magic != TLV_PROT_INFO_MAGIC || len != header.protected_tlv_size {
+            return Ok(false);
+        }
+
+        offset += TLV_HEADER_LEN as u32;
+        while offset + TLV_HEADER_LEN as u32 <= end {
+            self.read(image, from_dfu, offset, &mut tlv, aligned_buf)?;
+            let (ty, len) = parse_tlv_header(&tlv);
+            offset += TLV_HEADER_LEN as u32;
+            if ty == TLV_DEPENDENCY {
+                let mut value = [0; DEPENDENCY_LEN];
+                if len as usize != DEPENDENCY_LEN || offset + len as u32 > end {
+                    return Ok(false);
+                }
+                self.read(image, from_dfu, offset, &mut value, aligned_buf)?;
+                let Ok(dependency) = ImageDependency::parse(&value) else {
+                    return Ok(false);
+                };
+                let met = versions
+                    .get(dependency.image as usize)
+                    .copied()
+                    .flatten()
+                    .is_some_and(|version| version >= dependency.min_version);
+                if !met {
+                    return Ok(false);
+                }
+            }
+            offset += len as u32;
+        }
+        Ok(true)
+    }
+
+    /// Header of `image` in its DFU or active partition.
+    fn header(&mut self, image: usize, from_dfu: bool) -> Result<Option<ImageHeader>, BootError> {
+        let partitions = &mut self.images[image];
+        if from_dfu {
+            read_header(&mut partitions.dfu)
+        } else {
+            read_header(&mut partitions.active)
+        }
+    }
+
+    fn read(
+        &mut self,
+        image: usize,
+        from_dfu: bool,
+        offset: u32,
+        out: &mut [u8],
+        aligned_buf: &mut [u8],
+    ) -> Result<(), BootError> {
+        let partitions = &mut self.images[image];
+        if from_dfu {
+            read_unaligned(&mut partitions.dfu, offset, out, aligned_buf)
+        } else {
+            read_unaligned(&mut partitions.active, offset, out, aligned_buf)
+        }
+    }
+
+    /// Record a boot of the current images. Image 0 is confirmed, so its security counter is
+    /// now the lowest one accepted.
+    fn record_boot(&mut self) -> Result<(), BootError> {
+        let counter = self.header(0, false)?.map_or(0, |header| header.security_counter);
+        let mut buf = AlignedBuffer([0; MAX_RECORD_SIZE]);
+        security_counter::raise(&mut self.state, counter, &mut buf.0)?;
+        self.record(BootEvent::Boot, counter)
+    }
+
+    /// Append an event to the boot history.
+    fn record(&mut self, event: BootEvent, security_counter: u32) -> Result<(), BootError> {
+        let record = BootRecord {
+            event,
+            security_counter,
+            ..Default::default()
+        };
+        let mut buf = AlignedBuffer([0; MAX_RECORD_SIZE]);
+        security_counter::append(&mut self.state, record, &mut buf.0)?;
+        Ok(())
+    }
+
+    /// Bit mask of the images marked updated, from the words following the validity word.
+    fn updated_images(&mut self, aligned_buf: &mut [u8]) -> Result<u32, BootError> {
+        let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+        let mut updated = 0;
+        for image in 0..N {
+            self.state
+                .read((2 + image) as u32 * STATE::WRITE_SIZE as u32, state_word)?;
+            if state_word.iter().all(|&b| b != STATE_ERASE_VALUE) {
+                updated |= 1 << image;
+            }
+        }
+        Ok(updated)
+    }
+
+    /// Total number of pages of the active partitions of the `images`.
+    fn page_count(&self, images: u32) -> usize {
+        (0..N)
+            .filter(|&image| images & (1 << image) != 0)
+            .map(|image| self.images[image].active.capacity() / Self::PAGE_SIZE as usize)
+            .sum()
+    }
+
+    /// Invalidate the progress, clear the state and write a new magic.
+    fn set_magic(&mut self, magic: u8, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        let area = self.state_area();
+        write_magic(&mut self.state, area, magic, aligned_buf)
+    }
+
+    /// Size of the state partition used for the magic and progress.
+    fn state_area(&self) -> u32 {
+        security_counter::state_area(self.state.capacity(), STATE::ERASE_SIZE, STATE::WRITE_SIZE)
+    }
+
+    /// Progress index of the next step, after the per-image words.
+    fn current_progress(&mut self, aligned_buf: &mut [u8]) -> Result<usize, BootError> {
+        let write_size = STATE::WRITE_SIZE as u32;
+        let max_index = ((self.state_area() as usize - STATE::WRITE_SIZE) / STATE::WRITE_SIZE) - 2;
+        let state_word = &mut aligned_buf[..write_size as usize];
+
+        self.state.read(write_size, state_word)?;
+        if state_word.iter().any(|&b| b != STATE_ERASE_VALUE) {
+            // Progress is invalid
+            return Ok(max_index);
+        }
+
+        for index in N..max_index {
+            self.state.read((2 + index) as u32 * write_size, state_word)?;
+
+            if state_word.contains(&STATE_ERASE_VALUE) {
+                return Ok(index);
+            }
+        }
+        Ok(max_index)
+    }
+
+    fn copy_page_once_to_active(
+        &mut self,
+        image: usize,
+        progress_index: usize,
+        from_offset: u32,
+        to_offset: u32,
+        aligned_buf: &mut [u8],
+    ) -> Result<(), BootError> {
+        if self.current_progress(aligned_buf)? <= progress_index {
+            let page_size = Self::PAGE_SIZE;
+            let ImagePartitions { active, dfu } = &mut self.images[image];
+
+            active.erase(to_offset, to_offset + page_size)?;
+
+            for offset_in_page in (0..page_size).step_by(aligned_buf.len()) {
+                dfu.read(from_offset + offset_in_page, aligned_buf)?;
+                active.write(to_offset + offset_in_page, aligned_buf)?;
+            }
+
+            update_progress(&mut self.state, progress_index, aligned_buf)?;
+        }
+        Ok(())
+    }
+
+    fn copy_page_once_to_dfu(
+        &mut self,
+        image: usize,
+        progress_index: usize,
+        from_offset: u32,
+        to_offset: u32,
+        aligned_buf: &mut [u8],
+    ) -> Result<(), BootError> {
+        if self.current_progress(aligned_buf)? <= progress_index {
+            let page_size = Self::PAGE_SIZE;
+            let ImagePartitions { active, dfu } = &mut self.images[image];
+
+            dfu.erase(to_offset, to_offset + page_size)?;
+
+            for offset_in_page in (0..page_size).step_by(aligned_buf.len()) {
+                active.read(from_offset + offset_in_page, aligned_buf)?;
+                dfu.write(to_offset + offset_in_page, aligned_buf)?;
+            }
+
+            update_progress(&mut self.state, progress_index, aligned_buf)?;
+        }
+        Ok(())
+    }
+
+    /// Swap the partitions of `image` as [`BootLoader`](super::BootLoader) does, recording
+    /// progress from `base_index`.
+    fn swap(&mut self, image: usize, base_index: usize, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        let page_count = self.images[image].active.capacity() as u32 / Self::PAGE_SIZE;
+        for page_num in 0..page_count {
+            let progress_index = base_index + (page_num * 2) as usize;
+            let offset = (page_count - 1 - page_num) * Self::PAGE_SIZE;
+
+            // Copy active page to the 'next' DFU page, then the DFU page to the active page.
+            self.copy_page_once_to_dfu(image, progress_index, offset, offset + Self::PAGE_SIZE, aligned_buf)?;
+            self.copy_page_once_to_active(image, progress_index + 1, offset, offset, aligned_buf)?;
+        }
+
+        Ok(())
+    }
+
+    /// Revert the swap of `image`, recording progress from `base_index`.
+    fn revert(&mut self, image: usize, base_index: usize, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        let page_count = self.images[image].active.capacity() as u32 / Self::PAGE_SIZE;
+        for page_num in 0..page_count {
+            let progress_index = base_index + (page_num * 2) as usize;
+            let offset = page_num * Self::PAGE_SIZE;
+
+            // Copy the bad active page to the DFU page, then the previous page back.
+            self.copy_page_once_to_dfu(image, progress_index, offset, offset, aligned_buf)?;
+            self.copy_page_once_to_active(image, progress_index + 1, offset + Self::PAGE_SIZE, offset, aligned_buf)?;
+        }
+
+        Ok(())
+    }
+
+    fn assert_partitions(&self) {
+        for ImagePartitions { active, dfu } in &self.images {
+            assert_eq!(active.capacity() as u32 % Self::PAGE_SIZE, 0);
+            assert_eq!(dfu.capacity() as u32 % Self::PAGE_SIZE, 0);
+            // DFU partition has to be bigger than ACTIVE partition to handle swap algorithm
+            assert!(dfu.capacity() as u32 - active.capacity() as u32 >= Self::PAGE_SIZE);
+        }
+        let progress_count = N + 4 * self.page_count(u32::MAX);
+        assert!(2 + progress_count as u32 <= self.state_area() / STATE::WRITE_SIZE as u32);
+    }
+}
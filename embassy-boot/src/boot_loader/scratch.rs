@@ -0,0 +1,69 @@
+use embedded_storage::nor_flash::NorFlash;
+
+use super::{BootError, BootLoader, Payload};
+
+impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, STATE> {
+    /// Swap the active and DFU partitions through the scratch page, recording progress from
+    /// `base_index`. Swapping again from `3 * page_count` reverts the swap.
+    ///
+    /// Each page is moved in three steps, each of which leaves its source untouched:
+    /// active to scratch, DFU to active, and scratch to DFU. Pages are swapped backwards, so
+    /// the header page of the update stays in the DFU partition until the very last step.
+    pub(super) fn swap_scratch(&mut self, base_index: usize, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        let page_count = self.active.capacity() as u32 / Self::PAGE_SIZE;
+        for (step, page_num) in (0..page_count).rev().enumerate() {
+            let progress_index = base_index + step * 3;
+            let offset = page_num * Self::PAGE_SIZE;
+
+            self.copy_page_once_to_scratch(progress_index, offset, aligned_buf)?;
+            self.copy_page_once_to_active(progress_index + 1, offset, offset, Payload::Update, aligned_buf)?;
+            self.copy_page_once_from_scratch(progress_index + 2, offset, aligned_buf)?;
+        }
+
+        Ok(())
+    }
+
+    fn copy_page_once_to_scratch(
+        &mut self,
+        progress_index: usize,
+        from_offset: u32,
+        aligned_buf: &mut [u8],
+    ) -> Result<(), BootError> {
+        if self.current_progress(aligned_buf)? <= progress_index {
+            let page_size = Self::PAGE_SIZE;
+            let scratch = self.state_area();
+
+            self.state.erase(scratch, scratch + page_size)?;
+
+            for offset_in_page in (0..page_size).step_by(aligned_buf.len()) {
+                self.active.read(from_offset + offset_in_page, aligned_buf)?;
+                self.state.write(scratch + offset_in_page, aligned_buf)?;
+            }
+
+            self.update_progress(progress_index, aligned_buf)?;
+        }
+        Ok(())
+    }
+
+    fn copy_page_once_from_scratch(
+        &mut self,
+        progress_index: usize,
+        to_offset: u32,
+        aligned_buf: &mut [u8],
+    ) -> Result<(), BootError> {
+        if self.current_progress(aligned_buf)? <= progress_index {
+            let page_size = Self::PAGE_SIZE;
+            let scratch = self.state_area();
+
+            self.dfu.erase(to_offset, to_offset + page_size)?;
+
+            for offset_in_page in (0..page_size).step_by(aligned_buf.len()) {
+                self.state.read(scratch + offset_in_page, aligned_buf)?;
+                self.dfu.write(to_offset + offset_in_page, aligned_buf)?;
+            }
+
+            self.update_progress(progress_index, aligned_buf)?;
+        }
+        Ok(())
+    }
+}
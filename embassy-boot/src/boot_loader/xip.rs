@@ -0,0 +1,180 @@
+use digest::Digest;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use sha2::Sha256;
+
+use super::{BootError, BootLoader, Slot, read_header};
+use crate::image::{
+    FLAG_ENCRYPTED, HASH_LEN, ImageHeader, TLV_HEADER_LEN, TLV_INFO_MAGIC, TLV_SHA256, parse_tlv_header,
+};
+use crate::{BootEvent, REVERT_MAGIC, STATE_ERASE_VALUE, State};
+
+impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, STATE> {
+    /// Select the partition to boot with [`super::Strategy::DirectXip`].
+    ///
+    /// The first two progress indices mark the active or the DFU partition as on trial.
+    pub(super) fn prepare_xip<R: ReadNorFlash>(
+        &mut self,
+        state: State,
+        recovery: Option<&mut R>,
+        aligned_buf: &mut [u8],
+    ) -> Result<State, BootError> {
+        let state = match state {
+            State::Swap => {
+                let Some(failed) = self.trial_slot(aligned_buf)? else {
+                    let slot = self.newest_slot(aligned_buf)?.map_or(Slot::Active, |(slot, _)| slot);
+                    trace!("Trying {:?}", slot);
+                    self.record(BootEvent::Swap, 1, 0)?;
+                    self.update_progress(slot as usize, aligned_buf)?;
+                    self.slot = slot;
+                    return Ok(State::Swap);
+                };
+                if let Some(attempt) = self.retry(aligned_buf)? {
+                    trace!("Trying {:?} again, attempt {}", failed, attempt);
+                    self.record(BootEvent::Swap, attempt, 0)?;
+                    self.slot = failed;
+                    return Ok(State::Swap);
+                }
+
+                // Keep the image on trial if there is nothing else to boot.
+                let other = match failed {
+                    Slot::Active => Slot::Dfu,
+                    Slot::Dfu => Slot::Active,
+                };
+                self.record(BootEvent::Revert, 1, 0)?;
+                if self.slot_header(other, aligned_buf)?.is_some() {
+                    warn!("Image on trial was not marked booted, invalidating it");
+                    match failed {
+                        Slot::Active => self.active.erase(0, Self::PAGE_SIZE)?,
+                        Slot::Dfu => self.dfu.erase(0, Self::PAGE_SIZE)?,
+                    }
+                }
+                self.set_magic(REVERT_MAGIC, aligned_buf)?;
+                State::Revert
+            }
+            State::Revert => {
+                if let Some(attempt) = self.retry(aligned_buf)? {
+                    self.record(BootEvent::Revert, attempt, 0)?;
+                } else if let Some(recovery) = recovery {
+                    return self.start_recovery(recovery, aligned_buf);
+                } else {
+                    self.record(BootEvent::Revert, 0, 0)?;
+                }
+                State::Revert
+            }
+            State::Recovery => {
+                self.record(BootEvent::Recovery, 0, 0)?;
+                return self.recover(recovery, aligned_buf);
+            }
+            state => state,
+        };
+
+        let newest = self.newest_slot(aligned_buf)?;
+        self.slot = newest.map_or(Slot::Active, |(slot, _)| slot);
+        match state {
+            // The newest image is confirmed, its security counter is now the lowest one accepted.
+            State::Boot => {
+                let counter = newest.map_or(0, |(_, header)| header.security_counter);
+                self.record_confirmed(counter)?;
+            }
+            State::DfuDetach => self.record(BootEvent::DfuDetach, 0, 0)?,
+            _ => {}
+        }
+        Ok(state)
+    }
+
+    fn trial_slot(&mut self, aligned_buf: &mut [u8]) -> Result<Option<Slot>, BootError> {
+        let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+        for slot in [Slot::Active, Slot::Dfu] {
+            self.state
+                .read((2 + slot as u32) * STATE::WRITE_SIZE as u32, state_word)?;
+            if state_word.iter().all(|&b| b != STATE_ERASE_VALUE) {
+                return Ok(Some(slot));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The partition holding the newest bootable image, preferring the active one.
+    fn newest_slot(&mut self, aligned_buf: &mut [u8]) -> Result<Option<(Slot, ImageHeader)>, BootError> {
+        let active = self.slot_header(Slot::Active, aligned_buf)?;
+        let dfu = self.slot_header(Slot::Dfu, aligned_buf)?;
+        Ok(match (active, dfu) {
+            (Some(active), Some(dfu)) if dfu.version > active.version => Some((Slot::Dfu, dfu)),
+            (Some(active), _) => Some((Slot::Active, active)),
+            (None, dfu) => dfu.map(|dfu| (Slot::Dfu, dfu)),
+        })
+    }
+
+    /// Header of the image in `slot` if it is valid, not encrypted and not rolled back.
+    fn slot_header(&mut self, slot: Slot, aligned_buf: &mut [u8]) -> Result<Option<ImageHeader>, BootError> {
+        let header = match slot {
+            Slot::Active => valid_image(&mut self.active, aligned_buf)?,
+            Slot::Dfu => valid_image(&mut self.dfu, aligned_buf)?,
+        };
+        let Some(header) = header else {
+            return Ok(None);
+        };
+        let stored = self.security_counter()?.unwrap_or(0);
+        Ok((header.flags & FLAG_ENCRYPTED == 0 && header.security_counter >= stored).then_some(header))
+    }
+}
+
+/// Header of the image in `flash`, if it matches its SHA-256 TLV.
+fn valid_image<F: NorFlash>(flash: &mut F, aligned_buf: &mut [u8]) -> Result<Option<ImageHeader>, BootError> {
+    let Some(header) = read_header(flash)? else {
+        return Ok(None);
+    };
+    let tlv_offset = header.tlv_offset();
+    if tlv_offset as usize + TLV_HEADER_LEN > flash.capacity() {
+        return Ok(None);
+    }
+
+    let mut hasher = Sha256::new();
+    for offset in (0..tlv_offset).step_by(aligned_buf.len()) {
+        flash.read(offset, aligned_buf)?;
+        let len = (tlv_offset - offset).min(aligned_buf.len() as u32) as usize;
+        hasher.update(&aligned_buf[..len]);
+    }
+    let hash: [u8; HASH_LEN] = hasher.finalize().into();
+
+    let mut tlv = [0; TLV_HEADER_LEN];
+    read_unaligned(flash, tlv_offset, &mut tlv, aligned_buf)?;
+    let (magic, len) = parse_tlv_header(&tlv);
+    let end = tlv_offset + len as u32;
+    if magic != TLV_INFO_MAGIC || end as usize > flash.capacity() {
+        return Ok(None);
+    }
+
+    let mut offset = tlv_offset + TLV_HEADER_LEN as u32;
+    while offset + TLV_HEADER_LEN as u32 <= end {
+        read_unaligned(flash, offset, &mut tlv, aligned_buf)?;
+        let (ty, len) = parse_tlv_header(&tlv);
+        offset += TLV_HEADER_LEN as u32;
+        if ty == TLV_SHA256 && len as usize == HASH_LEN && offset + len as u32 <= end {
+            let mut expected = [0; HASH_LEN];
+            read_unaligned(flash, offset, &mut expected, aligned_buf)?;
+            return Ok((expected == hash).then_some(header));
+        }
+        offset += len as u32;
+    }
+    Ok(None)
+}
+
+/// Read `out.len()` bytes at any `offset`, through reads of the whole aligned buffer.
+pub(super) fn read_unaligned<F: NorFlash>(
+    flash: &mut F,
+    mut offset: u32,
+    out: &mut [u8],
+    aligned_buf: &mut [u8],
+) -> Result<(), BootError> {
+    let mut done = 0;
+    while done < out.len() {
+        let start = offset as usize % aligned_buf.len();
+        flash.read(offset - start as u32, aligned_buf)?;
+        let len = (aligned_buf.len() - start).min(out.len() - done);
+        out[done..done + len].copy_from_slice(&aligned_buf[start..start + len]);
+        done += len;
+        offset += len as u32;
+    }
+    Ok(())
+}
@@ -3,3 +3,8 @@ pub(crate) mod ed25519_dalek;
 
 #[cfg(feature = "ed25519-salty")]
 pub(crate) mod salty;
+
+#[cfg(feature = "ed25519-dalek")]
+pub(crate) use self::ed25519_dalek::Sha512;
+#[cfg(all(feature = "ed25519-salty", not(feature = "ed25519-dalek")))]
+pub(crate) use self::salty::Sha512;
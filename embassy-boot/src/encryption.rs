@@ -0,0 +1,203 @@
+//! Encrypted images.
+//!
+//! The firmware of an encrypted image is encrypted with AES-128-CTR under a random image
+//! key, starting from a zero counter at the first firmware byte. The header and TLV trailer
+//! stay in plaintext, so the updater can check the image hash and signature, which cover the
+//! encrypted firmware, without the key.
+//!
+//! The image key is wrapped for the device with ECIES over X25519 and stored right after the
+//! [`ImageHeader`](crate::image::ImageHeader), in the header padding, as an ephemeral public
+//! key, an HMAC-SHA256 tag and the encrypted key. The shared secret is expanded with
+//! HKDF-SHA256 into a 16-byte key encryption key and a 32-byte MAC key.
+//!
+//! The bootloader decrypts pages while swapping the image into the ACTIVE partition, and
+//! encrypts the pages it moves into the DFU partition, so that both images stay encrypted
+//! there. Give it the device private key with `BootLoader::set_decryption_key`.
+
+use aes::Aes128;
+use aes::cipher::{BlockEncrypt, KeyInit};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::boot_loader::Payload;
+pub use crate::image::FLAG_ENCRYPTED;
+use crate::image::{HEADER_LEN, ImageError, ImageHeader};
+
+/// Length of an image key.
+pub const KEY_LEN: usize = 16;
+/// Length of a wrapped image key, stored at [`HEADER_LEN`] in encrypted images.
+pub const WRAPPED_KEY_LEN: usize = 32 + 32 + KEY_LEN;
+
+const KDF_INFO: &[u8] = b"MCUBoot_ECIES_v1";
+
+fn derive_keys(shared: &[u8; 32]) -> ([u8; KEY_LEN], [u8; 32]) {
+    let mut okm = [0; KEY_LEN + 32];
+    // 48 bytes are always within the HKDF-SHA256 output limit.
+    unwrap!(Hkdf::<Sha256>::new(None, shared).expand(KDF_INFO, &mut okm).ok());
+    let mut kek = [0; KEY_LEN];
+    let mut mac_key = [0; 32];
+    kek.copy_from_slice(&okm[..KEY_LEN]);
+    mac_key.copy_from_slice(&okm[KEY_LEN..]);
+    (kek, mac_key)
+}
+
+fn mac(mac_key: &[u8; 32]) -> Hmac<Sha256> {
+    unwrap!(<Hmac<Sha256> as Mac>::new_from_slice(mac_key).ok())
+}
+
+/// XOR `key` with the first AES-CTR block of `kek`.
+fn crypt_key(kek: &[u8; KEY_LEN], key: &mut [u8; KEY_LEN]) {
+    let mut block = [0; 16];
+    Aes128::new(kek.into()).encrypt_block((&mut block).into());
+    key.iter_mut().zip(block).for_each(|(b, k)| *b ^= k);
+}
+
+/// Wrap `image_key` for the device with public key `device_public_key`.
+///
+/// `ephemeral_secret` must be random and used for a single image.
+pub fn wrap_key(
+    image_key: &[u8; KEY_LEN],
+    ephemeral_secret: [u8; 32],
+    device_public_key: &[u8; 32],
+) -> [u8; WRAPPED_KEY_LEN] {
+    let ephemeral = StaticSecret::from(ephemeral_secret);
+    let shared = ephemeral.diffie_hellman(&PublicKey::from(*device_public_key));
+    let (kek, mac_key) = derive_keys(shared.as_bytes());
+
+    let mut encrypted = *image_key;
+    crypt_key(&kek, &mut encrypted);
+    let tag = mac(&mac_key).chain_update(encrypted).finalize().into_bytes();
+
+    let mut wrapped = [0; WRAPPED_KEY_LEN];
+    wrapped[..32].copy_from_slice(PublicKey::from(&ephemeral).as_bytes());
+    wrapped[32..64].copy_from_slice(&tag);
+    wrapped[64..].copy_from_slice(&encrypted);
+    wrapped
+}
+
+/// Unwrap an image key with the device private key.
+pub fn unwrap_key(wrapped: &[u8; WRAPPED_KEY_LEN], device_secret: &[u8; 32]) -> Result<[u8; KEY_LEN], ImageError> {
+    let mut ephemeral = [0; 32];
+    ephemeral.copy_from_slice(&wrapped[..32]);
+    let shared = StaticSecret::from(*device_secret).diffie_hellman(&PublicKey::from(ephemeral));
+    let (kek, mac_key) = derive_keys(shared.as_bytes());
+
+    let mut key = [0; KEY_LEN];
+    key.copy_from_slice(&wrapped[64..]);
+    mac(&mac_key)
+        .chain_update(key)
+        .verify_slice(&wrapped[32..64])
+        .map_err(|_| ImageError::Decryption)?;
+    crypt_key(&kek, &mut key);
+    Ok(key)
+}
+
+/// The public key matching a device private key.
+pub fn public_key(device_secret: &[u8; 32]) -> [u8; 32] {
+    PublicKey::from(&StaticSecret::from(*device_secret)).to_bytes()
+}
+
+/// AES-CTR keystream of an image, indexed by the position of bytes in the ACTIVE partition.
+pub(crate) struct ImageCipher {
+    aes: Aes128,
+    start: u32,
+    end: u32,
+}
+
+impl ImageCipher {
+    /// Create the cipher for the image with `header` and wrapped key `wrapped`.
+    pub(crate) fn new(
+        header: &ImageHeader,
+        wrapped: &[u8; WRAPPED_KEY_LEN],
+        device_secret: &[u8; 32],
+    ) -> Result<Self, ImageError> {
+        let key = unwrap_key(wrapped, device_secret)?;
+        Ok(Self::from_key(&key, header))
+    }
+
+    pub(crate) fn from_key(key: &[u8; KEY_LEN], header: &ImageHeader) -> Self {
+        let start = header.header_size as u32;
+        Self {
+            aes: Aes128::new(key.into()),
+            start,
+            end: start + header.image_size,
+        }
+    }
+
+    /// Encrypt or decrypt `buf`, found at `position` in the ACTIVE partition.
+    pub(crate) fn apply(&self, payload: Payload, position: u32, buf: &mut [u8]) {
+        let (start, end, counter_base) = match payload {
+            Payload::Update => (self.start, self.end, 0),
+            Payload::Previous => (0, u32::MAX, 1 << 127),
+        };
+        let buf_end = position.saturating_add(buf.len() as u32);
+        let mut pos = position.max(start);
+        let to = buf_end.min(end);
+        while pos < to {
+            let offset = pos - start;
+            let mut block = (counter_base | (offset / 16) as u128).to_be_bytes();
+            self.aes.encrypt_block((&mut block).into());
+
+            let skip = (offset % 16) as usize;
+            let len = (16 - skip).min((to - pos) as usize);
+            let chunk = &mut buf[(pos - position) as usize..][..len];
+            chunk.iter_mut().zip(&block[skip..]).for_each(|(b, k)| *b ^= k);
+            pos += len as u32;
+        }
+    }
+}
+
+/// Read the wrapped key of an encrypted image from the start of its header.
+pub(crate) fn wrapped_key(header_buf: &[u8]) -> Option<&[u8; WRAPPED_KEY_LEN]> {
+    header_buf
+        .get(HEADER_LEN..HEADER_LEN + WRAPPED_KEY_LEN)?
+        .try_into()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::ImageVersion;
+
+    #[test]
+    fn wrap_unwrap() {
+        let device_secret = [7; 32];
+        let image_key = [0x42; KEY_LEN];
+        let wrapped = wrap_key(&image_key, [9; 32], &public_key(&device_secret));
+        assert_eq!(unwrap_key(&wrapped, &device_secret), Ok(image_key));
+        assert_eq!(unwrap_key(&wrapped, &[8; 32]), Err(ImageError::Decryption));
+
+        let mut tampered = wrapped;
+        tampered[70] ^= 1;
+        assert_eq!(unwrap_key(&tampered, &device_secret), Err(ImageError::Decryption));
+    }
+
+    #[test]
+    fn ctr_matches_contiguous_keystream() {
+        let header = crate::image::ImageBuilder::new(ImageVersion::new(1, 0, 0))
+            .header_size(128)
+            .header(1000);
+        let cipher = ImageCipher::from_key(&[1; KEY_LEN], &header);
+
+        let mut whole = [0u8; 2048];
+        cipher.apply(Payload::Update, 0, &mut whole);
+        assert!(whole[..128].iter().all(|&b| b == 0));
+        assert!(whole[1128..].iter().all(|&b| b == 0));
+
+        // Any split of the buffer produces the same keystream.
+        let mut split = [0u8; 2048];
+        for (i, chunk) in split.chunks_mut(12).enumerate() {
+            cipher.apply(Payload::Update, (i * 12) as u32, chunk);
+        }
+        assert_eq!(whole, split);
+
+        // The previous image uses a different keystream over the whole partition.
+        let mut previous = [0u8; 2048];
+        cipher.apply(Payload::Previous, 0, &mut previous);
+        assert!(previous.iter().any(|&b| b != 0));
+        assert_ne!(previous[128..1128], whole[128..1128]);
+    }
+}
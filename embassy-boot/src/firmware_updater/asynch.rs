@@ -3,10 +3,21 @@ use digest::Digest;
 use embassy_embedded_hal::flash::partition::Partition;
 #[cfg(target_os = "none")]
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
-use embedded_storage_async::nor_flash::NorFlash;
+use embedded_storage_async::nor_flash::{NorFlash, ReadNorFlash};
+use sha2::Sha256;
 
 use super::FirmwareUpdaterConfig;
-use crate::{BOOT_MAGIC, DFU_DETACH_MAGIC, FirmwareUpdaterError, STATE_ERASE_VALUE, SWAP_MAGIC, State};
+use crate::image::{
+    HASH_LEN, HEADER_LEN, ImageError, ImageFormat, ImageHeader, TLV_HEADER_LEN, TLV_INFO_MAGIC, TLV_PROT_INFO_MAGIC,
+    TLV_SEC_CNT, TLV_SHA256, parse_tlv_header,
+};
+use crate::security_counter::{self, MAX_RECORD_SIZE};
+use crate::stream::{Fill, NoSource, SOURCE_CHUNK_LEN, STAGING_LEN, StreamError, UpdateStream};
+use crate::verifier::{MAX_SIGNATURE_LEN, Verifier};
+use crate::{
+    AlignedBuffer, BOOT_MAGIC, BootEvent, BootRecord, DFU_DETACH_MAGIC, FirmwareUpdaterError, STATE_ERASE_VALUE,
+    SWAP_MAGIC, State,
+};
 
 /// FirmwareUpdater is an application API for interacting with the BootLoader without the ability to
 /// 'mess up' the internal bootloader state
@@ -88,61 +99,190 @@ impl<'d, DFU: NorFlash, STATE: NorFlash> FirmwareUpdater<'d, DFU, STATE> {
         _signature: &[u8; 64],
         _update_len: u32,
     ) -> Result<(), FirmwareUpdaterError> {
-        assert!(_update_len <= self.dfu.capacity() as u32);
-
-        self.state.verify_booted().await?;
-
-        #[cfg(feature = "ed25519-dalek")]
+        #[cfg(any(feature = "ed25519-dalek", feature = "ed25519-salty"))]
         {
-            use ed25519_dalek::{Signature, SignatureError, Verifier, VerifyingKey};
+            let mut verifier = crate::verifier::Ed25519::new(*_public_key);
+            self.verify_and_mark_updated_with(&mut verifier, _signature, _update_len)
+                .await
+        }
+        #[cfg(not(any(feature = "ed25519-dalek", feature = "ed25519-salty")))]
+        {
+            Err(FirmwareUpdaterError::Signature(signature::Error::new()))
+        }
+    }
 
-            use crate::digest_adapters::ed25519_dalek::Sha512;
+    /// Verify the DFU with a signature verifier backend. If there is an error then DO NOT
+    /// proceed with updating the firmware.
+    ///
+    /// The signature is expected to have been generated from a `V::Digest` digest of the
+    /// first `update_len` bytes of DFU. Mark to trigger firmware swap on next boot if verify
+    /// succeeds.
+    pub async fn verify_and_mark_updated_with<V: Verifier>(
+        &mut self,
+        verifier: &mut V,
+        signature: &[u8],
+        update_len: u32,
+    ) -> Result<(), FirmwareUpdaterError> {
+        assert!(update_len <= self.dfu.capacity() as u32);
 
-            let into_signature_error = |e: SignatureError| FirmwareUpdaterError::Signature(e.into());
+        self.state.verify_booted().await?;
 
-            let public_key = VerifyingKey::from_bytes(_public_key).map_err(into_signature_error)?;
-            let signature = Signature::from_bytes(_signature);
+        let mut chunk_buf = [0; 64];
+        let mut digest = digest::Output::<V::Digest>::default();
+        self.hash::<V::Digest>(update_len, &mut chunk_buf, &mut digest).await?;
 
-            let mut chunk_buf = [0; 64];
-            let mut message = [0; 64];
-            self.hash::<Sha512>(_update_len, &mut chunk_buf, &mut message).await?;
+        verifier
+            .verify(&digest, signature)
+            .map_err(FirmwareUpdaterError::Signature)?;
+        self.state.mark_updated().await
+    }
 
-            public_key.verify(&message, &signature).map_err(into_signature_error)?;
-            return self.state.mark_updated().await;
+    /// Verify the image in DFU given an ed25519 public key, and mark to trigger firmware swap
+    /// on next boot if it succeeds.
+    ///
+    /// See [`Self::verify_image_and_mark_updated_with`]. If no signature feature is set then
+    /// this method will always return a signature error.
+    #[cfg(feature = "_verify")]
+    pub async fn verify_image_and_mark_updated(
+        &mut self,
+        _public_key: &[u8; 32],
+    ) -> Result<ImageHeader, FirmwareUpdaterError> {
+        #[cfg(any(feature = "ed25519-dalek", feature = "ed25519-salty"))]
+        {
+            let mut verifier = crate::verifier::Ed25519::new(*_public_key);
+            self.verify_image_and_mark_updated_with(&mut verifier).await
         }
-        #[cfg(feature = "ed25519-salty")]
+        #[cfg(not(any(feature = "ed25519-dalek", feature = "ed25519-salty")))]
         {
-            use salty::{PublicKey, Signature};
+            Err(FirmwareUpdaterError::Signature(signature::Error::new()))
+        }
+    }
 
-            use crate::digest_adapters::salty::Sha512;
-            use crate::fmt::Bytes;
+    /// Verify the image in DFU with a signature verifier backend, and mark to trigger firmware
+    /// swap on next boot if it succeeds.
+    ///
+    /// The image is checked like in [`Self::verify_image`], and its signature TLV of type
+    /// `verifier.tlv_type()` must be a signature of its SHA-256 hash. If there is an error
+    /// then DO NOT proceed with updating the firmware.
+    pub async fn verify_image_and_mark_updated_with<V: Verifier>(
+        &mut self,
+        verifier: &mut V,
+    ) -> Result<ImageHeader, FirmwareUpdaterError> {
+        self.state.verify_booted().await?;
+        let header = self.verify_image().await?;
+
+        let mut hash = [0; HASH_LEN];
+        self.read_tlv(header.tlv_offset(), TLV_INFO_MAGIC, TLV_SHA256, &mut hash)
+            .await?;
+        let mut signature = [0; MAX_SIGNATURE_LEN];
+        let len = self
+            .read_tlv(header.tlv_offset(), TLV_INFO_MAGIC, verifier.tlv_type(), &mut signature)
+            .await?;
+
+        verifier
+            .verify(&hash, &signature[..len])
+            .map_err(FirmwareUpdaterError::Signature)?;
+        self.state.mark_updated().await?;
+        Ok(header)
+    }
 
-            fn into_signature_error<E>(_: E) -> FirmwareUpdaterError {
-                FirmwareUpdaterError::Signature(signature::Error::default())
+    /// Read the header of the image in DFU, with the security counter of MCUboot images read
+    /// from their protected trailer.
+    pub async fn read_image_header(&mut self) -> Result<ImageHeader, FirmwareUpdaterError> {
+        let mut header = AlignedBuffer([0; HEADER_LEN]);
+        self.dfu.read(0, &mut header.0).await?;
+        let mut header = ImageHeader::parse(&header.0)?;
+
+        if header.format == ImageFormat::McuBoot && header.protected_tlv_size != 0 {
+            let mut counter = [0; 4];
+            match self
+                .read_tlv(
+                    header.protected_tlv_offset(),
+                    TLV_PROT_INFO_MAGIC,
+                    TLV_SEC_CNT,
+                    &mut counter,
+                )
+                .await
+            {
+                Ok(4) => header.security_counter = u32::from_le_bytes(counter),
+                Ok(_) => return Err(ImageError::InvalidTlv.into()),
+                Err(FirmwareUpdaterError::Image(ImageError::MissingTlv(_))) => {}
+                Err(e) => return Err(e),
             }
+        }
+        Ok(header)
+    }
 
-            let public_key = PublicKey::try_from(_public_key).map_err(into_signature_error)?;
-            let signature = Signature::try_from(_signature).map_err(into_signature_error)?;
-
-            let mut message = [0; 64];
-            let mut chunk_buf = [0; 64];
-            self.hash::<Sha512>(_update_len, &mut chunk_buf, &mut message).await?;
-
-            let r = public_key.verify(&message, &signature);
-            trace!(
-                "Verifying with public key {}, signature {} and message {} yields ok: {}",
-                Bytes(&public_key.to_bytes()),
-                Bytes(&signature.to_bytes()),
-                Bytes(&message),
-                r.is_ok()
-            );
-            r.map_err(into_signature_error)?;
-            return self.state.mark_updated().await;
+    /// Check the image in DFU and return its header.
+    ///
+    /// The SHA-256 hash in its TLV trailer must match its header and payload, and its security
+    /// counter must not be below the one of the installed image, or the bootloader would
+    /// refuse it. The signature is not checked.
+    pub async fn verify_image(&mut self) -> Result<ImageHeader, FirmwareUpdaterError> {
+        let header = self.read_image_header().await?;
+        let tlv_offset = header.tlv_offset();
+        if tlv_offset as usize + TLV_HEADER_LEN > self.dfu.capacity() {
+            return Err(ImageError::TooLarge.into());
         }
-        #[cfg(not(any(feature = "ed25519-dalek", feature = "ed25519-salty")))]
+        if let Some(counter) = self.state.security_counter().await?
+            && header.security_counter < counter
         {
-            Err(FirmwareUpdaterError::Signature(signature::Error::new()))
+            return Err(FirmwareUpdaterError::Rollback);
+        }
+
+        let mut chunk_buf = [0; 64];
+        let mut hash = [0; HASH_LEN];
+        self.hash::<Sha256>(tlv_offset, &mut chunk_buf, &mut hash).await?;
+        let mut expected = [0; HASH_LEN];
+        if self
+            .read_tlv(tlv_offset, TLV_INFO_MAGIC, TLV_SHA256, &mut expected)
+            .await?
+            != HASH_LEN
+        {
+            return Err(ImageError::InvalidTlv.into());
+        }
+        if hash != expected {
+            return Err(ImageError::HashMismatch.into());
         }
+        Ok(header)
+    }
+
+    /// Read the value of the TLV entry of type `ty` in the TLV trailer at `offset`, starting
+    /// with `magic`, and return its length. The entry must fit in `value`.
+    async fn read_tlv(
+        &mut self,
+        offset: u32,
+        magic: u16,
+        ty: u16,
+        value: &mut [u8],
+    ) -> Result<usize, FirmwareUpdaterError> {
+        let mut tlv = [0; TLV_HEADER_LEN];
+        self.dfu.read(offset, &mut tlv).await?;
+        let (info_magic, len) = parse_tlv_header(&tlv);
+        let end = offset as usize + len as usize;
+        if info_magic != magic || (len as usize) < TLV_HEADER_LEN || end > self.dfu.capacity() {
+            return Err(ImageError::InvalidTlv.into());
+        }
+
+        let mut offset = offset as usize + TLV_HEADER_LEN;
+        while offset < end {
+            if offset + TLV_HEADER_LEN > end {
+                return Err(ImageError::InvalidTlv.into());
+            }
+            self.dfu.read(offset as u32, &mut tlv).await?;
+            let (entry_ty, len) = parse_tlv_header(&tlv);
+            let start = offset + TLV_HEADER_LEN;
+            offset = start + len as usize;
+            if offset > end {
+                return Err(ImageError::InvalidTlv.into());
+            }
+            if entry_ty == ty {
+                let value = value.get_mut(..len as usize).ok_or(ImageError::InvalidTlv)?;
+                self.dfu.read(start as u32, value).await?;
+                return Ok(len as usize);
+            }
+        }
+        Err(ImageError::MissingTlv(ty).into())
     }
 
     /// Verify the update in DFU with any digest.
@@ -190,6 +330,20 @@ impl<'d, DFU: NorFlash, STATE: NorFlash> FirmwareUpdater<'d, DFU, STATE> {
         self.state.mark_booted().await
     }
 
+    /// Mark the running firmware as unhealthy, see [`FirmwareState::mark_unhealthy`].
+    pub async fn mark_unhealthy(&mut self, reason: u8) -> Result<(), FirmwareUpdaterError> {
+        self.state.mark_unhealthy(reason).await
+    }
+
+    /// The DFU partition, for writing to it around [`Self::write_firmware`].
+    ///
+    /// The last erased page is forgotten, as it may be written to.
+    #[cfg(feature = "ota")]
+    pub(crate) fn dfu(&mut self) -> &mut DFU {
+        self.last_erased_dfu_sector_index = None;
+        &mut self.dfu
+    }
+
     /// Writes firmware data to the device.
     ///
     /// This function writes the given data to the firmware area starting at the specified offset.
@@ -256,6 +410,92 @@ impl<'d, DFU: NorFlash, STATE: NorFlash> FirmwareUpdater<'d, DFU, STATE> {
         Ok(())
     }
 
+    /// Write the next chunk of an update stream to the DFU partition.
+    ///
+    /// Chunks may be of any size. The decoded image is written from the start of the DFU
+    /// partition, and must be checked with [`Self::finish_stream`] once all chunks are written.
+    /// Patch streams must use [`Self::write_patch_stream`] instead.
+    pub async fn write_stream<const WINDOW: usize>(
+        &mut self,
+        stream: &mut UpdateStream<WINDOW>,
+        data: &[u8],
+    ) -> Result<(), FirmwareUpdaterError> {
+        if stream.is_patch() {
+            return Err(StreamError::SourceRequired.into());
+        }
+        self.write_stream_from(stream, &mut NoSource, data).await
+    }
+
+    /// Write the next chunk of an update stream that is a patch against `source`, usually the
+    /// active partition.
+    ///
+    /// `source` must have a read size of 1.
+    pub async fn write_patch_stream<const WINDOW: usize, SRC: ReadNorFlash>(
+        &mut self,
+        stream: &mut UpdateStream<WINDOW>,
+        source: &mut SRC,
+        data: &[u8],
+    ) -> Result<(), FirmwareUpdaterError> {
+        assert!(SRC::READ_SIZE == 1);
+        self.write_stream_from(stream, source, data).await
+    }
+
+    async fn write_stream_from<const WINDOW: usize, SRC: ReadNorFlash>(
+        &mut self,
+        stream: &mut UpdateStream<WINDOW>,
+        source: &mut SRC,
+        mut data: &[u8],
+    ) -> Result<(), FirmwareUpdaterError> {
+        assert!(STAGING_LEN.is_multiple_of(DFU::WRITE_SIZE));
+        let mut chunk = [0; SOURCE_CHUNK_LEN];
+        loop {
+            if let Some((offset, staged)) = stream.full() {
+                if offset + staged.len() > self.dfu.capacity() {
+                    return Err(ImageError::TooLarge.into());
+                }
+                self.write_firmware(offset, staged).await?;
+                stream.flushed();
+            }
+            match stream.fill(&mut data)? {
+                Fill::Data => {}
+                Fill::Diff { len, source_offset } => {
+                    if (source_offset as usize)
+                        .checked_add(len)
+                        .is_none_or(|end| end > source.capacity())
+                    {
+                        return Err(StreamError::Malformed.into());
+                    }
+                    source.read(source_offset, &mut chunk[..len]).await?;
+                    stream.apply_source(&chunk[..len]);
+                }
+                Fill::NeedInput | Fill::Done => return Ok(()),
+            }
+        }
+    }
+
+    /// Write the rest of an update stream to the DFU partition and check its SHA-256 hash.
+    ///
+    /// Returns the size of the image, which can then be marked as updated.
+    pub async fn finish_stream<const WINDOW: usize>(
+        &mut self,
+        stream: &mut UpdateStream<WINDOW>,
+        expected_sha256: &[u8; 32],
+    ) -> Result<usize, FirmwareUpdaterError> {
+        let len = stream.len();
+        let (offset, staged, hash) = stream.finish(DFU::WRITE_SIZE)?;
+        if offset + staged.len() > self.dfu.capacity() {
+            return Err(ImageError::TooLarge.into());
+        }
+        if !staged.is_empty() {
+            self.write_firmware(offset, staged).await?;
+        }
+        stream.flushed();
+        if hash != *expected_sha256 {
+            return Err(ImageError::HashMismatch.into());
+        }
+        Ok(len)
+    }
+
     /// Prepare for an incoming DFU update by erasing the entire DFU area and
     /// returning its `Partition`.
     ///
@@ -302,7 +542,7 @@ impl<'d, STATE: NorFlash> FirmwareState<'d, STATE> {
     // Make sure we are running a booted firmware to avoid reverting to a bad state.
     async fn verify_booted(&mut self) -> Result<(), FirmwareUpdaterError> {
         let state = self.get_state().await?;
-        if state == State::Boot || state == State::DfuDetach || state == State::Revert {
+        if state == State::Boot || state == State::DfuDetach || state == State::Revert || state == State::Recovery {
             Ok(())
         } else {
             Err(FirmwareUpdaterError::BadState)
@@ -324,20 +564,83 @@ impl<'d, STATE: NorFlash> FirmwareState<'d, STATE> {
         self.set_magic(SWAP_MAGIC).await
     }
 
+    /// Mark the images selected by the bits of `images`, bit `i` for image `i`, to be swapped
+    /// together by a [`crate::MultiBootLoader`] on next boot.
+    pub async fn mark_images_updated(&mut self, images: u32) -> Result<(), FirmwareUpdaterError> {
+        self.write_magic(SWAP_MAGIC, images).await
+    }
+
+    /// Read the security counter stored by the bootloader, or `None` without the
+    /// `security-counter` feature or if the state partition has no room for it.
+    pub async fn security_counter(&mut self) -> Result<Option<u32>, FirmwareUpdaterError> {
+        let mut record = AlignedBuffer([0; MAX_RECORD_SIZE]);
+        Ok(security_counter::read_async(&mut self.state, &mut record.0).await?)
+    }
+
     /// Mark to trigger USB DFU on next boot.
     pub async fn mark_dfu(&mut self) -> Result<(), FirmwareUpdaterError> {
         self.set_magic(DFU_DETACH_MAGIC).await
     }
 
     /// Mark firmware boot successful and stop rollback on reset.
+    ///
+    /// Confirming an update, a reverted image or the recovery image is stored in the boot
+    /// history.
     pub async fn mark_booted(&mut self) -> Result<(), FirmwareUpdaterError> {
-        self.set_magic(BOOT_MAGIC).await
+        let state = self.get_state().await?;
+        self.set_magic(BOOT_MAGIC).await?;
+        if matches!(state, State::Swap | State::Revert | State::Recovery) {
+            self.record(BootEvent::Confirmed).await?;
+        }
+        Ok(())
+    }
+
+    /// Mark the running firmware as unhealthy, for example after a failed self-test, with a
+    /// reason code of the application stored in the boot history.
+    ///
+    /// The bootloader then gives up on an update on trial, or on the image it was reverted
+    /// to, on the next boot instead of booting it again until its boot attempts are exhausted.
+    /// This has no effect once the firmware is marked booted.
+    ///
+    /// Returns [`FirmwareUpdaterError::BadState`] without the `security-counter` feature or if
+    /// the state partition has no room for the boot history.
+    pub async fn mark_unhealthy(&mut self, reason: u8) -> Result<(), FirmwareUpdaterError> {
+        if self.record(BootEvent::Unhealthy(reason)).await? {
+            Ok(())
+        } else {
+            Err(FirmwareUpdaterError::BadState)
+        }
+    }
+
+    /// Read the newest entries of the boot history into `out`, newest first, and return how
+    /// many were read. The history is empty without the `security-counter` feature or if the
+    /// state partition has no room for it, and starts over whenever its page fills up.
+    pub async fn boot_history(&mut self, out: &mut [BootRecord]) -> Result<usize, FirmwareUpdaterError> {
+        let mut record = AlignedBuffer([0; MAX_RECORD_SIZE]);
+        Ok(security_counter::history_async(&mut self.state, out, &mut record.0).await?)
+    }
+
+    /// Append an event of the application to the boot history. Returns `false` if there is
+    /// no room for it.
+    async fn record(&mut self, event: BootEvent) -> Result<bool, FirmwareUpdaterError> {
+        let record = BootRecord {
+            event,
+            ..Default::default()
+        };
+        let mut buf = AlignedBuffer([0; MAX_RECORD_SIZE]);
+        Ok(security_counter::append_async(&mut self.state, record, &mut buf.0).await?)
     }
 
     async fn set_magic(&mut self, magic: u8) -> Result<(), FirmwareUpdaterError> {
+        self.write_magic(magic, 0).await
+    }
+
+    /// Write `magic` if it is not already set or if `images` is not zero, along with the words
+    /// marking the `images` updated for a [`crate::MultiBootLoader`].
+    async fn write_magic(&mut self, magic: u8, images: u32) -> Result<(), FirmwareUpdaterError> {
         self.state.read(0, &mut self.aligned).await?;
 
-        if self.aligned[..STATE::WRITE_SIZE].iter().any(|&b| b != magic) {
+        if images != 0 || self.aligned[..STATE::WRITE_SIZE].iter().any(|&b| b != magic) {
             // Read progress validity
             if STATE::READ_SIZE <= 2 * STATE::WRITE_SIZE {
                 self.state.read(STATE::WRITE_SIZE as u32, &mut self.aligned).await?;
@@ -358,8 +661,20 @@ impl<'d, STATE: NorFlash> FirmwareState<'d, STATE> {
                     .await?;
             }
 
-            // Clear magic and progress
-            self.state.erase(0, self.state.capacity() as u32).await?;
+            // Clear magic and progress, but not the security counter
+            let state_area = security_counter::state_area(self.state.capacity(), STATE::ERASE_SIZE, STATE::WRITE_SIZE);
+            self.state.erase(0, state_area).await?;
+
+            // Mark images updated
+            self.aligned.fill(!STATE_ERASE_VALUE);
+            for image in (0..32).filter(|&image| images & (1 << image) != 0) {
+                self.state
+                    .write(
+                        (2 + image) * STATE::WRITE_SIZE as u32,
+                        &self.aligned[..STATE::WRITE_SIZE],
+                    )
+                    .await?;
+            }
 
             // Set magic
             self.aligned.fill(magic);
@@ -471,4 +786,111 @@ mod tests {
 
         assert_eq!(Sha1::digest(update).as_slice(), hash);
     }
+
+    #[test]
+    fn can_write_compressed_patch_stream() {
+        extern crate std;
+        use std::vec::Vec;
+
+        use crate::stream::{Entry, compress, encode_patch};
+
+        let flash = Mutex::<NoopRawMutex, _>::new(MemFlash::<131072, 4096, 8>::default());
+        let state = Partition::new(&flash, 0, 4096);
+        let dfu = Partition::new(&flash, 65536, 65536);
+        let mut active = MemFlash::<8192, 4096, 8>::default();
+        let mut aligned = [0; 8];
+
+        let old: Vec<u8> = (0..6000u32).map(|i| (i * 7 / 5) as u8).collect();
+        active.program(0, &old).unwrap();
+
+        // The new image changes a few bytes, inserts a block, and drops the end of the old one.
+        let mut new = old[..3000].to_vec();
+        new[100] ^= 0x55;
+        new.extend_from_slice(&[0xaa; 300]);
+        new.extend_from_slice(&old[3100..5000]);
+        let mut diff = [0; 3000];
+        diff[100] = new[100].wrapping_sub(old[100]);
+        let patch = encode_patch(
+            new.len() as u32,
+            &[
+                Entry {
+                    diff: &diff,
+                    extra: &[0xaa; 300],
+                    seek: 100,
+                },
+                Entry {
+                    diff: &[0; 1900],
+                    extra: &[],
+                    seek: 0,
+                },
+            ],
+        );
+        let compressed = compress(&patch, 8, 4);
+        assert!(compressed.len() < patch.len() / 4);
+
+        let mut updater = FirmwareUpdater::new(FirmwareUpdaterConfig { dfu, state }, &mut aligned);
+        let mut stream = UpdateStream::<256>::compressed_patch(4);
+        assert!(matches!(
+            block_on(updater.write_stream(&mut stream, &compressed)),
+            Err(FirmwareUpdaterError::Stream(StreamError::SourceRequired))
+        ));
+        for chunk in compressed.chunks(37) {
+            block_on(updater.write_patch_stream(&mut stream, &mut active, chunk)).unwrap();
+        }
+        let expected: [u8; 32] = Sha256::digest(&new).into();
+        assert_eq!(
+            block_on(updater.finish_stream(&mut stream, &expected)).unwrap(),
+            new.len()
+        );
+
+        let mut written = std::vec![0; new.len()];
+        block_on(updater.read_dfu(0, &mut written)).unwrap();
+        assert!(written == new);
+    }
+
+    #[test]
+    fn stream_hash_mismatch() {
+        let flash = Mutex::<NoopRawMutex, _>::new(MemFlash::<131072, 4096, 8>::default());
+        let state = Partition::new(&flash, 0, 4096);
+        let dfu = Partition::new(&flash, 65536, 65536);
+        let mut aligned = [0; 8];
+
+        let mut updater = FirmwareUpdater::new(FirmwareUpdaterConfig { dfu, state }, &mut aligned);
+        let mut stream = UpdateStream::<256>::raw();
+        block_on(updater.write_stream(&mut stream, &[0x42; 1000])).unwrap();
+        assert!(matches!(
+            block_on(updater.finish_stream(&mut stream, &[0; 32])),
+            Err(FirmwareUpdaterError::Image(ImageError::HashMismatch))
+        ));
+
+        // A patch that ends early is incomplete.
+        let mut stream = UpdateStream::<256>::patch();
+        let mut source = MemFlash::<4096, 4096, 8>::default();
+        block_on(updater.write_patch_stream(&mut stream, &mut source, b"EBDP\x10\0\0\0\0\x04\0abcd")).unwrap();
+        assert!(matches!(
+            block_on(updater.finish_stream(&mut stream, &[0; 32])),
+            Err(FirmwareUpdaterError::Stream(StreamError::Incomplete))
+        ));
+    }
+
+    #[test]
+    fn patch_seek_out_of_source() {
+        let flash = Mutex::<NoopRawMutex, _>::new(MemFlash::<131072, 4096, 8>::default());
+        let state = Partition::new(&flash, 0, 4096);
+        let dfu = Partition::new(&flash, 65536, 65536);
+        let mut aligned = [0; 8];
+
+        // Four extra bytes then a seek of -16, so the following diff starts before the source.
+        let mut updater = FirmwareUpdater::new(FirmwareUpdaterConfig { dfu, state }, &mut aligned);
+        let mut stream = UpdateStream::<256>::patch();
+        let mut source = MemFlash::<4096, 4096, 8>::default();
+        assert!(matches!(
+            block_on(updater.write_patch_stream(
+                &mut stream,
+                &mut source,
+                b"EBDP\x08\0\0\0\0\x04\x1fabcd\x04\0\0\0\0\0\0"
+            )),
+            Err(FirmwareUpdaterError::Stream(StreamError::Malformed))
+        ));
+    }
 }
@@ -5,6 +5,9 @@ pub use asynch::{FirmwareState, FirmwareUpdater};
 pub use blocking::{BlockingFirmwareState, BlockingFirmwareUpdater};
 use embedded_storage::nor_flash::{NorFlashError, NorFlashErrorKind};
 
+use crate::image::ImageError;
+use crate::stream::StreamError;
+
 /// Firmware updater flash configuration holding the two flashes used by the updater
 ///
 /// If only a single flash is actually used, then that flash should be partitioned into two partitions before use.
@@ -26,6 +29,12 @@ pub enum FirmwareUpdaterError {
     Signature(signature::Error),
     /// Bad state.
     BadState,
+    /// The image in the DFU partition is malformed or its hash does not match.
+    Image(ImageError),
+    /// The image in the DFU partition has a security counter below the one of the installed image.
+    Rollback,
+    /// The update stream could not be decoded.
+    Stream(StreamError),
 }
 
 #[cfg(feature = "defmt")]
@@ -35,6 +44,9 @@ impl defmt::Format for FirmwareUpdaterError {
             FirmwareUpdaterError::Flash(_) => defmt::write!(fmt, "FirmwareUpdaterError::Flash(_)"),
             FirmwareUpdaterError::Signature(_) => defmt::write!(fmt, "FirmwareUpdaterError::Signature(_)"),
             FirmwareUpdaterError::BadState => defmt::write!(fmt, "FirmwareUpdaterError::BadState"),
+            FirmwareUpdaterError::Image(e) => defmt::write!(fmt, "FirmwareUpdaterError::Image({})", e),
+            FirmwareUpdaterError::Rollback => defmt::write!(fmt, "FirmwareUpdaterError::Rollback"),
+            FirmwareUpdaterError::Stream(e) => defmt::write!(fmt, "FirmwareUpdaterError::Stream({})", e),
         }
     }
 }
@@ -47,3 +59,15 @@ where
         FirmwareUpdaterError::Flash(error.kind())
     }
 }
+
+impl From<ImageError> for FirmwareUpdaterError {
+    fn from(error: ImageError) -> Self {
+        FirmwareUpdaterError::Image(error)
+    }
+}
+
+impl From<StreamError> for FirmwareUpdaterError {
+    fn from(error: StreamError) -> Self {
+        FirmwareUpdaterError::Stream(error)
+    }
+}
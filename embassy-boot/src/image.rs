@@ -0,0 +1,797 @@
+//! Self-describing firmware images.
+//!
+//! An image starts with an [`ImageHeader`], padded to `header_size` bytes, followed by
+//! `image_size` bytes of firmware and a TLV trailer:
+//!
+//! | Offset                      | Size          | Contents                                   |
+//! |-----------------------------|---------------|--------------------------------------------|
+//! | 0                           | `header_size` | [`ImageHeader`], padded with zeroes        |
+//! | `header_size`               | `image_size`  | Firmware, linked to run at `header_size`   |
+//! | `header_size + image_size`  | 4             | TLV info: [`TLV_INFO_MAGIC`], total length |
+//! | ...                         | ...           | TLV entries                                |
+//!
+//! Each TLV entry is a little-endian `u16` type and `u16` length followed by the value.
+//! The [`TLV_SHA256`] entry holds the SHA-256 hash of the header, its padding and the
+//! firmware, and signature entries sign that hash. All multi-byte fields are little-endian.
+//!
+//! Entries that must be covered by the hash, like [`TLV_DEPENDENCY`], go in a protected
+//! trailer in front of the TLV info, starting with [`TLV_PROT_INFO_MAGIC`] and its total
+//! length, `protected_tlv_size` in the header. The hash then also covers the protected
+//! trailer.
+//!
+//! Since the header and its padding come first, the application's vector table is at
+//! `header_size` bytes into the ACTIVE partition, and the bootloader has to jump there.
+//!
+//! Images are produced with [`ImageBuilder`], which works on the host as well as on target.
+//!
+//! ## MCUboot images
+//!
+//! Images signed with MCUboot's `imgtool` are accepted unchanged. They start with a header in
+//! the [`ImageFormat::McuBoot`] layout and use the same TLV trailers, image hash and
+//! dependency entries. Their security counter is not in the header but in a [`TLV_SEC_CNT`]
+//! entry of the protected trailer, and is zero without one. Ed25519 and ECDSA P-256
+//! signatures made by `imgtool` are checked by the [verifiers](crate::verifier) of this crate.
+//! MCUboot encrypted images are not supported.
+
+use sha2::{Digest, Sha256};
+
+/// Magic number at the start of an [`ImageHeader`].
+pub const IMAGE_MAGIC: u32 = 0x454D_4249;
+/// Magic number at the start of an [`ImageHeader`] in the [`ImageFormat::McuBoot`] layout.
+pub const MCUBOOT_MAGIC: u32 = 0x96F3_B83D;
+/// Magic number at the start of the TLV trailer.
+pub const TLV_INFO_MAGIC: u16 = 0x6907;
+/// Magic number at the start of the protected TLV trailer.
+pub const TLV_PROT_INFO_MAGIC: u16 = 0x6908;
+
+/// Length of the encoded [`ImageHeader`].
+pub const HEADER_LEN: usize = 32;
+/// Length of the TLV info and of each TLV entry header.
+pub const TLV_HEADER_LEN: usize = 4;
+/// Length of the image hash.
+pub const HASH_LEN: usize = 32;
+/// Length of the value of a [`TLV_DEPENDENCY`] entry.
+pub const DEPENDENCY_LEN: usize = 12;
+/// Largest number of dependencies of an image built with [`ImageBuilder`].
+pub const MAX_DEPENDENCIES: usize = 4;
+
+/// Image flag set on images whose firmware is encrypted.
+pub const FLAG_ENCRYPTED: u16 = 0x0004;
+/// MCUboot image flags of AES-128 and AES-256 encrypted images.
+const MCUBOOT_FLAGS_ENCRYPTED: u32 = 0x0000_000C;
+
+/// TLV type of the SHA-256 hash of the header and firmware.
+pub const TLV_SHA256: u16 = 0x10;
+/// TLV type of an RSA-2048 PSS signature of the image hash.
+pub const TLV_RSA2048_PSS: u16 = 0x20;
+/// TLV type of an ECDSA P-256 signature of the image hash.
+pub const TLV_ECDSA_P256: u16 = 0x22;
+/// TLV type of an RSA-3072 PSS signature of the image hash.
+pub const TLV_RSA3072_PSS: u16 = 0x23;
+/// TLV type of an ed25519 signature of the image hash.
+pub const TLV_ED25519: u16 = 0x24;
+/// TLV type of an RSA-2048 PKCS#1 v1.5 signature of the image hash.
+pub const TLV_RSA2048_PKCS1V15: u16 = 0x60;
+/// TLV type of an RSA-3072 PKCS#1 v1.5 signature of the image hash.
+pub const TLV_RSA3072_PKCS1V15: u16 = 0x61;
+/// Protected TLV type of an [`ImageDependency`].
+pub const TLV_DEPENDENCY: u16 = 0x40;
+/// Protected TLV type of the `u32` security counter of MCUboot images.
+pub const TLV_SEC_CNT: u16 = 0x50;
+
+/// Errors from parsing or building an image.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ImageError {
+    /// The image does not start with [`IMAGE_MAGIC`].
+    BadMagic,
+    /// The header is malformed.
+    InvalidHeader,
+    /// The TLV trailer is malformed.
+    InvalidTlv,
+    /// A required TLV entry is missing.
+    MissingTlv(u16),
+    /// The image hash does not match the [`TLV_SHA256`] entry.
+    HashMismatch,
+    /// The image does not fit in the partition or buffer.
+    TooLarge,
+    /// The image key of an encrypted image could not be unwrapped.
+    Decryption,
+    /// The image uses a feature not supported by this crate, such as MCUboot encryption.
+    Unsupported,
+}
+
+/// Semantic version of an image.
+///
+/// Versions order by `major`, `minor`, `patch` and then `build`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ImageVersion {
+    /// Major version.
+    pub major: u8,
+    /// Minor version.
+    pub minor: u8,
+    /// Patch version.
+    pub patch: u16,
+    /// Build number.
+    pub build: u32,
+}
+
+impl ImageVersion {
+    /// Create a version with a build number of zero.
+    pub const fn new(major: u8, minor: u8, patch: u16) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+            build: 0,
+        }
+    }
+}
+
+impl core::fmt::Display for ImageVersion {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}.{}.{}+{}", self.major, self.minor, self.patch, self.build)
+    }
+}
+
+/// Layout of an [`ImageHeader`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ImageFormat {
+    /// The layout of this crate, starting with [`IMAGE_MAGIC`]:
+    ///
+    /// | Offset | Size | Field                |
+    /// |--------|------|----------------------|
+    /// | 0      | 4    | [`IMAGE_MAGIC`]      |
+    /// | 4      | 2    | `header_size`        |
+    /// | 6      | 2    | `flags`              |
+    /// | 8      | 4    | `image_size`         |
+    /// | 12     | 8    | `version`            |
+    /// | 20     | 4    | `security_counter`   |
+    /// | 24     | 2    | `protected_tlv_size` |
+    /// | 26     | 6    | Reserved, zero       |
+    #[default]
+    Embassy,
+    /// The layout of MCUboot, starting with [`MCUBOOT_MAGIC`]:
+    ///
+    /// | Offset | Size | Field                |
+    /// |--------|------|----------------------|
+    /// | 0      | 4    | [`MCUBOOT_MAGIC`]    |
+    /// | 4      | 4    | Load address, zero   |
+    /// | 8      | 2    | `header_size`        |
+    /// | 10     | 2    | `protected_tlv_size` |
+    /// | 12     | 4    | `image_size`         |
+    /// | 16     | 4    | `flags`              |
+    /// | 20     | 8    | `version`            |
+    /// | 28     | 4    | Reserved, zero       |
+    ///
+    /// The security counter is in a [`TLV_SEC_CNT`] entry of the protected trailer.
+    McuBoot,
+}
+
+/// Header at the start of an image, in one of the [`ImageFormat`] layouts.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ImageHeader {
+    /// Layout of the header.
+    pub format: ImageFormat,
+    /// Size of the header including padding; the firmware starts at this offset.
+    pub header_size: u16,
+    /// Image flags, such as [`FLAG_ENCRYPTED`].
+    pub flags: u16,
+    /// Size of the firmware, excluding header and TLV trailer.
+    pub image_size: u32,
+    /// Version of the firmware.
+    pub version: ImageVersion,
+    /// Security counter. The bootloader refuses to install an image whose counter is
+    /// lower than that of an image it has already confirmed.
+    ///
+    /// For [`ImageFormat::McuBoot`] headers this is the value of the [`TLV_SEC_CNT`] entry.
+    /// [`Self::parse`] sets it to zero, and the bootloader and firmware updater read it from
+    /// the protected trailer.
+    pub security_counter: u32,
+    /// Size of the protected TLV trailer, or zero if there is none.
+    pub protected_tlv_size: u16,
+}
+
+impl ImageHeader {
+    /// Parse a header from the first [`HEADER_LEN`] bytes of an image.
+    pub fn parse(buf: &[u8]) -> Result<Self, ImageError> {
+        let buf = buf.get(..HEADER_LEN).ok_or(ImageError::InvalidHeader)?;
+        let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+
+        let header = match u32_at(0) {
+            IMAGE_MAGIC => Self {
+                format: ImageFormat::Embassy,
+                header_size: u16_at(4),
+                flags: u16_at(6),
+                image_size: u32_at(8),
+                version: ImageVersion {
+                    major: buf[12],
+                    minor: buf[13],
+                    patch: u16_at(14),
+                    build: u32_at(16),
+                },
+                security_counter: u32_at(20),
+                protected_tlv_size: u16_at(24),
+            },
+            MCUBOOT_MAGIC => {
+                let flags = u32_at(16);
+                if flags & MCUBOOT_FLAGS_ENCRYPTED != 0 {
+                    return Err(ImageError::Unsupported);
+                }
+                Self {
+                    format: ImageFormat::McuBoot,
+                    header_size: u16_at(8),
+                    flags: u16::try_from(flags).map_err(|_| ImageError::Unsupported)?,
+                    image_size: u32_at(12),
+                    version: ImageVersion {
+                        major: buf[20],
+                        minor: buf[21],
+                        patch: u16_at(22),
+                        build: u32_at(24),
+                    },
+                    security_counter: 0,
+                    protected_tlv_size: u16_at(10),
+                }
+            }
+            _ => return Err(ImageError::BadMagic),
+        };
+        if (header.header_size as usize) < HEADER_LEN {
+            return Err(ImageError::InvalidHeader);
+        }
+        Ok(header)
+    }
+
+    /// Encode the header into the first [`HEADER_LEN`] bytes of `buf`. The security counter
+    /// of [`ImageFormat::McuBoot`] headers is not encoded.
+    pub fn write_to(&self, buf: &mut [u8]) {
+        let buf = &mut buf[..HEADER_LEN];
+        buf.fill(0);
+        let version = match self.format {
+            ImageFormat::Embassy => {
+                buf[0..4].copy_from_slice(&IMAGE_MAGIC.to_le_bytes());
+                buf[4..6].copy_from_slice(&self.header_size.to_le_bytes());
+                buf[6..8].copy_from_slice(&self.flags.to_le_bytes());
+                buf[8..12].copy_from_slice(&self.image_size.to_le_bytes());
+                buf[20..24].copy_from_slice(&self.security_counter.to_le_bytes());
+                buf[24..26].copy_from_slice(&self.protected_tlv_size.to_le_bytes());
+                &mut buf[12..20]
+            }
+            ImageFormat::McuBoot => {
+                buf[0..4].copy_from_slice(&MCUBOOT_MAGIC.to_le_bytes());
+                buf[8..10].copy_from_slice(&self.header_size.to_le_bytes());
+                buf[10..12].copy_from_slice(&self.protected_tlv_size.to_le_bytes());
+                buf[12..16].copy_from_slice(&self.image_size.to_le_bytes());
+                buf[16..18].copy_from_slice(&self.flags.to_le_bytes());
+                &mut buf[20..28]
+            }
+        };
+        version[0] = self.version.major;
+        version[1] = self.version.minor;
+        version[2..4].copy_from_slice(&self.version.patch.to_le_bytes());
+        version[4..8].copy_from_slice(&self.version.build.to_le_bytes());
+    }
+
+    /// Offset of the protected TLV trailer, right after the firmware.
+    pub fn protected_tlv_offset(&self) -> u32 {
+        self.header_size as u32 + self.image_size
+    }
+
+    /// Offset of the TLV trailer, which is also the length covered by the image hash.
+    pub fn tlv_offset(&self) -> u32 {
+        self.protected_tlv_offset() + self.protected_tlv_size as u32
+    }
+}
+
+/// Dependency of an image on the version of another image updated with it, see
+/// [`crate::MultiBootLoader`].
+///
+/// Encoded in a [`TLV_DEPENDENCY`] entry as the image index, three reserved bytes and the
+/// minimum version, laid out as in [`ImageHeader`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ImageDependency {
+    /// Index of the image depended on.
+    pub image: u8,
+    /// Lowest version of that image this image works with.
+    pub min_version: ImageVersion,
+}
+
+impl ImageDependency {
+    /// Parse the value of a [`TLV_DEPENDENCY`] entry.
+    pub fn parse(value: &[u8]) -> Result<Self, ImageError> {
+        let value: &[u8; DEPENDENCY_LEN] = value.try_into().map_err(|_| ImageError::InvalidTlv)?;
+        Ok(Self {
+            image: value[0],
+            min_version: ImageVersion {
+                major: value[4],
+                minor: value[5],
+                patch: u16::from_le_bytes([value[6], value[7]]),
+                build: u32::from_le_bytes([value[8], value[9], value[10], value[11]]),
+            },
+        })
+    }
+
+    /// Encode the value of a [`TLV_DEPENDENCY`] entry.
+    pub fn to_bytes(&self) -> [u8; DEPENDENCY_LEN] {
+        let mut value = [0; DEPENDENCY_LEN];
+        value[0] = self.image;
+        value[4] = self.min_version.major;
+        value[5] = self.min_version.minor;
+        value[6..8].copy_from_slice(&self.min_version.patch.to_le_bytes());
+        value[8..12].copy_from_slice(&self.min_version.build.to_le_bytes());
+        value
+    }
+}
+
+/// Parse a TLV info or TLV entry header into its two fields.
+pub(crate) fn parse_tlv_header(buf: &[u8; TLV_HEADER_LEN]) -> (u16, u16) {
+    (
+        u16::from_le_bytes([buf[0], buf[1]]),
+        u16::from_le_bytes([buf[2], buf[3]]),
+    )
+}
+
+/// Iterator over the entries of a TLV trailer held in memory.
+#[derive(Clone)]
+pub struct TlvIter<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> TlvIter<'a> {
+    /// Parse the TLV info at the start of `buf`.
+    pub fn new(buf: &'a [u8]) -> Result<Self, ImageError> {
+        Self::with_magic(buf, TLV_INFO_MAGIC)
+    }
+
+    /// Parse the protected TLV info at the start of `buf`.
+    pub fn protected(buf: &'a [u8]) -> Result<Self, ImageError> {
+        Self::with_magic(buf, TLV_PROT_INFO_MAGIC)
+    }
+
+    fn with_magic(buf: &'a [u8], expected: u16) -> Result<Self, ImageError> {
+        let info = buf.first_chunk().ok_or(ImageError::InvalidTlv)?;
+        let (magic, len) = parse_tlv_header(info);
+        if magic != expected || (len as usize) < TLV_HEADER_LEN {
+            return Err(ImageError::InvalidTlv);
+        }
+        let buf = buf.get(TLV_HEADER_LEN..len as usize).ok_or(ImageError::InvalidTlv)?;
+        Ok(Self { buf })
+    }
+
+    /// Find the value of the first entry of type `ty`.
+    pub fn find(self, ty: u16) -> Result<&'a [u8], ImageError> {
+        for entry in self {
+            let (entry_ty, value) = entry?;
+            if entry_ty == ty {
+                return Ok(value);
+            }
+        }
+        Err(ImageError::MissingTlv(ty))
+    }
+}
+
+impl<'a> Iterator for TlvIter<'a> {
+    type Item = Result<(u16, &'a [u8]), ImageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        let entry = self
+            .buf
+            .first_chunk()
+            .map(parse_tlv_header)
+            .and_then(|(ty, len)| Some((ty, self.buf.get(TLV_HEADER_LEN..TLV_HEADER_LEN + len as usize)?)));
+        match entry {
+            Some((ty, value)) => {
+                self.buf = &self.buf[TLV_HEADER_LEN + value.len()..];
+                Some(Ok((ty, value)))
+            }
+            None => {
+                self.buf = &[];
+                Some(Err(ImageError::InvalidTlv))
+            }
+        }
+    }
+}
+
+/// Appends entries to a TLV trailer.
+pub struct TlvWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    magic: u16,
+}
+
+impl<'a> TlvWriter<'a> {
+    /// Start a TLV trailer at the beginning of `buf`.
+    pub fn new(buf: &'a mut [u8]) -> Result<Self, ImageError> {
+        Self::with_magic(buf, TLV_INFO_MAGIC)
+    }
+
+    /// Start a protected TLV trailer at the beginning of `buf`.
+    pub fn protected(buf: &'a mut [u8]) -> Result<Self, ImageError> {
+        Self::with_magic(buf, TLV_PROT_INFO_MAGIC)
+    }
+
+    fn with_magic(buf: &'a mut [u8], magic: u16) -> Result<Self, ImageError> {
+        if buf.len() < TLV_HEADER_LEN {
+            return Err(ImageError::TooLarge);
+        }
+        Ok(Self {
+            buf,
+            len: TLV_HEADER_LEN,
+            magic,
+        })
+    }
+
+    /// Append an entry.
+    pub fn push(&mut self, ty: u16, value: &[u8]) -> Result<(), ImageError> {
+        let end = self.len + TLV_HEADER_LEN + value.len();
+        if end > self.buf.len() || end > u16::MAX as usize {
+            return Err(ImageError::TooLarge);
+        }
+        self.buf[self.len..self.len + 2].copy_from_slice(&ty.to_le_bytes());
+        self.buf[self.len + 2..self.len + 4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        self.buf[self.len + TLV_HEADER_LEN..end].copy_from_slice(value);
+        self.len = end;
+        Ok(())
+    }
+
+    /// Write the TLV info and return the total length of the trailer.
+    pub fn finish(self) -> usize {
+        self.buf[0..2].copy_from_slice(&self.magic.to_le_bytes());
+        self.buf[2..4].copy_from_slice(&(self.len as u16).to_le_bytes());
+        self.len
+    }
+}
+
+/// Builds images from raw firmware.
+///
+/// ```
+/// use embassy_boot::image::{ImageBuilder, ImageVersion};
+///
+/// let firmware = [0xAA; 100];
+/// let mut image = [0; 512];
+/// let len = ImageBuilder::new(ImageVersion::new(1, 2, 3))
+///     .security_counter(4)
+///     .build(&firmware, &mut image, |_hash, _tlv| Ok(()))
+///     .unwrap();
+/// assert_eq!(len, 256 + 100 + 40);
+/// ```
+#[derive(Copy, Clone, Debug)]
+pub struct ImageBuilder {
+    format: ImageFormat,
+    version: ImageVersion,
+    security_counter: u32,
+    header_size: u16,
+    flags: u16,
+    dependencies: [Option<ImageDependency>; MAX_DEPENDENCIES],
+    #[cfg(feature = "encryption")]
+    encryption: Option<(
+        [u8; crate::encryption::KEY_LEN],
+        [u8; crate::encryption::WRAPPED_KEY_LEN],
+    )>,
+}
+
+impl ImageBuilder {
+    /// Create a builder for an image with the given version, a security counter of zero and
+    /// a 256-byte header.
+    pub const fn new(version: ImageVersion) -> Self {
+        Self {
+            format: ImageFormat::Embassy,
+            version,
+            security_counter: 0,
+            header_size: 256,
+            flags: 0,
+            dependencies: [None; MAX_DEPENDENCIES],
+            #[cfg(feature = "encryption")]
+            encryption: None,
+        }
+    }
+
+    /// Set the layout of the header. [`ImageFormat::McuBoot`] images store a non-zero security
+    /// counter in the protected trailer, and cannot be encrypted.
+    pub const fn format(mut self, format: ImageFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Set the security counter.
+    pub const fn security_counter(mut self, security_counter: u32) -> Self {
+        self.security_counter = security_counter;
+        self
+    }
+
+    /// Set the size of the padded header, which is where the application has to be linked to
+    /// in the ACTIVE partition. Must be at least [`HEADER_LEN`], and is typically the vector
+    /// table alignment of the target.
+    pub const fn header_size(mut self, header_size: u16) -> Self {
+        self.header_size = header_size;
+        self
+    }
+
+    /// Set the image flags.
+    pub const fn flags(mut self, flags: u16) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Require image `image` to be at least at `min_version` when installed together with this
+    /// image, see [`crate::MultiBootLoader`]. At most [`MAX_DEPENDENCIES`] can be added.
+    pub const fn dependency(mut self, image: u8, min_version: ImageVersion) -> Self {
+        let mut i = 0;
+        while self.dependencies[i].is_some() {
+            i += 1;
+            core::assert!(i < MAX_DEPENDENCIES, "too many dependencies");
+        }
+        self.dependencies[i] = Some(ImageDependency { image, min_version });
+        self
+    }
+
+    /// Encrypt the firmware with `image_key`, wrapped for the device with public key
+    /// `device_public_key`. `image_key` and `ephemeral_secret` must be random and used for a
+    /// single image. See the [`encryption`](crate::encryption) module.
+    #[cfg(feature = "encryption")]
+    pub fn encrypt(
+        mut self,
+        image_key: [u8; crate::encryption::KEY_LEN],
+        ephemeral_secret: [u8; 32],
+        device_public_key: &[u8; 32],
+    ) -> Self {
+        let wrapped = crate::encryption::wrap_key(&image_key, ephemeral_secret, device_public_key);
+        self.encryption = Some((image_key, wrapped));
+        self
+    }
+
+    /// The header of an image holding `image_size` bytes of firmware.
+    pub fn header(&self, image_size: u32) -> ImageHeader {
+        #[allow(unused_mut)]
+        let mut flags = self.flags;
+        #[cfg(feature = "encryption")]
+        if self.encryption.is_some() {
+            flags |= FLAG_ENCRYPTED;
+        }
+        ImageHeader {
+            format: self.format,
+            header_size: self.header_size,
+            flags,
+            image_size,
+            version: self.version,
+            security_counter: self.security_counter,
+            protected_tlv_size: self.protected_tlv_size(),
+        }
+    }
+
+    /// Whether the security counter is stored in a [`TLV_SEC_CNT`] entry.
+    fn security_counter_tlv(&self) -> bool {
+        self.format == ImageFormat::McuBoot && self.security_counter != 0
+    }
+
+    fn protected_tlv_size(&self) -> u16 {
+        let mut size = self.dependencies.iter().flatten().count() * (TLV_HEADER_LEN + DEPENDENCY_LEN);
+        if self.security_counter_tlv() {
+            size += TLV_HEADER_LEN + 4;
+        }
+        match size {
+            0 => 0,
+            _ => (TLV_HEADER_LEN + size) as u16,
+        }
+    }
+
+    /// Write the image for `firmware` into `out` and return its length.
+    ///
+    /// `sign` is called with the image hash after the [`TLV_SHA256`] entry has been added, and
+    /// appends signature entries to the trailer. Pass `|_, _| Ok(())` for an unsigned image.
+    pub fn build(
+        &self,
+        firmware: &[u8],
+        out: &mut [u8],
+        sign: impl FnOnce(&[u8; HASH_LEN], &mut TlvWriter<'_>) -> Result<(), ImageError>,
+    ) -> Result<usize, ImageError> {
+        let header_size = self.header_size as usize;
+        if header_size < HEADER_LEN {
+            return Err(ImageError::InvalidHeader);
+        }
+        let protected_tlv_offset = header_size + firmware.len();
+        let tlv_offset = protected_tlv_offset + self.protected_tlv_size() as usize;
+        if tlv_offset > out.len() {
+            return Err(ImageError::TooLarge);
+        }
+
+        out[..header_size].fill(0);
+        let header = self.header(firmware.len() as u32);
+        header.write_to(out);
+        out[header_size..protected_tlv_offset].copy_from_slice(firmware);
+
+        #[cfg(feature = "encryption")]
+        if let Some((image_key, wrapped)) = &self.encryption {
+            use crate::boot_loader::Payload;
+            use crate::encryption::{ImageCipher, WRAPPED_KEY_LEN};
+
+            if self.format == ImageFormat::McuBoot {
+                return Err(ImageError::Unsupported);
+            }
+            if header_size < HEADER_LEN + WRAPPED_KEY_LEN {
+                return Err(ImageError::InvalidHeader);
+            }
+            out[HEADER_LEN..HEADER_LEN + WRAPPED_KEY_LEN].copy_from_slice(wrapped);
+            ImageCipher::from_key(image_key, &header).apply(Payload::Update, 0, &mut out[..protected_tlv_offset]);
+        }
+
+        if tlv_offset > protected_tlv_offset {
+            let mut protected = TlvWriter::protected(&mut out[protected_tlv_offset..tlv_offset])?;
+            for dependency in self.dependencies.iter().flatten() {
+                protected.push(TLV_DEPENDENCY, &dependency.to_bytes())?;
+            }
+            if self.security_counter_tlv() {
+                protected.push(TLV_SEC_CNT, &self.security_counter.to_le_bytes())?;
+            }
+            protected.finish();
+        }
+
+        let hash: [u8; HASH_LEN] = Sha256::digest(&out[..tlv_offset]).into();
+        let mut tlv = TlvWriter::new(&mut out[tlv_offset..])?;
+        tlv.push(TLV_SHA256, &hash)?;
+        sign(&hash, &mut tlv)?;
+        Ok(tlv_offset + tlv.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_roundtrip() {
+        let header = ImageBuilder::new(ImageVersion {
+            major: 1,
+            minor: 2,
+            patch: 300,
+            build: 70000,
+        })
+        .security_counter(5)
+        .header_size(0x200)
+        .header(1234);
+
+        let mut buf = [0xFF; HEADER_LEN];
+        header.write_to(&mut buf);
+        assert_eq!(ImageHeader::parse(&buf), Ok(header));
+        assert_eq!(header.tlv_offset(), 0x200 + 1234);
+
+        buf[0] ^= 1;
+        assert_eq!(ImageHeader::parse(&buf), Err(ImageError::BadMagic));
+        assert_eq!(ImageHeader::parse(&buf[..16]), Err(ImageError::InvalidHeader));
+    }
+
+    #[test]
+    fn mcuboot_header() {
+        // Header of an image signed by imgtool with `--header-size 0x200 --version 1.2.300+70000`
+        // and a security counter.
+        let mut buf = [
+            0x3d, 0xb8, 0xf3, 0x96, 0, 0, 0, 0, 0x00, 0x02, 0x0c, 0x00, 0xd2, 0x04, 0, 0, 0, 0, 0, 0, 1, 2, 0x2c, 0x01,
+            0x70, 0x11, 0x01, 0x00, 0, 0, 0, 0,
+        ];
+        let header = ImageHeader::parse(&buf).unwrap();
+        let mut expected = ImageBuilder::new(ImageVersion {
+            major: 1,
+            minor: 2,
+            patch: 300,
+            build: 70000,
+        })
+        .format(ImageFormat::McuBoot)
+        .header_size(0x200)
+        .security_counter(5)
+        .header(1234);
+        // The security counter is in the protected trailer.
+        expected.security_counter = 0;
+        assert_eq!(header, expected);
+        assert_eq!(header.protected_tlv_size, 12);
+        assert_eq!(header.tlv_offset(), 0x200 + 1234 + 12);
+
+        let mut written = [0xFF; HEADER_LEN];
+        header.write_to(&mut written);
+        assert_eq!(written, buf);
+
+        // MCUboot encryption is not supported.
+        buf[16] = 0x04;
+        assert_eq!(ImageHeader::parse(&buf), Err(ImageError::Unsupported));
+    }
+
+    #[test]
+    fn version_order() {
+        let v = |major, minor, patch, build| ImageVersion {
+            major,
+            minor,
+            patch,
+            build,
+        };
+        assert!(v(1, 0, 0, 0) > v(0, 255, 65535, 9));
+        assert!(v(1, 2, 3, 1) > v(1, 2, 3, 0));
+        assert!(v(1, 10, 0, 0) > v(1, 9, 99, 0));
+    }
+
+    #[test]
+    fn build_and_parse() {
+        let firmware = [0x5A; 300];
+        let mut image = [0; 1024];
+        let len = ImageBuilder::new(ImageVersion::new(2, 0, 1))
+            .header_size(64)
+            .build(&firmware, &mut image, |hash, tlv| tlv.push(0x7F, &hash[..4]))
+            .unwrap();
+
+        let header = ImageHeader::parse(&image).unwrap();
+        assert_eq!(header.image_size, 300);
+        assert_eq!(&image[64..364], &firmware);
+
+        let tlv_offset = header.tlv_offset() as usize;
+        let tlvs = TlvIter::new(&image[tlv_offset..len]).unwrap();
+        let hash = tlvs.clone().find(TLV_SHA256).unwrap();
+        assert_eq!(hash, Sha256::digest(&image[..tlv_offset]).as_slice());
+        assert_eq!(tlvs.clone().find(0x7F).unwrap(), &hash[..4]);
+        assert_eq!(tlvs.clone().count(), 2);
+        assert_eq!(tlvs.find(TLV_ED25519), Err(ImageError::MissingTlv(TLV_ED25519)));
+    }
+
+    #[test]
+    fn dependencies() {
+        let firmware = [0x5A; 100];
+        let mut image = [0; 512];
+        let len = ImageBuilder::new(ImageVersion::new(2, 0, 0))
+            .header_size(64)
+            .dependency(1, ImageVersion::new(1, 3, 0))
+            .dependency(2, ImageVersion::new(0, 9, 7))
+            .build(&firmware, &mut image, |_, _| Ok(()))
+            .unwrap();
+
+        let header = ImageHeader::parse(&image).unwrap();
+        assert_eq!(header.protected_tlv_offset(), 164);
+        assert_eq!(header.tlv_offset(), 164 + 4 + 2 * 16);
+
+        let protected = &image[header.protected_tlv_offset() as usize..header.tlv_offset() as usize];
+        let mut dependencies = TlvIter::protected(protected).unwrap().map(|entry| {
+            let (ty, value) = entry.unwrap();
+            assert_eq!(ty, TLV_DEPENDENCY);
+            ImageDependency::parse(value).unwrap()
+        });
+        assert_eq!(dependencies.next().unwrap().min_version, ImageVersion::new(1, 3, 0));
+        assert_eq!(dependencies.next().unwrap().image, 2);
+        assert_eq!(dependencies.next(), None);
+
+        // The hash covers the protected trailer.
+        let tlv_offset = header.tlv_offset() as usize;
+        let hash = TlvIter::new(&image[tlv_offset..len]).unwrap().find(TLV_SHA256).unwrap();
+        assert_eq!(hash, Sha256::digest(&image[..tlv_offset]).as_slice());
+        assert!(TlvIter::new(protected).is_err());
+    }
+
+    #[test]
+    fn malformed_tlv() {
+        assert!(TlvIter::new(&[0x07, 0x69, 2, 0]).is_err());
+        assert!(TlvIter::new(&[0x07, 0x69, 8, 0, 0x10]).is_err());
+
+        // Entry length runs past the trailer.
+        let tlv = [0x07, 0x69, 10, 0, 0x10, 0, 20, 0, 1, 2];
+        let mut iter = TlvIter::new(&tlv).unwrap();
+        assert_eq!(iter.next(), Some(Err(ImageError::InvalidTlv)));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn build_rejects_small_buffers() {
+        let builder = ImageBuilder::new(ImageVersion::new(1, 0, 0)).header_size(32);
+        let mut out = [0; 64];
+        assert_eq!(
+            builder.build(&[0; 40], &mut out, |_, _| Ok(())),
+            Err(ImageError::TooLarge)
+        );
+        let builder = builder.header_size(16);
+        assert_eq!(
+            builder.build(&[0; 4], &mut out, |_, _| Ok(())),
+            Err(ImageError::InvalidHeader)
+        );
+    }
+}
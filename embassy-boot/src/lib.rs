@@ -11,11 +11,20 @@ mod fmt;
 
 mod boot_loader;
 mod digest_adapters;
+#[cfg(feature = "encryption")]
+pub mod encryption;
 mod firmware_updater;
+pub mod image;
+pub mod mcuboot;
 #[cfg(test)]
 mod mem_flash;
+#[cfg(feature = "ota")]
+pub mod ota;
+mod security_counter;
+pub mod stream;
 #[cfg(test)]
 mod test_flash;
+pub mod verifier;
 
 // The expected value of the flash after an erase
 // TODO: Use the value provided by NorFlash when available
@@ -24,7 +33,9 @@ pub(crate) const STATE_ERASE_VALUE: u8 = 0xFF;
 #[cfg(feature = "flash-erase-zero")]
 pub(crate) const STATE_ERASE_VALUE: u8 = 0x00;
 
-pub use boot_loader::{BootError, BootLoader, BootLoaderConfig};
+pub use boot_loader::{
+    BootError, BootLoader, BootLoaderConfig, ImagePartitions, MultiBootLoader, MultiBootLoaderConfig, Slot, Strategy,
+};
 pub use firmware_updater::{
     BlockingFirmwareState, BlockingFirmwareUpdater, FirmwareState, FirmwareUpdater, FirmwareUpdaterConfig,
     FirmwareUpdaterError,
@@ -34,6 +45,7 @@ pub(crate) const REVERT_MAGIC: u8 = 0xC0;
 pub(crate) const BOOT_MAGIC: u8 = 0xD0;
 pub(crate) const SWAP_MAGIC: u8 = 0xF0;
 pub(crate) const DFU_DETACH_MAGIC: u8 = 0xE0;
+pub(crate) const RECOVERY_MAGIC: u8 = 0xB0;
 
 /// The state of the bootloader after running prepare.
 #[derive(PartialEq, Eq, Debug)]
@@ -47,6 +59,9 @@ pub enum State {
     Revert,
     /// Application has received a request to reboot into DFU mode to apply an update.
     DfuDetach,
+    /// Bootloader has replaced the active partition with the recovery image, after the boot
+    /// attempts of both the update and the image it was reverted to were exhausted.
+    Recovery,
 }
 
 impl<T> From<T> for State
@@ -61,12 +76,52 @@ where
             State::Revert
         } else if !magic.iter().any(|&b| b != DFU_DETACH_MAGIC) {
             State::DfuDetach
+        } else if !magic.iter().any(|&b| b != RECOVERY_MAGIC) {
+            State::Recovery
         } else {
             State::Boot
         }
     }
 }
 
+/// Event recorded in the boot history, see [`BootRecord`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BootEvent {
+    /// The bootloader booted the confirmed image.
+    #[default]
+    Boot,
+    /// The bootloader booted an update on trial.
+    Swap,
+    /// The bootloader reverted an update, or booted the image it was reverted to again.
+    Revert,
+    /// The bootloader booted into DFU mode.
+    DfuDetach,
+    /// The bootloader installed, or booted again, the recovery image.
+    Recovery,
+    /// The application marked the image on trial as booted.
+    Confirmed,
+    /// The application marked the running image as unhealthy, with its own reason code.
+    Unhealthy(u8),
+}
+
+/// Entry of the boot history kept next to the security counter in the state partition.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BootRecord {
+    /// What happened.
+    pub event: BootEvent,
+    /// Boot attempt of the image on trial or reverted to, starting at 1. Zero for the
+    /// confirmed image, for events of the application, and for a reverted image whose attempts
+    /// are exhausted with no recovery image to fall back to.
+    pub attempt: u8,
+    /// Reset reason given to the bootloader with [`BootLoader::set_reset_reason`], zero for
+    /// events of the application.
+    pub reset_reason: u8,
+    /// Security counter stored when the record was written.
+    pub security_counter: u32,
+}
+
 /// Buffer aligned to 32 byte boundary, largest known alignment requirement for embassy-boot.
 #[repr(align(32))]
 pub struct AlignedBuffer<const N: usize>(pub [u8; N]);
@@ -89,6 +144,9 @@ mod tests {
 
     extern crate std;
 
+    use core::cell::Cell;
+    use std::rc::Rc;
+
     use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
     use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
     use futures::executor::block_on;
@@ -97,7 +155,7 @@ mod tests {
     use crate::boot_loader::BootLoaderConfig;
     use crate::firmware_updater::FirmwareUpdaterConfig;
     use crate::mem_flash::MemFlash;
-    use crate::test_flash::{AsyncTestFlash, BlockingTestFlash};
+    use crate::test_flash::{AsyncTestFlash, BlockingTestFlash, PowerFailFlash};
 
     /*
     #[test]
@@ -243,6 +301,18 @@ mod tests {
         block_on(flash.active().erase(0, ORIGINAL.len() as u32)).unwrap();
         block_on(flash.active().write(0, &ORIGINAL)).unwrap();
 
+        // The original image boots first, which initializes the security counter.
+        let flash = flash.into_blocking();
+        let mut page = [0; 4096];
+        BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        })
+        .prepare_boot(&mut page)
+        .unwrap();
+        let flash = flash.into_async();
+
         let mut updater = FirmwareUpdater::new(
             FirmwareUpdaterConfig {
                 dfu: flash.dfu(),
@@ -260,7 +330,6 @@ mod tests {
             state: flash.state(),
         });
 
-        let mut page = [0; 4096];
         assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
 
         let mut read_buf = [0; FIRMWARE_SIZE];
@@ -288,6 +357,18 @@ mod tests {
         block_on(flash.active().erase(0, ORIGINAL.len() as u32)).unwrap();
         block_on(flash.active().write(0, &ORIGINAL)).unwrap();
 
+        // The original image boots first, which initializes the security counter.
+        let flash = flash.into_blocking();
+        let mut page = [0; 4096];
+        BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        })
+        .prepare_boot(&mut page)
+        .unwrap();
+        let flash = flash.into_async();
+
         let mut updater = FirmwareUpdater::new(
             FirmwareUpdaterConfig {
                 dfu: flash.dfu(),
@@ -304,7 +385,6 @@ mod tests {
             dfu: flash.dfu(),
             state: flash.state(),
         });
-        let mut page = [0; 4096];
         assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
 
         let mut read_buf = [0; FIRMWARE_SIZE];
@@ -315,8 +395,699 @@ mod tests {
         assert_eq!(ORIGINAL, read_buf);
     }
 
+    #[cfg(not(feature = "_verify"))]
+    fn build_image(
+        format: image::ImageFormat,
+        version: image::ImageVersion,
+        security_counter: u32,
+        fill: u8,
+        out: &mut [u8],
+    ) -> usize {
+        image::ImageBuilder::new(version)
+            .format(format)
+            .security_counter(security_counter)
+            .build(&[fill; 1024], out, |_, _| Ok(()))
+            .unwrap()
+    }
+
+    #[test]
+    #[cfg(all(feature = "security-counter", not(feature = "_verify")))]
+    fn test_security_counter() {
+        check_security_counter(image::ImageFormat::Embassy);
+    }
+
+    #[test]
+    #[cfg(all(feature = "security-counter", not(feature = "_verify")))]
+    fn test_mcuboot_security_counter() {
+        check_security_counter(image::ImageFormat::McuBoot);
+    }
+
+    #[cfg(all(feature = "security-counter", not(feature = "_verify")))]
+    fn check_security_counter(format: image::ImageFormat) {
+        use crate::image::ImageVersion;
+
+        let flash = AsyncTestFlash::new(BootLoaderConfig {
+            active: MemFlash::<16384, 4096, 4>::default(),
+            dfu: MemFlash::<20480, 4096, 4>::default(),
+            state: MemFlash::<16384, 4096, 4>::default(),
+        });
+        let mut aligned = [0; 4];
+        let mut image = [0; 4096];
+        let mut page = [0; 4096];
+
+        // Install an image with security counter 2.
+        build_image(format, ImageVersion::new(1, 1, 0), 2, 0xAA, &mut image);
+        let mut updater = FirmwareUpdater::new(
+            FirmwareUpdaterConfig {
+                dfu: flash.dfu(),
+                state: flash.state(),
+            },
+            &mut aligned,
+        );
+        block_on(updater.write_firmware(0, &image)).unwrap();
+        let header = block_on(updater.verify_image()).unwrap();
+        assert_eq!(header.format, format);
+        assert_eq!(header.version, ImageVersion::new(1, 1, 0));
+        assert_eq!(header.security_counter, 2);
+        block_on(updater.mark_updated()).unwrap();
+
+        let flash = flash.into_blocking();
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        });
+        assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
+        // Not committed before the image is confirmed.
+        assert_eq!(Some(0), bootloader.security_counter().unwrap());
+
+        let flash = flash.into_async();
+        let mut updater = FirmwareUpdater::new(
+            FirmwareUpdaterConfig {
+                dfu: flash.dfu(),
+                state: flash.state(),
+            },
+            &mut aligned,
+        );
+        block_on(updater.mark_booted()).unwrap();
+
+        let flash = flash.into_blocking();
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        });
+        assert_eq!(State::Boot, bootloader.prepare_boot(&mut page).unwrap());
+        assert_eq!(Some(2), bootloader.security_counter().unwrap());
+
+        // A downgrade is refused by the updater, and by the bootloader if marked anyway.
+        build_image(format, ImageVersion::new(1, 0, 0), 1, 0x55, &mut image);
+        let flash = flash.into_async();
+        let mut updater = FirmwareUpdater::new(
+            FirmwareUpdaterConfig {
+                dfu: flash.dfu(),
+                state: flash.state(),
+            },
+            &mut aligned,
+        );
+        block_on(updater.write_firmware(0, &image)).unwrap();
+        assert!(matches!(
+            block_on(updater.verify_image()),
+            Err(FirmwareUpdaterError::Rollback)
+        ));
+        block_on(updater.mark_updated()).unwrap();
+
+        let flash = flash.into_blocking();
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        });
+        assert_eq!(State::Boot, bootloader.prepare_boot(&mut page).unwrap());
+        assert_eq!(State::Boot, bootloader.read_state(&mut page).unwrap());
+        let mut read_buf = [0; 1024];
+        flash.active().read(256, &mut read_buf).unwrap();
+        assert_eq!([0xAA; 1024], read_buf);
+        assert_eq!(Some(2), bootloader.security_counter().unwrap());
+
+        // A corrupted image fails the hash check.
+        build_image(format, ImageVersion::new(1, 2, 0), 2, 0x55, &mut image);
+        image[300] ^= 1;
+        let flash = flash.into_async();
+        let mut updater = FirmwareUpdater::new(
+            FirmwareUpdaterConfig {
+                dfu: flash.dfu(),
+                state: flash.state(),
+            },
+            &mut aligned,
+        );
+        block_on(updater.write_firmware(0, &image)).unwrap();
+        assert!(matches!(
+            block_on(updater.verify_image()),
+            Err(FirmwareUpdaterError::Image(image::ImageError::HashMismatch))
+        ));
+    }
+
+    #[test]
+    #[cfg(all(feature = "encryption", not(feature = "_verify")))]
+    fn test_encrypted_swap_and_revert() {
+        use crate::encryption::public_key;
+        use crate::image::{ImageBuilder, ImageVersion};
+
+        const DEVICE_SECRET: [u8; 32] = [3; 32];
+        const ORIGINAL: [u8; 16384] = [0x55; 16384];
+        let firmware: [u8; 9000] = core::array::from_fn(|i| i as u8);
+
+        let mut image = [0; 16384];
+        ImageBuilder::new(ImageVersion::new(1, 0, 0))
+            .encrypt([0x42; 16], [9; 32], &public_key(&DEVICE_SECRET))
+            .build(&firmware, &mut image, |_, _| Ok(()))
+            .unwrap();
+        // The firmware is not readable in the DFU partition.
+        assert!(image[256..256 + 9000] != firmware[..]);
+
+        let flash = AsyncTestFlash::new(BootLoaderConfig {
+            active: MemFlash::<16384, 4096, 4>::default(),
+            dfu: MemFlash::<20480, 4096, 4>::default(),
+            state: MemFlash::<4096, 4096, 4>::default(),
+        });
+        block_on(flash.active().write(0, &ORIGINAL)).unwrap();
+
+        let mut aligned = [0; 4];
+        let mut updater = FirmwareUpdater::new(
+            FirmwareUpdaterConfig {
+                dfu: flash.dfu(),
+                state: flash.state(),
+            },
+            &mut aligned,
+        );
+        block_on(updater.write_firmware(0, &image)).unwrap();
+        block_on(updater.verify_image()).unwrap();
+        block_on(updater.mark_updated()).unwrap();
+
+        let flash = flash.into_blocking();
+        let mut page = [0; 1024];
+
+        // Without the device key the update is rejected.
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        });
+        assert_eq!(State::Boot, bootloader.prepare_boot(&mut page).unwrap());
+        let mut read_buf = [0; 16384];
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(ORIGINAL, read_buf);
+
+        let flash = flash.into_async();
+        let mut updater = FirmwareUpdater::new(
+            FirmwareUpdaterConfig {
+                dfu: flash.dfu(),
+                state: flash.state(),
+            },
+            &mut aligned,
+        );
+        block_on(updater.mark_updated()).unwrap();
+
+        let flash = flash.into_blocking();
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        });
+        bootloader.set_decryption_key(DEVICE_SECRET);
+        assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
+
+        // The update runs decrypted, the previous image is kept encrypted.
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(image[..256], read_buf[..256]);
+        assert_eq!(firmware[..], read_buf[256..256 + 9000]);
+        flash.dfu().read(4096, &mut read_buf).unwrap();
+        assert!(read_buf.iter().filter(|&&b| b == 0x55).count() < 1024);
+
+        // Not marked booted: revert, and the update is back in DFU as it was written.
+        assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(ORIGINAL, read_buf);
+        flash.dfu().read(0, &mut read_buf).unwrap();
+        assert_eq!(image, read_buf);
+    }
+
+    /// Flashes with 512 byte pages, sharing a budget of flash operations.
+    type PowerFailTestFlash = BlockingTestFlash<
+        PowerFailFlash<MemFlash<2048, 512, 4>>,
+        PowerFailFlash<MemFlash<2560, 512, 4>>,
+        PowerFailFlash<MemFlash<3072, 512, 4>>,
+    >;
+
+    fn power_fail_flash(budget: &Rc<Cell<usize>>, active: &[u8], dfu: &[u8]) -> PowerFailTestFlash {
+        budget.set(usize::MAX);
+        let flash = BlockingTestFlash::new(BootLoaderConfig {
+            active: PowerFailFlash::new(MemFlash::default(), budget),
+            dfu: PowerFailFlash::new(MemFlash::default(), budget),
+            state: PowerFailFlash::new(MemFlash::default(), budget),
+        });
+        flash.active().write(0, active).unwrap();
+        flash.dfu().write(0, dfu).unwrap();
+        flash.state().write(0, &[SWAP_MAGIC; 4]).unwrap();
+        flash
+    }
+
+    /// Cut the power after each flash operation of the `prepare_boot` following `runs`
+    /// uninterrupted ones, and check that the next `prepare_boot` leaves `expected` in the
+    /// active partition.
+    fn check_power_fail(strategy: Strategy, runs: usize, expected: fn(usize) -> u8) {
+        let budget = Rc::new(Cell::new(usize::MAX));
+        let original: [u8; 2048] = core::array::from_fn(original_byte);
+        let update: [u8; 2048] = core::array::from_fn(update_byte);
+        let expected: [u8; 2048] = core::array::from_fn(expected);
+
+        let run = |cut: usize| {
+            let flash = power_fail_flash(&budget, &original, &update);
+            let mut bootloader = BootLoader::new(BootLoaderConfig {
+                active: flash.active(),
+                dfu: flash.dfu(),
+                state: flash.state(),
+            });
+            bootloader.set_strategy(strategy);
+            let mut page = [0; 512];
+            for _ in 0..runs {
+                bootloader.prepare_boot(&mut page).unwrap();
+            }
+
+            budget.set(cut);
+            let result = bootloader.prepare_boot(&mut page);
+            let used = cut - budget.get();
+            if cut != usize::MAX {
+                assert!(result.is_err());
+                budget.set(usize::MAX);
+                bootloader.prepare_boot(&mut page).unwrap();
+            }
+
+            let mut active = [0; 2048];
+            flash.active().read(0, &mut active).unwrap();
+            assert!(active == expected, "power cut after {} operations", cut);
+            used
+        };
+
+        let operations = run(usize::MAX);
+        assert!(operations > 0);
+        for cut in 0..operations {
+            run(cut);
+        }
+    }
+
+    fn original_byte(i: usize) -> u8 {
+        (i / 512 * 0x10 + i % 13) as u8
+    }
+
+    fn update_byte(i: usize) -> u8 {
+        (0x80 + i / 512 * 0x10 + i % 11) as u8
+    }
+
+    #[test]
+    fn test_swap_power_fail() {
+        check_power_fail(Strategy::Swap, 0, update_byte);
+        check_power_fail(Strategy::Swap, 1, original_byte);
+    }
+
+    #[test]
+    fn test_overwrite_power_fail() {
+        check_power_fail(Strategy::Overwrite, 0, update_byte);
+
+        // There is nothing to revert to.
+        let budget = Rc::new(Cell::new(usize::MAX));
+        let flash = power_fail_flash(&budget, &[0x55; 2048], &[0xAA; 2048]);
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        });
+        bootloader.set_strategy(Strategy::Overwrite);
+        let mut page = [0; 512];
+        assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
+        assert_eq!(State::Boot, bootloader.prepare_boot(&mut page).unwrap());
+        let mut active = [0; 2048];
+        flash.active().read(0, &mut active).unwrap();
+        assert_eq!([0xAA; 2048], active);
+    }
+
+    #[test]
+    fn test_swap_scratch_power_fail() {
+        check_power_fail(Strategy::SwapScratch, 0, update_byte);
+        check_power_fail(Strategy::SwapScratch, 1, original_byte);
+    }
+
+    #[test]
+    #[cfg(all(feature = "security-counter", not(feature = "_verify")))]
+    fn test_boot_attempts() {
+        let budget = Rc::new(Cell::new(usize::MAX));
+        let mut page = [0; 512];
+        let mut aligned = [0; 4];
+        let mut history = [BootRecord::default(); 8];
+
+        // The update is booted three times before it is reverted.
+        let flash = power_fail_flash(&budget, &[0x55; 2048], &[0xAA; 2048]);
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        });
+        bootloader.set_boot_attempts(3);
+        bootloader.set_reset_reason(4);
+        for _ in 0..3 {
+            assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
+        }
+        let mut active = [0; 2048];
+        flash.active().read(0, &mut active).unwrap();
+        assert_eq!([0xAA; 2048], active);
+        assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
+        flash.active().read(0, &mut active).unwrap();
+        assert_eq!([0x55; 2048], active);
+        assert_eq!(State::Revert, bootloader.prepare_boot(&mut page).unwrap());
+
+        assert_eq!(5, bootloader.boot_history(&mut history).unwrap());
+        let events = history.map(|record| (record.event, record.attempt));
+        assert_eq!(
+            [
+                (BootEvent::Revert, 2),
+                (BootEvent::Revert, 1),
+                (BootEvent::Swap, 3),
+                (BootEvent::Swap, 2),
+                (BootEvent::Swap, 1),
+            ],
+            events[..5]
+        );
+        assert!(history[..5].iter().all(|record| record.reset_reason == 4));
+
+        // An update marked unhealthy is reverted right away.
+        let flash = power_fail_flash(&budget, &[0x55; 2048], &[0xAA; 2048]);
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        });
+        bootloader.set_boot_attempts(3);
+        assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
+        let mut state = BlockingFirmwareState::new(flash.state(), &mut aligned);
+        state.mark_unhealthy(42).unwrap();
+        assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
+        flash.active().read(0, &mut active).unwrap();
+        assert_eq!([0x55; 2048], active);
+
+        // Confirming the image it was reverted to is recorded.
+        let mut state = BlockingFirmwareState::new(flash.state(), &mut aligned);
+        assert_eq!(State::Revert, state.get_state().unwrap());
+        state.mark_booted().unwrap();
+        assert_eq!(4, state.boot_history(&mut history).unwrap());
+        assert_eq!(BootEvent::Confirmed, history[0].event);
+        assert_eq!(BootEvent::Revert, history[1].event);
+        assert_eq!(BootEvent::Unhealthy(42), history[2].event);
+        assert_eq!(BootEvent::Swap, history[3].event);
+        assert_eq!(State::Boot, bootloader.prepare_boot(&mut page).unwrap());
+    }
+
+    #[test]
+    #[cfg(feature = "security-counter")]
+    fn test_recovery_power_fail() {
+        let budget = Rc::new(Cell::new(usize::MAX));
+        let original: [u8; 2048] = core::array::from_fn(original_byte);
+        let update: [u8; 2048] = core::array::from_fn(update_byte);
+        let mut recovery = MemFlash::<1024, 512, 4>::default();
+        recovery.mem.fill(0x5A);
+
+        let mut run = |cut: usize| {
+            let flash = power_fail_flash(&budget, &original, &update);
+            let mut bootloader = BootLoader::new(BootLoaderConfig {
+                active: flash.active(),
+                dfu: flash.dfu(),
+                state: flash.state(),
+            });
+            let mut page = [0; 512];
+            // Swap, revert, then boot the reverted image once.
+            assert_eq!(
+                State::Swap,
+                bootloader.prepare_boot_with_recovery(&mut recovery, &mut page).unwrap()
+            );
+            assert_eq!(
+                State::Swap,
+                bootloader.prepare_boot_with_recovery(&mut recovery, &mut page).unwrap()
+            );
+
+            budget.set(cut);
+            let result = bootloader.prepare_boot_with_recovery(&mut recovery, &mut page);
+            let used = cut - budget.get();
+            if cut != usize::MAX {
+                assert!(result.is_err());
+                budget.set(usize::MAX);
+            }
+            assert_eq!(
+                State::Recovery,
+                bootloader.prepare_boot_with_recovery(&mut recovery, &mut page).unwrap(),
+                "power cut after {} operations",
+                cut
+            );
+
+            let mut active = [0; 2048];
+            flash.active().read(0, &mut active).unwrap();
+            assert!(
+                active[..1024].iter().all(|&b| b == 0x5A),
+                "power cut after {} operations",
+                cut
+            );
+            used
+        };
+
+        let operations = run(usize::MAX);
+        assert!(operations > 0);
+        for cut in 0..operations {
+            run(cut);
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "_verify"))]
+    fn test_direct_xip() {
+        use crate::image::{ImageFormat, ImageVersion};
+
+        let budget = Rc::new(Cell::new(usize::MAX));
+        let mut v1 = [0; 2048];
+        build_image(ImageFormat::Embassy, ImageVersion::new(1, 0, 0), 1, 0x11, &mut v1);
+        // The update is an MCUboot image, with its security counter in the protected trailer.
+        let mut v2 = [0; 2048];
+        build_image(ImageFormat::McuBoot, ImageVersion::new(1, 1, 0), 2, 0x22, &mut v2);
+        let mut page = [0; 512];
+        let mut aligned = [0; 4];
+
+        // A corrupted update is never tried.
+        let mut corrupted = v2;
+        corrupted[100] ^= 1;
+        let flash = power_fail_flash(&budget, &v1, &corrupted);
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        });
+        bootloader.set_strategy(Strategy::DirectXip);
+        assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
+        assert_eq!(Slot::Active, bootloader.boot_slot());
+
+        // The newest image is tried, and confirmed by the application.
+        let flash = power_fail_flash(&budget, &v1, &v2);
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        });
+        bootloader.set_strategy(Strategy::DirectXip);
+        assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
+        assert_eq!(Slot::Dfu, bootloader.boot_slot());
+        BlockingFirmwareState::new(flash.state(), &mut aligned)
+            .mark_booted()
+            .unwrap();
+        assert_eq!(State::Boot, bootloader.prepare_boot(&mut page).unwrap());
+        assert_eq!(Slot::Dfu, bootloader.boot_slot());
+        assert_eq!(
+            cfg!(feature = "security-counter").then_some(2),
+            bootloader.security_counter().unwrap()
+        );
+
+        // An image on trial that is not confirmed is invalidated, even when the power is cut.
+        let run = |cut: usize| {
+            let flash = power_fail_flash(&budget, &v1, &v2);
+            let mut bootloader = BootLoader::new(BootLoaderConfig {
+                active: flash.active(),
+                dfu: flash.dfu(),
+                state: flash.state(),
+            });
+            bootloader.set_strategy(Strategy::DirectXip);
+            let mut page = [0; 512];
+            assert_eq!(State::Swap, bootloader.prepare_boot(&mut page).unwrap());
+
+            budget.set(cut);
+            let result = bootloader.prepare_boot(&mut page);
+            let used = cut - budget.get();
+            if cut != usize::MAX {
+                assert!(result.is_err());
+                budget.set(usize::MAX);
+            }
+            let state = bootloader.prepare_boot(&mut page).unwrap();
+            assert!(state == State::Revert || state == State::Boot);
+            assert_eq!(Slot::Active, bootloader.boot_slot());
+            let mut magic = [0; 4];
+            flash.dfu().read(0, &mut magic).unwrap();
+            assert_eq!([0xFF; 4], magic);
+            used
+        };
+        let operations = run(usize::MAX);
+        for cut in 0..operations {
+            run(cut);
+        }
+    }
+
+    fn multi_image(major: u8, fill: u8, dependency: Option<(u8, u8)>, security_counter: u32) -> [u8; 2048] {
+        let mut builder =
+            image::ImageBuilder::new(image::ImageVersion::new(major, 0, 0)).security_counter(security_counter);
+        if let Some((image, major)) = dependency {
+            builder = builder.dependency(image, image::ImageVersion::new(major, 0, 0));
+        }
+        let mut out = [0; 2048];
+        builder.build(&[fill; 1024], &mut out, |_, _| Ok(())).unwrap();
+        out
+    }
+
+    /// Run a [`MultiBootLoader`] over two images, sharing the state partition of the first one.
+    fn multi_prepare_boot(flash: &[PowerFailTestFlash; 2]) -> Result<State, BootError> {
+        let mut bootloader = MultiBootLoader::new(MultiBootLoaderConfig {
+            images: flash.each_ref().map(|flash| ImagePartitions {
+                active: flash.active(),
+                dfu: flash.dfu(),
+            }),
+            state: flash[0].state(),
+        });
+        let mut page = [0; 512];
+        bootloader.prepare_boot(&mut page)
+    }
+
+    #[test]
+    fn test_multi_image() {
+        let budget = Rc::new(Cell::new(usize::MAX));
+        let mut aligned = [0; 4];
+        let old = [multi_image(1, 0x10, None, 0), multi_image(1, 0x20, None, 0)];
+        let new = [multi_image(2, 0x11, Some((1, 2)), 0), multi_image(2, 0x21, None, 0)];
+        let updated = |images: u32| {
+            let flash = [
+                power_fail_flash(&budget, &old[0], &new[0]),
+                power_fail_flash(&budget, &old[1], &new[1]),
+            ];
+            let mut aligned = [0; 4];
+            let mut state = BlockingFirmwareState::new(flash[0].state(), &mut aligned);
+            state.mark_images_updated(images).unwrap();
+            flash
+        };
+        let active = |flash: &[PowerFailTestFlash; 2]| {
+            flash.each_ref().map(|flash| {
+                let mut active = [0; 2048];
+                flash.active().read(0, &mut active).unwrap();
+                active
+            })
+        };
+
+        // Image 0 needs the update of image 1, so it is not installed alone.
+        let flash = updated(0b01);
+        assert_eq!(State::Boot, multi_prepare_boot(&flash).unwrap());
+        assert!(active(&flash) == old);
+
+        // Both images are swapped together, and reverted together when not marked booted.
+        let flash = updated(0b11);
+        budget.set(usize::MAX);
+        assert_eq!(State::Swap, multi_prepare_boot(&flash).unwrap());
+        let operations = usize::MAX - budget.get();
+        assert!(active(&flash) == new);
+        assert_eq!(State::Swap, multi_prepare_boot(&flash).unwrap());
+        assert!(active(&flash) == old);
+        assert_eq!(State::Revert, multi_prepare_boot(&flash).unwrap());
+
+        if cfg!(feature = "security-counter") {
+            let mut state = BlockingFirmwareState::new(flash[0].state(), &mut aligned);
+            let mut history = [BootRecord::default(); 4];
+            assert_eq!(3, state.boot_history(&mut history).unwrap());
+            assert_eq!(BootEvent::Revert, history[0].event);
+            assert_eq!(BootEvent::Revert, history[1].event);
+            assert_eq!(BootEvent::Swap, history[2].event);
+        }
+
+        // A power failure during the swap never leaves mismatched images behind.
+        for cut in 0..operations {
+            let flash = updated(0b11);
+            budget.set(cut);
+            assert!(multi_prepare_boot(&flash).is_err());
+            budget.set(usize::MAX);
+            assert_eq!(State::Swap, multi_prepare_boot(&flash).unwrap());
+            assert!(active(&flash) == new, "power cut after {} operations", cut);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "security-counter")]
+    fn test_multi_image_security_counter() {
+        let budget = Rc::new(Cell::new(usize::MAX));
+        let mut aligned = [0; 4];
+        let old = [multi_image(1, 0x10, None, 2), multi_image(1, 0x20, None, 2)];
+        let new = [multi_image(1, 0x10, None, 2), multi_image(2, 0x21, None, 1)];
+        let flash = [
+            power_fail_flash(&budget, &old[0], &new[0]),
+            power_fail_flash(&budget, &old[1], &new[1]),
+        ];
+
+        // Confirming the current images stores the counter of image 0.
+        let mut state = BlockingFirmwareState::new(flash[0].state(), &mut aligned);
+        state.mark_booted().unwrap();
+        assert_eq!(State::Boot, multi_prepare_boot(&flash).unwrap());
+        assert_eq!(Some(2), state.security_counter().unwrap());
+
+        // Image 1 alone is not downgraded below the stored counter.
+        state.mark_images_updated(0b10).unwrap();
+        assert_eq!(State::Boot, multi_prepare_boot(&flash).unwrap());
+        let mut active = [0; 2048];
+        flash[1].active().read(0, &mut active).unwrap();
+        assert!(active == old[1]);
+
+        flash[1].dfu().erase(0, 2048).unwrap();
+        flash[1].dfu().write(0, &multi_image(2, 0x21, None, 2)).unwrap();
+        let mut state = BlockingFirmwareState::new(flash[0].state(), &mut aligned);
+        state.mark_images_updated(0b10).unwrap();
+        assert_eq!(State::Swap, multi_prepare_boot(&flash).unwrap());
+    }
+
+    #[test]
+    #[cfg(any(feature = "ed25519-dalek", feature = "ed25519-salty"))]
+    fn test_verify_image() {
+        use ed25519_dalek::{Signer, SigningKey};
+        use rand::rngs::OsRng;
+
+        use crate::image::{ImageBuilder, ImageVersion, TLV_ED25519};
+
+        let keypair = SigningKey::generate(&mut OsRng {});
+
+        let mut image = [0; 4096];
+        ImageBuilder::new(ImageVersion::new(2, 0, 0))
+            .security_counter(1)
+            .build(&[0xAA; 1024], &mut image, |hash, tlv| {
+                tlv.push(TLV_ED25519, &keypair.sign(hash).to_bytes())
+            })
+            .unwrap();
+
+        let flash = BlockingTestFlash::new(BootLoaderConfig {
+            active: MemFlash::<0, 0, 0>::default(),
+            dfu: MemFlash::<4096, 4096, 4>::default(),
+            state: MemFlash::<8192, 4096, 4>::default(),
+        });
+        flash.dfu().write(0, &image).unwrap();
+
+        let mut aligned = [0; 4];
+        let mut updater = BlockingFirmwareUpdater::new(
+            FirmwareUpdaterConfig {
+                dfu: flash.dfu(),
+                state: flash.state(),
+            },
+            &mut aligned,
+        );
+
+        let other = SigningKey::generate(&mut OsRng {});
+        assert!(matches!(
+            updater.verify_image_and_mark_updated(&other.verifying_key().to_bytes()),
+            Err(FirmwareUpdaterError::Signature(_))
+        ));
+        let header = updater
+            .verify_image_and_mark_updated(&keypair.verifying_key().to_bytes())
+            .unwrap();
+        assert_eq!(header.security_counter, 1);
+        assert_eq!(State::Swap, updater.get_state().unwrap());
+    }
+
     #[test]
-    #[cfg(feature = "_verify")]
+    #[cfg(any(feature = "ed25519-dalek", feature = "ed25519-salty"))]
     fn test_verify() {
         // The following key setup is based on:
         // https://docs.rs/ed25519-dalek/latest/ed25519_dalek/#example
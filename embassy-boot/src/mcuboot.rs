@@ -0,0 +1,335 @@
+//! MCUboot image trailers.
+//!
+//! MCUboot keeps the state of an update in a trailer at the end of each slot instead of a
+//! state partition. Applications running under MCUboot request the update written to the
+//! secondary slot with [`McuBootSlot::set_pending`], and confirm themselves after a trial boot
+//! with [`McuBootSlot::set_confirmed`] on the primary slot, like `boot_set_pending` and
+//! `boot_set_confirmed` of MCUboot. Images in the secondary slot are written and checked with
+//! [`crate::FirmwareUpdater`] as usual, see [MCUboot images](crate::image#mcuboot-images).
+//!
+//! The trailer fields are aligned to [`MAX_ALIGN`] bytes, and end the slot:
+//!
+//! | Offset from the end | Size | Field                                    |
+//! |---------------------|------|------------------------------------------|
+//! | 48                  | 4    | Swap size                                |
+//! | 40                  | 1    | Swap info: image number and [`SwapType`] |
+//! | 32                  | 1    | Copy done                                |
+//! | 24                  | 1    | Image ok                                 |
+//! | 16                  | 16   | [`BOOT_MAGIC`]                           |
+//!
+//! The swap status written by MCUboot while swapping lies in front of the trailer and is left
+//! to MCUboot. [`crate::BootLoader`] keeps using its own state partition.
+
+use embedded_storage::nor_flash::NorFlash as BlockingNorFlash;
+use embedded_storage_async::nor_flash::NorFlash;
+
+use crate::{AlignedBuffer, FirmwareUpdaterError, STATE_ERASE_VALUE};
+
+/// Magic at the end of a slot whose trailer is valid.
+pub const BOOT_MAGIC: [u8; 16] = [
+    0x77, 0xc2, 0x95, 0xf3, 0x60, 0xd2, 0xef, 0x7f, 0x35, 0x52, 0x50, 0x0f, 0x2c, 0xb6, 0x79, 0x80,
+];
+/// Alignment of the trailer fields, the largest write size supported.
+pub const MAX_ALIGN: usize = 8;
+/// Length of the trailer, from the swap size to the end of the slot.
+pub const TRAILER_LEN: usize = 2 * MAX_ALIGN + 4 * MAX_ALIGN;
+
+/// Value of a set flag.
+const FLAG_SET: u8 = 0x01;
+
+const SWAP_SIZE: usize = 0;
+const SWAP_INFO: usize = MAX_ALIGN;
+const COPY_DONE: usize = 2 * MAX_ALIGN;
+const IMAGE_OK: usize = 3 * MAX_ALIGN;
+const MAGIC: usize = 4 * MAX_ALIGN;
+
+/// Kind of swap requested in the trailer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SwapType {
+    /// No swap.
+    None = 1,
+    /// Swap, and revert unless the new image is confirmed.
+    Test = 2,
+    /// Swap permanently.
+    Perm = 3,
+    /// Revert to the previous image.
+    Revert = 4,
+    /// The swap failed.
+    Fail = 5,
+}
+
+impl TryFrom<u8> for SwapType {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            1 => SwapType::None,
+            2 => SwapType::Test,
+            3 => SwapType::Perm,
+            4 => SwapType::Revert,
+            5 => SwapType::Fail,
+            _ => return Err(()),
+        })
+    }
+}
+
+/// State of the magic of a trailer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MagicState {
+    /// The magic is [`BOOT_MAGIC`].
+    Good,
+    /// The magic is erased.
+    Unset,
+    /// The magic holds anything else.
+    Bad,
+}
+
+/// The trailer of a slot.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Trailer {
+    /// State of the magic.
+    pub magic: MagicState,
+    /// Requested swap, if set.
+    pub swap_type: Option<SwapType>,
+    /// Image number of the requested swap.
+    pub image_num: u8,
+    /// Whether MCUboot has finished copying the image.
+    pub copy_done: bool,
+    /// Whether the image is confirmed.
+    pub image_ok: bool,
+    /// Size of the swapped area, if set.
+    pub swap_size: Option<u32>,
+}
+
+impl Trailer {
+    /// Parse the last [`TRAILER_LEN`] bytes of a slot.
+    pub fn parse(buf: &[u8; TRAILER_LEN]) -> Self {
+        let magic = &buf[MAGIC..];
+        let swap_info = buf[SWAP_INFO];
+        let swap_size = &buf[SWAP_SIZE..SWAP_SIZE + 4];
+        Self {
+            magic: if magic == BOOT_MAGIC {
+                MagicState::Good
+            } else if magic.iter().all(|&b| b == STATE_ERASE_VALUE) {
+                MagicState::Unset
+            } else {
+                MagicState::Bad
+            },
+            swap_type: SwapType::try_from(swap_info & 0x0F).ok(),
+            image_num: match swap_info {
+                STATE_ERASE_VALUE => 0,
+                info => info >> 4,
+            },
+            copy_done: buf[COPY_DONE] == FLAG_SET,
+            image_ok: buf[IMAGE_OK] == FLAG_SET,
+            swap_size: match swap_size.iter().all(|&b| b == STATE_ERASE_VALUE) {
+                true => None,
+                false => Some(u32::from_le_bytes([
+                    swap_size[0],
+                    swap_size[1],
+                    swap_size[2],
+                    swap_size[3],
+                ])),
+            },
+        }
+    }
+}
+
+/// A slot of MCUboot, holding an image and its trailer.
+pub struct McuBootSlot<F> {
+    flash: F,
+}
+
+impl<F: NorFlash> McuBootSlot<F> {
+    /// Create a slot over the whole of `flash`.
+    pub fn new(flash: F) -> Self {
+        assert!(F::WRITE_SIZE <= MAX_ALIGN && F::READ_SIZE <= MAX_ALIGN);
+        Self { flash }
+    }
+
+    /// The slot, to write an update to it.
+    pub fn flash(&mut self) -> &mut F {
+        &mut self.flash
+    }
+
+    /// Read the trailer.
+    pub async fn read_trailer(&mut self) -> Result<Trailer, FirmwareUpdaterError> {
+        let mut buf = AlignedBuffer([0; TRAILER_LEN]);
+        self.flash.read(self.trailer_offset(), &mut buf.0).await?;
+        Ok(Trailer::parse(&buf.0))
+    }
+
+    /// Request MCUboot to install the image of this secondary slot on the next boot, for a
+    /// trial boot unless `permanent`. Nothing is written if an update is already pending.
+    pub async fn set_pending(&mut self, permanent: bool) -> Result<(), FirmwareUpdaterError> {
+        match self.read_trailer().await?.magic {
+            MagicState::Good => Ok(()),
+            MagicState::Unset => {
+                self.write(MAGIC, &BOOT_MAGIC).await?;
+                if permanent {
+                    self.write(IMAGE_OK, &[FLAG_SET]).await?;
+                }
+                let swap_type = if permanent { SwapType::Perm } else { SwapType::Test };
+                self.write(SWAP_INFO, &[swap_type as u8]).await
+            }
+            MagicState::Bad => Err(FirmwareUpdaterError::BadState),
+        }
+    }
+
+    /// Confirm the image of this primary slot after a trial boot, so MCUboot does not revert
+    /// it. Nothing is written if it is already confirmed or was not swapped in.
+    pub async fn set_confirmed(&mut self) -> Result<(), FirmwareUpdaterError> {
+        let trailer = self.read_trailer().await?;
+        match trailer.magic {
+            MagicState::Good if !trailer.image_ok => self.write(IMAGE_OK, &[FLAG_SET]).await,
+            MagicState::Good | MagicState::Unset => Ok(()),
+            MagicState::Bad => Err(FirmwareUpdaterError::BadState),
+        }
+    }
+
+    fn trailer_offset(&self) -> u32 {
+        (self.flash.capacity() - TRAILER_LEN) as u32
+    }
+
+    /// Write `value` at `field` of the trailer, padded to the write size.
+    async fn write(&mut self, field: usize, value: &[u8]) -> Result<(), FirmwareUpdaterError> {
+        let mut buf = AlignedBuffer([STATE_ERASE_VALUE; 16]);
+        buf.0[..value.len()].copy_from_slice(value);
+        let len = value.len().next_multiple_of(F::WRITE_SIZE);
+        self.flash
+            .write(self.trailer_offset() + field as u32, &buf.0[..len])
+            .await?;
+        Ok(())
+    }
+}
+
+/// A slot of MCUboot, holding an image and its trailer. Blocking version of [`McuBootSlot`].
+pub struct BlockingMcuBootSlot<F> {
+    flash: F,
+}
+
+impl<F: BlockingNorFlash> BlockingMcuBootSlot<F> {
+    /// Create a slot over the whole of `flash`.
+    pub fn new(flash: F) -> Self {
+        assert!(F::WRITE_SIZE <= MAX_ALIGN && F::READ_SIZE <= MAX_ALIGN);
+        Self { flash }
+    }
+
+    /// The slot, to write an update to it.
+    pub fn flash(&mut self) -> &mut F {
+        &mut self.flash
+    }
+
+    /// Read the trailer.
+    pub fn read_trailer(&mut self) -> Result<Trailer, FirmwareUpdaterError> {
+        let mut buf = AlignedBuffer([0; TRAILER_LEN]);
+        self.flash.read(self.trailer_offset(), &mut buf.0)?;
+        Ok(Trailer::parse(&buf.0))
+    }
+
+    /// Request MCUboot to install the image of this secondary slot on the next boot, for a
+    /// trial boot unless `permanent`. Nothing is written if an update is already pending.
+    pub fn set_pending(&mut self, permanent: bool) -> Result<(), FirmwareUpdaterError> {
+        match self.read_trailer()?.magic {
+            MagicState::Good => Ok(()),
+            MagicState::Unset => {
+                self.write(MAGIC, &BOOT_MAGIC)?;
+                if permanent {
+                    self.write(IMAGE_OK, &[FLAG_SET])?;
+                }
+                let swap_type = if permanent { SwapType::Perm } else { SwapType::Test };
+                self.write(SWAP_INFO, &[swap_type as u8])
+            }
+            MagicState::Bad => Err(FirmwareUpdaterError::BadState),
+        }
+    }
+
+    /// Confirm the image of this primary slot after a trial boot, so MCUboot does not revert
+    /// it. Nothing is written if it is already confirmed or was not swapped in.
+    pub fn set_confirmed(&mut self) -> Result<(), FirmwareUpdaterError> {
+        let trailer = self.read_trailer()?;
+        match trailer.magic {
+            MagicState::Good if !trailer.image_ok => self.write(IMAGE_OK, &[FLAG_SET]),
+            MagicState::Good | MagicState::Unset => Ok(()),
+            MagicState::Bad => Err(FirmwareUpdaterError::BadState),
+        }
+    }
+
+    fn trailer_offset(&self) -> u32 {
+        (self.flash.capacity() - TRAILER_LEN) as u32
+    }
+
+    /// Write `value` at `field` of the trailer, padded to the write size.
+    fn write(&mut self, field: usize, value: &[u8]) -> Result<(), FirmwareUpdaterError> {
+        let mut buf = AlignedBuffer([STATE_ERASE_VALUE; 16]);
+        buf.0[..value.len()].copy_from_slice(value);
+        let len = value.len().next_multiple_of(F::WRITE_SIZE);
+        self.flash.write(self.trailer_offset() + field as u32, &buf.0[..len])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::mem_flash::MemFlash;
+
+    #[test]
+    fn pending_and_confirmed() {
+        let mut secondary = BlockingMcuBootSlot::new(MemFlash::<8192, 4096, 4>::default());
+        let trailer = secondary.read_trailer().unwrap();
+        assert_eq!(trailer.magic, MagicState::Unset);
+        assert_eq!(trailer.swap_type, None);
+
+        secondary.set_pending(false).unwrap();
+        let trailer = secondary.read_trailer().unwrap();
+        assert_eq!(trailer.magic, MagicState::Good);
+        assert_eq!(trailer.swap_type, Some(SwapType::Test));
+        assert!(!trailer.image_ok);
+        // Already pending, nothing is written.
+        secondary.set_pending(true).unwrap();
+        assert_eq!(secondary.read_trailer().unwrap(), trailer);
+
+        // The trailer written is the one of MCUboot.
+        let mem = &secondary.flash().mem;
+        assert_eq!(mem[8192 - 16..], BOOT_MAGIC);
+        assert_eq!(mem[8192 - 40..8192 - 36], [0x02, 0xFF, 0xFF, 0xFF]);
+
+        // MCUboot swapped the image in: the primary slot trailer has the magic and copy done.
+        let mut primary = McuBootSlot::new(MemFlash::<8192, 4096, 4>::default());
+        block_on(primary.set_confirmed()).unwrap();
+        assert_eq!(block_on(primary.read_trailer()).unwrap().magic, MagicState::Unset);
+        block_on(primary.write(MAGIC, &BOOT_MAGIC)).unwrap();
+        block_on(primary.write(COPY_DONE, &[FLAG_SET])).unwrap();
+        block_on(primary.set_confirmed()).unwrap();
+        let trailer = block_on(primary.read_trailer()).unwrap();
+        assert!(trailer.copy_done && trailer.image_ok);
+
+        let mut permanent = McuBootSlot::new(MemFlash::<8192, 4096, 8>::default());
+        block_on(permanent.set_pending(true)).unwrap();
+        let trailer = block_on(permanent.read_trailer()).unwrap();
+        assert_eq!(trailer.swap_type, Some(SwapType::Perm));
+        assert!(trailer.image_ok);
+    }
+
+    #[test]
+    fn parse_trailer() {
+        let mut buf = [0xFF; TRAILER_LEN];
+        buf[SWAP_SIZE..SWAP_SIZE + 4].copy_from_slice(&0x2_0000u32.to_le_bytes());
+        buf[SWAP_INFO] = 0x14;
+        buf[MAGIC..].copy_from_slice(&BOOT_MAGIC);
+        let trailer = Trailer::parse(&buf);
+        assert_eq!(trailer.swap_type, Some(SwapType::Revert));
+        assert_eq!(trailer.image_num, 1);
+        assert_eq!(trailer.swap_size, Some(0x2_0000));
+        assert!(!trailer.copy_done);
+
+        buf[MAGIC] = 0;
+        assert_eq!(Trailer::parse(&buf).magic, MagicState::Bad);
+    }
+}
@@ -0,0 +1,901 @@
+//! Resumable over-the-air updates over HTTP.
+//!
+//! [`OtaClient`] downloads an [image](crate::image) with HTTP `Range` requests, writes it to the
+//! DFU partition with [`FirmwareUpdater::write_firmware`], and marks it updated once
+//! [`FirmwareUpdater::verify_image_and_mark_updated_with`] has checked its hash and signature.
+//!
+//! Connections are opened by a [`Connect`] implementation, for example around the `TcpClient`
+//! of `embassy-net`, or TLS sessions over its connections for HTTPS:
+//!
+//! ```ignore
+//! use core::net::SocketAddr;
+//!
+//! use embassy_boot::ota::Connect;
+//! use embassy_net::tcp::client::{TcpClient, TcpConnection};
+//! use embedded_nal_async::TcpConnect;
+//!
+//! struct Tcp<'a> {
+//!     client: &'a TcpClient<'a, 1>,
+//!     server: SocketAddr,
+//! }
+//!
+//! impl<'a> Connect for Tcp<'a> {
+//!     type Error = embassy_net::tcp::Error;
+//!     type Connection<'c>
+//!         = TcpConnection<'a, 1, 1024, 1024>
+//!     where
+//!         Self: 'c;
+//!
+//!     async fn connect(&mut self) -> Result<Self::Connection<'_>, Self::Error> {
+//!         self.client.connect(self.server).await
+//!     }
+//! }
+//! ```
+//!
+//! The progress of a download is kept in the last erase page of the DFU partition: a header
+//! identifying the image being downloaded, followed by one word per page of the image written.
+//! When the connection drops or the device loses power, calling [`OtaClient::download`] again
+//! resumes from the first page not written. The header keeps the `ETag` of the image, or its
+//! `Last-Modified` date, which the resumed request sends in an `If-Range` header: a server
+//! serving another image by now answers with the whole of it, and the download starts over.
+//! Downloads from servers sending neither header always start over. Images must be at least
+//! one page smaller than the DFU partition, which the swap strategy of the bootloader requires
+//! anyway.
+
+use embedded_io_async::{ErrorType, Read, Write};
+use embedded_storage_async::nor_flash::NorFlash;
+use sha2::{Digest, Sha256};
+
+use crate::image::ImageHeader;
+use crate::verifier::Verifier;
+use crate::{AlignedBuffer, FirmwareUpdater, FirmwareUpdaterError, STATE_ERASE_VALUE};
+
+/// Magic of the progress header, "OTA2".
+const PROGRESS_MAGIC: [u8; 4] = *b"OTA2";
+/// Longest `ETag` or `Last-Modified` value kept to resume a download.
+const MAX_VALIDATOR_LEN: usize = 63;
+/// Length of the progress header: magic, image length, download id, and the length and value
+/// of the validator.
+const PROGRESS_LEN: usize = 17 + MAX_VALIDATOR_LEN;
+/// Largest write size supported for the progress page.
+const MAX_WORD_SIZE: usize = 32;
+
+/// Opens connections to the HTTP server.
+pub trait Connect {
+    /// Error of the transport.
+    type Error: embedded_io_async::Error;
+
+    /// A connection to the server.
+    type Connection<'a>: Read + Write + ErrorType<Error = Self::Error>
+    where
+        Self: 'a;
+
+    /// Open a new connection to the server. The connection is dropped at the end of each request.
+    async fn connect(&mut self) -> Result<Self::Connection<'_>, Self::Error>;
+}
+
+/// Errors returned by [`OtaClient::download`].
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OtaError<E> {
+    /// Error from the transport.
+    Transport(E),
+    /// The server answered with an unexpected HTTP status.
+    Status(u16),
+    /// The response of the server could not be parsed, uses an unsupported transfer encoding,
+    /// or does not fit in the buffer.
+    Malformed,
+    /// The connection was closed before the whole image was received. Download again to resume.
+    Incomplete,
+    /// The image does not fit in the DFU partition, leaving a page for the progress.
+    TooLarge,
+    /// Error from the firmware updater, or the downloaded image is invalid.
+    Updater(FirmwareUpdaterError),
+}
+
+impl<E> From<FirmwareUpdaterError> for OtaError<E> {
+    fn from(error: FirmwareUpdaterError) -> Self {
+        OtaError::Updater(error)
+    }
+}
+
+/// Downloads images over HTTP, see the [module documentation](self).
+pub struct OtaClient<'a> {
+    host: &'a str,
+    path: &'a str,
+}
+
+impl<'a> OtaClient<'a> {
+    /// Create a client for the image at `path` on `host`, used for the `Host` header.
+    pub const fn new(host: &'a str, path: &'a str) -> Self {
+        Self { host, path }
+    }
+
+    /// Download the image into the DFU partition, resuming an interrupted download of the same
+    /// image, then verify it with `verifier` and mark it updated.
+    ///
+    /// `buf` holds the response headers and then the chunks written to flash. Its length must
+    /// be a multiple of the write and read sizes of the DFU partition, and divide its erase size.
+    ///
+    /// On [`OtaError::Transport`] or [`OtaError::Incomplete`] the progress is kept and the
+    /// download can be retried, also after a reset.
+    pub async fn download<C: Connect, DFU: NorFlash, STATE: NorFlash, V: Verifier>(
+        &self,
+        connector: &mut C,
+        updater: &mut FirmwareUpdater<'_, DFU, STATE>,
+        verifier: &mut V,
+        buf: &mut [u8],
+    ) -> Result<ImageHeader, OtaError<C::Error>> {
+        assert!(DFU::WRITE_SIZE <= MAX_WORD_SIZE && DFU::READ_SIZE <= MAX_WORD_SIZE);
+        assert!(
+            !buf.is_empty() && buf.len().is_multiple_of(DFU::WRITE_SIZE) && buf.len().is_multiple_of(DFU::READ_SIZE)
+        );
+        assert_eq!(0, DFU::ERASE_SIZE % buf.len());
+
+        let page_size = DFU::ERASE_SIZE;
+        let progress = Progress {
+            offset: (updater.dfu().capacity() - page_size) as u32,
+            id: self.id(),
+        };
+        let resume = progress.read(updater.dfu(), page_size).await?;
+
+        if resume.as_ref().is_none_or(|resume| resume.offset < resume.len) {
+            // Without a validator, the image on the server cannot be told apart from the one
+            // being resumed.
+            let resume = resume.filter(|resume| !resume.validator.is_empty());
+            let mut offset = resume.as_ref().map_or(0, |resume| resume.offset);
+            let validator = resume.as_ref().map(|resume| &resume.validator);
+            let mut opened = self.open(connector, offset, validator, buf).await?;
+            if opened.1 != 0 && resume.as_ref().is_none_or(|resume| resume.len != opened.2) {
+                // The image changed on the server, download it again from the start.
+                drop(opened);
+                opened = self.open(connector, 0, None, buf).await?;
+            }
+            let (mut connection, start, len, validator, mut filled) = opened;
+
+            if resume.as_ref().is_some_and(|resume| resume.len == len) && start == offset {
+                debug!("Resuming download at {} of {} bytes", offset, len);
+            } else if start == 0 {
+                debug!("Starting download of {} bytes", len);
+                let pages = len.div_ceil(page_size);
+                if len == 0
+                    || len + page_size > updater.dfu().capacity()
+                    || progress.word::<DFU>(pages) > page_size as u32
+                {
+                    return Err(OtaError::TooLarge);
+                }
+                progress.start(updater.dfu(), page_size, len, &validator).await?;
+                offset = 0;
+            } else {
+                return Err(OtaError::Malformed);
+            }
+
+            // Pages are erased by `write_firmware` as they are reached, `FirmwareUpdater::dfu`
+            // forgets the last erased page since the DFU partition may have been written around it.
+            loop {
+                let want = buf.len().min(len - offset);
+                while filled < want {
+                    let n = connection
+                        .read(&mut buf[filled..want])
+                        .await
+                        .map_err(OtaError::Transport)?;
+                    if n == 0 {
+                        return Err(OtaError::Incomplete);
+                    }
+                    filled += n;
+                }
+
+                let size = want.next_multiple_of(DFU::WRITE_SIZE);
+                buf[want..size].fill(STATE_ERASE_VALUE);
+                updater.write_firmware(offset, &buf[..size]).await?;
+                offset += want;
+                filled = 0;
+
+                if offset % page_size == 0 || offset == len {
+                    progress.mark(updater.dfu(), (offset - 1) / page_size).await?;
+                }
+                if offset == len {
+                    break;
+                }
+            }
+        }
+
+        debug!("Download complete, verifying image");
+        let progress_end = progress.offset + page_size as u32;
+        updater
+            .dfu()
+            .erase(progress.offset, progress_end)
+            .await
+            .map_err(FirmwareUpdaterError::from)?;
+        Ok(updater.verify_image_and_mark_updated_with(verifier).await?)
+    }
+
+    /// Connect and request the image from `offset`, if it still matches `validator`.
+    ///
+    /// Returns the connection, the offset the body starts at, the length of the image, its
+    /// validator, and the number of body bytes already read to the start of `buf`.
+    async fn open<'c, C: Connect>(
+        &self,
+        connector: &'c mut C,
+        offset: usize,
+        validator: Option<&Validator>,
+        buf: &mut [u8],
+    ) -> Result<(C::Connection<'c>, usize, usize, Validator, usize), OtaError<C::Error>> {
+        let mut connection = connector.connect().await.map_err(OtaError::Transport)?;
+        let (response, filled) = self.request(&mut connection, offset, validator, buf).await?;
+        let (start, len) = match response.status {
+            206 => response.range.ok_or(OtaError::Malformed)?,
+            // The server ignored the range, or the image changed, the whole image is sent.
+            200 => (0, response.content_length.ok_or(OtaError::Malformed)?),
+            status => return Err(OtaError::Status(status)),
+        };
+        Ok((connection, start, len, response.validator, filled))
+    }
+
+    /// Send the request for the image from `offset`, if it still matches `validator`, and read
+    /// the response headers.
+    ///
+    /// Returns the response and the number of body bytes already read to the start of `buf`.
+    async fn request<T: Read + Write>(
+        &self,
+        connection: &mut T,
+        offset: usize,
+        validator: Option<&Validator>,
+        buf: &mut [u8],
+    ) -> Result<(Response, usize), OtaError<T::Error>> {
+        let mut number = [0; 20];
+        let (if_range, validator) = match validator {
+            Some(validator) => ("\r\nIf-Range: ", validator.as_str()),
+            None => ("", ""),
+        };
+        for part in [
+            "GET ",
+            self.path,
+            " HTTP/1.1\r\nHost: ",
+            self.host,
+            "\r\nRange: bytes=",
+            format_usize(offset, &mut number),
+            "-",
+            if_range,
+            validator,
+            "\r\nConnection: close\r\n\r\n",
+        ] {
+            connection
+                .write_all(part.as_bytes())
+                .await
+                .map_err(OtaError::Transport)?;
+        }
+        connection.flush().await.map_err(OtaError::Transport)?;
+
+        let mut filled = 0;
+        loop {
+            if filled == buf.len() {
+                return Err(OtaError::Malformed);
+            }
+            let n = connection.read(&mut buf[filled..]).await.map_err(OtaError::Transport)?;
+            if n == 0 {
+                return Err(OtaError::Incomplete);
+            }
+            filled += n;
+
+            if let Some(end) = buf[..filled].windows(4).position(|w| w == b"\r\n\r\n") {
+                let response = Response::parse(&buf[..end]).ok_or(OtaError::Malformed)?;
+                buf.copy_within(end + 4..filled, 0);
+                return Ok((response, filled - end - 4));
+            }
+        }
+    }
+
+    /// Identifies the downloaded image in the progress header.
+    fn id(&self) -> [u8; 8] {
+        let mut hasher = Sha256::new();
+        hasher.update(self.host.as_bytes());
+        hasher.update([0]);
+        hasher.update(self.path.as_bytes());
+        let mut id = [0; 8];
+        id.copy_from_slice(&hasher.finalize()[..8]);
+        id
+    }
+}
+
+/// The progress page at the end of the DFU partition.
+struct Progress {
+    offset: u32,
+    id: [u8; 8],
+}
+
+impl Progress {
+    /// Size of the words marking pages, and alignment of the header.
+    fn word_size<DFU: NorFlash>() -> usize {
+        DFU::WRITE_SIZE.max(DFU::READ_SIZE)
+    }
+
+    /// Offset in the page of the word marking page `page` of the image as written.
+    fn word<DFU: NorFlash>(&self, page: usize) -> u32 {
+        let word_size = Self::word_size::<DFU>();
+        (PROGRESS_LEN.next_multiple_of(word_size) + page * word_size) as u32
+    }
+
+    /// Read the download of this image that was started, if any.
+    async fn read<DFU: NorFlash>(
+        &self,
+        dfu: &mut DFU,
+        page_size: usize,
+    ) -> Result<Option<Resume>, FirmwareUpdaterError> {
+        let word_size = Self::word_size::<DFU>();
+        let mut buf = AlignedBuffer([0; PROGRESS_LEN.next_multiple_of(MAX_WORD_SIZE)]);
+        let header = &mut buf.0[..PROGRESS_LEN.next_multiple_of(word_size)];
+        dfu.read(self.offset, header).await?;
+        if header[..4] != PROGRESS_MAGIC || header[8..16] != self.id {
+            return Ok(None);
+        }
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let validator_len = (header[16] as usize).min(MAX_VALIDATOR_LEN);
+        let validator = core::str::from_utf8(&header[17..17 + validator_len])
+            .ok()
+            .and_then(Validator::new)
+            .unwrap_or_default();
+
+        let mut resume = Resume {
+            len,
+            offset: len,
+            validator,
+        };
+        for page in 0..len.div_ceil(page_size) {
+            let word = &mut buf.0[..word_size];
+            dfu.read(self.offset + self.word::<DFU>(page), word).await?;
+            if word.contains(&STATE_ERASE_VALUE) {
+                resume.offset = page * page_size;
+                break;
+            }
+        }
+        Ok(Some(resume))
+    }
+
+    /// Start a new download of `len` bytes of the image identified by `validator`.
+    async fn start<DFU: NorFlash>(
+        &self,
+        dfu: &mut DFU,
+        page_size: usize,
+        len: usize,
+        validator: &Validator,
+    ) -> Result<(), FirmwareUpdaterError> {
+        dfu.erase(self.offset, self.offset + page_size as u32).await?;
+
+        // The header is written at once, a header interrupted while written is not recognized.
+        let mut header = AlignedBuffer([0; PROGRESS_LEN.next_multiple_of(MAX_WORD_SIZE)]);
+        header.0[..4].copy_from_slice(&PROGRESS_MAGIC);
+        header.0[4..8].copy_from_slice(&(len as u32).to_le_bytes());
+        header.0[8..16].copy_from_slice(&self.id);
+        header.0[16] = validator.len;
+        header.0[17..PROGRESS_LEN].copy_from_slice(&validator.bytes);
+        let header_len = PROGRESS_LEN.next_multiple_of(Self::word_size::<DFU>());
+        dfu.write(self.offset, &header.0[..header_len]).await?;
+        Ok(())
+    }
+
+    /// Mark page `page` of the image as written.
+    async fn mark<DFU: NorFlash>(&self, dfu: &mut DFU, page: usize) -> Result<(), FirmwareUpdaterError> {
+        let word = AlignedBuffer([!STATE_ERASE_VALUE; MAX_WORD_SIZE]);
+        dfu.write(
+            self.offset + self.word::<DFU>(page),
+            &word.0[..Self::word_size::<DFU>()],
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+/// A download found in the progress page.
+struct Resume {
+    /// Length of the image.
+    len: usize,
+    /// Offset of the first page not written.
+    offset: usize,
+    /// Validator of the image, empty if the server sent none.
+    validator: Validator,
+}
+
+/// The `ETag` or `Last-Modified` value identifying the version of an image on the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+struct Validator {
+    len: u8,
+    bytes: [u8; MAX_VALIDATOR_LEN],
+}
+
+impl Default for Validator {
+    fn default() -> Self {
+        Self {
+            len: 0,
+            bytes: [0; MAX_VALIDATOR_LEN],
+        }
+    }
+}
+
+impl Validator {
+    /// Keep `value`, unless it is too long to be stored.
+    fn new(value: &str) -> Option<Self> {
+        let mut validator = Self::default();
+        validator
+            .bytes
+            .get_mut(..value.len())?
+            .copy_from_slice(value.as_bytes());
+        validator.len = value.len() as u8;
+        Some(validator)
+    }
+
+    fn as_str(&self) -> &str {
+        // Only built from a `str`, or from bytes checked as UTF-8.
+        core::str::from_utf8(&self.bytes[..self.len as usize]).unwrap()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// The parts of a response used by the client.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+struct Response {
+    status: u16,
+    content_length: Option<usize>,
+    /// Start offset and total length from the `Content-Range` header.
+    range: Option<(usize, usize)>,
+    /// The `ETag`, or else the `Last-Modified` date, empty if there is none or it is too long.
+    validator: Validator,
+}
+
+impl Response {
+    /// Parse the status line and headers, without the final empty line.
+    fn parse(head: &[u8]) -> Option<Self> {
+        let head = core::str::from_utf8(head).ok()?;
+        let mut lines = head.split("\r\n");
+
+        let mut status_line = lines.next()?.splitn(3, ' ');
+        if !status_line.next()?.starts_with("HTTP/1.") {
+            return None;
+        }
+        let status = status_line.next()?.parse().ok()?;
+
+        let mut response = Response {
+            status,
+            content_length: None,
+            range: None,
+            validator: Validator::default(),
+        };
+        let mut etag = false;
+        for line in lines {
+            let (name, value) = line.split_once(':')?;
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                response.content_length = Some(value.parse().ok()?);
+            } else if name.eq_ignore_ascii_case("content-range") {
+                let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+                let (start, _) = range.split_once('-')?;
+                response.range = Some((start.parse().ok()?, total.parse().ok()?));
+            } else if name.eq_ignore_ascii_case("etag") {
+                response.validator = Validator::new(value).unwrap_or_default();
+                etag = true;
+            } else if name.eq_ignore_ascii_case("last-modified") && !etag {
+                response.validator = Validator::new(value).unwrap_or_default();
+            } else if name.eq_ignore_ascii_case("transfer-encoding") && !value.eq_ignore_ascii_case("identity") {
+                return None;
+            }
+        }
+        Some(response)
+    }
+}
+
+/// Format `value` in decimal into `buf`.
+fn format_usize(mut value: usize, buf: &mut [u8; 20]) -> &str {
+    let mut start = buf.len();
+    loop {
+        start -= 1;
+        buf[start] = b'0' + (value % 10) as u8;
+        value /= 10;
+        if value == 0 {
+            break;
+        }
+    }
+    // Only ASCII digits were written.
+    core::str::from_utf8(&buf[start..]).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::format;
+    use std::string::String;
+    use std::vec::Vec;
+
+    use embassy_embedded_hal::flash::partition::Partition;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use embassy_sync::mutex::Mutex;
+    use embedded_io_async::ErrorKind;
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::image::{ImageBuilder, ImageVersion};
+    use crate::mem_flash::MemFlash;
+    use crate::{FirmwareUpdaterConfig, State};
+
+    type Flash = Mutex<NoopRawMutex, MemFlash<24576, 4096, 8>>;
+
+    const TLV_TEST: u16 = 0xF0;
+
+    /// Accepts the image hash as its signature.
+    struct HashVerifier;
+
+    impl Verifier for HashVerifier {
+        type Digest = Sha256;
+
+        fn tlv_type(&self) -> u16 {
+            TLV_TEST
+        }
+
+        fn verify(&mut self, digest: &[u8], signature: &[u8]) -> Result<(), signature::Error> {
+            match digest == signature {
+                true => Ok(()),
+                false => Err(signature::Error::new()),
+            }
+        }
+    }
+
+    /// Serves an image tagged `etag`, closing connections after `drop_after` body bytes.
+    struct Server {
+        image: Vec<u8>,
+        etag: Option<&'static str>,
+        ranges: bool,
+        drop_after: Option<usize>,
+        requests: Vec<usize>,
+    }
+
+    impl Server {
+        fn new(image: Vec<u8>) -> Self {
+            Self {
+                image,
+                etag: Some("\"1\""),
+                ranges: true,
+                drop_after: None,
+                requests: Vec::new(),
+            }
+        }
+
+        fn respond(&mut self, request: &[u8]) -> Vec<u8> {
+            let request = core::str::from_utf8(request).unwrap();
+            assert!(request.starts_with("GET /firmware.bin HTTP/1.1\r\nHost: updates.example.com\r\n"));
+            let (_, range) = request.split_once("Range: bytes=").unwrap();
+            let start: usize = range.split_once('-').unwrap().0.parse().unwrap();
+            self.requests.push(start);
+            let if_range = request
+                .split_once("If-Range: ")
+                .map(|(_, value)| value.split_once("\r\n").unwrap().0);
+
+            let len = self.image.len();
+            let etag = self.etag.map_or(String::new(), |etag| format!("ETag: {}\r\n", etag));
+            let (head, body) = match self.ranges && if_range.is_none_or(|value| Some(value) == self.etag) {
+                true => (
+                    format!(
+                        "HTTP/1.1 206 Partial Content\r\n{}Content-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\n\r\n",
+                        etag,
+                        start,
+                        len - 1,
+                        len,
+                        len - start
+                    ),
+                    &self.image[start..],
+                ),
+                false => (
+                    format!("HTTP/1.1 200 OK\r\n{}Content-Length: {}\r\n\r\n", etag, len),
+                    &self.image[..],
+                ),
+            };
+            let body = &body[..body.len().min(self.drop_after.unwrap_or(usize::MAX))];
+            let mut response = head.into_bytes();
+            response.extend_from_slice(body);
+            response
+        }
+    }
+
+    impl Connect for Server {
+        type Error = ErrorKind;
+        type Connection<'a> = Connection<'a>;
+
+        async fn connect(&mut self) -> Result<Connection<'_>, ErrorKind> {
+            Ok(Connection {
+                server: self,
+                request: Vec::new(),
+                response: None,
+            })
+        }
+    }
+
+    struct Connection<'a> {
+        server: &'a mut Server,
+        request: Vec<u8>,
+        response: Option<(Vec<u8>, usize)>,
+    }
+
+    impl ErrorType for Connection<'_> {
+        type Error = ErrorKind;
+    }
+
+    impl Write for Connection<'_> {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+            self.request.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        async fn flush(&mut self) -> Result<(), ErrorKind> {
+            Ok(())
+        }
+    }
+
+    impl Read for Connection<'_> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+            let (response, pos) = self
+                .response
+                .get_or_insert_with(|| (self.server.respond(&self.request), 0));
+            // Short reads, as from a socket.
+            let n = buf.len().min(response.len() - *pos).min(700);
+            buf[..n].copy_from_slice(&response[*pos..*pos + n]);
+            *pos += n;
+            Ok(n)
+        }
+    }
+
+    /// Connects to a server on the loopback interface over TCP.
+    struct Tcp(std::net::SocketAddr);
+
+    impl Connect for Tcp {
+        type Error = ErrorKind;
+        type Connection<'a> = TcpConnection;
+
+        async fn connect(&mut self) -> Result<TcpConnection, ErrorKind> {
+            std::net::TcpStream::connect(self.0)
+                .map(TcpConnection)
+                .map_err(|_| ErrorKind::ConnectionRefused)
+        }
+    }
+
+    struct TcpConnection(std::net::TcpStream);
+
+    impl ErrorType for TcpConnection {
+        type Error = ErrorKind;
+    }
+
+    impl Write for TcpConnection {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+            std::io::Write::write(&mut self.0, buf).map_err(|_| ErrorKind::Other)
+        }
+
+        async fn flush(&mut self) -> Result<(), ErrorKind> {
+            std::io::Write::flush(&mut self.0).map_err(|_| ErrorKind::Other)
+        }
+    }
+
+    impl Read for TcpConnection {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+            std::io::Read::read(&mut self.0, buf).map_err(|_| ErrorKind::Other)
+        }
+    }
+
+    /// Serves `server` on a loopback TCP socket for `connections` connections, dropping the
+    /// first one after `drop_after` body bytes. Returns the address and the requested offsets.
+    fn serve_tcp(
+        mut server: Server,
+        connections: usize,
+    ) -> (std::net::SocketAddr, std::thread::JoinHandle<Vec<usize>>) {
+        use std::io::{Read as _, Write as _};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let thread = std::thread::spawn(move || {
+            for stream in listener.incoming().take(connections) {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    let mut buf = [0; 256];
+                    let n = stream.read(&mut buf).unwrap();
+                    assert_ne!(n, 0);
+                    request.extend_from_slice(&buf[..n]);
+                }
+                stream.write_all(&server.respond(&request)).unwrap();
+                server.drop_after = None;
+            }
+            server.requests
+        });
+        (addr, thread)
+    }
+
+    fn image(version: ImageVersion, len: usize) -> Vec<u8> {
+        let firmware: Vec<u8> = (0..len).map(|i| (i * 7 / 3) as u8).collect();
+        let mut image = std::vec![0; len + 512];
+        let len = ImageBuilder::new(version)
+            .build(&firmware, &mut image, |hash, tlv| tlv.push(TLV_TEST, hash))
+            .unwrap();
+        image.truncate(len);
+        image
+    }
+
+    /// Download with a new updater and client, as after a reset.
+    fn download<C: Connect>(flash: &Flash, connector: &mut C) -> Result<ImageHeader, OtaError<C::Error>> {
+        let mut aligned = [0; 8];
+        let mut updater = FirmwareUpdater::new(
+            FirmwareUpdaterConfig {
+                dfu: Partition::new(flash, 4096, 20480),
+                state: Partition::new(flash, 0, 4096),
+            },
+            &mut aligned,
+        );
+        let mut buf = [0; 1024];
+        block_on(OtaClient::new("updates.example.com", "/firmware.bin").download(
+            connector,
+            &mut updater,
+            &mut HashVerifier,
+            &mut buf,
+        ))
+    }
+
+    fn assert_updated(flash: &Flash, image: &[u8]) {
+        let mut aligned = [0; 8];
+        let mut updater = FirmwareUpdater::new(
+            FirmwareUpdaterConfig {
+                dfu: Partition::new(flash, 4096, 20480),
+                state: Partition::new(flash, 0, 4096),
+            },
+            &mut aligned,
+        );
+        assert_eq!(State::Swap, block_on(updater.get_state()).unwrap());
+
+        let flash = flash.try_lock().unwrap();
+        assert_eq!(image, &flash.mem[4096..4096 + image.len()]);
+        // The progress page is left erased for the bootloader.
+        assert!(flash.mem[20480..].iter().all(|&b| b == STATE_ERASE_VALUE));
+    }
+
+    #[test]
+    fn resumes_interrupted_download() {
+        let image = image(ImageVersion::new(1, 2, 3), 10000);
+        let flash = Flash::new(MemFlash::default());
+        let mut server = Server::new(image.clone());
+        server.drop_after = Some(5000);
+
+        assert!(matches!(download(&flash, &mut server), Err(OtaError::Incomplete)));
+        assert!(matches!(download(&flash, &mut server), Err(OtaError::Incomplete)));
+        let header = download(&flash, &mut server).unwrap();
+        assert_eq!(ImageVersion::new(1, 2, 3), header.version);
+
+        // Downloads resume from the first page not written.
+        assert_eq!(server.requests[..], [0, 4096, 8192]);
+        assert_updated(&flash, &image);
+    }
+
+    #[test]
+    fn resumes_over_tcp() {
+        let image = image(ImageVersion::new(1, 2, 3), 10000);
+        let flash = Flash::new(MemFlash::default());
+        let mut server = Server::new(image.clone());
+        server.drop_after = Some(5000);
+        let (addr, thread) = serve_tcp(server, 2);
+
+        // The server closes the first connection half way through the body.
+        assert!(matches!(download(&flash, &mut Tcp(addr)), Err(OtaError::Incomplete)));
+        download(&flash, &mut Tcp(addr)).unwrap();
+
+        assert_eq!(thread.join().unwrap()[..], [0, 4096]);
+        assert_updated(&flash, &image);
+    }
+
+    #[test]
+    fn restarts_changed_image() {
+        let flash = Flash::new(MemFlash::default());
+        let mut server = Server::new(image(ImageVersion::new(1, 0, 0), 10000));
+        server.drop_after = Some(5000);
+        assert!(matches!(download(&flash, &mut server), Err(OtaError::Incomplete)));
+
+        let image = image(ImageVersion::new(1, 0, 1), 9000);
+        server.image = image.clone();
+        server.drop_after = None;
+        download(&flash, &mut server).unwrap();
+
+        assert_eq!(server.requests[..], [0, 4096, 0]);
+        assert_updated(&flash, &image);
+    }
+
+    #[test]
+    fn restarts_replaced_image() {
+        let flash = Flash::new(MemFlash::default());
+        let mut server = Server::new(image(ImageVersion::new(1, 0, 0), 10000));
+        server.drop_after = Some(5000);
+        assert!(matches!(download(&flash, &mut server), Err(OtaError::Incomplete)));
+
+        // An image of the same length, only told apart by its ETag.
+        let image = image(ImageVersion::new(1, 0, 1), 10000);
+        server.image = image.clone();
+        server.etag = Some("\"2\"");
+        server.drop_after = None;
+        download(&flash, &mut server).unwrap();
+
+        // The server answers the If-Range request with the whole new image.
+        assert_eq!(server.requests[..], [0, 4096]);
+        assert_updated(&flash, &image);
+    }
+
+    #[test]
+    fn restarts_without_validator() {
+        let image = image(ImageVersion::new(1, 0, 0), 10000);
+        let flash = Flash::new(MemFlash::default());
+        let mut server = Server::new(image.clone());
+        server.etag = None;
+        server.drop_after = Some(5000);
+        assert!(matches!(download(&flash, &mut server), Err(OtaError::Incomplete)));
+
+        server.drop_after = None;
+        download(&flash, &mut server).unwrap();
+
+        assert_eq!(server.requests[..], [0, 0]);
+        assert_updated(&flash, &image);
+    }
+
+    #[test]
+    fn restarts_without_range_support() {
+        let image = image(ImageVersion::new(1, 0, 0), 10000);
+        let flash = Flash::new(MemFlash::default());
+        let mut server = Server::new(image.clone());
+        server.ranges = false;
+        server.drop_after = Some(5000);
+        assert!(matches!(download(&flash, &mut server), Err(OtaError::Incomplete)));
+
+        server.drop_after = None;
+        download(&flash, &mut server).unwrap();
+
+        assert_eq!(server.requests[..], [0, 4096]);
+        assert_updated(&flash, &image);
+    }
+
+    #[test]
+    fn rejects_invalid_image() {
+        let mut image = image(ImageVersion::new(1, 0, 0), 10000);
+        image[1000] ^= 1;
+        let flash = Flash::new(MemFlash::default());
+        let mut server = Server::new(image);
+
+        assert!(matches!(
+            download(&flash, &mut server),
+            Err(OtaError::Updater(FirmwareUpdaterError::Image(_)))
+        ));
+    }
+
+    #[test]
+    fn parse_response() {
+        assert_eq!(
+            Response::parse(b"HTTP/1.1 206 Partial Content\r\ncontent-range: bytes 4096-9999/10000\r\nServer: test"),
+            Some(Response {
+                status: 206,
+                content_length: None,
+                range: Some((4096, 10000)),
+                validator: Validator::default(),
+            })
+        );
+        // The ETag is preferred over the Last-Modified date, whatever their order.
+        let etag = Validator::new("W/\"abc\"");
+        for response in [
+            &b"HTTP/1.1 200 OK\r\nETag: W/\"abc\"\r\nLast-Modified: Wed, 21 Oct 2015 07:28:00 GMT"[..],
+            b"HTTP/1.1 200 OK\r\nLast-Modified: Wed, 21 Oct 2015 07:28:00 GMT\r\netag: W/\"abc\"",
+        ] {
+            assert_eq!(Response::parse(response).map(|response| response.validator), etag);
+        }
+        assert_eq!(
+            Response::parse(b"HTTP/1.1 200 OK\r\nLast-Modified: Wed, 21 Oct 2015 07:28:00 GMT").map(|r| r.validator),
+            Validator::new("Wed, 21 Oct 2015 07:28:00 GMT")
+        );
+        let long = format!("HTTP/1.1 200 OK\r\nETag: \"{}\"", "a".repeat(MAX_VALIDATOR_LEN));
+        assert!(Response::parse(long.as_bytes()).unwrap().validator.is_empty());
+        assert_eq!(Response::parse(b"HTTP/1.1 404 Not Found").unwrap().status, 404);
+        assert_eq!(Response::parse(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked"), None);
+        assert_eq!(Response::parse(b"SSH-2.0-OpenSSH"), None);
+    }
+}
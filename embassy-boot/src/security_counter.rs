@@ -0,0 +1,566 @@
+//! Security counter and boot history storage in the STATE partition.
+//!
+//! With the `security-counter` feature, the last three erase pages of the STATE partition are
+//! never erased when the bootloader state changes: a boot history page followed by two
+//! security counter pages. A STATE partition of fewer than four erase pages, or with erase
+//! pages smaller than a record, has no room for them, and then no counter is enforced and no
+//! history is kept.
+//!
+//! Each page is a log of [`BootRecord`]s: the event kind, its detail, the boot attempt, the
+//! reset reason and the counter as a little-endian `u32`, padded to the write size with
+//! `!STATE_ERASE_VALUE`. Records are written in order, so a log ends with its last
+//! well-formed record, and a record torn by a power failure is skipped.
+//!
+//! The counter is only written when the bootloader raises it, to one counter page at a time.
+//! When that page is full, the raised counter is written to the other page before the full one
+//! is erased, so that a power failure at any point leaves at least one page holding the stored
+//! counter, the highest of both pages. Counter pages that are not erased but hold no
+//! well-formed record, like pages that were never initialized, read as a counter of
+//! `u32::MAX` that refuses every update, until the bootloader records the counter of a
+//! confirmed image.
+//!
+//! The history page is erased and starts over when it fills up. Repeated boots of the same
+//! image with the same reset reason are recorded once, so that the history page is not worn
+//! out by plain boots.
+
+use embedded_storage::nor_flash::NorFlash;
+use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
+
+use crate::{BootEvent, BootRecord, STATE_ERASE_VALUE};
+
+/// Largest record, for flashes with a write size of up to 32 bytes.
+pub(crate) const MAX_RECORD_SIZE: usize = 32;
+
+/// Length of a record before its padding.
+const RECORD_LEN: usize = 8;
+
+/// Number of erase pages kept apart at the end of the STATE partition: the history page and
+/// the two counter pages.
+const PAGE_COUNT: usize = 3;
+
+/// Offset of the history page in a STATE partition, followed by the counter pages, if there
+/// is room for them.
+pub(crate) const fn page_offset(capacity: usize, erase_size: usize, write_size: usize) -> Option<u32> {
+    if cfg!(feature = "security-counter") && capacity > PAGE_COUNT * erase_size && erase_size >= record_size(write_size)
+    {
+        Some((capacity - PAGE_COUNT * erase_size) as u32)
+    } else {
+        None
+    }
+}
+
+/// Size of the STATE partition that holds the bootloader state, in front of the history page.
+pub(crate) const fn state_area(capacity: usize, erase_size: usize, write_size: usize) -> u32 {
+    match page_offset(capacity, erase_size, write_size) {
+        Some(offset) => offset,
+        None => capacity as u32,
+    }
+}
+
+/// Records have at least one byte of padding, so that every record is distinct from erased
+/// flash.
+const fn record_size(write_size: usize) -> usize {
+    (RECORD_LEN + 1).next_multiple_of(write_size)
+}
+
+/// Offsets of the two counter pages following the history page at `offset`.
+fn counter_pages(offset: u32, erase_size: usize) -> [u32; 2] {
+    [offset + erase_size as u32, offset + 2 * erase_size as u32]
+}
+
+enum Record {
+    Erased,
+    Valid(BootRecord),
+    Invalid,
+}
+
+fn decode(record: &[u8]) -> Record {
+    if record.iter().all(|&b| b == STATE_ERASE_VALUE) {
+        return Record::Erased;
+    }
+    if record[RECORD_LEN..].iter().any(|&b| b != !STATE_ERASE_VALUE) {
+        return Record::Invalid;
+    }
+    let event = match (record[0], record[1]) {
+        (1, _) => BootEvent::Boot,
+        (2, _) => BootEvent::Swap,
+        (3, _) => BootEvent::Revert,
+        (4, _) => BootEvent::DfuDetach,
+        (5, _) => BootEvent::Recovery,
+        (6, _) => BootEvent::Confirmed,
+        (7, reason) => BootEvent::Unhealthy(reason),
+        _ => return Record::Invalid,
+    };
+    Record::Valid(BootRecord {
+        event,
+        attempt: record[2],
+        reset_reason: record[3],
+        security_counter: u32::from_le_bytes([record[4], record[5], record[6], record[7]]),
+    })
+}
+
+fn encode(value: &BootRecord, record: &mut [u8]) {
+    let (kind, detail) = match value.event {
+        BootEvent::Boot => (1, 0),
+        BootEvent::Swap => (2, 0),
+        BootEvent::Revert => (3, 0),
+        BootEvent::DfuDetach => (4, 0),
+        BootEvent::Recovery => (5, 0),
+        BootEvent::Confirmed => (6, 0),
+        BootEvent::Unhealthy(reason) => (7, reason),
+    };
+    record.fill(!STATE_ERASE_VALUE);
+    record[..4].copy_from_slice(&[kind, detail, value.attempt, value.reset_reason]);
+    record[4..RECORD_LEN].copy_from_slice(&value.security_counter.to_le_bytes());
+}
+
+/// End of the record log of a page.
+#[derive(Clone, Copy)]
+struct Log {
+    /// Number of written records, well-formed or not.
+    written: usize,
+    /// The last well-formed record.
+    last: Option<BootRecord>,
+}
+
+impl Log {
+    fn counter(&self) -> Option<u32> {
+        self.last.map(|last| last.security_counter)
+    }
+}
+
+/// Locate the end of the record log with a binary search, as records are written in order,
+/// and its last well-formed record.
+fn scan<F: NorFlash>(state: &mut F, offset: u32, record: &mut [u8]) -> Result<Log, F::Error> {
+    let (mut lo, mut hi) = (0, F::ERASE_SIZE / record.len());
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        state.read(offset + (mid * record.len()) as u32, record)?;
+        match decode(record) {
+            Record::Erased => hi = mid,
+            _ => lo = mid + 1,
+        }
+    }
+    for index in (0..lo).rev() {
+        state.read(offset + (index * record.len()) as u32, record)?;
+        if let Record::Valid(last) = decode(record) {
+            return Ok(Log {
+                written: lo,
+                last: Some(last),
+            });
+        }
+    }
+    Ok(Log {
+        written: lo,
+        last: None,
+    })
+}
+
+/// Locate the end of the record log with a binary search, as records are written in order,
+/// and its last well-formed record.
+async fn scan_async<F: AsyncNorFlash>(state: &mut F, offset: u32, record: &mut [u8]) -> Result<Log, F::Error> {
+    let (mut lo, mut hi) = (0, F::ERASE_SIZE / record.len());
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        state.read(offset + (mid * record.len()) as u32, record).await?;
+        match decode(record) {
+            Record::Erased => hi = mid,
+            _ => lo = mid + 1,
+        }
+    }
+    for index in (0..lo).rev() {
+        state.read(offset + (index * record.len()) as u32, record).await?;
+        if let Record::Valid(last) = decode(record) {
+            return Ok(Log {
+                written: lo,
+                last: Some(last),
+            });
+        }
+    }
+    Ok(Log {
+        written: lo,
+        last: None,
+    })
+}
+
+/// The counter stored in the counter pages, or `None` if neither holds a well-formed record
+/// but one is not erased.
+fn counter(logs: [Log; 2]) -> Option<u32> {
+    match logs.iter().filter_map(Log::counter).max() {
+        Some(counter) => Some(counter),
+        None if logs.iter().all(|log| log.written == 0) => Some(0),
+        None => None,
+    }
+}
+
+/// Read the stored security counter, or `None` if the STATE partition has no counter pages.
+pub(crate) fn read<F: NorFlash>(state: &mut F, buf: &mut [u8]) -> Result<Option<u32>, F::Error> {
+    let Some(offset) = page_offset(state.capacity(), F::ERASE_SIZE, F::WRITE_SIZE) else {
+        return Ok(None);
+    };
+    let record = &mut buf[..record_size(F::WRITE_SIZE)];
+    let [first, second] = counter_pages(offset, F::ERASE_SIZE);
+    let logs = [scan(state, first, record)?, scan(state, second, record)?];
+    Ok(Some(counter(logs).unwrap_or(u32::MAX)))
+}
+
+/// Read the stored security counter, or `None` if the STATE partition has no counter pages.
+pub(crate) async fn read_async<F: AsyncNorFlash>(state: &mut F, buf: &mut [u8]) -> Result<Option<u32>, F::Error> {
+    let Some(offset) = page_offset(state.capacity(), F::ERASE_SIZE, F::WRITE_SIZE) else {
+        return Ok(None);
+    };
+    let record = &mut buf[..record_size(F::WRITE_SIZE)];
+    let [first, second] = counter_pages(offset, F::ERASE_SIZE);
+    let logs = [
+        scan_async(state, first, record).await?,
+        scan_async(state, second, record).await?,
+    ];
+    Ok(Some(counter(logs).unwrap_or(u32::MAX)))
+}
+
+/// Raise the stored security counter to `value` if it is higher, or if the stored counter
+/// cannot be read.
+pub(crate) fn raise<F: NorFlash>(state: &mut F, value: u32, aligned_buf: &mut [u8]) -> Result<(), F::Error> {
+    let Some(offset) = page_offset(state.capacity(), F::ERASE_SIZE, F::WRITE_SIZE) else {
+        return Ok(());
+    };
+    let record = &mut aligned_buf[..record_size(F::WRITE_SIZE)];
+    let pages = counter_pages(offset, F::ERASE_SIZE);
+    let logs = [scan(state, pages[0], record)?, scan(state, pages[1], record)?];
+    if counter(logs).is_some_and(|stored| stored >= value) {
+        return Ok(());
+    }
+
+    let value = BootRecord {
+        security_counter: value,
+        ..Default::default()
+    };
+    encode(&value, record);
+    let current = usize::from(logs[1].counter() > logs[0].counter());
+    let log = logs[current];
+    if (log.last.is_some() || log.written == 0) && log.written < F::ERASE_SIZE / record.len() {
+        state.write(pages[current] + (log.written * record.len()) as u32, record)?;
+        return Ok(());
+    }
+
+    // Carry the counter to the other page before erasing this one.
+    let other = 1 - current;
+    if logs[other].written != 0 {
+        state.erase(pages[other], pages[other] + F::ERASE_SIZE as u32)?;
+    }
+    state.write(pages[other], record)?;
+    state.erase(pages[current], pages[current] + F::ERASE_SIZE as u32)?;
+    Ok(())
+}
+
+/// Read the newest records into `out`, newest first, and return how many were read.
+pub(crate) fn history<F: NorFlash>(state: &mut F, out: &mut [BootRecord], buf: &mut [u8]) -> Result<usize, F::Error> {
+    let Some(offset) = page_offset(state.capacity(), F::ERASE_SIZE, F::WRITE_SIZE) else {
+        return Ok(0);
+    };
+    let record = &mut buf[..record_size(F::WRITE_SIZE)];
+    let log = scan(state, offset, record)?;
+    let mut count = 0;
+    for index in (0..log.written).rev() {
+        if count == out.len() {
+            break;
+        }
+        state.read(offset + (index * record.len()) as u32, record)?;
+        if let Record::Valid(value) = decode(record) {
+            out[count] = value;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Read the newest records into `out`, newest first, and return how many were read.
+pub(crate) async fn history_async<F: AsyncNorFlash>(
+    state: &mut F,
+    out: &mut [BootRecord],
+    buf: &mut [u8],
+) -> Result<usize, F::Error> {
+    let Some(offset) = page_offset(state.capacity(), F::ERASE_SIZE, F::WRITE_SIZE) else {
+        return Ok(0);
+    };
+    let record = &mut buf[..record_size(F::WRITE_SIZE)];
+    let log = scan_async(state, offset, record).await?;
+    let mut count = 0;
+    for index in (0..log.written).rev() {
+        if count == out.len() {
+            break;
+        }
+        state.read(offset + (index * record.len()) as u32, record).await?;
+        if let Record::Valid(value) = decode(record) {
+            out[count] = value;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Append `value` to the history, raising its security counter to the stored one if it is
+/// lower. The counter pages are only read. A boot identical to the last record is not
+/// recorded again. Returns `false` if the STATE partition has no history page.
+pub(crate) fn append<F: NorFlash>(
+    state: &mut F,
+    mut value: BootRecord,
+    aligned_buf: &mut [u8],
+) -> Result<bool, F::Error> {
+    let Some(offset) = page_offset(state.capacity(), F::ERASE_SIZE, F::WRITE_SIZE) else {
+        return Ok(false);
+    };
+    if let Some(stored) = read(state, aligned_buf)? {
+        value.security_counter = value.security_counter.max(stored);
+    }
+    let record = &mut aligned_buf[..record_size(F::WRITE_SIZE)];
+    let log = scan(state, offset, record)?;
+    if value.event == BootEvent::Boot && log.last == Some(value) {
+        return Ok(true);
+    }
+    let mut written = log.written;
+    if written == F::ERASE_SIZE / record.len() {
+        state.erase(offset, offset + F::ERASE_SIZE as u32)?;
+        written = 0;
+    }
+    encode(&value, record);
+    state.write(offset + (written * record.len()) as u32, record)?;
+    Ok(true)
+}
+
+/// Append `value` to the history, raising its security counter to the stored one if it is
+/// lower. The counter pages are only read. A boot identical to the last record is not
+/// recorded again. Returns `false` if the STATE partition has no history page.
+pub(crate) async fn append_async<F: AsyncNorFlash>(
+    state: &mut F,
+    mut value: BootRecord,
+    aligned_buf: &mut [u8],
+) -> Result<bool, F::Error> {
+    let Some(offset) = page_offset(state.capacity(), F::ERASE_SIZE, F::WRITE_SIZE) else {
+        return Ok(false);
+    };
+    if let Some(stored) = read_async(state, aligned_buf).await? {
+        value.security_counter = value.security_counter.max(stored);
+    }
+    let record = &mut aligned_buf[..record_size(F::WRITE_SIZE)];
+    let log = scan_async(state, offset, record).await?;
+    if value.event == BootEvent::Boot && log.last == Some(value) {
+        return Ok(true);
+    }
+    let mut written = log.written;
+    if written == F::ERASE_SIZE / record.len() {
+        state.erase(offset, offset + F::ERASE_SIZE as u32).await?;
+        written = 0;
+    }
+    encode(&value, record);
+    state.write(offset + (written * record.len()) as u32, record).await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::mem_flash::MemFlash;
+
+    fn raise_to<F: NorFlash>(state: &mut F, value: u32, buf: &mut [u8]) {
+        raise(state, value, buf).unwrap();
+    }
+
+    #[test]
+    fn no_counter_page() {
+        let mut flash = MemFlash::<3072, 1024, 4>::default();
+        let mut buf = [0; 12];
+        assert_eq!(read(&mut flash, &mut buf).unwrap(), None);
+        raise_to(&mut flash, 2, &mut buf);
+        assert!(!append(&mut flash, BootRecord::default(), &mut buf).unwrap());
+        assert_eq!(
+            history(&mut flash, &mut [BootRecord::default(); 2], &mut buf).unwrap(),
+            0
+        );
+        assert!(flash.mem.iter().all(|&b| b == STATE_ERASE_VALUE));
+        assert_eq!(state_area(3072, 1024, 4), 3072);
+    }
+
+    #[test]
+    #[cfg(feature = "security-counter")]
+    fn raise_is_monotonic() {
+        let mut flash = MemFlash::<4096, 1024, 8>::default();
+        let mut buf = [0; 16];
+        assert_eq!(state_area(4096, 1024, 8), 1024);
+        assert_eq!(read(&mut flash, &mut buf).unwrap(), Some(0));
+
+        raise_to(&mut flash, 3, &mut buf);
+        assert_eq!(read(&mut flash, &mut buf).unwrap(), Some(3));
+        raise_to(&mut flash, 2, &mut buf);
+        assert_eq!(read(&mut flash, &mut buf).unwrap(), Some(3));
+        raise_to(&mut flash, 7, &mut buf);
+        assert_eq!(read(&mut flash, &mut buf).unwrap(), Some(7));
+        assert!(flash.mem[..2048].iter().all(|&b| b == STATE_ERASE_VALUE));
+    }
+
+    #[test]
+    #[cfg(feature = "security-counter")]
+    fn pages_alternate() {
+        let mut flash = MemFlash::<512, 128, 4>::default();
+        let mut buf = [0; 12];
+        // 10 records per page.
+        for value in 1..=25 {
+            raise_to(&mut flash, value, &mut buf);
+            assert_eq!(read(&mut flash, &mut buf).unwrap(), Some(value));
+        }
+        assert_eq!(&flash.mem[256 + 4 * 12 + 4..256 + 4 * 12 + 8], &25u32.to_le_bytes());
+        assert!(flash.mem[384..].iter().all(|&b| b == STATE_ERASE_VALUE));
+        assert!(flash.mem[..256].iter().all(|&b| b == STATE_ERASE_VALUE));
+    }
+
+    #[test]
+    #[cfg(feature = "security-counter")]
+    fn power_fail_while_raising() {
+        use core::cell::Cell;
+        use std::rc::Rc;
+
+        use crate::test_flash::PowerFailFlash;
+
+        let budget = Rc::new(Cell::new(usize::MAX));
+        let mut buf = [0; 12];
+        // Moving to the other page takes a write and an erase, or an erase, a write and an
+        // erase if the other page was not erased.
+        for cut in 0..3 {
+            let mut flash = PowerFailFlash::new(MemFlash::<512, 128, 4>::default(), &budget);
+            budget.set(usize::MAX);
+            for value in 1..=10 {
+                raise_to(&mut flash, value, &mut buf);
+            }
+            budget.set(cut);
+            assert!(raise(&mut flash, 11, &mut buf).is_err() || cut == 2);
+            let counter = read(&mut flash, &mut buf).unwrap().unwrap();
+            assert!(counter == 10 || counter == 11, "power cut after {} operations", cut);
+
+            budget.set(usize::MAX);
+            raise_to(&mut flash, 11, &mut buf);
+            assert_eq!(read(&mut flash, &mut buf).unwrap(), Some(11));
+            raise_to(&mut flash, 12, &mut buf);
+            assert_eq!(read(&mut flash, &mut buf).unwrap(), Some(12));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "security-counter")]
+    fn torn_record() {
+        let mut flash = MemFlash::<512, 128, 4>::default();
+        let mut buf = [0; 12];
+        raise_to(&mut flash, 5, &mut buf);
+        // A record cut short by a power failure.
+        flash.mem[268..272].copy_from_slice(&[1, 0, 0, 0]);
+        assert_eq!(read(&mut flash, &mut buf).unwrap(), Some(5));
+        raise_to(&mut flash, 6, &mut buf);
+        assert_eq!(read(&mut flash, &mut buf).unwrap(), Some(6));
+    }
+
+    #[test]
+    #[cfg(feature = "security-counter")]
+    fn uninitialized_pages() {
+        let mut flash = MemFlash::<512, 128, 4>::default();
+        flash.mem[128..].fill(0x5A);
+        let mut buf = [0; 12];
+        assert_eq!(read(&mut flash, &mut buf).unwrap(), Some(u32::MAX));
+        assert_eq!(
+            history(&mut flash, &mut [BootRecord::default(); 2], &mut buf).unwrap(),
+            0
+        );
+        raise_to(&mut flash, 2, &mut buf);
+        assert_eq!(read(&mut flash, &mut buf).unwrap(), Some(2));
+        assert!(flash.mem[256..384].iter().all(|&b| b == STATE_ERASE_VALUE));
+        assert!(flash.mem[396..].iter().all(|&b| b == STATE_ERASE_VALUE));
+    }
+
+    #[test]
+    #[cfg(feature = "security-counter")]
+    fn boot_history() {
+        let mut flash = MemFlash::<512, 128, 4>::default();
+        let mut buf = [0; 12];
+        raise_to(&mut flash, 1, &mut buf);
+        let counter_pages: [u8; 256] = flash.mem[256..].try_into().unwrap();
+        let events = [
+            (BootEvent::Boot, 0, 5),
+            (BootEvent::Swap, 1, 0),
+            (BootEvent::Unhealthy(42), 0, 0),
+            (BootEvent::Revert, 1, 3),
+        ];
+        for (event, attempt, reset_reason) in events {
+            let record = BootRecord {
+                event,
+                attempt,
+                reset_reason,
+                security_counter: 0,
+            };
+            append(&mut flash, record, &mut buf).unwrap();
+        }
+
+        let mut out = [BootRecord::default(); 3];
+        assert_eq!(history(&mut flash, &mut out, &mut buf).unwrap(), 3);
+        assert_eq!(out[0].event, BootEvent::Revert);
+        assert_eq!(out[0].reset_reason, 3);
+        assert_eq!(out[0].security_counter, 1);
+        assert_eq!(out[1].event, BootEvent::Unhealthy(42));
+        assert_eq!(out[2].event, BootEvent::Swap);
+        assert_eq!(out[2].attempt, 1);
+
+        let mut out = [BootRecord::default(); 8];
+        assert_eq!(
+            futures::executor::block_on(history_async(&mut flash, &mut out, &mut buf)).unwrap(),
+            4
+        );
+        assert_eq!(out[3].event, BootEvent::Boot);
+        assert_eq!(out[3].reset_reason, 5);
+
+        // Repeated boots are recorded once.
+        let boot = BootRecord {
+            event: BootEvent::Boot,
+            security_counter: 1,
+            ..Default::default()
+        };
+        for _ in 0..3 {
+            append(&mut flash, boot, &mut buf).unwrap();
+        }
+        let mut out = [BootRecord::default(); 2];
+        assert_eq!(history(&mut flash, &mut out, &mut buf).unwrap(), 2);
+        assert_eq!(
+            out,
+            [
+                boot,
+                BootRecord {
+                    event: BootEvent::Revert,
+                    attempt: 1,
+                    reset_reason: 3,
+                    security_counter: 1
+                }
+            ]
+        );
+
+        // The history wraps without touching the counter.
+        for _ in 0..20 {
+            let record = BootRecord {
+                event: BootEvent::Confirmed,
+                ..Default::default()
+            };
+            futures::executor::block_on(append_async(&mut flash, record, &mut buf)).unwrap();
+        }
+        assert_eq!(flash.mem[256..], counter_pages);
+        assert_eq!(read(&mut flash, &mut buf).unwrap(), Some(1));
+    }
+
+    #[test]
+    #[cfg(feature = "security-counter")]
+    fn async_read() {
+        let mut flash = MemFlash::<512, 128, 4>::default();
+        let mut buf = [0; 12];
+        raise_to(&mut flash, 0xFFFF_FFFE, &mut buf);
+        assert_eq!(
+            futures::executor::block_on(read_async(&mut flash, &mut buf)).unwrap(),
+            Some(0xFFFF_FFFE)
+        );
+    }
+}
@@ -0,0 +1,199 @@
+//! Streaming heatshrink decoder.
+
+use super::StreamError;
+
+#[derive(Copy, Clone)]
+enum Token {
+    Tag,
+    Literal,
+    Index,
+    Count { offset: usize },
+    Backref { offset: usize, remaining: usize },
+}
+
+/// Decoder for the heatshrink LZSS format, with a window of `WINDOW` bytes.
+///
+/// `WINDOW` and the lookahead size must match the `-w` and `-l` options of the encoder,
+/// as `WINDOW = 1 << w`.
+pub struct Heatshrink<const WINDOW: usize> {
+    window: [u8; WINDOW],
+    head: usize,
+    lookahead_bits: u8,
+    token: Token,
+    bits: u32,
+    bit_count: u8,
+}
+
+impl<const WINDOW: usize> Heatshrink<WINDOW> {
+    const WINDOW_BITS: u8 = WINDOW.trailing_zeros() as u8;
+
+    /// Create a decoder for a lookahead of `1 << lookahead_bits` bytes.
+    pub const fn new(lookahead_bits: u8) -> Self {
+        const {
+            core::assert!(WINDOW.is_power_of_two() && WINDOW >= 16 && WINDOW <= 1 << 15);
+        }
+        core::assert!(lookahead_bits >= 3 && lookahead_bits < Self::WINDOW_BITS);
+        Self {
+            window: [0; WINDOW],
+            head: 0,
+            lookahead_bits,
+            token: Token::Tag,
+            bits: 0,
+            bit_count: 0,
+        }
+    }
+
+    /// Take the next `count` bits of the input, most significant first.
+    fn take_bits(&mut self, input: &mut &[u8], count: u8) -> Option<usize> {
+        while self.bit_count < count {
+            let (&byte, rest) = input.split_first()?;
+            *input = rest;
+            self.bits = (self.bits << 8) | byte as u32;
+            self.bit_count += 8;
+        }
+        self.bit_count -= count;
+        let value = (self.bits >> self.bit_count) & ((1 << count) - 1);
+        self.bits &= (1 << self.bit_count) - 1;
+        Some(value as usize)
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.window[self.head] = byte;
+        self.head = (self.head + 1) % WINDOW;
+    }
+
+    /// Decode from `input` into `out`, consuming input as needed, and return the number of
+    /// bytes written. Fewer than `out.len()` bytes are written only when `input` is used up.
+    pub fn decode(&mut self, input: &mut &[u8], out: &mut [u8]) -> Result<usize, StreamError> {
+        let mut written = 0;
+        while written < out.len() {
+            match self.token {
+                Token::Tag => match self.take_bits(input, 1) {
+                    Some(1) => self.token = Token::Literal,
+                    Some(_) => self.token = Token::Index,
+                    None => break,
+                },
+                Token::Literal => {
+                    let Some(byte) = self.take_bits(input, 8) else { break };
+                    out[written] = byte as u8;
+                    written += 1;
+                    self.push(byte as u8);
+                    self.token = Token::Tag;
+                }
+                Token::Index => {
+                    let Some(index) = self.take_bits(input, Self::WINDOW_BITS) else {
+                        break;
+                    };
+                    self.token = Token::Count { offset: index + 1 };
+                }
+                Token::Count { offset } => {
+                    let Some(count) = self.take_bits(input, self.lookahead_bits) else {
+                        break;
+                    };
+                    self.token = Token::Backref {
+                        offset,
+                        remaining: count + 1,
+                    };
+                }
+                Token::Backref { offset, remaining } => {
+                    let byte = self.window[(self.head + WINDOW - offset) % WINDOW];
+                    out[written] = byte;
+                    written += 1;
+                    self.push(byte);
+                    self.token = match remaining {
+                        1 => Token::Tag,
+                        _ => Token::Backref {
+                            offset,
+                            remaining: remaining - 1,
+                        },
+                    };
+                }
+            }
+        }
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// Greedy heatshrink encoder, for tests.
+    pub(crate) fn encode(data: &[u8], window_bits: u8, lookahead_bits: u8) -> Vec<u8> {
+        let (window, lookahead) = (1usize << window_bits, 1usize << lookahead_bits);
+        let mut bits = Vec::new();
+        let push = |value: usize, count: u8, bits: &mut Vec<bool>| {
+            for i in (0..count).rev() {
+                bits.push(value >> i & 1 == 1);
+            }
+        };
+
+        let mut pos = 0;
+        while pos < data.len() {
+            let (mut best_len, mut best_offset) = (0, 0);
+            for offset in 1..=window.min(pos) {
+                let len = (0..lookahead.min(data.len() - pos))
+                    .take_while(|&i| data[pos - offset + i] == data[pos + i])
+                    .count();
+                if len > best_len {
+                    (best_len, best_offset) = (len, offset);
+                }
+            }
+            if best_len >= 2 {
+                push(0, 1, &mut bits);
+                push(best_offset - 1, window_bits, &mut bits);
+                push(best_len - 1, lookahead_bits, &mut bits);
+                pos += best_len;
+            } else {
+                push(1, 1, &mut bits);
+                push(data[pos] as usize, 8, &mut bits);
+                pos += 1;
+            }
+        }
+        bits.chunks(8)
+            .map(|byte| {
+                byte.iter()
+                    .enumerate()
+                    .fold(0, |acc, (i, &b)| acc | (b as u8) << (7 - i))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn decode_vector() {
+        // "abc" as literals, then a backref of 6 bytes at offset 3.
+        let mut input: &[u8] = &[0xb0, 0xd8, 0xac, 0x60, 0x25];
+        let mut decoder = Heatshrink::<256>::new(4);
+        let mut out = [0; 16];
+        assert_eq!(decoder.decode(&mut input, &mut out), Ok(9));
+        assert_eq!(&out[..9], b"abcabcabc");
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn roundtrip_in_chunks() {
+        let data: Vec<u8> = b"embassy-boot heatshrink ".iter().cycle().take(5000).copied().collect();
+        let encoded = encode(&data, 8, 4);
+        assert!(encoded.len() < data.len() / 4);
+
+        for chunk_len in [1, 7, 64, encoded.len()] {
+            let mut decoder = Heatshrink::<256>::new(4);
+            let mut decoded = Vec::new();
+            for mut chunk in encoded.chunks(chunk_len) {
+                let mut out = [0; 33];
+                loop {
+                    let n = decoder.decode(&mut chunk, &mut out).unwrap();
+                    decoded.extend_from_slice(&out[..n]);
+                    if n < out.len() {
+                        break;
+                    }
+                }
+            }
+            assert!(decoded == data);
+        }
+    }
+}
@@ -0,0 +1,255 @@
+//! Compressed and delta firmware update streams.
+//!
+//! An [`UpdateStream`] decodes an update as it arrives in chunks of any size and writes the
+//! resulting image to the DFU partition through
+//! [`FirmwareUpdater::write_stream`](crate::FirmwareUpdater::write_stream) or
+//! [`FirmwareUpdater::write_patch_stream`](crate::FirmwareUpdater::write_patch_stream).
+//! Once the stream is complete,
+//! [`FirmwareUpdater::finish_stream`](crate::FirmwareUpdater::finish_stream) checks the SHA-256
+//! hash of the image before it can be marked as updated.
+//!
+//! Updates can be compressed with [heatshrink](https://github.com/atomicobject/heatshrink), and
+//! can be patches against the image in the active partition. Patches start with
+//! [`PATCH_MAGIC`] and the size of the target image as a little-endian `u32`, followed by entries
+//! of three LEB128 encoded fields and their data, as in bsdiff:
+//!
+//! - `diff_len`, the number of bytes to add to the source at the current source offset.
+//! - `extra_len`, the number of bytes to copy as is.
+//! - `seek`, the zigzag encoded amount to move the source offset by after the entry.
+//!
+//! Each entry is followed by `diff_len` diff bytes and `extra_len` extra bytes.
+//! A compressed patch is the heatshrink compression of the whole patch.
+
+mod heatshrink;
+mod patch;
+
+use digest::Digest;
+use embedded_storage::nor_flash::{ErrorType, NorFlashErrorKind};
+use sha2::Sha256;
+
+pub use self::heatshrink::Heatshrink;
+pub use self::patch::{PATCH_MAGIC, Patch, PatchOutput};
+#[cfg(test)]
+pub(crate) use self::{
+    heatshrink::tests::encode as compress, patch::tests::Entry, patch::tests::encode as encode_patch,
+};
+
+/// Size of the buffer used to stage the decoded image before writing it to flash.
+/// Must be a multiple of the write size of the DFU partition.
+pub const STAGING_LEN: usize = 256;
+
+/// Size of the chunks read from the source image when applying a patch.
+pub(crate) const SOURCE_CHUNK_LEN: usize = 64;
+
+const MID_LEN: usize = 64;
+
+/// Errors from decoding an update stream.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StreamError {
+    /// The stream is malformed.
+    Malformed,
+    /// The stream is a patch, but no source image was given.
+    SourceRequired,
+    /// The stream ended before the image was complete.
+    Incomplete,
+}
+
+/// Progress of [`UpdateStream::fill`].
+pub(crate) enum Fill {
+    /// Bytes were added to the staging buffer.
+    Data,
+    /// Diff bytes were added to the staging buffer and must be applied with
+    /// [`UpdateStream::apply_source`] before anything else.
+    Diff { len: usize, source_offset: u32 },
+    /// All input was consumed.
+    NeedInput,
+    /// The patch is complete.
+    Done,
+}
+
+/// State of an update being received in chunks.
+///
+/// `WINDOW` is the heatshrink window size of compressed streams, 256 bytes by default.
+pub struct UpdateStream<const WINDOW: usize = 256> {
+    decompressor: Option<Heatshrink<WINDOW>>,
+    patch: Option<Patch>,
+    mid: [u8; MID_LEN],
+    mid_start: usize,
+    mid_end: usize,
+    staging: [u8; STAGING_LEN],
+    staged: usize,
+    offset: usize,
+    hasher: Sha256,
+}
+
+impl<const WINDOW: usize> UpdateStream<WINDOW> {
+    fn with(decompressor: Option<Heatshrink<WINDOW>>, patch: Option<Patch>) -> Self {
+        Self {
+            decompressor,
+            patch,
+            mid: [0; MID_LEN],
+            mid_start: 0,
+            mid_end: 0,
+            staging: [0; STAGING_LEN],
+            staged: 0,
+            offset: 0,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Stream of an uncompressed image.
+    pub fn raw() -> Self {
+        Self::with(None, None)
+    }
+
+    /// Stream of a heatshrink compressed image, with a lookahead of `1 << lookahead_bits` bytes.
+    pub fn compressed(lookahead_bits: u8) -> Self {
+        Self::with(Some(Heatshrink::new(lookahead_bits)), None)
+    }
+
+    /// Stream of an uncompressed patch.
+    pub fn patch() -> Self {
+        Self::with(None, Some(Patch::new()))
+    }
+
+    /// Stream of a heatshrink compressed patch, with a lookahead of `1 << lookahead_bits` bytes.
+    pub fn compressed_patch(lookahead_bits: u8) -> Self {
+        Self::with(Some(Heatshrink::new(lookahead_bits)), Some(Patch::new()))
+    }
+
+    /// Whether the stream is a patch and needs a source image.
+    pub fn is_patch(&self) -> bool {
+        self.patch.is_some()
+    }
+
+    /// Number of image bytes decoded so far.
+    pub fn len(&self) -> usize {
+        self.offset + self.staged
+    }
+
+    /// Whether no image bytes were decoded yet.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Decode from `input` into the staging buffer, which must not be full.
+    pub(crate) fn fill(&mut self, input: &mut &[u8]) -> Result<Fill, StreamError> {
+        let out = &mut self.staging[self.staged..];
+        let Some(patch) = &mut self.patch else {
+            let n = match &mut self.decompressor {
+                Some(decompressor) => decompressor.decode(input, out)?,
+                None => {
+                    let n = input.len().min(out.len());
+                    out[..n].copy_from_slice(&input[..n]);
+                    *input = &input[n..];
+                    n
+                }
+            };
+            if n == 0 {
+                return Ok(Fill::NeedInput);
+            }
+            self.commit(n);
+            return Ok(Fill::Data);
+        };
+
+        // Diffs are limited to what can be read from the source in one go.
+        let len = out.len().min(SOURCE_CHUNK_LEN);
+        let out = &mut out[..len];
+        let output = loop {
+            let Some(decompressor) = &mut self.decompressor else {
+                break patch.decode(input, out)?;
+            };
+            if self.mid_start == self.mid_end && !patch.is_done() {
+                self.mid_end = decompressor.decode(input, &mut self.mid)?;
+                self.mid_start = 0;
+                if self.mid_end == 0 {
+                    break PatchOutput::NeedInput;
+                }
+            }
+            let mut mid = &self.mid[self.mid_start..self.mid_end];
+            let available = mid.len();
+            let output = patch.decode(&mut mid, out)?;
+            self.mid_start += available - mid.len();
+            if output != PatchOutput::NeedInput {
+                break output;
+            }
+        };
+
+        match output {
+            PatchOutput::Data(n) => {
+                self.commit(n);
+                Ok(Fill::Data)
+            }
+            PatchOutput::Diff { len, source_offset } => Ok(Fill::Diff { len, source_offset }),
+            PatchOutput::NeedInput => Ok(Fill::NeedInput),
+            PatchOutput::Done => Ok(Fill::Done),
+        }
+    }
+
+    /// Add the `source` bytes to the diff bytes returned by the last [`UpdateStream::fill`].
+    pub(crate) fn apply_source(&mut self, source: &[u8]) {
+        for (byte, source) in self.staging[self.staged..][..source.len()].iter_mut().zip(source) {
+            *byte = byte.wrapping_add(*source);
+        }
+        self.commit(source.len());
+    }
+
+    fn commit(&mut self, n: usize) {
+        self.hasher.update(&self.staging[self.staged..][..n]);
+        self.staged += n;
+    }
+
+    /// Offset in the DFU partition and contents of the staging buffer, once it is full.
+    pub(crate) fn full(&self) -> Option<(usize, &[u8])> {
+        (self.staged == STAGING_LEN).then_some((self.offset, &self.staging[..]))
+    }
+
+    /// Mark the staging buffer as written.
+    pub(crate) fn flushed(&mut self) {
+        self.offset += self.staged;
+        self.staged = 0;
+    }
+
+    /// Finish the stream, returning the staged bytes padded to `write_size` and the image hash.
+    pub(crate) fn finish(&mut self, write_size: usize) -> Result<(usize, &[u8], [u8; 32]), StreamError> {
+        if self.patch.as_ref().is_some_and(|patch| !patch.is_done()) {
+            return Err(StreamError::Incomplete);
+        }
+        let hash = self.hasher.finalize_reset().into();
+        let len = self.staged.next_multiple_of(write_size);
+        self.staging[self.staged..len].fill(crate::STATE_ERASE_VALUE);
+        Ok((self.offset, &self.staging[..len], hash))
+    }
+}
+
+/// Source flash for streams that are not patches.
+pub(crate) struct NoSource;
+
+impl ErrorType for NoSource {
+    type Error = NorFlashErrorKind;
+}
+
+impl embedded_storage::nor_flash::ReadNorFlash for NoSource {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, _offset: u32, _bytes: &mut [u8]) -> Result<(), Self::Error> {
+        Err(NorFlashErrorKind::OutOfBounds)
+    }
+
+    fn capacity(&self) -> usize {
+        0
+    }
+}
+
+impl embedded_storage_async::nor_flash::ReadNorFlash for NoSource {
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, _offset: u32, _bytes: &mut [u8]) -> Result<(), Self::Error> {
+        Err(NorFlashErrorKind::OutOfBounds)
+    }
+
+    fn capacity(&self) -> usize {
+        0
+    }
+}
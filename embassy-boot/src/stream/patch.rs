@@ -0,0 +1,300 @@
+//! Streaming binary patch decoder.
+
+use super::StreamError;
+
+/// Magic number at the start of a patch.
+pub const PATCH_MAGIC: [u8; 4] = *b"EBDP";
+
+const HEADER_LEN: usize = 8;
+
+#[derive(Copy, Clone)]
+enum State {
+    Header { len: usize },
+    Control { field: usize, value: u32, shift: u32 },
+    Diff { remaining: u32 },
+    Extra { remaining: u32 },
+    Done,
+}
+
+/// A chunk of output from a [`Patch`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PatchOutput {
+    /// `len` bytes of the target were written.
+    Data(usize),
+    /// `len` diff bytes were written, to be added to the source bytes at `source_offset`.
+    Diff {
+        /// Number of diff bytes.
+        len: usize,
+        /// Offset of the matching bytes in the source.
+        source_offset: u32,
+    },
+    /// More input is needed.
+    NeedInput,
+    /// The target is complete.
+    Done,
+}
+
+/// Decoder for patches in the format of the [`stream`](super) module.
+pub struct Patch {
+    state: State,
+    header: [u8; HEADER_LEN],
+    target_size: u32,
+    written: u32,
+    control: [u32; 3],
+    source_offset: u32,
+}
+
+impl Default for Patch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Patch {
+    /// Create a decoder.
+    pub const fn new() -> Self {
+        Self {
+            state: State::Header { len: 0 },
+            header: [0; HEADER_LEN],
+            target_size: 0,
+            written: 0,
+            control: [0; 3],
+            source_offset: 0,
+        }
+    }
+
+    /// Size of the target, once the patch header has been decoded.
+    pub fn target_size(&self) -> Option<u32> {
+        match self.state {
+            State::Header { .. } => None,
+            _ => Some(self.target_size),
+        }
+    }
+
+    /// Whether the target is complete.
+    pub fn is_done(&self) -> bool {
+        self.target_size() == Some(self.written)
+    }
+
+    /// Decode from `input` into `out`, consuming input as needed. `out` must not be empty.
+    pub fn decode(&mut self, input: &mut &[u8], out: &mut [u8]) -> Result<PatchOutput, StreamError> {
+        loop {
+            match self.state {
+                State::Header { len } => {
+                    let n = (HEADER_LEN - len).min(input.len());
+                    self.header[len..len + n].copy_from_slice(&input[..n]);
+                    *input = &input[n..];
+                    if len + n < HEADER_LEN {
+                        self.state = State::Header { len: len + n };
+                        return Ok(PatchOutput::NeedInput);
+                    }
+                    if self.header[..4] != PATCH_MAGIC {
+                        return Err(StreamError::Malformed);
+                    }
+                    self.target_size =
+                        u32::from_le_bytes([self.header[4], self.header[5], self.header[6], self.header[7]]);
+                    self.state = self.next_control();
+                }
+                State::Control { field, value, shift } => {
+                    let Some((&byte, rest)) = input.split_first() else {
+                        return Ok(PatchOutput::NeedInput);
+                    };
+                    *input = rest;
+                    if shift > 28 || (shift == 28 && byte > 0x0f) {
+                        return Err(StreamError::Malformed);
+                    }
+                    let value = value | ((byte & 0x7f) as u32) << shift;
+                    if byte & 0x80 != 0 {
+                        self.state = State::Control {
+                            field,
+                            value,
+                            shift: shift + 7,
+                        };
+                        continue;
+                    }
+                    self.control[field] = value;
+                    if field < 2 {
+                        self.state = State::Control {
+                            field: field + 1,
+                            value: 0,
+                            shift: 0,
+                        };
+                        continue;
+                    }
+
+                    let [diff, extra, _] = self.control;
+                    let end = self.written as u64 + diff as u64 + extra as u64;
+                    if end > self.target_size as u64 || diff as u64 + extra as u64 == 0 {
+                        return Err(StreamError::Malformed);
+                    }
+                    self.state = State::Diff { remaining: diff };
+                }
+                State::Diff { remaining: 0 } => {
+                    self.state = State::Extra {
+                        remaining: self.control[1],
+                    };
+                }
+                State::Diff { remaining } => {
+                    let n = input.len().min(out.len()).min(remaining as usize);
+                    if n == 0 {
+                        return Ok(PatchOutput::NeedInput);
+                    }
+                    out[..n].copy_from_slice(&input[..n]);
+                    *input = &input[n..];
+                    let source_offset = self.source_offset;
+                    self.source_offset = self.source_offset.wrapping_add(n as u32);
+                    self.written += n as u32;
+                    self.state = State::Diff {
+                        remaining: remaining - n as u32,
+                    };
+                    return Ok(PatchOutput::Diff { len: n, source_offset });
+                }
+                State::Extra { remaining: 0 } => {
+                    // The seek is zigzag encoded.
+                    let seek = self.control[2];
+                    let seek = (seek >> 1) as i32 ^ -((seek & 1) as i32);
+                    self.source_offset = self.source_offset.wrapping_add_signed(seek);
+                    self.state = self.next_control();
+                }
+                State::Extra { remaining } => {
+                    let n = input.len().min(out.len()).min(remaining as usize);
+                    if n == 0 {
+                        return Ok(PatchOutput::NeedInput);
+                    }
+                    out[..n].copy_from_slice(&input[..n]);
+                    *input = &input[n..];
+                    self.written += n as u32;
+                    self.state = State::Extra {
+                        remaining: remaining - n as u32,
+                    };
+                    return Ok(PatchOutput::Data(n));
+                }
+                State::Done => return Ok(PatchOutput::Done),
+            }
+        }
+    }
+
+    fn next_control(&self) -> State {
+        if self.written == self.target_size {
+            State::Done
+        } else {
+            State::Control {
+                field: 0,
+                value: 0,
+                shift: 0,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// Patch entry: diff against the source at the current offset, extra bytes, and a seek.
+    pub(crate) struct Entry<'a> {
+        pub diff: &'a [u8],
+        pub extra: &'a [u8],
+        pub seek: i32,
+    }
+
+    fn varint(mut value: u32, out: &mut Vec<u8>) {
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    /// Encode a patch from its entries, for tests.
+    pub(crate) fn encode(target_size: u32, entries: &[Entry]) -> Vec<u8> {
+        let mut out = Vec::from(PATCH_MAGIC);
+        out.extend_from_slice(&target_size.to_le_bytes());
+        for entry in entries {
+            varint(entry.diff.len() as u32, &mut out);
+            varint(entry.extra.len() as u32, &mut out);
+            varint(((entry.seek << 1) ^ (entry.seek >> 31)) as u32, &mut out);
+            out.extend_from_slice(entry.diff);
+            out.extend_from_slice(entry.extra);
+        }
+        out
+    }
+
+    /// Apply a patch in memory, feeding it in chunks of `chunk_len` bytes.
+    pub(crate) fn apply(source: &[u8], patch: &[u8], chunk_len: usize) -> Result<Vec<u8>, StreamError> {
+        let mut decoder = Patch::new();
+        let mut target = Vec::new();
+        for mut chunk in patch.chunks(chunk_len) {
+            loop {
+                let mut out = [0; 5];
+                match decoder.decode(&mut chunk, &mut out)? {
+                    PatchOutput::Data(n) => target.extend_from_slice(&out[..n]),
+                    PatchOutput::Diff { len, source_offset } => {
+                        let source = &source[source_offset as usize..][..len];
+                        target.extend(out[..len].iter().zip(source).map(|(d, s)| d.wrapping_add(*s)));
+                    }
+                    PatchOutput::NeedInput | PatchOutput::Done => break,
+                }
+            }
+        }
+        assert!(decoder.is_done());
+        Ok(target)
+    }
+
+    #[test]
+    fn apply_in_chunks() {
+        let source: Vec<u8> = (0..=255).collect();
+        // Keep 0..10 with bytes 2 and 3 bumped, insert "xyz", skip to 200, and keep 200..206.
+        let patch = encode(
+            19,
+            &[
+                Entry {
+                    diff: &[0, 0, 1, 1, 0, 0, 0, 0, 0, 0],
+                    extra: b"xyz",
+                    seek: 190,
+                },
+                Entry {
+                    diff: &[0; 6],
+                    extra: &[],
+                    seek: -100,
+                },
+            ],
+        );
+        let mut expected: Vec<u8> = (0..10).collect();
+        expected[2] += 1;
+        expected[3] += 1;
+        expected.extend_from_slice(b"xyz");
+        expected.extend(200..206);
+
+        for chunk_len in [1, 3, patch.len()] {
+            assert!(apply(&source, &patch, chunk_len).unwrap() == expected);
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        let mut decoder = Patch::new();
+        let mut out = [0; 8];
+        assert_eq!(
+            decoder.decode(&mut &b"EBDX\x01\0\0\0"[..], &mut out),
+            Err(StreamError::Malformed)
+        );
+
+        // An entry going past the target size.
+        let patch = encode(
+            2,
+            &[Entry {
+                diff: &[],
+                extra: b"abc",
+                seek: 0,
+            }],
+        );
+        let mut decoder = Patch::new();
+        assert_eq!(decoder.decode(&mut &patch[..], &mut out), Err(StreamError::Malformed));
+    }
+}
@@ -1,5 +1,7 @@
 mod asynch;
 mod blocking;
+mod power_fail;
 
 pub(crate) use asynch::AsyncTestFlash;
 pub(crate) use blocking::BlockingTestFlash;
+pub(crate) use power_fail::PowerFailFlash;
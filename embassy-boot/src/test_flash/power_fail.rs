@@ -0,0 +1,63 @@
+extern crate std;
+
+use core::cell::Cell;
+use std::rc::Rc;
+
+use embedded_storage::nor_flash::{ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash};
+
+/// Flash that stops erasing and writing once a budget of operations, shared between
+/// partitions, runs out, as on a power failure.
+pub struct PowerFailFlash<F> {
+    flash: F,
+    budget: Rc<Cell<usize>>,
+}
+
+impl<F> PowerFailFlash<F> {
+    pub fn new(flash: F, budget: &Rc<Cell<usize>>) -> Self {
+        Self {
+            flash,
+            budget: budget.clone(),
+        }
+    }
+
+    fn consume(&self) -> Result<(), NorFlashErrorKind> {
+        match self.budget.get() {
+            0 => Err(NorFlashErrorKind::Other),
+            budget => {
+                self.budget.set(budget - 1);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<F: NorFlash> ErrorType for PowerFailFlash<F> {
+    type Error = NorFlashErrorKind;
+}
+
+impl<F: NorFlash> ReadNorFlash for PowerFailFlash<F> {
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.flash.read(offset, bytes).map_err(|e| e.kind())
+    }
+
+    fn capacity(&self) -> usize {
+        self.flash.capacity()
+    }
+}
+
+impl<F: NorFlash> NorFlash for PowerFailFlash<F> {
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.consume()?;
+        self.flash.erase(from, to).map_err(|e| e.kind())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.consume()?;
+        self.flash.write(offset, bytes).map_err(|e| e.kind())
+    }
+}
@@ -0,0 +1,483 @@
+//! Signature verification backends.
+//!
+//! A [`Verifier`] checks the signature of an update, either over a digest of the raw DFU
+//! contents with `FirmwareUpdater::verify_and_mark_updated_with`, or over the hash of an
+//! [image](crate::image) with `FirmwareUpdater::verify_image_and_mark_updated_with`.
+//!
+//! Software backends are provided behind features, and a hardware accelerator can be used
+//! by implementing [`Verifier`] on top of it.
+
+use digest::Digest;
+use signature::Error;
+
+/// Longest signature read from an image trailer, an RSA-3072 signature.
+pub(crate) const MAX_SIGNATURE_LEN: usize = 384;
+
+/// A signature verification backend.
+pub trait Verifier {
+    /// The digest the signature is computed over, when signing raw DFU contents.
+    type Digest: Digest;
+
+    /// The TLV type holding this kind of signature in an image trailer.
+    fn tlv_type(&self) -> u16;
+
+    /// Verify `signature` over `digest`.
+    ///
+    /// `digest` is computed with [`Self::Digest`] for raw DFU contents, and is the SHA-256
+    /// image hash for images.
+    fn verify(&mut self, digest: &[u8], signature: &[u8]) -> Result<(), Error>;
+}
+
+/// Ed25519 signatures, verified with `ed25519-dalek` or `salty`.
+///
+/// For raw DFU contents the signed message is the SHA-512 digest of the firmware, for images
+/// it is the image hash.
+#[cfg(any(feature = "ed25519-dalek", feature = "ed25519-salty"))]
+pub struct Ed25519 {
+    public_key: [u8; 32],
+}
+
+#[cfg(any(feature = "ed25519-dalek", feature = "ed25519-salty"))]
+impl Ed25519 {
+    /// Create a verifier for a public key.
+    pub const fn new(public_key: [u8; 32]) -> Self {
+        Self { public_key }
+    }
+}
+
+#[cfg(any(feature = "ed25519-dalek", feature = "ed25519-salty"))]
+impl Verifier for Ed25519 {
+    type Digest = crate::digest_adapters::Sha512;
+
+    fn tlv_type(&self) -> u16 {
+        crate::image::TLV_ED25519
+    }
+
+    fn verify(&mut self, digest: &[u8], signature: &[u8]) -> Result<(), Error> {
+        let signature: &[u8; 64] = signature.try_into().map_err(|_| Error::new())?;
+
+        #[cfg(feature = "ed25519-dalek")]
+        {
+            use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+            let public_key = VerifyingKey::from_bytes(&self.public_key)?;
+            let signature = Signature::from_bytes(signature);
+
+            public_key.verify(digest, &signature)
+        }
+        #[cfg(all(feature = "ed25519-salty", not(feature = "ed25519-dalek")))]
+        {
+            use salty::{PublicKey, Signature};
+
+            use crate::fmt::Bytes;
+
+            let public_key = PublicKey::try_from(&self.public_key).map_err(|_| Error::new())?;
+            let signature = Signature::try_from(signature).map_err(|_| Error::new())?;
+
+            let r = public_key.verify(digest, &signature);
+            trace!(
+                "Verifying with public key {}, signature {} and message {} yields ok: {}",
+                Bytes(&public_key.to_bytes()),
+                Bytes(&signature.to_bytes()),
+                Bytes(digest),
+                r.is_ok()
+            );
+            r.map_err(|_| Error::new())
+        }
+    }
+}
+
+/// ECDSA P-256 signatures over SHA-256, verified with `p256`.
+///
+/// Signatures are accepted both as 64 bytes of `r || s` and DER encoded.
+#[cfg(feature = "ecdsa-p256")]
+pub struct EcdsaP256 {
+    key: p256::ecdsa::VerifyingKey,
+}
+
+#[cfg(feature = "ecdsa-p256")]
+impl EcdsaP256 {
+    /// Create a verifier for a SEC1 encoded public key, compressed or not.
+    pub fn new(public_key: &[u8]) -> Result<Self, Error> {
+        let key = p256::ecdsa::VerifyingKey::from_sec1_bytes(public_key)?;
+        Ok(Self { key })
+    }
+}
+
+#[cfg(feature = "ecdsa-p256")]
+impl Verifier for EcdsaP256 {
+    type Digest = sha2::Sha256;
+
+    fn tlv_type(&self) -> u16 {
+        crate::image::TLV_ECDSA_P256
+    }
+
+    fn verify(&mut self, digest: &[u8], signature: &[u8]) -> Result<(), Error> {
+        use p256::ecdsa::Signature;
+        use signature::hazmat::PrehashVerifier;
+
+        let signature = if signature.len() == 64 {
+            Signature::from_slice(signature)?
+        } else {
+            Signature::from_der(signature)?
+        };
+        self.key.verify_prehash(digest, &signature)
+    }
+}
+
+/// Padding scheme of RSA signatures.
+#[cfg(feature = "rsa")]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RsaPadding {
+    /// PKCS#1 v1.5 padding.
+    Pkcs1v15,
+    /// PSS padding with MGF1, both using SHA-256, and a 32 byte salt as used by `imgtool`.
+    Pss,
+}
+
+/// RSA signatures over SHA-256, verified with the `rsa` crate.
+///
+/// The `rsa` crate allocates, so a global allocator is needed. Use the [`Rsa2048`] and
+/// [`Rsa3072`] aliases.
+#[cfg(feature = "rsa")]
+pub struct Rsa<const BITS: usize> {
+    key: RsaKey,
+}
+
+#[cfg(feature = "rsa")]
+enum RsaKey {
+    Pkcs1v15(rsa::pkcs1v15::VerifyingKey<sha2::Sha256>),
+    Pss(rsa::pss::VerifyingKey<sha2::Sha256>),
+}
+
+/// RSA-2048 signatures over SHA-256.
+#[cfg(feature = "rsa")]
+pub type Rsa2048 = Rsa<2048>;
+
+/// RSA-3072 signatures over SHA-256.
+#[cfg(feature = "rsa")]
+pub type Rsa3072 = Rsa<3072>;
+
+#[cfg(feature = "rsa")]
+impl<const BITS: usize> Rsa<BITS> {
+    /// Create a verifier for a public key, from its big-endian modulus and its public exponent.
+    ///
+    /// The modulus must use all of its 2048 or 3072 bits.
+    pub fn new(modulus: &[u8], exponent: u32, padding: RsaPadding) -> Result<Self, Error> {
+        use rsa::{BigUint, RsaPublicKey};
+
+        const { core::assert!(BITS == 2048 || BITS == 3072) }
+        if modulus.len() != BITS / 8 || modulus[0] & 0x80 == 0 {
+            return Err(Error::new());
+        }
+        let key =
+            RsaPublicKey::new(BigUint::from_bytes_be(modulus), BigUint::from(exponent)).map_err(|_| Error::new())?;
+        let key = match padding {
+            RsaPadding::Pkcs1v15 => RsaKey::Pkcs1v15(rsa::pkcs1v15::VerifyingKey::new(key)),
+            RsaPadding::Pss => RsaKey::Pss(rsa::pss::VerifyingKey::new(key)),
+        };
+        Ok(Self { key })
+    }
+}
+
+#[cfg(feature = "rsa")]
+impl<const BITS: usize> Verifier for Rsa<BITS> {
+    type Digest = sha2::Sha256;
+
+    fn tlv_type(&self) -> u16 {
+        use crate::image::{TLV_RSA2048_PKCS1V15, TLV_RSA2048_PSS, TLV_RSA3072_PKCS1V15, TLV_RSA3072_PSS};
+
+        match (BITS, &self.key) {
+            (2048, RsaKey::Pkcs1v15(_)) => TLV_RSA2048_PKCS1V15,
+            (2048, RsaKey::Pss(_)) => TLV_RSA2048_PSS,
+            (_, RsaKey::Pkcs1v15(_)) => TLV_RSA3072_PKCS1V15,
+            (_, RsaKey::Pss(_)) => TLV_RSA3072_PSS,
+        }
+    }
+
+    fn verify(&mut self, digest: &[u8], signature: &[u8]) -> Result<(), Error> {
+        use signature::hazmat::PrehashVerifier;
+
+        match &self.key {
+            RsaKey::Pkcs1v15(key) => key.verify_prehash(digest, &signature.try_into()?),
+            RsaKey::Pss(key) => key.verify_prehash(digest, &signature.try_into()?),
+        }
+    }
+}
+
+#[cfg(all(test, any(feature = "rsa", feature = "ecdsa-p256")))]
+mod tests {
+    #![allow(unused_imports)]
+
+    use futures::executor::block_on;
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+    use crate::mem_flash::MemFlash;
+    use crate::{FirmwareUpdater, FirmwareUpdaterConfig, FirmwareUpdaterError, State};
+
+    const FIRMWARE: &[u8] = b"This are bytes that would otherwise be firmware bytes for DFU.";
+
+    #[cfg(feature = "rsa")]
+    use vectors::*;
+
+    #[cfg(feature = "rsa")]
+    mod vectors {
+        pub const RSA2048_MODULUS: [u8; 256] = [
+            0xad, 0x65, 0x8c, 0x97, 0x7d, 0x70, 0x63, 0x7c, 0xf3, 0x47, 0xd1, 0x42, 0x95, 0x0b, 0xeb, 0x56, 0x1a, 0xac,
+            0x3d, 0xe4, 0x74, 0x3f, 0xeb, 0xec, 0x81, 0xdb, 0x6b, 0x5c, 0x0d, 0xff, 0x96, 0x73, 0xa4, 0xbc, 0x97, 0x0f,
+            0x72, 0xe8, 0x19, 0x6f, 0xad, 0xee, 0x77, 0xd2, 0xd8, 0x1d, 0xed, 0x17, 0x6b, 0x80, 0x2b, 0x5b, 0x30, 0xec,
+            0xf8, 0x51, 0xf1, 0x96, 0x31, 0x59, 0xac, 0x3c, 0x4c, 0x6a, 0xf4, 0x12, 0x12, 0x7d, 0x8a, 0x19, 0x0f, 0x91,
+            0x6e, 0x65, 0x6e, 0x91, 0xfe, 0xb6, 0xe5, 0x10, 0x00, 0x53, 0x8f, 0xe5, 0xaa, 0x96, 0x21, 0x70, 0xcb, 0x5d,
+            0xb2, 0x5c, 0x38, 0x4a, 0xec, 0xf5, 0xd3, 0x06, 0x0b, 0x5d, 0x88, 0x25, 0xaa, 0x9d, 0x86, 0xb0, 0x42, 0x6e,
+            0xf0, 0xec, 0x41, 0x94, 0x56, 0xd6, 0x3d, 0x0f, 0x3d, 0xf7, 0x09, 0x07, 0x8b, 0xe2, 0x14, 0x8d, 0xab, 0x2b,
+            0x41, 0x26, 0x51, 0xaa, 0x0e, 0x1c, 0x6f, 0x77, 0xc9, 0xf3, 0x43, 0x8e, 0xbd, 0x83, 0x38, 0xca, 0x0d, 0x74,
+            0x58, 0x43, 0xa8, 0xa1, 0x82, 0x7a, 0x9f, 0x0b, 0x11, 0x9d, 0x7f, 0x3d, 0x46, 0x16, 0x6a, 0x67, 0xc4, 0x22,
+            0x55, 0x7d, 0xd6, 0x61, 0xca, 0x6d, 0x8d, 0x35, 0x29, 0xc6, 0xb4, 0x80, 0x1e, 0xc9, 0x6e, 0x73, 0x39, 0x22,
+            0xc5, 0x62, 0xe7, 0xd9, 0x23, 0x7c, 0xb7, 0x85, 0x12, 0xa5, 0x6c, 0x34, 0xab, 0x86, 0x83, 0xbe, 0xc0, 0x46,
+            0xf0, 0xf6, 0xac, 0x2a, 0xe8, 0xef, 0xed, 0x23, 0x3f, 0x1b, 0xaf, 0x18, 0x2e, 0x77, 0x6f, 0xb9, 0x6d, 0xa6,
+            0x7b, 0x79, 0x66, 0x16, 0x2e, 0xd3, 0x01, 0x45, 0x24, 0x4f, 0xa9, 0x37, 0x62, 0x83, 0x52, 0xd7, 0xfb, 0xd6,
+            0x55, 0x8d, 0x9e, 0xce, 0xe4, 0x57, 0xfb, 0x9e, 0x9f, 0x28, 0xcc, 0x11, 0x26, 0x29, 0xc1, 0x67, 0xb9, 0xbd,
+            0x96, 0x09, 0x12, 0xc9,
+        ];
+
+        pub const RSA2048_PKCS1V15_SIGNATURE: [u8; 256] = [
+            0x5c, 0xa4, 0x93, 0x4d, 0xeb, 0x1f, 0xd1, 0x5a, 0xef, 0x87, 0x58, 0x24, 0x34, 0xa3, 0x43, 0xec, 0x47, 0x85,
+            0x3d, 0x42, 0x53, 0x74, 0x67, 0x71, 0x5b, 0xf5, 0xf2, 0xb3, 0xc9, 0x7b, 0x5f, 0xf1, 0x5a, 0x76, 0x9d, 0x2f,
+            0xf9, 0x61, 0xde, 0x5a, 0x2a, 0x50, 0x6d, 0x19, 0x1a, 0x82, 0x0c, 0x5f, 0xf3, 0x84, 0x6d, 0x0f, 0xdd, 0xc5,
+            0x6e, 0xa4, 0x63, 0xf8, 0x71, 0x49, 0xbd, 0xa3, 0x26, 0x51, 0x40, 0x1f, 0x3e, 0xd3, 0xb6, 0x0c, 0x69, 0xe7,
+            0x74, 0x51, 0x32, 0x38, 0xe1, 0xe5, 0x83, 0x18, 0xd5, 0xee, 0x4c, 0x01, 0x50, 0xa3, 0x6c, 0x2e, 0x66, 0x8a,
+            0xb1, 0xdf, 0xd3, 0x00, 0xbe, 0x16, 0x18, 0x8e, 0x7d, 0x44, 0x63, 0x3f, 0x10, 0x29, 0xb5, 0xf4, 0xeb, 0x52,
+            0xa3, 0x67, 0xbd, 0x28, 0xe7, 0xeb, 0x22, 0x08, 0xbb, 0xc4, 0xf9, 0x01, 0x94, 0x1f, 0x21, 0xb0, 0xeb, 0x7f,
+            0x55, 0xc0, 0x8d, 0x8b, 0x0e, 0xd8, 0x56, 0xa1, 0xfc, 0x04, 0x00, 0x45, 0x55, 0x96, 0x06, 0x03, 0x48, 0xbb,
+            0x12, 0xaf, 0x3e, 0xa4, 0xdd, 0x9b, 0x73, 0x50, 0xf9, 0x47, 0x81, 0x72, 0xdf, 0x8e, 0x85, 0xfd, 0x3f, 0xd4,
+            0x4e, 0x86, 0x9c, 0xa0, 0xc3, 0x8e, 0xe0, 0x33, 0x9f, 0x08, 0xc2, 0x26, 0x2b, 0x02, 0x80, 0xb8, 0xf2, 0x39,
+            0x68, 0xe4, 0xee, 0xc1, 0xe2, 0x55, 0xa2, 0xc9, 0x43, 0x38, 0xb4, 0xd7, 0xed, 0x12, 0x2a, 0xab, 0x8e, 0x41,
+            0x3c, 0xff, 0x54, 0xb1, 0x6c, 0x3b, 0x38, 0x07, 0xc7, 0x54, 0x75, 0xf7, 0xd0, 0x0e, 0xc0, 0x7b, 0xf5, 0x25,
+            0x0e, 0x54, 0x32, 0x69, 0x35, 0x19, 0x95, 0xcb, 0x05, 0x71, 0x19, 0xb3, 0x2a, 0x19, 0x7c, 0x84, 0xad, 0x82,
+            0x91, 0x50, 0x11, 0xc2, 0x39, 0x19, 0x94, 0x14, 0xef, 0x2a, 0xab, 0xf4, 0x8e, 0x11, 0x25, 0x42, 0xed, 0xd0,
+            0xfe, 0xf5, 0x58, 0x89,
+        ];
+
+        pub const RSA2048_PSS_SIGNATURE: [u8; 256] = [
+            0x64, 0x52, 0x18, 0x86, 0x1a, 0xcf, 0x40, 0x0d, 0xd6, 0xd9, 0xd5, 0xcf, 0x76, 0x9b, 0x19, 0xa2, 0xa0, 0xec,
+            0xed, 0x40, 0x8e, 0xd4, 0x66, 0xc3, 0xbb, 0xbf, 0x59, 0x8d, 0xfa, 0xcc, 0x79, 0x9c, 0x8d, 0x5d, 0x6b, 0xc6,
+            0x28, 0xcc, 0x37, 0x3f, 0xa5, 0x80, 0x34, 0xe5, 0x89, 0x27, 0x3f, 0xcf, 0x8b, 0xb6, 0x17, 0x48, 0x88, 0x7c,
+            0xea, 0xfb, 0xd8, 0xa7, 0x02, 0x48, 0x2b, 0x90, 0x1c, 0x26, 0xf8, 0x49, 0x05, 0xd2, 0x43, 0xdf, 0x30, 0x33,
+            0x0d, 0x29, 0xd2, 0xd3, 0x29, 0x6e, 0x16, 0x2f, 0xa8, 0xcb, 0xcd, 0x37, 0xcc, 0x90, 0x39, 0xa1, 0x9d, 0xc2,
+            0x08, 0xd2, 0x2e, 0x55, 0xe0, 0x05, 0x35, 0x5f, 0xfa, 0x3f, 0x6c, 0x92, 0x39, 0x07, 0x3b, 0x25, 0xcf, 0xe7,
+            0xb8, 0x94, 0x22, 0xc7, 0xaa, 0x80, 0xc7, 0x54, 0xc9, 0xb7, 0x25, 0xf5, 0xca, 0x52, 0x81, 0x71, 0xda, 0x94,
+            0xed, 0x4f, 0x4a, 0xef, 0x6b, 0x12, 0x6a, 0x97, 0x1e, 0x08, 0x84, 0x99, 0x58, 0xf4, 0x41, 0x3e, 0x66, 0x8b,
+            0xc2, 0xc7, 0x81, 0xf9, 0x8a, 0x15, 0x90, 0xb2, 0x09, 0x17, 0x37, 0xb3, 0x05, 0xc4, 0xb6, 0xaa, 0x6e, 0x1b,
+            0x95, 0xd3, 0xec, 0x0c, 0xc5, 0xdf, 0xd7, 0x7a, 0x91, 0x2f, 0x78, 0x92, 0x6e, 0x10, 0x5a, 0xf9, 0x7a, 0xe2,
+            0xf5, 0x94, 0x91, 0xf0, 0xa6, 0x11, 0x51, 0xb0, 0xe4, 0xc9, 0xbc, 0xa6, 0x80, 0x16, 0x6c, 0x5b, 0xd2, 0x24,
+            0x47, 0x1a, 0x0b, 0xc5, 0xa7, 0x60, 0xef, 0xb2, 0x39, 0xee, 0xdc, 0xad, 0x00, 0x98, 0xfe, 0x77, 0xfb, 0x43,
+            0xb9, 0xc6, 0x65, 0x8e, 0xb1, 0x2f, 0xef, 0x16, 0xc1, 0x34, 0x50, 0x34, 0x6e, 0x04, 0xf6, 0xc9, 0x8d, 0x49,
+            0xd4, 0xe7, 0x9e, 0x13, 0x6a, 0xc7, 0xa4, 0xdf, 0x8f, 0x0c, 0x64, 0x27, 0x01, 0x52, 0x5f, 0xd4, 0x6a, 0x2f,
+            0x46, 0x6a, 0x0a, 0x59,
+        ];
+
+        pub const RSA3072_MODULUS: [u8; 384] = [
+            0xc6, 0xdc, 0x78, 0x49, 0xe4, 0x96, 0xa4, 0x43, 0x99, 0x09, 0x0a, 0xf9, 0x9d, 0xaf, 0xf0, 0x9c, 0x30, 0x60,
+            0xd1, 0x0a, 0x18, 0x5d, 0xef, 0x32, 0x5b, 0xc7, 0x6c, 0xdf, 0x2a, 0xab, 0xbc, 0xc3, 0x29, 0x4b, 0xd8, 0xfe,
+            0x91, 0xff, 0x24, 0x9f, 0x62, 0x8b, 0x5e, 0x7c, 0x32, 0x43, 0x33, 0xbd, 0x54, 0xf9, 0xe0, 0x1b, 0x84, 0x01,
+            0x0b, 0x4d, 0xf2, 0xa8, 0x14, 0x95, 0x38, 0x63, 0x5a, 0xc7, 0x75, 0x6f, 0x81, 0x39, 0x91, 0x3c, 0x4b, 0x75,
+            0x25, 0x8c, 0x71, 0x1d, 0x0a, 0x70, 0xd3, 0xf1, 0xc8, 0x55, 0x15, 0x3f, 0x3c, 0x88, 0xac, 0xa9, 0x44, 0x10,
+            0x8c, 0xd1, 0x73, 0xbb, 0xe7, 0xed, 0x6e, 0x66, 0xab, 0xca, 0x9e, 0x4f, 0x9d, 0x61, 0xcd, 0x7c, 0x66, 0x65,
+            0x73, 0x37, 0xcb, 0xbb, 0x1e, 0xf0, 0x46, 0x60, 0xad, 0xf3, 0xa9, 0x7e, 0xd5, 0xfc, 0x70, 0x0e, 0x70, 0x63,
+            0x44, 0x1d, 0x5b, 0x0d, 0x39, 0xc2, 0x04, 0xd3, 0xeb, 0x53, 0x10, 0x66, 0x65, 0xff, 0xf3, 0x8a, 0x2b, 0xe9,
+            0x91, 0x1e, 0x62, 0xcf, 0x00, 0x07, 0xc0, 0xe7, 0xaf, 0x46, 0xf2, 0x23, 0x3a, 0x7c, 0x03, 0xff, 0x7a, 0x29,
+            0xb8, 0x27, 0xd2, 0xb9, 0xf2, 0x08, 0x4c, 0x6c, 0x42, 0xbb, 0x6f, 0xc6, 0xd5, 0x1e, 0x2d, 0xb2, 0x7b, 0x9b,
+            0xeb, 0xa5, 0xb5, 0x6d, 0x68, 0xa9, 0xec, 0xf1, 0x2e, 0x00, 0x95, 0xba, 0xa1, 0xc2, 0x55, 0xf7, 0xfe, 0xda,
+            0x82, 0x24, 0xd5, 0x8f, 0x0b, 0xb0, 0x92, 0xdf, 0xd2, 0xcc, 0x37, 0x93, 0x22, 0x56, 0x5e, 0xe4, 0x69, 0x54,
+            0xe2, 0xb9, 0x10, 0x86, 0x4f, 0xb9, 0xad, 0x29, 0xb2, 0xf0, 0x02, 0x9a, 0x56, 0x07, 0xe6, 0x88, 0x7c, 0x06,
+            0xeb, 0x3a, 0x6d, 0x06, 0x71, 0x8f, 0x47, 0xfa, 0x16, 0xb0, 0x93, 0x28, 0x52, 0x98, 0x6b, 0xbf, 0x6b, 0x1d,
+            0xa6, 0xee, 0xb8, 0x1e, 0x5f, 0x97, 0xf5, 0xa1, 0x7e, 0xaa, 0x09, 0x2f, 0xa1, 0x5e, 0x86, 0xbf, 0xf8, 0x2b,
+            0x3b, 0x1c, 0x7d, 0x4d, 0x88, 0xa1, 0xd2, 0x1b, 0xd8, 0xa2, 0x0a, 0x57, 0x9a, 0x01, 0x4d, 0xf1, 0xf5, 0x9d,
+            0x91, 0xd6, 0x55, 0xfb, 0x64, 0xf7, 0xc4, 0x22, 0xd2, 0xad, 0xbd, 0x87, 0x41, 0xb6, 0x97, 0x12, 0x91, 0x8e,
+            0xb9, 0xed, 0x42, 0x41, 0x9d, 0x55, 0x54, 0x85, 0xfa, 0x06, 0x8a, 0x1d, 0x3f, 0xe0, 0xfb, 0x30, 0x07, 0x09,
+            0xf3, 0xd5, 0x99, 0xb4, 0x7d, 0x70, 0x64, 0xc6, 0xc9, 0x36, 0x1d, 0xd3, 0x6c, 0xe6, 0x93, 0x03, 0xd2, 0xe7,
+            0x65, 0x6d, 0xee, 0xc5, 0xfa, 0x28, 0x88, 0x13, 0xe1, 0x18, 0x17, 0xeb, 0xb3, 0xd5, 0xfc, 0x9b, 0x6b, 0x69,
+            0x07, 0x2e, 0x25, 0xdb, 0x90, 0x1b, 0xc7, 0xad, 0x4b, 0xec, 0x1a, 0x22, 0xa5, 0x8c, 0x14, 0x86, 0x84, 0xfc,
+            0x46, 0x37, 0x27, 0xcf, 0x09, 0xc7,
+        ];
+
+        pub const RSA3072_PKCS1V15_SIGNATURE: [u8; 384] = [
+            0x9c, 0x4e, 0x27, 0xc3, 0x28, 0x85, 0xf3, 0x78, 0xf2, 0xfc, 0xc5, 0x36, 0x1d, 0xcd, 0x55, 0x1d, 0xf9, 0xe7,
+            0x75, 0xd4, 0x33, 0x13, 0x2d, 0x0a, 0x12, 0x53, 0x34, 0x8f, 0x5d, 0xbc, 0xde, 0x26, 0x15, 0xd7, 0xcf, 0xae,
+            0xde, 0x51, 0x7d, 0x15, 0x68, 0x92, 0xf3, 0x6e, 0x2e, 0xc8, 0x18, 0x0f, 0xcb, 0x4b, 0x0e, 0x0c, 0x28, 0x1f,
+            0xaf, 0x39, 0x7d, 0x8f, 0xc6, 0x11, 0xaa, 0xaa, 0xe8, 0xd2, 0x9b, 0x22, 0x74, 0x0a, 0xb5, 0xa6, 0x5d, 0x68,
+            0x31, 0xe0, 0x27, 0x45, 0x20, 0xd6, 0xcc, 0x9e, 0x19, 0xfe, 0x58, 0x89, 0x0f, 0x2d, 0x11, 0xed, 0x66, 0xa2,
+            0x6c, 0x0d, 0x24, 0xc8, 0xb6, 0x4b, 0x21, 0x50, 0xb8, 0x51, 0x34, 0xd2, 0x74, 0xfc, 0xf7, 0xde, 0xb4, 0xb7,
+            0x86, 0xa7, 0x6a, 0x87, 0xdb, 0x4d, 0x1d, 0xbf, 0xa3, 0xdd, 0xb3, 0x8b, 0xa6, 0x87, 0x5c, 0x12, 0xac, 0x17,
+            0x65, 0x4b, 0x12, 0x72, 0x53, 0x3c, 0xb8, 0x64, 0x52, 0xc6, 0x87, 0xa1, 0x10, 0x00, 0x34, 0x6f, 0x9d, 0xd3,
+            0xaf, 0x99, 0xcd, 0xd7, 0xd4, 0xfb, 0xd3, 0x61, 0x94, 0xc4, 0xf7, 0xde, 0x8a, 0x22, 0xc9, 0xec, 0x8d, 0x42,
+            0xaf, 0x8f, 0x86, 0x7e, 0x4b, 0x75, 0x1f, 0xc0, 0x28, 0x9f, 0xe9, 0x17, 0x7a, 0xd4, 0x78, 0x80, 0x07, 0x7a,
+            0x79, 0x56, 0x1c, 0xf9, 0xa5, 0xe4, 0x66, 0x2c, 0x91, 0x06, 0x65, 0x00, 0xa5, 0x3b, 0x63, 0x3d, 0x49, 0x16,
+            0xf3, 0xd1, 0xb7, 0x52, 0x46, 0xbf, 0x10, 0x09, 0x14, 0x2f, 0x8f, 0x1d, 0x56, 0x0c, 0x7a, 0xc9, 0xe1, 0xcb,
+            0x12, 0x56, 0x46, 0x88, 0x9b, 0x29, 0x29, 0x40, 0x7b, 0x12, 0x94, 0x51, 0x45, 0x92, 0x94, 0x0d, 0xe0, 0x18,
+            0xb2, 0x6d, 0x3f, 0xc3, 0x54, 0x8b, 0xc2, 0xb6, 0x04, 0x7b, 0x94, 0x1e, 0xd8, 0xd1, 0xc9, 0x2f, 0x1e, 0xe4,
+            0xfc, 0x2b, 0x19, 0xc8, 0x11, 0x3d, 0x2f, 0x0b, 0x06, 0x64, 0x29, 0x30, 0xc2, 0x84, 0x21, 0xa0, 0x65, 0xed,
+            0x83, 0x6f, 0xb3, 0x0e, 0xa9, 0xf1, 0x29, 0x1e, 0xb4, 0xc9, 0x5d, 0x93, 0x57, 0xc8, 0x51, 0x41, 0x23, 0x85,
+            0xe7, 0x1d, 0x3f, 0x3a, 0x94, 0x30, 0xfe, 0x59, 0xbb, 0x89, 0xb9, 0x45, 0x8e, 0x6b, 0xec, 0xc6, 0x73, 0xaf,
+            0x85, 0x97, 0x55, 0x6a, 0x2d, 0xb0, 0x81, 0x01, 0x8e, 0xfa, 0xd5, 0x6f, 0x7d, 0xc9, 0x45, 0xe7, 0xd8, 0xfd,
+            0xdd, 0xe7, 0xa8, 0xfc, 0xf1, 0x5c, 0x50, 0x5a, 0x2e, 0xe0, 0x61, 0xb7, 0x19, 0x68, 0x4b, 0x10, 0x34, 0xd4,
+            0xdf, 0xe9, 0x43, 0xe7, 0x4e, 0x10, 0xaf, 0x91, 0xe1, 0x3d, 0x53, 0xb8, 0x42, 0x8b, 0x68, 0x6f, 0xca, 0x5e,
+            0xe7, 0xd1, 0xa3, 0xc6, 0x75, 0xc0, 0x40, 0x7f, 0xd9, 0x41, 0xf9, 0x6f, 0x08, 0x11, 0xa8, 0xd0, 0x4b, 0xe7,
+            0x6d, 0xb2, 0xc8, 0xfa, 0xf6, 0xf6,
+        ];
+
+        pub const RSA3072_PSS_SIGNATURE: [u8; 384] = [
+            0x22, 0xca, 0x08, 0x83, 0x97, 0x2d, 0x89, 0x9d, 0x28, 0x70, 0x1e, 0x83, 0x28, 0x44, 0xf0, 0xea, 0xd3, 0xb6,
+            0xaa, 0x8a, 0x3c, 0x5b, 0x28, 0x3f, 0x5c, 0x9e, 0x8f, 0xc5, 0x7d, 0x98, 0x9b, 0x74, 0x04, 0xd5, 0x93, 0x1d,
+            0x03, 0xd2, 0xae, 0x5a, 0xee, 0x6d, 0x26, 0xab, 0x5c, 0x15, 0x7b, 0x7b, 0xab, 0x39, 0xb3, 0x26, 0xcd, 0xae,
+            0xa1, 0x61, 0x0b, 0x3d, 0xbf, 0x37, 0x07, 0x0e, 0xd5, 0x25, 0x5d, 0x19, 0x02, 0x10, 0x54, 0x90, 0x58, 0xce,
+            0xea, 0x2a, 0x21, 0x8b, 0x10, 0x5e, 0x16, 0x9e, 0x66, 0xac, 0xc6, 0x96, 0xff, 0xa9, 0x51, 0x83, 0x22, 0x40,
+            0x04, 0x44, 0xfb, 0x09, 0xde, 0x0f, 0x32, 0x01, 0x7b, 0x02, 0xf6, 0x90, 0x85, 0xcd, 0xf1, 0x16, 0x83, 0x90,
+            0x21, 0x5c, 0x40, 0x3b, 0xce, 0x8c, 0x2f, 0x4f, 0x60, 0xb7, 0x7b, 0x32, 0xa6, 0x27, 0xbf, 0xcb, 0x5f, 0x46,
+            0x6b, 0x3d, 0xff, 0x21, 0xf5, 0xcc, 0x19, 0x98, 0x4b, 0x70, 0xd0, 0x6c, 0xca, 0x19, 0x99, 0x24, 0x88, 0xb1,
+            0xe5, 0x8c, 0x5e, 0x55, 0x98, 0x72, 0xe0, 0xe6, 0xc9, 0xf1, 0xde, 0x1d, 0xe8, 0x55, 0xda, 0x54, 0x15, 0x20,
+            0xe2, 0x59, 0x5a, 0x2e, 0x14, 0x6f, 0x58, 0x04, 0xbc, 0x6b, 0x60, 0xb0, 0xdf, 0x3d, 0x9e, 0xfd, 0xc5, 0x4f,
+            0x77, 0x63, 0x0b, 0x13, 0xcf, 0x65, 0xff, 0x78, 0xb2, 0xa4, 0x74, 0x60, 0x45, 0x9f, 0x63, 0x84, 0x25, 0x6e,
+            0x04, 0xaf, 0x7c, 0xca, 0x72, 0xe7, 0x92, 0xf6, 0x91, 0x93, 0x29, 0xef, 0x28, 0xd5, 0x5d, 0xff, 0x07, 0x3f,
+            0x44, 0x76, 0xf2, 0x24, 0xfd, 0xa4, 0xa6, 0x1a, 0xb4, 0x2f, 0x41, 0x22, 0x9c, 0x1a, 0xd6, 0x87, 0x8b, 0xcb,
+            0xcd, 0x80, 0x8b, 0x64, 0x60, 0x4f, 0x8d, 0x0f, 0x20, 0x66, 0xa7, 0xef, 0xbd, 0x9d, 0x08, 0x78, 0x85, 0xd1,
+            0x3e, 0x15, 0xa9, 0xc7, 0x9a, 0x5f, 0xac, 0x57, 0x70, 0x4d, 0xeb, 0x49, 0x21, 0x49, 0x04, 0x41, 0xf5, 0xeb,
+            0x44, 0x5b, 0x12, 0xcf, 0xc9, 0xd0, 0x32, 0x69, 0x85, 0x72, 0xf0, 0x6a, 0xec, 0x78, 0xc3, 0x9c, 0xec, 0x1b,
+            0xbc, 0xf4, 0xe1, 0x40, 0x37, 0xc7, 0xd4, 0xd7, 0xb0, 0x06, 0x2f, 0x48, 0xa2, 0xc0, 0x73, 0xd4, 0xcb, 0x99,
+            0xff, 0x8f, 0x62, 0x3e, 0xab, 0x89, 0xf4, 0x15, 0x69, 0xc8, 0x5d, 0xe3, 0x2a, 0x62, 0x00, 0x46, 0xaf, 0xfc,
+            0x86, 0x0d, 0x00, 0xdf, 0x82, 0xa0, 0xfc, 0x4f, 0x35, 0x07, 0x0f, 0x36, 0x66, 0xa2, 0xd2, 0x57, 0xf9, 0x64,
+            0x61, 0xbc, 0x84, 0x11, 0xa7, 0x95, 0xa0, 0x0a, 0x8a, 0xd7, 0x3a, 0x47, 0x02, 0xd4, 0x1a, 0xbc, 0x46, 0x43,
+            0x99, 0x3d, 0x21, 0xe6, 0x9a, 0x43, 0x25, 0x39, 0x99, 0xdd, 0xac, 0x40, 0xfa, 0x1f, 0xd5, 0xd8, 0x71, 0xab,
+            0x57, 0xf8, 0x20, 0xdf, 0xbc, 0x2a,
+        ];
+    }
+
+    #[test]
+    #[cfg(feature = "rsa")]
+    fn rsa() {
+        let digest = Sha256::digest(FIRMWARE);
+
+        let mut verifier = Rsa2048::new(&RSA2048_MODULUS, 65537, RsaPadding::Pkcs1v15).unwrap();
+        assert!(verifier.verify(&digest, &RSA2048_PKCS1V15_SIGNATURE).is_ok());
+        assert!(verifier.verify(&digest, &RSA2048_PSS_SIGNATURE).is_err());
+
+        let mut verifier = Rsa2048::new(&RSA2048_MODULUS, 65537, RsaPadding::Pss).unwrap();
+        assert!(verifier.verify(&digest, &RSA2048_PSS_SIGNATURE).is_ok());
+        assert!(verifier.verify(&digest, &RSA2048_PKCS1V15_SIGNATURE).is_err());
+
+        let mut verifier = Rsa3072::new(&RSA3072_MODULUS, 65537, RsaPadding::Pkcs1v15).unwrap();
+        assert!(verifier.verify(&digest, &RSA3072_PKCS1V15_SIGNATURE).is_ok());
+
+        let mut verifier = Rsa3072::new(&RSA3072_MODULUS, 65537, RsaPadding::Pss).unwrap();
+        assert!(verifier.verify(&digest, &RSA3072_PSS_SIGNATURE).is_ok());
+
+        let mut tampered = RSA3072_PSS_SIGNATURE;
+        tampered[100] ^= 1;
+        assert!(verifier.verify(&digest, &tampered).is_err());
+        assert!(
+            verifier
+                .verify(&Sha256::digest(b"other"), &RSA3072_PSS_SIGNATURE)
+                .is_err()
+        );
+        assert!(verifier.verify(&digest, &RSA3072_PSS_SIGNATURE[1..]).is_err());
+
+        assert!(Rsa2048::new(&RSA3072_MODULUS, 65537, RsaPadding::Pss).is_err());
+        assert!(Rsa2048::new(&RSA2048_MODULUS, 65536, RsaPadding::Pss).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "ecdsa-p256")]
+    fn ecdsa_p256() {
+        use p256::ecdsa::signature::hazmat::PrehashSigner;
+        use p256::ecdsa::{Signature, SigningKey};
+
+        let key = SigningKey::random(&mut rand::rngs::OsRng);
+        let digest = Sha256::digest(FIRMWARE);
+        let signature: Signature = key.sign_prehash(&digest).unwrap();
+
+        let mut verifier = EcdsaP256::new(key.verifying_key().to_encoded_point(false).as_bytes()).unwrap();
+        assert!(verifier.verify(&digest, &signature.to_bytes()).is_ok());
+        assert!(verifier.verify(&digest, signature.to_der().as_bytes()).is_ok());
+        assert!(
+            verifier
+                .verify(&Sha256::digest(b"other"), &signature.to_bytes())
+                .is_err()
+        );
+        assert!(EcdsaP256::new(&[4; 65]).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "rsa")]
+    fn verify_and_mark_updated_with() {
+        let flash = crate::test_flash::BlockingTestFlash::new(crate::BootLoaderConfig {
+            active: MemFlash::<0, 0, 0>::default(),
+            dfu: MemFlash::<4096, 4096, 4>::default(),
+            state: MemFlash::<4096, 4096, 4>::default(),
+        });
+        let mut write_buf = [0; 4096];
+        write_buf[..FIRMWARE.len()].copy_from_slice(FIRMWARE);
+        embedded_storage::nor_flash::NorFlash::write(&mut flash.dfu(), 0, &write_buf).unwrap();
+
+        let flash = flash.into_async();
+        let mut aligned = [0; 4];
+        let mut updater = FirmwareUpdater::new(
+            FirmwareUpdaterConfig {
+                dfu: flash.dfu(),
+                state: flash.state(),
+            },
+            &mut aligned,
+        );
+
+        let mut verifier = Rsa2048::new(&RSA2048_MODULUS, 65537, RsaPadding::Pss).unwrap();
+        let len = FIRMWARE.len() as u32;
+        assert!(matches!(
+            block_on(updater.verify_and_mark_updated_with(&mut verifier, &RSA2048_PKCS1V15_SIGNATURE, len)),
+            Err(FirmwareUpdaterError::Signature(_))
+        ));
+        block_on(updater.verify_and_mark_updated_with(&mut verifier, &RSA2048_PSS_SIGNATURE, len)).unwrap();
+        assert_eq!(State::Swap, block_on(updater.get_state()).unwrap());
+    }
+
+    #[test]
+    #[cfg(feature = "ecdsa-p256")]
+    fn verify_image_and_mark_updated_with() {
+        use p256::ecdsa::signature::hazmat::PrehashSigner;
+        use p256::ecdsa::{Signature, SigningKey};
+
+        use crate::image::{ImageBuilder, ImageFormat, ImageVersion, TLV_ECDSA_P256};
+
+        // An image as signed by imgtool, with an MCUboot header and a DER encoded signature.
+        let key = SigningKey::random(&mut rand::rngs::OsRng);
+        let mut image = [0; 4096];
+        ImageBuilder::new(ImageVersion::new(1, 0, 0))
+            .format(ImageFormat::McuBoot)
+            .security_counter(1)
+            .build(FIRMWARE, &mut image, |hash, tlv| {
+                let signature: Signature = key.sign_prehash(hash).unwrap();
+                tlv.push(TLV_ECDSA_P256, signature.to_der().as_bytes())
+            })
+            .unwrap();
+
+        let flash = crate::test_flash::BlockingTestFlash::new(crate::BootLoaderConfig {
+            active: MemFlash::<0, 0, 0>::default(),
+            dfu: MemFlash::<4096, 4096, 4>::default(),
+            state: MemFlash::<4096, 4096, 4>::default(),
+        });
+        embedded_storage::nor_flash::NorFlash::write(&mut flash.dfu(), 0, &image).unwrap();
+
+        let mut aligned = [0; 4];
+        let mut updater = crate::BlockingFirmwareUpdater::new(
+            FirmwareUpdaterConfig {
+                dfu: flash.dfu(),
+                state: flash.state(),
+            },
+            &mut aligned,
+        );
+        let mut verifier = EcdsaP256::new(key.verifying_key().to_encoded_point(false).as_bytes()).unwrap();
+        let header = updater.verify_image_and_mark_updated_with(&mut verifier).unwrap();
+        assert_eq!(header.security_counter, 1);
+        assert_eq!(State::Swap, updater.get_state().unwrap());
+    }
+}
@@ -0,0 +1,172 @@
+//! BIOS parameter block and volume geometry.
+
+use crate::BLOCK_SIZE;
+
+/// Directory entry size in bytes.
+pub(crate) const DIR_ENTRY_SIZE: u32 = 32;
+/// Directory entries per sector.
+pub(crate) const DIR_ENTRIES_PER_SECTOR: u32 = BLOCK_SIZE as u32 / DIR_ENTRY_SIZE;
+
+/// FAT variant, determined by the number of clusters (FAT spec §3.5).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FatType {
+    /// Fewer than 4085 clusters, 12-bit FAT entries.
+    Fat12,
+    /// Fewer than 65525 clusters, 16-bit FAT entries.
+    Fat16,
+    /// 32-bit FAT entries, of which 28 bits are used.
+    Fat32,
+}
+
+impl FatType {
+    /// FAT type of a volume with `clusters` data clusters.
+    pub(crate) fn from_cluster_count(clusters: u32) -> Self {
+        if clusters < 4085 {
+            Self::Fat12
+        } else if clusters < 65525 {
+            Self::Fat16
+        } else {
+            Self::Fat32
+        }
+    }
+
+    /// FAT entry values at or above this mark the end of a cluster chain.
+    pub(crate) fn end_of_chain(self) -> u32 {
+        match self {
+            Self::Fat12 => 0xFF8,
+            Self::Fat16 => 0xFFF8,
+            Self::Fat32 => 0x0FFF_FFF8,
+        }
+    }
+}
+
+/// Parsed BIOS parameter block with the derived volume layout, in sectors
+/// relative to the start of the volume.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Bpb {
+    pub fat_type: FatType,
+    pub sectors_per_cluster: u32,
+    pub num_fats: u32,
+    pub root_entries: u32,
+    pub total_sectors: u32,
+    pub fat_size: u32,
+    pub fat_start: u32,
+    pub root_dir_start: u32,
+    pub data_start: u32,
+    pub cluster_count: u32,
+    /// First cluster of the root directory (FAT32 only).
+    pub root_cluster: u32,
+    /// FSInfo sector (FAT32 only, 0 if absent).
+    pub fs_info: u32,
+    pub volume_id: u32,
+    pub volume_label: [u8; 11],
+}
+
+impl Bpb {
+    /// Parse a boot sector. Returns `None` if it does not describe a FAT volume
+    /// with 512-byte sectors.
+    pub fn parse(sector: &[u8; BLOCK_SIZE]) -> Option<Self> {
+        let u16_at = |o: usize| u16::from_le_bytes([sector[o], sector[o + 1]]) as u32;
+        let u32_at = |o: usize| u32::from_le_bytes(sector[o..o + 4].try_into().unwrap());
+
+        if !matches!(sector[0], 0xEB | 0xE9) || u16_at(11) != BLOCK_SIZE as u32 {
+            return None;
+        }
+        let sectors_per_cluster = sector[13] as u32;
+        let reserved = u16_at(14);
+        let num_fats = sector[16] as u32;
+        let root_entries = u16_at(17);
+        let total_sectors = match u16_at(19) {
+            0 => u32_at(32),
+            n => n,
+        };
+        let fat_size = match u16_at(22) {
+            0 => u32_at(36),
+            n => n,
+        };
+        if !sectors_per_cluster.is_power_of_two() || reserved == 0 || num_fats == 0 || fat_size == 0 {
+            return None;
+        }
+
+        let fat_start = reserved;
+        let root_dir_start = fat_start.checked_add(num_fats.checked_mul(fat_size)?)?;
+        let root_dir_sectors = (root_entries * DIR_ENTRY_SIZE).div_ceil(BLOCK_SIZE as u32);
+        let data_start = root_dir_start.checked_add(root_dir_sectors)?;
+        let cluster_count = total_sectors.checked_sub(data_start)? / sectors_per_cluster;
+        let fat_type = FatType::from_cluster_count(cluster_count);
+
+        // The FAT must be able to hold an entry for every cluster.
+        let fat_bytes = fat_size as u64 * BLOCK_SIZE as u64;
+        let needed = match fat_type {
+            FatType::Fat12 => (cluster_count as u64 + 2) * 3 / 2 + 1,
+            FatType::Fat16 => (cluster_count as u64 + 2) * 2,
+            FatType::Fat32 => (cluster_count as u64 + 2) * 4,
+        };
+        if cluster_count == 0 || fat_bytes < needed {
+            return None;
+        }
+
+        let (root_cluster, fs_info, ext) = match fat_type {
+            FatType::Fat32 => {
+                if root_entries != 0 {
+                    return None;
+                }
+                (u32_at(44), u16_at(48), 64)
+            }
+            _ => {
+                if root_entries == 0 {
+                    return None;
+                }
+                (0, 0, 36)
+            }
+        };
+        if fat_type == FatType::Fat32 && !(2..cluster_count + 2).contains(&root_cluster) {
+            return None;
+        }
+
+        // Extended boot signature: volume ID and label are valid.
+        let (volume_id, volume_label) = if sector[ext + 2] == 0x29 {
+            (u32_at(ext + 3), sector[ext + 7..ext + 18].try_into().unwrap())
+        } else {
+            (0, *b"NO NAME    ")
+        };
+
+        Some(Self {
+            fat_type,
+            sectors_per_cluster,
+            num_fats,
+            root_entries,
+            total_sectors,
+            fat_size,
+            fat_start,
+            root_dir_start,
+            data_start,
+            cluster_count,
+            root_cluster,
+            fs_info: if fs_info != 0 && fs_info < reserved { fs_info } else { 0 },
+            volume_id,
+            volume_label,
+        })
+    }
+
+    /// Cluster size in bytes.
+    pub fn cluster_size(&self) -> u32 {
+        self.sectors_per_cluster * BLOCK_SIZE as u32
+    }
+
+    /// Directory entries per cluster.
+    pub fn dir_entries_per_cluster(&self) -> u32 {
+        self.sectors_per_cluster * DIR_ENTRIES_PER_SECTOR
+    }
+
+    /// Whether `cluster` is a valid data cluster number.
+    pub fn is_valid_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+
+    /// First sector of a data cluster.
+    pub fn cluster_sector(&self, cluster: u32) -> u32 {
+        self.data_start + (cluster - 2) * self.sectors_per_cluster
+    }
+}
@@ -0,0 +1,609 @@
+//! Directory entries, short names and long file names.
+
+use bitflags::bitflags;
+use heapless::String;
+
+/// Maximum length of a long file name, in UTF-16 code units.
+pub const MAX_NAME_LEN: usize = 255;
+/// Long file name characters per directory entry.
+const LFN_CHARS_PER_ENTRY: usize = 13;
+/// Byte offsets of the UTF-16 characters within a long file name entry.
+const LFN_CHAR_OFFSETS: [usize; LFN_CHARS_PER_ENTRY] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// Attribute value marking a long file name entry.
+pub(crate) const ATTR_LFN: u8 = 0x0F;
+/// First name byte of a deleted entry.
+pub(crate) const DELETED: u8 = 0xE5;
+/// Reserved-field flag: the base name is stored in lowercase.
+const NT_LOWER_BASE: u8 = 0x08;
+/// Reserved-field flag: the extension is stored in lowercase.
+const NT_LOWER_EXT: u8 = 0x10;
+
+bitflags! {
+    /// Directory entry attributes.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+    pub struct Attributes: u8 {
+        /// Writes are not allowed.
+        const READ_ONLY = 0x01;
+        /// Hidden from normal directory listings.
+        const HIDDEN = 0x02;
+        /// Operating system file.
+        const SYSTEM = 0x04;
+        /// Volume label, only valid in the root directory.
+        const VOLUME_ID = 0x08;
+        /// The entry is a directory.
+        const DIRECTORY = 0x10;
+        /// Modified since the last backup.
+        const ARCHIVE = 0x20;
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Attributes {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "Attributes({=u8:b})", self.bits());
+    }
+}
+
+/// A date and time with the two-second resolution of FAT timestamps.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Timestamp {
+    /// Year, 1980 to 2107.
+    pub year: u16,
+    /// Month, 1 to 12.
+    pub month: u8,
+    /// Day of the month, 1 to 31.
+    pub day: u8,
+    /// Hour, 0 to 23.
+    pub hour: u8,
+    /// Minute, 0 to 59.
+    pub minute: u8,
+    /// Second, 0 to 59. Stored rounded down to an even number.
+    pub second: u8,
+}
+
+impl Timestamp {
+    /// The FAT epoch, 1980-01-01 00:00:00, used when no time source is set.
+    pub const EPOCH: Self = Self {
+        year: 1980,
+        month: 1,
+        day: 1,
+        hour: 0,
+        minute: 0,
+        second: 0,
+    };
+
+    pub(crate) fn from_fat(date: u16, time: u16) -> Self {
+        Self {
+            year: 1980 + (date >> 9),
+            month: ((date >> 5) & 0x0F) as u8,
+            day: (date & 0x1F) as u8,
+            hour: (time >> 11) as u8,
+            minute: ((time >> 5) & 0x3F) as u8,
+            second: ((time & 0x1F) * 2) as u8,
+        }
+    }
+
+    /// Encode as FAT `(date, time)`, clamping out-of-range values.
+    pub(crate) fn to_fat(self) -> (u16, u16) {
+        let year = self.year.clamp(1980, 2107) - 1980;
+        let date = (year << 9) | ((self.month.clamp(1, 12) as u16) << 5) | self.day.clamp(1, 31) as u16;
+        let time =
+            ((self.hour.min(23) as u16) << 11) | ((self.minute.min(59) as u16) << 5) | (self.second.min(59) / 2) as u16;
+        (date, time)
+    }
+}
+
+impl Default for Timestamp {
+    fn default() -> Self {
+        Self::EPOCH
+    }
+}
+
+/// Position of a directory entry.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct DirCursor {
+    /// First cluster of the directory, or 0 for a FAT12/16 root directory.
+    pub start: u32,
+    /// Current cluster, unused for a FAT12/16 root directory.
+    pub cluster: u32,
+    /// Entry index within the current cluster or the root directory.
+    pub index: u32,
+    /// Number of clusters advanced past `start`.
+    pub clusters: u32,
+}
+
+impl DirCursor {
+    pub fn new(start: u32) -> Self {
+        Self {
+            start,
+            cluster: start,
+            index: 0,
+            clusters: 0,
+        }
+    }
+}
+
+/// A directory entry returned by [`Dir::next`](super::Dir::next) or
+/// [`FileSystem::metadata`](super::FileSystem::metadata).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    pub(crate) name: String<MAX_NAME_LEN>,
+    pub(crate) short_name: [u8; 11],
+    pub(crate) attributes: Attributes,
+    pub(crate) size: u32,
+    pub(crate) first_cluster: u32,
+    pub(crate) created: Timestamp,
+    pub(crate) modified: Timestamp,
+    /// Position of the short entry.
+    pub(crate) pos: DirCursor,
+    /// Position of the first long name entry, or of the short entry.
+    pub(crate) first_pos: DirCursor,
+    /// Number of entries, long name entries included.
+    pub(crate) entry_count: u8,
+}
+
+impl DirEntry {
+    /// The long file name, or the short name if there is none.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The 8.3 short name, e.g. `README~1.TXT`.
+    pub fn short_name(&self) -> String<12> {
+        short_name_display(&self.short_name, 0)
+    }
+
+    /// Entry attributes.
+    pub fn attributes(&self) -> Attributes {
+        self.attributes
+    }
+
+    /// Whether the entry is a directory.
+    pub fn is_dir(&self) -> bool {
+        self.attributes.contains(Attributes::DIRECTORY)
+    }
+
+    /// Whether the entry is a regular file.
+    pub fn is_file(&self) -> bool {
+        !self.is_dir()
+    }
+
+    /// File size in bytes, 0 for directories.
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Creation time.
+    pub fn created(&self) -> Timestamp {
+        self.created
+    }
+
+    /// Last modification time.
+    pub fn modified(&self) -> Timestamp {
+        self.modified
+    }
+
+    /// Parse a short directory entry.
+    pub(crate) fn parse(raw: &[u8; 32], pos: DirCursor) -> Self {
+        let u16_at = |o: usize| u16::from_le_bytes([raw[o], raw[o + 1]]);
+        let mut short_name: [u8; 11] = raw[0..11].try_into().unwrap();
+        if short_name[0] == 0x05 {
+            short_name[0] = DELETED;
+        }
+        let size = u32::from_le_bytes(raw[28..32].try_into().unwrap());
+        let attributes = Attributes::from_bits_truncate(raw[11]);
+        Self {
+            name: String::new(),
+            short_name,
+            attributes,
+            size: if attributes.contains(Attributes::DIRECTORY) {
+                0
+            } else {
+                size
+            },
+            first_cluster: ((u16_at(20) as u32) << 16) | u16_at(26) as u32,
+            created: Timestamp::from_fat(u16_at(16), u16_at(14)),
+            modified: Timestamp::from_fat(u16_at(24), u16_at(22)),
+            pos,
+            first_pos: pos,
+            entry_count: 1,
+        }
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for DirEntry {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "DirEntry {{ name: {=str}, attributes: {}, size: {=u32} }}",
+            self.name.as_str(),
+            self.attributes,
+            self.size
+        );
+    }
+}
+
+/// Render a raw 8.3 name as `NAME.EXT`, applying the lowercase flags.
+pub(crate) fn short_name_display(raw: &[u8; 11], nt_flags: u8) -> String<12> {
+    let mut s = String::new();
+    let part = |bytes: &[u8], lower: bool, s: &mut String<12>| {
+        for &b in bytes.iter().take_while(|&&b| b != b' ') {
+            let c = if lower { b.to_ascii_lowercase() } else { b };
+            // Non-ASCII OEM characters have no defined mapping here.
+            let _ = s.push(if c.is_ascii() { c as char } else { '_' });
+        }
+    };
+    part(&raw[..8], nt_flags & NT_LOWER_BASE != 0, &mut s);
+    if raw[8] != b' ' {
+        let _ = s.push('.');
+        part(&raw[8..], nt_flags & NT_LOWER_EXT != 0, &mut s);
+    }
+    s
+}
+
+/// Checksum of a short name, stored in its long name entries.
+pub(crate) fn short_name_checksum(name: &[u8; 11]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, &c| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(c))
+}
+
+/// Whether `c` may appear in a short name (FAT spec §6.1).
+fn is_short_name_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"$%'-_@~`!(){}^#&".contains(&c)
+}
+
+/// Check a long file name for validity.
+pub(crate) fn validate_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.ends_with(['.', ' '])
+        && name.encode_utf16().count() <= MAX_NAME_LEN
+        && !name.chars().any(|c| c < ' ' || "\"*/:<>?\\|\x7F".contains(c))
+}
+
+/// How a name is stored as a short entry.
+pub(crate) enum ShortName {
+    /// The name is a valid 8.3 name, possibly with lowercase flags; no long
+    /// name entries are needed.
+    Exact([u8; 11], u8),
+    /// The name needs long name entries. Holds the basis short name and
+    /// whether a numeric tail is required because information was lost.
+    Basis([u8; 11], bool),
+}
+
+/// Split `name` into base and extension at the last dot.
+fn split_ext(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(i) if i > 0 => (&name[..i], &name[i + 1..]),
+        _ => (name, ""),
+    }
+}
+
+/// Derive the short name for a long file name (FAT spec §6.4).
+pub(crate) fn short_name_for(name: &str) -> ShortName {
+    let (base, ext) = split_ext(name);
+
+    // Exact 8.3 match, with each part either entirely upper- or lowercase.
+    let case = |part: &str| -> Option<u8> {
+        let bytes = part.as_bytes();
+        if bytes.iter().any(|c| !is_short_name_char(c.to_ascii_uppercase())) {
+            return None;
+        }
+        let lower = bytes.iter().any(|c| c.is_ascii_lowercase());
+        let upper = bytes.iter().any(|c| c.is_ascii_uppercase());
+        match (lower, upper) {
+            (true, true) => None,
+            (true, false) => Some(1),
+            _ => Some(0),
+        }
+    };
+    if (1..=8).contains(&base.len())
+        && ext.len() <= 3
+        && !(ext.is_empty() && name.ends_with('.'))
+        && let (Some(b), Some(e)) = (case(base), case(ext))
+    {
+        let mut raw = [b' '; 11];
+        for (d, s) in raw.iter_mut().zip(base.bytes()) {
+            *d = s.to_ascii_uppercase();
+        }
+        for (d, s) in raw[8..].iter_mut().zip(ext.bytes()) {
+            *d = s.to_ascii_uppercase();
+        }
+        let flags = if b == 1 { NT_LOWER_BASE } else { 0 } | if e == 1 { NT_LOWER_EXT } else { 0 };
+        return ShortName::Exact(raw, flags);
+    }
+
+    let mut raw = [b' '; 11];
+    let trimmed = base.trim_start_matches('.');
+    let mut lossy = trimmed.len() != base.len();
+    let mut convert = |part: &str, out: &mut [u8]| {
+        let mut n = 0;
+        for c in part.chars() {
+            if c == ' ' || c == '.' {
+                lossy = true;
+                continue;
+            }
+            let c = if c.is_ascii() && is_short_name_char(c.to_ascii_uppercase() as u8) {
+                c.to_ascii_uppercase() as u8
+            } else {
+                lossy = true;
+                b'_'
+            };
+            if n == out.len() {
+                lossy = true;
+                break;
+            }
+            out[n] = c;
+            n += 1;
+        }
+        n
+    };
+    let base_len = convert(trimmed, &mut raw[..8]);
+    convert(ext, &mut raw[8..]);
+    if base_len == 0 {
+        raw[0] = b'_';
+        lossy = true;
+    }
+    ShortName::Basis(raw, lossy)
+}
+
+/// Apply the numeric tail `~n` to a basis name.
+pub(crate) fn with_numeric_tail(basis: &[u8; 11], n: u32) -> [u8; 11] {
+    let mut digits = [0u8; 10];
+    let mut len = 0;
+    let mut v = n;
+    loop {
+        digits[len] = b'0' + (v % 10) as u8;
+        len += 1;
+        v /= 10;
+        if v == 0 {
+            break;
+        }
+    }
+    let base_len = basis[..8].iter().position(|&c| c == b' ').unwrap_or(8);
+    let keep = base_len.min(8 - len - 1);
+    let mut raw = *basis;
+    raw[keep] = b'~';
+    for i in 0..len {
+        raw[keep + 1 + i] = digits[len - 1 - i];
+    }
+    raw[keep + 1 + len..8].fill(b' ');
+    raw
+}
+
+/// Number of long name entries needed for `name`.
+pub(crate) fn lfn_entry_count(name: &str) -> usize {
+    name.encode_utf16().count().div_ceil(LFN_CHARS_PER_ENTRY)
+}
+
+/// Build long name entry `seq` (1-based) of `name`.
+pub(crate) fn lfn_entry(name: &str, seq: usize, last: bool, checksum: u8) -> [u8; 32] {
+    let mut raw = [0u8; 32];
+    raw[0] = seq as u8 | if last { 0x40 } else { 0 };
+    raw[11] = ATTR_LFN;
+    raw[13] = checksum;
+    let start = (seq - 1) * LFN_CHARS_PER_ENTRY;
+    let mut units = name.encode_utf16().skip(start);
+    let mut terminated = false;
+    for off in LFN_CHAR_OFFSETS {
+        let u = match units.next() {
+            Some(u) => u,
+            None if !terminated => {
+                terminated = true;
+                0x0000
+            }
+            None => 0xFFFF,
+        };
+        raw[off..off + 2].copy_from_slice(&u.to_le_bytes());
+    }
+    raw
+}
+
+/// Build a short directory entry.
+pub(crate) fn short_entry(
+    name: &[u8; 11],
+    nt_flags: u8,
+    attributes: Attributes,
+    first_cluster: u32,
+    size: u32,
+    now: Timestamp,
+) -> [u8; 32] {
+    let mut raw = [0u8; 32];
+    raw[..11].copy_from_slice(name);
+    if raw[0] == DELETED {
+        raw[0] = 0x05;
+    }
+    raw[11] = attributes.bits();
+    raw[12] = nt_flags;
+    let (date, time) = now.to_fat();
+    raw[14..16].copy_from_slice(&time.to_le_bytes());
+    raw[16..18].copy_from_slice(&date.to_le_bytes());
+    raw[18..20].copy_from_slice(&date.to_le_bytes());
+    raw[22..24].copy_from_slice(&time.to_le_bytes());
+    raw[24..26].copy_from_slice(&date.to_le_bytes());
+    set_first_cluster(&mut raw, first_cluster);
+    raw[28..32].copy_from_slice(&size.to_le_bytes());
+    raw
+}
+
+/// Store the first cluster in a short entry.
+pub(crate) fn set_first_cluster(raw: &mut [u8], cluster: u32) {
+    raw[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    raw[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+/// Accumulates long name entries preceding a short entry.
+pub(crate) struct LfnBuilder {
+    units: [u16; 20 * LFN_CHARS_PER_ENTRY],
+    /// Sequence number expected next, 0 once complete.
+    next: u8,
+    count: u8,
+    checksum: u8,
+    valid: bool,
+    pub first_pos: DirCursor,
+}
+
+impl LfnBuilder {
+    pub fn new() -> Self {
+        Self {
+            units: [0; 20 * LFN_CHARS_PER_ENTRY],
+            next: 0,
+            count: 0,
+            checksum: 0,
+            valid: false,
+            first_pos: DirCursor::new(0),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.valid = false;
+    }
+
+    /// Add a long name entry. Entries are stored in reverse order, the one
+    /// flagged as last coming first.
+    pub fn push(&mut self, raw: &[u8; 32], pos: DirCursor) {
+        let seq = raw[0] & 0x1F;
+        if raw[0] & 0x40 != 0 {
+            self.valid = (1..=20).contains(&seq);
+            self.count = seq;
+            self.checksum = raw[13];
+            self.first_pos = pos;
+        } else if !(self.valid && seq == self.next && raw[13] == self.checksum) {
+            self.valid = false;
+        }
+        if !self.valid {
+            return;
+        }
+        self.next = seq - 1;
+        let start = (seq as usize - 1) * LFN_CHARS_PER_ENTRY;
+        for (i, off) in LFN_CHAR_OFFSETS.iter().enumerate() {
+            self.units[start + i] = u16::from_le_bytes([raw[*off], raw[off + 1]]);
+        }
+    }
+
+    /// The long name for the short entry with `checksum`, with the number of
+    /// long name entries, if the sequence is complete and matches.
+    pub fn finish(&mut self, checksum: u8) -> Option<(String<MAX_NAME_LEN>, u8)> {
+        if !self.valid || self.next != 0 || self.checksum != checksum {
+            return None;
+        }
+        self.valid = false;
+        let len = self.count as usize * LFN_CHARS_PER_ENTRY;
+        let units = self.units[..len].iter().copied().take_while(|&u| u != 0);
+        let mut name = String::new();
+        for c in char::decode_utf16(units) {
+            name.push(c.ok()?).ok()?;
+        }
+        if name.is_empty() {
+            return None;
+        }
+        Some((name, self.count))
+    }
+}
+
+/// Case-insensitive name comparison.
+pub(crate) fn names_equal(a: &str, b: &str) -> bool {
+    a.chars()
+        .flat_map(char::to_lowercase)
+        .eq(b.chars().flat_map(char::to_lowercase))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_names() {
+        match short_name_for("README.TXT") {
+            ShortName::Exact(raw, 0) => assert_eq!(&raw, b"README  TXT"),
+            _ => panic!(),
+        }
+        match short_name_for("readme.TXT") {
+            ShortName::Exact(raw, flags) => {
+                assert_eq!(&raw, b"README  TXT");
+                assert_eq!(short_name_display(&raw, flags), "readme.TXT");
+            }
+            _ => panic!(),
+        }
+        assert!(matches!(short_name_for("ReadMe.txt"), ShortName::Basis(_, false)));
+        match short_name_for("long file name.jpeg") {
+            ShortName::Basis(raw, true) => {
+                assert_eq!(&raw, b"LONGFILEJPE");
+                assert_eq!(&with_numeric_tail(&raw, 1), b"LONGFI~1JPE");
+                assert_eq!(&with_numeric_tail(&raw, 12345), b"LO~12345JPE");
+            }
+            _ => panic!(),
+        }
+        match short_name_for(".bashrc") {
+            ShortName::Basis(raw, true) => assert_eq!(&raw, b"BASHRC     "),
+            _ => panic!(),
+        }
+        match short_name_for("é") {
+            ShortName::Basis(raw, true) => assert_eq!(&with_numeric_tail(&raw, 1), b"_~1        "),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(short_name_checksum(b"NEWFIL~1TXT"), 0x32);
+        assert_eq!(short_name_checksum(b"           "), 0xF7);
+    }
+
+    #[test]
+    fn names() {
+        assert!(validate_name("a b.c"));
+        assert!(!validate_name(""));
+        assert!(!validate_name(".."));
+        assert!(!validate_name("a:b"));
+        assert!(!validate_name("trailing."));
+        assert!(names_equal("Straße.TXT", "straße.txt"));
+        assert!(!names_equal("a", "b"));
+    }
+
+    #[test]
+    fn lfn_roundtrip() {
+        let name = "A rather long file name, with ünïcode.txt";
+        let short = *b"ARATHE~1TXT";
+        let sum = short_name_checksum(&short);
+        let n = lfn_entry_count(name);
+        assert_eq!(n, 4);
+
+        let mut b = LfnBuilder::new();
+        for seq in (1..=n).rev() {
+            b.push(&lfn_entry(name, seq, seq == n, sum), DirCursor::new(2));
+        }
+        let (decoded, count) = b.finish(sum).unwrap();
+        assert_eq!(decoded, name);
+        assert_eq!(count as usize, n);
+
+        // Wrong checksum or a missing entry invalidates the name.
+        let mut b = LfnBuilder::new();
+        for seq in (1..=n).rev() {
+            b.push(&lfn_entry(name, seq, seq == n, sum), DirCursor::new(2));
+        }
+        assert!(b.finish(sum ^ 1).is_none());
+        let mut b = LfnBuilder::new();
+        for seq in (1..=n).rev().filter(|&s| s != 2) {
+            b.push(&lfn_entry(name, seq, seq == n, sum), DirCursor::new(2));
+        }
+        assert!(b.finish(sum).is_none());
+    }
+
+    #[test]
+    fn timestamps() {
+        let t = Timestamp {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 23,
+            minute: 59,
+            second: 59,
+        };
+        let (date, time) = t.to_fat();
+        assert_eq!(Timestamp::from_fat(date, time), Timestamp { second: 58, ..t });
+    }
+}
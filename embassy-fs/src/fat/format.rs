@@ -0,0 +1,212 @@
+//! Creating FAT volumes.
+
+use super::bpb::{Bpb, FatType};
+use super::dir::{Attributes, Timestamp, short_entry};
+use super::{FSINFO_LEAD_SIG, FSINFO_STRUC_SIG, FSINFO_TRAIL_SIG};
+use crate::{BLOCK_SIZE, BlockDevice, Error, write_block, zero_block};
+
+/// Options for [`format`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FormatOptions {
+    /// FAT variant. By default FAT12 below 4 MiB, FAT16 up to 512 MiB and FAT32 above.
+    pub fat_type: Option<FatType>,
+    /// Sectors per cluster, a power of two. By default chosen from the volume
+    /// size as recommended by the FAT specification.
+    pub sectors_per_cluster: Option<u8>,
+    /// Volume label, padded with spaces.
+    pub volume_label: [u8; 11],
+    /// Volume serial number.
+    pub volume_id: u32,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            fat_type: None,
+            sectors_per_cluster: None,
+            volume_label: *b"NO NAME    ",
+            volume_id: 0,
+        }
+    }
+}
+
+/// Default cluster size in sectors (FAT spec §3.5 tables for FAT16 and FAT32).
+fn default_sectors_per_cluster(fat_type: FatType, sectors: u32) -> Option<u32> {
+    match fat_type {
+        FatType::Fat12 => (0..8).map(|i| 1 << i).find(|spc| sectors / spc < 4085 - 16),
+        FatType::Fat16 => match sectors {
+            0..=8400 => None,
+            8401..=32680 => Some(2),
+            32681..=262_144 => Some(4),
+            262_145..=524_288 => Some(8),
+            524_289..=1_048_576 => Some(16),
+            1_048_577..=2_097_152 => Some(32),
+            2_097_153..=4_194_304 => Some(64),
+            _ => None,
+        },
+        FatType::Fat32 => match sectors {
+            0..=66600 => None,
+            66601..=532_480 => Some(1),
+            532_481..=16_777_216 => Some(8),
+            16_777_217..=33_554_432 => Some(16),
+            33_554_433..=67_108_864 => Some(32),
+            _ => Some(64),
+        },
+    }
+}
+
+/// Create an empty FAT volume of `sectors` blocks starting at `start_lba`.
+///
+/// The partition table, if any, is not modified. Fails with
+/// [`Error::InvalidFileSystem`] if the requested FAT type or cluster size
+/// does not fit the volume size.
+pub async fn format<D: BlockDevice<BLOCK_SIZE>>(
+    dev: &mut D,
+    start_lba: u32,
+    sectors: u32,
+    opts: &FormatOptions,
+) -> Result<(), Error<D::Error>> {
+    let end = start_lba.checked_add(sectors).ok_or(Error::OutOfBounds)?;
+    if end as u64 > dev.size().await? / BLOCK_SIZE as u64 {
+        return Err(Error::OutOfBounds);
+    }
+
+    let fat_type = opts.fat_type.unwrap_or(match sectors {
+        0..8400 => FatType::Fat12,
+        8400..=1_048_576 => FatType::Fat16,
+        _ => FatType::Fat32,
+    });
+    let spc = match opts.sectors_per_cluster {
+        Some(n) if n.is_power_of_two() => n as u32,
+        Some(_) => return Err(Error::InvalidFileSystem),
+        None => default_sectors_per_cluster(fat_type, sectors).ok_or(Error::InvalidFileSystem)?,
+    };
+    let (reserved, root_entries, entry_bits) = match fat_type {
+        FatType::Fat12 => (1, 512, 12),
+        FatType::Fat16 => (1, 512, 16),
+        FatType::Fat32 => (32, 0, 32),
+    };
+    let root_sectors = root_entries * 32 / BLOCK_SIZE as u32;
+    let num_fats = 2;
+
+    // The FAT size depends on the cluster count, which depends on the FAT size.
+    let mut fat_size = 1;
+    let clusters = loop {
+        let overhead = reserved + root_sectors + num_fats * fat_size;
+        let clusters = sectors.checked_sub(overhead).ok_or(Error::InvalidFileSystem)? / spc;
+        let needed = ((clusters as u64 + 2) * entry_bits).div_ceil(8 * BLOCK_SIZE as u64) as u32;
+        if needed <= fat_size {
+            break clusters;
+        }
+        fat_size = needed;
+    };
+    if FatType::from_cluster_count(clusters) != fat_type {
+        return Err(Error::InvalidFileSystem);
+    }
+
+    // Zero the reserved sectors, FATs and root directory.
+    let zero = zero_block::<D>();
+    let root_start = reserved + num_fats * fat_size;
+    for sector in 0..root_start + root_sectors.max(spc) {
+        write_block(dev, start_lba + sector, &zero).await?;
+    }
+
+    let mut b = zero_block::<D>();
+    b[0..3].copy_from_slice(if fat_type == FatType::Fat32 {
+        &[0xEB, 0x58, 0x90]
+    } else {
+        &[0xEB, 0x3C, 0x90]
+    });
+    b[3..11].copy_from_slice(b"MSWIN4.1");
+    b[11..13].copy_from_slice(&(BLOCK_SIZE as u16).to_le_bytes());
+    b[13] = spc as u8;
+    b[14..16].copy_from_slice(&(reserved as u16).to_le_bytes());
+    b[16] = num_fats as u8;
+    b[17..19].copy_from_slice(&(root_entries as u16).to_le_bytes());
+    if sectors < 0x10000 && fat_type != FatType::Fat32 {
+        b[19..21].copy_from_slice(&(sectors as u16).to_le_bytes());
+    } else {
+        b[32..36].copy_from_slice(&sectors.to_le_bytes());
+    }
+    b[21] = 0xF8;
+    b[24..26].copy_from_slice(&63u16.to_le_bytes());
+    b[26..28].copy_from_slice(&255u16.to_le_bytes());
+    b[28..32].copy_from_slice(&start_lba.to_le_bytes());
+    let ext = match fat_type {
+        FatType::Fat32 => {
+            b[36..40].copy_from_slice(&fat_size.to_le_bytes());
+            b[44..48].copy_from_slice(&2u32.to_le_bytes());
+            b[48..50].copy_from_slice(&1u16.to_le_bytes());
+            b[50..52].copy_from_slice(&6u16.to_le_bytes());
+            64
+        }
+        _ => {
+            b[22..24].copy_from_slice(&(fat_size as u16).to_le_bytes());
+            36
+        }
+    };
+    b[ext] = 0x80;
+    b[ext + 2] = 0x29;
+    b[ext + 3..ext + 7].copy_from_slice(&opts.volume_id.to_le_bytes());
+    b[ext + 7..ext + 18].copy_from_slice(&opts.volume_label);
+    b[ext + 18..ext + 26].copy_from_slice(match fat_type {
+        FatType::Fat12 => b"FAT12   ",
+        FatType::Fat16 => b"FAT16   ",
+        FatType::Fat32 => b"FAT32   ",
+    });
+    b[510] = 0x55;
+    b[511] = 0xAA;
+    let bpb = Bpb::parse(&b).ok_or(Error::InvalidFileSystem)?;
+    write_block(dev, start_lba, &b).await?;
+
+    if fat_type == FatType::Fat32 {
+        write_block(dev, start_lba + 6, &b).await?;
+        let mut info = zero_block::<D>();
+        info[0..4].copy_from_slice(&FSINFO_LEAD_SIG.to_le_bytes());
+        info[484..488].copy_from_slice(&FSINFO_STRUC_SIG.to_le_bytes());
+        info[488..492].copy_from_slice(&(clusters - 1).to_le_bytes());
+        info[492..496].copy_from_slice(&3u32.to_le_bytes());
+        info[508..512].copy_from_slice(&FSINFO_TRAIL_SIG.to_le_bytes());
+        write_block(dev, start_lba + 1, &info).await?;
+        write_block(dev, start_lba + 7, &info).await?;
+    }
+
+    // Reserved entries 0 and 1, and the FAT32 root directory cluster.
+    let mut fat = zero_block::<D>();
+    let head: &[u8] = match fat_type {
+        FatType::Fat12 => &[0xF8, 0xFF, 0xFF],
+        FatType::Fat16 => &[0xF8, 0xFF, 0xFF, 0xFF],
+        FatType::Fat32 => &[0xF8, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F],
+    };
+    fat[..head.len()].copy_from_slice(head);
+    for i in 0..num_fats {
+        write_block(dev, start_lba + reserved + i * fat_size, &fat).await?;
+    }
+
+    if opts.volume_label != *b"NO NAME    " {
+        let mut root = zero_block::<D>();
+        let entry = short_entry(&opts.volume_label, 0, Attributes::VOLUME_ID, 0, 0, Timestamp::EPOCH);
+        root[..32].copy_from_slice(&entry);
+        let sector = match fat_type {
+            FatType::Fat32 => bpb.cluster_sector(2),
+            _ => bpb.root_dir_start,
+        };
+        write_block(dev, start_lba + sector, &root).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cluster_sizes() {
+        assert_eq!(default_sectors_per_cluster(FatType::Fat16, 8400), None);
+        assert_eq!(default_sectors_per_cluster(FatType::Fat16, 65536), Some(4));
+        assert_eq!(default_sectors_per_cluster(FatType::Fat32, 1 << 21), Some(8));
+        assert_eq!(default_sectors_per_cluster(FatType::Fat12, 2880), Some(1));
+        assert_eq!(default_sectors_per_cluster(FatType::Fat12, 8000), Some(2));
+    }
+}
@@ -0,0 +1,1210 @@
+//! FAT12/16/32 filesystem.
+//!
+//! A [`FileSystem`] owns its block device; pass `&mut dev` to keep using the
+//! device after unmounting. Files and directory iterators borrow the
+//! filesystem, so one of them can be open at a time.
+//!
+//! Sectors are cached, and metadata is only written back by
+//! [`File::flush`], [`File::close`], [`FileSystem::flush`] or
+//! [`FileSystem::unmount`]. Dropping a modified [`File`] without closing it
+//! leaves its directory entry stale.
+
+mod bpb;
+mod dir;
+mod format;
+
+pub use bpb::FatType;
+use dir::{
+    ATTR_LFN, DELETED, DirCursor, LfnBuilder, ShortName, lfn_entry, lfn_entry_count, names_equal, set_first_cluster,
+    short_entry, short_name_checksum, short_name_display, short_name_for, validate_name, with_numeric_tail,
+};
+pub use dir::{Attributes, DirEntry, MAX_NAME_LEN, Timestamp};
+pub use embedded_io_async::SeekFrom;
+pub use format::{FormatOptions, format};
+use heapless::String;
+
+use crate::partition::{PartitionInfo, read_partitions};
+use crate::{BLOCK_SIZE, Block, BlockDevice, Error, read_block, write_block, zero_block};
+
+/// FAT entry value of a free cluster.
+const FREE: u32 = 0;
+/// Directories may hold at most 65536 entries (FAT spec §6).
+const MAX_DIR_ENTRIES: u32 = 65536;
+/// Highest numeric tail tried when generating a short name.
+const MAX_NUMERIC_TAIL: u32 = 999_999;
+
+/// FSInfo signatures (FAT spec §5).
+const FSINFO_LEAD_SIG: u32 = 0x4161_5252;
+const FSINFO_STRUC_SIG: u32 = 0x6141_7272;
+const FSINFO_TRAIL_SIG: u32 = 0xAA55_0000;
+/// FSInfo value for an unknown free count or next free cluster.
+const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+/// How [`FileSystem::open`] opens a file. Files can always be read.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Mode {
+    /// Open an existing file read-only.
+    Read,
+    /// Open an existing file for reading and writing.
+    ReadWrite,
+    /// Create a file, or truncate it if it exists.
+    Create,
+    /// Create a file, failing with [`Error::AlreadyExists`] if it exists.
+    CreateNew,
+    /// Open or create a file, positioned at its end.
+    Append,
+}
+
+impl Mode {
+    fn writable(self) -> bool {
+        self != Self::Read
+    }
+}
+
+/// A single-sector cache.
+struct Cache<D: BlockDevice<BLOCK_SIZE>> {
+    block: Block<D>,
+    sector: Option<u32>,
+    dirty: bool,
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> Cache<D> {
+    fn new() -> Self {
+        Self {
+            block: zero_block::<D>(),
+            sector: None,
+            dirty: false,
+        }
+    }
+}
+
+/// Directory iteration state.
+struct DirState {
+    cursor: DirCursor,
+    done: bool,
+}
+
+impl DirState {
+    fn new(start: u32) -> Self {
+        Self {
+            cursor: DirCursor::new(start),
+            done: false,
+        }
+    }
+}
+
+fn default_time() -> Timestamp {
+    Timestamp::EPOCH
+}
+
+/// A mounted FAT volume.
+pub struct FileSystem<D: BlockDevice<BLOCK_SIZE>> {
+    dev: D,
+    /// First block of the volume on the device.
+    base: u32,
+    bpb: bpb::Bpb,
+    /// Cache for directory and file data.
+    data: Cache<D>,
+    /// Cache for the first FAT; writes are mirrored to the other copies.
+    fat: Cache<D>,
+    next_free: u32,
+    free_count: Option<u32>,
+    fs_info_dirty: bool,
+    time_source: fn() -> Timestamp,
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> FileSystem<D> {
+    /// Mount a volume occupying the whole device, without a partition table.
+    pub async fn mount(dev: D) -> Result<Self, Error<D::Error>> {
+        Self::mount_at(dev, 0, None).await
+    }
+
+    /// Mount a partition found by [`read_partitions`].
+    pub async fn mount_partition(dev: D, partition: &PartitionInfo) -> Result<Self, Error<D::Error>> {
+        let start = u32::try_from(partition.start_lba).map_err(|_| Error::OutOfBounds)?;
+        Self::mount_at(dev, start, Some(partition.block_count)).await
+    }
+
+    /// Mount the first FAT partition of the device, or the whole device if it
+    /// has no partition table.
+    pub async fn mount_auto(mut dev: D) -> Result<Self, Error<D::Error>> {
+        match read_partitions::<_, 4>(&mut dev).await {
+            Ok(parts) => {
+                let part = *parts.iter().find(|p| p.is_fat()).ok_or(Error::InvalidFileSystem)?;
+                Self::mount_partition(dev, &part).await
+            }
+            Err(Error::NoPartitionTable) => Self::mount(dev).await,
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn mount_at(mut dev: D, base: u32, limit: Option<u64>) -> Result<Self, Error<D::Error>> {
+        let mut block = zero_block::<D>();
+        read_block(&mut dev, base, &mut block).await?;
+        let bpb = bpb::Bpb::parse(&block).ok_or(Error::InvalidFileSystem)?;
+
+        let end = base.checked_add(bpb.total_sectors).ok_or(Error::OutOfBounds)?;
+        let device_blocks = dev.size().await? / BLOCK_SIZE as u64;
+        if end as u64 > device_blocks || limit.is_some_and(|l| bpb.total_sectors as u64 > l) {
+            return Err(Error::OutOfBounds);
+        }
+        debug!(
+            "Mounted {:?} volume: {} clusters of {} bytes",
+            bpb.fat_type,
+            bpb.cluster_count,
+            bpb.cluster_size()
+        );
+
+        let mut fs = Self {
+            dev,
+            base,
+            bpb,
+            data: Cache::new(),
+            fat: Cache::new(),
+            next_free: 2,
+            free_count: None,
+            fs_info_dirty: false,
+            time_source: default_time,
+        };
+        if bpb.fs_info != 0 {
+            let b = fs.load(bpb.fs_info, true).await?;
+            let u32_at = |o: usize| u32::from_le_bytes(b[o..o + 4].try_into().unwrap());
+            if u32_at(0) == FSINFO_LEAD_SIG && u32_at(484) == FSINFO_STRUC_SIG && u32_at(508) == FSINFO_TRAIL_SIG {
+                let (free, next) = (u32_at(488), u32_at(492));
+                if free <= bpb.cluster_count {
+                    fs.free_count = Some(free);
+                }
+                if bpb.is_valid_cluster(next) {
+                    fs.next_free = next;
+                }
+            } else {
+                warn!("Invalid FSInfo sector, ignoring it");
+                fs.bpb.fs_info = 0;
+            }
+        }
+        Ok(fs)
+    }
+
+    /// Write back all cached data and metadata.
+    pub async fn flush(&mut self) -> Result<(), Error<D::Error>> {
+        self.flush_data().await?;
+        self.flush_fat().await?;
+        if self.fs_info_dirty && self.bpb.fs_info != 0 {
+            let free = self.free_count.unwrap_or(FSINFO_UNKNOWN);
+            let next = self.next_free;
+            let b = self.load(self.bpb.fs_info, true).await?;
+            b[488..492].copy_from_slice(&free.to_le_bytes());
+            b[492..496].copy_from_slice(&next.to_le_bytes());
+            self.data.dirty = true;
+            self.flush_data().await?;
+        }
+        self.fs_info_dirty = false;
+        Ok(())
+    }
+
+    /// Flush and return the block device.
+    pub async fn unmount(mut self) -> Result<D, Error<D::Error>> {
+        self.flush().await?;
+        Ok(self.dev)
+    }
+
+    /// Set the clock used for file timestamps. Defaults to [`Timestamp::EPOCH`].
+    pub fn set_time_source(&mut self, time_source: fn() -> Timestamp) {
+        self.time_source = time_source;
+    }
+
+    /// FAT variant of the volume.
+    pub fn fat_type(&self) -> FatType {
+        self.bpb.fat_type
+    }
+
+    /// Cluster size in bytes.
+    pub fn cluster_size(&self) -> u32 {
+        self.bpb.cluster_size()
+    }
+
+    /// Volume serial number.
+    pub fn volume_id(&self) -> u32 {
+        self.bpb.volume_id
+    }
+
+    /// Total data space in bytes.
+    pub fn total_space(&self) -> u64 {
+        self.bpb.cluster_count as u64 * self.bpb.cluster_size() as u64
+    }
+
+    /// Free space in bytes. Scans the FAT unless the free cluster count is known.
+    pub async fn free_space(&mut self) -> Result<u64, Error<D::Error>> {
+        let free = match self.free_count {
+            Some(n) => n,
+            None => {
+                let mut n = 0;
+                for c in 2..self.bpb.cluster_count + 2 {
+                    if self.fat_entry(c).await? == FREE {
+                        n += 1;
+                    }
+                }
+                self.free_count = Some(n);
+                n
+            }
+        };
+        Ok(free as u64 * self.bpb.cluster_size() as u64)
+    }
+
+    /// The volume label, from the root directory or else the boot sector.
+    pub async fn volume_label(&mut self) -> Result<String<11>, Error<D::Error>> {
+        let mut cursor = DirCursor::new(self.root());
+        let mut raw_label = self.bpb.volume_label;
+        loop {
+            let raw = self.read_raw(&cursor).await?;
+            if raw[0] == 0 {
+                break;
+            }
+            if raw[0] != DELETED && raw[11] != ATTR_LFN && raw[11] & Attributes::VOLUME_ID.bits() != 0 {
+                raw_label.copy_from_slice(&raw[..11]);
+                break;
+            }
+            if !self.advance(&mut cursor, false).await? {
+                break;
+            }
+        }
+        let mut label = String::new();
+        let len = raw_label.iter().rposition(|&c| c != b' ').map_or(0, |i| i + 1);
+        for &c in &raw_label[..len] {
+            let _ = label.push(if c.is_ascii() { c as char } else { '_' });
+        }
+        Ok(label)
+    }
+
+    /// Iterate over a directory. `""` or `"/"` is the root directory.
+    pub async fn read_dir(&mut self, path: &str) -> Result<Dir<'_, D>, Error<D::Error>> {
+        let start = self.resolve_dir(path).await?;
+        Ok(Dir {
+            fs: self,
+            state: DirState::new(start),
+        })
+    }
+
+    /// Look up a file or directory.
+    pub async fn metadata(&mut self, path: &str) -> Result<DirEntry, Error<D::Error>> {
+        let (dir, name) = self.split_path(path).await?;
+        self.find(dir, name).await?.ok_or(Error::NotFound)
+    }
+
+    /// Open a file. Paths use `/` as separator and are matched case-insensitively.
+    pub async fn open(&mut self, path: &str, mode: Mode) -> Result<File<'_, D>, Error<D::Error>> {
+        let (dir, name) = self.split_path(path).await?;
+        let entry = match (self.find(dir, name).await?, mode) {
+            (Some(e), _) if e.is_dir() => return Err(Error::IsADirectory),
+            (Some(_), Mode::CreateNew) => return Err(Error::AlreadyExists),
+            (Some(e), m) if m.writable() && e.attributes.contains(Attributes::READ_ONLY) => {
+                return Err(Error::ReadOnly);
+            }
+            (Some(e), _) => e,
+            (None, Mode::Read | Mode::ReadWrite) => return Err(Error::NotFound),
+            (None, _) => self.create_entry(dir, name, Attributes::ARCHIVE, 0).await?,
+        };
+
+        let mut file = File {
+            fs: self,
+            entry: entry.pos,
+            first_cluster: entry.first_cluster,
+            size: entry.size,
+            pos: 0,
+            cluster: 0,
+            cluster_index: 0,
+            writable: mode.writable(),
+            dirty: false,
+        };
+        match mode {
+            Mode::Create => file.truncate().await?,
+            Mode::Append => file.pos = file.size,
+            _ => {}
+        }
+        Ok(file)
+    }
+
+    /// Create a directory. The parent directory must exist.
+    pub async fn create_dir(&mut self, path: &str) -> Result<(), Error<D::Error>> {
+        let (dir, name) = self.split_path(path).await?;
+        if !validate_name(name) {
+            return Err(Error::InvalidName);
+        }
+        if self.find(dir, name).await?.is_some() {
+            return Err(Error::AlreadyExists);
+        }
+
+        let cluster = self.alloc_cluster(None).await?;
+        let result = async {
+            self.zero_cluster(cluster).await?;
+            let now = (self.time_source)();
+            let parent = if dir == self.root() { 0 } else { dir };
+            let mut cursor = DirCursor::new(cluster);
+            let dot = short_entry(b".          ", 0, Attributes::DIRECTORY, cluster, 0, now);
+            self.write_raw(&cursor, &dot).await?;
+            cursor.index = 1;
+            let dotdot = short_entry(b"..         ", 0, Attributes::DIRECTORY, parent, 0, now);
+            self.write_raw(&cursor, &dotdot).await?;
+            self.create_entry(dir, name, Attributes::DIRECTORY, cluster).await
+        }
+        .await;
+        if let Err(e) = result {
+            self.free_chain(cluster).await?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Remove a file or an empty directory.
+    pub async fn remove(&mut self, path: &str) -> Result<(), Error<D::Error>> {
+        let (dir, name) = self.split_path(path).await?;
+        if !validate_name(name) {
+            return Err(Error::InvalidName);
+        }
+        let entry = self.find(dir, name).await?.ok_or(Error::NotFound)?;
+        if entry.attributes.contains(Attributes::READ_ONLY) {
+            return Err(Error::ReadOnly);
+        }
+        if entry.is_dir() {
+            let mut state = DirState::new(self.dir_cluster(entry.first_cluster));
+            if self.next_entry(&mut state, false).await?.is_some() {
+                return Err(Error::DirectoryNotEmpty);
+            }
+        }
+
+        let mut cursor = entry.first_pos;
+        for i in 0..entry.entry_count {
+            let mut raw = self.read_raw(&cursor).await?;
+            raw[0] = DELETED;
+            self.write_raw(&cursor, &raw).await?;
+            if i + 1 < entry.entry_count && !self.advance(&mut cursor, false).await? {
+                return Err(Error::Corrupt);
+            }
+        }
+        if entry.first_cluster != 0 {
+            self.free_chain(entry.first_cluster).await?;
+        }
+        Ok(())
+    }
+
+    // Paths and directories.
+
+    /// First cluster of the root directory, 0 for a FAT12/16 root directory.
+    fn root(&self) -> u32 {
+        self.bpb.root_cluster
+    }
+
+    /// Directory start for a first cluster stored in a directory entry.
+    fn dir_cluster(&self, cluster: u32) -> u32 {
+        if cluster == 0 { self.root() } else { cluster }
+    }
+
+    /// Resolve a directory path to its first cluster.
+    async fn resolve_dir(&mut self, path: &str) -> Result<u32, Error<D::Error>> {
+        let mut dir = self.root();
+        for name in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+            let entry = self.find(dir, name).await?.ok_or(Error::NotFound)?;
+            if !entry.is_dir() {
+                return Err(Error::NotADirectory);
+            }
+            dir = self.dir_cluster(entry.first_cluster);
+        }
+        Ok(dir)
+    }
+
+    /// Split a path into its parent directory and last component.
+    async fn split_path<'p>(&mut self, path: &'p str) -> Result<(u32, &'p str), Error<D::Error>> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if name.is_empty() {
+            return Err(Error::InvalidName);
+        }
+        Ok((self.resolve_dir(parent).await?, name))
+    }
+
+    /// Find an entry by long or short name.
+    async fn find(&mut self, dir: u32, name: &str) -> Result<Option<DirEntry>, Error<D::Error>> {
+        let mut state = DirState::new(dir);
+        while let Some(entry) = self.next_entry(&mut state, true).await? {
+            if names_equal(&entry.name, name) || names_equal(&entry.short_name(), name) {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whether a short name is used in a directory.
+    async fn short_name_exists(&mut self, dir: u32, short: &[u8; 11]) -> Result<bool, Error<D::Error>> {
+        let mut state = DirState::new(dir);
+        while let Some(entry) = self.next_entry(&mut state, true).await? {
+            if &entry.short_name == short {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Return the next file or directory entry.
+    async fn next_entry(&mut self, state: &mut DirState, dots: bool) -> Result<Option<DirEntry>, Error<D::Error>> {
+        let mut lfn = LfnBuilder::new();
+        while !state.done {
+            let pos = state.cursor;
+            let raw = self.read_raw(&pos).await?;
+            if raw[0] == 0 {
+                state.done = true;
+                break;
+            }
+            if !self.advance(&mut state.cursor, false).await? {
+                state.done = true;
+            }
+            if raw[0] == DELETED {
+                lfn.reset();
+                continue;
+            }
+            if raw[11] & 0x3F == ATTR_LFN {
+                lfn.push(&raw, pos);
+                continue;
+            }
+            if raw[11] & Attributes::VOLUME_ID.bits() != 0 || (!dots && raw[0] == b'.') {
+                lfn.reset();
+                continue;
+            }
+
+            let mut entry = DirEntry::parse(&raw, pos);
+            match lfn.finish(short_name_checksum(&entry.short_name)) {
+                Some((name, count)) => {
+                    entry.name = name;
+                    entry.first_pos = lfn.first_pos;
+                    entry.entry_count = count + 1;
+                }
+                None => {
+                    let short = short_name_display(&entry.short_name, raw[12]);
+                    entry.name.push_str(&short).unwrap();
+                }
+            }
+            return Ok(Some(entry));
+        }
+        Ok(None)
+    }
+
+    /// Add an entry, with long name entries if needed, to a directory.
+    async fn create_entry(
+        &mut self,
+        dir: u32,
+        name: &str,
+        attributes: Attributes,
+        first_cluster: u32,
+    ) -> Result<DirEntry, Error<D::Error>> {
+        if !validate_name(name) {
+            return Err(Error::InvalidName);
+        }
+        let (short, nt_flags, long) = match short_name_for(name) {
+            ShortName::Exact(raw, flags) => (raw, flags, false),
+            ShortName::Basis(basis, lossy) => {
+                let short = if !lossy && !self.short_name_exists(dir, &basis).await? {
+                    basis
+                } else {
+                    let mut found = None;
+                    for n in 1..=MAX_NUMERIC_TAIL {
+                        let candidate = with_numeric_tail(&basis, n);
+                        if !self.short_name_exists(dir, &candidate).await? {
+                            found = Some(candidate);
+                            break;
+                        }
+                    }
+                    found.ok_or(Error::DirectoryFull)?
+                };
+                (short, 0, true)
+            }
+        };
+        let lfn_count = if long { lfn_entry_count(name) } else { 0 };
+        let count = lfn_count as u32 + 1;
+
+        // Find `count` consecutive free entries, extending the directory if needed.
+        let mut cursor = DirCursor::new(dir);
+        let mut first = cursor;
+        let mut run = 0;
+        loop {
+            let raw = self.read_raw(&cursor).await?;
+            if raw[0] == 0 || raw[0] == DELETED {
+                if run == 0 {
+                    first = cursor;
+                }
+                run += 1;
+                if run == count {
+                    break;
+                }
+            } else {
+                run = 0;
+            }
+            if !self.advance(&mut cursor, true).await? {
+                return Err(Error::DirectoryFull);
+            }
+        }
+
+        let checksum = short_name_checksum(&short);
+        let mut pos = first;
+        for seq in (1..=lfn_count).rev() {
+            self.write_raw(&pos, &lfn_entry(name, seq, seq == lfn_count, checksum))
+                .await?;
+            self.advance(&mut pos, false).await?;
+        }
+        let raw = short_entry(&short, nt_flags, attributes, first_cluster, 0, (self.time_source)());
+        self.write_raw(&pos, &raw).await?;
+
+        let mut entry = DirEntry::parse(&raw, pos);
+        entry.name.push_str(name).map_err(|_| Error::InvalidName)?;
+        entry.first_pos = first;
+        entry.entry_count = count as u8;
+        Ok(entry)
+    }
+
+    /// Sector and byte offset of a directory entry.
+    fn entry_location(&self, cursor: &DirCursor) -> (u32, usize) {
+        let base = if cursor.start == 0 {
+            self.bpb.root_dir_start
+        } else {
+            self.bpb.cluster_sector(cursor.cluster)
+        };
+        let per_sector = bpb::DIR_ENTRIES_PER_SECTOR;
+        (
+            base + cursor.index / per_sector,
+            (cursor.index % per_sector * bpb::DIR_ENTRY_SIZE) as usize,
+        )
+    }
+
+    async fn read_raw(&mut self, cursor: &DirCursor) -> Result<[u8; 32], Error<D::Error>> {
+        let (sector, offset) = self.entry_location(cursor);
+        let b = self.load(sector, true).await?;
+        Ok(b[offset..offset + 32].try_into().unwrap())
+    }
+
+    async fn write_raw(&mut self, cursor: &DirCursor, raw: &[u8; 32]) -> Result<(), Error<D::Error>> {
+        let (sector, offset) = self.entry_location(cursor);
+        let b = self.load(sector, true).await?;
+        b[offset..offset + 32].copy_from_slice(raw);
+        self.data.dirty = true;
+        Ok(())
+    }
+
+    /// Move to the next directory entry. Returns `false` at the end of the
+    /// directory, unless `extend` allows allocating a new cluster.
+    async fn advance(&mut self, cursor: &mut DirCursor, extend: bool) -> Result<bool, Error<D::Error>> {
+        cursor.index += 1;
+        if cursor.start == 0 {
+            return Ok(cursor.index < self.bpb.root_entries);
+        }
+        let per_cluster = self.bpb.dir_entries_per_cluster();
+        if cursor.index < per_cluster {
+            return Ok(true);
+        }
+        let next = match self.next_cluster(cursor.cluster).await? {
+            Some(next) => next,
+            None if extend => {
+                let next = self.alloc_cluster(Some(cursor.cluster)).await?;
+                self.zero_cluster(next).await?;
+                next
+            }
+            None => return Ok(false),
+        };
+        // Guards against cycles in corrupt directory chains.
+        cursor.clusters += 1;
+        if cursor.clusters * per_cluster >= MAX_DIR_ENTRIES {
+            return Ok(false);
+        }
+        cursor.cluster = next;
+        cursor.index = 0;
+        Ok(true)
+    }
+
+    // Clusters and FAT.
+
+    /// Value of a FAT entry.
+    async fn fat_entry(&mut self, cluster: u32) -> Result<u32, Error<D::Error>> {
+        match self.bpb.fat_type {
+            FatType::Fat12 => {
+                let off = cluster + cluster / 2;
+                let v = self.fat_byte(off).await? as u32 | (self.fat_byte(off + 1).await? as u32) << 8;
+                Ok(if cluster & 1 == 1 { v >> 4 } else { v & 0xFFF })
+            }
+            FatType::Fat16 => {
+                let (b, o) = self.fat_slot(cluster * 2).await?;
+                Ok(u16::from_le_bytes([b[o], b[o + 1]]) as u32)
+            }
+            FatType::Fat32 => {
+                let (b, o) = self.fat_slot(cluster * 4).await?;
+                Ok(u32::from_le_bytes(b[o..o + 4].try_into().unwrap()) & 0x0FFF_FFFF)
+            }
+        }
+    }
+
+    async fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), Error<D::Error>> {
+        match self.bpb.fat_type {
+            FatType::Fat12 => {
+                let off = cluster + cluster / 2;
+                let (lo, hi) = (self.fat_byte(off).await?, self.fat_byte(off + 1).await?);
+                let (lo, hi) = if cluster & 1 == 1 {
+                    ((lo & 0x0F) | (value << 4) as u8, (value >> 4) as u8)
+                } else {
+                    (value as u8, (hi & 0xF0) | ((value >> 8) as u8 & 0x0F))
+                };
+                self.set_fat_byte(off, lo).await?;
+                self.set_fat_byte(off + 1, hi).await?;
+            }
+            FatType::Fat16 => {
+                let (b, o) = self.fat_slot(cluster * 2).await?;
+                b[o..o + 2].copy_from_slice(&(value as u16).to_le_bytes());
+                self.fat.dirty = true;
+            }
+            FatType::Fat32 => {
+                let (b, o) = self.fat_slot(cluster * 4).await?;
+                let old = u32::from_le_bytes(b[o..o + 4].try_into().unwrap());
+                let new = (old & 0xF000_0000) | (value & 0x0FFF_FFFF);
+                b[o..o + 4].copy_from_slice(&new.to_le_bytes());
+                self.fat.dirty = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Next cluster in a chain, or `None` at its end.
+    async fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>, Error<D::Error>> {
+        let next = self.fat_entry(cluster).await?;
+        if next >= self.bpb.fat_type.end_of_chain() {
+            Ok(None)
+        } else if self.bpb.is_valid_cluster(next) {
+            Ok(Some(next))
+        } else {
+            Err(Error::Corrupt)
+        }
+    }
+
+    /// Allocate a cluster, appending it to the chain ending at `prev`.
+    async fn alloc_cluster(&mut self, prev: Option<u32>) -> Result<u32, Error<D::Error>> {
+        let count = self.bpb.cluster_count;
+        let start = if self.bpb.is_valid_cluster(self.next_free) {
+            self.next_free - 2
+        } else {
+            0
+        };
+        for i in 0..count {
+            let cluster = 2 + (start + i) % count;
+            if self.fat_entry(cluster).await? != FREE {
+                continue;
+            }
+            self.set_fat_entry(cluster, 0x0FFF_FFFF).await?;
+            if let Some(prev) = prev {
+                self.set_fat_entry(prev, cluster).await?;
+            }
+            self.next_free = cluster + 1;
+            if let Some(n) = &mut self.free_count {
+                *n = n.saturating_sub(1);
+            }
+            self.fs_info_dirty = true;
+            return Ok(cluster);
+        }
+        Err(Error::DiskFull)
+    }
+
+    /// Free a cluster chain.
+    async fn free_chain(&mut self, first: u32) -> Result<(), Error<D::Error>> {
+        let mut cluster = first;
+        for _ in 0..self.bpb.cluster_count {
+            let next = self.next_cluster(cluster).await;
+            self.set_fat_entry(cluster, FREE).await?;
+            if let Some(n) = &mut self.free_count {
+                *n += 1;
+            }
+            self.fs_info_dirty = true;
+            match next? {
+                Some(n) => cluster = n,
+                None => return Ok(()),
+            }
+        }
+        Err(Error::Corrupt)
+    }
+
+    /// Zero all sectors of a cluster.
+    async fn zero_cluster(&mut self, cluster: u32) -> Result<(), Error<D::Error>> {
+        let first = self.bpb.cluster_sector(cluster);
+        let zero = zero_block::<D>();
+        for sector in first..first + self.bpb.sectors_per_cluster {
+            if self.data.sector == Some(sector) {
+                self.data.sector = None;
+                self.data.dirty = false;
+            }
+            let lba = self.lba(sector)?;
+            write_block(&mut self.dev, lba, &zero).await?;
+        }
+        Ok(())
+    }
+
+    // Sector cache.
+
+    /// Device block of a volume sector.
+    fn lba(&self, sector: u32) -> Result<u32, Error<D::Error>> {
+        if sector < self.bpb.total_sectors {
+            Ok(self.base + sector)
+        } else {
+            Err(Error::Corrupt)
+        }
+    }
+
+    /// Load a sector into the data cache. With `read` false the sector is
+    /// zeroed instead of read, for sectors about to be overwritten.
+    async fn load(&mut self, sector: u32, read: bool) -> Result<&mut [u8; BLOCK_SIZE], Error<D::Error>> {
+        if self.data.sector != Some(sector) {
+            self.flush_data().await?;
+            let lba = self.lba(sector)?;
+            self.data.sector = None;
+            if read {
+                read_block(&mut self.dev, lba, &mut self.data.block).await?;
+            } else {
+                self.data.block.fill(0);
+            }
+            self.data.sector = Some(sector);
+        }
+        Ok(&mut self.data.block)
+    }
+
+    async fn flush_data(&mut self) -> Result<(), Error<D::Error>> {
+        if let (true, Some(sector)) = (self.data.dirty, self.data.sector) {
+            write_block(&mut self.dev, self.base + sector, &self.data.block).await?;
+        }
+        self.data.dirty = false;
+        Ok(())
+    }
+
+    /// Load the FAT sector holding byte `offset` of the FAT, returning the sector and offset within it.
+    async fn fat_slot(&mut self, offset: u32) -> Result<(&mut [u8; BLOCK_SIZE], usize), Error<D::Error>> {
+        let sector = self.bpb.fat_start + offset / BLOCK_SIZE as u32;
+        if self.fat.sector != Some(sector) {
+            self.flush_fat().await?;
+            let lba = self.lba(sector)?;
+            self.fat.sector = None;
+            read_block(&mut self.dev, lba, &mut self.fat.block).await?;
+            self.fat.sector = Some(sector);
+        }
+        Ok((&mut self.fat.block, offset as usize % BLOCK_SIZE))
+    }
+
+    async fn fat_byte(&mut self, offset: u32) -> Result<u8, Error<D::Error>> {
+        let (b, o) = self.fat_slot(offset).await?;
+        Ok(b[o])
+    }
+
+    async fn set_fat_byte(&mut self, offset: u32, value: u8) -> Result<(), Error<D::Error>> {
+        let (b, o) = self.fat_slot(offset).await?;
+        b[o] = value;
+        self.fat.dirty = true;
+        Ok(())
+    }
+
+    /// Write the cached FAT sector to all FAT copies.
+    async fn flush_fat(&mut self) -> Result<(), Error<D::Error>> {
+        if let (true, Some(sector)) = (self.fat.dirty, self.fat.sector) {
+            for i in 0..self.bpb.num_fats {
+                let lba = self.lba(sector + i * self.bpb.fat_size)?;
+                write_block(&mut self.dev, lba, &self.fat.block).await?;
+            }
+        }
+        self.fat.dirty = false;
+        Ok(())
+    }
+}
+
+/// Iterator over the entries of a directory, returned by [`FileSystem::read_dir`].
+///
+/// The `.` and `..` entries and the volume label are skipped.
+pub struct Dir<'a, D: BlockDevice<BLOCK_SIZE>> {
+    fs: &'a mut FileSystem<D>,
+    state: DirState,
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> Dir<'_, D> {
+    /// Return the next entry, or `None` at the end of the directory.
+    pub async fn next(&mut self) -> Result<Option<DirEntry>, Error<D::Error>> {
+        self.fs.next_entry(&mut self.state, false).await
+    }
+
+    /// Restart iteration from the first entry.
+    pub fn rewind(&mut self) {
+        self.state = DirState::new(self.state.cursor.start);
+    }
+}
+
+/// An open file, returned by [`FileSystem::open`].
+///
+/// Seeking past the end of the file is not supported; files grow by writing
+/// at their end.
+pub struct File<'a, D: BlockDevice<BLOCK_SIZE>> {
+    fs: &'a mut FileSystem<D>,
+    /// Position of the short directory entry.
+    entry: DirCursor,
+    first_cluster: u32,
+    size: u32,
+    pos: u32,
+    /// Cached cluster of the chain and its index, 0 if unknown.
+    cluster: u32,
+    cluster_index: u32,
+    writable: bool,
+    dirty: bool,
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> File<'_, D> {
+    /// File size in bytes.
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Current position.
+    pub fn position(&self) -> u32 {
+        self.pos
+    }
+
+    /// Read at the current position. Returns 0 at the end of the file.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error<D::Error>> {
+        let mut n = 0;
+        while n < buf.len() && self.pos < self.size {
+            let cluster = self.cluster_at(self.pos / self.fs.cluster_size(), false).await?;
+            let cluster = cluster.ok_or(Error::Corrupt)?;
+            let sector = self.sector_of(cluster);
+            let off = self.pos as usize % BLOCK_SIZE;
+            let len = (BLOCK_SIZE - off)
+                .min(buf.len() - n)
+                .min((self.size - self.pos) as usize);
+            let b = self.fs.load(sector, true).await?;
+            buf[n..n + len].copy_from_slice(&b[off..off + len]);
+            n += len;
+            self.pos += len as u32;
+        }
+        Ok(n)
+    }
+
+    /// Write at the current position, growing the file as needed.
+    pub async fn write(&mut self, buf: &[u8]) -> Result<usize, Error<D::Error>> {
+        if !self.writable {
+            return Err(Error::ReadOnly);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let max = (u32::MAX - self.pos) as usize;
+        if max == 0 {
+            return Err(Error::FileTooLarge);
+        }
+        let buf = &buf[..buf.len().min(max)];
+
+        let mut n = 0;
+        while n < buf.len() {
+            let cluster = self.cluster_at(self.pos / self.fs.cluster_size(), true).await?;
+            let cluster = cluster.ok_or(Error::Corrupt)?;
+            let sector = self.sector_of(cluster);
+            let off = self.pos as usize % BLOCK_SIZE;
+            let len = (BLOCK_SIZE - off).min(buf.len() - n);
+            // Sectors overwritten entirely or past the end need not be read.
+            let read = !(off == 0 && (len == BLOCK_SIZE || self.pos >= self.size));
+            let b = self.fs.load(sector, read).await?;
+            b[off..off + len].copy_from_slice(&buf[n..n + len]);
+            self.fs.data.dirty = true;
+            n += len;
+            self.pos += len as u32;
+            self.size = self.size.max(self.pos);
+            self.dirty = true;
+        }
+        Ok(n)
+    }
+
+    /// Write all of `buf`.
+    pub async fn write_all(&mut self, mut buf: &[u8]) -> Result<(), Error<D::Error>> {
+        while !buf.is_empty() {
+            let n = self.write(buf).await?;
+            buf = &buf[n..];
+        }
+        Ok(())
+    }
+
+    /// Move the position within the file and return the new position.
+    pub async fn seek(&mut self, pos: SeekFrom) -> Result<u32, Error<D::Error>> {
+        let new = match pos {
+            SeekFrom::Start(n) => i64::try_from(n).unwrap_or(i64::MAX),
+            SeekFrom::End(d) => (self.size as i64).saturating_add(d),
+            SeekFrom::Current(d) => (self.pos as i64).saturating_add(d),
+        };
+        if !(0..=self.size as i64).contains(&new) {
+            return Err(Error::InvalidSeek);
+        }
+        self.pos = new as u32;
+        Ok(self.pos)
+    }
+
+    /// Truncate the file at the current position, freeing the clusters past it.
+    pub async fn truncate(&mut self) -> Result<(), Error<D::Error>> {
+        if !self.writable {
+            return Err(Error::ReadOnly);
+        }
+        if self.pos >= self.size {
+            return Ok(());
+        }
+        let keep = self.pos.div_ceil(self.fs.cluster_size());
+        if keep == 0 {
+            if self.first_cluster != 0 {
+                self.fs.free_chain(self.first_cluster).await?;
+            }
+            self.first_cluster = 0;
+        } else {
+            let last = self.cluster_at(keep - 1, false).await?.ok_or(Error::Corrupt)?;
+            if let Some(next) = self.fs.next_cluster(last).await? {
+                self.fs.set_fat_entry(last, 0x0FFF_FFFF).await?;
+                self.fs.free_chain(next).await?;
+            }
+        }
+        self.cluster = 0;
+        self.cluster_index = 0;
+        self.size = self.pos;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Update the directory entry and write back all cached data.
+    pub async fn flush(&mut self) -> Result<(), Error<D::Error>> {
+        if self.dirty {
+            let mut raw = self.fs.read_raw(&self.entry).await?;
+            let (date, time) = (self.fs.time_source)().to_fat();
+            raw[11] |= Attributes::ARCHIVE.bits();
+            raw[18..20].copy_from_slice(&date.to_le_bytes());
+            raw[22..24].copy_from_slice(&time.to_le_bytes());
+            raw[24..26].copy_from_slice(&date.to_le_bytes());
+            set_first_cluster(&mut raw, self.first_cluster);
+            raw[28..32].copy_from_slice(&self.size.to_le_bytes());
+            self.fs.write_raw(&self.entry, &raw).await?;
+            self.dirty = false;
+        }
+        self.fs.flush().await
+    }
+
+    /// Flush and close the file.
+    pub async fn close(mut self) -> Result<(), Error<D::Error>> {
+        self.flush().await
+    }
+
+    /// Sector holding the current position, within `cluster`.
+    fn sector_of(&self, cluster: u32) -> u32 {
+        self.fs.bpb.cluster_sector(cluster) + (self.pos % self.fs.cluster_size()) / BLOCK_SIZE as u32
+    }
+
+    /// Cluster `index` of the file's chain, allocating missing clusters if `alloc` is set.
+    async fn cluster_at(&mut self, index: u32, alloc: bool) -> Result<Option<u32>, Error<D::Error>> {
+        if self.first_cluster == 0 {
+            if !alloc {
+                return Ok(None);
+            }
+            self.first_cluster = self.fs.alloc_cluster(None).await?;
+            self.dirty = true;
+            self.cluster = 0;
+        }
+        if self.cluster == 0 || self.cluster_index > index {
+            self.cluster = self.first_cluster;
+            self.cluster_index = 0;
+        }
+        while self.cluster_index < index {
+            let next = match self.fs.next_cluster(self.cluster).await? {
+                Some(next) => next,
+                None if alloc => self.fs.alloc_cluster(Some(self.cluster)).await?,
+                None => return Ok(None),
+            };
+            self.cluster = next;
+            self.cluster_index += 1;
+        }
+        Ok(Some(self.cluster))
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> embedded_io_async::ErrorType for File<'_, D> {
+    type Error = Error<D::Error>;
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> embedded_io_async::Read for File<'_, D> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        File::read(self, buf).await
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> embedded_io_async::Write for File<'_, D> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        File::write(self, buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        File::flush(self).await
+    }
+}
+
+impl<D: BlockDevice<BLOCK_SIZE>> embedded_io_async::Seek for File<'_, D> {
+    async fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+        Ok(File::seek(self, pos).await? as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::testutil::RamDisk;
+
+    fn formatted(sectors: u32, fat_type: FatType) -> RamDisk {
+        let mut disk = RamDisk::new(sectors as usize);
+        let opts = FormatOptions {
+            fat_type: Some(fat_type),
+            ..Default::default()
+        };
+        block_on(format(&mut disk, 0, sectors, &opts)).unwrap();
+        disk
+    }
+
+    #[test]
+    fn fat12_entries_span_sectors() {
+        let mut disk = formatted(4096, FatType::Fat12);
+        block_on(async {
+            let mut fs = FileSystem::mount(&mut disk).await.unwrap();
+            assert_eq!(fs.fat_type(), FatType::Fat12);
+            // Entry 341 occupies bytes 511 and 512 of the FAT.
+            fs.set_fat_entry(341, 0xABC).await.unwrap();
+            fs.set_fat_entry(340, 0x123).await.unwrap();
+            fs.set_fat_entry(342, 0x456).await.unwrap();
+            assert_eq!(fs.fat_entry(341).await.unwrap(), 0xABC);
+            assert_eq!(fs.fat_entry(340).await.unwrap(), 0x123);
+            assert_eq!(fs.fat_entry(342).await.unwrap(), 0x456);
+            fs.flush().await.unwrap();
+        });
+        // Both FAT copies were written.
+        let bpb = bpb::Bpb::parse(disk.data[..512].try_into().unwrap()).unwrap();
+        let fat1 = bpb.fat_start as usize * 512;
+        let fat2 = (bpb.fat_start + bpb.fat_size) as usize * 512;
+        let len = bpb.fat_size as usize * 512;
+        assert_eq!(disk.data[fat1..fat1 + len], disk.data[fat2..fat2 + len]);
+    }
+
+    #[test]
+    fn files_and_directories() {
+        for (sectors, fat_type) in [
+            (4096, FatType::Fat12),
+            (65536, FatType::Fat16),
+            (140_000, FatType::Fat32),
+        ] {
+            let mut disk = formatted(sectors, fat_type);
+            block_on(async {
+                let mut fs = FileSystem::mount(&mut disk).await.unwrap();
+                assert_eq!(fs.fat_type(), fat_type);
+                let free = fs.free_space().await.unwrap();
+
+                fs.create_dir("Documents").await.unwrap();
+                assert_eq!(fs.create_dir("documents").await, Err(Error::AlreadyExists));
+                let mut f = fs
+                    .open("/Documents/A long file name.txt", Mode::CreateNew)
+                    .await
+                    .unwrap();
+                let data: [u8; 3000] = core::array::from_fn(|i| i as u8);
+                f.write_all(&data).await.unwrap();
+                f.seek(SeekFrom::Start(10)).await.unwrap();
+                let mut buf = [0; 20];
+                assert_eq!(f.read(&mut buf).await.unwrap(), 20);
+                assert_eq!(buf[..], data[10..30]);
+                assert_eq!(f.seek(SeekFrom::End(1)).await, Err(Error::InvalidSeek));
+                f.close().await.unwrap();
+
+                let e = fs.metadata("DOCUMENTS/a long FILE name.TXT").await.unwrap();
+                assert_eq!(e.name(), "A long file name.txt");
+                assert_eq!(e.short_name(), "ALONGF~1.TXT");
+                assert_eq!(e.size(), 3000);
+
+                let mut dir = fs.read_dir("/").await.unwrap();
+                let e = dir.next().await.unwrap().unwrap();
+                assert_eq!(e.name(), "Documents");
+                assert!(e.is_dir());
+                assert!(dir.next().await.unwrap().is_none());
+
+                assert_eq!(fs.remove("Documents").await, Err(Error::DirectoryNotEmpty));
+                fs.remove("Documents/A long file name.txt").await.unwrap();
+                fs.remove("Documents").await.unwrap();
+                assert!(fs.read_dir("").await.unwrap().next().await.unwrap().is_none());
+                fs.flush().await.unwrap();
+                assert_eq!(fs.free_space().await.unwrap(), free);
+            });
+        }
+    }
+
+    #[test]
+    fn truncate_and_append() {
+        let mut disk = formatted(4096, FatType::Fat12);
+        block_on(async {
+            let mut fs = FileSystem::mount(&mut disk).await.unwrap();
+            let free = fs.free_space().await.unwrap();
+            let cluster = fs.cluster_size() as usize;
+
+            let mut f = fs.open("log.txt", Mode::Create).await.unwrap();
+            f.write_all(&[b'a'; 4096]).await.unwrap();
+            f.seek(SeekFrom::Start(cluster as u64 + 1)).await.unwrap();
+            f.truncate().await.unwrap();
+            assert_eq!(f.size() as usize, cluster + 1);
+            f.close().await.unwrap();
+            assert_eq!(fs.free_space().await.unwrap(), free - 2 * cluster as u64);
+
+            let mut f = fs.open("LOG.TXT", Mode::Append).await.unwrap();
+            f.write_all(b"bc").await.unwrap();
+            f.close().await.unwrap();
+            let mut f = fs.open("log.txt", Mode::Read).await.unwrap();
+            assert_eq!(f.size() as usize, cluster + 3);
+            f.seek(SeekFrom::End(-3)).await.unwrap();
+            let mut buf = [0; 8];
+            assert_eq!(f.read(&mut buf).await.unwrap(), 3);
+            assert_eq!(&buf[..3], b"abc");
+            assert_eq!(f.write(b"x").await, Err(Error::ReadOnly));
+
+            let f = fs.open("log.txt", Mode::Create).await.unwrap();
+            assert_eq!(f.size(), 0);
+            f.close().await.unwrap();
+            assert_eq!(fs.free_space().await.unwrap(), free);
+        });
+    }
+
+    #[test]
+    fn root_directory_full() {
+        let mut disk = formatted(4096, FatType::Fat12);
+        block_on(async {
+            let mut fs = FileSystem::mount(&mut disk).await.unwrap();
+            let mut name = String::<12>::new();
+            for i in 0..512 {
+                name.clear();
+                core::fmt::write(&mut name, format_args!("F{}", i)).unwrap();
+                fs.open(&name, Mode::CreateNew).await.unwrap().close().await.unwrap();
+            }
+            assert_eq!(fs.open("X", Mode::CreateNew).await.err(), Some(Error::DirectoryFull));
+        });
+    }
+
+    #[test]
+    fn subdirectory_grows() {
+        let mut disk = formatted(4096, FatType::Fat12);
+        block_on(async {
+            let mut fs = FileSystem::mount(&mut disk).await.unwrap();
+            fs.create_dir("d").await.unwrap();
+            let per_cluster = fs.cluster_size() / 32;
+            let mut name = String::<32>::new();
+            for i in 0..per_cluster {
+                name.clear();
+                core::fmt::write(&mut name, format_args!("d/file number {}", i)).unwrap();
+                fs.open(&name, Mode::CreateNew).await.unwrap().close().await.unwrap();
+            }
+            let mut dir = fs.read_dir("d").await.unwrap();
+            let mut count = 0;
+            while let Some(e) = dir.next().await.unwrap() {
+                assert!(e.name().starts_with("file number "));
+                count += 1;
+            }
+            assert_eq!(count, per_cluster);
+        });
+    }
+}
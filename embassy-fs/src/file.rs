@@ -0,0 +1,59 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::{BLOCK_SIZE, Block, BlockDevice};
+
+/// Block device backed by a disk image file.
+///
+/// Intended for testing on a host, e.g. against an image created with
+/// `mkfs.fat -C disk.img 65536`.
+pub struct FileBlockDevice {
+    file: File,
+}
+
+impl FileBlockDevice {
+    /// Wrap an open file. It must be opened for reading, and for writing if
+    /// the filesystem is modified.
+    pub fn new(file: File) -> Self {
+        Self { file }
+    }
+
+    /// Open an existing image file for reading and writing.
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = File::options().read(true).write(true).open(path)?;
+        Ok(Self::new(file))
+    }
+
+    /// Return the underlying file.
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+}
+
+impl BlockDevice<BLOCK_SIZE> for FileBlockDevice {
+    type Error = std::io::Error;
+    type Align = aligned::A4;
+
+    async fn read(&mut self, block_address: u32, data: &mut [Block<Self>]) -> Result<(), Self::Error> {
+        self.file
+            .seek(SeekFrom::Start(block_address as u64 * BLOCK_SIZE as u64))?;
+        for block in data {
+            self.file.read_exact(&mut block[..])?;
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, block_address: u32, data: &[Block<Self>]) -> Result<(), Self::Error> {
+        self.file
+            .seek(SeekFrom::Start(block_address as u64 * BLOCK_SIZE as u64))?;
+        for block in data {
+            self.file.write_all(&block[..])?;
+        }
+        Ok(())
+    }
+
+    async fn size(&mut self) -> Result<u64, Self::Error> {
+        Ok(self.file.metadata()?.len())
+    }
+}
@@ -0,0 +1,188 @@
+#![no_std]
+#![allow(async_fn_in_trait)]
+#![doc = include_str!("../README.md")]
+#![warn(missing_docs)]
+
+#[cfg(any(feature = "std", test))]
+extern crate std;
+
+// This mod MUST go first, so that the others see its macros.
+pub(crate) mod fmt;
+
+pub mod fat;
+#[cfg(feature = "std")]
+mod file;
+pub mod partition;
+
+pub use block_device_driver::BlockDevice;
+#[cfg(feature = "std")]
+pub use file::FileBlockDevice;
+
+/// Block size supported by this crate, in bytes.
+pub const BLOCK_SIZE: usize = 512;
+
+/// A block buffer with the alignment required by the device `D`.
+pub(crate) type Block<D> = aligned::Aligned<<D as BlockDevice<BLOCK_SIZE>>::Align, [u8; BLOCK_SIZE]>;
+
+/// Filesystem and partition table error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    /// The block device returned an error.
+    Device(E),
+    /// The device has no MBR or GPT.
+    NoPartitionTable,
+    /// The partition table is corrupt.
+    InvalidPartitionTable,
+    /// The volume is not a supported FAT filesystem.
+    InvalidFileSystem,
+    /// The filesystem structures are inconsistent, e.g. a broken cluster chain.
+    Corrupt,
+    /// No file or directory with this name.
+    NotFound,
+    /// A file or directory with this name already exists.
+    AlreadyExists,
+    /// A path component is not a directory.
+    NotADirectory,
+    /// The operation requires a file but the path names a directory.
+    IsADirectory,
+    /// The directory is not empty.
+    DirectoryNotEmpty,
+    /// The name is empty, too long or contains invalid characters.
+    InvalidName,
+    /// No free cluster left.
+    DiskFull,
+    /// No free entry left in a fixed-size root directory or a full directory.
+    DirectoryFull,
+    /// The file was not opened for writing, or is read-only.
+    ReadOnly,
+    /// Seek before the start or past the end of a file.
+    InvalidSeek,
+    /// The file would exceed 4 GiB.
+    FileTooLarge,
+    /// The requested range lies outside the volume or partition.
+    OutOfBounds,
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(e: E) -> Self {
+        Self::Device(e)
+    }
+}
+
+impl<E: core::fmt::Debug> core::fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Device(e) => write!(f, "Block device error: {:?}", e),
+            Self::NoPartitionTable => write!(f, "No partition table"),
+            Self::InvalidPartitionTable => write!(f, "Invalid partition table"),
+            Self::InvalidFileSystem => write!(f, "Invalid or unsupported filesystem"),
+            Self::Corrupt => write!(f, "Filesystem is corrupt"),
+            Self::NotFound => write!(f, "Not found"),
+            Self::AlreadyExists => write!(f, "Already exists"),
+            Self::NotADirectory => write!(f, "Not a directory"),
+            Self::IsADirectory => write!(f, "Is a directory"),
+            Self::DirectoryNotEmpty => write!(f, "Directory not empty"),
+            Self::InvalidName => write!(f, "Invalid name"),
+            Self::DiskFull => write!(f, "Disk full"),
+            Self::DirectoryFull => write!(f, "Directory full"),
+            Self::ReadOnly => write!(f, "Read-only"),
+            Self::InvalidSeek => write!(f, "Invalid seek"),
+            Self::FileTooLarge => write!(f, "File too large"),
+            Self::OutOfBounds => write!(f, "Out of bounds"),
+        }
+    }
+}
+
+impl<E: core::fmt::Debug> core::error::Error for Error<E> {}
+
+impl<E: core::fmt::Debug> embedded_io_async::Error for Error<E> {
+    fn kind(&self) -> embedded_io_async::ErrorKind {
+        use embedded_io_async::ErrorKind;
+        match self {
+            Self::NotFound => ErrorKind::NotFound,
+            Self::AlreadyExists => ErrorKind::AlreadyExists,
+            Self::ReadOnly => ErrorKind::PermissionDenied,
+            Self::InvalidName | Self::InvalidSeek | Self::NotADirectory | Self::IsADirectory => ErrorKind::InvalidInput,
+            Self::InvalidFileSystem | Self::InvalidPartitionTable | Self::Corrupt => ErrorKind::InvalidData,
+            Self::DiskFull | Self::DirectoryFull | Self::FileTooLarge => ErrorKind::OutOfMemory,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+/// Read a single block.
+pub(crate) async fn read_block<D: BlockDevice<BLOCK_SIZE>>(
+    dev: &mut D,
+    lba: u32,
+    block: &mut Block<D>,
+) -> Result<(), Error<D::Error>> {
+    dev.read(lba, core::slice::from_mut(block)).await?;
+    Ok(())
+}
+
+/// Write a single block.
+pub(crate) async fn write_block<D: BlockDevice<BLOCK_SIZE>>(
+    dev: &mut D,
+    lba: u32,
+    block: &Block<D>,
+) -> Result<(), Error<D::Error>> {
+    dev.write(lba, core::slice::from_ref(block)).await?;
+    Ok(())
+}
+
+/// An all-zero block.
+pub(crate) fn zero_block<D: BlockDevice<BLOCK_SIZE>>() -> Block<D> {
+    aligned::Aligned([0u8; BLOCK_SIZE])
+}
+
+#[cfg(test)]
+pub(crate) mod testutil {
+    use std::vec;
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// In-memory block device.
+    pub struct RamDisk {
+        pub data: Vec<u8>,
+    }
+
+    impl RamDisk {
+        pub fn new(blocks: usize) -> Self {
+            Self {
+                data: vec![0; blocks * BLOCK_SIZE],
+            }
+        }
+    }
+
+    impl BlockDevice<BLOCK_SIZE> for RamDisk {
+        type Error = ();
+        type Align = aligned::A4;
+
+        async fn read(&mut self, lba: u32, data: &mut [Block<Self>]) -> Result<(), ()> {
+            let start = lba as usize * BLOCK_SIZE;
+            for (i, b) in data.iter_mut().enumerate() {
+                let off = start + i * BLOCK_SIZE;
+                b.copy_from_slice(self.data.get(off..off + BLOCK_SIZE).ok_or(())?);
+            }
+            Ok(())
+        }
+
+        async fn write(&mut self, lba: u32, data: &[Block<Self>]) -> Result<(), ()> {
+            let start = lba as usize * BLOCK_SIZE;
+            for (i, b) in data.iter().enumerate() {
+                let off = start + i * BLOCK_SIZE;
+                self.data
+                    .get_mut(off..off + BLOCK_SIZE)
+                    .ok_or(())?
+                    .copy_from_slice(&b[..]);
+            }
+            Ok(())
+        }
+
+        async fn size(&mut self) -> Result<u64, ()> {
+            Ok(self.data.len() as u64)
+        }
+    }
+}
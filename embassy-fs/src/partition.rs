@@ -0,0 +1,511 @@
+//! MBR and GPT partition tables.
+//!
+//! [`read_partitions`] reads the partition table of a block device. Devices
+//! formatted without a partition table ("superfloppy", common on USB sticks)
+//! report [`Error::NoPartitionTable`] and are mounted as a whole.
+
+use heapless::Vec;
+
+use crate::{BLOCK_SIZE, Block, BlockDevice, Error, read_block};
+
+/// MBR partition types of extended partitions, which hold a chain of logical partitions.
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+/// MBR partition type of a GPT protective MBR.
+const MBR_GPT_PROTECTIVE: u8 = 0xEE;
+/// Upper bound on the number of logical partitions followed in an extended partition.
+const MAX_LOGICAL_PARTITIONS: usize = 64;
+
+/// GPT header signature (UEFI 2.10 §5.3.2).
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// Minimum GPT partition entry size (UEFI 2.10 §5.3.3).
+const GPT_MIN_ENTRY_SIZE: u32 = 128;
+
+/// A GUID in its on-disk (mixed-endian) byte order.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// EFI system partition, `C12A7328-F81F-11D2-BA4B-00A0C93EC93B`.
+    pub const EFI_SYSTEM: Self = Self::from_u128(0xC12A7328_F81F_11D2_BA4B_00A0C93EC93B);
+    /// Microsoft basic data partition, `EBD0A0A2-B9E5-4433-87C0-68B6B72699C7`.
+    pub const BASIC_DATA: Self = Self::from_u128(0xEBD0A0A2_B9E5_4433_87C0_68B6B72699C7);
+
+    /// Build a GUID from its canonical textual value, e.g. `0xC12A7328_F81F_11D2_BA4B_00A0C93EC93B`.
+    pub const fn from_u128(v: u128) -> Self {
+        let b = v.to_be_bytes();
+        Self([
+            b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15],
+        ])
+    }
+
+    /// Whether this is the all-zero GUID, which marks an unused GPT entry.
+    pub fn is_nil(&self) -> bool {
+        self.0 == [0; 16]
+    }
+}
+
+impl core::fmt::Debug for Guid {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Display::fmt(self, f)
+    }
+}
+
+impl core::fmt::Display for Guid {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-",
+            b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9]
+        )?;
+        for x in &b[10..] {
+            write!(f, "{:02X}", x)?;
+        }
+        Ok(())
+    }
+}
+
+/// Partition type.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PartitionKind {
+    /// MBR partition with its system ID.
+    Mbr(u8),
+    /// GPT partition.
+    Gpt {
+        /// Partition type GUID.
+        type_guid: Guid,
+        /// Unique partition GUID.
+        unique_guid: Guid,
+        /// Attribute flags.
+        attributes: u64,
+    },
+}
+
+/// A partition found by [`read_partitions`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PartitionInfo {
+    /// First block of the partition.
+    pub start_lba: u64,
+    /// Number of blocks in the partition.
+    pub block_count: u64,
+    /// Partition type.
+    pub kind: PartitionKind,
+}
+
+impl PartitionInfo {
+    /// Whether the partition type denotes a FAT filesystem.
+    ///
+    /// GPT basic data partitions may also hold NTFS or exFAT; mounting tells them apart.
+    pub fn is_fat(&self) -> bool {
+        match self.kind {
+            PartitionKind::Mbr(t) => matches!(
+                t,
+                0x01 | 0x04 | 0x06 | 0x0B | 0x0C | 0x0E | 0x11 | 0x14 | 0x16 | 0x1B | 0x1C | 0x1E
+            ),
+            PartitionKind::Gpt { type_guid, .. } => type_guid == Guid::BASIC_DATA || type_guid == Guid::EFI_SYSTEM,
+        }
+    }
+}
+
+/// A primary MBR partition entry.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MbrEntry {
+    /// Active (bootable) flag.
+    pub bootable: bool,
+    /// System ID, 0 for an unused entry.
+    pub partition_type: u8,
+    /// First block, relative to the table's base.
+    pub start_lba: u32,
+    /// Number of blocks.
+    pub block_count: u32,
+}
+
+/// Parse the four primary entries of an MBR or extended boot record.
+///
+/// Returns `None` if the sector lacks the `0x55AA` signature or has invalid
+/// status bytes.
+pub fn parse_mbr(sector: &[u8; BLOCK_SIZE]) -> Option<[MbrEntry; 4]> {
+    if sector[510..512] != [0x55, 0xAA] {
+        return None;
+    }
+    let mut entries = [MbrEntry {
+        bootable: false,
+        partition_type: 0,
+        start_lba: 0,
+        block_count: 0,
+    }; 4];
+    for (i, e) in entries.iter_mut().enumerate() {
+        let b = &sector[446 + 16 * i..462 + 16 * i];
+        if b[0] & 0x7F != 0 {
+            return None;
+        }
+        *e = MbrEntry {
+            bootable: b[0] == 0x80,
+            partition_type: b[4],
+            start_lba: u32::from_le_bytes(b[8..12].try_into().unwrap()),
+            block_count: u32::from_le_bytes(b[12..16].try_into().unwrap()),
+        };
+    }
+    Some(entries)
+}
+
+/// Whether a sector looks like a FAT boot sector rather than an MBR.
+fn is_boot_sector(sector: &[u8; BLOCK_SIZE]) -> bool {
+    let bytes_per_sector = u16::from_le_bytes([sector[11], sector[12]]);
+    let sectors_per_cluster = sector[13];
+    let reserved = u16::from_le_bytes([sector[14], sector[15]]);
+    matches!(sector[0], 0xEB | 0xE9)
+        && bytes_per_sector as usize == BLOCK_SIZE
+        && sectors_per_cluster.is_power_of_two()
+        && reserved != 0
+        && matches!(sector[16], 1 | 2)
+}
+
+/// GPT header (UEFI 2.10 §5.3.2).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct GptHeader {
+    /// Location of this header.
+    pub current_lba: u64,
+    /// Location of the other header copy.
+    pub backup_lba: u64,
+    /// First block usable by partitions.
+    pub first_usable_lba: u64,
+    /// Last block usable by partitions.
+    pub last_usable_lba: u64,
+    /// Disk GUID.
+    pub disk_guid: Guid,
+    /// First block of the partition entry array.
+    pub entries_lba: u64,
+    /// Number of partition entries.
+    pub num_entries: u32,
+    /// Size of each partition entry in bytes.
+    pub entry_size: u32,
+    /// CRC32 of the partition entry array.
+    pub entries_crc32: u32,
+}
+
+impl GptHeader {
+    /// Parse and validate a GPT header, including its CRC.
+    pub fn parse(sector: &[u8; BLOCK_SIZE]) -> Option<Self> {
+        if &sector[0..8] != GPT_SIGNATURE {
+            return None;
+        }
+        let header_size = u32::from_le_bytes(sector[12..16].try_into().unwrap()) as usize;
+        if !(92..=BLOCK_SIZE).contains(&header_size) {
+            return None;
+        }
+        let crc = u32::from_le_bytes(sector[16..20].try_into().unwrap());
+        let mut copy = [0u8; BLOCK_SIZE];
+        copy[..header_size].copy_from_slice(&sector[..header_size]);
+        copy[16..20].fill(0);
+        if crc32(&copy[..header_size]) != crc {
+            return None;
+        }
+
+        let u64_at = |o: usize| u64::from_le_bytes(sector[o..o + 8].try_into().unwrap());
+        let u32_at = |o: usize| u32::from_le_bytes(sector[o..o + 4].try_into().unwrap());
+        let header = Self {
+            current_lba: u64_at(24),
+            backup_lba: u64_at(32),
+            first_usable_lba: u64_at(40),
+            last_usable_lba: u64_at(48),
+            disk_guid: Guid(sector[56..72].try_into().unwrap()),
+            entries_lba: u64_at(72),
+            num_entries: u32_at(80),
+            entry_size: u32_at(84),
+            entries_crc32: u32_at(88),
+        };
+        if header.entry_size < GPT_MIN_ENTRY_SIZE
+            || !header.entry_size.is_power_of_two()
+            || header.entry_size as usize > BLOCK_SIZE
+        {
+            return None;
+        }
+        Some(header)
+    }
+}
+
+/// Parse a GPT partition entry. Returns `None` for unused entries.
+pub fn parse_gpt_entry(entry: &[u8]) -> Option<PartitionInfo> {
+    let type_guid = Guid(entry.get(0..16)?.try_into().unwrap());
+    if type_guid.is_nil() {
+        return None;
+    }
+    let first = u64::from_le_bytes(entry.get(32..40)?.try_into().unwrap());
+    let last = u64::from_le_bytes(entry.get(40..48)?.try_into().unwrap());
+    Some(PartitionInfo {
+        start_lba: first,
+        block_count: last.checked_sub(first)? + 1,
+        kind: PartitionKind::Gpt {
+            type_guid,
+            unique_guid: Guid(entry[16..32].try_into().unwrap()),
+            attributes: u64::from_le_bytes(entry.get(48..56)?.try_into().unwrap()),
+        },
+    })
+}
+
+/// Read the partition table of a block device.
+///
+/// GPT is used when the MBR is protective; the backup GPT header at the end
+/// of the device is tried when the primary one is corrupt. Logical
+/// partitions of MBR extended partitions are listed after the primary ones.
+/// Partitions beyond `N` are ignored.
+pub async fn read_partitions<D: BlockDevice<BLOCK_SIZE>, const N: usize>(
+    dev: &mut D,
+) -> Result<Vec<PartitionInfo, N>, Error<D::Error>> {
+    let mut block: Block<D> = crate::zero_block::<D>();
+    read_block(dev, 0, &mut block).await?;
+
+    if is_boot_sector(&block) {
+        return Err(Error::NoPartitionTable);
+    }
+    let entries = parse_mbr(&block).ok_or(Error::NoPartitionTable)?;
+
+    if entries.iter().any(|e| e.partition_type == MBR_GPT_PROTECTIVE) {
+        return read_gpt(dev, &mut block).await;
+    }
+
+    let mut parts = Vec::new();
+    for e in entries.iter().filter(|e| e.partition_type != 0) {
+        if !MBR_EXTENDED.contains(&e.partition_type) {
+            let _ = parts.push(PartitionInfo {
+                start_lba: e.start_lba as u64,
+                block_count: e.block_count as u64,
+                kind: PartitionKind::Mbr(e.partition_type),
+            });
+        }
+    }
+    for e in entries.iter().filter(|e| MBR_EXTENDED.contains(&e.partition_type)) {
+        read_logical(dev, &mut block, e.start_lba, &mut parts).await?;
+    }
+    Ok(parts)
+}
+
+/// Follow the chain of extended boot records of an extended partition.
+async fn read_logical<D: BlockDevice<BLOCK_SIZE>, const N: usize>(
+    dev: &mut D,
+    block: &mut Block<D>,
+    extended_start: u32,
+    parts: &mut Vec<PartitionInfo, N>,
+) -> Result<(), Error<D::Error>> {
+    let mut ebr = extended_start;
+    for _ in 0..MAX_LOGICAL_PARTITIONS {
+        read_block(dev, ebr, block).await?;
+        let entries = parse_mbr(block).ok_or(Error::InvalidPartitionTable)?;
+        if entries[0].partition_type != 0 {
+            let _ = parts.push(PartitionInfo {
+                start_lba: ebr as u64 + entries[0].start_lba as u64,
+                block_count: entries[0].block_count as u64,
+                kind: PartitionKind::Mbr(entries[0].partition_type),
+            });
+        }
+        if entries[1].partition_type == 0 {
+            return Ok(());
+        }
+        ebr = extended_start
+            .checked_add(entries[1].start_lba)
+            .ok_or(Error::InvalidPartitionTable)?;
+    }
+    Err(Error::InvalidPartitionTable)
+}
+
+async fn read_gpt<D: BlockDevice<BLOCK_SIZE>, const N: usize>(
+    dev: &mut D,
+    block: &mut Block<D>,
+) -> Result<Vec<PartitionInfo, N>, Error<D::Error>> {
+    read_block(dev, 1, block).await?;
+    let primary = GptHeader::parse(block);
+    if let Some(header) = primary
+        && let Ok(parts) = read_gpt_entries(dev, block, &header).await
+    {
+        return Ok(parts);
+    }
+
+    warn!("Primary GPT is corrupt, trying backup");
+    let last = (dev.size().await? / BLOCK_SIZE as u64).saturating_sub(1);
+    let last = u32::try_from(last).map_err(|_| Error::InvalidPartitionTable)?;
+    read_block(dev, last, block).await?;
+    let header = GptHeader::parse(block).ok_or(Error::InvalidPartitionTable)?;
+    read_gpt_entries(dev, block, &header).await
+}
+
+async fn read_gpt_entries<D: BlockDevice<BLOCK_SIZE>, const N: usize>(
+    dev: &mut D,
+    block: &mut Block<D>,
+    header: &GptHeader,
+) -> Result<Vec<PartitionInfo, N>, Error<D::Error>> {
+    let entry_size = header.entry_size as usize;
+    let per_block = BLOCK_SIZE / entry_size;
+    let blocks = (header.num_entries as usize).div_ceil(per_block);
+    let start = u32::try_from(header.entries_lba).map_err(|_| Error::InvalidPartitionTable)?;
+
+    let mut parts = Vec::new();
+    let mut crc = !0;
+    let mut remaining = header.num_entries as usize;
+    for i in 0..blocks {
+        read_block(dev, start + i as u32, block).await?;
+        for entry in block.chunks_exact(entry_size).take(remaining) {
+            crc = crc32_update(crc, entry);
+            if let Some(p) = parse_gpt_entry(entry) {
+                let _ = parts.push(p);
+            }
+        }
+        remaining = remaining.saturating_sub(per_block);
+    }
+    if !crc != header.entries_crc32 {
+        return Err(Error::InvalidPartitionTable);
+    }
+    Ok(parts)
+}
+
+/// CRC-32 (IEEE 802.3) as used by GPT.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(!0, data)
+}
+
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::testutil::RamDisk;
+
+    fn mbr_entry(sector: &mut [u8], i: usize, kind: u8, start: u32, count: u32) {
+        let e = &mut sector[446 + 16 * i..462 + 16 * i];
+        e[4] = kind;
+        e[8..12].copy_from_slice(&start.to_le_bytes());
+        e[12..16].copy_from_slice(&count.to_le_bytes());
+    }
+
+    fn sign(sector: &mut [u8]) {
+        sector[510] = 0x55;
+        sector[511] = 0xAA;
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn guid_display() {
+        let mut s = heapless::String::<40>::new();
+        core::fmt::write(&mut s, format_args!("{}", Guid::BASIC_DATA)).unwrap();
+        assert_eq!(s, "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7");
+        assert_eq!(Guid::EFI_SYSTEM.0[..4], [0x28, 0x73, 0x2A, 0xC1]);
+    }
+
+    #[test]
+    fn mbr_with_logical_partitions() {
+        let mut disk = RamDisk::new(4096);
+        let s0 = &mut disk.data[..512];
+        mbr_entry(s0, 0, 0x0C, 2048, 1000);
+        mbr_entry(s0, 1, 0x0F, 3072, 1024);
+        sign(s0);
+        // First EBR: logical at +16, next EBR at extended + 512.
+        let s = &mut disk.data[3072 * 512..3073 * 512];
+        mbr_entry(s, 0, 0x06, 16, 100);
+        mbr_entry(s, 1, 0x05, 512, 200);
+        sign(s);
+        let s = &mut disk.data[3584 * 512..3585 * 512];
+        mbr_entry(s, 0, 0x83, 8, 50);
+        sign(s);
+
+        let parts = block_on(read_partitions::<_, 8>(&mut disk)).unwrap();
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[0].start_lba, 2048);
+        assert!(parts[0].is_fat());
+        assert_eq!(parts[1].start_lba, 3088);
+        assert_eq!(parts[1].kind, PartitionKind::Mbr(0x06));
+        assert_eq!(parts[2].start_lba, 3592);
+        assert!(!parts[2].is_fat());
+    }
+
+    #[test]
+    fn no_partition_table() {
+        let mut disk = RamDisk::new(16);
+        assert_eq!(
+            block_on(read_partitions::<_, 4>(&mut disk)),
+            Err(Error::NoPartitionTable)
+        );
+
+        // FAT boot sector at LBA 0.
+        let s = &mut disk.data[..512];
+        s[0] = 0xEB;
+        s[11..13].copy_from_slice(&512u16.to_le_bytes());
+        s[13] = 4;
+        s[14] = 1;
+        s[16] = 2;
+        sign(s);
+        assert_eq!(
+            block_on(read_partitions::<_, 4>(&mut disk)),
+            Err(Error::NoPartitionTable)
+        );
+    }
+
+    fn write_gpt(disk: &mut RamDisk, header_lba: u64, entries_lba: u64) {
+        let mut entries = [0u8; 4 * 128];
+        entries[..16].copy_from_slice(&Guid::BASIC_DATA.0);
+        entries[16] = 0xAB;
+        entries[32..40].copy_from_slice(&2048u64.to_le_bytes());
+        entries[40..48].copy_from_slice(&4095u64.to_le_bytes());
+        let off = entries_lba as usize * 512;
+        disk.data[off..off + entries.len()].copy_from_slice(&entries);
+
+        let mut h = [0u8; 92];
+        h[..8].copy_from_slice(GPT_SIGNATURE);
+        h[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        h[12..16].copy_from_slice(&92u32.to_le_bytes());
+        h[24..32].copy_from_slice(&header_lba.to_le_bytes());
+        h[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        h[80..84].copy_from_slice(&4u32.to_le_bytes());
+        h[84..88].copy_from_slice(&128u32.to_le_bytes());
+        h[88..92].copy_from_slice(&crc32(&entries).to_le_bytes());
+        let crc = crc32(&h);
+        h[16..20].copy_from_slice(&crc.to_le_bytes());
+        let off = header_lba as usize * 512;
+        disk.data[off..off + 92].copy_from_slice(&h);
+    }
+
+    #[test]
+    fn gpt_primary_and_backup() {
+        let mut disk = RamDisk::new(8192);
+        mbr_entry(&mut disk.data[..512], 0, MBR_GPT_PROTECTIVE, 1, 8191);
+        sign(&mut disk.data[..512]);
+        write_gpt(&mut disk, 1, 2);
+        write_gpt(&mut disk, 8191, 8190);
+
+        let parts = block_on(read_partitions::<_, 4>(&mut disk)).unwrap();
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].start_lba, 2048);
+        assert_eq!(parts[0].block_count, 2048);
+        assert!(parts[0].is_fat());
+
+        // Corrupt the primary entry array: the backup is used.
+        disk.data[2 * 512 + 40] ^= 1;
+        let parts = block_on(read_partitions::<_, 4>(&mut disk)).unwrap();
+        assert_eq!(parts[0].block_count, 2048);
+
+        // Both corrupt.
+        disk.data[8190 * 512 + 40] ^= 1;
+        assert_eq!(
+            block_on(read_partitions::<_, 4>(&mut disk)),
+            Err(Error::InvalidPartitionTable)
+        );
+    }
+}
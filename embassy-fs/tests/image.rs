@@ -0,0 +1,187 @@
+//! Tests against disk image files, and against `mkfs.fat`/`fsck.fat` when installed.
+
+use std::fs::File;
+use std::io::{Seek, SeekFrom as StdSeekFrom, Write};
+use std::path::PathBuf;
+use std::process::Command;
+
+use embassy_fs::fat::{FatType, FileSystem, FormatOptions, Mode, SeekFrom, format};
+use embassy_fs::partition::{PartitionKind, read_partitions};
+use embassy_fs::{Error, FileBlockDevice};
+use embassy_futures::block_on;
+
+struct TempImage(PathBuf);
+
+impl TempImage {
+    fn new(name: &str, sectors: u64) -> Self {
+        let path = std::env::temp_dir().join(format!("embassy-fs-{}-{}.img", name, std::process::id()));
+        let file = File::create(&path).unwrap();
+        file.set_len(sectors * 512).unwrap();
+        Self(path)
+    }
+
+    fn open(&self) -> FileBlockDevice {
+        FileBlockDevice::open(&self.0).unwrap()
+    }
+}
+
+impl Drop for TempImage {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Write an MBR with a single partition.
+fn write_mbr(image: &TempImage, kind: u8, start: u32, count: u32) {
+    let mut mbr = [0u8; 512];
+    mbr[446 + 4] = kind;
+    mbr[446 + 8..446 + 12].copy_from_slice(&start.to_le_bytes());
+    mbr[446 + 12..446 + 16].copy_from_slice(&count.to_le_bytes());
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+    let mut file = File::options().write(true).open(&image.0).unwrap();
+    file.seek(StdSeekFrom::Start(0)).unwrap();
+    file.write_all(&mbr).unwrap();
+}
+
+fn tool_available(name: &str) -> bool {
+    Command::new(name).arg("--help").output().is_ok()
+}
+
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u32).wrapping_mul(31).wrapping_add(seed as u32) as u8)
+        .collect()
+}
+
+async fn populate(fs: &mut FileSystem<&mut FileBlockDevice>) {
+    fs.create_dir("Photos").await.unwrap();
+    fs.create_dir("Photos/2024 Summer Holiday").await.unwrap();
+    for i in 0..40u8 {
+        let name = format!("Photos/2024 Summer Holiday/Picture number {i}.jpeg");
+        let mut f = fs.open(&name, Mode::CreateNew).await.unwrap();
+        f.write_all(&pattern(1000 + 777 * i as usize, i)).await.unwrap();
+        f.close().await.unwrap();
+    }
+    let mut f = fs.open("README.TXT", Mode::Create).await.unwrap();
+    f.write_all(b"hello").await.unwrap();
+    f.close().await.unwrap();
+}
+
+async fn verify(fs: &mut FileSystem<&mut FileBlockDevice>) {
+    let mut names = Vec::new();
+    let mut dir = fs.read_dir("/photos/2024 summer holiday").await.unwrap();
+    while let Some(e) = dir.next().await.unwrap() {
+        names.push((e.name().to_string(), e.size()));
+    }
+    assert_eq!(names.len(), 40);
+    for i in 0..40u8 {
+        let name = format!("Picture number {i}.jpeg");
+        assert!(names.contains(&(name.clone(), 1000 + 777 * i as u32)), "{name} missing");
+
+        let mut f = fs
+            .open(&format!("Photos/2024 Summer Holiday/{name}"), Mode::Read)
+            .await
+            .unwrap();
+        let mut data = vec![0; f.size() as usize + 10];
+        let mut n = 0;
+        loop {
+            let r = f.read(&mut data[n..]).await.unwrap();
+            if r == 0 {
+                break;
+            }
+            n += r;
+        }
+        assert_eq!(data[..n], pattern(1000 + 777 * i as usize, i));
+    }
+
+    let mut f = fs.open("readme.txt", Mode::Read).await.unwrap();
+    let mut buf = [0; 16];
+    assert_eq!(f.read(&mut buf).await.unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+}
+
+#[test]
+fn partitioned_image_roundtrip() {
+    for (sectors, fat_type) in [(16_384u32, FatType::Fat16), (200_000, FatType::Fat32)] {
+        let image = TempImage::new(&format!("{fat_type:?}"), sectors as u64 + 2048);
+        write_mbr(&image, 0x0C, 2048, sectors);
+        let mut dev = image.open();
+
+        block_on(async {
+            let parts = read_partitions::<_, 4>(&mut dev).await.unwrap();
+            assert_eq!(parts.len(), 1);
+            assert_eq!(parts[0].kind, PartitionKind::Mbr(0x0C));
+            let opts = FormatOptions {
+                fat_type: Some(fat_type),
+                volume_label: *b"EMBASSY    ",
+                volume_id: 0x1234_5678,
+                ..Default::default()
+            };
+            format(&mut dev, 2048, sectors, &opts).await.unwrap();
+
+            let mut fs = FileSystem::mount_auto(&mut dev).await.unwrap();
+            assert_eq!(fs.fat_type(), fat_type);
+            assert_eq!(fs.volume_label().await.unwrap(), "EMBASSY");
+            assert_eq!(fs.volume_id(), 0x1234_5678);
+            populate(&mut fs).await;
+            fs.unmount().await.unwrap();
+
+            let mut fs = FileSystem::mount_auto(&mut dev).await.unwrap();
+            verify(&mut fs).await;
+
+            // Rewrite part of a file in place.
+            let mut f = fs.open("README.TXT", Mode::ReadWrite).await.unwrap();
+            f.seek(SeekFrom::Start(1)).await.unwrap();
+            f.write_all(b"ELLO, WORLD").await.unwrap();
+            f.close().await.unwrap();
+            let mut f = fs.open("README.TXT", Mode::Read).await.unwrap();
+            let mut buf = [0; 32];
+            assert_eq!(f.read(&mut buf).await.unwrap(), 12);
+            assert_eq!(&buf[..12], b"hELLO, WORLD");
+
+            assert!(matches!(fs.remove("Photos").await, Err(Error::DirectoryNotEmpty)));
+            fs.unmount().await.unwrap();
+        });
+
+        if tool_available("fsck.fat") {
+            let status = Command::new("fsck.fat")
+                .args(["-n", "--offset", &(2048 * 512).to_string()])
+                .arg(&image.0)
+                .status()
+                .unwrap();
+            assert!(status.success(), "fsck.fat reported errors");
+        }
+    }
+}
+
+#[test]
+#[ignore = "requires mkfs.fat"]
+fn mkfs_fat_image() {
+    assert!(tool_available("mkfs.fat"), "mkfs.fat is not installed");
+    for fat in ["12", "16", "32"] {
+        let image = TempImage::new(&format!("mkfs{fat}"), 0);
+        std::fs::remove_file(&image.0).unwrap();
+        let status = Command::new("mkfs.fat")
+            .args(["-F", fat, "-C"])
+            .arg(&image.0)
+            .arg(if fat == "32" { "100000" } else { "16000" })
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let mut dev = image.open();
+        block_on(async {
+            let mut fs = FileSystem::mount_auto(&mut dev).await.unwrap();
+            populate(&mut fs).await;
+            fs.unmount().await.unwrap();
+            let mut fs = FileSystem::mount(&mut dev).await.unwrap();
+            verify(&mut fs).await;
+        });
+
+        if tool_available("fsck.fat") {
+            let status = Command::new("fsck.fat").arg("-n").arg(&image.0).status().unwrap();
+            assert!(status.success(), "fsck.fat reported errors");
+        }
+    }
+}
@@ -1,12 +1,14 @@
 //! DFU bootloader part of DFU logic
 use embassy_boot::{AlignedBuffer, BlockingFirmwareUpdater, FirmwareUpdaterError};
 use embassy_usb::class::dfu::consts::{DfuAttributes, Status};
+/// Re-export DfuSe from embassy-usb for convenience.
+pub use embassy_usb::class::dfu::dfu_mode::DfuSe;
 /// Re-export DfuState from embassy-usb for convenience.
 pub use embassy_usb::class::dfu::dfu_mode::DfuState as UsbDfuState;
 use embassy_usb::class::dfu::dfu_mode::{self, DfuState};
 use embassy_usb::driver::Driver;
 use embassy_usb::{Builder, FunctionBuilder};
-use embedded_storage::nor_flash::{NorFlash, NorFlashErrorKind};
+use embedded_storage::nor_flash::{ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash};
 
 use crate::Reset;
 
@@ -14,11 +16,21 @@ use crate::Reset;
 ///
 /// This implements the `embassy_usb::class::dfu::dfu_mode::Handler` trait,
 /// providing the firmware write logic using `BlockingFirmwareUpdater`.
-pub struct FirmwareHandler<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize> {
+///
+/// Uploads are read from `SRC`, see [`FirmwareHandler::with_upload`].
+pub struct FirmwareHandler<
+    'd,
+    DFU: NorFlash,
+    STATE: NorFlash,
+    RST: Reset,
+    const BLOCK_SIZE: usize,
+    SRC: ReadNorFlash = NoUpload,
+> {
     updater: BlockingFirmwareUpdater<'d, DFU, STATE>,
     offset: usize,
     buf: AlignedBuffer<BLOCK_SIZE>,
     reset: RST,
+    upload: SRC,
 
     #[cfg(feature = "_verify")]
     public_key: &'static [u8; 32],
@@ -38,6 +50,7 @@ impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize>
             offset: 0,
             buf: AlignedBuffer([0; BLOCK_SIZE]),
             reset,
+            upload: NoUpload,
 
             #[cfg(feature = "_verify")]
             public_key,
@@ -45,20 +58,67 @@ impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize>
     }
 }
 
+impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize, SRC: ReadNorFlash>
+    FirmwareHandler<'d, DFU, STATE, RST, BLOCK_SIZE, SRC>
+{
+    /// Serve DFU_UPLOAD requests from `source`, such as the active partition. The host reads
+    /// all of `source`, or from the base address with DfuSe.
+    ///
+    /// Uploads are only requested if [`DfuAttributes::CAN_UPLOAD`] is set.
+    pub fn with_upload<S: ReadNorFlash>(self, source: S) -> FirmwareHandler<'d, DFU, STATE, RST, BLOCK_SIZE, S> {
+        FirmwareHandler {
+            updater: self.updater,
+            offset: self.offset,
+            buf: self.buf,
+            reset: self.reset,
+            upload: source,
+
+            #[cfg(feature = "_verify")]
+            public_key: self.public_key,
+        }
+    }
+}
+
+/// Upload source of a [`FirmwareHandler`] without uploads, which has nothing to read.
+pub struct NoUpload;
+
+impl ErrorType for NoUpload {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for NoUpload {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, _offset: u32, _bytes: &mut [u8]) -> Result<(), Self::Error> {
+        Err(NorFlashErrorKind::OutOfBounds)
+    }
+
+    fn capacity(&self) -> usize {
+        0
+    }
+}
+
+fn flash_error_to_status(e: NorFlashErrorKind) -> Status {
+    match e {
+        NorFlashErrorKind::NotAligned => Status::ErrWrite,
+        NorFlashErrorKind::OutOfBounds => Status::ErrAddress,
+        _ => Status::ErrUnknown,
+    }
+}
+
 fn firmware_error_to_status(e: FirmwareUpdaterError) -> Status {
     match e {
-        FirmwareUpdaterError::Flash(e) => match e {
-            NorFlashErrorKind::NotAligned => Status::ErrWrite,
-            NorFlashErrorKind::OutOfBounds => Status::ErrAddress,
-            _ => Status::ErrUnknown,
-        },
+        FirmwareUpdaterError::Flash(e) => flash_error_to_status(e),
         FirmwareUpdaterError::Signature(_) => Status::ErrVerify,
         FirmwareUpdaterError::BadState => Status::ErrUnknown,
+        FirmwareUpdaterError::Image(_) | FirmwareUpdaterError::Rollback | FirmwareUpdaterError::Stream(_) => {
+            Status::ErrFile
+        }
     }
 }
 
-impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize> dfu_mode::Handler
-    for FirmwareHandler<'d, DFU, STATE, RST, BLOCK_SIZE>
+impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize, SRC: ReadNorFlash> dfu_mode::Handler
+    for FirmwareHandler<'d, DFU, STATE, RST, BLOCK_SIZE, SRC>
 {
     fn start(&mut self) -> Result<(), Status> {
         info!("Download starting");
@@ -67,6 +127,10 @@ impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize> df
     }
 
     fn write(&mut self, data: &[u8]) -> Result<(), Status> {
+        self.write_at(self.offset, data)
+    }
+
+    fn write_at(&mut self, offset: usize, data: &[u8]) -> Result<(), Status> {
         if data.len() > BLOCK_SIZE {
             error!("USB data len exceeded block size");
             return Err(Status::ErrUnknown);
@@ -75,10 +139,10 @@ impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize> df
         debug!("Copying {} bytes to buffer", data.len());
         self.buf.as_mut()[..data.len()].copy_from_slice(data);
 
-        debug!("Writing {} bytes at {}", data.len(), self.offset);
-        match self.updater.write_firmware(self.offset, self.buf.as_ref()) {
+        debug!("Writing {} bytes at {}", data.len(), offset);
+        match self.updater.write_firmware(offset, self.buf.as_ref()) {
             Ok(_) => {
-                self.offset += data.len();
+                self.offset = offset + data.len();
                 Ok(())
             }
             Err(e) => {
@@ -113,7 +177,7 @@ impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize> df
                 Ok(())
             }
             Err(e) => {
-                error!("Error completing update: {}", e);
+                error!("Error completing update: {:?}", e);
                 Err(firmware_error_to_status(e))
             }
         }
@@ -122,11 +186,29 @@ impl<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize> df
     fn system_reset(&mut self) {
         self.reset.sys_reset()
     }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<usize, Status> {
+        let len = buf.len().min(self.upload.capacity().saturating_sub(offset));
+        debug!("Reading {} bytes at {}", len, offset);
+        match self.upload.read(offset as u32, &mut buf[..len]) {
+            Ok(_) => Ok(len),
+            Err(e) => {
+                let status = flash_error_to_status(e.kind());
+                error!("Error reading upload: {:?}", status);
+                Err(status)
+            }
+        }
+    }
+
+    fn erase(&mut self, _offset: Option<usize>) -> Result<(), Status> {
+        // `write_firmware` erases the pages it writes to.
+        Ok(())
+    }
 }
 
 /// Convenience type alias for the DFU state with firmware handler.
-pub type State<'d, DFU, STATE, RST, const BLOCK_SIZE: usize> =
-    DfuState<FirmwareHandler<'d, DFU, STATE, RST, BLOCK_SIZE>>;
+pub type State<'d, DFU, STATE, RST, const BLOCK_SIZE: usize, SRC = NoUpload> =
+    DfuState<FirmwareHandler<'d, DFU, STATE, RST, BLOCK_SIZE, SRC>>;
 
 /// Create a new DFU state instance.
 ///
@@ -150,14 +232,27 @@ pub fn new_state<'d, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZ
 /// An implementation of the USB DFU 1.1 protocol
 ///
 /// This function will add a DFU interface descriptor to the provided Builder, and register the provided Control as a handler for the USB device
-/// The handler is responsive to DFU GetState, GetStatus, Abort, and ClrStatus commands, as well as Download if configured by the user.
+/// The handler is responsive to DFU GetState, GetStatus, Abort, and ClrStatus commands, as well as Download and Upload if configured by the user.
 ///
 /// Once the host has initiated a DFU download operation, the chunks sent by the host will be written to the DFU partition.
 /// Once the final sync in the manifestation phase has been received, the handler will trigger a system reset to swap the new firmware.
-pub fn usb_dfu<'d, D: Driver<'d>, DFU: NorFlash, STATE: NorFlash, RST: Reset, const BLOCK_SIZE: usize>(
-    builder: &mut Builder<'d, D>,
-    state: &'d mut State<'d, DFU, STATE, RST, BLOCK_SIZE>,
-    func_modifier: impl Fn(&mut FunctionBuilder<'_, 'd, D>),
+///
+/// For DfuSe, create the state with [`UsbDfuState::new_dfuse`]. Downloaded addresses are then relative to
+/// [`DfuSe::base_address`], which is usually the start of the active partition.
+pub fn usb_dfu<
+    'd,
+    D: Driver<'d>,
+    DFU: NorFlash,
+    STATE: NorFlash,
+    RST: Reset,
+    SRC: ReadNorFlash,
+    const BLOCK_SIZE: usize,
+    const MAX_INTERFACES: usize,
+    const MAX_HANDLERS: usize,
+>(
+    builder: &mut Builder<'d, D, MAX_INTERFACES, MAX_HANDLERS>,
+    state: &'d mut State<'d, DFU, STATE, RST, BLOCK_SIZE, SRC>,
+    func_modifier: impl Fn(&mut FunctionBuilder<'_, 'd, D, MAX_INTERFACES, MAX_HANDLERS>),
 ) {
     dfu_mode::usb_dfu(builder, state, BLOCK_SIZE, func_modifier);
 }
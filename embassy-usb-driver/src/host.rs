@@ -123,6 +123,8 @@ pub enum HostError {
     NoSuchDevice,
     /// Insufficient memory for the requested operation.
     InsufficientMemory,
+    /// Not enough periodic bus time left to schedule the pipe.
+    InsufficientBandwidth,
     /// An unspecified error with a static description.
     Other(&'static str),
 }
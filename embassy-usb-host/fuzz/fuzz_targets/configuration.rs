@@ -0,0 +1,39 @@
+#![no_main]
+
+use embassy_usb_host::class::uac::descriptors::AudioInterfaceCollection;
+use embassy_usb_host::class::{cdc_acm, cdc_net, dfu, gip, hid, midi, msc, printer};
+use embassy_usb_host::descriptor::{
+    ConfigurationDescriptorChain, DescriptorVisitor, EndpointDescriptor, InterfaceDescriptorChain,
+};
+use libfuzzer_sys::fuzz_target;
+
+struct Visitor;
+
+impl<'a> DescriptorVisitor<'a> for Visitor {
+    type Error = ();
+
+    fn on_endpoint(&mut self, _iface: &InterfaceDescriptorChain<'a>, _e: &EndpointDescriptor) -> bool {
+        true
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(cfg) = ConfigurationDescriptorChain::try_from_slice(data) {
+        for _ in cfg.iter_descriptors() {}
+        for iface in cfg.iter_interface() {
+            for _ in iface.iter_descriptors() {}
+            for _ in iface.iter_endpoints() {}
+        }
+        let _ = cfg.visit_descriptors(&mut Visitor);
+        let _ = AudioInterfaceCollection::try_from_configuration(&cfg);
+    }
+
+    let _ = hid::find_hid_interfaces::<8>(data);
+    let _ = cdc_acm::find_cdc_acm(data);
+    let _ = cdc_net::find_cdc_net(data);
+    let _ = dfu::find_dfu(data);
+    let _ = gip::find_gip(data);
+    let _ = midi::find_midi(data);
+    let _ = msc::find_msc(data);
+    let _ = printer::find_printer(data);
+});
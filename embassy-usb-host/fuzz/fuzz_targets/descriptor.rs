@@ -0,0 +1,33 @@
+#![no_main]
+
+use embassy_usb_host::class::hub::HubDescriptor;
+use embassy_usb_host::class::kbd::HIDDescriptor;
+use embassy_usb_host::class::uac::descriptors::*;
+use embassy_usb_host::class::uac::{Layout1ParameterBlock, Layout2ParameterBlock, Layout3ParameterBlock};
+use embassy_usb_host::descriptor::*;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = DeviceDescriptorPartial::try_from_bytes(data);
+    let _ = DeviceDescriptor::try_from_bytes(data);
+    let _ = ConfigurationDescriptor::try_from_bytes(data);
+    let _ = InterfaceDescriptor::try_from_bytes(data);
+    let _ = EndpointDescriptor::try_from_bytes(data);
+    let _ = StringDescriptorZero::try_from_bytes(data);
+    let _ = StringDescriptor::try_from_bytes(data);
+    let _ = StringDescriptorLossy::try_from_bytes(data);
+    let _ = HubDescriptor::try_from_bytes(data);
+    let _ = HIDDescriptor::try_from_bytes(data);
+
+    let _ = InterfaceAssociationDescriptor::try_from_bytes(data);
+    let _ = AudioControlHeaderDescriptor::try_from_bytes(data);
+    let _ = ClockDescriptor::try_from_bytes(data);
+    let _ = TerminalDescriptor::try_from_bytes(data);
+    let _ = UnitDescriptor::try_from_bytes(data);
+    let _ = AudioStreamingClassDescriptor::try_from_bytes(data);
+    let _ = AudioEndpointDescriptor::try_from_bytes(data);
+    let _ = FormatTypeDescriptor::try_from_bytes(data);
+    let _ = Layout1ParameterBlock::try_from_bytes(data);
+    let _ = Layout2ParameterBlock::try_from_bytes(data);
+    let _ = Layout3ParameterBlock::try_from_bytes(data);
+});
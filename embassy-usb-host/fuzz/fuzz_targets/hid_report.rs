@@ -0,0 +1,18 @@
+#![no_main]
+
+use embassy_usb_host::class::hid_input;
+use embassy_usb_host::class::hid_report::ReportDescriptor;
+use libfuzzer_sys::fuzz_target;
+
+// The input doubles as the report, so corpus descriptors work as seeds.
+fuzz_target!(|data: &[u8]| {
+    let (descriptor, report) = (data, data);
+    let desc: ReportDescriptor<32> = ReportDescriptor::parse(descriptor);
+    let _ = ReportDescriptor::<32>::try_parse(descriptor);
+    for field in desc.fields() {
+        for i in 0..(field.count as usize).min(64) {
+            let _ = field.extract_i32(report, i);
+        }
+    }
+    let _ = hid_input::decode(&desc, report);
+});
@@ -0,0 +1,142 @@
+//! [`embassy-net`](https://crates.io/crates/embassy-net) driver for the CDC-ECM/NCM host class.
+
+use embassy_futures::select::{Either3, select3};
+use embassy_net_driver_channel as ch;
+use embassy_net_driver_channel::driver::LinkState;
+use embassy_usb_driver::host::{PipeError, UsbHostAllocator};
+
+use super::{CdcNetError, CdcNetHost, Notification, Notifications, Receiver, Sender};
+
+/// Internal state for the embassy-net integration.
+pub struct State<const MTU: usize, const N_RX: usize, const N_TX: usize> {
+    ch_state: ch::State<MTU, N_RX, N_TX>,
+}
+
+impl<const MTU: usize, const N_RX: usize, const N_TX: usize> State<MTU, N_RX, N_TX> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            ch_state: ch::State::new(),
+        }
+    }
+}
+
+impl<const MTU: usize, const N_RX: usize, const N_TX: usize> Default for State<MTU, N_RX, N_TX> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn is_disconnected(e: &CdcNetError) -> bool {
+    matches!(e, CdcNetError::Transfer(PipeError::Disconnected))
+}
+
+/// Background runner for the CDC-ECM/NCM host class.
+///
+/// You must call `.run()` in a background task for the device to operate.
+pub struct Runner<'d, A: UsbHostAllocator<'d>, const MTU: usize> {
+    tx_usb: Sender<'d, A>,
+    rx_usb: Receiver<'d, A>,
+    notifications: Option<Notifications<'d, A>>,
+    ch: ch::Runner<'d, MTU>,
+}
+
+impl<'d, A: UsbHostAllocator<'d>, const MTU: usize> Runner<'d, A, MTU> {
+    /// Run the CDC-ECM/NCM host class.
+    ///
+    /// The link goes up on a network connection notification, or right away
+    /// if the function has no notification endpoint. Returns the error once
+    /// the device is disconnected, with the link set down.
+    pub async fn run(self) -> CdcNetError {
+        let Self {
+            mut tx_usb,
+            mut rx_usb,
+            mut notifications,
+            ch,
+        } = self;
+        let (state_chan, mut rx_chan, mut tx_chan) = ch.split();
+
+        let initial = match notifications {
+            Some(_) => LinkState::Down,
+            None => LinkState::Up,
+        };
+        state_chan.set_link_state(initial);
+
+        let rx_fut = async {
+            loop {
+                let mut p = rx_chan.rx_buf().await;
+                match rx_usb.read_packet(&mut p).await {
+                    Ok(n) => p.rx_done(n),
+                    Err(e) if is_disconnected(&e) => return e,
+                    Err(e) => warn!("error reading packet: {:?}", e),
+                }
+            }
+        };
+        let tx_fut = async {
+            loop {
+                let p = tx_chan.tx_buf().await;
+                let r = tx_usb.write_packet(&p).await;
+                p.tx_done();
+                match r {
+                    Ok(()) => {}
+                    Err(e) if is_disconnected(&e) => return e,
+                    Err(e) => warn!("Failed to TX packet: {:?}", e),
+                }
+            }
+        };
+        let notify_fut = async {
+            let Some(notifications) = &mut notifications else {
+                return core::future::pending().await;
+            };
+            loop {
+                match notifications.wait().await {
+                    Ok(Notification::NetworkConnection(true)) => state_chan.set_link_state(LinkState::Up),
+                    Ok(Notification::NetworkConnection(false)) => state_chan.set_link_state(LinkState::Down),
+                    Ok(Notification::ConnectionSpeedChange {
+                        downlink_bps,
+                        uplink_bps,
+                    }) => {
+                        debug!("link speed: down {} bps, up {} bps", downlink_bps, uplink_bps)
+                    }
+                    Ok(Notification::Other(_)) => {}
+                    Err(e) if is_disconnected(&e) => return e,
+                    Err(e) => warn!("error reading notification: {:?}", e),
+                }
+            }
+        };
+
+        let e = match select3(rx_fut, tx_fut, notify_fut).await {
+            Either3::First(e) | Either3::Second(e) | Either3::Third(e) => e,
+        };
+        state_chan.set_link_state(LinkState::Down);
+        e
+    }
+}
+
+/// Type alias for the embassy-net driver for CDC-ECM/NCM.
+pub type Device<'d, const MTU: usize> = embassy_net_driver_channel::Device<'d, MTU>;
+
+impl<'d, A: UsbHostAllocator<'d>> CdcNetHost<'d, A> {
+    /// Obtain a driver for using the function with [`embassy-net`](https://crates.io/crates/embassy-net).
+    ///
+    /// Call [`enable`](Self::enable) first; the hardware address is the
+    /// function's MAC address.
+    pub fn into_embassy_net_device<const MTU: usize, const N_RX: usize, const N_TX: usize>(
+        self,
+        state: &'d mut State<MTU, N_RX, N_TX>,
+    ) -> (Runner<'d, A, MTU>, Device<'d, MTU>) {
+        let mac_address = self.mac_address;
+        let (tx_usb, rx_usb, notifications) = self.split();
+        let (runner, device) = ch::new(&mut state.ch_state, ch::driver::HardwareAddress::Ethernet(mac_address));
+
+        (
+            Runner {
+                tx_usb,
+                rx_usb,
+                notifications,
+                ch: runner,
+            },
+            device,
+        )
+    }
+}
@@ -0,0 +1,672 @@
+//! CDC-ECM and CDC-NCM (Ethernet over USB) host class driver.
+//!
+//! Drives USB Ethernet adapters, phones offering USB tethering and cellular
+//! modems exposing an Ethernet Control Model or Network Control Model
+//! function. ECM carries one Ethernet frame per bulk transfer; NCM wraps
+//! frames in NCM Transfer Blocks (NTBs), of which this driver uses the
+//! 16-bit format with one datagram per OUT block.
+//!
+//! [`CdcNetHost::new`] parses the CDC functional descriptors and allocates
+//! pipes. [`CdcNetHost::enable`] then reads the MAC address, negotiates NTB
+//! sizes for NCM and selects the data interface's active alternate setting.
+//! Link state and speed arrive as [`Notification`]s on the interrupt
+//! endpoint.
+//!
+//! The [`embassy_net`] module turns the driver into an
+//! [`embassy-net`](https://crates.io/crates/embassy-net) device.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! use embassy_usb_host::class::cdc_net::{CdcNetHost, embassy_net::State};
+//!
+//! let mut host = CdcNetHost::new(&bus, &config_buf[..config_len], &enum_info)?;
+//! host.enable().await?;
+//!
+//! static STATE: StaticCell<State<1514, 4, 4>> = StaticCell::new();
+//! let (runner, device) = host.into_embassy_net_device(STATE.init(State::new()));
+//! spawner.spawn(usb_net_task(runner)).unwrap();
+//! ```
+
+use core::ops::Range;
+
+use embassy_usb_driver::host::{PipeError, UsbHostAllocator, UsbPipe, pipe};
+use embassy_usb_driver::{Direction as UsbDirection, EndpointAddress, EndpointInfo, EndpointType};
+
+use crate::control::SetupPacket;
+use crate::descriptor::{ConfigurationDescriptorChain, InterfaceDescriptorChain};
+use crate::handler::EnumerationInfo;
+
+pub mod embassy_net;
+mod ntb;
+
+pub use ntb::{NtbError, NtbParameters};
+
+/// CDC class code.
+const USB_CLASS_CDC: u8 = 0x02;
+/// CDC Data class code.
+const USB_CLASS_CDC_DATA: u8 = 0x0A;
+/// Ethernet Control Model subclass.
+const CDC_SUBCLASS_ECM: u8 = 0x06;
+/// Network Control Model subclass.
+const CDC_SUBCLASS_NCM: u8 = 0x0D;
+
+/// Class-specific interface descriptor type.
+const CS_INTERFACE: u8 = 0x24;
+const CDC_TYPE_UNION: u8 = 0x06;
+const CDC_TYPE_ETHERNET: u8 = 0x0F;
+const CDC_TYPE_NCM: u8 = 0x1A;
+
+const REQ_SET_ETHERNET_PACKET_FILTER: u8 = 0x43;
+const REQ_GET_NTB_PARAMETERS: u8 = 0x80;
+const REQ_SET_NTB_INPUT_SIZE: u8 = 0x86;
+
+/// `bmNetworkCapabilities`: SetEthernetPacketFilter is supported.
+const NCM_CAP_PACKET_FILTER: u8 = 1 << 0;
+/// `bmNetworkCapabilities`: SET_NTB_INPUT_SIZE takes an 8-byte argument.
+const NCM_CAP_NTB_INPUT_SIZE_8: u8 = 1 << 5;
+
+// Packet filter bits, CDC ECM 1.2 §6.2.4.
+const PACKET_TYPE_ALL_MULTICAST: u16 = 1 << 1;
+const PACKET_TYPE_DIRECTED: u16 = 1 << 2;
+const PACKET_TYPE_BROADCAST: u16 = 1 << 3;
+
+const NOTIFY_NETWORK_CONNECTION: u8 = 0x00;
+const NOTIFY_CONNECTION_SPEED_CHANGE: u8 = 0x2A;
+const NOTIFICATION_HEADER_LEN: usize = 8;
+
+/// Size of the NTB buffers, and the `dwNtbInMaxSize` requested from NCM functions.
+///
+/// Every NCM function must accept 2048-byte NTBs.
+pub const NTB_MAX_SIZE: usize = 2048;
+
+/// Control model of the network function.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CdcNetKind {
+    /// Ethernet Control Model, one frame per transfer.
+    Ecm,
+    /// Network Control Model, frames wrapped in NTBs.
+    Ncm,
+}
+
+/// CDC-ECM/NCM host class driver error.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CdcNetError {
+    /// Transfer error.
+    Transfer(PipeError),
+    /// No CDC-ECM or CDC-NCM function found in the device.
+    NoInterface,
+    /// Failed to allocate a pipe.
+    NoPipe,
+    /// Device response was malformed.
+    InvalidResponse,
+    /// Received NTB was malformed.
+    InvalidNtb(NtbError),
+    /// Frame does not fit in the buffer or NTB.
+    BufferOverflow,
+}
+
+impl From<PipeError> for CdcNetError {
+    fn from(e: PipeError) -> Self {
+        Self::Transfer(e)
+    }
+}
+
+impl From<NtbError> for CdcNetError {
+    fn from(e: NtbError) -> Self {
+        Self::InvalidNtb(e)
+    }
+}
+
+impl core::fmt::Display for CdcNetError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Transfer(_e) => write!(f, "Transfer error"),
+            Self::NoInterface => write!(f, "No CDC-ECM/NCM interface found"),
+            Self::NoPipe => write!(f, "No free pipe"),
+            Self::InvalidResponse => write!(f, "Invalid response from device"),
+            Self::InvalidNtb(_e) => write!(f, "Malformed NTB"),
+            Self::BufferOverflow => write!(f, "Frame too large"),
+        }
+    }
+}
+
+impl core::error::Error for CdcNetError {}
+
+/// Information about a CDC-ECM/NCM function found in a configuration descriptor.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CdcNetInfo {
+    /// Control model.
+    pub kind: CdcNetKind,
+    /// Communication interface number.
+    pub comm_interface: u8,
+    /// Data interface number.
+    pub data_interface: u8,
+    /// Alternate setting of the data interface carrying the bulk endpoints.
+    pub data_alt_setting: u8,
+    /// Bulk IN endpoint address.
+    pub bulk_in_ep: u8,
+    /// Bulk IN max packet size.
+    pub bulk_in_mps: u16,
+    /// Bulk OUT endpoint address.
+    pub bulk_out_ep: u8,
+    /// Bulk OUT max packet size.
+    pub bulk_out_mps: u16,
+    /// Notification endpoint: address, max packet size and interval.
+    pub interrupt_in: Option<(u8, u16, u8)>,
+    /// String index of the MAC address, from the Ethernet Networking descriptor.
+    pub mac_address_string: u8,
+    /// Largest Ethernet frame the function handles, without CRC.
+    pub max_segment_size: u16,
+    /// `bmNetworkCapabilities` from the NCM functional descriptor, 0 for ECM.
+    pub ncm_capabilities: u8,
+}
+
+/// Functional descriptors of a communication interface.
+struct CommFunctional {
+    data_interface: Option<u8>,
+    mac_address_string: Option<u8>,
+    max_segment_size: u16,
+    ncm_capabilities: u8,
+}
+
+fn parse_functional(iface: &InterfaceDescriptorChain<'_>) -> CommFunctional {
+    let mut out = CommFunctional {
+        data_interface: None,
+        mac_address_string: None,
+        max_segment_size: 1514,
+        ncm_capabilities: 0,
+    };
+    for (_, desc) in iface.iter_descriptors() {
+        if desc.get(1) != Some(&CS_INTERFACE) {
+            continue;
+        }
+        match (desc.get(2), desc.len()) {
+            (Some(&CDC_TYPE_UNION), 5..) => out.data_interface = Some(desc[4]),
+            (Some(&CDC_TYPE_ETHERNET), 13..) => {
+                out.mac_address_string = Some(desc[3]);
+                out.max_segment_size = u16::from_le_bytes([desc[8], desc[9]]);
+            }
+            (Some(&CDC_TYPE_NCM), 6..) => out.ncm_capabilities = desc[5],
+            _ => {}
+        }
+    }
+    out
+}
+
+/// Find the first CDC-ECM or CDC-NCM function in a configuration descriptor.
+///
+/// The function must have a Union and an Ethernet Networking functional
+/// descriptor and a data interface alternate setting with a bulk IN/OUT pair.
+pub fn find_cdc_net(config_desc: &[u8]) -> Option<CdcNetInfo> {
+    let cfg = ConfigurationDescriptorChain::try_from_slice(config_desc).ok()?;
+
+    let (kind, comm_interface, functional, interrupt_in) = cfg.iter_interface().find_map(|iface| {
+        let kind = match (iface.interface_class, iface.interface_subclass) {
+            (USB_CLASS_CDC, CDC_SUBCLASS_ECM) => CdcNetKind::Ecm,
+            (USB_CLASS_CDC, CDC_SUBCLASS_NCM) => CdcNetKind::Ncm,
+            _ => return None,
+        };
+        let interrupt_in = iface
+            .iter_endpoints()
+            .find(|ep| ep.ep_type() == EndpointType::Interrupt && ep.is_in())
+            .map(|ep| (ep.endpoint_address, ep.max_packet_size, ep.interval));
+        Some((kind, iface.interface_number, parse_functional(&iface), interrupt_in))
+    })?;
+
+    let data_interface = functional.data_interface?;
+    let mac_address_string = functional.mac_address_string?;
+
+    for iface in cfg.iter_interface() {
+        if iface.interface_number != data_interface || iface.interface_class != USB_CLASS_CDC_DATA {
+            continue;
+        }
+        let mut bulk_in = None;
+        let mut bulk_out = None;
+        for ep in iface.iter_endpoints() {
+            if ep.ep_type() != EndpointType::Bulk {
+                continue;
+            }
+            if ep.is_in() {
+                bulk_in = Some((ep.endpoint_address, ep.max_packet_size));
+            } else {
+                bulk_out = Some((ep.endpoint_address, ep.max_packet_size));
+            }
+        }
+        if let (Some((in_ep, in_mps)), Some((out_ep, out_mps))) = (bulk_in, bulk_out) {
+            return Some(CdcNetInfo {
+                kind,
+                comm_interface,
+                data_interface,
+                data_alt_setting: iface.alternate_setting,
+                bulk_in_ep: in_ep,
+                bulk_in_mps: in_mps,
+                bulk_out_ep: out_ep,
+                bulk_out_mps: out_mps,
+                interrupt_in,
+                mac_address_string,
+                max_segment_size: functional.max_segment_size,
+                ncm_capabilities: functional.ncm_capabilities,
+            });
+        }
+    }
+    None
+}
+
+/// Parse the MAC address string descriptor: 12 hex digits in UTF-16LE.
+fn parse_mac_address(desc: &[u8]) -> Option<[u8; 6]> {
+    let text = desc.get(2..26)?;
+    let mut mac = [0u8; 6];
+    for (i, unit) in text.chunks_exact(2).enumerate() {
+        let c = char::from_u32(u16::from_le_bytes([unit[0], unit[1]]) as u32)?;
+        let nibble = c.to_digit(16)? as u8;
+        mac[i / 2] = mac[i / 2] << 4 | nibble;
+    }
+    Some(mac)
+}
+
+/// Notification from the communication interface, CDC PSTN/ECM §6.3.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Notification {
+    /// Network cable connected (`true`) or disconnected.
+    NetworkConnection(bool),
+    /// Link speed changed, in bits per second.
+    ConnectionSpeedChange {
+        /// Speed from the function to the host.
+        downlink_bps: u32,
+        /// Speed from the host to the function.
+        uplink_bps: u32,
+    },
+    /// Any other notification, by `bNotificationCode`.
+    Other(u8),
+}
+
+impl Notification {
+    /// Parse a notification, or `None` if it is truncated.
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        let header = buf.get(..NOTIFICATION_HEADER_LEN)?;
+        Some(match header[1] {
+            NOTIFY_NETWORK_CONNECTION => Self::NetworkConnection(header[2] != 0),
+            NOTIFY_CONNECTION_SPEED_CHANGE => {
+                let data = buf.get(NOTIFICATION_HEADER_LEN..NOTIFICATION_HEADER_LEN + 8)?;
+                Self::ConnectionSpeedChange {
+                    downlink_bps: u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+                    uplink_bps: u32::from_le_bytes([data[4], data[5], data[6], data[7]]),
+                }
+            }
+            code => Self::Other(code),
+        })
+    }
+}
+
+/// CDC-ECM/NCM host driver.
+///
+/// Split it with [`split`](Self::split) to send and receive concurrently,
+/// or hand it to embassy-net with
+/// [`into_embassy_net_device`](Self::into_embassy_net_device).
+pub struct CdcNetHost<'d, A: UsbHostAllocator<'d>> {
+    ctrl_ch: A::Pipe<pipe::Control, pipe::InOut>,
+    in_ch: A::Pipe<pipe::Bulk, pipe::In>,
+    out_ch: A::Pipe<pipe::Bulk, pipe::Out>,
+    int_ch: Option<A::Pipe<pipe::Interrupt, pipe::In>>,
+    info: CdcNetInfo,
+    mac_address: [u8; 6],
+    ntb_params: NtbParameters,
+    _phantom: core::marker::PhantomData<&'d ()>,
+}
+
+impl<'d, A: UsbHostAllocator<'d>> CdcNetHost<'d, A> {
+    /// Create a new CDC-ECM/NCM host driver.
+    ///
+    /// Parses the config descriptor to find the network function and allocates
+    /// pipes. Performs no I/O; call [`enable`](Self::enable) next.
+    pub fn new(alloc: &A, config_desc: &[u8], enum_info: &EnumerationInfo) -> Result<Self, CdcNetError> {
+        let info = find_cdc_net(config_desc).ok_or(CdcNetError::NoInterface)?;
+
+        let ctrl_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts(0, UsbDirection::In),
+            ep_type: EndpointType::Control,
+            max_packet_size: enum_info.device_desc.max_packet_size0 as u16,
+            interval_ms: 0,
+        };
+
+        let in_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts((info.bulk_in_ep & 0x0F) as usize, UsbDirection::In),
+            ep_type: EndpointType::Bulk,
+            max_packet_size: info.bulk_in_mps,
+            interval_ms: 0,
+        };
+
+        let out_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts((info.bulk_out_ep & 0x0F) as usize, UsbDirection::Out),
+            ep_type: EndpointType::Bulk,
+            max_packet_size: info.bulk_out_mps,
+            interval_ms: 0,
+        };
+
+        let device_address = enum_info.device_address;
+        let split = enum_info.split();
+
+        let ctrl_ch = alloc
+            .alloc_pipe::<pipe::Control, pipe::InOut>(device_address, &ctrl_ep_info, split)
+            .map_err(|_| CdcNetError::NoPipe)?;
+        let in_ch = alloc
+            .alloc_pipe::<pipe::Bulk, pipe::In>(device_address, &in_ep_info, split)
+            .map_err(|_| CdcNetError::NoPipe)?;
+        let out_ch = alloc
+            .alloc_pipe::<pipe::Bulk, pipe::Out>(device_address, &out_ep_info, split)
+            .map_err(|_| CdcNetError::NoPipe)?;
+        let int_ch = match info.interrupt_in {
+            Some((ep, mps, interval)) => {
+                let int_ep_info = EndpointInfo {
+                    addr: EndpointAddress::from_parts((ep & 0x0F) as usize, UsbDirection::In),
+                    ep_type: EndpointType::Interrupt,
+                    max_packet_size: mps,
+                    interval_ms: interval,
+                };
+                Some(
+                    alloc
+                        .alloc_pipe::<pipe::Interrupt, pipe::In>(device_address, &int_ep_info, split)
+                        .map_err(|_| CdcNetError::NoPipe)?,
+                )
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            ctrl_ch,
+            in_ch,
+            out_ch,
+            int_ch,
+            info,
+            mac_address: [0; 6],
+            ntb_params: NtbParameters::default(),
+            _phantom: core::marker::PhantomData,
+        })
+    }
+
+    /// Function information parsed from the configuration descriptor.
+    pub fn info(&self) -> &CdcNetInfo {
+        &self.info
+    }
+
+    /// MAC address of the function, valid after [`enable`](Self::enable).
+    pub fn mac_address(&self) -> [u8; 6] {
+        self.mac_address
+    }
+
+    /// NTB parameters of an NCM function, valid after [`enable`](Self::enable).
+    pub fn ntb_parameters(&self) -> &NtbParameters {
+        &self.ntb_params
+    }
+
+    /// Bring up the network function.
+    ///
+    /// Reads the MAC address string, negotiates NTB sizes for NCM, selects
+    /// the data interface's bulk alternate setting and enables reception of
+    /// directed, broadcast and multicast frames.
+    pub async fn enable(&mut self) -> Result<(), CdcNetError> {
+        self.mac_address = self.read_mac_address().await?;
+        let comm = self.info.comm_interface as u16;
+
+        if self.info.kind == CdcNetKind::Ncm {
+            let mut buf = [0u8; ntb::NTB_PARAMETERS_LEN];
+            let setup = SetupPacket::class_interface_in(REQ_GET_NTB_PARAMETERS, 0, comm, buf.len() as u16);
+            let n = self.ctrl_ch.control_in(&setup.to_bytes(), &mut buf).await?;
+            self.ntb_params = NtbParameters::parse(&buf[..n]).ok_or(CdcNetError::InvalidResponse)?;
+            if self.ntb_params.formats_supported & 1 == 0 {
+                return Err(CdcNetError::InvalidResponse);
+            }
+
+            // Must be issued while the data interface is in alternate setting 0.
+            let in_size = (NTB_MAX_SIZE as u32).min(self.ntb_params.ntb_in_max_size);
+            let mut data = [0u8; 8];
+            data[..4].copy_from_slice(&in_size.to_le_bytes());
+            let len = if self.info.ncm_capabilities & NCM_CAP_NTB_INPUT_SIZE_8 != 0 {
+                8
+            } else {
+                4
+            };
+            let setup = SetupPacket::class_interface_out(REQ_SET_NTB_INPUT_SIZE, 0, comm, len as u16);
+            self.ctrl_ch.control_out(&setup.to_bytes(), &data[..len]).await?;
+        }
+
+        let setup = SetupPacket::set_interface(self.info.data_interface as u16, self.info.data_alt_setting);
+        self.ctrl_ch.control_out(&setup.to_bytes(), &[]).await?;
+
+        if self.info.kind == CdcNetKind::Ecm || self.info.ncm_capabilities & NCM_CAP_PACKET_FILTER != 0 {
+            let filter = PACKET_TYPE_DIRECTED | PACKET_TYPE_BROADCAST | PACKET_TYPE_ALL_MULTICAST;
+            let setup = SetupPacket::class_interface_out(REQ_SET_ETHERNET_PACKET_FILTER, filter, comm, 0);
+            self.ctrl_ch.control_out(&setup.to_bytes(), &[]).await?;
+        }
+        Ok(())
+    }
+
+    async fn read_mac_address(&mut self) -> Result<[u8; 6], CdcNetError> {
+        // Use the first language the device supports.
+        let mut buf = [0u8; 26];
+        let setup = SetupPacket::get_string_descriptor(0, 0, 4);
+        let n = self.ctrl_ch.control_in(&setup.to_bytes(), &mut buf[..4]).await?;
+        let lang_id = match n {
+            4.. => u16::from_le_bytes([buf[2], buf[3]]),
+            _ => 0x0409,
+        };
+
+        let setup = SetupPacket::get_string_descriptor(self.info.mac_address_string, lang_id, buf.len() as u16);
+        let n = self.ctrl_ch.control_in(&setup.to_bytes(), &mut buf).await?;
+        parse_mac_address(&buf[..n]).ok_or(CdcNetError::InvalidResponse)
+    }
+
+    /// Split into independent sender, receiver and notification halves.
+    ///
+    /// The control pipe is released. The notification half is `None` if the
+    /// function has no interrupt endpoint.
+    pub fn split(self) -> (Sender<'d, A>, Receiver<'d, A>, Option<Notifications<'d, A>>) {
+        (
+            Sender {
+                out_ch: self.out_ch,
+                kind: self.info.kind,
+                ntb_params: self.ntb_params,
+                seq: 0,
+                buf: [0; NTB_MAX_SIZE],
+            },
+            Receiver {
+                in_ch: self.in_ch,
+                kind: self.info.kind,
+                buf: [0; NTB_MAX_SIZE],
+                len: 0,
+                cursor: None,
+            },
+            self.int_ch.map(|int_ch| Notifications { int_ch }),
+        )
+    }
+}
+
+/// Sending half of a [`CdcNetHost`].
+pub struct Sender<'d, A: UsbHostAllocator<'d>> {
+    out_ch: A::Pipe<pipe::Bulk, pipe::Out>,
+    kind: CdcNetKind,
+    ntb_params: NtbParameters,
+    seq: u16,
+    buf: [u8; NTB_MAX_SIZE],
+}
+
+impl<'d, A: UsbHostAllocator<'d>> Sender<'d, A> {
+    /// Send one Ethernet frame, without CRC.
+    pub async fn write_packet(&mut self, frame: &[u8]) -> Result<(), CdcNetError> {
+        match self.kind {
+            CdcNetKind::Ecm => self.out_ch.request_out(frame, true).await?,
+            CdcNetKind::Ncm => {
+                let n = ntb::write_ntb16(&mut self.buf, self.seq, frame, &self.ntb_params)
+                    .ok_or(CdcNetError::BufferOverflow)?;
+                self.seq = self.seq.wrapping_add(1);
+                self.out_ch.request_out(&self.buf[..n], true).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Receiving half of a [`CdcNetHost`].
+pub struct Receiver<'d, A: UsbHostAllocator<'d>> {
+    in_ch: A::Pipe<pipe::Bulk, pipe::In>,
+    kind: CdcNetKind,
+    buf: [u8; NTB_MAX_SIZE],
+    len: usize,
+    /// Datagrams left in the current NTB.
+    cursor: Option<ntb::NtbCursor>,
+}
+
+impl<'d, A: UsbHostAllocator<'d>> Receiver<'d, A> {
+    /// Receive one Ethernet frame into `frame`, returning its length.
+    ///
+    /// For NCM, frames left in a received NTB are returned by the next calls.
+    /// A malformed NTB is dropped and reported as [`CdcNetError::InvalidNtb`].
+    pub async fn read_packet(&mut self, frame: &mut [u8]) -> Result<usize, CdcNetError> {
+        if self.kind == CdcNetKind::Ecm {
+            return Ok(self.in_ch.request_in(frame).await?);
+        }
+        loop {
+            if let Some(range) = self.next_datagram()? {
+                let datagram = &self.buf[range];
+                let dst = frame.get_mut(..datagram.len()).ok_or(CdcNetError::BufferOverflow)?;
+                dst.copy_from_slice(datagram);
+                return Ok(datagram.len());
+            }
+            self.len = self.in_ch.request_in(&mut self.buf).await?;
+            // Some functions send empty transfers between NTBs.
+            if self.len > 0 {
+                self.cursor = Some(ntb::parse_nth16(&self.buf[..self.len])?);
+            }
+        }
+    }
+
+    fn next_datagram(&mut self) -> Result<Option<Range<usize>>, NtbError> {
+        let Some(cursor) = &mut self.cursor else {
+            return Ok(None);
+        };
+        let r = ntb::next_datagram(&self.buf[..self.len], cursor);
+        if !matches!(r, Ok(Some(_))) {
+            self.cursor = None;
+        }
+        r
+    }
+}
+
+/// Notification half of a [`CdcNetHost`].
+pub struct Notifications<'d, A: UsbHostAllocator<'d>> {
+    int_ch: A::Pipe<pipe::Interrupt, pipe::In>,
+}
+
+impl<'d, A: UsbHostAllocator<'d>> Notifications<'d, A> {
+    /// Wait for the next notification.
+    ///
+    /// Truncated notifications are skipped.
+    pub async fn wait(&mut self) -> Result<Notification, CdcNetError> {
+        let mut buf = [0u8; 16];
+        loop {
+            let n = self.int_ch.request_in(&mut buf).await?;
+            if let Some(notification) = Notification::parse(&buf[..n]) {
+                return Ok(notification);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rustfmt::skip]
+    const NCM_CONFIG: &[u8] = &[
+        // Configuration
+        0x09, 0x02, 0x56, 0x00, 0x02, 0x01, 0x00, 0x80, 0xFA,
+        // Communication interface 0: CDC, NCM
+        0x09, 0x04, 0x00, 0x00, 0x01, 0x02, 0x0D, 0x00, 0x00,
+        // Header
+        0x05, 0x24, 0x00, 0x10, 0x01,
+        // Union: control 0, subordinate 1
+        0x05, 0x24, 0x06, 0x00, 0x01,
+        // Ethernet Networking: iMACAddress 4, max segment 1514
+        0x0D, 0x24, 0x0F, 0x04, 0x00, 0x00, 0x00, 0x00, 0xEA, 0x05, 0x00, 0x00, 0x00,
+        // NCM: version 1.0, capabilities
+        0x06, 0x24, 0x1A, 0x00, 0x01, 0x21,
+        // Notification endpoint 0x81
+        0x07, 0x05, 0x81, 0x03, 0x10, 0x00, 0x09,
+        // Data interface 1 alt 0: no endpoints
+        0x09, 0x04, 0x01, 0x00, 0x00, 0x0A, 0x00, 0x01, 0x00,
+        // Data interface 1 alt 1
+        0x09, 0x04, 0x01, 0x01, 0x02, 0x0A, 0x00, 0x01, 0x00,
+        0x07, 0x05, 0x82, 0x02, 0x00, 0x02, 0x00,
+        0x07, 0x05, 0x03, 0x02, 0x00, 0x02, 0x00,
+    ];
+
+    #[test]
+    fn find_ncm() {
+        let info = find_cdc_net(NCM_CONFIG).unwrap();
+        assert_eq!(info.kind, CdcNetKind::Ncm);
+        assert_eq!(
+            (info.comm_interface, info.data_interface, info.data_alt_setting),
+            (0, 1, 1)
+        );
+        assert_eq!((info.bulk_in_ep, info.bulk_out_ep, info.bulk_in_mps), (0x82, 0x03, 512));
+        assert_eq!(info.interrupt_in, Some((0x81, 16, 9)));
+        assert_eq!((info.mac_address_string, info.max_segment_size), (4, 1514));
+        assert_eq!(info.ncm_capabilities, 0x21);
+    }
+
+    #[test]
+    fn find_ecm() {
+        let mut config: [u8; NCM_CONFIG.len()] = NCM_CONFIG.try_into().unwrap();
+        config[15] = CDC_SUBCLASS_ECM;
+        assert_eq!(find_cdc_net(&config).unwrap().kind, CdcNetKind::Ecm);
+
+        // Without an Ethernet Networking descriptor there is no MAC address.
+        config[30] = 0x00;
+        assert!(find_cdc_net(&config).is_none());
+    }
+
+    #[test]
+    fn mac_address() {
+        let mut desc = [0u8; 26];
+        desc[0] = 26;
+        desc[1] = 0x03;
+        for (i, c) in "02AbCd0012fF".bytes().enumerate() {
+            desc[2 + 2 * i] = c;
+        }
+        assert_eq!(parse_mac_address(&desc), Some([0x02, 0xAB, 0xCD, 0x00, 0x12, 0xFF]));
+        desc[4] = b'g';
+        assert_eq!(parse_mac_address(&desc), None);
+        assert_eq!(parse_mac_address(&desc[..20]), None);
+    }
+
+    #[test]
+    fn notifications() {
+        let connected = [0xA1, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(
+            Notification::parse(&connected),
+            Some(Notification::NetworkConnection(true))
+        );
+
+        let mut speed = [0u8; 16];
+        speed[..8].copy_from_slice(&[0xA1, 0x2A, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00]);
+        speed[8..12].copy_from_slice(&100_000_000u32.to_le_bytes());
+        speed[12..16].copy_from_slice(&10_000_000u32.to_le_bytes());
+        assert_eq!(
+            Notification::parse(&speed),
+            Some(Notification::ConnectionSpeedChange {
+                downlink_bps: 100_000_000,
+                uplink_bps: 10_000_000,
+            })
+        );
+        assert_eq!(Notification::parse(&speed[..12]), None);
+        assert_eq!(
+            Notification::parse(&[0xA1, 0x01, 0, 0, 0, 0, 0, 0]),
+            Some(Notification::Other(0x01))
+        );
+    }
+}
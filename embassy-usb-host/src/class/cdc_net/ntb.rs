@@ -0,0 +1,352 @@
+//! NCM Transfer Block framing, 16-bit format (CDC NCM 1.0 §3).
+
+use core::ops::Range;
+
+/// NTH16 signature, "NCMH".
+const SIG_NTH16: u32 = 0x484D_434E;
+/// NDP16 signature without CRC, "NCM0".
+const SIG_NDP16_NO_FCS: u32 = 0x304D_434E;
+/// NDP16 signature with CRC, "NCM1".
+const SIG_NDP16_WITH_FCS: u32 = 0x314D_434E;
+
+const NTH16_LEN: usize = 12;
+/// NDP16 header plus one datagram pointer and the terminating null entry.
+const NDP16_SINGLE_LEN: usize = 16;
+const NDP16_HEADER_LEN: usize = 8;
+const NDP16_ENTRY_LEN: usize = 4;
+
+/// Length of the `GET_NTB_PARAMETERS` response.
+pub(crate) const NTB_PARAMETERS_LEN: usize = 28;
+
+/// NTB parameters reported by the function, CDC NCM 1.0 §6.2.1.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NtbParameters {
+    /// Supported NTB formats; bit 0 is 16-bit, bit 1 is 32-bit.
+    pub formats_supported: u16,
+    /// Largest NTB the function sends.
+    pub ntb_in_max_size: u32,
+    /// Largest NTB the function accepts.
+    pub ntb_out_max_size: u32,
+    /// Datagram alignment modulus in OUT NTBs.
+    pub ndp_out_divisor: u16,
+    /// Datagram offset remainder in OUT NTBs.
+    pub ndp_out_payload_remainder: u16,
+    /// NDP alignment in OUT NTBs.
+    pub ndp_out_alignment: u16,
+    /// Largest number of datagrams per OUT NTB, 0 if unlimited.
+    pub ntb_out_max_datagrams: u16,
+}
+
+impl NtbParameters {
+    /// Parse the `GET_NTB_PARAMETERS` response.
+    pub(crate) fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < NTB_PARAMETERS_LEN {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        // A zero divisor means no constraint; the remainder is only meaningful modulo the divisor.
+        let divisor = u16_at(20).max(1);
+        Some(Self {
+            formats_supported: u16_at(2),
+            ntb_in_max_size: u32_at(4),
+            ntb_out_max_size: u32_at(16),
+            ndp_out_divisor: divisor,
+            ndp_out_payload_remainder: u16_at(22) % divisor,
+            ndp_out_alignment: u16_at(24),
+            ntb_out_max_datagrams: u16_at(26),
+        })
+    }
+}
+
+impl Default for NtbParameters {
+    /// Minimal parameters every function must accept.
+    fn default() -> Self {
+        Self {
+            formats_supported: 1,
+            ntb_in_max_size: 2048,
+            ntb_out_max_size: 2048,
+            ndp_out_divisor: 4,
+            ndp_out_payload_remainder: 0,
+            ndp_out_alignment: 4,
+            ntb_out_max_datagrams: 0,
+        }
+    }
+}
+
+/// Malformed NTB.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum NtbError {
+    /// Bad NTH16 or NDP16 signature.
+    Signature,
+    /// A header, pointer or datagram lies outside the block.
+    OutOfBounds,
+}
+
+/// Position of the next datagram pointer in a received NTB.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct NtbCursor {
+    /// Offset of the current NDP16, 0 once all NDPs are consumed.
+    ndp: usize,
+    /// Offset of the next datagram pointer entry within the NTB.
+    entry: usize,
+    /// Block length from the NTH16.
+    block_len: usize,
+}
+
+fn u16_at(buf: &[u8], i: usize) -> Result<u16, NtbError> {
+    let b = buf.get(i..i + 2).ok_or(NtbError::OutOfBounds)?;
+    Ok(u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn u32_at(buf: &[u8], i: usize) -> Result<u32, NtbError> {
+    let b = buf.get(i..i + 4).ok_or(NtbError::OutOfBounds)?;
+    Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Validate the NTH16 of a received NTB and return a cursor to its first datagram.
+pub(crate) fn parse_nth16(ntb: &[u8]) -> Result<NtbCursor, NtbError> {
+    if u32_at(ntb, 0)? != SIG_NTH16 {
+        return Err(NtbError::Signature);
+    }
+    let block_len = u16_at(ntb, 8)? as usize;
+    if block_len > ntb.len() || block_len < NTH16_LEN {
+        return Err(NtbError::OutOfBounds);
+    }
+    let ndp = u16_at(ntb, 10)? as usize;
+    enter_ndp(ntb, ndp, block_len)
+}
+
+fn enter_ndp(ntb: &[u8], ndp: usize, block_len: usize) -> Result<NtbCursor, NtbError> {
+    if ndp == 0 {
+        return Ok(NtbCursor {
+            ndp: 0,
+            entry: 0,
+            block_len,
+        });
+    }
+    if ndp < NTH16_LEN || ndp + NDP16_HEADER_LEN > block_len {
+        return Err(NtbError::OutOfBounds);
+    }
+    match u32_at(ntb, ndp)? {
+        SIG_NDP16_NO_FCS | SIG_NDP16_WITH_FCS => {}
+        _ => return Err(NtbError::Signature),
+    }
+    Ok(NtbCursor {
+        ndp,
+        entry: ndp + NDP16_HEADER_LEN,
+        block_len,
+    })
+}
+
+/// Return the byte range of the next datagram, following chained NDPs.
+///
+/// Returns `Ok(None)` once the block is exhausted.
+pub(crate) fn next_datagram(ntb: &[u8], cursor: &mut NtbCursor) -> Result<Option<Range<usize>>, NtbError> {
+    while cursor.ndp != 0 {
+        let ndp_len = u16_at(ntb, cursor.ndp + 4)? as usize;
+        let ndp_end = (cursor.ndp + ndp_len).min(cursor.block_len);
+        if cursor.entry + NDP16_ENTRY_LEN <= ndp_end {
+            let index = u16_at(ntb, cursor.entry)? as usize;
+            let len = u16_at(ntb, cursor.entry + 2)? as usize;
+            cursor.entry += NDP16_ENTRY_LEN;
+            if index != 0 && len != 0 {
+                if index + len > cursor.block_len {
+                    return Err(NtbError::OutOfBounds);
+                }
+                return Ok(Some(index..index + len));
+            }
+        }
+        // Null entry or end of the NDP: move on to the next one.
+        let next = u16_at(ntb, cursor.ndp + 6)? as usize;
+        if next != 0 && next <= cursor.ndp {
+            // Pointers must move forward, or a loop would never end.
+            return Err(NtbError::OutOfBounds);
+        }
+        *cursor = enter_ndp(ntb, next, cursor.block_len)?;
+    }
+    Ok(None)
+}
+
+const fn align_up(offset: usize, align: usize) -> usize {
+    offset.div_ceil(align) * align
+}
+
+/// Frame `datagram` into a single-datagram NTB16 in `buf`.
+///
+/// Places the NDP16 right after the NTH16 and the datagram at the first
+/// offset satisfying the function's divisor and remainder. Returns the
+/// block length, or `None` if the block does not fit in `buf` or in the
+/// function's maximum OUT size.
+pub(crate) fn write_ntb16(buf: &mut [u8], seq: u16, datagram: &[u8], params: &NtbParameters) -> Option<usize> {
+    let ndp = align_up(NTH16_LEN, params.ndp_out_alignment.max(4) as usize);
+    let divisor = params.ndp_out_divisor.max(1) as usize;
+    let remainder = params.ndp_out_payload_remainder as usize % divisor;
+    let start = ndp + NDP16_SINGLE_LEN;
+    let index = start + (remainder + divisor - start % divisor) % divisor;
+    let block_len = index + datagram.len();
+    if block_len > buf.len() || block_len > params.ntb_out_max_size as usize || block_len > u16::MAX as usize {
+        return None;
+    }
+
+    buf[..index].fill(0);
+    buf[0..4].copy_from_slice(&SIG_NTH16.to_le_bytes());
+    buf[4..6].copy_from_slice(&(NTH16_LEN as u16).to_le_bytes());
+    buf[6..8].copy_from_slice(&seq.to_le_bytes());
+    buf[8..10].copy_from_slice(&(block_len as u16).to_le_bytes());
+    buf[10..12].copy_from_slice(&(ndp as u16).to_le_bytes());
+
+    buf[ndp..ndp + 4].copy_from_slice(&SIG_NDP16_NO_FCS.to_le_bytes());
+    buf[ndp + 4..ndp + 6].copy_from_slice(&(NDP16_SINGLE_LEN as u16).to_le_bytes());
+    buf[ndp + 8..ndp + 10].copy_from_slice(&(index as u16).to_le_bytes());
+    buf[ndp + 10..ndp + 12].copy_from_slice(&(datagram.len() as u16).to_le_bytes());
+
+    buf[index..block_len].copy_from_slice(datagram);
+    Some(block_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagrams(ntb: &[u8]) -> Result<heapless::Vec<&[u8], 8>, NtbError> {
+        let mut cursor = parse_nth16(ntb)?;
+        let mut out = heapless::Vec::new();
+        while let Some(r) = next_datagram(ntb, &mut cursor)? {
+            out.push(&ntb[r]).unwrap();
+        }
+        Ok(out)
+    }
+
+    #[test]
+    fn roundtrip_single() {
+        let mut buf = [0u8; 128];
+        let params = NtbParameters::default();
+        let n = write_ntb16(&mut buf, 7, b"hello", &params).unwrap();
+        assert_eq!(n, 28 + 5);
+        assert_eq!(u16::from_le_bytes([buf[6], buf[7]]), 7);
+        assert_eq!(datagrams(&buf[..n]).unwrap().as_slice(), &[b"hello".as_slice()]);
+    }
+
+    #[test]
+    fn write_alignment() {
+        let mut buf = [0u8; 128];
+        let params = NtbParameters {
+            ndp_out_divisor: 16,
+            ndp_out_payload_remainder: 2,
+            ndp_out_alignment: 8,
+            ..Default::default()
+        };
+        let n = write_ntb16(&mut buf, 0, &[0xAA; 4], &params).unwrap();
+        // NDP at 16, datagram at the first offset >= 32 that is 2 mod 16.
+        assert_eq!(u16::from_le_bytes([buf[10], buf[11]]), 16);
+        assert_eq!(u16::from_le_bytes([buf[24], buf[25]]), 34);
+        assert_eq!(n, 38);
+
+        let params = NtbParameters {
+            ntb_out_max_size: 32,
+            ..Default::default()
+        };
+        assert_eq!(write_ntb16(&mut buf, 0, &[0; 8], &params), None);
+    }
+
+    #[test]
+    fn write_remainder_past_ndp() {
+        let mut buf = [0u8; 128];
+        let params = NtbParameters {
+            ndp_out_divisor: 64,
+            ndp_out_payload_remainder: 40,
+            ..Default::default()
+        };
+        let n = write_ntb16(&mut buf, 0, &[0xAA; 4], &params).unwrap();
+        // NDP at 12 ending at 28, datagram at the first offset >= 28 that is 40 mod 64.
+        assert_eq!(u16::from_le_bytes([buf[20], buf[21]]), 40);
+        assert_eq!(n, 44);
+        assert_eq!(datagrams(&buf[..n]).unwrap().as_slice(), &[&[0xAA; 4][..]]);
+    }
+
+    #[test]
+    fn parse_parameters_clamped() {
+        let mut buf = [0u8; NTB_PARAMETERS_LEN];
+        buf[2] = 1;
+        buf[20..22].copy_from_slice(&0u16.to_le_bytes());
+        buf[22..24].copy_from_slice(&5u16.to_le_bytes());
+        let params = NtbParameters::parse(&buf).unwrap();
+        assert_eq!(params.ndp_out_divisor, 1);
+        assert_eq!(params.ndp_out_payload_remainder, 0);
+
+        buf[20..22].copy_from_slice(&16u16.to_le_bytes());
+        buf[22..24].copy_from_slice(&18u16.to_le_bytes());
+        let params = NtbParameters::parse(&buf).unwrap();
+        assert_eq!(params.ndp_out_divisor, 16);
+        assert_eq!(params.ndp_out_payload_remainder, 2);
+    }
+
+    #[test]
+    fn parse_chained_ndps() {
+        let mut ntb = [0u8; 64];
+        ntb[0..4].copy_from_slice(&SIG_NTH16.to_le_bytes());
+        ntb[4..6].copy_from_slice(&12u16.to_le_bytes());
+        ntb[8..10].copy_from_slice(&64u16.to_le_bytes());
+        ntb[10..12].copy_from_slice(&12u16.to_le_bytes());
+        // First NDP: two datagrams, then chains to the NDP at 36.
+        ntb[12..16].copy_from_slice(&SIG_NDP16_NO_FCS.to_le_bytes());
+        ntb[16..18].copy_from_slice(&20u16.to_le_bytes());
+        ntb[18..20].copy_from_slice(&36u16.to_le_bytes());
+        ntb[20..24].copy_from_slice(&[52, 0, 2, 0]);
+        ntb[24..28].copy_from_slice(&[56, 0, 3, 0]);
+        // Second NDP: one datagram.
+        ntb[36..40].copy_from_slice(&SIG_NDP16_WITH_FCS.to_le_bytes());
+        ntb[40..42].copy_from_slice(&16u16.to_le_bytes());
+        ntb[44..48].copy_from_slice(&[60, 0, 4, 0]);
+        ntb[52..64].copy_from_slice(&[1, 2, 0, 0, 3, 4, 5, 0, 6, 7, 8, 9]);
+
+        let d = datagrams(&ntb).unwrap();
+        assert_eq!(d.as_slice(), &[&[1, 2][..], &[3, 4, 5][..], &[6, 7, 8, 9][..]]);
+    }
+
+    #[test]
+    fn parse_malformed() {
+        let mut buf = [0u8; 64];
+        let n = write_ntb16(&mut buf, 0, &[1, 2, 3], &NtbParameters::default()).unwrap();
+
+        let mut bad = buf;
+        bad[0] = 0;
+        assert_eq!(parse_nth16(&bad[..n]), Err(NtbError::Signature));
+
+        // Block length beyond the received data.
+        assert_eq!(parse_nth16(&buf[..n - 1]), Err(NtbError::OutOfBounds));
+
+        // Datagram pointer past the end of the block.
+        let mut bad = buf;
+        bad[22] = 0xFF;
+        assert_eq!(datagrams(&bad[..n]), Err(NtbError::OutOfBounds));
+
+        // NDP chaining back to itself.
+        let mut bad = buf;
+        bad[18] = 12;
+        bad[20..24].fill(0);
+        assert_eq!(datagrams(&bad[..n]), Err(NtbError::OutOfBounds));
+    }
+
+    #[test]
+    fn parameters() {
+        let mut buf = [0u8; NTB_PARAMETERS_LEN];
+        buf[0] = 28;
+        buf[2] = 1;
+        buf[4..8].copy_from_slice(&16384u32.to_le_bytes());
+        buf[16..20].copy_from_slice(&8192u32.to_le_bytes());
+        buf[20] = 4;
+        buf[24] = 4;
+        buf[26] = 32;
+        let p = NtbParameters::parse(&buf).unwrap();
+        assert_eq!((p.ntb_in_max_size, p.ntb_out_max_size), (16384, 8192));
+        assert_eq!(
+            (p.ndp_out_divisor, p.ndp_out_alignment, p.ntb_out_max_datagrams),
+            (4, 4, 32)
+        );
+        assert_eq!(NtbParameters::parse(&buf[..27]), None);
+    }
+}
@@ -0,0 +1,549 @@
+//! USB DFU host class driver.
+//!
+//! Flashes firmware into devices implementing USB Device Firmware Upgrade 1.1,
+//! such as bootloaders built with `embassy-usb-dfu`.
+//!
+//! A device in runtime mode exposes a DFU interface next to its normal
+//! functions. [`DfuHost::detach`] asks it to switch to DFU mode, after which it
+//! re-enumerates with a DFU-only configuration. [`DfuHost::download`] then
+//! transfers the image and drives the device through manifestation.
+
+use embassy_time::Timer;
+use embassy_usb::class::dfu::consts::{
+    APPN_SPEC_SUBCLASS_DFU, DESC_DFU_FUNCTIONAL, DFU_PROTOCOL_RT, Request, USB_CLASS_APPN_SPEC,
+};
+pub use embassy_usb::class::dfu::consts::{DfuAttributes, State, Status};
+use embassy_usb_driver::host::{PipeError, UsbHostAllocator, UsbPipe, pipe};
+use embassy_usb_driver::{Direction as UsbDirection, EndpointAddress, EndpointInfo, EndpointType};
+
+use crate::control::SetupPacket;
+use crate::descriptor::ConfigurationDescriptorChain;
+use crate::handler::EnumerationInfo;
+
+/// Number of DFU_GETSTATUS/DFU_ABORT rounds [`DfuHost::recover`] tries before giving up.
+const RECOVER_ATTEMPTS: usize = 8;
+
+/// Mode the DFU interface is operating in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DfuMode {
+    /// The device runs its application and only accepts DFU_DETACH.
+    Runtime,
+    /// The device runs its bootloader and accepts firmware downloads.
+    Dfu,
+}
+
+/// Information about a DFU interface found in a configuration descriptor.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DfuInfo {
+    /// DFU interface number.
+    pub interface: u8,
+    /// Alternate setting of the DFU interface.
+    pub alternate_setting: u8,
+    /// Runtime or DFU mode.
+    pub mode: DfuMode,
+    /// Capabilities from the DFU functional descriptor.
+    pub attributes: DfuAttributes,
+    /// Time the device waits for a USB reset after DFU_DETACH, in milliseconds.
+    pub detach_timeout_ms: u16,
+    /// Maximum number of bytes per DFU_DNLOAD or DFU_UPLOAD request.
+    pub transfer_size: u16,
+    /// DFU specification release in BCD, e.g. `0x0110` for DFU 1.1.
+    pub dfu_version: u16,
+}
+
+/// Find the first DFU interface in a configuration descriptor.
+///
+/// Interfaces without a DFU functional descriptor are skipped. DFU 1.0
+/// functional descriptors, which lack `bcdDFUVersion`, report version 1.0.
+pub fn find_dfu(config_desc: &[u8]) -> Option<DfuInfo> {
+    let cfg = ConfigurationDescriptorChain::try_from_slice(config_desc).ok()?;
+
+    for iface in cfg.iter_interface() {
+        if iface.interface_class != USB_CLASS_APPN_SPEC || iface.interface_subclass != APPN_SPEC_SUBCLASS_DFU {
+            continue;
+        }
+
+        // Layout: bLength, bDescriptorType(0x21), bmAttributes, wDetachTimeOut(2),
+        //         wTransferSize(2), bcdDFUVersion(2)
+        let Some(func) = iface
+            .iter_descriptors()
+            .map(|(_, d)| d)
+            .find(|d| d.len() >= 7 && d[1] == DESC_DFU_FUNCTIONAL)
+        else {
+            continue;
+        };
+
+        return Some(DfuInfo {
+            interface: iface.interface_number,
+            alternate_setting: iface.alternate_setting,
+            mode: if iface.interface_protocol == DFU_PROTOCOL_RT {
+                DfuMode::Runtime
+            } else {
+                DfuMode::Dfu
+            },
+            attributes: DfuAttributes::from_bits_truncate(func[2]),
+            detach_timeout_ms: u16::from_le_bytes([func[3], func[4]]),
+            transfer_size: u16::from_le_bytes([func[5], func[6]]),
+            dfu_version: match func.get(7..9) {
+                Some(v) => u16::from_le_bytes([v[0], v[1]]),
+                None => 0x0100,
+            },
+        });
+    }
+
+    None
+}
+
+/// DFU host class driver error.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DfuError {
+    /// Transfer error.
+    Transfer(PipeError),
+    /// No DFU interface found in the device.
+    NoInterface,
+    /// Failed to allocate a pipe.
+    NoPipe,
+    /// The operation is not supported by the device or its current mode.
+    NotSupported,
+    /// The device reported an error. The error status has been cleared.
+    Device {
+        /// Status reported by the device.
+        status: Status,
+        /// State the device was in.
+        state: State,
+    },
+    /// The device entered a state that does not fit the current operation.
+    UnexpectedState(State),
+    /// The device sent a malformed response.
+    InvalidResponse,
+    /// Reading the firmware image failed.
+    Read(embedded_io_async::ErrorKind),
+}
+
+impl From<PipeError> for DfuError {
+    fn from(e: PipeError) -> Self {
+        Self::Transfer(e)
+    }
+}
+
+impl core::fmt::Display for DfuError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Transfer(_e) => write!(f, "Transfer error"),
+            Self::NoInterface => write!(f, "No DFU interface found"),
+            Self::NoPipe => write!(f, "No free pipe"),
+            Self::NotSupported => write!(f, "Operation not supported"),
+            Self::Device { status, state } => write!(f, "Device error {:?} in state {:?}", status, state),
+            Self::UnexpectedState(state) => write!(f, "Unexpected state {:?}", state),
+            Self::InvalidResponse => write!(f, "Invalid response"),
+            Self::Read(kind) => write!(f, "Image read error: {:?}", kind),
+        }
+    }
+}
+
+impl core::error::Error for DfuError {}
+
+/// Response to DFU_GETSTATUS.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DfuStatus {
+    /// Result of the most recent request.
+    pub status: Status,
+    /// Minimum time to wait before the next DFU_GETSTATUS, in milliseconds.
+    pub poll_timeout_ms: u32,
+    /// State the device enters right after this response.
+    pub state: State,
+    /// Index of a string descriptor describing the status.
+    pub string_index: u8,
+}
+
+impl DfuStatus {
+    /// Parse a 6-byte DFU_GETSTATUS response.
+    pub fn parse(buf: &[u8]) -> Result<Self, DfuError> {
+        let buf: &[u8; 6] = buf
+            .get(..6)
+            .and_then(|b| b.try_into().ok())
+            .ok_or(DfuError::InvalidResponse)?;
+        Ok(Self {
+            status: Status::try_from(buf[0]).map_err(|_| DfuError::InvalidResponse)?,
+            poll_timeout_ms: u32::from_le_bytes([buf[1], buf[2], buf[3], 0]),
+            state: State::try_from(buf[4]).map_err(|_| DfuError::InvalidResponse)?,
+            string_index: buf[5],
+        })
+    }
+}
+
+/// What the host has to do to finish a detach or a download.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ResetAction {
+    /// Nothing, the device remains in dfuIDLE.
+    None,
+    /// The host must reset the bus. After [`DfuHost::detach`] the reset must
+    /// happen within [`DfuInfo::detach_timeout_ms`].
+    BusReset,
+    /// The device detaches from the bus by itself and re-enumerates.
+    Detached,
+}
+
+/// DFU host driver.
+///
+/// Drives a device through the DFU 1.1 detach, download and manifestation phases.
+pub struct DfuHost<'d, A: UsbHostAllocator<'d>> {
+    ctrl_ch: A::Pipe<pipe::Control, pipe::InOut>,
+    info: DfuInfo,
+    _phantom: core::marker::PhantomData<&'d ()>,
+}
+
+impl<'d, A: UsbHostAllocator<'d>> DfuHost<'d, A> {
+    /// Create a new DFU host driver.
+    ///
+    /// Parses the config descriptor to find the DFU interface and allocates the control channel.
+    /// Call [`enable`](Self::enable) before use.
+    pub fn new(alloc: &A, config_desc: &[u8], enum_info: &EnumerationInfo) -> Result<Self, DfuError> {
+        let info = find_dfu(config_desc).ok_or(DfuError::NoInterface)?;
+
+        let ctrl_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts(0, UsbDirection::In),
+            ep_type: EndpointType::Control,
+            max_packet_size: enum_info.device_desc.max_packet_size0 as u16,
+            interval_ms: 0,
+        };
+
+        let ctrl_ch = alloc
+            .alloc_pipe::<pipe::Control, pipe::InOut>(enum_info.device_address, &ctrl_ep_info, enum_info.split())
+            .map_err(|_| DfuError::NoPipe)?;
+
+        Ok(Self {
+            ctrl_ch,
+            info,
+            _phantom: core::marker::PhantomData,
+        })
+    }
+
+    /// Select the alternate setting of the DFU interface.
+    pub async fn enable(&mut self) -> Result<(), DfuError> {
+        if self.info.alternate_setting != 0 {
+            let setup = SetupPacket::set_interface(self.info.interface as u16, self.info.alternate_setting);
+            self.ctrl_ch.control_out(&setup.to_bytes(), &[]).await?;
+        }
+        Ok(())
+    }
+
+    /// Interface and functional descriptor information.
+    pub fn info(&self) -> &DfuInfo {
+        &self.info
+    }
+
+    async fn request_out(&mut self, request: Request, value: u16, data: &[u8]) -> Result<(), DfuError> {
+        let setup =
+            SetupPacket::class_interface_out(request as u8, value, self.info.interface as u16, data.len() as u16);
+        self.ctrl_ch.control_out(&setup.to_bytes(), data).await?;
+        Ok(())
+    }
+
+    async fn request_in(&mut self, request: Request, buf: &mut [u8]) -> Result<usize, DfuError> {
+        let setup = SetupPacket::class_interface_in(request as u8, 0, self.info.interface as u16, buf.len() as u16);
+        Ok(self.ctrl_ch.control_in(&setup.to_bytes(), buf).await?)
+    }
+
+    /// Read the device status with DFU_GETSTATUS.
+    ///
+    /// This may advance the device state, e.g. from dfuDNLOAD-SYNC to dfuDNLOAD-IDLE.
+    pub async fn get_status(&mut self) -> Result<DfuStatus, DfuError> {
+        let mut buf = [0u8; 6];
+        let n = self.request_in(Request::GetStatus, &mut buf).await?;
+        DfuStatus::parse(&buf[..n])
+    }
+
+    /// Read the device state with DFU_GETSTATE.
+    pub async fn get_state(&mut self) -> Result<State, DfuError> {
+        let mut buf = [0u8; 1];
+        let n = self.request_in(Request::GetState, &mut buf).await?;
+        if n != 1 {
+            return Err(DfuError::InvalidResponse);
+        }
+        State::try_from(buf[0]).map_err(|_| DfuError::InvalidResponse)
+    }
+
+    /// Clear an error status with DFU_CLRSTATUS, returning the device to dfuIDLE.
+    pub async fn clear_status(&mut self) -> Result<(), DfuError> {
+        self.request_out(Request::ClrStatus, 0, &[]).await
+    }
+
+    /// Abort the current operation with DFU_ABORT, returning the device to dfuIDLE.
+    pub async fn abort(&mut self) -> Result<(), DfuError> {
+        self.request_out(Request::Abort, 0, &[]).await
+    }
+
+    /// Ask a device in runtime mode to switch to DFU mode.
+    ///
+    /// Returns what the host has to do for the switch to happen. The device
+    /// then re-enumerates with its DFU configuration, for which a new
+    /// `DfuHost` must be created. Devices already in DFU mode are left alone.
+    pub async fn detach(&mut self) -> Result<ResetAction, DfuError> {
+        if self.info.mode == DfuMode::Dfu {
+            return Ok(ResetAction::None);
+        }
+        let timeout = self.info.detach_timeout_ms;
+        self.request_out(Request::Detach, timeout, &[]).await?;
+        Ok(if self.info.attributes.contains(DfuAttributes::WILL_DETACH) {
+            ResetAction::Detached
+        } else {
+            ResetAction::BusReset
+        })
+    }
+
+    /// Bring the device back to dfuIDLE from whatever state a previous,
+    /// possibly interrupted, operation left it in.
+    pub async fn recover(&mut self) -> Result<(), DfuError> {
+        if self.info.mode == DfuMode::Runtime {
+            return Err(DfuError::NotSupported);
+        }
+        for _ in 0..RECOVER_ATTEMPTS {
+            let st = self.get_status().await?;
+            match st.state {
+                State::DfuIdle => return Ok(()),
+                State::Error => self.clear_status().await?,
+                State::DlBusy | State::Manifest => Timer::after_millis(st.poll_timeout_ms as u64).await,
+                State::DlSync | State::Download | State::ManifestSync | State::UploadIdle => self.abort().await?,
+                state => return Err(DfuError::UnexpectedState(state)),
+            }
+        }
+        Err(DfuError::UnexpectedState(self.get_state().await?))
+    }
+
+    /// Turn a failed request into the device error that caused it, clearing
+    /// the error so the device is ready for a new attempt.
+    async fn device_error(&mut self, err: DfuError) -> DfuError {
+        if let DfuError::Transfer(PipeError::Stall) = err
+            && let Ok(st) = self.get_status().await
+            && st.state == State::Error
+        {
+            let _ = self.clear_status().await;
+            return DfuError::Device {
+                status: st.status,
+                state: st.state,
+            };
+        }
+        err
+    }
+
+    /// Poll DFU_GETSTATUS until the device leaves the states in `busy`.
+    ///
+    /// An error status is cleared and returned as [`DfuError::Device`].
+    async fn poll_status(&mut self, busy: &[State]) -> Result<DfuStatus, DfuError> {
+        loop {
+            let st = self.get_status().await?;
+            if st.status != Status::Ok || st.state == State::Error {
+                let _ = self.clear_status().await;
+                return Err(DfuError::Device {
+                    status: st.status,
+                    state: st.state,
+                });
+            }
+            if !busy.contains(&st.state) {
+                return Ok(st);
+            }
+            Timer::after_millis(st.poll_timeout_ms as u64).await;
+        }
+    }
+
+    async fn download_block(&mut self, block_num: u16, data: &[u8]) -> Result<(), DfuError> {
+        if let Err(e) = self.request_out(Request::Dnload, block_num, data).await {
+            return Err(self.device_error(e).await);
+        }
+        match self.poll_status(&[State::DlSync, State::DlBusy]).await?.state {
+            State::Download => Ok(()),
+            state => Err(DfuError::UnexpectedState(state)),
+        }
+    }
+
+    async fn manifest(&mut self, block_num: u16) -> Result<ResetAction, DfuError> {
+        if let Err(e) = self.request_out(Request::Dnload, block_num, &[]).await {
+            return Err(self.device_error(e).await);
+        }
+
+        let tolerant = self.info.attributes.contains(DfuAttributes::MANIFESTATION_TOLERANT);
+        let will_detach = self.info.attributes.contains(DfuAttributes::WILL_DETACH);
+        let busy: &[State] = if tolerant {
+            &[State::ManifestSync, State::Manifest]
+        } else {
+            &[State::ManifestSync]
+        };
+        match self.poll_status(busy).await {
+            Ok(st) => match st.state {
+                State::DfuIdle => Ok(ResetAction::None),
+                State::Manifest | State::ManifestWaitReset if will_detach => Ok(ResetAction::Detached),
+                State::Manifest | State::ManifestWaitReset => Ok(ResetAction::BusReset),
+                state => Err(DfuError::UnexpectedState(state)),
+            },
+            // A device that detaches by itself may drop off the bus before answering.
+            Err(DfuError::Transfer(PipeError::Disconnected | PipeError::Timeout)) if will_detach && !tolerant => {
+                Ok(ResetAction::Detached)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Download a firmware image to a device in DFU mode.
+    ///
+    /// The device is first brought back to dfuIDLE with [`recover`](Self::recover).
+    /// `progress` is called with the number of bytes transferred after each
+    /// block. Returns what the host has to do for the device to run the new
+    /// firmware.
+    pub async fn download(&mut self, image: &[u8], progress: impl FnMut(usize)) -> Result<ResetAction, DfuError> {
+        self.download_inner(Source::<&[u8]>::Slice(image), progress).await
+    }
+
+    /// Download a firmware image read from `reader` to a device in DFU mode.
+    ///
+    /// Blocks are assembled in `buf`, which limits the block size to
+    /// `buf.len()` when it is smaller than the device's transfer size.
+    /// See [`download`](Self::download).
+    pub async fn download_from<R: embedded_io_async::Read>(
+        &mut self,
+        reader: &mut R,
+        buf: &mut [u8],
+        progress: impl FnMut(usize),
+    ) -> Result<ResetAction, DfuError> {
+        self.download_inner(Source::Reader(reader, buf), progress).await
+    }
+
+    async fn download_inner<R: embedded_io_async::Read>(
+        &mut self,
+        mut source: Source<'_, R>,
+        mut progress: impl FnMut(usize),
+    ) -> Result<ResetAction, DfuError> {
+        if self.info.mode == DfuMode::Runtime || !self.info.attributes.contains(DfuAttributes::CAN_DOWNLOAD) {
+            return Err(DfuError::NotSupported);
+        }
+        let block_size = match &source {
+            Source::Slice(_) => self.info.transfer_size as usize,
+            Source::Reader(_, buf) => buf.len().min(self.info.transfer_size as usize),
+        };
+        if block_size == 0 {
+            return Err(DfuError::NotSupported);
+        }
+
+        self.recover().await?;
+
+        let mut block_num: u16 = 0;
+        let mut sent = 0;
+        loop {
+            let block = source.next_block(sent, block_size).await?;
+            if block.is_empty() {
+                break;
+            }
+            let len = block.len();
+            self.download_block(block_num, block).await?;
+            block_num = block_num.wrapping_add(1);
+            sent += len;
+            progress(sent);
+            if len < block_size {
+                break;
+            }
+        }
+
+        self.manifest(block_num).await
+    }
+}
+
+/// Where [`DfuHost::download_inner`] takes the image from.
+enum Source<'a, R> {
+    Slice(&'a [u8]),
+    Reader(&'a mut R, &'a mut [u8]),
+}
+
+impl<R: embedded_io_async::Read> Source<'_, R> {
+    /// The block starting at `offset`, shorter than `block_size` only at the end of the image.
+    async fn next_block(&mut self, offset: usize, block_size: usize) -> Result<&[u8], DfuError> {
+        match self {
+            Self::Slice(image) => Ok(&image[offset..(offset + block_size).min(image.len())]),
+            Self::Reader(reader, buf) => {
+                let mut n = 0;
+                while n < block_size {
+                    match reader.read(&mut buf[n..block_size]).await {
+                        Ok(0) => break,
+                        Ok(len) => n += len,
+                        Err(e) => return Err(DfuError::Read(embedded_io_async::Error::kind(&e))),
+                    }
+                }
+                Ok(&buf[..n])
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Composite device with a vendor interface and a DFU runtime interface.
+    #[rustfmt::skip]
+    const CFG_RUNTIME: [u8; 36] = [
+        9, 0x02, 36, 0, 2, 1, 0, 0x80, 50,
+        9, 0x04, 0, 0, 0, 0xFF, 0x00, 0x00, 0,
+        9, 0x04, 1, 0, 0, 0xFE, 0x01, 0x01, 0,
+        9, 0x21, 0x0B, 0xE8, 0x03, 0x00, 0x04, 0x10, 0x01,
+    ];
+
+    #[test]
+    fn find_dfu_runtime() {
+        let info = find_dfu(&CFG_RUNTIME).unwrap();
+        assert_eq!(info.interface, 1);
+        assert_eq!(info.alternate_setting, 0);
+        assert_eq!(info.mode, DfuMode::Runtime);
+        assert_eq!(
+            info.attributes,
+            DfuAttributes::WILL_DETACH | DfuAttributes::CAN_UPLOAD | DfuAttributes::CAN_DOWNLOAD
+        );
+        assert_eq!(info.detach_timeout_ms, 1000);
+        assert_eq!(info.transfer_size, 1024);
+        assert_eq!(info.dfu_version, 0x0110);
+    }
+
+    #[test]
+    fn find_dfu_mode() {
+        // DFU 1.0 functional descriptor without bcdDFUVersion, DFU mode protocol.
+        let mut cfg = [0u8; 25];
+        cfg[..9].copy_from_slice(&CFG_RUNTIME[..9]);
+        cfg[2] = 25;
+        cfg[4] = 1;
+        cfg[9..18].copy_from_slice(&[9, 0x04, 0, 0, 0, 0xFE, 0x01, 0x02, 0]);
+        cfg[18..25].copy_from_slice(&[7, 0x21, 0x05, 0xFF, 0x00, 0x40, 0x00]);
+        let info = find_dfu(&cfg).unwrap();
+        assert_eq!(info.mode, DfuMode::Dfu);
+        assert!(info.attributes.contains(DfuAttributes::MANIFESTATION_TOLERANT));
+        assert_eq!(info.transfer_size, 64);
+        assert_eq!(info.dfu_version, 0x0100);
+
+        // No functional descriptor.
+        let mut short = [0u8; 18];
+        short.copy_from_slice(&cfg[..18]);
+        short[2] = 18;
+        assert!(find_dfu(&short).is_none());
+    }
+
+    #[test]
+    fn parse_status() {
+        let st = DfuStatus::parse(&[0x00, 0x32, 0x01, 0x00, 0x05, 0x00]).unwrap();
+        assert_eq!(st.status, Status::Ok);
+        assert_eq!(st.poll_timeout_ms, 0x132);
+        assert_eq!(st.state, State::Download);
+
+        let st = DfuStatus::parse(&[0x0B, 0, 0, 0, 0x0A, 0x04]).unwrap();
+        assert_eq!(st.status, Status::ErrVendor);
+        assert_eq!(st.state, State::Error);
+        assert_eq!(st.string_index, 4);
+
+        assert!(matches!(
+            DfuStatus::parse(&[0, 0, 0, 0, 0x0B, 0]),
+            Err(DfuError::InvalidResponse)
+        ));
+        assert!(matches!(DfuStatus::parse(&[0; 5]), Err(DfuError::InvalidResponse)));
+    }
+}
@@ -5,9 +5,10 @@
 use embassy_usb_driver::host::{PipeError, UsbHostAllocator, UsbPipe, pipe};
 use embassy_usb_driver::{Direction as UsbDirection, EndpointAddress, EndpointInfo, EndpointType};
 
+use super::hid_input::{self, InputEvent};
 pub use super::hid_report::{ReportDescriptor, ReportField};
 use crate::control::SetupPacket;
-use crate::descriptor::ConfigurationDescriptorChain;
+use crate::descriptor::{ConfigurationDescriptorChain, InterfaceDescriptorChain};
 use crate::handler::EnumerationInfo;
 
 /// HID class code.
@@ -163,40 +164,57 @@ pub struct HidInfo {
 /// Find the first HID interface in a configuration descriptor.
 pub fn find_hid(config_desc: &[u8]) -> Option<HidInfo> {
     let cfg = ConfigurationDescriptorChain::try_from_slice(config_desc).ok()?;
+    cfg.iter_interface().find_map(|iface| parse_hid_interface(&iface))
+}
 
-    for iface in cfg.iter_interface() {
-        if iface.interface_class != USB_CLASS_HID {
-            continue;
+/// Find all HID interfaces in a configuration descriptor, in descriptor order.
+///
+/// Composite devices often expose several HID interfaces, e.g. a keyboard
+/// with a separate interface for media keys, or a wireless receiver with one
+/// interface per paired device. Create one [`HidHost`] per interface with
+/// [`HidHost::new_with_info`]. At most `M` interfaces are returned.
+pub fn find_hid_interfaces<const M: usize>(config_desc: &[u8]) -> heapless::Vec<HidInfo, M> {
+    let mut found = heapless::Vec::new();
+    if let Ok(cfg) = ConfigurationDescriptorChain::try_from_slice(config_desc) {
+        for info in cfg.iter_interface().filter_map(|iface| parse_hid_interface(&iface)) {
+            if found.push(info).is_err() {
+                break;
+            }
         }
+    }
+    found
+}
 
-        // Extract report descriptor length from the HID class descriptor (type 0x21).
-        // Layout: bLength, bDescriptorType(0x21), bcdHID(2), bCountryCode,
-        //         bNumDescriptors, bDescriptorType(0x22), wDescriptorLength(2)
-        let report_desc_len = iface
-            .iter_descriptors()
-            .find_map(|(_, data)| {
-                if data.len() >= 7 && data[1] == DESC_HID {
-                    Some(u16::from_le_bytes([data[5], data[6]]))
-                } else {
-                    None
-                }
-            })
-            .unwrap_or(0);
-
-        let ep = iface
-            .iter_endpoints()
-            .find(|ep| ep.transfer_type() == TRANSFER_INTERRUPT && ep.is_in())?;
-
-        return Some(HidInfo {
-            interface_number: iface.interface_number,
-            interrupt_in_ep: ep.endpoint_address,
-            interrupt_in_mps: ep.max_packet_size,
-            interrupt_in_interval: ep.interval,
-            report_descriptor_len: report_desc_len,
-        });
+fn parse_hid_interface(iface: &InterfaceDescriptorChain<'_>) -> Option<HidInfo> {
+    if iface.interface_class != USB_CLASS_HID {
+        return None;
     }
 
-    None
+    // Extract report descriptor length from the HID class descriptor (type 0x21).
+    // Layout: bLength, bDescriptorType(0x21), bcdHID(2), bCountryCode,
+    //         bNumDescriptors, bDescriptorType(0x22), wDescriptorLength(2)
+    let report_desc_len = iface
+        .iter_descriptors()
+        .find_map(|(_, data)| {
+            if data.len() >= 9 && data[1] == DESC_HID {
+                Some(u16::from_le_bytes([data[7], data[8]]))
+            } else {
+                None
+            }
+        })
+        .unwrap_or(0);
+
+    let ep = iface
+        .iter_endpoints()
+        .find(|ep| ep.transfer_type() == TRANSFER_INTERRUPT && ep.is_in())?;
+
+    Some(HidInfo {
+        interface_number: iface.interface_number,
+        interrupt_in_ep: ep.endpoint_address,
+        interrupt_in_mps: ep.max_packet_size,
+        interrupt_in_interval: ep.interval,
+        report_descriptor_len: report_desc_len,
+    })
 }
 
 /// HID host class driver error.
@@ -247,7 +265,14 @@ impl<'d, A: UsbHostAllocator<'d>> HidHost<'d, A> {
     /// then allocates the necessary channels.
     pub fn new(alloc: &A, config_desc: &[u8], enum_info: &EnumerationInfo) -> Result<Self, HidError> {
         let info = find_hid(config_desc).ok_or(HidError::NoInterface)?;
+        Self::new_with_info(alloc, &info, enum_info)
+    }
 
+    /// Create a HID host driver for a specific interface.
+    ///
+    /// Use this with [`find_hid_interfaces`] to drive each HID interface of a
+    /// composite device.
+    pub fn new_with_info(alloc: &A, info: &HidInfo, enum_info: &EnumerationInfo) -> Result<Self, HidError> {
         let ctrl_ep_info = EndpointInfo {
             addr: EndpointAddress::from_parts(0, UsbDirection::In),
             ep_type: EndpointType::Control,
@@ -327,6 +352,38 @@ impl<'d, A: UsbHostAllocator<'d>> HidHost<'d, A> {
         Ok(())
     }
 
+    /// Switch the device to report protocol and fetch and parse its report descriptor.
+    ///
+    /// `buf` receives the raw descriptor and should be at least
+    /// `HidInfo::report_descriptor_len` bytes. SET_PROTOCOL is only mandatory for
+    /// boot devices, so a STALL is treated as success: other devices always use
+    /// report protocol.
+    pub async fn enable_report_protocol<const N: usize>(
+        &mut self,
+        buf: &mut [u8],
+    ) -> Result<ReportDescriptor<N>, HidError> {
+        match self.set_protocol(PROTOCOL_REPORT).await {
+            Ok(()) | Err(HidError::Transfer(PipeError::Stall)) => {}
+            Err(e) => return Err(e),
+        }
+        let raw = self.fetch_report_descriptor(buf).await?;
+        Ok(ReportDescriptor::parse(raw))
+    }
+
+    /// Read an input report and decode it into a typed [`InputEvent`].
+    ///
+    /// `desc` is the descriptor returned by [`HidHost::enable_report_protocol`],
+    /// and `buf` must hold the largest input report. Returns `None` for reports
+    /// that are not keyboard, mouse, touch or gamepad input, see [`hid_input::decode`].
+    pub async fn read_event<const N: usize>(
+        &mut self,
+        desc: &ReportDescriptor<N>,
+        buf: &mut [u8],
+    ) -> Result<Option<InputEvent>, HidError> {
+        let n = self.in_ch.request_in(buf).await?;
+        Ok(hid_input::decode(desc, &buf[..n]))
+    }
+
     /// Read a raw input report from the interrupt IN endpoint.
     ///
     /// Returns the number of bytes received.
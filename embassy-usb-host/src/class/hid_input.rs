@@ -0,0 +1,674 @@
+//! Typed input events decoded from HID reports in report protocol.
+//!
+//! [`decode`] classifies each report by the Application collection its fields
+//! belong to and turns it into a keyboard, mouse, touch or gamepad event, using
+//! the layout from a parsed [`ReportDescriptor`]. This covers devices that the
+//! boot-protocol readers cannot: N-key rollover keyboards, high-resolution mice,
+//! multi-touch digitizers and gamepads, including devices that multiplex several
+//! of them over report IDs.
+//!
+//! # Usage
+//!
+//! ```ignore
+//! use embassy_usb_host::class::hid::HidHost;
+//! use embassy_usb_host::class::hid_input::InputEvent;
+//!
+//! let mut desc_buf = [0u8; 512];
+//! let desc = hid.enable_report_protocol::<64>(&mut desc_buf).await?;
+//!
+//! let mut prev_keys = Default::default();
+//! let mut buf = [0u8; 64];
+//! loop {
+//!     match hid.read_event(&desc, &mut buf).await? {
+//!         Some(InputEvent::Keyboard(keys)) => {
+//!             for ev in keys.changes(&prev_keys) {
+//!                 info!("key {} {}", ev.usage, if ev.pressed { "down" } else { "up" });
+//!             }
+//!             prev_keys = keys;
+//!         }
+//!         Some(InputEvent::Mouse(m)) => info!("mouse {} {} {}", m.x, m.y, m.wheel),
+//!         _ => {}
+//!     }
+//! }
+//! ```
+
+pub use embassy_usb::class::hid::gamepad::Hat;
+use heapless::Vec;
+
+use super::hid_report::{ReportDescriptor, ReportField, digitizer, usage, usage_page};
+
+/// Maximum number of touch contacts reported in a [`TouchState`].
+pub const MAX_CONTACTS: usize = 10;
+
+/// Keyboard usage reported in array slots when too many keys are pressed.
+const KEY_ERROR_ROLL_OVER: u16 = 0x01;
+/// First keyboard usage that is an actual key.
+const KEY_FIRST: u16 = 0x04;
+/// Consumer page usage: AC Pan (horizontal scroll).
+const CONSUMER_AC_PAN: u16 = 0x238;
+
+/// Kind of device an Application collection describes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum InputKind {
+    /// Keyboard or keypad.
+    Keyboard,
+    /// Mouse or other relative/absolute pointer.
+    Mouse,
+    /// Touch screen or touch pad.
+    Touch,
+    /// Gamepad, joystick or multi-axis controller.
+    Gamepad,
+}
+
+impl InputKind {
+    /// Classify an Application collection by its usage.
+    pub fn from_application(page: u16, app_usage: u16) -> Option<Self> {
+        match (page, app_usage) {
+            (usage_page::GENERIC_DESKTOP, usage::KEYBOARD | usage::KEYPAD) => Some(Self::Keyboard),
+            (usage_page::GENERIC_DESKTOP, usage::MOUSE | usage::POINTER) => Some(Self::Mouse),
+            (usage_page::GENERIC_DESKTOP, usage::JOYSTICK | usage::GAMEPAD | usage::MULTI_AXIS) => Some(Self::Gamepad),
+            (usage_page::DIGITIZER, digitizer::TOUCH_SCREEN | digitizer::TOUCH_PAD) => Some(Self::Touch),
+            _ => None,
+        }
+    }
+}
+
+/// Pressed keys of a keyboard, as a bitmap over keyboard usages `0x00..=0xFF`.
+///
+/// Modifiers are usages `0xE0..=0xE7` and are included in the bitmap.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeyboardState {
+    keys: [u32; 8],
+    rollover: bool,
+}
+
+/// A key press or release, see [`KeyboardState::changes`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeyEvent {
+    /// Keyboard usage (HID usage page 0x07).
+    pub usage: u8,
+    /// `true` when pressed, `false` when released.
+    pub pressed: bool,
+}
+
+impl KeyboardState {
+    fn set(&mut self, usage: u8) {
+        self.keys[usage as usize / 32] |= 1 << (usage % 32);
+    }
+
+    /// Returns `true` if the key with the given usage is pressed.
+    pub fn is_pressed(&self, usage: u8) -> bool {
+        self.keys[usage as usize / 32] & (1 << (usage % 32)) != 0
+    }
+
+    /// Iterate over the usages of all pressed keys, in ascending order.
+    pub fn pressed(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..=255u8).filter(|&u| self.is_pressed(u))
+    }
+
+    /// Modifier bitmask in boot-protocol layout: bit 0 = Left Ctrl … bit 7 = Right GUI.
+    pub fn modifiers(&self) -> u8 {
+        self.keys[7] as u8
+    }
+
+    /// Returns `true` if the keyboard reported a rollover error, i.e. too many
+    /// keys are pressed. The key bitmap is then empty and should be ignored.
+    pub fn is_rollover(&self) -> bool {
+        self.rollover
+    }
+
+    /// Keys pressed or released since `previous`.
+    ///
+    /// A rollover report produces no changes, so keys are not spuriously released.
+    pub fn changes<'a>(&'a self, previous: &'a KeyboardState) -> impl Iterator<Item = KeyEvent> + 'a {
+        let skip = self.rollover || previous.rollover;
+        (0..=255u8).filter_map(move |u| {
+            let (now, before) = (self.is_pressed(u), previous.is_pressed(u));
+            (!skip && now != before).then_some(KeyEvent { usage: u, pressed: now })
+        })
+    }
+}
+
+/// State of a mouse or pointer.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MouseState {
+    /// Pressed buttons, bit 0 = button 1 (primary).
+    pub buttons: u32,
+    /// Horizontal motion, or position when `absolute`.
+    pub x: i32,
+    /// Vertical motion, or position when `absolute`.
+    pub y: i32,
+    /// Vertical wheel motion.
+    pub wheel: i32,
+    /// Horizontal wheel (AC Pan) motion.
+    pub pan: i32,
+    /// `x` and `y` are absolute positions rather than motion, e.g. for tablets.
+    pub absolute: bool,
+}
+
+/// A single touch contact.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Contact {
+    /// Contact identifier, stable while the contact touches the surface.
+    pub id: u16,
+    /// The contact touches the surface. A contact that was touching and
+    /// reports `false` has been lifted.
+    pub touching: bool,
+    /// Horizontal position in logical units.
+    pub x: u16,
+    /// Vertical position in logical units.
+    pub y: u16,
+    /// Pressure, when reported by the digitizer.
+    pub pressure: Option<u16>,
+}
+
+/// State of a touch screen or touch pad.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TouchState {
+    /// Contacts in this report.
+    ///
+    /// When the digitizer reports a contact count, unused contact slots are
+    /// dropped. Devices in hybrid mode spread more contacts over several
+    /// reports; only the first one then carries a non-zero count.
+    pub contacts: Vec<Contact, MAX_CONTACTS>,
+    /// Number of contacts in the frame, when reported.
+    pub contact_count: Option<u8>,
+}
+
+/// State of a gamepad or joystick.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct GamepadState {
+    /// Pressed buttons, bit 0 = button 1.
+    pub buttons: u32,
+    /// Axes in logical units, indexed from X: X, Y, Z, Rx, Ry, Rz, Slider, Dial.
+    pub axes: [i32; 8],
+    /// Hat switch position.
+    pub hat: Hat,
+}
+
+impl GamepadState {
+    /// Value of a Generic Desktop axis such as [`usage::X`] or [`usage::SLIDER`].
+    pub fn axis(&self, axis_usage: u16) -> Option<i32> {
+        let index = axis_usage.checked_sub(usage::X)? as usize;
+        self.axes.get(index).copied()
+    }
+}
+
+/// An input event decoded from a report.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum InputEvent {
+    /// Keyboard state.
+    Keyboard(KeyboardState),
+    /// Mouse state.
+    Mouse(MouseState),
+    /// Touch contacts.
+    Touch(TouchState),
+    /// Gamepad state.
+    Gamepad(GamepadState),
+}
+
+/// Decode a complete input report into a typed event.
+///
+/// `report` includes the leading report-ID byte when the descriptor uses
+/// report IDs. Returns `None` for reports that do not belong to a keyboard,
+/// mouse, touch or gamepad collection, such as consumer controls or vendor
+/// reports, and for reports too short for their layout.
+pub fn decode<const N: usize>(desc: &ReportDescriptor<N>, report: &[u8]) -> Option<InputEvent> {
+    let (report_id, payload) = if desc.has_report_ids {
+        (*report.first()?, &report[1..])
+    } else {
+        (0, report)
+    };
+    let kind = report_kind(desc, report_id)?;
+    let fields = || {
+        desc.fields()
+            .filter(move |f| f.report_id == report_id && !f.is_constant())
+    };
+
+    // Reject reports shorter than the layout, instead of decoding missing fields as zero.
+    let bits = fields()
+        .map(|f| f.bit_offset.saturating_add(f.bit_size as u32 * f.count as u32))
+        .max()
+        .unwrap_or(0);
+    if (payload.len() as u32) * 8 < bits {
+        return None;
+    }
+
+    Some(match kind {
+        InputKind::Keyboard => InputEvent::Keyboard(decode_keyboard(fields(), payload)),
+        InputKind::Mouse => InputEvent::Mouse(decode_mouse(fields(), payload)),
+        InputKind::Touch => InputEvent::Touch(decode_touch(fields(), payload)),
+        InputKind::Gamepad => InputEvent::Gamepad(decode_gamepad(fields(), payload)),
+    })
+}
+
+/// Kind of the Application collection that report `report_id` belongs to.
+pub fn report_kind<const N: usize>(desc: &ReportDescriptor<N>, report_id: u8) -> Option<InputKind> {
+    desc.fields()
+        .filter(|f| f.report_id == report_id)
+        .find_map(|f| InputKind::from_application(f.application_page, f.application_usage))
+}
+
+/// Active `(usage, value)` pairs of a field.
+///
+/// Variable fields yield every element with its usage. Array fields yield the
+/// usage selected by each element with a value of 1, skipping empty slots.
+fn elements<'a>(f: &'a ReportField, payload: &'a [u8]) -> impl Iterator<Item = (u16, i32)> + 'a {
+    (0..f.count as usize).filter_map(move |i| {
+        let value = f.extract_i32(payload, i)?;
+        if f.is_variable() {
+            let u = f.usage_min.saturating_add(i as u16).min(f.usage_max.max(f.usage_min));
+            Some((u, value))
+        } else if value >= f.logical_min && value <= f.logical_max {
+            let index = u16::try_from(value as i64 - f.logical_min as i64).ok()?;
+            Some((f.usage_min.checked_add(index)?, 1))
+        } else {
+            None
+        }
+    })
+}
+
+fn decode_keyboard<'a>(fields: impl Iterator<Item = &'a ReportField>, payload: &[u8]) -> KeyboardState {
+    let mut state = KeyboardState::default();
+    for f in fields.filter(|f| f.usage_page == usage_page::KEYBOARD) {
+        for (u, value) in elements(f, payload) {
+            match u {
+                _ if value == 0 => {}
+                KEY_ERROR_ROLL_OVER => state.rollover = true,
+                KEY_FIRST..=0xFF => state.set(u as u8),
+                _ => {}
+            }
+        }
+    }
+    if state.rollover {
+        state.keys = [0; 8];
+    }
+    state
+}
+
+fn set_button(buttons: &mut u32, u: u16, value: i32) {
+    if value != 0 && (1..=32).contains(&u) {
+        *buttons |= 1 << (u - 1);
+    }
+}
+
+fn decode_mouse<'a>(fields: impl Iterator<Item = &'a ReportField>, payload: &[u8]) -> MouseState {
+    let mut state = MouseState::default();
+    for f in fields {
+        for (u, value) in elements(f, payload) {
+            match (f.usage_page, u) {
+                (usage_page::BUTTON, _) => set_button(&mut state.buttons, u, value),
+                (usage_page::GENERIC_DESKTOP, usage::X) => {
+                    state.x = value;
+                    state.absolute = !f.is_relative();
+                }
+                (usage_page::GENERIC_DESKTOP, usage::Y) => state.y = value,
+                (usage_page::GENERIC_DESKTOP, usage::WHEEL) => state.wheel = value,
+                (usage_page::CONSUMER, CONSUMER_AC_PAN) => state.pan = value,
+                _ => {}
+            }
+        }
+    }
+    state
+}
+
+fn decode_touch<'a>(fields: impl Iterator<Item = &'a ReportField>, payload: &[u8]) -> TouchState {
+    // Contacts are told apart by the collection their fields are in.
+    let mut slots: Vec<(u16, Contact), MAX_CONTACTS> = Vec::new();
+    let mut contact_count = None;
+    for f in fields {
+        for (u, value) in elements(f, payload) {
+            let field = match (f.usage_page, u) {
+                (usage_page::DIGITIZER, digitizer::CONTACT_COUNT) => {
+                    contact_count = Some(value.clamp(0, u8::MAX as i32) as u8);
+                    continue;
+                }
+                (usage_page::DIGITIZER, digitizer::TIP_SWITCH | digitizer::CONTACT_ID | digitizer::TIP_PRESSURE)
+                | (usage_page::GENERIC_DESKTOP, usage::X | usage::Y) => u,
+                _ => continue,
+            };
+            let slot = match slots.iter().position(|(c, _)| *c == f.collection) {
+                Some(i) => i,
+                None => {
+                    if slots.push((f.collection, Contact::default())).is_err() {
+                        continue;
+                    }
+                    slots.len() - 1
+                }
+            };
+            let contact = &mut slots[slot].1;
+            match (f.usage_page, field) {
+                (usage_page::DIGITIZER, digitizer::TIP_SWITCH) => contact.touching = value != 0,
+                (usage_page::DIGITIZER, digitizer::CONTACT_ID) => contact.id = value as u16,
+                (usage_page::DIGITIZER, _) => contact.pressure = Some(value as u16),
+                (_, usage::X) => contact.x = value as u16,
+                _ => contact.y = value as u16,
+            }
+        }
+    }
+
+    let mut contacts: Vec<Contact, MAX_CONTACTS> = slots.into_iter().map(|(_, c)| c).collect();
+    if let Some(n) = contact_count.filter(|&n| n > 0) {
+        contacts.truncate(n as usize);
+    }
+    TouchState {
+        contacts,
+        contact_count,
+    }
+}
+
+fn hat_from(f: &ReportField, value: i32) -> Hat {
+    // Widened, as a descriptor may declare the full i32 range.
+    let (value, min, max) = (value as i64, f.logical_min as i64, f.logical_max as i64);
+    let positions = max - min + 1;
+    if positions <= 0 || value < min || value > max {
+        return Hat::Centered;
+    }
+    match (value - min) * 8 / positions {
+        0 => Hat::Up,
+        1 => Hat::UpRight,
+        2 => Hat::Right,
+        3 => Hat::DownRight,
+        4 => Hat::Down,
+        5 => Hat::DownLeft,
+        6 => Hat::Left,
+        _ => Hat::UpLeft,
+    }
+}
+
+fn decode_gamepad<'a>(fields: impl Iterator<Item = &'a ReportField>, payload: &[u8]) -> GamepadState {
+    let mut state = GamepadState::default();
+    for f in fields {
+        for (u, value) in elements(f, payload) {
+            match (f.usage_page, u) {
+                (usage_page::BUTTON, _) => set_button(&mut state.buttons, u, value),
+                (usage_page::GENERIC_DESKTOP, usage::X..=usage::DIAL) => state.axes[(u - usage::X) as usize] = value,
+                (usage_page::GENERIC_DESKTOP, usage::HAT_SWITCH) => state.hat = hat_from(f, value),
+                _ => {}
+            }
+        }
+    }
+    state
+}
+
+#[cfg(test)]
+mod test {
+    use embassy_usb::class::hid::descriptor::{Collection, ItemFlags, ReportDescriptorBuilder};
+
+    use super::*;
+
+    const LEFT_SHIFT: u8 = 0xE1;
+    const KEY_A: u8 = 0x04;
+    const KEY_Z: u8 = 0x1D;
+
+    /// N-key rollover keyboard (report 1), mouse (report 2) and consumer control (report 3).
+    const COMBO: ReportDescriptorBuilder<256> = ReportDescriptorBuilder::new()
+        .usage_page(usage_page::GENERIC_DESKTOP)
+        .usage(usage::KEYBOARD)
+        .collection(Collection::Application)
+        .report_id(1)
+        .usage_page(usage_page::KEYBOARD)
+        .usage_range(0xE0, 0xE7)
+        .logical_range(0, 1)
+        .input_field(1, 8, ItemFlags::VARIABLE)
+        .usage_range(0x00, 0x67)
+        .input_field(1, 0x68, ItemFlags::VARIABLE)
+        .end_collection()
+        .usage_page(usage_page::GENERIC_DESKTOP)
+        .usage(usage::MOUSE)
+        .collection(Collection::Application)
+        .report_id(2)
+        .usage(usage::POINTER)
+        .collection(Collection::Physical)
+        .usage_page(usage_page::BUTTON)
+        .usage_range(1, 5)
+        .logical_range(0, 1)
+        .input_field(1, 5, ItemFlags::VARIABLE)
+        .input_field(3, 1, ItemFlags::CONSTANT)
+        .usage_page(usage_page::GENERIC_DESKTOP)
+        .usage(usage::X)
+        .usage(usage::Y)
+        .logical_range(-32767, 32767)
+        .input_field(16, 2, ItemFlags::VARIABLE.union(ItemFlags::RELATIVE))
+        .usage(usage::WHEEL)
+        .logical_range(-127, 127)
+        .input_field(8, 1, ItemFlags::VARIABLE.union(ItemFlags::RELATIVE))
+        .usage_extended(usage_page::CONSUMER, CONSUMER_AC_PAN)
+        .input_field(8, 1, ItemFlags::VARIABLE.union(ItemFlags::RELATIVE))
+        .end_collection()
+        .end_collection()
+        .usage_page(usage_page::CONSUMER)
+        .usage(0x01)
+        .collection(Collection::Application)
+        .report_id(3)
+        .usage_range(0, 0x3FF)
+        .logical_range(0, 0x3FF)
+        .input_field(16, 1, ItemFlags::DATA)
+        .end_collection();
+
+    #[test]
+    fn nkro_keyboard() {
+        let desc: ReportDescriptor<16> = ReportDescriptor::parse(COMBO.as_bytes());
+        assert_eq!(report_kind(&desc, 1), Some(InputKind::Keyboard));
+
+        let mut report = [0u8; 15];
+        report[0] = 1;
+        report[1] = 1 << (LEFT_SHIFT - 0xE0);
+        report[2 + KEY_A as usize / 8] |= 1 << (KEY_A % 8);
+        report[2 + KEY_Z as usize / 8] |= 1 << (KEY_Z % 8);
+
+        let Some(InputEvent::Keyboard(keys)) = decode(&desc, &report) else {
+            panic!("not a keyboard event");
+        };
+        assert_eq!(keys.modifiers(), 0x02);
+        assert!(keys.is_pressed(KEY_A) && keys.is_pressed(KEY_Z));
+        let pressed: heapless::Vec<u8, 8> = keys.pressed().collect();
+        assert_eq!(pressed, [KEY_A, KEY_Z, LEFT_SHIFT]);
+
+        let mut prev = KeyboardState::default();
+        prev.set(KEY_A);
+        prev.set(0x05);
+        let changes: heapless::Vec<KeyEvent, 8> = keys.changes(&prev).collect();
+        assert_eq!(
+            changes,
+            [
+                KeyEvent {
+                    usage: 0x05,
+                    pressed: false
+                },
+                KeyEvent {
+                    usage: KEY_Z,
+                    pressed: true
+                },
+                KeyEvent {
+                    usage: LEFT_SHIFT,
+                    pressed: true
+                },
+            ]
+        );
+
+        // Truncated reports are rejected rather than read as released keys.
+        assert_eq!(decode(&desc, &report[..8]), None);
+    }
+
+    #[test]
+    fn array_keyboard_rollover() {
+        const BOOT: ReportDescriptorBuilder<64> = ReportDescriptorBuilder::new()
+            .usage_page(usage_page::GENERIC_DESKTOP)
+            .usage(usage::KEYBOARD)
+            .collection(Collection::Application)
+            .usage_page(usage_page::KEYBOARD)
+            .usage_range(0xE0, 0xE7)
+            .logical_range(0, 1)
+            .input_field(1, 8, ItemFlags::VARIABLE)
+            .input_field(8, 1, ItemFlags::CONSTANT)
+            .usage_range(0x00, 0xFF)
+            .logical_range(0, 0xFF)
+            .input_field(8, 6, ItemFlags::DATA)
+            .end_collection();
+        let desc: ReportDescriptor<8> = ReportDescriptor::parse(BOOT.as_bytes());
+
+        let Some(InputEvent::Keyboard(keys)) = decode(&desc, &[0x01, 0, KEY_A, 0, 0, 0, 0, 0]) else {
+            panic!("not a keyboard event");
+        };
+        assert_eq!(keys.modifiers(), 0x01);
+        assert!(keys.is_pressed(KEY_A) && !keys.is_pressed(0));
+        assert!(!keys.is_rollover());
+
+        let Some(InputEvent::Keyboard(rollover)) = decode(&desc, &[0, 0, 1, 1, 1, 1, 1, 1]) else {
+            panic!("not a keyboard event");
+        };
+        assert!(rollover.is_rollover());
+        assert_eq!(rollover.pressed().count(), 0);
+        assert_eq!(rollover.changes(&keys).count(), 0);
+    }
+
+    #[test]
+    fn mouse_and_report_ids() {
+        let desc: ReportDescriptor<16> = ReportDescriptor::parse(COMBO.as_bytes());
+        assert_eq!(report_kind(&desc, 2), Some(InputKind::Mouse));
+
+        let x = (-300i16).to_le_bytes();
+        let y = 1200i16.to_le_bytes();
+        let report = [2, 0b0_0101, x[0], x[1], y[0], y[1], (-2i8) as u8, 3];
+        assert_eq!(
+            decode(&desc, &report),
+            Some(InputEvent::Mouse(MouseState {
+                buttons: 0b101,
+                x: -300,
+                y: 1200,
+                wheel: -2,
+                pan: 3,
+                absolute: false,
+            }))
+        );
+
+        // Consumer control and unknown report IDs are not input events.
+        assert_eq!(report_kind(&desc, 3), None);
+        assert_eq!(decode(&desc, &[3, 0xE9, 0x00]), None);
+        assert_eq!(decode(&desc, &[7, 0, 0]), None);
+        assert_eq!(decode(&desc, &[]), None);
+    }
+
+    #[test]
+    fn multi_touch_contacts() {
+        const FINGER: ReportDescriptorBuilder<64> = ReportDescriptorBuilder::new()
+            .usage_page(usage_page::DIGITIZER)
+            .usage(digitizer::FINGER)
+            .collection(Collection::Logical)
+            .usage(digitizer::TIP_SWITCH)
+            .logical_range(0, 1)
+            .input_field(1, 1, ItemFlags::VARIABLE)
+            .input_field(7, 1, ItemFlags::CONSTANT)
+            .usage(digitizer::CONTACT_ID)
+            .logical_range(0, 255)
+            .input_field(8, 1, ItemFlags::VARIABLE)
+            .usage_page(usage_page::GENERIC_DESKTOP)
+            .usage(usage::X)
+            .usage(usage::Y)
+            .logical_range(0, 4095)
+            .input_field(16, 2, ItemFlags::VARIABLE)
+            .end_collection();
+        const TOUCH: ReportDescriptorBuilder<192> = ReportDescriptorBuilder::new()
+            .usage_page(usage_page::DIGITIZER)
+            .usage(digitizer::TOUCH_SCREEN)
+            .collection(Collection::Application)
+            .report_id(4)
+            .raw(FINGER.as_bytes())
+            .raw(FINGER.as_bytes())
+            .raw(FINGER.as_bytes())
+            .usage_page(usage_page::DIGITIZER)
+            .usage(digitizer::CONTACT_COUNT)
+            .logical_range(0, 10)
+            .input_field(8, 1, ItemFlags::VARIABLE)
+            .end_collection();
+        let desc: ReportDescriptor<32> = ReportDescriptor::parse(TOUCH.as_bytes());
+        assert_eq!(report_kind(&desc, 4), Some(InputKind::Touch));
+
+        let mut report = [0u8; 20];
+        report[0] = 4;
+        report[1..7].copy_from_slice(&[1, 7, 0x10, 0x00, 0x20, 0x00]);
+        report[7..13].copy_from_slice(&[0, 9, 0xFF, 0x0F, 0x00, 0x01]);
+        report[19] = 2;
+
+        let Some(InputEvent::Touch(touch)) = decode(&desc, &report) else {
+            panic!("not a touch event");
+        };
+        assert_eq!(touch.contact_count, Some(2));
+        assert_eq!(
+            touch.contacts,
+            [
+                Contact {
+                    id: 7,
+                    touching: true,
+                    x: 0x10,
+                    y: 0x20,
+                    pressure: None
+                },
+                Contact {
+                    id: 9,
+                    touching: false,
+                    x: 0xFFF,
+                    y: 0x100,
+                    pressure: None
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn gamepad_axes_and_hat() {
+        const GAMEPAD: ReportDescriptorBuilder<128> = ReportDescriptorBuilder::new()
+            .usage_page(usage_page::GENERIC_DESKTOP)
+            .usage(usage::GAMEPAD)
+            .collection(Collection::Application)
+            .usage_page(usage_page::BUTTON)
+            .usage_range(1, 16)
+            .logical_range(0, 1)
+            .input_field(1, 16, ItemFlags::VARIABLE)
+            .usage_page(usage_page::GENERIC_DESKTOP)
+            .usage(usage::HAT_SWITCH)
+            .logical_range(0, 7)
+            .input_field(4, 1, ItemFlags::VARIABLE.union(ItemFlags::NULL_STATE))
+            .input_field(4, 1, ItemFlags::CONSTANT)
+            .usage(usage::X)
+            .usage(usage::Y)
+            .usage(usage::Z)
+            .usage(usage::RZ)
+            .logical_range(-32768, 32767)
+            .input_field(16, 4, ItemFlags::VARIABLE)
+            .end_collection();
+        let desc: ReportDescriptor<16> = ReportDescriptor::parse(GAMEPAD.as_bytes());
+
+        let mut report = [0u8; 11];
+        report[0..2].copy_from_slice(&0x8001u16.to_le_bytes());
+        report[2] = 2;
+        report[3..5].copy_from_slice(&(-100i16).to_le_bytes());
+        report[9..11].copy_from_slice(&32767i16.to_le_bytes());
+
+        let Some(InputEvent::Gamepad(pad)) = decode(&desc, &report) else {
+            panic!("not a gamepad event");
+        };
+        assert_eq!(pad.buttons, 0x8001);
+        assert_eq!(pad.hat, Hat::Right);
+        assert_eq!(pad.axis(usage::X), Some(-100));
+        assert_eq!(pad.axis(usage::Y), Some(0));
+        assert_eq!(pad.axis(usage::RZ), Some(32767));
+        assert_eq!(pad.axis(usage::WHEEL), None);
+
+        // Hat in its null state.
+        report[2] = 0x0F;
+        let Some(InputEvent::Gamepad(pad)) = decode(&desc, &report) else {
+            panic!("not a gamepad event");
+        };
+        assert_eq!(pad.hat, Hat::Centered);
+    }
+}
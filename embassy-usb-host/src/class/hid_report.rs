@@ -23,6 +23,8 @@
 //! let btn1 = report.extract_bool(&buf, 0, usage_page::BUTTON, 1);
 //! ```
 
+use crate::descriptor::DescriptorError;
+
 // ── Usage page constants ───────────────────────────────────────────────────────
 
 /// Common HID usage page identifiers.
@@ -37,6 +39,8 @@ pub mod usage_page {
     pub const BUTTON: u16 = 0x09;
     /// Consumer Controls (media keys, volume, etc.).
     pub const CONSUMER: u16 = 0x0C;
+    /// Digitizers (touchscreens, touchpads, pens).
+    pub const DIGITIZER: u16 = 0x0D;
 }
 
 /// Generic Desktop usages (usage page [`usage_page::GENERIC_DESKTOP`]).
@@ -51,6 +55,10 @@ pub mod usage {
     pub const GAMEPAD: u16 = 0x05;
     /// Keyboard collection.
     pub const KEYBOARD: u16 = 0x06;
+    /// Keypad collection.
+    pub const KEYPAD: u16 = 0x07;
+    /// Multi-axis controller collection.
+    pub const MULTI_AXIS: u16 = 0x08;
     /// X axis.
     pub const X: u16 = 0x30;
     /// Y axis.
@@ -81,6 +89,34 @@ pub mod usage {
     pub const DPAD_LEFT: u16 = 0x93;
 }
 
+/// Digitizer usages (usage page [`usage_page::DIGITIZER`]).
+pub mod digitizer {
+    /// Pen collection.
+    pub const PEN: u16 = 0x02;
+    /// Touch screen collection.
+    pub const TOUCH_SCREEN: u16 = 0x04;
+    /// Touch pad collection.
+    pub const TOUCH_PAD: u16 = 0x05;
+    /// Finger collection, one per contact.
+    pub const FINGER: u16 = 0x22;
+    /// Pressure of the contact.
+    pub const TIP_PRESSURE: u16 = 0x30;
+    /// Contact is within detection range.
+    pub const IN_RANGE: u16 = 0x32;
+    /// Contact is touching the surface.
+    pub const TIP_SWITCH: u16 = 0x42;
+    /// Contact is an intended touch rather than e.g. a palm.
+    pub const CONFIDENCE: u16 = 0x47;
+    /// Contact width.
+    pub const WIDTH: u16 = 0x48;
+    /// Contact height.
+    pub const HEIGHT: u16 = 0x49;
+    /// Identifier of the contact, stable while it touches the surface.
+    pub const CONTACT_ID: u16 = 0x51;
+    /// Number of valid contacts in the report.
+    pub const CONTACT_COUNT: u16 = 0x54;
+}
+
 // ── Input item flag constants ──────────────────────────────────────────────────
 
 /// Bit flags from a HID Input/Output/Feature item (bits 0–7 of the item data).
@@ -134,6 +170,14 @@ pub struct ReportField {
     pub logical_max: i32,
     /// Input item flags — see the [`flags`] module.
     pub flags: u8,
+    /// Innermost collection containing this field, numbered from 1 in
+    /// descriptor order (0 outside any collection). Fields of the same
+    /// touch contact share a collection.
+    pub collection: u16,
+    /// Usage page of the enclosing Application collection (0 if none).
+    pub application_page: u16,
+    /// Usage of the enclosing Application collection, e.g. [`usage::MOUSE`].
+    pub application_usage: u16,
 }
 
 impl ReportField {
@@ -172,7 +216,7 @@ impl ReportField {
         if index >= self.count as usize {
             return None;
         }
-        let bit_start = self.bit_offset as usize + index * (self.bit_size as usize);
+        let bit_start = (self.bit_offset as usize).checked_add(index * self.bit_size as usize)?;
         extract_bits(report_payload, bit_start, self.bit_size as usize)
     }
 
@@ -202,7 +246,7 @@ fn extract_bits(data: &[u8], bit_offset: usize, bit_count: usize) -> Option<u32>
         return None;
     }
     let byte_start = bit_offset / 8;
-    let byte_end = (bit_offset + bit_count + 7) / 8;
+    let byte_end = bit_offset.checked_add(bit_count + 7)? / 8;
     if byte_end > data.len() {
         return None;
     }
@@ -223,7 +267,7 @@ fn extract_bits(data: &[u8], bit_offset: usize, bit_count: usize) -> Option<u32>
 /// `N` is the maximum number of input fields to store. A typical gamepad
 /// descriptor produces 8–20 fields; **32 is sufficient for most devices**.
 ///
-/// Obtain one by calling [`ReportDescriptor::parse`].
+/// Obtain one by calling [`ReportDescriptor::parse`] or [`ReportDescriptor::try_parse`].
 #[derive(Debug)]
 pub struct ReportDescriptor<const N: usize> {
     fields: [Option<ReportField>; N],
@@ -237,7 +281,26 @@ impl<const N: usize> ReportDescriptor<N> {
     /// Parse a raw HID report descriptor byte slice.
     ///
     /// Fields beyond the `N`-th are silently dropped — increase `N` if needed.
+    /// Malformed descriptors are parsed as far as possible; use
+    /// [`ReportDescriptor::try_parse`] to reject them instead.
     pub fn parse(descriptor: &[u8]) -> Self {
+        Self::parse_checked(descriptor).0
+    }
+
+    /// Parse a raw HID report descriptor, rejecting malformed descriptors.
+    ///
+    /// Returns [`DescriptorError::UnexpectedEndOfBuffer`] if the last item is
+    /// truncated, and [`DescriptorError::BadDescriptorData`] if the collections
+    /// are not balanced.
+    pub fn try_parse(descriptor: &[u8]) -> Result<Self, DescriptorError> {
+        match Self::parse_checked(descriptor) {
+            (result, None) => Ok(result),
+            (_, Some(e)) => Err(e),
+        }
+    }
+
+    /// Parse leniently, also returning the first error found.
+    fn parse_checked(descriptor: &[u8]) -> (Self, Option<DescriptorError>) {
         let mut global = GlobalState::default();
         let mut local = LocalState::default();
         let mut stack = [GlobalState::DEFAULT; 4];
@@ -248,13 +311,23 @@ impl<const N: usize> ReportDescriptor<N> {
         let mut offsets: [(u8, u32); 16] = [(0, 0); 16];
         let mut offset_count: usize = 1;
 
+        // Collection nesting: (collection number, application page, application usage).
+        let mut collection: (u16, u16, u16) = (0, 0, 0);
+        let mut collection_stack = [(0u16, 0u16, 0u16); 8];
+        let mut collection_depth: usize = 0;
+        let mut collection_count: u16 = 0;
+        // Unlike `collection_depth`, not capped by the stack size.
+        let mut open_collections: usize = 0;
+        let mut error = None;
+
         let mut result = ReportDescriptor {
             fields: [const { None }; N],
             count: 0,
             has_report_ids: false,
         };
 
-        for item in ItemIter::new(descriptor) {
+        let mut items = ItemIter::new(descriptor);
+        for item in &mut items {
             match (item.item_type, item.tag) {
                 // ── Global items ──────────────────────────────────────────
                 (1, 0) => global.usage_page = item.data as u16,
@@ -322,7 +395,7 @@ impl<const N: usize> ReportDescriptor<N> {
                             .find(|(id, _)| *id == global.report_id)
                             .map(|(_, off)| {
                                 let start = *off;
-                                *off += rc as u32 * rs as u32;
+                                *off = off.saturating_add(rc as u32 * rs as u32);
                                 start
                             })
                             .unwrap_or(0);
@@ -351,12 +424,15 @@ impl<const N: usize> ReportDescriptor<N> {
                                     usage_page: page,
                                     usage_min: u,
                                     usage_max: u,
-                                    bit_offset: bit_offset + i as u32 * rs as u32,
+                                    bit_offset: bit_offset.saturating_add(i as u32 * rs as u32),
                                     bit_size: rs,
                                     count: 1,
                                     logical_min: global.logical_min,
                                     logical_max: global.logical_max,
                                     flags: item_flags,
+                                    collection: collection.0,
+                                    application_page: collection.1,
+                                    application_usage: collection.2,
                                 });
                                 result.count += 1;
                             }
@@ -386,6 +462,9 @@ impl<const N: usize> ReportDescriptor<N> {
                                 logical_min: global.logical_min,
                                 logical_max: global.logical_max,
                                 flags: item_flags,
+                                collection: collection.0,
+                                application_page: collection.1,
+                                application_usage: collection.2,
                             });
                             result.count += 1;
                         }
@@ -400,7 +479,32 @@ impl<const N: usize> ReportDescriptor<N> {
                 }
 
                 // ── Main: Collection / End Collection ─────────────────────
-                (0, 10) | (0, 12) => {
+                (0, 10) => {
+                    open_collections += 1;
+                    if collection_depth < collection_stack.len() {
+                        collection_stack[collection_depth] = collection;
+                        collection_depth += 1;
+                    }
+                    collection_count = collection_count.saturating_add(1);
+                    collection.0 = collection_count;
+                    // Application collection: remember its usage to classify the fields inside.
+                    if item.data == 0x01 {
+                        let packed = if local.usage_count > 0 { local.usages[0] } else { 0 };
+                        let page = (packed >> 16) as u16;
+                        collection.1 = if page != 0 { page } else { global.usage_page };
+                        collection.2 = packed as u16;
+                    }
+                    local = LocalState::default();
+                }
+                (0, 12) => {
+                    if open_collections == 0 {
+                        error.get_or_insert(DescriptorError::BadDescriptorData);
+                    }
+                    open_collections = open_collections.saturating_sub(1);
+                    if collection_depth > 0 {
+                        collection_depth -= 1;
+                        collection = collection_stack[collection_depth];
+                    }
                     local = LocalState::default();
                 }
 
@@ -408,7 +512,13 @@ impl<const N: usize> ReportDescriptor<N> {
             }
         }
 
-        result
+        if items.truncated {
+            error.get_or_insert(DescriptorError::UnexpectedEndOfBuffer);
+        }
+        if open_collections != 0 {
+            error.get_or_insert(DescriptorError::BadDescriptorData);
+        }
+        (result, error)
     }
 
     /// Iterate over all parsed Input fields (skips empty slots).
@@ -538,11 +648,17 @@ struct Item {
 struct ItemIter<'a> {
     bytes: &'a [u8],
     pos: usize,
+    /// Set when the descriptor ends in the middle of an item.
+    truncated: bool,
 }
 
 impl<'a> ItemIter<'a> {
     fn new(bytes: &'a [u8]) -> Self {
-        Self { bytes, pos: 0 }
+        Self {
+            bytes,
+            pos: 0,
+            truncated: false,
+        }
     }
 }
 
@@ -559,11 +675,12 @@ impl<'a> Iterator for ItemIter<'a> {
 
             // Long item (prefix == 0xFE): skip entirely.
             if prefix == 0xFE {
-                if self.pos >= self.bytes.len() {
+                let data_size = self.bytes.get(self.pos).copied().unwrap_or(0) as usize;
+                self.pos += 2 + data_size; // skip bDataSize + bLongItemTag + data
+                if self.pos > self.bytes.len() {
+                    self.truncated = true;
                     return None;
                 }
-                let data_size = self.bytes[self.pos] as usize;
-                self.pos += 2 + data_size; // skip bDataSize + bLongItemTag + data
                 continue;
             }
 
@@ -578,6 +695,7 @@ impl<'a> Iterator for ItemIter<'a> {
             let tag = (prefix >> 4) & 0x0F;
 
             if self.pos + size > self.bytes.len() {
+                self.truncated = true;
                 return None;
             }
 
@@ -611,3 +729,172 @@ impl<'a> Iterator for ItemIter<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use embassy_usb::class::hid::descriptor::{Collection, ItemFlags, ReportDescriptorBuilder};
+    use embassy_usb::class::hid::report::{
+        DecodeReport, EncodeReport, ReportError, ReportReader, ReportWriter, decode_report, encode_report,
+    };
+
+    use super::*;
+
+    const GAMEPAD: ReportDescriptorBuilder<128> = ReportDescriptorBuilder::new()
+        .usage_page(usage_page::GENERIC_DESKTOP)
+        .usage(usage::GAMEPAD)
+        .collection(Collection::Application)
+        .report_id(2)
+        // 12 buttons + 4 bits padding
+        .usage_page(usage_page::BUTTON)
+        .usage_range(1, 12)
+        .logical_range(0, 1)
+        .input_field(1, 12, ItemFlags::VARIABLE)
+        .input_field(4, 1, ItemFlags::CONSTANT)
+        // Signed 16-bit X/Y, unsigned 10-bit Z
+        .usage_page(usage_page::GENERIC_DESKTOP)
+        .usage(usage::X)
+        .usage(usage::Y)
+        .logical_range(-32768, 32767)
+        .input_field(16, 2, ItemFlags::VARIABLE)
+        .usage(usage::Z)
+        .logical_range(0, 1023)
+        .input_field(10, 1, ItemFlags::VARIABLE)
+        .input_field(6, 1, ItemFlags::CONSTANT)
+        // Rumble output, not part of the input layout
+        .usage_page(0xFF00)
+        .usage(0x01)
+        .logical_range(0, 255)
+        .output_field(8, 2, ItemFlags::VARIABLE)
+        .end_collection();
+
+    #[derive(Debug, PartialEq)]
+    struct GamepadReport {
+        buttons: u16,
+        x: i16,
+        y: i16,
+        z: u16,
+    }
+
+    impl EncodeReport for GamepadReport {
+        const REPORT_ID: u8 = 2;
+
+        fn encode(&self, w: &mut ReportWriter<'_>) -> Result<(), ReportError> {
+            w.write_unsigned(self.buttons as u32, 12)?;
+            w.pad(4)?;
+            w.write_signed(self.x as i32, 16)?;
+            w.write_signed(self.y as i32, 16)?;
+            w.write_unsigned(self.z as u32, 10)?;
+            w.pad(6)
+        }
+    }
+
+    impl DecodeReport for GamepadReport {
+        const REPORT_ID: u8 = 2;
+
+        fn decode(r: &mut ReportReader<'_>) -> Result<Self, ReportError> {
+            let buttons = r.read_unsigned(12)? as u16;
+            r.skip(4)?;
+            let x = r.read_signed(16)? as i16;
+            let y = r.read_signed(16)? as i16;
+            let z = r.read_unsigned(10)? as u16;
+            r.skip(6)?;
+            Ok(Self { buttons, x, y, z })
+        }
+    }
+
+    #[test]
+    fn builder_encodes_minimal_items() {
+        let desc = ReportDescriptorBuilder::<16>::new()
+            .logical_range(0, 255)
+            .logical_minimum(-1)
+            .usage_extended(0xFF00, 0x0001)
+            .input(ItemFlags::DATA);
+        assert_eq!(
+            desc.as_bytes(),
+            &[
+                0x15, 0x00, 0x26, 0xFF, 0x00, 0x15, 0xFF, 0x0B, 0x01, 0x00, 0x00, 0xFF, 0x81, 0x00
+            ]
+        );
+    }
+
+    #[test]
+    fn builder_parses_with_host_parser() {
+        let desc: ReportDescriptor<16> = ReportDescriptor::parse(GAMEPAD.as_bytes());
+        assert!(desc.has_report_ids);
+
+        let fields: heapless::Vec<&ReportField, 16> = desc.fields().collect();
+        assert_eq!(fields.len(), 6);
+
+        let buttons = fields[0];
+        assert_eq!(buttons.report_id, 2);
+        assert_eq!(
+            (buttons.usage_page, buttons.usage_min, buttons.usage_max),
+            (usage_page::BUTTON, 1, 12)
+        );
+        assert_eq!((buttons.bit_offset, buttons.bit_size, buttons.count), (0, 1, 12));
+        assert!(fields[1].is_constant());
+
+        let (x, _) = desc.find(2, usage_page::GENERIC_DESKTOP, usage::X).unwrap();
+        assert_eq!(
+            (x.bit_offset, x.bit_size, x.logical_min, x.logical_max),
+            (16, 16, -32768, 32767)
+        );
+        let (z, _) = desc.find(2, usage_page::GENERIC_DESKTOP, usage::Z).unwrap();
+        assert_eq!((z.bit_offset, z.bit_size, z.logical_max), (48, 10, 1023));
+    }
+
+    #[test]
+    fn codec_roundtrip_through_host_parser() {
+        let desc: ReportDescriptor<16> = ReportDescriptor::parse(GAMEPAD.as_bytes());
+        let report = GamepadReport {
+            buttons: 0b1000_0000_0101,
+            x: -1234,
+            y: 32767,
+            z: 1000,
+        };
+
+        let mut buf = [0u8; 16];
+        let len = encode_report(&report, &mut buf).unwrap();
+        assert_eq!(len, 1 + 2 + 4 + 2);
+        assert_eq!(buf[0], 2);
+
+        let buf = &buf[..len];
+        assert_eq!(desc.extract_bool(buf, 2, usage_page::BUTTON, 1), Some(true));
+        assert_eq!(desc.extract_bool(buf, 2, usage_page::BUTTON, 2), Some(false));
+        assert_eq!(desc.extract_bool(buf, 2, usage_page::BUTTON, 3), Some(true));
+        assert_eq!(desc.extract_bool(buf, 2, usage_page::BUTTON, 12), Some(true));
+        assert_eq!(
+            desc.extract_i32(buf, 2, usage_page::GENERIC_DESKTOP, usage::X),
+            Some(-1234)
+        );
+        assert_eq!(
+            desc.extract_i32(buf, 2, usage_page::GENERIC_DESKTOP, usage::Y),
+            Some(32767)
+        );
+        assert_eq!(
+            desc.extract_u32(buf, 2, usage_page::GENERIC_DESKTOP, usage::Z),
+            Some(1000)
+        );
+
+        assert_eq!(decode_report::<GamepadReport>(buf), Ok(report));
+    }
+
+    #[test]
+    fn codec_errors() {
+        let mut buf = [0u8; 3];
+        let report = GamepadReport {
+            buttons: 0,
+            x: 0,
+            y: 0,
+            z: 0,
+        };
+        assert_eq!(encode_report(&report, &mut buf), Err(ReportError::BufferOverflow));
+        assert_eq!(
+            decode_report::<GamepadReport>(&[1, 0, 0, 0, 0, 0, 0, 0, 0]),
+            Err(ReportError::WrongReportId)
+        );
+
+        let mut w = ReportWriter::new(&mut buf);
+        assert_eq!(w.write_unsigned(0, 33), Err(ReportError::InvalidFieldSize));
+    }
+}
@@ -0,0 +1,745 @@
+//! USB MIDI 1.0 host class driver.
+//!
+//! This driver can communicate with USB MIDI devices (keyboards, controllers,
+//! synthesizers). MIDI messages travel as 4-byte USB-MIDI event packets
+//! (USB MIDI 1.0 §4); [`UsbMidiEventPacket`], [`encode_message`] and
+//! [`SysExAssembler`] convert between packets and MIDI byte streams.
+
+use embassy_usb_driver::host::{PipeError, UsbHostAllocator, UsbPipe, pipe};
+use embassy_usb_driver::{Direction as UsbDirection, EndpointAddress, EndpointInfo, EndpointType};
+use heapless::Vec;
+
+use crate::descriptor::{ConfigurationDescriptorChain, descriptor_type};
+use crate::handler::EnumerationInfo;
+
+/// Audio class code.
+const USB_CLASS_AUDIO: u8 = 0x01;
+/// MIDIStreaming subclass.
+const MIDISTREAMING_SUBCLASS: u8 = 0x03;
+/// Class-specific interface descriptor subtype: MIDI IN jack.
+const MIDI_IN_JACK: u8 = 0x02;
+/// Class-specific interface descriptor subtype: MIDI OUT jack.
+const MIDI_OUT_JACK: u8 = 0x03;
+/// Class-specific endpoint descriptor subtype: MS_GENERAL.
+const MS_GENERAL: u8 = 0x01;
+/// Bulk transfer type.
+const TRANSFER_BULK: u8 = 0x02;
+
+/// Maximum number of jacks recorded per MIDIStreaming interface.
+pub const MAX_JACKS: usize = 32;
+/// Number of virtual cables per endpoint (USB MIDI 1.0 §4).
+pub const MAX_CABLES: usize = 16;
+
+/// MIDI host class driver error.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MidiError {
+    /// Transfer error.
+    Transfer(PipeError),
+    /// No MIDIStreaming interface found in the device.
+    NoInterface,
+    /// Failed to allocate a pipe.
+    NoPipe,
+    /// The device has no endpoint in the requested direction.
+    NoEndpoint,
+}
+
+impl From<PipeError> for MidiError {
+    fn from(e: PipeError) -> Self {
+        Self::Transfer(e)
+    }
+}
+
+impl core::fmt::Display for MidiError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Transfer(_e) => write!(f, "Transfer error"),
+            Self::NoInterface => write!(f, "No MIDIStreaming interface found"),
+            Self::NoPipe => write!(f, "No free pipe"),
+            Self::NoEndpoint => write!(f, "No endpoint in this direction"),
+        }
+    }
+}
+
+impl core::error::Error for MidiError {}
+
+/// Whether a jack is connected to the USB function or to the outside world.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum JackType {
+    /// Jack connected to a USB endpoint (a virtual cable).
+    Embedded,
+    /// Jack representing a physical connector or synthesizer.
+    External,
+    /// Reserved jack type.
+    Other(u8),
+}
+
+impl From<u8> for JackType {
+    fn from(v: u8) -> Self {
+        match v {
+            0x01 => Self::Embedded,
+            0x02 => Self::External,
+            v => Self::Other(v),
+        }
+    }
+}
+
+/// A MIDI IN or OUT jack of a MIDIStreaming interface (USB MIDI 1.0 §6.1.2.2, §6.1.2.3).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Jack {
+    /// Jack ID, unique within the interface.
+    pub id: u8,
+    /// `true` for a MIDI IN jack, `false` for a MIDI OUT jack.
+    pub is_in: bool,
+    /// Embedded or external.
+    pub jack_type: JackType,
+    /// String descriptor index, 0 if none.
+    pub string: u8,
+}
+
+/// Information about a MIDIStreaming interface found in a configuration descriptor.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MidiInfo {
+    /// MIDIStreaming interface number.
+    pub interface: u8,
+    /// Bulk IN endpoint address, if any.
+    pub bulk_in_ep: Option<u8>,
+    /// Bulk IN max packet size.
+    pub bulk_in_mps: u16,
+    /// Bulk OUT endpoint address, if any.
+    pub bulk_out_ep: Option<u8>,
+    /// Bulk OUT max packet size.
+    pub bulk_out_mps: u16,
+    /// Jacks declared by the interface.
+    pub jacks: Vec<Jack, MAX_JACKS>,
+    /// Embedded jack IDs of the IN endpoint, indexed by cable number.
+    pub in_cables: Vec<u8, MAX_CABLES>,
+    /// Embedded jack IDs of the OUT endpoint, indexed by cable number.
+    pub out_cables: Vec<u8, MAX_CABLES>,
+}
+
+impl MidiInfo {
+    /// Look up a jack by ID.
+    pub fn jack(&self, id: u8) -> Option<&Jack> {
+        self.jacks.iter().find(|j| j.id == id)
+    }
+
+    /// Cable number for sending to an embedded MIDI IN jack.
+    pub fn out_cable(&self, jack_id: u8) -> Option<u8> {
+        self.out_cables.iter().position(|&j| j == jack_id).map(|c| c as u8)
+    }
+
+    /// Cable number on which an embedded MIDI OUT jack's data is received.
+    pub fn in_cable(&self, jack_id: u8) -> Option<u8> {
+        self.in_cables.iter().position(|&j| j == jack_id).map(|c| c as u8)
+    }
+}
+
+/// Find the first MIDIStreaming interface in a configuration descriptor.
+///
+/// Only bulk endpoints are considered. Jacks beyond [`MAX_JACKS`] are ignored.
+pub fn find_midi(config_desc: &[u8]) -> Option<MidiInfo> {
+    let cfg = ConfigurationDescriptorChain::try_from_slice(config_desc).ok()?;
+
+    let mut info: Option<MidiInfo> = None;
+    let mut in_ms = false;
+    let mut cur_ep_in: Option<bool> = None;
+
+    for (_, d) in cfg.iter_descriptors() {
+        if d.len() < 3 {
+            continue;
+        }
+        match d[1] {
+            descriptor_type::INTERFACE if d.len() >= 9 => {
+                let is_ms = d[5] == USB_CLASS_AUDIO && d[6] == MIDISTREAMING_SUBCLASS;
+                match &info {
+                    // Alternate settings of the interface already being parsed.
+                    Some(i) if i.interface == d[2] => in_ms = is_ms,
+                    Some(_) => break,
+                    None if is_ms => {
+                        in_ms = true;
+                        info = Some(MidiInfo {
+                            interface: d[2],
+                            bulk_in_ep: None,
+                            bulk_in_mps: 0,
+                            bulk_out_ep: None,
+                            bulk_out_mps: 0,
+                            jacks: Vec::new(),
+                            in_cables: Vec::new(),
+                            out_cables: Vec::new(),
+                        });
+                    }
+                    None => in_ms = false,
+                }
+                cur_ep_in = None;
+            }
+            _ if !in_ms => {}
+            descriptor_type::CS_INTERFACE => {
+                let Some(info) = info.as_mut() else { continue };
+                let jack = match d[2] {
+                    MIDI_IN_JACK if d.len() >= 6 => Jack {
+                        id: d[4],
+                        is_in: true,
+                        jack_type: d[3].into(),
+                        string: d[5],
+                    },
+                    MIDI_OUT_JACK if d.len() >= 6 => {
+                        let pins = d[5] as usize;
+                        Jack {
+                            id: d[4],
+                            is_in: false,
+                            jack_type: d[3].into(),
+                            string: d.get(6 + 2 * pins).copied().unwrap_or(0),
+                        }
+                    }
+                    _ => continue,
+                };
+                let _ = info.jacks.push(jack);
+            }
+            descriptor_type::ENDPOINT if d.len() >= 7 => {
+                let Some(info) = info.as_mut() else { continue };
+                cur_ep_in = None;
+                if d[3] & 0x03 != TRANSFER_BULK {
+                    continue;
+                }
+                let addr = d[2];
+                let mps = u16::from_le_bytes([d[4], d[5]]);
+                if addr & 0x80 != 0 {
+                    info.bulk_in_ep.get_or_insert(addr);
+                    info.bulk_in_mps = mps;
+                } else {
+                    info.bulk_out_ep.get_or_insert(addr);
+                    info.bulk_out_mps = mps;
+                }
+                cur_ep_in = Some(addr & 0x80 != 0);
+            }
+            descriptor_type::CS_ENDPOINT if d.len() >= 4 && d[2] == MS_GENERAL => {
+                let (Some(info), Some(is_in)) = (info.as_mut(), cur_ep_in) else {
+                    continue;
+                };
+                let n = d[3] as usize;
+                let jacks = d.get(4..4 + n).unwrap_or(&d[4..]);
+                let cables = if is_in {
+                    &mut info.in_cables
+                } else {
+                    &mut info.out_cables
+                };
+                cables.clear();
+                for &j in jacks.iter().take(MAX_CABLES) {
+                    let _ = cables.push(j);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    info.filter(|i| i.bulk_in_ep.is_some() || i.bulk_out_ep.is_some())
+}
+
+/// Code Index Number of a USB-MIDI event packet (USB MIDI 1.0 §4, Table 4-1).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum CodeIndex {
+    /// Miscellaneous function codes, reserved.
+    Misc = 0x0,
+    /// Cable events, reserved.
+    CableEvent = 0x1,
+    /// Two-byte System Common message.
+    SystemCommon2 = 0x2,
+    /// Three-byte System Common message.
+    SystemCommon3 = 0x3,
+    /// SysEx starts or continues.
+    SysExStart = 0x4,
+    /// Single-byte System Common message, or SysEx ends with one byte.
+    SysExEnd1 = 0x5,
+    /// SysEx ends with two bytes.
+    SysExEnd2 = 0x6,
+    /// SysEx ends with three bytes.
+    SysExEnd3 = 0x7,
+    /// Note-off.
+    NoteOff = 0x8,
+    /// Note-on.
+    NoteOn = 0x9,
+    /// Poly-KeyPress.
+    PolyKeyPress = 0xA,
+    /// Control Change.
+    ControlChange = 0xB,
+    /// Program Change.
+    ProgramChange = 0xC,
+    /// Channel Pressure.
+    ChannelPressure = 0xD,
+    /// PitchBend Change.
+    PitchBend = 0xE,
+    /// Single byte.
+    SingleByte = 0xF,
+}
+
+impl CodeIndex {
+    /// Decode the low nibble of a packet header.
+    pub const fn from_bits(v: u8) -> Self {
+        match v & 0x0F {
+            0x0 => Self::Misc,
+            0x1 => Self::CableEvent,
+            0x2 => Self::SystemCommon2,
+            0x3 => Self::SystemCommon3,
+            0x4 => Self::SysExStart,
+            0x5 => Self::SysExEnd1,
+            0x6 => Self::SysExEnd2,
+            0x7 => Self::SysExEnd3,
+            0x8 => Self::NoteOff,
+            0x9 => Self::NoteOn,
+            0xA => Self::PolyKeyPress,
+            0xB => Self::ControlChange,
+            0xC => Self::ProgramChange,
+            0xD => Self::ChannelPressure,
+            0xE => Self::PitchBend,
+            _ => Self::SingleByte,
+        }
+    }
+
+    /// Number of valid MIDI bytes in a packet with this code index.
+    ///
+    /// Reserved code indices carry no data.
+    pub const fn message_len(self) -> usize {
+        match self {
+            Self::Misc | Self::CableEvent => 0,
+            Self::SysExEnd1 | Self::SingleByte => 1,
+            Self::SystemCommon2 | Self::SysExEnd2 | Self::ProgramChange | Self::ChannelPressure => 2,
+            _ => 3,
+        }
+    }
+}
+
+/// A 4-byte USB-MIDI event packet (USB MIDI 1.0 §4).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UsbMidiEventPacket {
+    /// Virtual cable number (0..=15).
+    pub cable: u8,
+    /// Code index number.
+    pub code_index: CodeIndex,
+    /// MIDI bytes, unused bytes are zero.
+    pub data: [u8; 3],
+}
+
+impl UsbMidiEventPacket {
+    /// Decode a packet.
+    pub const fn from_bytes(b: [u8; 4]) -> Self {
+        Self {
+            cable: b[0] >> 4,
+            code_index: CodeIndex::from_bits(b[0]),
+            data: [b[1], b[2], b[3]],
+        }
+    }
+
+    /// Encode the packet.
+    pub const fn to_bytes(&self) -> [u8; 4] {
+        [
+            (self.cable << 4) | self.code_index as u8,
+            self.data[0],
+            self.data[1],
+            self.data[2],
+        ]
+    }
+
+    /// Build a packet from a single, complete MIDI message that is not SysEx.
+    ///
+    /// Returns `None` for SysEx, running-status data or a length that does not
+    /// match the status byte. Use [`encode_message`] for SysEx.
+    pub fn from_message(cable: u8, message: &[u8]) -> Option<Self> {
+        let status = *message.first()?;
+        let code_index = match status {
+            0x80..=0xEF => CodeIndex::from_bits(status >> 4),
+            0xF1 | 0xF3 => CodeIndex::SystemCommon2,
+            0xF2 => CodeIndex::SystemCommon3,
+            0xF6 => CodeIndex::SysExEnd1,
+            0xF8..=0xFF => CodeIndex::SingleByte,
+            _ => return None,
+        };
+        if cable as usize >= MAX_CABLES || message.len() != code_index.message_len() {
+            return None;
+        }
+        let mut data = [0; 3];
+        data[..message.len()].copy_from_slice(message);
+        Some(Self {
+            cable,
+            code_index,
+            data,
+        })
+    }
+
+    /// The MIDI bytes carried by this packet.
+    pub fn message(&self) -> &[u8] {
+        &self.data[..self.code_index.message_len()]
+    }
+}
+
+/// Iterate over the event packets of a bulk IN transfer.
+///
+/// Empty padding packets (all zeros) are skipped, as are trailing bytes that
+/// do not form a whole packet.
+pub fn packets(buf: &[u8]) -> impl Iterator<Item = UsbMidiEventPacket> + '_ {
+    buf.chunks_exact(4)
+        .filter(|c| c.iter().any(|&b| b != 0))
+        .map(|c| UsbMidiEventPacket::from_bytes([c[0], c[1], c[2], c[3]]))
+}
+
+/// Encode a complete MIDI message, including SysEx, into event packets.
+///
+/// SysEx messages must start with `0xF0` and end with `0xF7`. Returns the
+/// number of bytes written to `buf`, or `None` if the message is malformed,
+/// `cable` is out of range or `buf` is too small.
+pub fn encode_message(cable: u8, message: &[u8], buf: &mut [u8]) -> Option<usize> {
+    if message.first() != Some(&0xF0) {
+        let p = UsbMidiEventPacket::from_message(cable, message)?;
+        buf.get_mut(..4)?.copy_from_slice(&p.to_bytes());
+        return Some(4);
+    }
+
+    if cable as usize >= MAX_CABLES || message.last() != Some(&0xF7) || message.len() < 2 {
+        return None;
+    }
+    let n = message.len().div_ceil(3);
+    let out = buf.get_mut(..n * 4)?;
+    for (chunk, o) in message.chunks(3).zip(out.chunks_exact_mut(4)) {
+        let last = chunk.last() == Some(&0xF7);
+        let code_index = match (last, chunk.len()) {
+            (false, _) => CodeIndex::SysExStart,
+            (true, 1) => CodeIndex::SysExEnd1,
+            (true, 2) => CodeIndex::SysExEnd2,
+            (true, _) => CodeIndex::SysExEnd3,
+        };
+        let mut data = [0; 3];
+        data[..chunk.len()].copy_from_slice(chunk);
+        o.copy_from_slice(
+            &UsbMidiEventPacket {
+                cable,
+                code_index,
+                data,
+            }
+            .to_bytes(),
+        );
+    }
+    Some(n * 4)
+}
+
+/// SysEx reassembly error.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SysExError {
+    /// The message did not fit in the buffer and was dropped.
+    Overflow,
+}
+
+/// Reassembles SysEx messages from the event packets of one cable.
+///
+/// Packets of other cables and non-SysEx packets, such as real-time
+/// messages interleaved with a SysEx transfer, are ignored.
+pub struct SysExAssembler<const N: usize> {
+    cable: u8,
+    buf: Vec<u8, N>,
+    active: bool,
+    overflow: bool,
+}
+
+impl<const N: usize> SysExAssembler<N> {
+    /// Create an assembler for `cable`.
+    pub const fn new(cable: u8) -> Self {
+        Self {
+            cable,
+            buf: Vec::new(),
+            active: false,
+            overflow: false,
+        }
+    }
+
+    /// Feed a packet.
+    ///
+    /// Returns the complete message, from `0xF0` to `0xF7`, once its last
+    /// packet has been fed.
+    pub fn push(&mut self, packet: &UsbMidiEventPacket) -> Result<Option<&[u8]>, SysExError> {
+        if packet.cable != self.cable {
+            return Ok(None);
+        }
+        let end = match packet.code_index {
+            CodeIndex::SysExStart => false,
+            CodeIndex::SysExEnd2 | CodeIndex::SysExEnd3 => true,
+            // Also a single-byte System Common message unless it ends a SysEx.
+            CodeIndex::SysExEnd1 if self.active || packet.data[0] == 0xF7 => true,
+            _ => return Ok(None),
+        };
+
+        let data = packet.message();
+        if data.first() == Some(&0xF0) {
+            // A new start discards any unterminated message.
+            self.buf.clear();
+            self.active = true;
+            self.overflow = false;
+        } else if !self.active {
+            return Ok(None);
+        }
+
+        if !self.overflow && self.buf.extend_from_slice(data).is_err() {
+            self.overflow = true;
+        }
+        if !end {
+            return Ok(None);
+        }
+
+        self.active = false;
+        if self.overflow {
+            self.buf.clear();
+            return Err(SysExError::Overflow);
+        }
+        Ok(Some(&self.buf))
+    }
+}
+
+/// MIDI host driver.
+///
+/// Exchanges raw USB-MIDI event packets with a USB MIDI device.
+pub struct MidiHost<'d, A: UsbHostAllocator<'d>> {
+    in_ch: Option<A::Pipe<pipe::Bulk, pipe::In>>,
+    out_ch: Option<A::Pipe<pipe::Bulk, pipe::Out>>,
+    info: MidiInfo,
+    _phantom: core::marker::PhantomData<&'d ()>,
+}
+
+impl<'d, A: UsbHostAllocator<'d>> MidiHost<'d, A> {
+    /// Create a new MIDI host driver.
+    ///
+    /// Parses the config descriptor to find the MIDIStreaming endpoints and allocates channels.
+    pub fn new(alloc: &A, config_desc: &[u8], enum_info: &EnumerationInfo) -> Result<Self, MidiError> {
+        let info = find_midi(config_desc).ok_or(MidiError::NoInterface)?;
+
+        let device_address = enum_info.device_address;
+        let split = enum_info.split();
+
+        let in_ch = match info.bulk_in_ep {
+            Some(ep) => {
+                let ep_info = EndpointInfo {
+                    addr: EndpointAddress::from_parts((ep & 0x0F) as usize, UsbDirection::In),
+                    ep_type: EndpointType::Bulk,
+                    max_packet_size: info.bulk_in_mps,
+                    interval_ms: 0,
+                };
+                Some(
+                    alloc
+                        .alloc_pipe::<pipe::Bulk, pipe::In>(device_address, &ep_info, split)
+                        .map_err(|_| MidiError::NoPipe)?,
+                )
+            }
+            None => None,
+        };
+        let out_ch = match info.bulk_out_ep {
+            Some(ep) => {
+                let ep_info = EndpointInfo {
+                    addr: EndpointAddress::from_parts((ep & 0x0F) as usize, UsbDirection::Out),
+                    ep_type: EndpointType::Bulk,
+                    max_packet_size: info.bulk_out_mps,
+                    interval_ms: 0,
+                };
+                Some(
+                    alloc
+                        .alloc_pipe::<pipe::Bulk, pipe::Out>(device_address, &ep_info, split)
+                        .map_err(|_| MidiError::NoPipe)?,
+                )
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            in_ch,
+            out_ch,
+            info,
+            _phantom: core::marker::PhantomData,
+        })
+    }
+
+    /// Interface, endpoint and jack information.
+    pub fn info(&self) -> &MidiInfo {
+        &self.info
+    }
+
+    /// Read a transfer of event packets from the device.
+    ///
+    /// `buf` should hold at least one max-size packet. Decode the result with [`packets`].
+    pub async fn read_packets(&mut self, buf: &mut [u8]) -> Result<usize, MidiError> {
+        let ch = self.in_ch.as_mut().ok_or(MidiError::NoEndpoint)?;
+        Ok(ch.request_in(buf).await?)
+    }
+
+    /// Write event packets to the device.
+    ///
+    /// `data` must be a sequence of whole 4-byte packets, e.g. built with [`encode_message`].
+    pub async fn write_packets(&mut self, data: &[u8]) -> Result<(), MidiError> {
+        let ch = self.out_ch.as_mut().ok_or(MidiError::NoEndpoint)?;
+        ch.request_out(data, true).await?;
+        Ok(())
+    }
+
+    /// Send a single MIDI message, SysEx included, on `cable`.
+    ///
+    /// SysEx messages longer than 48 bytes must be encoded with
+    /// [`encode_message`] into a larger buffer and sent with
+    /// [`write_packets`](Self::write_packets).
+    pub async fn send_message(&mut self, cable: u8, message: &[u8]) -> Result<(), MidiError> {
+        let mut buf = [0u8; 64];
+        let n = encode_message(cable, message, &mut buf).ok_or(MidiError::Transfer(PipeError::BufferOverflow))?;
+        self.write_packets(&buf[..n]).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Audio Control + MIDIStreaming with one embedded/external jack pair per
+    /// direction, bulk OUT (0x01) feeding IN jack 1, bulk IN (0x81) fed by OUT jack 3.
+    #[rustfmt::skip]
+    const CFG_MIDI: [u8; 101] = [
+        9, 0x02, 101, 0, 2, 1, 0, 0x80, 50,
+        // Audio Control
+        9, 0x04, 0, 0, 0, 0x01, 0x01, 0, 0,
+        9, 0x24, 0x01, 0x00, 0x01, 9, 0, 1, 1,
+        // MIDIStreaming
+        9, 0x04, 1, 0, 2, 0x01, 0x03, 0, 0,
+        7, 0x24, 0x01, 0x00, 0x01, 37, 0,
+        6, 0x24, 0x02, 0x01, 1, 0,
+        6, 0x24, 0x02, 0x02, 2, 5,
+        9, 0x24, 0x03, 0x01, 3, 1, 2, 1, 0,
+        9, 0x24, 0x03, 0x02, 4, 1, 1, 1, 6,
+        9, 0x05, 0x01, 0x02, 64, 0, 0, 0, 0,
+        5, 0x25, 0x01, 1, 1,
+        9, 0x05, 0x81, 0x02, 64, 0, 0, 0, 0,
+        5, 0x25, 0x01, 1, 3,
+    ];
+
+    #[test]
+    fn find_midi_jacks_and_cables() {
+        let info = find_midi(&CFG_MIDI).unwrap();
+        assert_eq!(info.interface, 1);
+        assert_eq!(info.bulk_in_ep, Some(0x81));
+        assert_eq!(info.bulk_out_ep, Some(0x01));
+        assert_eq!(info.bulk_in_mps, 64);
+        assert_eq!(info.jacks.len(), 4);
+        assert_eq!(
+            info.jack(2),
+            Some(&Jack {
+                id: 2,
+                is_in: true,
+                jack_type: JackType::External,
+                string: 5,
+            })
+        );
+        assert_eq!(info.jack(4).unwrap().string, 6);
+        assert!(!info.jack(3).unwrap().is_in);
+        assert_eq!(info.out_cable(1), Some(0));
+        assert_eq!(info.in_cable(3), Some(0));
+        assert_eq!(info.out_cable(3), None);
+    }
+
+    #[test]
+    fn find_midi_rejects_invalid() {
+        // Truncated before the MIDIStreaming interface.
+        assert!(find_midi(&CFG_MIDI[..27]).is_none());
+        assert!(find_midi(&[]).is_none());
+    }
+
+    #[test]
+    fn packet_roundtrip() {
+        let p = UsbMidiEventPacket::from_bytes([0x19, 0x90, 0x3C, 0x7F]);
+        assert_eq!(p.cable, 1);
+        assert_eq!(p.code_index, CodeIndex::NoteOn);
+        assert_eq!(p.message(), &[0x90, 0x3C, 0x7F]);
+        assert_eq!(p.to_bytes(), [0x19, 0x90, 0x3C, 0x7F]);
+
+        let p = UsbMidiEventPacket::from_bytes([0x0C, 0xC0, 0x05, 0x00]);
+        assert_eq!(p.message(), &[0xC0, 0x05]);
+    }
+
+    #[test]
+    fn packet_from_message() {
+        assert_eq!(
+            UsbMidiEventPacket::from_message(2, &[0xB1, 0x07, 0x64])
+                .unwrap()
+                .to_bytes(),
+            [0x2B, 0xB1, 0x07, 0x64]
+        );
+        assert_eq!(
+            UsbMidiEventPacket::from_message(0, &[0xF8]).unwrap().to_bytes(),
+            [0x0F, 0xF8, 0, 0]
+        );
+        assert_eq!(
+            UsbMidiEventPacket::from_message(0, &[0xF3, 0x01]).unwrap().to_bytes(),
+            [0x02, 0xF3, 0x01, 0]
+        );
+        assert!(UsbMidiEventPacket::from_message(0, &[0x90, 0x3C]).is_none());
+        assert!(UsbMidiEventPacket::from_message(0, &[0x3C, 0x7F]).is_none());
+        assert!(UsbMidiEventPacket::from_message(16, &[0xF8]).is_none());
+    }
+
+    #[test]
+    fn encode_sysex() {
+        let mut buf = [0u8; 16];
+        let n = encode_message(3, &[0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7], &mut buf).unwrap();
+        assert_eq!(n, 8);
+        assert_eq!(buf[..8], [0x34, 0xF0, 0x7E, 0x7F, 0x37, 0x06, 0x01, 0xF7]);
+
+        let n = encode_message(0, &[0xF0, 0x01, 0x02, 0xF7], &mut buf).unwrap();
+        assert_eq!(buf[..n], [0x04, 0xF0, 0x01, 0x02, 0x05, 0xF7, 0, 0]);
+
+        assert!(encode_message(0, &[0xF0, 0x01], &mut buf).is_none());
+        assert!(encode_message(0, &[0xF0, 0x01, 0x02, 0xF7], &mut buf[..4]).is_none());
+    }
+
+    #[test]
+    fn sysex_reassembly() {
+        let mut buf = [0u8; 16];
+        let msg = [0xF0, 0x43, 0x10, 0x4C, 0x00, 0xF7];
+        let n = encode_message(1, &msg, &mut buf).unwrap();
+
+        let mut asm = SysExAssembler::<16>::new(1);
+        let mut it = packets(&buf[..n]);
+        assert_eq!(asm.push(&it.next().unwrap()), Ok(None));
+        // Real-time and other cables interleave freely.
+        assert_eq!(
+            asm.push(&UsbMidiEventPacket::from_message(1, &[0xF8]).unwrap()),
+            Ok(None)
+        );
+        assert_eq!(
+            asm.push(&UsbMidiEventPacket::from_bytes([0x07, 0xF0, 0x01, 0xF7])),
+            Ok(None)
+        );
+        assert_eq!(asm.push(&it.next().unwrap()), Ok(Some(&msg[..])));
+        assert!(it.next().is_none());
+
+        // Short SysEx in a single packet.
+        assert_eq!(
+            asm.push(&UsbMidiEventPacket::from_bytes([0x17, 0xF0, 0x01, 0xF7])),
+            Ok(Some(&[0xF0, 0x01, 0xF7][..]))
+        );
+    }
+
+    #[test]
+    fn sysex_overflow() {
+        let mut buf = [0u8; 16];
+        let n = encode_message(0, &[0xF0, 1, 2, 3, 4, 5, 6, 0xF7], &mut buf).unwrap();
+        let mut asm = SysExAssembler::<4>::new(0);
+        let results: Vec<_, 4> = packets(&buf[..n]).map(|p| asm.push(&p).map(|r| r.is_some())).collect();
+        assert_eq!(&results[..], &[Ok(false), Ok(false), Err(SysExError::Overflow)]);
+    }
+
+    #[test]
+    fn packets_skip_padding() {
+        let buf = [0x09, 0x90, 0x3C, 0x7F, 0, 0, 0, 0, 0x08, 0x80, 0x3C, 0x00, 0xAA];
+        let mut it = packets(&buf);
+        assert_eq!(it.next().unwrap().code_index, CodeIndex::NoteOn);
+        assert_eq!(it.next().unwrap().code_index, CodeIndex::NoteOff);
+        assert!(it.next().is_none());
+    }
+}
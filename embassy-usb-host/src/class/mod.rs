@@ -1,11 +1,17 @@
 //! USB host class drivers.
 
 pub mod cdc_acm;
+pub mod cdc_net;
+pub mod dfu;
 pub mod gip;
 pub mod hid;
+pub mod hid_input;
 pub mod hid_report;
 pub mod hub;
 pub mod kbd;
+pub mod midi;
 pub mod msc;
+pub mod printer;
 pub mod uac;
+pub mod uvc;
 pub mod vcp;
@@ -0,0 +1,500 @@
+//! USB printer host class driver.
+//!
+//! This driver can communicate with USB printers (USB Printer Class 1.1),
+//! such as receipt and label printers.
+
+use bitflags::bitflags;
+use embassy_time::{Duration, with_timeout};
+use embassy_usb_driver::host::{PipeError, UsbHostAllocator, UsbPipe, pipe};
+use embassy_usb_driver::{Direction as UsbDirection, EndpointAddress, EndpointInfo, EndpointType};
+
+use crate::control::SetupPacket;
+use crate::descriptor::ConfigurationDescriptorChain;
+use crate::handler::EnumerationInfo;
+
+/// Printer class code.
+const USB_CLASS_PRINTER: u8 = 0x07;
+/// Printer subclass.
+const PRINTER_SUBCLASS: u8 = 0x01;
+/// Bulk transfer type.
+const TRANSFER_BULK: u8 = 0x02;
+
+/// Printer class request: GET_DEVICE_ID.
+const REQ_GET_DEVICE_ID: u8 = 0x00;
+/// Printer class request: GET_PORT_STATUS.
+const REQ_GET_PORT_STATUS: u8 = 0x01;
+/// Printer class request: SOFT_RESET.
+const REQ_SOFT_RESET: u8 = 0x02;
+
+/// Default timeout of [`PrinterHost::write`].
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Printer interface protocol.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PrinterProtocol {
+    /// Bulk OUT only.
+    Unidirectional,
+    /// Bulk OUT and bulk IN.
+    Bidirectional,
+    /// IEEE 1284.4 compatible bidirectional interface.
+    Ieee1284_4,
+    /// Unknown protocol.
+    Other(u8),
+}
+
+impl From<u8> for PrinterProtocol {
+    fn from(v: u8) -> Self {
+        match v {
+            0x01 => Self::Unidirectional,
+            0x02 => Self::Bidirectional,
+            0x03 => Self::Ieee1284_4,
+            v => Self::Other(v),
+        }
+    }
+}
+
+/// Printer host class driver error.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PrinterError {
+    /// Transfer error.
+    Transfer(PipeError),
+    /// No printer interface found in the device.
+    NoInterface,
+    /// Failed to allocate a pipe.
+    NoPipe,
+    /// The printer has no bulk IN endpoint.
+    Unidirectional,
+    /// The transfer did not complete in time.
+    Timeout,
+    /// The device ID returned by the printer is malformed.
+    InvalidDeviceId,
+}
+
+impl From<PipeError> for PrinterError {
+    fn from(e: PipeError) -> Self {
+        Self::Transfer(e)
+    }
+}
+
+impl core::fmt::Display for PrinterError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Transfer(_e) => write!(f, "Transfer error"),
+            Self::NoInterface => write!(f, "No printer interface found"),
+            Self::NoPipe => write!(f, "No free pipe"),
+            Self::Unidirectional => write!(f, "Printer is unidirectional"),
+            Self::Timeout => write!(f, "Transfer timed out"),
+            Self::InvalidDeviceId => write!(f, "Invalid device ID"),
+        }
+    }
+}
+
+impl core::error::Error for PrinterError {}
+
+impl embedded_io_async::Error for PrinterError {
+    fn kind(&self) -> embedded_io_async::ErrorKind {
+        match self {
+            Self::Transfer(e) => match e {
+                PipeError::Disconnected => embedded_io_async::ErrorKind::NotConnected,
+                PipeError::BufferOverflow => embedded_io_async::ErrorKind::OutOfMemory,
+                PipeError::Timeout => embedded_io_async::ErrorKind::TimedOut,
+                _ => embedded_io_async::ErrorKind::Other,
+            },
+            Self::NoInterface => embedded_io_async::ErrorKind::NotFound,
+            Self::NoPipe => embedded_io_async::ErrorKind::OutOfMemory,
+            Self::Unidirectional => embedded_io_async::ErrorKind::Unsupported,
+            Self::Timeout => embedded_io_async::ErrorKind::TimedOut,
+            Self::InvalidDeviceId => embedded_io_async::ErrorKind::InvalidData,
+        }
+    }
+}
+
+bitflags! {
+    /// Printer port status, as returned by GET_PORT_STATUS (USB Printer Class 1.1 §4.2.2).
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct PortStatus: u8 {
+        /// The printer is out of paper.
+        const PAPER_EMPTY = 1 << 5;
+        /// The printer is selected (online).
+        const SELECTED = 1 << 4;
+        /// Cleared when the printer is in an error state.
+        const NOT_ERROR = 1 << 3;
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for PortStatus {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "PortStatus({=u8:b})", self.bits());
+    }
+}
+
+impl PortStatus {
+    /// Whether the printer reports an error.
+    pub fn is_error(&self) -> bool {
+        !self.contains(Self::NOT_ERROR)
+    }
+}
+
+/// IEEE 1284 device ID returned by GET_DEVICE_ID.
+///
+/// The ID is a sequence of `KEY:value;` pairs, e.g.
+/// `MFG:ACME;MDL:Receipt 80;CMD:ESC/POS;CLS:PRINTER;`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceId<'a>(&'a str);
+
+impl<'a> DeviceId<'a> {
+    /// Parse a GET_DEVICE_ID response: a big-endian length, which includes
+    /// its own two bytes, followed by the ID string.
+    ///
+    /// A length larger than `buf` is clamped, since some printers report the
+    /// full length even when the request was shorter.
+    pub fn parse(buf: &'a [u8]) -> Result<Self, PrinterError> {
+        let len = u16::from_be_bytes(buf.get(..2).ok_or(PrinterError::InvalidDeviceId)?.try_into().unwrap());
+        let len = (len as usize).clamp(2, buf.len());
+        let s = core::str::from_utf8(&buf[2..len]).map_err(|_| PrinterError::InvalidDeviceId)?;
+        Ok(Self(s.trim_end_matches('\0')))
+    }
+
+    /// The raw ID string.
+    pub fn as_str(&self) -> &'a str {
+        self.0
+    }
+
+    /// Iterate over the `(key, value)` pairs, trimmed of surrounding spaces.
+    pub fn fields(&self) -> impl Iterator<Item = (&'a str, &'a str)> {
+        self.0.split(';').filter_map(|f| {
+            let (k, v) = f.split_once(':')?;
+            Some((k.trim(), v.trim()))
+        })
+    }
+
+    /// Value of the first key matching any of `keys`, compared case-insensitively.
+    pub fn get(&self, keys: &[&str]) -> Option<&'a str> {
+        self.fields()
+            .find(|(k, _)| keys.iter().any(|key| k.eq_ignore_ascii_case(key)))
+            .map(|(_, v)| v)
+    }
+
+    /// Manufacturer (`MANUFACTURER` or `MFG`).
+    pub fn manufacturer(&self) -> Option<&'a str> {
+        self.get(&["MANUFACTURER", "MFG"])
+    }
+
+    /// Model (`MODEL` or `MDL`).
+    pub fn model(&self) -> Option<&'a str> {
+        self.get(&["MODEL", "MDL"])
+    }
+
+    /// Comma-separated command sets (`COMMAND SET` or `CMD`).
+    pub fn command_set(&self) -> Option<&'a str> {
+        self.get(&["COMMAND SET", "CMD"])
+    }
+
+    /// Device class (`CLASS` or `CLS`).
+    pub fn class(&self) -> Option<&'a str> {
+        self.get(&["CLASS", "CLS"])
+    }
+
+    /// Description (`DESCRIPTION` or `DES`).
+    pub fn description(&self) -> Option<&'a str> {
+        self.get(&["DESCRIPTION", "DES"])
+    }
+}
+
+/// Information about a printer interface found in a configuration descriptor.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PrinterInfo {
+    /// Printer interface number.
+    pub interface: u8,
+    /// Alternate setting of the selected protocol.
+    pub alternate_setting: u8,
+    /// Interface protocol.
+    pub protocol: PrinterProtocol,
+    /// Bulk OUT endpoint address.
+    pub bulk_out_ep: u8,
+    /// Bulk OUT max packet size.
+    pub bulk_out_mps: u16,
+    /// Bulk IN endpoint address, for bidirectional printers.
+    pub bulk_in_ep: Option<u8>,
+    /// Bulk IN max packet size.
+    pub bulk_in_mps: u16,
+}
+
+/// Find a printer interface in a configuration descriptor.
+///
+/// When several alternate settings are offered, a bidirectional one is preferred.
+pub fn find_printer(config_desc: &[u8]) -> Option<PrinterInfo> {
+    let cfg = ConfigurationDescriptorChain::try_from_slice(config_desc).ok()?;
+
+    let mut best: Option<PrinterInfo> = None;
+    for iface in cfg.iter_interface() {
+        if iface.interface_class != USB_CLASS_PRINTER || iface.interface_subclass != PRINTER_SUBCLASS {
+            continue;
+        }
+        if best.as_ref().is_some_and(|b| b.interface != iface.interface_number) {
+            break;
+        }
+
+        let mut bulk_in: Option<(u8, u16)> = None;
+        let mut bulk_out: Option<(u8, u16)> = None;
+        for ep in iface.iter_endpoints() {
+            if ep.transfer_type() == TRANSFER_BULK {
+                if ep.is_in() {
+                    bulk_in.get_or_insert((ep.endpoint_address, ep.max_packet_size));
+                } else {
+                    bulk_out.get_or_insert((ep.endpoint_address, ep.max_packet_size));
+                }
+            }
+        }
+        let Some((out_ep, out_mps)) = bulk_out else { continue };
+        if best
+            .as_ref()
+            .is_some_and(|b| b.bulk_in_ep.is_some() || bulk_in.is_none())
+        {
+            continue;
+        }
+        best = Some(PrinterInfo {
+            interface: iface.interface_number,
+            alternate_setting: iface.alternate_setting,
+            protocol: iface.interface_protocol.into(),
+            bulk_out_ep: out_ep,
+            bulk_out_mps: out_mps,
+            bulk_in_ep: bulk_in.map(|(ep, _)| ep),
+            bulk_in_mps: bulk_in.map_or(0, |(_, mps)| mps),
+        });
+    }
+    best
+}
+
+/// Printer host driver.
+///
+/// Sends print data to a USB printer and queries its status.
+pub struct PrinterHost<'d, A: UsbHostAllocator<'d>> {
+    ctrl_ch: A::Pipe<pipe::Control, pipe::InOut>,
+    out_ch: A::Pipe<pipe::Bulk, pipe::Out>,
+    in_ch: Option<A::Pipe<pipe::Bulk, pipe::In>>,
+    info: PrinterInfo,
+    write_timeout: Duration,
+    _phantom: core::marker::PhantomData<&'d ()>,
+}
+
+impl<'d, A: UsbHostAllocator<'d>> PrinterHost<'d, A> {
+    /// Create a new printer host driver.
+    ///
+    /// Parses the config descriptor to find the printer endpoints and allocates channels.
+    /// Call [`enable`](Self::enable) before use.
+    pub fn new(alloc: &A, config_desc: &[u8], enum_info: &EnumerationInfo) -> Result<Self, PrinterError> {
+        let info = find_printer(config_desc).ok_or(PrinterError::NoInterface)?;
+
+        let ctrl_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts(0, UsbDirection::In),
+            ep_type: EndpointType::Control,
+            max_packet_size: enum_info.device_desc.max_packet_size0 as u16,
+            interval_ms: 0,
+        };
+
+        let out_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts((info.bulk_out_ep & 0x0F) as usize, UsbDirection::Out),
+            ep_type: EndpointType::Bulk,
+            max_packet_size: info.bulk_out_mps,
+            interval_ms: 0,
+        };
+
+        let device_address = enum_info.device_address;
+        let split = enum_info.split();
+
+        let ctrl_ch = alloc
+            .alloc_pipe::<pipe::Control, pipe::InOut>(device_address, &ctrl_ep_info, split)
+            .map_err(|_| PrinterError::NoPipe)?;
+        let out_ch = alloc
+            .alloc_pipe::<pipe::Bulk, pipe::Out>(device_address, &out_ep_info, split)
+            .map_err(|_| PrinterError::NoPipe)?;
+        let in_ch = match info.bulk_in_ep {
+            Some(ep) => {
+                let in_ep_info = EndpointInfo {
+                    addr: EndpointAddress::from_parts((ep & 0x0F) as usize, UsbDirection::In),
+                    ep_type: EndpointType::Bulk,
+                    max_packet_size: info.bulk_in_mps,
+                    interval_ms: 0,
+                };
+                Some(
+                    alloc
+                        .alloc_pipe::<pipe::Bulk, pipe::In>(device_address, &in_ep_info, split)
+                        .map_err(|_| PrinterError::NoPipe)?,
+                )
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            ctrl_ch,
+            out_ch,
+            in_ch,
+            info,
+            write_timeout: DEFAULT_WRITE_TIMEOUT,
+            _phantom: core::marker::PhantomData,
+        })
+    }
+
+    /// Select the alternate setting of the chosen protocol.
+    pub async fn enable(&mut self) -> Result<(), PrinterError> {
+        if self.info.alternate_setting != 0 {
+            let setup = SetupPacket::set_interface(self.info.interface as u16, self.info.alternate_setting);
+            self.ctrl_ch.control_out(&setup.to_bytes(), &[]).await?;
+        }
+        Ok(())
+    }
+
+    /// Interface and endpoint information.
+    pub fn info(&self) -> &PrinterInfo {
+        &self.info
+    }
+
+    /// Set the timeout applied to each [`write`](Self::write).
+    pub fn set_write_timeout(&mut self, timeout: Duration) {
+        self.write_timeout = timeout;
+    }
+
+    /// Read the IEEE 1284 device ID into `buf` and parse it.
+    ///
+    /// `buf` is limited to 1023 bytes; longer IDs are truncated.
+    pub async fn device_id<'b>(&mut self, buf: &'b mut [u8]) -> Result<DeviceId<'b>, PrinterError> {
+        let len = buf.len().min(1023);
+        let index = ((self.info.interface as u16) << 8) | self.info.alternate_setting as u16;
+        let setup = SetupPacket::class_interface_in(REQ_GET_DEVICE_ID, 0, index, len as u16);
+        let n = self.ctrl_ch.control_in(&setup.to_bytes(), &mut buf[..len]).await?;
+        DeviceId::parse(&buf[..n])
+    }
+
+    /// Read the port status.
+    pub async fn port_status(&mut self) -> Result<PortStatus, PrinterError> {
+        let setup = SetupPacket::class_interface_in(REQ_GET_PORT_STATUS, 0, self.info.interface as u16, 1);
+        let mut buf = [0u8; 1];
+        self.ctrl_ch.control_in(&setup.to_bytes(), &mut buf).await?;
+        Ok(PortStatus::from_bits_truncate(buf[0]))
+    }
+
+    /// Flush the printer's buffers and reset its bulk endpoints.
+    pub async fn soft_reset(&mut self) -> Result<(), PrinterError> {
+        let setup = SetupPacket::class_interface_out(REQ_SOFT_RESET, 0, self.info.interface as u16, 0);
+        self.ctrl_ch.control_out(&setup.to_bytes(), &[]).await?;
+        Ok(())
+    }
+
+    /// Send print data.
+    ///
+    /// Fails with [`PrinterError::Timeout`] if the printer does not accept the
+    /// data within the write timeout, e.g. because it is out of paper.
+    pub async fn write(&mut self, data: &[u8]) -> Result<usize, PrinterError> {
+        with_timeout(self.write_timeout, self.out_ch.request_out(data, true))
+            .await
+            .map_err(|_| PrinterError::Timeout)??;
+        Ok(data.len())
+    }
+
+    /// Read status data from a bidirectional printer.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, PrinterError> {
+        let ch = self.in_ch.as_mut().ok_or(PrinterError::Unidirectional)?;
+        Ok(ch.request_in(buf).await?)
+    }
+}
+
+impl<'d, A: UsbHostAllocator<'d>> embedded_io_async::ErrorType for PrinterHost<'d, A> {
+    type Error = PrinterError;
+}
+
+impl<'d, A: UsbHostAllocator<'d>> embedded_io_async::Read for PrinterHost<'d, A> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        PrinterHost::read(self, buf).await
+    }
+}
+
+impl<'d, A: UsbHostAllocator<'d>> embedded_io_async::Write for PrinterHost<'d, A> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        PrinterHost::write(self, buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        // USB bulk transfers are flushed immediately
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Printer interface with a unidirectional alt 0 and a bidirectional alt 1.
+    #[rustfmt::skip]
+    const CFG_PRINTER: [u8; 48] = [
+        9, 0x02, 48, 0, 1, 1, 0, 0xC0, 1,
+        9, 0x04, 0, 0, 1, 0x07, 0x01, 0x01, 0,
+        7, 0x05, 0x01, 0x02, 0x40, 0x00, 0,
+        9, 0x04, 0, 1, 2, 0x07, 0x01, 0x02, 0,
+        7, 0x05, 0x02, 0x02, 0x40, 0x00, 0,
+        7, 0x05, 0x82, 0x02, 0x40, 0x00, 0,
+    ];
+
+    #[test]
+    fn find_printer_prefers_bidirectional() {
+        let info = find_printer(&CFG_PRINTER).unwrap();
+        assert_eq!(info.interface, 0);
+        assert_eq!(info.alternate_setting, 1);
+        assert_eq!(info.protocol, PrinterProtocol::Bidirectional);
+        assert_eq!(info.bulk_out_ep, 0x02);
+        assert_eq!(info.bulk_in_ep, Some(0x82));
+        assert_eq!(info.bulk_in_mps, 64);
+    }
+
+    #[test]
+    fn find_printer_unidirectional() {
+        let mut cfg = [0u8; 25];
+        cfg.copy_from_slice(&CFG_PRINTER[..25]);
+        cfg[2] = 25;
+        let info = find_printer(&cfg).unwrap();
+        assert_eq!(info.alternate_setting, 0);
+        assert_eq!(info.protocol, PrinterProtocol::Unidirectional);
+        assert_eq!(info.bulk_out_ep, 0x01);
+        assert_eq!(info.bulk_in_ep, None);
+
+        // Not a printer.
+        cfg[14] = 0x08;
+        assert!(find_printer(&cfg).is_none());
+    }
+
+    #[test]
+    fn parse_device_id() {
+        let s = b"MFG:ACME;MDL:Receipt 80; CMD:ESC/POS,TEXT;CLASS:PRINTER;";
+        let mut buf = [0u8; 64];
+        buf[..2].copy_from_slice(&((s.len() + 2) as u16).to_be_bytes());
+        buf[2..2 + s.len()].copy_from_slice(s);
+        let id = DeviceId::parse(&buf[..2 + s.len()]).unwrap();
+        assert_eq!(id.manufacturer(), Some("ACME"));
+        assert_eq!(id.model(), Some("Receipt 80"));
+        assert_eq!(id.command_set(), Some("ESC/POS,TEXT"));
+        assert_eq!(id.class(), Some("PRINTER"));
+        assert_eq!(id.description(), None);
+        assert_eq!(id.fields().count(), 4);
+
+        // Reported length exceeds the transfer.
+        let id = DeviceId::parse(&buf[..10]).unwrap();
+        assert_eq!(id.as_str(), "MFG:ACME");
+        assert_eq!(id.manufacturer(), Some("ACME"));
+
+        assert!(matches!(DeviceId::parse(&buf[..1]), Err(PrinterError::InvalidDeviceId)));
+    }
+
+    #[test]
+    fn port_status() {
+        let s = PortStatus::from_bits_truncate(0x18);
+        assert!(s.contains(PortStatus::SELECTED));
+        assert!(!s.contains(PortStatus::PAPER_EMPTY));
+        assert!(!s.is_error());
+        assert!(PortStatus::from_bits_truncate(0x30).is_error());
+    }
+}
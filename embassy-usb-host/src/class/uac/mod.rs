@@ -904,15 +904,15 @@ impl Layout1ParameterBlock {
             return None;
         }
         let mut ranges = Vec::new();
-        let mut bytes = &bytes[2..];
-        for _ in 0..num_ranges {
+        // The response may be shorter than the number of ranges it announces.
+        let data = bytes.get(2..2 + num_ranges as usize * 3)?;
+        for bytes in data.chunks_exact(3) {
             let range = Range1 {
                 min: bytes[0],
                 max: bytes[1],
                 step: bytes[2],
             };
             ranges.push(range).unwrap();
-            bytes = &bytes[3..];
         }
         Some(Self { ranges })
     }
@@ -941,15 +941,15 @@ impl Layout2ParameterBlock {
             return None;
         }
         let mut ranges = Vec::new();
-        let mut bytes = &bytes[2..];
-        for _ in 0..num_ranges {
+        // The response may be shorter than the number of ranges it announces.
+        let data = bytes.get(2..2 + num_ranges as usize * 6)?;
+        for bytes in data.chunks_exact(6) {
             let range = Range2 {
                 min: u16::from_le_bytes([bytes[0], bytes[1]]),
                 max: u16::from_le_bytes([bytes[2], bytes[3]]),
                 step: u16::from_le_bytes([bytes[4], bytes[5]]),
             };
             ranges.push(range).unwrap();
-            bytes = &bytes[6..];
         }
         Some(Self { ranges })
     }
@@ -978,15 +978,15 @@ impl Layout3ParameterBlock {
             );
             return None;
         }
-        let mut bytes = &bytes[2..];
-        for _ in 0..num_ranges {
+        // The response may be shorter than the number of ranges it announces.
+        let data = bytes.get(2..2 + num_ranges as usize * 12)?;
+        for bytes in data.chunks_exact(12) {
             let range = Range4 {
                 min: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
                 max: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
                 step: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
             };
             ranges.push(range).unwrap();
-            bytes = &bytes[12..];
         }
         Some(Self { ranges })
     }
@@ -0,0 +1,1163 @@
+//! USB Video Class host driver.
+//!
+//! Captures video from USB Video Class 1.0, 1.1 and 1.5 cameras, such as webcams.
+//!
+//! [`find_uvc`] parses the VideoControl and VideoStreaming descriptors into the MJPEG
+//! and YUY2 formats and frame sizes the camera offers. [`UvcHost::negotiate`] agrees
+//! on the format, frame size and frame interval with the probe and commit controls,
+//! [`UvcHost::start`] selects the alternate setting with enough isochronous bandwidth
+//! for the committed payload size, and [`UvcHost::read_frame`] reassembles the
+//! payloads into complete frames.
+//!
+//! ```rust,ignore
+//! let mut uvc = UvcHost::new(&alloc, config_desc, &enum_info)?;
+//! let (format, frame) = uvc.info().find_frame(Encoding::Mjpeg, 640, 480).unwrap();
+//! let (format, frame, interval) = (format.index, frame.index, frame.default_interval);
+//! uvc.negotiate(format, frame, interval).await?;
+//! uvc.start(&alloc).await?;
+//! loop {
+//!     let frame = uvc.read_frame(&mut buf).await?;
+//!     process(&buf[..frame.len]);
+//! }
+//! ```
+
+pub use embassy_usb::class::uvc::{Encoding, ProbeCommit, TransferMode};
+use embassy_usb::control::Request;
+use embassy_usb_driver::host::{PipeError, SplitInfo, UsbHostAllocator, UsbPipe, pipe};
+use embassy_usb_driver::{Direction as UsbDirection, EndpointAddress, EndpointInfo, EndpointType};
+use heapless::Vec;
+
+use crate::control::{ControlType, Recipient, RequestType, SetupPacket};
+use crate::descriptor::{ConfigurationDescriptorChain, EndpointDescriptor};
+use crate::handler::EnumerationInfo;
+
+/// Video interface class code.
+const CC_VIDEO: u8 = 0x0E;
+/// VideoControl interface subclass.
+const SC_VIDEOCONTROL: u8 = 0x01;
+/// VideoStreaming interface subclass.
+const SC_VIDEOSTREAMING: u8 = 0x02;
+/// Class-specific interface descriptor type.
+const CS_INTERFACE: u8 = 0x24;
+
+/// VideoControl header descriptor subtype.
+const VC_HEADER: u8 = 0x01;
+/// Uncompressed format descriptor subtype.
+const VS_FORMAT_UNCOMPRESSED: u8 = 0x04;
+/// Uncompressed frame descriptor subtype.
+const VS_FRAME_UNCOMPRESSED: u8 = 0x05;
+/// MJPEG format descriptor subtype.
+const VS_FORMAT_MJPEG: u8 = 0x06;
+/// MJPEG frame descriptor subtype.
+const VS_FRAME_MJPEG: u8 = 0x07;
+
+/// Class request: SET_CUR.
+const SET_CUR: u8 = 0x01;
+/// Class request: GET_CUR.
+const GET_CUR: u8 = 0x81;
+/// VideoStreaming control selector: probe.
+const VS_PROBE_CONTROL: u8 = 0x01;
+/// VideoStreaming control selector: commit.
+const VS_COMMIT_CONTROL: u8 = 0x02;
+
+/// Payload header `bmHeaderInfo`: frame ID, toggled for every frame.
+const HEADER_FID: u8 = 1 << 0;
+/// Payload header `bmHeaderInfo`: end of frame.
+const HEADER_EOF: u8 = 1 << 1;
+/// Payload header `bmHeaderInfo`: a presentation time stamp follows.
+const HEADER_PTS: u8 = 1 << 2;
+/// Payload header `bmHeaderInfo`: the camera failed to capture this frame.
+const HEADER_ERR: u8 = 1 << 6;
+
+/// GUID of the YUY2 uncompressed format.
+const GUID_YUY2: [u8; 16] = [
+    b'Y', b'U', b'Y', b'2', 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71,
+];
+
+/// Length of the probe and commit controls in UVC 1.5.
+const PROBE_LEN_1_5: usize = 48;
+
+/// Consecutive isochronous transfer errors [`UvcHost::read_frame`] tolerates before giving up.
+const MAX_CONSECUTIVE_ERRORS: usize = 8;
+
+/// The maximum number of supported formats kept from the descriptors.
+pub const MAX_FORMATS: usize = 4;
+
+/// The maximum number of frame sizes kept per format.
+pub const MAX_FRAMES: usize = 16;
+
+/// The maximum number of isochronous alternate settings kept.
+pub const MAX_ALT_SETTINGS: usize = 8;
+
+/// The largest payload read in one transfer: three 1024 byte high-bandwidth packets.
+pub const MAX_PAYLOAD_SIZE: usize = 3072;
+
+/// UVC host class driver error.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UvcError {
+    /// Transfer error.
+    Transfer(PipeError),
+    /// No video streaming interface found in the device.
+    NoInterface,
+    /// Failed to allocate a pipe.
+    NoPipe,
+    /// The camera does not offer the requested format or frame size.
+    UnsupportedFormat,
+    /// The camera returned a malformed or unknown probe control.
+    InvalidResponse,
+    /// No alternate setting offers the bandwidth the committed stream needs.
+    InsufficientBandwidth,
+    /// No streaming parameters have been committed.
+    NotCommitted,
+    /// The stream has not been started.
+    NotStreaming,
+    /// A frame did not fit in the buffer. The frame is dropped.
+    BufferTooSmall,
+}
+
+impl From<PipeError> for UvcError {
+    fn from(e: PipeError) -> Self {
+        Self::Transfer(e)
+    }
+}
+
+impl core::fmt::Display for UvcError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Transfer(_e) => write!(f, "Transfer error"),
+            Self::NoInterface => write!(f, "No video streaming interface found"),
+            Self::NoPipe => write!(f, "No free pipe"),
+            Self::UnsupportedFormat => write!(f, "Unsupported format or frame size"),
+            Self::InvalidResponse => write!(f, "Invalid probe control"),
+            Self::InsufficientBandwidth => write!(f, "Insufficient bandwidth"),
+            Self::NotCommitted => write!(f, "Streaming parameters not committed"),
+            Self::NotStreaming => write!(f, "Stream not started"),
+            Self::BufferTooSmall => write!(f, "Frame buffer too small"),
+        }
+    }
+}
+
+impl core::error::Error for UvcError {}
+
+/// A frame size from a frame descriptor.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FrameInfo {
+    /// `bFrameIndex`, 1-based.
+    pub index: u8,
+    /// Width in pixels.
+    pub width: u16,
+    /// Height in pixels.
+    pub height: u16,
+    /// Largest frame in bytes (`dwMaxVideoFrameBufferSize`).
+    pub max_frame_size: u32,
+    /// Default frame interval in 100 ns units, e.g. 333_333 for 30 fps.
+    pub default_interval: u32,
+    /// Shortest supported frame interval in 100 ns units.
+    pub min_interval: u32,
+    /// Longest supported frame interval in 100 ns units.
+    pub max_interval: u32,
+}
+
+/// A video format from a format descriptor, with its frame sizes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FormatInfo {
+    /// `bFormatIndex`, 1-based.
+    pub index: u8,
+    /// Encoding of the frames.
+    pub encoding: Encoding,
+    /// Bits per pixel of uncompressed frames, 0 for MJPEG.
+    pub bits_per_pixel: u8,
+    /// `bDefaultFrameIndex`.
+    pub default_frame_index: u8,
+    /// Frame sizes, at most [`MAX_FRAMES`].
+    pub frames: Vec<FrameInfo, MAX_FRAMES>,
+}
+
+impl FormatInfo {
+    /// The frame with `bFrameIndex` `index`.
+    pub fn frame(&self, index: u8) -> Option<&FrameInfo> {
+        self.frames.iter().find(|f| f.index == index)
+    }
+
+    /// Exact size of a frame in bytes, for uncompressed formats.
+    pub fn frame_len(&self, frame: &FrameInfo) -> Option<usize> {
+        match self.encoding {
+            Encoding::Mjpeg => None,
+            Encoding::Yuy2 => Some(frame.width as usize * frame.height as usize * self.bits_per_pixel as usize / 8),
+        }
+    }
+}
+
+/// An isochronous alternate setting of the streaming interface.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AltSetting {
+    /// Alternate setting number.
+    pub alternate_setting: u8,
+    /// The video data endpoint.
+    pub endpoint: EndpointDescriptor,
+}
+
+impl AltSetting {
+    /// Bytes per (micro)frame, including additional high-bandwidth transactions.
+    pub fn bandwidth(&self) -> usize {
+        let mps = self.endpoint.max_packet_size;
+        (mps & 0x7FF) as usize * (1 + ((mps >> 11) & 0x3) as usize)
+    }
+}
+
+/// Information about a video function found in a configuration descriptor.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UvcInfo {
+    /// VideoControl interface number.
+    pub control_interface: u8,
+    /// VideoStreaming interface number.
+    pub streaming_interface: u8,
+    /// UVC specification release in BCD, e.g. `0x0110` for UVC 1.1.
+    pub uvc_version: u16,
+    /// Whether the video data is isochronous or bulk.
+    pub transfer_mode: TransferMode,
+    /// Bulk video data endpoint, in alternate setting 0.
+    pub bulk_endpoint: Option<EndpointDescriptor>,
+    /// Isochronous alternate settings, in descriptor order.
+    pub alt_settings: Vec<AltSetting, MAX_ALT_SETTINGS>,
+    /// MJPEG and YUY2 formats. Other formats are skipped.
+    pub formats: Vec<FormatInfo, MAX_FORMATS>,
+}
+
+impl UvcInfo {
+    /// The format with `bFormatIndex` `index`.
+    pub fn format(&self, index: u8) -> Option<&FormatInfo> {
+        self.formats.iter().find(|f| f.index == index)
+    }
+
+    /// Find a format with `encoding` offering a `width` x `height` frame size.
+    pub fn find_frame(&self, encoding: Encoding, width: u16, height: u16) -> Option<(&FormatInfo, &FrameInfo)> {
+        self.formats
+            .iter()
+            .filter(|f| f.encoding == encoding)
+            .find_map(|format| {
+                let frame = format.frames.iter().find(|f| (f.width, f.height) == (width, height))?;
+                Some((format, frame))
+            })
+    }
+
+    /// The alternate setting with the least bandwidth still fitting payloads of
+    /// `payload_size` bytes.
+    ///
+    /// Some cameras report a payload size of 0; the alternate setting with the most
+    /// bandwidth is returned then.
+    pub fn alt_setting_for(&self, payload_size: u32) -> Option<&AltSetting> {
+        if payload_size == 0 {
+            return self.alt_settings.iter().max_by_key(|a| a.bandwidth());
+        }
+        self.alt_settings
+            .iter()
+            .filter(|a| a.bandwidth() >= payload_size as usize)
+            .min_by_key(|a| a.bandwidth())
+    }
+}
+
+/// Find the first video function in a configuration descriptor.
+///
+/// The streaming interface is the first one listed in the VideoControl header.
+/// Functions without an MJPEG or YUY2 format, or without a video endpoint, are skipped.
+pub fn find_uvc(config_desc: &[u8]) -> Option<UvcInfo> {
+    let cfg = ConfigurationDescriptorChain::try_from_slice(config_desc).ok()?;
+
+    let mut found: Option<UvcInfo> = None;
+    for iface in cfg.iter_interface() {
+        if iface.interface_class != CC_VIDEO {
+            continue;
+        }
+
+        if iface.interface_subclass == SC_VIDEOCONTROL {
+            if found.as_ref().is_some_and(is_complete) {
+                break;
+            }
+            // VC header: bcdUVC at 3, bInCollection at 11, baInterfaceNr from 12.
+            found = iface
+                .iter_descriptors()
+                .find(|(_, d)| d.len() >= 13 && d[1] == CS_INTERFACE && d[2] == VC_HEADER && d[11] > 0)
+                .map(|(_, d)| UvcInfo {
+                    control_interface: iface.interface_number,
+                    streaming_interface: d[12],
+                    uvc_version: u16::from_le_bytes([d[3], d[4]]),
+                    transfer_mode: TransferMode::Isochronous,
+                    bulk_endpoint: None,
+                    alt_settings: Vec::new(),
+                    formats: Vec::new(),
+                });
+            continue;
+        }
+
+        let Some(info) = found.as_mut() else { continue };
+        if iface.interface_subclass != SC_VIDEOSTREAMING || iface.interface_number != info.streaming_interface {
+            continue;
+        }
+
+        if iface.alternate_setting == 0 {
+            parse_formats(iface.iter_descriptors().map(|(_, d)| d), &mut info.formats);
+            if let Some(ep) = iface
+                .iter_endpoints()
+                .find(|ep| ep.is_in() && ep.ep_type() == EndpointType::Bulk)
+            {
+                info.transfer_mode = TransferMode::Bulk;
+                info.bulk_endpoint = Some(ep);
+            }
+        } else if let Some(ep) = iface
+            .iter_endpoints()
+            .find(|ep| ep.is_in() && ep.ep_type() == EndpointType::Isochronous)
+        {
+            // Alternate settings beyond the limit only offer more bandwidth than most hosts can use.
+            let _ = info.alt_settings.push(AltSetting {
+                alternate_setting: iface.alternate_setting,
+                endpoint: ep,
+            });
+        }
+    }
+    found.filter(is_complete)
+}
+
+fn is_complete(info: &UvcInfo) -> bool {
+    !info.formats.is_empty()
+        && match info.transfer_mode {
+            TransferMode::Isochronous => !info.alt_settings.is_empty(),
+            TransferMode::Bulk => info.bulk_endpoint.is_some(),
+        }
+}
+
+/// Collects the MJPEG and YUY2 formats and their frames from the class-specific
+/// descriptors of the streaming interface.
+fn parse_formats<'a>(descriptors: impl Iterator<Item = &'a [u8]>, formats: &mut Vec<FormatInfo, MAX_FORMATS>) {
+    // Frame descriptors belong to the format descriptor preceding them.
+    let mut current: Option<(u8, usize)> = None;
+    for d in descriptors {
+        if d.len() < 3 || d[1] != CS_INTERFACE {
+            continue;
+        }
+        let format = match d[2] {
+            VS_FORMAT_MJPEG if d.len() >= 11 => Some((Encoding::Mjpeg, 0, d[6])),
+            VS_FORMAT_UNCOMPRESSED if d.len() >= 27 && d[5..21] == GUID_YUY2 => Some((Encoding::Yuy2, d[21], d[22])),
+            VS_FORMAT_UNCOMPRESSED => None,
+            VS_FRAME_MJPEG | VS_FRAME_UNCOMPRESSED => {
+                if let Some((subtype, i)) = current
+                    && subtype + 1 == d[2]
+                    && let Some(frame) = parse_frame(d)
+                {
+                    let _ = formats[i].frames.push(frame);
+                }
+                continue;
+            }
+            // Other formats, such as frame-based H.264, end the current format too.
+            subtype if subtype >= VS_FORMAT_UNCOMPRESSED && subtype % 2 == 0 => None,
+            _ => continue,
+        };
+
+        current = None;
+        if let Some((encoding, bits_per_pixel, default_frame_index)) = format {
+            let format = FormatInfo {
+                index: d[3],
+                encoding,
+                bits_per_pixel,
+                default_frame_index,
+                frames: Vec::new(),
+            };
+            if formats.push(format).is_ok() {
+                current = Some((d[2], formats.len() - 1));
+            }
+        }
+    }
+}
+
+/// Parses an MJPEG or uncompressed frame descriptor.
+fn parse_frame(d: &[u8]) -> Option<FrameInfo> {
+    let u16_at = |i: usize| u16::from_le_bytes([d[i], d[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes([d[i], d[i + 1], d[i + 2], d[i + 3]]);
+    if d.len() < 26 {
+        return None;
+    }
+
+    let default_interval = u32_at(21);
+    let (min_interval, max_interval) = match d[25] {
+        // Continuous: min, max and step.
+        0 if d.len() >= 38 => (u32_at(26), u32_at(30)),
+        0 => (default_interval, default_interval),
+        n => (26..d.len().min(26 + 4 * n as usize))
+            .step_by(4)
+            .filter(|&i| i + 4 <= d.len())
+            .map(u32_at)
+            .fold((u32::MAX, 0), |(min, max), v| (min.min(v), max.max(v))),
+    };
+    let (min_interval, max_interval) = if min_interval > max_interval {
+        (default_interval, default_interval)
+    } else {
+        (min_interval, max_interval)
+    };
+
+    Some(FrameInfo {
+        index: d[3],
+        width: u16_at(5),
+        height: u16_at(7),
+        max_frame_size: u32_at(17),
+        default_interval,
+        min_interval,
+        max_interval,
+    })
+}
+
+/// A complete frame returned by [`UvcHost::read_frame`] or [`FrameAssembler::push`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Frame {
+    /// Length of the frame at the start of the buffer.
+    pub len: usize,
+    /// Presentation time stamp, in device clock ticks, if the camera sends one.
+    pub pts: Option<u32>,
+}
+
+/// Frame counters of a stream.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StreamStats {
+    /// Frames delivered.
+    pub frames: u32,
+    /// Frames dropped: flagged as erroneous by the camera, missing payloads, missing
+    /// end of frame, wrong size or too large for the buffer.
+    pub dropped: u32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Phase {
+    /// Waiting for a frame boundary, the current frame started before the stream.
+    Sync,
+    /// Between frames, after an end of frame.
+    Idle,
+    /// Receiving a frame.
+    Frame,
+}
+
+/// Reassembles frames from video payloads (UVC 1.1 §2.4.3.3).
+///
+/// Every payload starts with a header whose frame ID bit toggles with each frame.
+/// A frame ends with the end of frame bit, or when the frame ID toggles; the
+/// latter means payloads were lost, so the frame is dropped. Frames with the
+/// error bit set, frames of the wrong size and frames too large for the buffer
+/// are dropped too, and counted in [`StreamStats::dropped`].
+///
+/// The frame in progress when the stream started is discarded without counting it.
+pub struct FrameAssembler {
+    expected_len: Option<usize>,
+    phase: Phase,
+    fid: Option<bool>,
+    len: usize,
+    pts: Option<u32>,
+    error: bool,
+    overflow: bool,
+    // The current payload has a valid header and its data belongs to the frame.
+    in_payload: bool,
+    eof: bool,
+    stats: StreamStats,
+}
+
+impl FrameAssembler {
+    /// Create an assembler.
+    ///
+    /// `expected_len` is the exact frame size of uncompressed formats; frames of any
+    /// other size are dropped.
+    pub const fn new(expected_len: Option<usize>) -> Self {
+        Self {
+            expected_len,
+            phase: Phase::Sync,
+            fid: None,
+            len: 0,
+            pts: None,
+            error: false,
+            overflow: false,
+            in_payload: false,
+            eof: false,
+            stats: StreamStats { frames: 0, dropped: 0 },
+        }
+    }
+
+    /// Discard the frame in progress and wait for the next frame boundary.
+    pub fn reset(&mut self) {
+        *self = Self {
+            stats: self.stats,
+            ..Self::new(self.expected_len)
+        };
+    }
+
+    /// Frame counters.
+    pub fn stats(&self) -> StreamStats {
+        self.stats
+    }
+
+    /// Add a complete payload, header included, to the frame in `buf`.
+    ///
+    /// Returns the frame once its last payload was added. The same buffer must be
+    /// passed until a frame is returned. Empty payloads, which isochronous
+    /// endpoints send when there is no data, are ignored.
+    ///
+    /// Fails with [`UvcError::BufferTooSmall`] when the frame overflows `buf`; the
+    /// rest of that frame is discarded.
+    pub fn push(&mut self, payload: &[u8], buf: &mut [u8]) -> Result<Option<Frame>, UvcError> {
+        let res = self.start_payload(payload, buf);
+        let frame = self.end_payload();
+        res.map(|()| frame)
+    }
+
+    /// Notes that a payload was lost, e.g. due to a transfer error.
+    pub fn lost(&mut self) {
+        if self.phase == Phase::Frame {
+            self.error = true;
+        }
+    }
+
+    fn start_payload(&mut self, chunk: &[u8], buf: &mut [u8]) -> Result<(), UvcError> {
+        self.in_payload = false;
+        self.eof = false;
+        let Some(&header_len) = chunk.first() else {
+            return Ok(());
+        };
+        let header_len = header_len as usize;
+        if header_len < 2 || header_len > chunk.len() {
+            // The data of a payload without a valid header cannot be placed.
+            self.lost();
+            return Ok(());
+        }
+
+        let info = chunk[1];
+        let fid = info & HEADER_FID != 0;
+        match self.phase {
+            Phase::Sync if self.fid.is_some_and(|f| f != fid) => self.begin(fid),
+            Phase::Sync => {
+                self.fid = Some(fid);
+                if info & HEADER_EOF != 0 {
+                    self.phase = Phase::Idle;
+                }
+                return Ok(());
+            }
+            // A payload of the frame that just ended, e.g. a header-only one.
+            Phase::Idle if self.fid == Some(fid) => return Ok(()),
+            Phase::Idle => self.begin(fid),
+            Phase::Frame if self.fid != Some(fid) => {
+                trace!("uvc: frame without end of frame, dropped");
+                self.stats.dropped += 1;
+                self.begin(fid);
+            }
+            Phase::Frame => {}
+        }
+
+        self.in_payload = true;
+        self.eof = info & HEADER_EOF != 0;
+        if info & HEADER_ERR != 0 {
+            self.error = true;
+        }
+        if info & HEADER_PTS != 0 && header_len >= 6 && self.pts.is_none() {
+            self.pts = Some(u32::from_le_bytes([chunk[2], chunk[3], chunk[4], chunk[5]]));
+        }
+        self.extend_payload(&chunk[header_len..], buf)
+    }
+
+    fn extend_payload(&mut self, data: &[u8], buf: &mut [u8]) -> Result<(), UvcError> {
+        if !self.in_payload || self.overflow {
+            return Ok(());
+        }
+        let end = self.len + data.len();
+        match buf.get_mut(self.len..end) {
+            Some(dst) => {
+                dst.copy_from_slice(data);
+                self.len = end;
+                Ok(())
+            }
+            None => {
+                self.overflow = true;
+                self.error = true;
+                Err(UvcError::BufferTooSmall)
+            }
+        }
+    }
+
+    fn end_payload(&mut self) -> Option<Frame> {
+        if !self.in_payload || !self.eof {
+            return None;
+        }
+        self.in_payload = false;
+        self.phase = Phase::Idle;
+        if self.error || self.expected_len.is_some_and(|n| n != self.len) {
+            trace!("uvc: frame of {} bytes dropped", self.len);
+            self.stats.dropped += 1;
+            return None;
+        }
+        self.stats.frames += 1;
+        Some(Frame {
+            len: self.len,
+            pts: self.pts,
+        })
+    }
+
+    fn begin(&mut self, fid: bool) {
+        self.phase = Phase::Frame;
+        self.fid = Some(fid);
+        self.len = 0;
+        self.pts = None;
+        self.error = false;
+        self.overflow = false;
+    }
+}
+
+enum StreamPipe<'d, A: UsbHostAllocator<'d>> {
+    Isochronous(A::Pipe<pipe::Isochronous, pipe::In>),
+    Bulk(A::Pipe<pipe::Bulk, pipe::In>),
+}
+
+/// UVC host driver.
+///
+/// Negotiates the streaming parameters with a camera and receives its frames.
+pub struct UvcHost<'d, A: UsbHostAllocator<'d>> {
+    ctrl_ch: A::Pipe<pipe::Control, pipe::InOut>,
+    stream: Option<StreamPipe<'d, A>>,
+    info: UvcInfo,
+    device_address: u8,
+    split: Option<SplitInfo>,
+    committed: Option<ProbeCommit>,
+    assembler: FrameAssembler,
+    // Bytes per transfer: the alternate setting bandwidth, or the bulk payload size.
+    payload_size: usize,
+    // Bytes left in the bulk payload being received, 0 between payloads.
+    bulk_left: usize,
+}
+
+impl<'d, A: UsbHostAllocator<'d>> UvcHost<'d, A> {
+    /// Create a new UVC host driver.
+    ///
+    /// Parses the config descriptor to find the video function and allocates the
+    /// control channel. The video channel is allocated by [`start`](Self::start).
+    pub fn new(alloc: &A, config_desc: &[u8], enum_info: &EnumerationInfo) -> Result<Self, UvcError> {
+        let info = find_uvc(config_desc).ok_or(UvcError::NoInterface)?;
+
+        let ctrl_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts(0, UsbDirection::In),
+            ep_type: EndpointType::Control,
+            max_packet_size: enum_info.device_desc.max_packet_size0 as u16,
+            interval_ms: 0,
+        };
+        let ctrl_ch = alloc
+            .alloc_pipe::<pipe::Control, pipe::InOut>(enum_info.device_address, &ctrl_ep_info, enum_info.split())
+            .map_err(|_| UvcError::NoPipe)?;
+
+        Ok(Self {
+            ctrl_ch,
+            stream: None,
+            info,
+            device_address: enum_info.device_address,
+            split: enum_info.split(),
+            committed: None,
+            assembler: FrameAssembler::new(None),
+            payload_size: 0,
+            bulk_left: 0,
+        })
+    }
+
+    /// Interface, format and endpoint information.
+    pub fn info(&self) -> &UvcInfo {
+        &self.info
+    }
+
+    /// The committed streaming parameters.
+    pub fn committed(&self) -> Option<&ProbeCommit> {
+        self.committed.as_ref()
+    }
+
+    /// Frame counters of the current stream.
+    pub fn stats(&self) -> StreamStats {
+        self.assembler.stats()
+    }
+
+    /// Length of the probe and commit controls for the camera's UVC version.
+    fn probe_len(&self) -> usize {
+        match self.info.uvc_version {
+            ..0x0110 => ProbeCommit::LEN_1_0,
+            0x0110..0x0150 => ProbeCommit::LEN,
+            _ => PROBE_LEN_1_5,
+        }
+    }
+
+    async fn set_control(&mut self, selector: u8, value: &ProbeCommit) -> Result<(), UvcError> {
+        let len = self.probe_len();
+        let mut buf = [0u8; PROBE_LEN_1_5];
+        buf[..ProbeCommit::LEN].copy_from_slice(&value.to_bytes());
+        let setup = SetupPacket::class_interface_out(
+            SET_CUR,
+            (selector as u16) << 8,
+            self.info.streaming_interface as u16,
+            len as u16,
+        );
+        self.ctrl_ch.control_out(&setup.to_bytes(), &buf[..len]).await?;
+        Ok(())
+    }
+
+    /// Propose streaming parameters with the probe control, and read back the
+    /// parameters the camera would use.
+    pub async fn probe(&mut self, proposal: &ProbeCommit) -> Result<ProbeCommit, UvcError> {
+        self.set_control(VS_PROBE_CONTROL, proposal).await?;
+
+        let len = self.probe_len();
+        let mut buf = [0u8; PROBE_LEN_1_5];
+        let setup = SetupPacket::class_interface_in(
+            GET_CUR,
+            (VS_PROBE_CONTROL as u16) << 8,
+            self.info.streaming_interface as u16,
+            len as u16,
+        );
+        let n = self.ctrl_ch.control_in(&setup.to_bytes(), &mut buf[..len]).await?;
+        ProbeCommit::parse(&buf[..n]).ok_or(UvcError::InvalidResponse)
+    }
+
+    /// Commit streaming parameters returned by [`probe`](Self::probe).
+    ///
+    /// Stops the stream if it is running.
+    pub async fn commit(&mut self, params: &ProbeCommit) -> Result<(), UvcError> {
+        let format = self.info.format(params.format_index).ok_or(UvcError::InvalidResponse)?;
+        let frame = format.frame(params.frame_index).ok_or(UvcError::InvalidResponse)?;
+        let expected_len = format.frame_len(frame);
+
+        self.stop().await?;
+        self.set_control(VS_COMMIT_CONTROL, params).await?;
+        debug!(
+            "uvc: commit format {} frame {} interval {} payload {}",
+            params.format_index, params.frame_index, params.frame_interval, params.max_payload_transfer_size
+        );
+        self.committed = Some(*params);
+        self.assembler = FrameAssembler::new(expected_len);
+        Ok(())
+    }
+
+    /// Negotiate and commit a format, frame size and frame interval.
+    ///
+    /// `format_index` and `frame_index` are the 1-based descriptor indices from
+    /// [`info`](Self::info); `frame_interval` is in 100 ns units. The camera may
+    /// adjust the interval; the committed parameters are returned.
+    pub async fn negotiate(
+        &mut self,
+        format_index: u8,
+        frame_index: u8,
+        frame_interval: u32,
+    ) -> Result<ProbeCommit, UvcError> {
+        self.info
+            .format(format_index)
+            .and_then(|f| f.frame(frame_index))
+            .ok_or(UvcError::UnsupportedFormat)?;
+
+        let proposal = ProbeCommit {
+            // bmHint: keep the frame interval.
+            hint: 1,
+            format_index,
+            frame_index,
+            frame_interval,
+            ..Default::default()
+        };
+        let params = self.probe(&proposal).await?;
+        self.commit(&params).await?;
+        Ok(params)
+    }
+
+    /// Start streaming with the committed parameters.
+    ///
+    /// For isochronous cameras, selects the alternate setting with the least
+    /// bandwidth fitting the committed payload size.
+    pub async fn start(&mut self, alloc: &A) -> Result<(), UvcError> {
+        let params = self.committed.ok_or(UvcError::NotCommitted)?;
+        self.stop().await?;
+
+        let (ep, alt_setting) = match self.info.transfer_mode {
+            TransferMode::Isochronous => {
+                let alt = *self
+                    .info
+                    .alt_setting_for(params.max_payload_transfer_size)
+                    .ok_or(UvcError::InsufficientBandwidth)?;
+                self.payload_size = alt.bandwidth().min(MAX_PAYLOAD_SIZE);
+                (alt.endpoint, alt.alternate_setting)
+            }
+            TransferMode::Bulk => {
+                self.payload_size = params.max_payload_transfer_size as usize;
+                (self.info.bulk_endpoint.ok_or(UvcError::NoInterface)?, 0)
+            }
+        };
+
+        let ep_info = EndpointInfo::from(ep);
+        self.stream = Some(match self.info.transfer_mode {
+            TransferMode::Isochronous => StreamPipe::Isochronous(
+                alloc
+                    .alloc_pipe::<pipe::Isochronous, pipe::In>(self.device_address, &ep_info, self.split)
+                    .map_err(|_| UvcError::NoPipe)?,
+            ),
+            TransferMode::Bulk => StreamPipe::Bulk(
+                alloc
+                    .alloc_pipe::<pipe::Bulk, pipe::In>(self.device_address, &ep_info, self.split)
+                    .map_err(|_| UvcError::NoPipe)?,
+            ),
+        });
+        if alt_setting != 0 {
+            debug!("uvc: streaming interface alt setting {}", alt_setting);
+            let setup = SetupPacket::set_interface(self.info.streaming_interface as u16, alt_setting);
+            if let Err(e) = self.ctrl_ch.control_out(&setup.to_bytes(), &[]).await {
+                self.stream = None;
+                return Err(e.into());
+            }
+        }
+
+        self.assembler.reset();
+        self.bulk_left = 0;
+        Ok(())
+    }
+
+    /// Stop streaming and release the video channel.
+    pub async fn stop(&mut self) -> Result<(), UvcError> {
+        let Some(stream) = self.stream.take() else {
+            return Ok(());
+        };
+        drop(stream);
+
+        let setup = match self.info.transfer_mode {
+            TransferMode::Isochronous => SetupPacket::set_interface(self.info.streaming_interface as u16, 0),
+            // Bulk cameras stop streaming when the endpoint halt is cleared (UVC 1.1 §4.3.1.1).
+            TransferMode::Bulk => SetupPacket {
+                request_type: RequestType {
+                    direction: UsbDirection::Out,
+                    control_type: ControlType::Standard,
+                    recipient: Recipient::Endpoint,
+                },
+                request: Request::CLEAR_FEATURE,
+                value: Request::FEATURE_ENDPOINT_HALT,
+                index: self.info.bulk_endpoint.map_or(0, |ep| ep.endpoint_address as u16),
+                length: 0,
+            },
+        };
+        self.ctrl_ch.control_out(&setup.to_bytes(), &[]).await?;
+        Ok(())
+    }
+
+    /// Receive the next complete frame into `buf`.
+    ///
+    /// Dropped frames are skipped and counted in [`stats`](Self::stats). Fails with
+    /// [`UvcError::BufferTooSmall`] when a frame does not fit in `buf`. The same
+    /// buffer must be passed again after an error or a cancelled call, since it
+    /// may hold the start of the next frame.
+    pub async fn read_frame(&mut self, buf: &mut [u8]) -> Result<Frame, UvcError> {
+        let mut payload = [0u8; MAX_PAYLOAD_SIZE];
+        let mut errors = 0;
+        loop {
+            match self.stream.as_mut().ok_or(UvcError::NotStreaming)? {
+                StreamPipe::Isochronous(ch) => {
+                    let n = match ch.request_in(&mut payload[..self.payload_size]).await {
+                        Ok(n) => n,
+                        Err(PipeError::Disconnected) => return Err(PipeError::Disconnected.into()),
+                        // A lost isochronous payload damages the frame, but the stream goes on.
+                        Err(e) if errors < MAX_CONSECUTIVE_ERRORS => {
+                            trace!("uvc: isochronous transfer error {:?}", e);
+                            errors += 1;
+                            self.assembler.lost();
+                            continue;
+                        }
+                        Err(e) => return Err(e.into()),
+                    };
+                    errors = 0;
+                    if let Some(frame) = self.assembler.push(&payload[..n], buf)? {
+                        return Ok(frame);
+                    }
+                }
+                StreamPipe::Bulk(ch) => {
+                    // A bulk payload spans transfers up to the payload size, ending early with a short packet.
+                    let first = self.bulk_left == 0;
+                    let left = if first { self.payload_size } else { self.bulk_left };
+                    let want = left.min(MAX_PAYLOAD_SIZE);
+                    let n = ch.request_in(&mut payload[..want]).await?;
+                    let ended = n < want || n == left;
+                    self.bulk_left = if ended { 0 } else { left - n };
+
+                    let res = if first {
+                        self.assembler.start_payload(&payload[..n], buf)
+                    } else {
+                        self.assembler.extend_payload(&payload[..n], buf)
+                    };
+                    let frame = if ended { self.assembler.end_payload() } else { None };
+                    res?;
+                    if let Some(frame) = frame {
+                        return Ok(frame);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MJPEG_FRAME_640: [u8; 30] = [
+        30, 0x24, 0x07, 1, 0x00, 0x80, 0x02, 0xE0, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x60,
+        0x09, 0x00, 0x15, 0x16, 0x05, 0x00, 1, 0x15, 0x16, 0x05, 0x00,
+    ];
+
+    /// A UVC 1.1 webcam: MJPEG 640x480 and 320x240, YUY2 160x120, an NV12 format
+    /// that is skipped, and three isochronous alternate settings.
+    fn webcam_config() -> Vec<u8, 512> {
+        let mut cfg: Vec<u8, 512> = Vec::new();
+        let mut add = |d: &[u8]| cfg.extend_from_slice(d).unwrap();
+
+        add(&[9, 0x02, 0, 0, 2, 1, 0, 0x80, 250]);
+        add(&[8, 0x0B, 0, 2, 0x0E, 0x03, 0x00, 0]);
+        add(&[9, 0x04, 0, 0, 0, 0x0E, 0x01, 0x00, 0]);
+        add(&[13, 0x24, 0x01, 0x10, 0x01, 0x1E, 0x00, 0x00, 0x6C, 0xDC, 0x02, 1, 1]);
+        add(&[9, 0x04, 1, 0, 0, 0x0E, 0x02, 0x00, 0]);
+        add(&[16, 0x24, 0x01, 3, 0, 0, 0x81, 0, 1, 0, 0, 0, 1, 0, 0, 0]);
+        add(&[11, 0x24, 0x06, 1, 2, 0x01, 1, 0, 0, 0, 0]);
+        add(&MJPEG_FRAME_640);
+        // 320x240, continuous intervals from 30 to 5 fps.
+        add(&[
+            38, 0x24, 0x07, 2, 0x00, 0x40, 0x01, 0xF0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x58, 0x02, 0x00, 0x15, 0x16, 0x05, 0x00, 0, 0x15, 0x16, 0x05, 0x00, 0x80, 0x84, 0x1E, 0x00, 0x40, 0x42,
+            0x0F, 0x00,
+        ]);
+        let mut nv12 = [0u8; 27];
+        nv12[..5].copy_from_slice(&[27, 0x24, 0x04, 2, 1]);
+        nv12[5..9].copy_from_slice(b"NV12");
+        add(&nv12);
+        add(&[
+            30, 0x24, 0x05, 1, 0x00, 0xA0, 0x00, 0x78, 0x00, 0, 0, 0, 0, 0, 0, 0, 0, 0x00, 0x96, 0x00, 0x00, 0x15,
+            0x16, 0x05, 0x00, 1, 0x15, 0x16, 0x05, 0x00,
+        ]);
+        let mut yuy2 = [0u8; 27];
+        yuy2[..5].copy_from_slice(&[27, 0x24, 0x04, 3, 1]);
+        yuy2[5..21].copy_from_slice(&GUID_YUY2);
+        yuy2[21] = 16;
+        yuy2[22] = 1;
+        add(&yuy2);
+        // 160x120, two discrete intervals: 30 and 15 fps.
+        add(&[
+            34, 0x24, 0x05, 1, 0x00, 0xA0, 0x00, 0x78, 0x00, 0, 0, 0, 0, 0, 0, 0, 0, 0x00, 0x96, 0x00, 0x00, 0x15,
+            0x16, 0x05, 0x00, 2, 0x15, 0x16, 0x05, 0x00, 0x2A, 0x2C, 0x0A, 0x00,
+        ]);
+        add(&[9, 0x04, 1, 1, 1, 0x0E, 0x02, 0x00, 0]);
+        add(&[7, 0x05, 0x81, 0x05, 0x00, 0x02, 1]);
+        add(&[9, 0x04, 1, 2, 1, 0x0E, 0x02, 0x00, 0]);
+        add(&[7, 0x05, 0x81, 0x05, 0x00, 0x04, 1]);
+        add(&[9, 0x04, 1, 3, 1, 0x0E, 0x02, 0x00, 0]);
+        add(&[7, 0x05, 0x81, 0x05, 0x00, 0x14, 1]);
+
+        let len = (cfg.len() as u16).to_le_bytes();
+        cfg[2..4].copy_from_slice(&len);
+        cfg
+    }
+
+    #[test]
+    fn find_webcam() {
+        let info = find_uvc(&webcam_config()).unwrap();
+        assert_eq!(info.control_interface, 0);
+        assert_eq!(info.streaming_interface, 1);
+        assert_eq!(info.uvc_version, 0x0110);
+        assert_eq!(info.transfer_mode, TransferMode::Isochronous);
+
+        assert_eq!(info.formats.len(), 2);
+        let mjpeg = info.format(1).unwrap();
+        assert_eq!(mjpeg.encoding, Encoding::Mjpeg);
+        assert_eq!(mjpeg.frames.len(), 2);
+        let frame = mjpeg.frame(1).unwrap();
+        assert_eq!((frame.width, frame.height), (640, 480));
+        assert_eq!(frame.max_frame_size, 640 * 480 * 2);
+        assert_eq!((frame.min_interval, frame.max_interval), (333_333, 333_333));
+        let frame = mjpeg.frame(2).unwrap();
+        assert_eq!((frame.min_interval, frame.max_interval), (333_333, 2_000_000));
+        assert_eq!(frame.default_interval, 333_333);
+
+        assert!(info.format(2).is_none());
+        let (yuy2, frame) = info.find_frame(Encoding::Yuy2, 160, 120).unwrap();
+        assert_eq!(yuy2.index, 3);
+        assert_eq!(yuy2.bits_per_pixel, 16);
+        assert_eq!(yuy2.frame_len(frame), Some(160 * 120 * 2));
+        assert_eq!((frame.min_interval, frame.max_interval), (333_333, 666_666));
+        assert!(info.find_frame(Encoding::Mjpeg, 160, 120).is_none());
+
+        let bandwidths: Vec<usize, 4> = info.alt_settings.iter().map(|a| a.bandwidth()).collect();
+        assert_eq!(bandwidths, [512, 1024, 3072]);
+    }
+
+    #[test]
+    fn alt_setting_selection() {
+        let info = find_uvc(&webcam_config()).unwrap();
+        assert_eq!(info.alt_setting_for(512).unwrap().alternate_setting, 1);
+        assert_eq!(info.alt_setting_for(513).unwrap().alternate_setting, 2);
+        assert_eq!(info.alt_setting_for(3072).unwrap().alternate_setting, 3);
+        assert!(info.alt_setting_for(3073).is_none());
+        assert_eq!(info.alt_setting_for(0).unwrap().alternate_setting, 3);
+    }
+
+    #[test]
+    fn find_bulk_camera() {
+        let mut cfg: Vec<u8, 128> = Vec::new();
+        cfg.extend_from_slice(&[9, 0x02, 0, 0, 2, 1, 0, 0x80, 250]).unwrap();
+        cfg.extend_from_slice(&[9, 0x04, 0, 0, 0, 0x0E, 0x01, 0x00, 0]).unwrap();
+        cfg.extend_from_slice(&[13, 0x24, 0x01, 0x00, 0x01, 0x1E, 0x00, 0x00, 0x6C, 0xDC, 0x02, 1, 1])
+            .unwrap();
+        cfg.extend_from_slice(&[9, 0x04, 1, 0, 1, 0x0E, 0x02, 0x00, 0]).unwrap();
+        cfg.extend_from_slice(&[14, 0x24, 0x01, 1, 0, 0, 0x82, 0, 1, 0, 0, 0, 1, 0])
+            .unwrap();
+        cfg.extend_from_slice(&[11, 0x24, 0x06, 1, 1, 0x01, 1, 0, 0, 0, 0])
+            .unwrap();
+        cfg.extend_from_slice(&MJPEG_FRAME_640).unwrap();
+        cfg.extend_from_slice(&[7, 0x05, 0x82, 0x02, 0x00, 0x02, 0]).unwrap();
+        let len = (cfg.len() as u16).to_le_bytes();
+        cfg[2..4].copy_from_slice(&len);
+
+        let info = find_uvc(&cfg).unwrap();
+        assert_eq!(info.uvc_version, 0x0100);
+        assert_eq!(info.transfer_mode, TransferMode::Bulk);
+        assert_eq!(info.bulk_endpoint.unwrap().endpoint_address, 0x82);
+        assert!(info.alt_settings.is_empty());
+
+        // Without any supported format there is no usable function.
+        let format_subtype = cfg.len() - 7 - 30 - 11 + 2;
+        cfg[format_subtype] = 0x10;
+        assert!(find_uvc(&cfg).is_none());
+    }
+
+    fn payload<'a>(buf: &'a mut [u8; 16], info: u8, data: &[u8]) -> &'a [u8] {
+        buf[0] = 2;
+        buf[1] = info | 0x80;
+        buf[2..2 + data.len()].copy_from_slice(data);
+        &buf[..2 + data.len()]
+    }
+
+    #[test]
+    fn reassemble_frames() {
+        let mut asm = FrameAssembler::new(None);
+        let mut frame = [0u8; 8];
+        let mut p = [0u8; 16];
+
+        // Tail of a frame that started before the stream: discarded, not counted.
+        assert_eq!(asm.push(payload(&mut p, 0, &[9, 9]), &mut frame).unwrap(), None);
+        assert_eq!(asm.push(payload(&mut p, HEADER_EOF, &[9]), &mut frame).unwrap(), None);
+        // A trailing header-only payload of the same frame.
+        assert_eq!(asm.push(payload(&mut p, 0, &[]), &mut frame).unwrap(), None);
+
+        assert_eq!(asm.push(&[], &mut frame).unwrap(), None);
+        assert_eq!(
+            asm.push(payload(&mut p, HEADER_FID, &[1, 2, 3]), &mut frame).unwrap(),
+            None
+        );
+        assert_eq!(asm.push(payload(&mut p, HEADER_FID, &[4]), &mut frame).unwrap(), None);
+        let done = asm.push(payload(&mut p, HEADER_FID | HEADER_EOF, &[5]), &mut frame);
+        assert_eq!(done.unwrap(), Some(Frame { len: 5, pts: None }));
+        assert_eq!(frame[..5], [1, 2, 3, 4, 5]);
+
+        // Presentation time stamp from the first payload header.
+        let first = [6, 0x80 | HEADER_PTS, 0x78, 0x56, 0x34, 0x12, 7, 8];
+        assert_eq!(asm.push(&first, &mut frame).unwrap(), None);
+        let done = asm.push(payload(&mut p, HEADER_EOF, &[]), &mut frame);
+        assert_eq!(
+            done.unwrap(),
+            Some(Frame {
+                len: 2,
+                pts: Some(0x1234_5678)
+            })
+        );
+        assert_eq!(frame[..2], [7, 8]);
+        assert_eq!(asm.stats(), StreamStats { frames: 2, dropped: 0 });
+    }
+
+    #[test]
+    fn drop_frames() {
+        let mut asm = FrameAssembler::new(None);
+        let mut frame = [0u8; 4];
+        let mut p = [0u8; 16];
+        asm.push(payload(&mut p, HEADER_EOF, &[]), &mut frame).unwrap();
+
+        // The frame ID toggles before the end of frame: payloads were lost.
+        assert_eq!(asm.push(payload(&mut p, HEADER_FID, &[1]), &mut frame).unwrap(), None);
+        assert_eq!(asm.push(payload(&mut p, 0, &[2]), &mut frame).unwrap(), None);
+        assert_eq!(asm.stats().dropped, 1);
+        let done = asm.push(payload(&mut p, HEADER_EOF, &[3]), &mut frame).unwrap();
+        assert_eq!(done, Some(Frame { len: 2, pts: None }));
+        assert_eq!(frame[..2], [2, 3]);
+
+        // Error bit.
+        assert_eq!(
+            asm.push(payload(&mut p, HEADER_FID | HEADER_ERR, &[1]), &mut frame)
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            asm.push(payload(&mut p, HEADER_FID | HEADER_EOF, &[2]), &mut frame)
+                .unwrap(),
+            None
+        );
+        assert_eq!(asm.stats().dropped, 2);
+
+        // Lost payload.
+        asm.push(payload(&mut p, 0, &[1]), &mut frame).unwrap();
+        asm.lost();
+        assert_eq!(asm.push(payload(&mut p, HEADER_EOF, &[2]), &mut frame).unwrap(), None);
+        assert_eq!(asm.stats().dropped, 3);
+
+        // Overflow.
+        asm.push(payload(&mut p, HEADER_FID, &[1, 2, 3]), &mut frame).unwrap();
+        assert!(matches!(
+            asm.push(payload(&mut p, HEADER_FID, &[4, 5]), &mut frame),
+            Err(UvcError::BufferTooSmall)
+        ));
+        assert_eq!(
+            asm.push(payload(&mut p, HEADER_FID | HEADER_EOF, &[6]), &mut frame)
+                .unwrap(),
+            None
+        );
+        assert_eq!(asm.stats(), StreamStats { frames: 1, dropped: 4 });
+
+        // Malformed header.
+        asm.push(payload(&mut p, 0, &[1]), &mut frame).unwrap();
+        asm.push(&[9, 0x80, 1], &mut frame).unwrap();
+        assert_eq!(asm.push(payload(&mut p, HEADER_EOF, &[2]), &mut frame).unwrap(), None);
+        assert_eq!(asm.stats(), StreamStats { frames: 1, dropped: 5 });
+    }
+
+    #[test]
+    fn uncompressed_frame_size() {
+        let mut asm = FrameAssembler::new(Some(4));
+        let mut frame = [0u8; 8];
+        let mut p = [0u8; 16];
+        asm.push(payload(&mut p, HEADER_EOF, &[]), &mut frame).unwrap();
+
+        assert_eq!(
+            asm.push(payload(&mut p, HEADER_FID | HEADER_EOF, &[1, 2, 3]), &mut frame)
+                .unwrap(),
+            None
+        );
+        let done = asm
+            .push(payload(&mut p, HEADER_EOF, &[1, 2, 3, 4]), &mut frame)
+            .unwrap();
+        assert_eq!(done, Some(Frame { len: 4, pts: None }));
+        assert_eq!(asm.stats(), StreamStats { frames: 1, dropped: 1 });
+    }
+
+    #[test]
+    fn bulk_payload_spanning_transfers() {
+        let mut asm = FrameAssembler::new(None);
+        let mut frame = [0u8; 8];
+        let mut p = [0u8; 16];
+        asm.push(payload(&mut p, HEADER_EOF, &[]), &mut frame).unwrap();
+
+        // One payload for the whole frame, received in three transfers.
+        asm.start_payload(payload(&mut p, HEADER_FID | HEADER_EOF, &[1, 2]), &mut frame)
+            .unwrap();
+        asm.extend_payload(&[3, 4], &mut frame).unwrap();
+        asm.extend_payload(&[5], &mut frame).unwrap();
+        assert_eq!(asm.end_payload(), Some(Frame { len: 5, pts: None }));
+        assert_eq!(frame[..5], [1, 2, 3, 4, 5]);
+    }
+}
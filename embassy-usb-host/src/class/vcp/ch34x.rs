@@ -0,0 +1,567 @@
+//! WCH CH340/CH341 USB ↔ UART bridge driver.
+//!
+//! Implements the WCH vendor protocol: vendor-class bulk data transport
+//! (one bulk IN + one bulk OUT) plus vendor device requests that read and
+//! write the chip's UART registers for baud rate, line coding, flow
+//! control, modem signalling and break.
+//!
+//! The newer CH342, CH343, CH344 and CH9102 implement standard CDC-ACM
+//! and are driven by [`cdc_acm`](crate::class::cdc_acm) instead; their
+//! PIDs are listed in [`id`] so [`probe`](super::probe) can route them.
+//!
+//! CH34x chips have a single UART, so [`Ch34xDevice::port`] takes no
+//! interface index.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! use embassy_usb_host::class::vcp::ch34x::{Ch34xDevice, LineCoding};
+//!
+//! let device = Ch34xDevice::new(&bus, &enum_info)?;
+//! let mut port = device.port(&config_buf[..config_len])?;
+//! port.enable().await?;
+//! port.set_line_coding(&LineCoding::default()).await?;
+//! port.set_control_line_state(true, true).await?;
+//!
+//! let mut buf = [0u8; 64];
+//! let n = port.read(&mut buf).await?;
+//! port.write(&buf[..n]).await?;
+//! ```
+
+use core::marker::PhantomData;
+
+use embassy_sync::blocking_mutex::raw::{NoopRawMutex, RawMutex};
+use embassy_sync::mutex::Mutex;
+use embassy_usb_driver::host::{PipeError, SplitInfo, UsbHostAllocator, UsbPipe, pipe};
+use embassy_usb_driver::{Direction as UsbDirection, EndpointAddress, EndpointInfo, EndpointType};
+
+use super::find_vendor_interface;
+pub use super::{LineCoding, ModemStatus, Parity, StopBits};
+use crate::control::SetupPacket;
+use crate::handler::EnumerationInfo;
+
+/// WCH VID and CH34x PIDs.
+pub mod id {
+    /// Nanjing Qinheng Microelectronics (WCH) vendor ID.
+    pub const VID_WCH: u16 = 0x1A86;
+    /// CH340 product ID.
+    pub const PID_CH340: u16 = 0x7523;
+    /// CH340K product ID.
+    pub const PID_CH340K: u16 = 0x7522;
+    /// CH341 in UART mode product ID.
+    pub const PID_CH341: u16 = 0x5523;
+    /// CH342 product ID (CDC-ACM).
+    pub const PID_CH342: u16 = 0x55D2;
+    /// CH343 product ID (CDC-ACM).
+    pub const PID_CH343: u16 = 0x55D3;
+    /// CH344 product ID (CDC-ACM).
+    pub const PID_CH344: u16 = 0x55D5;
+    /// CH9102 product ID (CDC-ACM).
+    pub const PID_CH9102: u16 = 0x55D4;
+}
+
+// Vendor request codes.
+const REQ_READ_VERSION: u8 = 0x5F;
+const REQ_WRITE_REG: u8 = 0x9A;
+const REQ_READ_REG: u8 = 0x95;
+const REQ_SERIAL_INIT: u8 = 0xA1;
+const REQ_MODEM_CTRL: u8 = 0xA4;
+
+// Registers, accessed in pairs as `hi << 8 | lo`.
+const REG_BREAK: u16 = 0x05;
+const REG_STATUS: u16 = 0x06;
+const REG_STATUS2: u16 = 0x07;
+const REG_PRESCALER: u16 = 0x12;
+const REG_DIVISOR: u16 = 0x13;
+const REG_LCR: u16 = 0x18;
+const REG_LCR2: u16 = 0x25;
+const REG_FLOW_CTL: u16 = 0x27;
+
+// LCR bits.
+const LCR_ENABLE_RX: u8 = 0x80;
+const LCR_ENABLE_TX: u8 = 0x40;
+const LCR_MARK_SPACE: u8 = 0x20;
+const LCR_PAR_EVEN: u8 = 0x10;
+const LCR_ENABLE_PAR: u8 = 0x08;
+const LCR_STOP_BITS_2: u8 = 0x04;
+const LCR_CS8: u8 = 0x03;
+
+/// Break control bit in `REG_BREAK`, active low.
+const NBREAK_BITS: u8 = 0x01;
+
+// MODEM_CTRL bits, sent inverted.
+const CONTROL_DTR: u8 = 1 << 5;
+const CONTROL_RTS: u8 = 1 << 6;
+
+// Modem status bits, read inverted from `REG_STATUS`.
+const STATUS_CTS: u8 = 0x01;
+const STATUS_DSR: u8 = 0x02;
+const STATUS_RI: u8 = 0x04;
+const STATUS_DCD: u8 = 0x08;
+
+const FLOW_CTL_NONE: u16 = 0x00;
+const FLOW_CTL_RTSCTS: u16 = 0x01;
+
+/// Chip version from which `REG_DIVISOR` needs bit 7 set.
+const VERSION_DIVISOR_BIT7: u8 = 0x28;
+/// Chip version from which the LCR can be changed after init.
+const VERSION_LCR: u8 = 0x30;
+
+/// Baud-rate generator clock.
+const CLOCK: u32 = 48_000_000;
+const MIN_BAUD: u32 = 46;
+const MAX_BAUD: u32 = 3_000_000;
+
+const fn clk_div(ps: u32, fact: u32) -> u32 {
+    1 << (12 - 3 * ps - fact)
+}
+
+/// Compute the `REG_DIVISOR << 8 | REG_PRESCALER` register pair value for `baud`.
+///
+/// The rate is clamped to the chip's range and rounded to the nearest
+/// achievable one. Follows the Linux `ch341` driver.
+pub(crate) fn encode_baud_rate(baud: u32) -> u16 {
+    let baud = baud.clamp(MIN_BAUD, MAX_BAUD);
+
+    // Highest prescaler (fact = 1) that gives a divisor below 512.
+    let mut fact = 1;
+    let ps = (0..4u32)
+        .rev()
+        .find(|&ps| baud > CLOCK / (clk_div(ps, 1) * 512))
+        .unwrap_or(0);
+
+    let mut clk = clk_div(ps, fact);
+    let mut div = CLOCK / (clk * baud);
+
+    // Halve the base clock if out of the divisor range.
+    if !(9..=255).contains(&div) {
+        div /= 2;
+        clk *= 2;
+        fact = 0;
+    }
+
+    // Pick the next divisor if it is closer to the requested rate.
+    if 16 * CLOCK / (clk * div) - 16 * baud >= 16 * baud - 16 * CLOCK / (clk * (div + 1)) {
+        div += 1;
+    }
+
+    // Prefer the lower base clock for even divisors, as it is more tolerant.
+    if fact == 1 && div.is_multiple_of(2) {
+        div /= 2;
+        fact = 0;
+    }
+
+    ((0x100 - div) << 8 | fact << 2 | ps) as u16
+}
+
+/// Encode the LCR register for `coding`, or `None` if the chip can't do it.
+fn encode_lcr(coding: &LineCoding) -> Option<u8> {
+    let mut lcr = LCR_ENABLE_RX | LCR_ENABLE_TX;
+    lcr |= match coding.data_bits {
+        5..=8 => coding.data_bits - 5,
+        _ => return None,
+    };
+    lcr |= match coding.parity {
+        Parity::None => 0,
+        Parity::Odd => LCR_ENABLE_PAR,
+        Parity::Even => LCR_ENABLE_PAR | LCR_PAR_EVEN,
+        Parity::Mark => LCR_ENABLE_PAR | LCR_MARK_SPACE,
+        Parity::Space => LCR_ENABLE_PAR | LCR_MARK_SPACE | LCR_PAR_EVEN,
+    };
+    lcr |= match coding.stop_bits {
+        StopBits::One => 0,
+        StopBits::OneAndHalf => return None,
+        StopBits::Two => LCR_STOP_BITS_2,
+    };
+    Some(lcr)
+}
+
+/// CH34x host driver error.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Ch34xError {
+    /// Transfer error.
+    Transfer(PipeError),
+    /// No vendor-class interface with a bulk IN/OUT pair.
+    NoInterface,
+    /// Failed to allocate a pipe.
+    NoPipe,
+    /// Device response had an unexpected length.
+    InvalidResponse,
+    /// Argument was out of range for the chip.
+    InvalidArgument,
+}
+
+impl From<PipeError> for Ch34xError {
+    fn from(e: PipeError) -> Self {
+        Self::Transfer(e)
+    }
+}
+
+impl core::fmt::Display for Ch34xError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Transfer(_) => write!(f, "Transfer error"),
+            Self::NoInterface => write!(f, "No CH34x interface found"),
+            Self::NoPipe => write!(f, "No free pipe"),
+            Self::InvalidResponse => write!(f, "Invalid response from device"),
+            Self::InvalidArgument => write!(f, "Invalid argument"),
+        }
+    }
+}
+
+impl core::error::Error for Ch34xError {}
+
+impl embedded_io_async::Error for Ch34xError {
+    fn kind(&self) -> embedded_io_async::ErrorKind {
+        match self {
+            Self::Transfer(e) => match e {
+                PipeError::Disconnected => embedded_io_async::ErrorKind::NotConnected,
+                PipeError::BufferOverflow => embedded_io_async::ErrorKind::OutOfMemory,
+                PipeError::Timeout => embedded_io_async::ErrorKind::TimedOut,
+                _ => embedded_io_async::ErrorKind::Other,
+            },
+            Self::NoInterface => embedded_io_async::ErrorKind::NotFound,
+            Self::NoPipe => embedded_io_async::ErrorKind::OutOfMemory,
+            Self::InvalidResponse => embedded_io_async::ErrorKind::InvalidData,
+            Self::InvalidArgument => embedded_io_async::ErrorKind::InvalidInput,
+        }
+    }
+}
+
+/// CH34x device — owns the control pipe on endpoint 0.
+///
+/// Open the UART via [`Ch34xDevice::port`].
+pub struct Ch34xDevice<'d, A, M = NoopRawMutex>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    alloc: A,
+    ctrl: Mutex<M, A::Pipe<pipe::Control, pipe::InOut>>,
+    device_address: u8,
+    split: Option<SplitInfo>,
+    _phantom: PhantomData<&'d ()>,
+}
+
+impl<'d, A> Ch34xDevice<'d, A, NoopRawMutex>
+where
+    A: UsbHostAllocator<'d>,
+{
+    /// Allocate the device-level control pipe on endpoint 0, using a
+    /// [`NoopRawMutex`] for the control pipe.
+    ///
+    /// Performs no I/O.
+    pub fn new(alloc: &A, enum_info: &EnumerationInfo) -> Result<Self, Ch34xError> {
+        Self::new_with_raw_mutex(alloc, enum_info)
+    }
+}
+
+impl<'d, A, M> Ch34xDevice<'d, A, M>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    /// Allocate the device-level control pipe on endpoint 0, using
+    /// the caller-chosen raw mutex `M` for the control pipe.
+    ///
+    /// Performs no I/O.
+    pub fn new_with_raw_mutex(alloc: &A, enum_info: &EnumerationInfo) -> Result<Self, Ch34xError> {
+        let ctrl_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts(0, UsbDirection::In),
+            ep_type: EndpointType::Control,
+            max_packet_size: enum_info.device_desc.max_packet_size0 as u16,
+            interval_ms: 0,
+        };
+
+        let device_address = enum_info.device_address;
+        let split = enum_info.split();
+
+        let ctrl = alloc
+            .alloc_pipe::<pipe::Control, pipe::InOut>(device_address, &ctrl_ep_info, split)
+            .map_err(|_| Ch34xError::NoPipe)?;
+
+        Ok(Self {
+            alloc: alloc.clone(),
+            ctrl: Mutex::new(ctrl),
+            device_address,
+            split,
+            _phantom: PhantomData,
+        })
+    }
+
+    /// Open the UART.
+    ///
+    /// Allocates the bulk pipes but performs no I/O; call
+    /// [`Ch34xPort::enable`] before use.
+    pub fn port<'dev>(&'dev self, config_desc: &[u8]) -> Result<Ch34xPort<'dev, 'd, A, M>, Ch34xError> {
+        let info = find_vendor_interface(config_desc, 0).ok_or(Ch34xError::NoInterface)?;
+
+        let in_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts((info.bulk_in_ep & 0x0F) as usize, UsbDirection::In),
+            ep_type: EndpointType::Bulk,
+            max_packet_size: info.bulk_in_mps,
+            interval_ms: 0,
+        };
+
+        let out_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts((info.bulk_out_ep & 0x0F) as usize, UsbDirection::Out),
+            ep_type: EndpointType::Bulk,
+            max_packet_size: info.bulk_out_mps,
+            interval_ms: 0,
+        };
+
+        let in_ch = self
+            .alloc
+            .alloc_pipe::<pipe::Bulk, pipe::In>(self.device_address, &in_ep_info, self.split)
+            .map_err(|_| Ch34xError::NoPipe)?;
+        let out_ch = self
+            .alloc
+            .alloc_pipe::<pipe::Bulk, pipe::Out>(self.device_address, &out_ep_info, self.split)
+            .map_err(|_| Ch34xError::NoPipe)?;
+
+        Ok(Ch34xPort {
+            device: self,
+            in_ch,
+            out_ch,
+            version: 0,
+            lcr: LCR_ENABLE_RX | LCR_ENABLE_TX | LCR_CS8,
+            lines: 0,
+        })
+    }
+}
+
+/// The UART of a [`Ch34xDevice`].
+///
+/// Owns the bulk IN/OUT pipes and borrows the device for control requests.
+pub struct Ch34xPort<'dev, 'd, A, M = NoopRawMutex>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    device: &'dev Ch34xDevice<'d, A, M>,
+    in_ch: A::Pipe<pipe::Bulk, pipe::In>,
+    out_ch: A::Pipe<pipe::Bulk, pipe::Out>,
+    /// Chip version read by [`Ch34xPort::enable`].
+    version: u8,
+    /// Current LCR value.
+    lcr: u8,
+    /// Current `MODEM_CTRL` bits, not inverted.
+    lines: u8,
+}
+
+impl<'dev, 'd, A, M> Ch34xPort<'dev, 'd, A, M>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    async fn vendor_out(&mut self, request: u8, value: u16, index: u16) -> Result<(), Ch34xError> {
+        let setup = SetupPacket::vendor_device_out(request, value, index, 0);
+        let mut ctrl = self.device.ctrl.lock().await;
+        ctrl.control_out(&setup.to_bytes(), &[]).await?;
+        Ok(())
+    }
+
+    async fn vendor_in(&mut self, request: u8, value: u16, buf: &mut [u8]) -> Result<(), Ch34xError> {
+        let setup = SetupPacket::vendor_device_in(request, value, 0, buf.len() as u16);
+        let mut ctrl = self.device.ctrl.lock().await;
+        let n = ctrl.control_in(&setup.to_bytes(), buf).await?;
+        if n != buf.len() {
+            return Err(Ch34xError::InvalidResponse);
+        }
+        Ok(())
+    }
+
+    /// Chip version, valid after [`enable`](Self::enable).
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Initialize the UART at 115200 8N1 with DTR and RTS released.
+    ///
+    /// Reads the chip version, then issues `SERIAL_INIT`.
+    pub async fn enable(&mut self) -> Result<(), Ch34xError> {
+        let mut buf = [0u8; 2];
+        self.vendor_in(REQ_READ_VERSION, 0, &mut buf).await?;
+        self.version = buf[0];
+        self.vendor_out(REQ_SERIAL_INIT, 0, 0).await?;
+        self.set_line_coding(&LineCoding::default()).await?;
+        self.set_control_line_state(false, false).await
+    }
+
+    /// Program the UART baud rate in bauds per second.
+    ///
+    /// The rate is clamped to 46..=3000000 and rounded to the nearest
+    /// one the prescaler and divisor can produce.
+    pub async fn set_baud_rate(&mut self, baud: u32) -> Result<(), Ch34xError> {
+        let mut value = encode_baud_rate(baud);
+        if self.version >= VERSION_DIVISOR_BIT7 {
+            value |= 1 << 7;
+        }
+        self.vendor_out(REQ_WRITE_REG, REG_DIVISOR << 8 | REG_PRESCALER, value)
+            .await
+    }
+
+    /// Program baud rate, data/stop bits and parity.
+    ///
+    /// 1.5 stop bits are not supported. Chips older than version 0x30
+    /// keep the framing set up by [`enable`](Self::enable) and only
+    /// accept 8N1.
+    pub async fn set_line_coding(&mut self, coding: &LineCoding) -> Result<(), Ch34xError> {
+        let lcr = encode_lcr(coding).ok_or(Ch34xError::InvalidArgument)?;
+        if self.version < VERSION_LCR && lcr != LCR_ENABLE_RX | LCR_ENABLE_TX | LCR_CS8 {
+            return Err(Ch34xError::InvalidArgument);
+        }
+        self.set_baud_rate(coding.baud_rate).await?;
+        if self.version >= VERSION_LCR {
+            self.vendor_out(REQ_WRITE_REG, REG_LCR2 << 8 | REG_LCR, lcr as u16)
+                .await?;
+        }
+        self.lcr = lcr;
+        Ok(())
+    }
+
+    /// Drive DTR and RTS to the given levels.
+    pub async fn set_control_line_state(&mut self, dtr: bool, rts: bool) -> Result<(), Ch34xError> {
+        let mut lines = 0;
+        if dtr {
+            lines |= CONTROL_DTR;
+        }
+        if rts {
+            lines |= CONTROL_RTS;
+        }
+        self.vendor_out(REQ_MODEM_CTRL, !(lines as u16), 0).await?;
+        self.lines = lines;
+        Ok(())
+    }
+
+    /// Read the modem status lines.
+    ///
+    /// The DTR and RTS bits reflect the last
+    /// [`set_control_line_state`](Self::set_control_line_state) call.
+    pub async fn modem_status(&mut self) -> Result<ModemStatus, Ch34xError> {
+        let mut buf = [0u8; 2];
+        self.vendor_in(REQ_READ_REG, REG_STATUS2 << 8 | REG_STATUS, &mut buf)
+            .await?;
+        let status = !buf[0];
+
+        let mut out = ModemStatus::empty();
+        out.set(ModemStatus::DTR, self.lines & CONTROL_DTR != 0);
+        out.set(ModemStatus::RTS, self.lines & CONTROL_RTS != 0);
+        out.set(ModemStatus::CTS, status & STATUS_CTS != 0);
+        out.set(ModemStatus::DSR, status & STATUS_DSR != 0);
+        out.set(ModemStatus::RI, status & STATUS_RI != 0);
+        out.set(ModemStatus::DCD, status & STATUS_DCD != 0);
+        Ok(out)
+    }
+
+    /// Assert or release a break condition on TX.
+    ///
+    /// Reads the break and LCR registers, then writes them back with the
+    /// break bit and the transmitter enable changed.
+    pub async fn set_break(&mut self, asserted: bool) -> Result<(), Ch34xError> {
+        let reg = REG_LCR << 8 | REG_BREAK;
+        let mut buf = [0u8; 2];
+        self.vendor_in(REQ_READ_REG, reg, &mut buf).await?;
+        if asserted {
+            buf[0] &= !NBREAK_BITS;
+            buf[1] &= !LCR_ENABLE_TX;
+        } else {
+            buf[0] |= NBREAK_BITS;
+            buf[1] |= LCR_ENABLE_TX;
+        }
+        self.vendor_out(REQ_WRITE_REG, reg, u16::from_le_bytes(buf)).await
+    }
+
+    /// Enable or disable RTS/CTS hardware flow control.
+    pub async fn set_rts_cts(&mut self, enabled: bool) -> Result<(), Ch34xError> {
+        let fc = if enabled { FLOW_CTL_RTSCTS } else { FLOW_CTL_NONE };
+        self.vendor_out(REQ_WRITE_REG, REG_FLOW_CTL << 8 | REG_FLOW_CTL, fc << 8 | fc)
+            .await
+    }
+
+    /// Read bytes from the UART receive stream.
+    ///
+    /// # Cancellation
+    ///
+    /// Not cancel-safe: dropping the future mid-transfer loses any
+    /// bytes the device already sent in that transfer.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Ch34xError> {
+        Ok(self.in_ch.request_in(buf).await?)
+    }
+
+    /// Write bytes to the UART transmit stream.
+    ///
+    /// # Cancellation
+    ///
+    /// Not cancel-safe: the remote may observe partial data if the
+    /// future is dropped mid-transfer.
+    pub async fn write(&mut self, data: &[u8]) -> Result<usize, Ch34xError> {
+        self.out_ch.request_out(data, false).await?;
+        Ok(data.len())
+    }
+}
+
+impl<'dev, 'd, A, M> embedded_io_async::ErrorType for Ch34xPort<'dev, 'd, A, M>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    type Error = Ch34xError;
+}
+
+impl<'dev, 'd, A, M> embedded_io_async::Read for Ch34xPort<'dev, 'd, A, M>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        Ch34xPort::read(self, buf).await
+    }
+}
+
+impl<'dev, 'd, A, M> embedded_io_async::Write for Ch34xPort<'dev, 'd, A, M>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        Ch34xPort::write(self, buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn baud_divisor() {
+        assert_eq!(encode_baud_rate(9600), 0xB202);
+        assert_eq!(encode_baud_rate(115200), 0xCC03);
+        assert_eq!(encode_baud_rate(2400), 0xD901);
+        // Clamped to the supported range.
+        assert_eq!(encode_baud_rate(1), encode_baud_rate(MIN_BAUD));
+        assert_eq!(encode_baud_rate(u32::MAX), encode_baud_rate(MAX_BAUD));
+    }
+
+    #[test]
+    fn lcr() {
+        assert_eq!(encode_lcr(&LineCoding::default()), Some(0xC3));
+        let coding = LineCoding {
+            data_bits: 7,
+            parity: Parity::Even,
+            stop_bits: StopBits::Two,
+            ..Default::default()
+        };
+        assert_eq!(encode_lcr(&coding), Some(0xC0 | 0x02 | 0x18 | 0x04));
+        let coding = LineCoding {
+            stop_bits: StopBits::OneAndHalf,
+            ..Default::default()
+        };
+        assert_eq!(encode_lcr(&coding), None);
+    }
+}
@@ -48,8 +48,9 @@ use embassy_sync::mutex::Mutex;
 use embassy_usb_driver::host::{PipeError, SplitInfo, UsbHostAllocator, UsbPipe, pipe};
 use embassy_usb_driver::{Direction as UsbDirection, EndpointAddress, EndpointInfo, EndpointType};
 
+use super::find_vendor_interface;
+pub use super::{LineCoding, ModemStatus, Parity, StopBits};
 use crate::control::SetupPacket;
-use crate::descriptor::ConfigurationDescriptorChain;
 use crate::handler::EnumerationInfo;
 
 /// Silicon Labs VID and CP210x PIDs.
@@ -77,8 +78,6 @@ const GET_FLOW: u8 = 0x14;
 const GET_BAUDRATE: u8 = 0x1D;
 const SET_BAUDRATE: u8 = 0x1E;
 
-const VENDOR_CLASS: u8 = 0xFF;
-
 /// Baud-rate generator reference clock (AN571 §5.1).
 const BAUD_CLOCK: u32 = 3_686_400;
 
@@ -161,112 +160,6 @@ where
     Ok(BAUD_CLOCK / div as u32)
 }
 
-/// Parity setting.
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(u8)]
-pub enum Parity {
-    /// No parity bit.
-    None = 0,
-    /// Odd parity.
-    Odd = 1,
-    /// Even parity.
-    Even = 2,
-    /// Always 1.
-    Mark = 3,
-    /// Always 0.
-    Space = 4,
-}
-
-impl Parity {
-    fn from_bits(b: u8) -> Option<Self> {
-        Some(match b {
-            0 => Self::None,
-            1 => Self::Odd,
-            2 => Self::Even,
-            3 => Self::Mark,
-            4 => Self::Space,
-            _ => return None,
-        })
-    }
-}
-
-/// Number of stop bits.
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[repr(u8)]
-pub enum StopBits {
-    /// 1 stop bit.
-    One = 0,
-    /// 1.5 stop bits.
-    OneAndHalf = 1,
-    /// 2 stop bits.
-    Two = 2,
-}
-
-impl StopBits {
-    fn from_bits(b: u8) -> Option<Self> {
-        Some(match b {
-            0 => Self::One,
-            1 => Self::OneAndHalf,
-            2 => Self::Two,
-            _ => return None,
-        })
-    }
-}
-
-/// Serial line parameters.
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct LineCoding {
-    /// Baud rate in bits per second.
-    pub baud_rate: u32,
-    /// Data bits. Legal values are 5, 6, 7 and 8.
-    pub data_bits: u8,
-    /// Parity setting.
-    pub parity: Parity,
-    /// Stop bits.
-    pub stop_bits: StopBits,
-}
-
-impl Default for LineCoding {
-    fn default() -> Self {
-        Self {
-            baud_rate: 115200,
-            data_bits: 8,
-            parity: Parity::None,
-            stop_bits: StopBits::One,
-        }
-    }
-}
-
-macro_rules! bitflags {
-    ($($tt:tt)*) => {
-        #[cfg(feature = "defmt")]
-        defmt::bitflags! { $($tt)* }
-        #[cfg(not(feature = "defmt"))]
-        bitflags::bitflags! { #[derive(Debug, Clone, PartialEq)] $($tt)* }
-    };
-}
-
-bitflags! {
-    /// Modem status byte returned by `GET_MDMSTS` (AN571 §5.10).
-    pub struct ModemStatus: u8 {
-        /// DTR output asserted.
-        const DTR = 1 << 0;
-        /// RTS output asserted.
-        const RTS = 1 << 1;
-        /// CTS input asserted.
-        const CTS = 1 << 4;
-        /// DSR input asserted.
-        const DSR = 1 << 5;
-        /// Ring indicator input asserted.
-        const RI  = 1 << 6;
-        /// Data-carrier-detect input asserted.
-        const DCD = 1 << 7;
-    }
-}
-
 bitflags! {
     /// Bitmask passed to [`Cp210xPort::purge`] (AN571 §5.27).
     pub struct PurgeMask: u16 {
@@ -496,42 +389,14 @@ pub struct Cp210xInfo {
 /// Use `interface_idx = 0` for single-port CP210x parts; `0..2` for
 /// CP2105; `0..4` for CP2108.
 pub fn find_cp210x(config_desc: &[u8], interface_idx: u8) -> Option<Cp210xInfo> {
-    let cfg = ConfigurationDescriptorChain::try_from_slice(config_desc).ok()?;
-
-    let mut seen = 0u8;
-    for iface in cfg.iter_interface() {
-        if iface.interface_class != VENDOR_CLASS || iface.alternate_setting != 0 {
-            continue;
-        }
-
-        let mut in_ep = None;
-        let mut out_ep = None;
-        for ep in iface.iter_endpoints() {
-            if ep.ep_type() != EndpointType::Bulk {
-                continue;
-            }
-            if ep.is_in() {
-                in_ep = Some((ep.endpoint_address, ep.max_packet_size));
-            } else {
-                out_ep = Some((ep.endpoint_address, ep.max_packet_size));
-            }
-        }
-
-        if let (Some((in_a, in_m)), Some((out_a, out_m))) = (in_ep, out_ep) {
-            if seen == interface_idx {
-                return Some(Cp210xInfo {
-                    interface: iface.interface_number,
-                    bulk_in_ep: in_a,
-                    bulk_in_mps: in_m,
-                    bulk_out_ep: out_a,
-                    bulk_out_mps: out_m,
-                });
-            }
-            seen += 1;
-        }
-    }
-
-    None
+    let info = find_vendor_interface(config_desc, interface_idx)?;
+    Some(Cp210xInfo {
+        interface: info.interface,
+        bulk_in_ep: info.bulk_in_ep,
+        bulk_in_mps: info.bulk_in_mps,
+        bulk_out_ep: info.bulk_out_ep,
+        bulk_out_mps: info.bulk_out_mps,
+    })
 }
 
 /// CP210x device — owns the shared control pipe on endpoint 0.
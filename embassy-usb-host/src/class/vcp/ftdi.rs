@@ -0,0 +1,768 @@
+//! FTDI FT232/FT2232/FT4232 USB ↔ UART bridge driver.
+//!
+//! Implements the FTDI `SIO` vendor protocol: vendor-class bulk data
+//! transport (one bulk IN + one bulk OUT per interface) plus vendor device
+//! requests for baud rate, line coding, flow control, modem signalling
+//! and break. Covers the AM/BM/R/X single-port parts and the multi-port
+//! FT2232C/D, FT2232H and FT4232H as well as the high-speed FT232H.
+//!
+//! Every bulk IN packet from an FTDI chip starts with two status bytes
+//! (modem status and line status). [`FtdiPort::read`] strips them and
+//! keeps the latest values for [`FtdiPort::line_status`]. The chip sends
+//! a status-only packet whenever the latency timer expires, so `read`
+//! waits until actual data arrives.
+//!
+//! As with the CP210x driver, an [`FtdiDevice`] owns the control pipe on
+//! endpoint 0 and each UART interface is opened as an [`FtdiPort`].
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! use embassy_usb_host::class::vcp::ftdi::{FtdiChip, FtdiDevice, LineCoding};
+//!
+//! let chip = FtdiChip::detect(&enum_info.device_desc).unwrap();
+//! let device = FtdiDevice::new(&bus, &enum_info, chip)?;
+//! let mut port = device.port(&config_buf[..config_len], 0)?;
+//! port.reset().await?;
+//! port.set_line_coding(&LineCoding::default()).await?;
+//! port.set_control_line_state(true, true).await?;
+//!
+//! let mut buf = [0u8; 64];
+//! let n = port.read(&mut buf).await?;
+//! port.write(&buf[..n]).await?;
+//! ```
+
+use core::marker::PhantomData;
+
+use embassy_sync::blocking_mutex::raw::{NoopRawMutex, RawMutex};
+use embassy_sync::mutex::Mutex;
+use embassy_usb_driver::host::{PipeError, SplitInfo, UsbHostAllocator, UsbPipe, pipe};
+use embassy_usb_driver::{Direction as UsbDirection, EndpointAddress, EndpointInfo, EndpointType};
+
+use super::find_vendor_interface;
+pub use super::{LineCoding, ModemStatus, Parity, StopBits};
+use crate::control::SetupPacket;
+use crate::descriptor::DeviceDescriptor;
+use crate::handler::EnumerationInfo;
+
+/// FTDI VID and PIDs.
+pub mod id {
+    /// Future Technology Devices International vendor ID.
+    pub const VID_FTDI: u16 = 0x0403;
+    /// FT232AM/BM/R and FT245 product ID.
+    pub const PID_FT232: u16 = 0x6001;
+    /// FT2232C/D/H product ID.
+    pub const PID_FT2232: u16 = 0x6010;
+    /// FT4232H product ID.
+    pub const PID_FT4232H: u16 = 0x6011;
+    /// FT232H product ID.
+    pub const PID_FT232H: u16 = 0x6014;
+    /// FT-X series (FT230X, FT231X, FT234XD) product ID.
+    pub const PID_FT_X: u16 = 0x6015;
+}
+
+// FTDI AN232B-03/AN232B-05 request codes.
+const SIO_RESET: u8 = 0x00;
+const SIO_MODEM_CTRL: u8 = 0x01;
+const SIO_SET_FLOW_CTRL: u8 = 0x02;
+const SIO_SET_BAUD_RATE: u8 = 0x03;
+const SIO_SET_DATA: u8 = 0x04;
+const SIO_GET_MODEM_STATUS: u8 = 0x05;
+const SIO_SET_LATENCY_TIMER: u8 = 0x09;
+const SIO_GET_LATENCY_TIMER: u8 = 0x0A;
+
+const SIO_RESET_SIO: u16 = 0;
+const SIO_RESET_PURGE_RX: u16 = 1;
+const SIO_RESET_PURGE_TX: u16 = 2;
+
+const SIO_SET_DTR_MASK: u16 = 1 << 8;
+const SIO_SET_RTS_MASK: u16 = 1 << 9;
+const SIO_SET_BREAK: u16 = 1 << 14;
+
+/// Length of the status header at the start of every bulk IN packet.
+const STATUS_LEN: usize = 2;
+
+/// Receive buffer, holding up to one high-speed packet.
+const RX_BUF_LEN: usize = 512;
+
+/// Baud-rate generator clock of the AM/BM/R/X and FT2232C parts.
+const CLOCK_48MHZ: u32 = 48_000_000;
+/// Baud-rate generator clock of the high-speed parts.
+const CLOCK_120MHZ: u32 = 120_000_000;
+
+/// FTDI chip revision, told apart by `bcdDevice`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FtdiChip {
+    /// FT8U232AM.
+    Am,
+    /// FT232BM/FT245BM.
+    Bm,
+    /// FT2232C/D, two ports.
+    Ft2232C,
+    /// FT232R/FT245R.
+    R,
+    /// FT2232H, two high-speed ports.
+    Ft2232H,
+    /// FT4232H, four high-speed ports.
+    Ft4232H,
+    /// FT232H, one high-speed port.
+    Ft232H,
+    /// FT-X series.
+    X,
+}
+
+impl FtdiChip {
+    /// Identify the chip from its device descriptor.
+    ///
+    /// Returns `None` for unknown `bcdDevice` values.
+    pub fn detect(desc: &DeviceDescriptor) -> Option<Self> {
+        Some(match desc.bcd_device {
+            0x0200 => Self::Am,
+            0x0400 => Self::Bm,
+            0x0500 => Self::Ft2232C,
+            0x0600 => Self::R,
+            0x0700 => Self::Ft2232H,
+            0x0800 => Self::Ft4232H,
+            0x0900 => Self::Ft232H,
+            0x1000 => Self::X,
+            _ => return None,
+        })
+    }
+
+    /// Whether the chip has more than one UART interface.
+    ///
+    /// Requests to multi-port chips carry the 1-based port number in `wIndex`.
+    pub const fn is_multi_port(self) -> bool {
+        matches!(self, Self::Ft2232C | Self::Ft2232H | Self::Ft4232H)
+    }
+
+    /// Whether the chip has the 120 MHz high-speed baud-rate generator.
+    pub const fn is_high_speed(self) -> bool {
+        matches!(self, Self::Ft2232H | Self::Ft4232H | Self::Ft232H)
+    }
+
+    /// Highest supported baud rate.
+    pub const fn max_baud_rate(self) -> u32 {
+        if self.is_high_speed() { 12_000_000 } else { 3_000_000 }
+    }
+}
+
+/// Sub-integer divisor encodings for eighths 0..8 (AN232B-05 §3).
+const DIV_FRAC: [u32; 8] = [0, 3, 2, 4, 1, 5, 6, 7];
+
+/// FT8U232AM divisor, which only supports 1/8, 1/4 and 1/2 fractions.
+fn am_divisor(baud: u32) -> u32 {
+    let mut divisor3 = (CLOCK_48MHZ + baud) / (2 * baud);
+    if divisor3 & 0x7 == 7 {
+        divisor3 += 1;
+    }
+    let mut divisor = divisor3 >> 3;
+    match divisor3 & 0x7 {
+        0 if divisor == 1 => divisor = 0,
+        0 => {}
+        1 => divisor |= 0xC000,
+        4.. => divisor |= 0x4000,
+        _ => divisor |= 0x8000,
+    }
+    divisor
+}
+
+/// Divisor with the full set of eighth fractions, `divisor3` in eighths.
+fn frac_divisor(divisor3: u32) -> u32 {
+    let divisor = (divisor3 >> 3) | (DIV_FRAC[(divisor3 & 0x7) as usize] << 14);
+    // Special encodings for divisors 1 and 1.5.
+    match divisor {
+        1 => 0,
+        0x4001 => 1,
+        d => d,
+    }
+}
+
+/// Encode `baud` into the 18-bit `SIO_SET_BAUD_RATE` divisor for `chip`.
+///
+/// Returns `None` if the rate is out of range.
+pub(crate) fn encode_baud_rate(chip: FtdiChip, baud: u32) -> Option<u32> {
+    if baud == 0 || baud > chip.max_baud_rate() {
+        return None;
+    }
+    let divisor3 = if chip.is_high_speed() && baud >= 1200 {
+        // Round 8 * clock / (10 * baud) to the nearest eighth.
+        let num = 8 * CLOCK_120MHZ as u64;
+        let den = 10 * baud as u64;
+        ((num + den / 2) / den) as u32
+    } else {
+        (CLOCK_48MHZ + baud) / (2 * baud)
+    };
+    if divisor3 >> 3 > 0x3FFF {
+        return None;
+    }
+
+    Some(match chip {
+        FtdiChip::Am => am_divisor(baud),
+        c if c.is_high_speed() && baud >= 1200 => frac_divisor(divisor3) | 1 << 17,
+        _ => frac_divisor(divisor3),
+    })
+}
+
+/// Remove the status header from each `mps`-sized packet in `buf`, moving the
+/// payload to the front. Returns the payload length.
+///
+/// The header of the last packet is stored in `status`.
+fn strip_status(buf: &mut [u8], mps: usize, status: &mut [u8; STATUS_LEN]) -> usize {
+    let mut out = 0;
+    let mut pos = 0;
+    while pos < buf.len() {
+        let end = (pos + mps).min(buf.len());
+        if end - pos >= STATUS_LEN {
+            status.copy_from_slice(&buf[pos..pos + STATUS_LEN]);
+            buf.copy_within(pos + STATUS_LEN..end, out);
+            out += end - pos - STATUS_LEN;
+        }
+        pos = end;
+    }
+    out
+}
+
+bitflags! {
+    /// Line status byte sent in the header of every bulk IN packet.
+    pub struct LineStatus: u8 {
+        /// Data ready.
+        const DATA_READY = 1 << 0;
+        /// Receive overrun.
+        const OVERRUN = 1 << 1;
+        /// Parity error.
+        const PARITY = 1 << 2;
+        /// Framing error.
+        const FRAMING = 1 << 3;
+        /// Break received.
+        const BREAK = 1 << 4;
+        /// Transmit holding register empty.
+        const TX_HOLDING_EMPTY = 1 << 5;
+        /// Transmitter empty.
+        const TX_EMPTY = 1 << 6;
+        /// Error in the receive FIFO.
+        const FIFO_ERROR = 1 << 7;
+    }
+}
+
+bitflags! {
+    /// Bitmask passed to [`FtdiPort::purge`].
+    pub struct PurgeMask: u8 {
+        /// Clear the transmit buffer.
+        const TX = 1 << 0;
+        /// Clear the receive buffer.
+        const RX = 1 << 1;
+        /// Clear both buffers.
+        const ALL = Self::TX.bits() | Self::RX.bits();
+    }
+}
+
+/// Flow-control mode.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FlowControl {
+    /// No flow control.
+    None,
+    /// RTS/CTS hardware handshake.
+    RtsCts,
+    /// DTR/DSR hardware handshake.
+    DtrDsr,
+    /// XON/XOFF software flow control with the given characters.
+    XonXoff {
+        /// Character resuming transmission.
+        xon: u8,
+        /// Character pausing transmission.
+        xoff: u8,
+    },
+}
+
+/// FTDI host driver error.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FtdiError {
+    /// Transfer error.
+    Transfer(PipeError),
+    /// No vendor-class interface at `interface_idx` with a bulk IN/OUT pair.
+    NoInterface,
+    /// Failed to allocate a pipe.
+    NoPipe,
+    /// Device response had an unexpected length.
+    InvalidResponse,
+    /// Argument was out of range for the chip.
+    InvalidArgument,
+}
+
+impl From<PipeError> for FtdiError {
+    fn from(e: PipeError) -> Self {
+        Self::Transfer(e)
+    }
+}
+
+impl core::fmt::Display for FtdiError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Transfer(_) => write!(f, "Transfer error"),
+            Self::NoInterface => write!(f, "No FTDI interface found"),
+            Self::NoPipe => write!(f, "No free pipe"),
+            Self::InvalidResponse => write!(f, "Invalid response from device"),
+            Self::InvalidArgument => write!(f, "Invalid argument"),
+        }
+    }
+}
+
+impl core::error::Error for FtdiError {}
+
+impl embedded_io_async::Error for FtdiError {
+    fn kind(&self) -> embedded_io_async::ErrorKind {
+        match self {
+            Self::Transfer(e) => match e {
+                PipeError::Disconnected => embedded_io_async::ErrorKind::NotConnected,
+                PipeError::BufferOverflow => embedded_io_async::ErrorKind::OutOfMemory,
+                PipeError::Timeout => embedded_io_async::ErrorKind::TimedOut,
+                _ => embedded_io_async::ErrorKind::Other,
+            },
+            Self::NoInterface => embedded_io_async::ErrorKind::NotFound,
+            Self::NoPipe => embedded_io_async::ErrorKind::OutOfMemory,
+            Self::InvalidResponse => embedded_io_async::ErrorKind::InvalidData,
+            Self::InvalidArgument => embedded_io_async::ErrorKind::InvalidInput,
+        }
+    }
+}
+
+/// FTDI device — owns the shared control pipe on endpoint 0.
+///
+/// Open one [`FtdiPort`] per UART interface via [`FtdiDevice::port`].
+/// See [`Cp210xDevice`](super::cp210x::Cp210xDevice) for the choice of
+/// raw mutex.
+pub struct FtdiDevice<'d, A, M = NoopRawMutex>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    alloc: A,
+    ctrl: Mutex<M, A::Pipe<pipe::Control, pipe::InOut>>,
+    chip: FtdiChip,
+    device_address: u8,
+    split: Option<SplitInfo>,
+    _phantom: PhantomData<&'d ()>,
+}
+
+impl<'d, A> FtdiDevice<'d, A, NoopRawMutex>
+where
+    A: UsbHostAllocator<'d>,
+{
+    /// Allocate the device-level control pipe on endpoint 0, using a
+    /// [`NoopRawMutex`] for the shared control pipe.
+    ///
+    /// Performs no I/O.
+    pub fn new(alloc: &A, enum_info: &EnumerationInfo, chip: FtdiChip) -> Result<Self, FtdiError> {
+        Self::new_with_raw_mutex(alloc, enum_info, chip)
+    }
+}
+
+impl<'d, A, M> FtdiDevice<'d, A, M>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    /// Allocate the device-level control pipe on endpoint 0, using
+    /// the caller-chosen raw mutex `M` for the shared control pipe.
+    ///
+    /// Performs no I/O.
+    pub fn new_with_raw_mutex(alloc: &A, enum_info: &EnumerationInfo, chip: FtdiChip) -> Result<Self, FtdiError> {
+        let ctrl_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts(0, UsbDirection::In),
+            ep_type: EndpointType::Control,
+            max_packet_size: enum_info.device_desc.max_packet_size0 as u16,
+            interval_ms: 0,
+        };
+
+        let device_address = enum_info.device_address;
+        let split = enum_info.split();
+
+        let ctrl = alloc
+            .alloc_pipe::<pipe::Control, pipe::InOut>(device_address, &ctrl_ep_info, split)
+            .map_err(|_| FtdiError::NoPipe)?;
+
+        Ok(Self {
+            alloc: alloc.clone(),
+            ctrl: Mutex::new(ctrl),
+            chip,
+            device_address,
+            split,
+            _phantom: PhantomData,
+        })
+    }
+
+    /// The chip revision this device was created for.
+    pub fn chip(&self) -> FtdiChip {
+        self.chip
+    }
+
+    /// Open the `interface_idx`-th UART port.
+    ///
+    /// Use `0` for single-port parts, `0..2` for FT2232 and `0..4` for
+    /// FT4232H. Allocates the bulk pipes but performs no I/O; call
+    /// [`FtdiPort::reset`] to start from a known state.
+    ///
+    /// The driver does not validate which port is in use.
+    /// Avoid opening the same port multiple times.
+    pub fn port<'dev>(
+        &'dev self,
+        config_desc: &[u8],
+        interface_idx: u8,
+    ) -> Result<FtdiPort<'dev, 'd, A, M>, FtdiError> {
+        let info = find_vendor_interface(config_desc, interface_idx).ok_or(FtdiError::NoInterface)?;
+
+        let in_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts((info.bulk_in_ep & 0x0F) as usize, UsbDirection::In),
+            ep_type: EndpointType::Bulk,
+            max_packet_size: info.bulk_in_mps,
+            interval_ms: 0,
+        };
+
+        let out_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts((info.bulk_out_ep & 0x0F) as usize, UsbDirection::Out),
+            ep_type: EndpointType::Bulk,
+            max_packet_size: info.bulk_out_mps,
+            interval_ms: 0,
+        };
+
+        let in_ch = self
+            .alloc
+            .alloc_pipe::<pipe::Bulk, pipe::In>(self.device_address, &in_ep_info, self.split)
+            .map_err(|_| FtdiError::NoPipe)?;
+        let out_ch = self
+            .alloc
+            .alloc_pipe::<pipe::Bulk, pipe::Out>(self.device_address, &out_ep_info, self.split)
+            .map_err(|_| FtdiError::NoPipe)?;
+
+        let mps = (info.bulk_in_mps as usize).clamp(STATUS_LEN + 1, RX_BUF_LEN);
+        let channel = if self.chip.is_multi_port() {
+            info.interface as u16 + 1
+        } else {
+            0
+        };
+
+        Ok(FtdiPort {
+            device: self,
+            in_ch,
+            out_ch,
+            interface: info.interface,
+            channel,
+            mps,
+            rx: [0; RX_BUF_LEN],
+            rx_start: 0,
+            rx_end: 0,
+            status: [0; STATUS_LEN],
+            data_config: 8,
+            lines: ModemStatus::empty(),
+        })
+    }
+}
+
+/// A single UART port on an [`FtdiDevice`].
+///
+/// Owns the bulk IN/OUT pipes for one interface plus a receive buffer of
+/// one high-speed packet, and borrows the device for control requests.
+pub struct FtdiPort<'dev, 'd, A, M = NoopRawMutex>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    device: &'dev FtdiDevice<'d, A, M>,
+    in_ch: A::Pipe<pipe::Bulk, pipe::In>,
+    out_ch: A::Pipe<pipe::Bulk, pipe::Out>,
+    interface: u8,
+    /// `wIndex` port selector, 0 on single-port chips.
+    channel: u16,
+    mps: usize,
+    rx: [u8; RX_BUF_LEN],
+    rx_start: usize,
+    rx_end: usize,
+    status: [u8; STATUS_LEN],
+    /// Last `SIO_SET_DATA` value without the break bit.
+    data_config: u16,
+    /// Last DTR/RTS levels set by the host.
+    lines: ModemStatus,
+}
+
+impl<'dev, 'd, A, M> FtdiPort<'dev, 'd, A, M>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    /// USB interface number this port is bound to.
+    pub fn interface(&self) -> u8 {
+        self.interface
+    }
+
+    async fn vendor_out(&mut self, request: u8, value: u16, index: u16) -> Result<(), FtdiError> {
+        let setup = SetupPacket::vendor_device_out(request, value, index, 0);
+        let mut ctrl = self.device.ctrl.lock().await;
+        ctrl.control_out(&setup.to_bytes(), &[]).await?;
+        Ok(())
+    }
+
+    async fn vendor_in(&mut self, request: u8, buf: &mut [u8]) -> Result<(), FtdiError> {
+        let setup = SetupPacket::vendor_device_in(request, 0, self.channel, buf.len() as u16);
+        let mut ctrl = self.device.ctrl.lock().await;
+        let n = ctrl.control_in(&setup.to_bytes(), buf).await?;
+        if n != buf.len() {
+            return Err(FtdiError::InvalidResponse);
+        }
+        Ok(())
+    }
+
+    /// Reset the UART and discard buffered data.
+    ///
+    /// Issues `SIO_RESET(SIO)`.
+    pub async fn reset(&mut self) -> Result<(), FtdiError> {
+        self.rx_start = 0;
+        self.rx_end = 0;
+        self.vendor_out(SIO_RESET, SIO_RESET_SIO, self.channel).await
+    }
+
+    /// Clear the selected TX and/or RX buffers.
+    ///
+    /// Issues `SIO_RESET(PURGE_RX)` and/or `SIO_RESET(PURGE_TX)`.
+    pub async fn purge(&mut self, mask: PurgeMask) -> Result<(), FtdiError> {
+        if mask.contains(PurgeMask::RX) {
+            self.rx_start = 0;
+            self.rx_end = 0;
+            self.vendor_out(SIO_RESET, SIO_RESET_PURGE_RX, self.channel).await?;
+        }
+        if mask.contains(PurgeMask::TX) {
+            self.vendor_out(SIO_RESET, SIO_RESET_PURGE_TX, self.channel).await?;
+        }
+        Ok(())
+    }
+
+    /// Program the UART baud rate in bauds per second.
+    ///
+    /// Issues `SIO_SET_BAUD_RATE` with the divisor of the chip's
+    /// baud-rate generator closest to `baud`.
+    pub async fn set_baud_rate(&mut self, baud: u32) -> Result<(), FtdiError> {
+        let divisor = encode_baud_rate(self.device.chip, baud).ok_or(FtdiError::InvalidArgument)?;
+        let mut index = (divisor >> 16) as u16;
+        if self.channel != 0 {
+            index = (index << 8) | self.channel;
+        }
+        self.vendor_out(SIO_SET_BAUD_RATE, divisor as u16, index).await
+    }
+
+    /// Program baud rate, data/stop bits and parity.
+    ///
+    /// Issues `SIO_SET_DATA` followed by [`set_baud_rate`](Self::set_baud_rate).
+    /// FTDI chips cannot report the line coding back.
+    ///
+    /// # Cancellation
+    ///
+    /// Not cancel-safe: dropping the future between the two control
+    /// transfers leaves the device with the new framing but the old
+    /// baud rate.
+    pub async fn set_line_coding(&mut self, coding: &LineCoding) -> Result<(), FtdiError> {
+        if !matches!(coding.data_bits, 7 | 8) {
+            return Err(FtdiError::InvalidArgument);
+        }
+        let config = coding.data_bits as u16 | (coding.parity as u16) << 8 | (coding.stop_bits as u16) << 11;
+        self.vendor_out(SIO_SET_DATA, config, self.channel).await?;
+        self.data_config = config;
+        self.set_baud_rate(coding.baud_rate).await
+    }
+
+    /// Drive DTR and RTS to the given levels.
+    ///
+    /// Issues `SIO_MODEM_CTRL` with both lines selected.
+    pub async fn set_control_line_state(&mut self, dtr: bool, rts: bool) -> Result<(), FtdiError> {
+        let value = dtr as u16 | (rts as u16) << 1 | SIO_SET_DTR_MASK | SIO_SET_RTS_MASK;
+        self.vendor_out(SIO_MODEM_CTRL, value, self.channel).await?;
+        self.lines = ModemStatus::empty();
+        self.lines.set(ModemStatus::DTR, dtr);
+        self.lines.set(ModemStatus::RTS, rts);
+        Ok(())
+    }
+
+    /// Read the modem status lines.
+    ///
+    /// Issues `SIO_GET_MODEM_STATUS`. The DTR and RTS bits reflect the
+    /// last [`set_control_line_state`](Self::set_control_line_state) call.
+    pub async fn modem_status(&mut self) -> Result<ModemStatus, FtdiError> {
+        let mut buf = [0u8; 2];
+        let len = if self.device.chip == FtdiChip::Am { 1 } else { 2 };
+        self.vendor_in(SIO_GET_MODEM_STATUS, &mut buf[..len]).await?;
+        // CTS, DSR, RI and DCD use the same bits as `ModemStatus`.
+        Ok(ModemStatus::from_bits_truncate(buf[0] & 0xF0) | self.lines.clone())
+    }
+
+    /// Line status reported in the header of the last received packet.
+    pub fn line_status(&self) -> LineStatus {
+        LineStatus::from_bits_truncate(self.status[1])
+    }
+
+    /// Assert or release a break condition on TX.
+    ///
+    /// Issues `SIO_SET_DATA` with the current line coding.
+    pub async fn set_break(&mut self, asserted: bool) -> Result<(), FtdiError> {
+        let value = if asserted {
+            self.data_config | SIO_SET_BREAK
+        } else {
+            self.data_config
+        };
+        self.vendor_out(SIO_SET_DATA, value, self.channel).await
+    }
+
+    /// Apply a flow-control mode.
+    ///
+    /// Issues `SIO_SET_FLOW_CTRL`.
+    pub async fn set_flow_control(&mut self, fc: FlowControl) -> Result<(), FtdiError> {
+        let (value, mode) = match fc {
+            FlowControl::None => (0, 0x00),
+            FlowControl::RtsCts => (0, 0x01),
+            FlowControl::DtrDsr => (0, 0x02),
+            FlowControl::XonXoff { xon, xoff } => ((xoff as u16) << 8 | xon as u16, 0x04),
+        };
+        self.vendor_out(SIO_SET_FLOW_CTRL, value, mode << 8 | self.channel)
+            .await
+    }
+
+    /// Set the latency timer in milliseconds, 1 to 255.
+    ///
+    /// The chip flushes a partially filled packet to the host when the
+    /// timer expires. Issues `SIO_SET_LATENCY_TIMER`.
+    pub async fn set_latency_timer(&mut self, ms: u8) -> Result<(), FtdiError> {
+        if ms == 0 {
+            return Err(FtdiError::InvalidArgument);
+        }
+        self.vendor_out(SIO_SET_LATENCY_TIMER, ms as u16, self.channel).await
+    }
+
+    /// Read the latency timer in milliseconds.
+    ///
+    /// Issues `SIO_GET_LATENCY_TIMER`.
+    pub async fn latency_timer(&mut self) -> Result<u8, FtdiError> {
+        let mut buf = [0u8; 1];
+        self.vendor_in(SIO_GET_LATENCY_TIMER, &mut buf).await?;
+        Ok(buf[0])
+    }
+
+    /// Read bytes from the UART receive stream.
+    ///
+    /// Status headers are removed; data that does not fit in `buf` is kept
+    /// for the next call.
+    ///
+    /// # Cancellation
+    ///
+    /// Cancel-safe once a packet has been received; dropping the future
+    /// while a transfer is in flight loses that packet.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, FtdiError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.rx_start == self.rx_end {
+            let len = RX_BUF_LEN - RX_BUF_LEN % self.mps;
+            let n = self.in_ch.request_in(&mut self.rx[..len]).await?;
+            self.rx_start = 0;
+            self.rx_end = strip_status(&mut self.rx[..n], self.mps, &mut self.status);
+        }
+        let n = (self.rx_end - self.rx_start).min(buf.len());
+        buf[..n].copy_from_slice(&self.rx[self.rx_start..self.rx_start + n]);
+        self.rx_start += n;
+        Ok(n)
+    }
+
+    /// Write bytes to the UART transmit stream.
+    ///
+    /// # Cancellation
+    ///
+    /// Not cancel-safe: the remote may observe partial data if the
+    /// future is dropped mid-transfer.
+    pub async fn write(&mut self, data: &[u8]) -> Result<usize, FtdiError> {
+        self.out_ch.request_out(data, false).await?;
+        Ok(data.len())
+    }
+}
+
+impl<'dev, 'd, A, M> embedded_io_async::ErrorType for FtdiPort<'dev, 'd, A, M>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    type Error = FtdiError;
+}
+
+impl<'dev, 'd, A, M> embedded_io_async::Read for FtdiPort<'dev, 'd, A, M>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        FtdiPort::read(self, buf).await
+    }
+}
+
+impl<'dev, 'd, A, M> embedded_io_async::Write for FtdiPort<'dev, 'd, A, M>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        FtdiPort::write(self, buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn baud_divisor_bm() {
+        // 3 MHz / 9600 = 312.5
+        assert_eq!(encode_baud_rate(FtdiChip::R, 9600), Some(0x4138));
+        assert_eq!(encode_baud_rate(FtdiChip::R, 115200), Some(26));
+        // Special encodings of divisors 1 and 1.5.
+        assert_eq!(encode_baud_rate(FtdiChip::Bm, 3_000_000), Some(0));
+        assert_eq!(encode_baud_rate(FtdiChip::Bm, 2_000_000), Some(1));
+        assert_eq!(encode_baud_rate(FtdiChip::X, 0), None);
+        assert_eq!(encode_baud_rate(FtdiChip::X, 4_000_000), None);
+        assert_eq!(encode_baud_rate(FtdiChip::X, 100), None);
+    }
+
+    #[test]
+    fn baud_divisor_am() {
+        assert_eq!(encode_baud_rate(FtdiChip::Am, 9600), Some(0x4138));
+        // 3 MHz / 38400 = 78.125
+        assert_eq!(encode_baud_rate(FtdiChip::Am, 38400), Some(0xC04E));
+    }
+
+    #[test]
+    fn baud_divisor_high_speed() {
+        assert_eq!(encode_baud_rate(FtdiChip::Ft232H, 12_000_000), Some(0x2_0000));
+        // 12 MHz / 115200 = 104.17, rounded to 104.125
+        assert_eq!(encode_baud_rate(FtdiChip::Ft2232H, 115200), Some(0x2_C068));
+        // Low rates fall back to the 48 MHz clock.
+        assert_eq!(encode_baud_rate(FtdiChip::Ft4232H, 300), Some(10000));
+    }
+
+    #[test]
+    fn strip_status_headers() {
+        let mut status = [0; STATUS_LEN];
+        let mut buf = [0x31, 0x60, 1, 2, 3, 4, 0x31, 0x62, 5];
+        let n = strip_status(&mut buf, 6, &mut status);
+        assert_eq!(&buf[..n], &[1, 2, 3, 4, 5]);
+        assert_eq!(status, [0x31, 0x62]);
+        assert_eq!(
+            LineStatus::from_bits_truncate(status[1]),
+            LineStatus::OVERRUN | LineStatus::TX_HOLDING_EMPTY | LineStatus::TX_EMPTY
+        );
+
+        // Status-only packet, as sent when the latency timer expires.
+        let mut buf = [0x01, 0x60];
+        assert_eq!(strip_status(&mut buf, 64, &mut status), 0);
+    }
+}
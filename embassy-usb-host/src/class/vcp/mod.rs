@@ -4,6 +4,280 @@
 //! transport data over bulk pipes and expose private control requests
 //! for line configuration, flow control, and modem signalling. Unlike
 //! CDC-ACM they carry no class descriptors, so device discovery is
-//! VID/PID based.
+//! VID/PID based: [`probe`] maps an [`EnumerationInfo`] to the driver
+//! for the chip.
+//!
+//! The line coding and modem status types are shared by all drivers.
+
+use embassy_usb_driver::EndpointType;
+
+use crate::descriptor::ConfigurationDescriptorChain;
+use crate::handler::EnumerationInfo;
+
+macro_rules! bitflags {
+    ($($tt:tt)*) => {
+        #[cfg(feature = "defmt")]
+        defmt::bitflags! { $($tt)* }
+        #[cfg(not(feature = "defmt"))]
+        bitflags::bitflags! { #[derive(Debug, Clone, PartialEq)] $($tt)* }
+    };
+}
 
+pub mod ch34x;
 pub mod cp210x;
+pub mod ftdi;
+pub mod pl2303;
+
+/// Parity setting.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Parity {
+    /// No parity bit.
+    None = 0,
+    /// Odd parity.
+    Odd = 1,
+    /// Even parity.
+    Even = 2,
+    /// Always 1.
+    Mark = 3,
+    /// Always 0.
+    Space = 4,
+}
+
+impl Parity {
+    fn from_bits(b: u8) -> Option<Self> {
+        Some(match b {
+            0 => Self::None,
+            1 => Self::Odd,
+            2 => Self::Even,
+            3 => Self::Mark,
+            4 => Self::Space,
+            _ => return None,
+        })
+    }
+}
+
+/// Number of stop bits.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum StopBits {
+    /// 1 stop bit.
+    One = 0,
+    /// 1.5 stop bits.
+    OneAndHalf = 1,
+    /// 2 stop bits.
+    Two = 2,
+}
+
+impl StopBits {
+    fn from_bits(b: u8) -> Option<Self> {
+        Some(match b {
+            0 => Self::One,
+            1 => Self::OneAndHalf,
+            2 => Self::Two,
+            _ => return None,
+        })
+    }
+}
+
+/// Serial line parameters.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LineCoding {
+    /// Baud rate in bits per second.
+    pub baud_rate: u32,
+    /// Data bits. Legal values are 5, 6, 7 and 8.
+    pub data_bits: u8,
+    /// Parity setting.
+    pub parity: Parity,
+    /// Stop bits.
+    pub stop_bits: StopBits,
+}
+
+impl Default for LineCoding {
+    fn default() -> Self {
+        Self {
+            baud_rate: 115200,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+        }
+    }
+}
+
+bitflags! {
+    /// Modem control and status lines.
+    ///
+    /// The bit layout is that of the CP210x `GET_MDMSTS` byte (AN571 §5.10); the
+    /// other drivers translate their chip's status into it.
+    pub struct ModemStatus: u8 {
+        /// DTR output asserted.
+        const DTR = 1 << 0;
+        /// RTS output asserted.
+        const RTS = 1 << 1;
+        /// CTS input asserted.
+        const CTS = 1 << 4;
+        /// DSR input asserted.
+        const DSR = 1 << 5;
+        /// Ring indicator input asserted.
+        const RI  = 1 << 6;
+        /// Data-carrier-detect input asserted.
+        const DCD = 1 << 7;
+    }
+}
+
+const VENDOR_CLASS: u8 = 0xFF;
+
+/// Endpoints of a vendor-class bridge interface.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct VendorInterface {
+    pub interface: u8,
+    pub bulk_in_ep: u8,
+    pub bulk_in_mps: u16,
+    pub bulk_out_ep: u8,
+    pub bulk_out_mps: u16,
+    /// Interrupt IN endpoint address, max packet size and interval, if present.
+    pub interrupt_in: Option<(u8, u16, u8)>,
+}
+
+/// Return the `n`th (0-indexed) vendor-class interface in `config_desc`
+/// that exposes a bulk IN + bulk OUT endpoint pair.
+pub(crate) fn find_vendor_interface(config_desc: &[u8], interface_idx: u8) -> Option<VendorInterface> {
+    let cfg = ConfigurationDescriptorChain::try_from_slice(config_desc).ok()?;
+
+    let mut seen = 0u8;
+    for iface in cfg.iter_interface() {
+        if iface.interface_class != VENDOR_CLASS || iface.alternate_setting != 0 {
+            continue;
+        }
+
+        let mut in_ep = None;
+        let mut out_ep = None;
+        let mut interrupt_in = None;
+        for ep in iface.iter_endpoints() {
+            match ep.ep_type() {
+                EndpointType::Bulk if ep.is_in() => in_ep = Some((ep.endpoint_address, ep.max_packet_size)),
+                EndpointType::Bulk => out_ep = Some((ep.endpoint_address, ep.max_packet_size)),
+                EndpointType::Interrupt if ep.is_in() => {
+                    interrupt_in = Some((ep.endpoint_address, ep.max_packet_size, ep.interval))
+                }
+                _ => {}
+            }
+        }
+
+        if let (Some((in_a, in_m)), Some((out_a, out_m))) = (in_ep, out_ep) {
+            if seen == interface_idx {
+                return Some(VendorInterface {
+                    interface: iface.interface_number,
+                    bulk_in_ep: in_a,
+                    bulk_in_mps: in_m,
+                    bulk_out_ep: out_a,
+                    bulk_out_mps: out_m,
+                    interrupt_in,
+                });
+            }
+            seen += 1;
+        }
+    }
+
+    None
+}
+
+/// USB-serial bridge identified by [`probe`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum VcpKind {
+    /// Silicon Labs CP210x, use [`cp210x`].
+    Cp210x,
+    /// FTDI FT232/FT2232/FT4232 family, use [`ftdi`].
+    Ftdi(ftdi::FtdiChip),
+    /// WCH CH340/CH341, use [`ch34x`].
+    Ch34x,
+    /// Prolific PL2303 family, use [`pl2303`].
+    Pl2303(pl2303::Pl2303Chip),
+    /// Bridge implementing standard CDC-ACM (WCH CH342/CH343/CH344/CH9102),
+    /// use [`cdc_acm`](crate::class::cdc_acm).
+    CdcAcm,
+}
+
+/// Pick the driver for a USB-serial bridge from its VID/PID.
+///
+/// FTDI and PL2303 chip revisions are told apart by `bcdDevice`, as they
+/// share product IDs. Returns `None` for devices that are not known bridges.
+pub fn probe(enum_info: &EnumerationInfo) -> Option<VcpKind> {
+    let desc = &enum_info.device_desc;
+    match (desc.vendor_id, desc.product_id) {
+        (cp210x::id::VID_SILABS, cp210x::id::PID_CP210X | cp210x::id::PID_CP210X_ALT) => Some(VcpKind::Cp210x),
+        (ftdi::id::VID_FTDI, ftdi::id::PID_FT232 | ftdi::id::PID_FT2232 | ftdi::id::PID_FT4232H)
+        | (ftdi::id::VID_FTDI, ftdi::id::PID_FT232H | ftdi::id::PID_FT_X) => {
+            ftdi::FtdiChip::detect(desc).map(VcpKind::Ftdi)
+        }
+        (ch34x::id::VID_WCH, ch34x::id::PID_CH340 | ch34x::id::PID_CH340K | ch34x::id::PID_CH341) => {
+            Some(VcpKind::Ch34x)
+        }
+        (ch34x::id::VID_WCH, ch34x::id::PID_CH342 | ch34x::id::PID_CH343 | ch34x::id::PID_CH344)
+        | (ch34x::id::VID_WCH, ch34x::id::PID_CH9102) => Some(VcpKind::CdcAcm),
+        (pl2303::id::VID_PROLIFIC, pid) if pl2303::id::PIDS.contains(&pid) => {
+            pl2303::Pl2303Chip::detect(desc).map(VcpKind::Pl2303)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_usb_driver::Speed;
+
+    use super::*;
+    use crate::descriptor::DeviceDescriptor;
+    use crate::handler::BusRoute;
+
+    fn info(vendor_id: u16, product_id: u16, bcd_device: u16) -> EnumerationInfo {
+        EnumerationInfo {
+            device_address: 1,
+            route: BusRoute::Direct(Speed::Full),
+            device_desc: DeviceDescriptor {
+                bcd_usb: 0x0200,
+                device_class: 0,
+                device_subclass: 0,
+                device_protocol: 0,
+                max_packet_size0: 64,
+                vendor_id,
+                product_id,
+                bcd_device,
+                manufacturer: 0,
+                product: 0,
+                serial_number: 0,
+                num_configurations: 1,
+            },
+        }
+    }
+
+    #[test]
+    fn probe_known_bridges() {
+        assert_eq!(probe(&info(0x10C4, 0xEA60, 0x0100)), Some(VcpKind::Cp210x));
+        assert_eq!(
+            probe(&info(0x0403, 0x6001, 0x0600)),
+            Some(VcpKind::Ftdi(ftdi::FtdiChip::R))
+        );
+        assert_eq!(
+            probe(&info(0x0403, 0x6010, 0x0700)),
+            Some(VcpKind::Ftdi(ftdi::FtdiChip::Ft2232H))
+        );
+        assert_eq!(probe(&info(0x1A86, 0x7523, 0x0264)), Some(VcpKind::Ch34x));
+        assert_eq!(probe(&info(0x1A86, 0x55D3, 0x0442)), Some(VcpKind::CdcAcm));
+        assert_eq!(
+            probe(&info(0x067B, 0x23A3, 0x0100)),
+            Some(VcpKind::Pl2303(pl2303::Pl2303Chip::Hxn))
+        );
+    }
+
+    #[test]
+    fn probe_unknown() {
+        assert_eq!(probe(&info(0x1234, 0x5678, 0x0100)), None);
+        // Known PID with an unknown revision.
+        assert_eq!(probe(&info(0x0403, 0x6001, 0x0300)), None);
+    }
+}
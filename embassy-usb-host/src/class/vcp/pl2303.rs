@@ -0,0 +1,763 @@
+//! Prolific PL2303 USB ↔ UART bridge driver.
+//!
+//! Implements the PL2303 protocol: vendor-class bulk data transport (one
+//! bulk IN + one bulk OUT), CDC-style class requests for line coding,
+//! control lines and break, and vendor register accesses for chip setup
+//! and flow control. Modem status changes arrive as notifications on the
+//! interrupt IN endpoint.
+//!
+//! Covers the legacy PL2303H, the PL2303HX/HXD/TA family and the newer
+//! PL2303G series (HXN). Chip revisions share product IDs and are told
+//! apart by the device descriptor, see [`Pl2303Chip::detect`].
+//!
+//! PL2303 chips have a single UART, so [`Pl2303Device::port`] takes no
+//! interface index.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! use embassy_usb_host::class::vcp::pl2303::{LineCoding, Pl2303Chip, Pl2303Device};
+//!
+//! let chip = Pl2303Chip::detect(&enum_info.device_desc).unwrap();
+//! let device = Pl2303Device::new(&bus, &enum_info, chip)?;
+//! let mut port = device.port(&config_buf[..config_len])?;
+//! port.enable().await?;
+//! port.set_line_coding(&LineCoding::default()).await?;
+//! port.set_control_line_state(true, true).await?;
+//!
+//! let mut buf = [0u8; 64];
+//! let n = port.read(&mut buf).await?;
+//! port.write(&buf[..n]).await?;
+//! ```
+
+use core::marker::PhantomData;
+
+use embassy_sync::blocking_mutex::raw::{NoopRawMutex, RawMutex};
+use embassy_sync::mutex::Mutex;
+use embassy_usb_driver::host::{PipeError, SplitInfo, UsbHostAllocator, UsbPipe, pipe};
+use embassy_usb_driver::{Direction as UsbDirection, EndpointAddress, EndpointInfo, EndpointType};
+
+use super::find_vendor_interface;
+pub use super::{LineCoding, ModemStatus, Parity, StopBits};
+use crate::control::SetupPacket;
+use crate::descriptor::DeviceDescriptor;
+use crate::handler::EnumerationInfo;
+
+/// Prolific VID and PL2303 PIDs.
+pub mod id {
+    /// Prolific Technology vendor ID.
+    pub const VID_PROLIFIC: u16 = 0x067B;
+    /// PL2303 (H/HX/HXD/TA) product ID.
+    pub const PID_PL2303: u16 = 0x2303;
+    /// PL2303GC product ID.
+    pub const PID_PL2303GC: u16 = 0x23A3;
+    /// PL2303GB product ID.
+    pub const PID_PL2303GB: u16 = 0x23B3;
+    /// PL2303GT product ID.
+    pub const PID_PL2303GT: u16 = 0x23C3;
+    /// PL2303GL product ID.
+    pub const PID_PL2303GL: u16 = 0x23D3;
+    /// PL2303GE product ID.
+    pub const PID_PL2303GE: u16 = 0x23E3;
+    /// PL2303GS product ID.
+    pub const PID_PL2303GS: u16 = 0x23F3;
+
+    /// All PL2303 product IDs.
+    pub const PIDS: &[u16] = &[
+        PID_PL2303,
+        PID_PL2303GC,
+        PID_PL2303GB,
+        PID_PL2303GT,
+        PID_PL2303GL,
+        PID_PL2303GE,
+        PID_PL2303GS,
+    ];
+}
+
+// Class requests (interface recipient).
+const SET_LINE_REQUEST: u8 = 0x20;
+const GET_LINE_REQUEST: u8 = 0x21;
+const SET_CONTROL_REQUEST: u8 = 0x22;
+const BREAK_REQUEST: u8 = 0x23;
+
+const CONTROL_DTR: u16 = 0x01;
+const CONTROL_RTS: u16 = 0x02;
+const BREAK_ON: u16 = 0xFFFF;
+const BREAK_OFF: u16 = 0x0000;
+
+// Vendor register requests.
+const VENDOR_READ_REQUEST: u8 = 0x01;
+const VENDOR_WRITE_REQUEST: u8 = 0x01;
+const HXN_VENDOR_READ_REQUEST: u8 = 0x81;
+const HXN_VENDOR_WRITE_REQUEST: u8 = 0x80;
+
+const FLOWCTRL_REG: u16 = 0x00;
+const FLOWCTRL_MASK: u8 = 0xF0;
+const HXN_RESET_REG: u16 = 0x07;
+const HXN_RESET_PIPES: u16 = 0x03;
+const HXN_FLOWCTRL_REG: u16 = 0x0A;
+const HXN_FLOWCTRL_MASK: u8 = 0x1C;
+
+/// Register read that only the TA revision answers.
+const TA_PROBE_REG: u16 = 0x8080;
+
+/// Offset of the UART state byte in interrupt notifications.
+const UART_STATE_INDEX: usize = 8;
+const UART_DCD: u8 = 0x01;
+const UART_DSR: u8 = 0x02;
+const UART_RING: u8 = 0x08;
+const UART_CTS: u8 = 0x80;
+
+/// Baud rates the chips generate exactly.
+const SUPPORTED_BAUD_RATES: [u32; 25] = [
+    75, 150, 300, 600, 1200, 1800, 2400, 3600, 4800, 7200, 9600, 14400, 19200, 28800, 38400, 57600, 115200, 230400,
+    460800, 614400, 921600, 1228800, 2457600, 3000000, 6000000,
+];
+
+/// Base of the divisor encoding.
+const DIVISOR_BASELINE: u32 = 12_000_000 * 32;
+
+/// PL2303 chip revision.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Pl2303Chip {
+    /// Legacy PL2303H.
+    H,
+    /// PL2303HX (rev A).
+    Hx,
+    /// PL2303HXD, EA, RA and SA.
+    Hxd,
+    /// PL2303TA.
+    Ta,
+    /// PL2303G series (GC, GB, GT, GL, GE, GS).
+    Hxn,
+}
+
+impl Pl2303Chip {
+    /// Identify the chip from its device descriptor.
+    ///
+    /// PL2303TA and some PL2303G parts share descriptors and are both
+    /// reported as [`Hxn`](Self::Hxn); [`Pl2303Port::enable`] tells them
+    /// apart. Returns `None` for unknown revisions.
+    pub fn detect(desc: &DeviceDescriptor) -> Option<Self> {
+        if desc.device_class == 0x02 || desc.max_packet_size0 != 64 {
+            return Some(Self::H);
+        }
+        match (desc.bcd_usb, desc.bcd_device) {
+            (0x0101 | 0x0110, 0x0400) => Some(Self::Hxd),
+            (0x0101 | 0x0110, _) => Some(Self::Hx),
+            (0x0200, 0x0100 | 0x0105 | 0x0300 | 0x0305 | 0x0400 | 0x0405) => Some(Self::Hxn),
+            _ => None,
+        }
+    }
+
+    /// Highest supported baud rate.
+    pub const fn max_baud_rate(self) -> u32 {
+        match self {
+            Self::H => 1_228_800,
+            Self::Hx | Self::Ta => 6_000_000,
+            Self::Hxd | Self::Hxn => 12_000_000,
+        }
+    }
+}
+
+/// Nearest rate in [`SUPPORTED_BAUD_RATES`].
+fn nearest_supported_baud_rate(baud: u32) -> u32 {
+    let i = SUPPORTED_BAUD_RATES.partition_point(|&b| b <= baud);
+    let Some(&hi) = SUPPORTED_BAUD_RATES.get(i) else {
+        return SUPPORTED_BAUD_RATES[i - 1];
+    };
+    if i > 0 && hi - baud > baud - SUPPORTED_BAUD_RATES[i - 1] {
+        SUPPORTED_BAUD_RATES[i - 1]
+    } else {
+        hi
+    }
+}
+
+/// Divisor encoding of HX/HXD: 9-bit mantissa, exponent in powers of 4.
+fn divisor(baud: u32) -> [u8; 4] {
+    let mut mantissa = (DIVISOR_BASELINE / baud).max(1);
+    let mut exponent = 0;
+    while mantissa >= 512 {
+        if exponent < 7 {
+            mantissa >>= 2;
+            exponent += 1;
+        } else {
+            mantissa = 511;
+            break;
+        }
+    }
+    [mantissa as u8, (exponent << 1 | mantissa >> 8) as u8, 0, 0x80]
+}
+
+/// Divisor encoding of TA: 11-bit mantissa, exponent in powers of 2.
+fn divisor_alt(baud: u32) -> [u8; 4] {
+    let mut mantissa = (DIVISOR_BASELINE / baud).max(1);
+    let mut exponent = 0;
+    while mantissa >= 2048 {
+        if exponent < 15 {
+            mantissa >>= 1;
+            exponent += 1;
+        } else {
+            mantissa = 2047;
+            break;
+        }
+    }
+    [
+        mantissa as u8,
+        ((exponent & !0x01) << 4 | mantissa >> 8) as u8,
+        (exponent & 0x01) as u8,
+        0x80,
+    ]
+}
+
+/// Encode `baud` into the `dwDTERate` field of the line request for `chip`.
+///
+/// Rates above the chip maximum are clamped. Supported rates are sent as
+/// is; others use the divisor encoding, except on HXN which only takes
+/// supported rates.
+pub(crate) fn encode_baud_rate(chip: Pl2303Chip, baud: u32) -> [u8; 4] {
+    let baud = baud.min(chip.max_baud_rate());
+    let supported = nearest_supported_baud_rate(baud);
+    match chip {
+        Pl2303Chip::Hxn => supported.to_le_bytes(),
+        Pl2303Chip::Ta => divisor_alt(baud),
+        _ if baud == supported => baud.to_le_bytes(),
+        _ => divisor(baud),
+    }
+}
+
+/// Convert an interrupt notification into modem status bits.
+///
+/// Returns `None` for notifications too short to carry the UART state.
+fn parse_notification(buf: &[u8]) -> Option<ModemStatus> {
+    let state = *buf.get(UART_STATE_INDEX)?;
+    let mut out = ModemStatus::empty();
+    out.set(ModemStatus::DCD, state & UART_DCD != 0);
+    out.set(ModemStatus::DSR, state & UART_DSR != 0);
+    out.set(ModemStatus::RI, state & UART_RING != 0);
+    out.set(ModemStatus::CTS, state & UART_CTS != 0);
+    Some(out)
+}
+
+/// Flow-control mode.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FlowControl {
+    /// No flow control.
+    None,
+    /// RTS/CTS hardware handshake.
+    RtsCts,
+    /// XON/XOFF software flow control with the default characters.
+    XonXoff,
+}
+
+/// PL2303 host driver error.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Pl2303Error {
+    /// Transfer error.
+    Transfer(PipeError),
+    /// No vendor-class interface with a bulk IN/OUT pair.
+    NoInterface,
+    /// Failed to allocate a pipe.
+    NoPipe,
+    /// Device response had an unexpected length.
+    InvalidResponse,
+    /// Argument was out of range for the chip.
+    InvalidArgument,
+}
+
+impl From<PipeError> for Pl2303Error {
+    fn from(e: PipeError) -> Self {
+        Self::Transfer(e)
+    }
+}
+
+impl core::fmt::Display for Pl2303Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Transfer(_) => write!(f, "Transfer error"),
+            Self::NoInterface => write!(f, "No PL2303 interface found"),
+            Self::NoPipe => write!(f, "No free pipe"),
+            Self::InvalidResponse => write!(f, "Invalid response from device"),
+            Self::InvalidArgument => write!(f, "Invalid argument"),
+        }
+    }
+}
+
+impl core::error::Error for Pl2303Error {}
+
+impl embedded_io_async::Error for Pl2303Error {
+    fn kind(&self) -> embedded_io_async::ErrorKind {
+        match self {
+            Self::Transfer(e) => match e {
+                PipeError::Disconnected => embedded_io_async::ErrorKind::NotConnected,
+                PipeError::BufferOverflow => embedded_io_async::ErrorKind::OutOfMemory,
+                PipeError::Timeout => embedded_io_async::ErrorKind::TimedOut,
+                _ => embedded_io_async::ErrorKind::Other,
+            },
+            Self::NoInterface => embedded_io_async::ErrorKind::NotFound,
+            Self::NoPipe => embedded_io_async::ErrorKind::OutOfMemory,
+            Self::InvalidResponse => embedded_io_async::ErrorKind::InvalidData,
+            Self::InvalidArgument => embedded_io_async::ErrorKind::InvalidInput,
+        }
+    }
+}
+
+/// PL2303 device — owns the control pipe on endpoint 0.
+///
+/// Open the UART via [`Pl2303Device::port`].
+pub struct Pl2303Device<'d, A, M = NoopRawMutex>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    alloc: A,
+    ctrl: Mutex<M, A::Pipe<pipe::Control, pipe::InOut>>,
+    chip: Pl2303Chip,
+    device_address: u8,
+    split: Option<SplitInfo>,
+    _phantom: PhantomData<&'d ()>,
+}
+
+impl<'d, A> Pl2303Device<'d, A, NoopRawMutex>
+where
+    A: UsbHostAllocator<'d>,
+{
+    /// Allocate the device-level control pipe on endpoint 0, using a
+    /// [`NoopRawMutex`] for the control pipe.
+    ///
+    /// Performs no I/O.
+    pub fn new(alloc: &A, enum_info: &EnumerationInfo, chip: Pl2303Chip) -> Result<Self, Pl2303Error> {
+        Self::new_with_raw_mutex(alloc, enum_info, chip)
+    }
+}
+
+impl<'d, A, M> Pl2303Device<'d, A, M>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    /// Allocate the device-level control pipe on endpoint 0, using
+    /// the caller-chosen raw mutex `M` for the control pipe.
+    ///
+    /// Performs no I/O.
+    pub fn new_with_raw_mutex(alloc: &A, enum_info: &EnumerationInfo, chip: Pl2303Chip) -> Result<Self, Pl2303Error> {
+        let ctrl_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts(0, UsbDirection::In),
+            ep_type: EndpointType::Control,
+            max_packet_size: enum_info.device_desc.max_packet_size0 as u16,
+            interval_ms: 0,
+        };
+
+        let device_address = enum_info.device_address;
+        let split = enum_info.split();
+
+        let ctrl = alloc
+            .alloc_pipe::<pipe::Control, pipe::InOut>(device_address, &ctrl_ep_info, split)
+            .map_err(|_| Pl2303Error::NoPipe)?;
+
+        Ok(Self {
+            alloc: alloc.clone(),
+            ctrl: Mutex::new(ctrl),
+            chip,
+            device_address,
+            split,
+            _phantom: PhantomData,
+        })
+    }
+
+    /// Open the UART.
+    ///
+    /// Allocates the bulk pipes and, if present, the interrupt pipe for
+    /// modem status notifications, but performs no I/O; call
+    /// [`Pl2303Port::enable`] before use.
+    pub fn port<'dev>(&'dev self, config_desc: &[u8]) -> Result<Pl2303Port<'dev, 'd, A, M>, Pl2303Error> {
+        let info = find_vendor_interface(config_desc, 0).ok_or(Pl2303Error::NoInterface)?;
+
+        let in_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts((info.bulk_in_ep & 0x0F) as usize, UsbDirection::In),
+            ep_type: EndpointType::Bulk,
+            max_packet_size: info.bulk_in_mps,
+            interval_ms: 0,
+        };
+
+        let out_ep_info = EndpointInfo {
+            addr: EndpointAddress::from_parts((info.bulk_out_ep & 0x0F) as usize, UsbDirection::Out),
+            ep_type: EndpointType::Bulk,
+            max_packet_size: info.bulk_out_mps,
+            interval_ms: 0,
+        };
+
+        let in_ch = self
+            .alloc
+            .alloc_pipe::<pipe::Bulk, pipe::In>(self.device_address, &in_ep_info, self.split)
+            .map_err(|_| Pl2303Error::NoPipe)?;
+        let out_ch = self
+            .alloc
+            .alloc_pipe::<pipe::Bulk, pipe::Out>(self.device_address, &out_ep_info, self.split)
+            .map_err(|_| Pl2303Error::NoPipe)?;
+
+        let int_ch = match info.interrupt_in {
+            Some((ep, mps, interval)) => {
+                let int_ep_info = EndpointInfo {
+                    addr: EndpointAddress::from_parts((ep & 0x0F) as usize, UsbDirection::In),
+                    ep_type: EndpointType::Interrupt,
+                    max_packet_size: mps,
+                    interval_ms: interval,
+                };
+                Some(
+                    self.alloc
+                        .alloc_pipe::<pipe::Interrupt, pipe::In>(self.device_address, &int_ep_info, self.split)
+                        .map_err(|_| Pl2303Error::NoPipe)?,
+                )
+            }
+            None => None,
+        };
+
+        Ok(Pl2303Port {
+            device: self,
+            in_ch,
+            out_ch,
+            int_ch,
+            interface: info.interface,
+            chip: self.chip,
+            lines: ModemStatus::empty(),
+            status: ModemStatus::empty(),
+        })
+    }
+}
+
+/// The UART of a [`Pl2303Device`].
+///
+/// Owns the bulk IN/OUT pipes and the interrupt IN pipe, and borrows the
+/// device for control requests.
+pub struct Pl2303Port<'dev, 'd, A, M = NoopRawMutex>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    device: &'dev Pl2303Device<'d, A, M>,
+    in_ch: A::Pipe<pipe::Bulk, pipe::In>,
+    out_ch: A::Pipe<pipe::Bulk, pipe::Out>,
+    int_ch: Option<A::Pipe<pipe::Interrupt, pipe::In>>,
+    interface: u8,
+    /// Chip revision, refined by [`Pl2303Port::enable`].
+    chip: Pl2303Chip,
+    /// Last DTR/RTS levels set by the host.
+    lines: ModemStatus,
+    /// Input lines from the last interrupt notification.
+    status: ModemStatus,
+}
+
+impl<'dev, 'd, A, M> Pl2303Port<'dev, 'd, A, M>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    /// Chip revision, refined by [`enable`](Self::enable).
+    pub fn chip(&self) -> Pl2303Chip {
+        self.chip
+    }
+
+    async fn vendor_read(&mut self, reg: u16) -> Result<u8, Pl2303Error> {
+        let request = if self.chip == Pl2303Chip::Hxn {
+            HXN_VENDOR_READ_REQUEST
+        } else {
+            VENDOR_READ_REQUEST
+        };
+        let setup = SetupPacket::vendor_device_in(request, reg, 0, 1);
+        let mut buf = [0u8; 1];
+        let mut ctrl = self.device.ctrl.lock().await;
+        let n = ctrl.control_in(&setup.to_bytes(), &mut buf).await?;
+        if n != 1 {
+            return Err(Pl2303Error::InvalidResponse);
+        }
+        Ok(buf[0])
+    }
+
+    async fn vendor_write(&mut self, reg: u16, value: u16) -> Result<(), Pl2303Error> {
+        let request = if self.chip == Pl2303Chip::Hxn {
+            HXN_VENDOR_WRITE_REQUEST
+        } else {
+            VENDOR_WRITE_REQUEST
+        };
+        let setup = SetupPacket::vendor_device_out(request, reg, value, 0);
+        let mut ctrl = self.device.ctrl.lock().await;
+        ctrl.control_out(&setup.to_bytes(), &[]).await?;
+        Ok(())
+    }
+
+    async fn update_reg(&mut self, reg: u16, mask: u8, value: u8) -> Result<(), Pl2303Error> {
+        let read_reg = if self.chip == Pl2303Chip::Hxn { reg } else { reg | 0x80 };
+        let old = self.vendor_read(read_reg).await?;
+        self.vendor_write(reg, ((old & !mask) | (value & mask)) as u16).await
+    }
+
+    async fn class_out(&mut self, request: u8, value: u16, data: &[u8]) -> Result<(), Pl2303Error> {
+        let setup = SetupPacket::class_interface_out(request, value, self.interface as u16, data.len() as u16);
+        let mut ctrl = self.device.ctrl.lock().await;
+        ctrl.control_out(&setup.to_bytes(), data).await?;
+        Ok(())
+    }
+
+    /// Run the chip initialization sequence and reset the data pipes.
+    ///
+    /// Also tells a PL2303TA apart from the PL2303G parts it shares
+    /// descriptors with.
+    pub async fn enable(&mut self) -> Result<(), Pl2303Error> {
+        if self.chip == Pl2303Chip::Hxn {
+            // Only the TA answers this legacy register read.
+            self.chip = Pl2303Chip::Ta;
+            if self.vendor_read(TA_PROBE_REG).await.is_err() {
+                self.chip = Pl2303Chip::Hxn;
+            }
+        }
+
+        if self.chip == Pl2303Chip::Hxn {
+            return self.vendor_write(HXN_RESET_REG, HXN_RESET_PIPES).await;
+        }
+
+        self.vendor_read(0x8484).await?;
+        self.vendor_write(0x0404, 0).await?;
+        self.vendor_read(0x8484).await?;
+        self.vendor_read(0x8383).await?;
+        self.vendor_read(0x8484).await?;
+        self.vendor_write(0x0404, 1).await?;
+        self.vendor_read(0x8484).await?;
+        self.vendor_read(0x8383).await?;
+        self.vendor_write(0, 1).await?;
+        self.vendor_write(1, 0).await?;
+        let legacy = self.chip == Pl2303Chip::H;
+        self.vendor_write(2, if legacy { 0x24 } else { 0x44 }).await?;
+        if !legacy {
+            self.vendor_write(8, 0).await?;
+            self.vendor_write(9, 0).await?;
+        }
+        Ok(())
+    }
+
+    /// Program baud rate, data/stop bits and parity.
+    ///
+    /// Issues `SET_LINE_REQUEST`. Rates the chip can't generate exactly
+    /// are approximated; read back the result with
+    /// [`line_coding`](Self::line_coding).
+    pub async fn set_line_coding(&mut self, coding: &LineCoding) -> Result<(), Pl2303Error> {
+        if !(5..=8).contains(&coding.data_bits) || coding.baud_rate == 0 {
+            return Err(Pl2303Error::InvalidArgument);
+        }
+        let mut buf = [0u8; 7];
+        buf[..4].copy_from_slice(&encode_baud_rate(self.chip, coding.baud_rate));
+        buf[4] = coding.stop_bits as u8;
+        buf[5] = coding.parity as u8;
+        buf[6] = coding.data_bits;
+        self.class_out(SET_LINE_REQUEST, 0, &buf).await
+    }
+
+    /// Read the current line coding.
+    ///
+    /// Issues `GET_LINE_REQUEST`. For rates set through a divisor the
+    /// baud rate field holds the raw divisor encoding.
+    pub async fn line_coding(&mut self) -> Result<LineCoding, Pl2303Error> {
+        let setup = SetupPacket::class_interface_in(GET_LINE_REQUEST, 0, self.interface as u16, 7);
+        let mut buf = [0u8; 7];
+        let n = {
+            let mut ctrl = self.device.ctrl.lock().await;
+            ctrl.control_in(&setup.to_bytes(), &mut buf).await?
+        };
+        if n != buf.len() {
+            return Err(Pl2303Error::InvalidResponse);
+        }
+        Ok(LineCoding {
+            baud_rate: u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            stop_bits: StopBits::from_bits(buf[4]).ok_or(Pl2303Error::InvalidResponse)?,
+            parity: Parity::from_bits(buf[5]).ok_or(Pl2303Error::InvalidResponse)?,
+            data_bits: buf[6],
+        })
+    }
+
+    /// Drive DTR and RTS to the given levels.
+    ///
+    /// Issues `SET_CONTROL_REQUEST`.
+    pub async fn set_control_line_state(&mut self, dtr: bool, rts: bool) -> Result<(), Pl2303Error> {
+        let mut value = 0;
+        if dtr {
+            value |= CONTROL_DTR;
+        }
+        if rts {
+            value |= CONTROL_RTS;
+        }
+        self.class_out(SET_CONTROL_REQUEST, value, &[]).await?;
+        self.lines = ModemStatus::empty();
+        self.lines.set(ModemStatus::DTR, dtr);
+        self.lines.set(ModemStatus::RTS, rts);
+        Ok(())
+    }
+
+    /// Assert or release a break condition on TX.
+    ///
+    /// Issues `BREAK_REQUEST`.
+    pub async fn set_break(&mut self, asserted: bool) -> Result<(), Pl2303Error> {
+        let value = if asserted { BREAK_ON } else { BREAK_OFF };
+        self.class_out(BREAK_REQUEST, value, &[]).await
+    }
+
+    /// Apply a flow-control mode.
+    ///
+    /// Read-modify-writes the chip's flow-control register.
+    pub async fn set_flow_control(&mut self, fc: FlowControl) -> Result<(), Pl2303Error> {
+        if self.chip == Pl2303Chip::Hxn {
+            let value = match fc {
+                FlowControl::None => 0x1C,
+                FlowControl::RtsCts => 0x18,
+                FlowControl::XonXoff => 0x0C,
+            };
+            return self.update_reg(HXN_FLOWCTRL_REG, HXN_FLOWCTRL_MASK, value).await;
+        }
+        let value = match fc {
+            FlowControl::None => 0x00,
+            FlowControl::RtsCts if self.chip == Pl2303Chip::H => 0x40,
+            FlowControl::RtsCts => 0x60,
+            FlowControl::XonXoff => 0xC0,
+        };
+        self.update_reg(FLOWCTRL_REG, FLOWCTRL_MASK, value).await
+    }
+
+    /// Modem status from the last interrupt notification.
+    ///
+    /// The DTR and RTS bits reflect the last
+    /// [`set_control_line_state`](Self::set_control_line_state) call.
+    pub fn modem_status(&self) -> ModemStatus {
+        self.status.clone() | self.lines.clone()
+    }
+
+    /// Wait for the next modem status notification on the interrupt
+    /// endpoint and return the updated status.
+    ///
+    /// Returns [`Pl2303Error::NoPipe`] if the interface has no interrupt
+    /// endpoint.
+    pub async fn wait_modem_status(&mut self) -> Result<ModemStatus, Pl2303Error> {
+        let int_ch = self.int_ch.as_mut().ok_or(Pl2303Error::NoPipe)?;
+        let mut buf = [0u8; 16];
+        loop {
+            let n = int_ch.request_in(&mut buf).await?;
+            if let Some(status) = parse_notification(&buf[..n]) {
+                self.status = status;
+                return Ok(self.modem_status());
+            }
+        }
+    }
+
+    /// Read bytes from the UART receive stream.
+    ///
+    /// # Cancellation
+    ///
+    /// Not cancel-safe: dropping the future mid-transfer loses any
+    /// bytes the device already sent in that transfer.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Pl2303Error> {
+        Ok(self.in_ch.request_in(buf).await?)
+    }
+
+    /// Write bytes to the UART transmit stream.
+    ///
+    /// # Cancellation
+    ///
+    /// Not cancel-safe: the remote may observe partial data if the
+    /// future is dropped mid-transfer.
+    pub async fn write(&mut self, data: &[u8]) -> Result<usize, Pl2303Error> {
+        self.out_ch.request_out(data, false).await?;
+        Ok(data.len())
+    }
+}
+
+impl<'dev, 'd, A, M> embedded_io_async::ErrorType for Pl2303Port<'dev, 'd, A, M>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    type Error = Pl2303Error;
+}
+
+impl<'dev, 'd, A, M> embedded_io_async::Read for Pl2303Port<'dev, 'd, A, M>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        Pl2303Port::read(self, buf).await
+    }
+}
+
+impl<'dev, 'd, A, M> embedded_io_async::Write for Pl2303Port<'dev, 'd, A, M>
+where
+    A: UsbHostAllocator<'d>,
+    M: RawMutex,
+{
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        Pl2303Port::write(self, buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn desc(device_class: u8, mps0: u8, bcd_usb: u16, bcd_device: u16) -> DeviceDescriptor {
+        DeviceDescriptor {
+            bcd_usb,
+            device_class,
+            device_subclass: 0,
+            device_protocol: 0,
+            max_packet_size0: mps0,
+            vendor_id: id::VID_PROLIFIC,
+            product_id: id::PID_PL2303,
+            bcd_device,
+            manufacturer: 0,
+            product: 0,
+            serial_number: 0,
+            num_configurations: 1,
+        }
+    }
+
+    #[test]
+    fn detect_chip() {
+        assert_eq!(Pl2303Chip::detect(&desc(0x02, 64, 0x110, 0x300)), Some(Pl2303Chip::H));
+        assert_eq!(Pl2303Chip::detect(&desc(0x00, 8, 0x110, 0x300)), Some(Pl2303Chip::H));
+        assert_eq!(Pl2303Chip::detect(&desc(0x00, 64, 0x110, 0x300)), Some(Pl2303Chip::Hx));
+        assert_eq!(Pl2303Chip::detect(&desc(0x00, 64, 0x110, 0x400)), Some(Pl2303Chip::Hxd));
+        assert_eq!(Pl2303Chip::detect(&desc(0x00, 64, 0x200, 0x100)), Some(Pl2303Chip::Hxn));
+        assert_eq!(Pl2303Chip::detect(&desc(0x00, 64, 0x200, 0x300)), Some(Pl2303Chip::Hxn));
+        assert_eq!(Pl2303Chip::detect(&desc(0x00, 64, 0x200, 0x999)), None);
+    }
+
+    #[test]
+    fn baud_rate() {
+        assert_eq!(nearest_supported_baud_rate(9000), 9600);
+        assert_eq!(nearest_supported_baud_rate(100_000), 115200);
+        assert_eq!(nearest_supported_baud_rate(1), 75);
+        assert_eq!(nearest_supported_baud_rate(u32::MAX), 6_000_000);
+
+        // Supported rates are sent directly.
+        assert_eq!(encode_baud_rate(Pl2303Chip::Hx, 115200), 115200u32.to_le_bytes());
+        // HXN only takes supported rates.
+        assert_eq!(encode_baud_rate(Pl2303Chip::Hxn, 100_000), 115200u32.to_le_bytes());
+        // 384 MHz / 250000 = 1536 = 384 << 2
+        assert_eq!(encode_baud_rate(Pl2303Chip::Hxd, 250_000), [0x80, 0x03, 0x00, 0x80]);
+        // The TA has an 11-bit mantissa: 1536 = 0x600
+        assert_eq!(encode_baud_rate(Pl2303Chip::Ta, 250_000), [0x00, 0x06, 0x00, 0x80]);
+        // Clamped to the chip maximum.
+        assert_eq!(encode_baud_rate(Pl2303Chip::H, 6_000_000), 1_228_800u32.to_le_bytes());
+    }
+
+    #[test]
+    fn notification() {
+        let buf = [0xA1, 0x20, 0, 0, 0, 0, 2, 0, UART_CTS | UART_DCD, 0];
+        assert_eq!(parse_notification(&buf), Some(ModemStatus::CTS | ModemStatus::DCD));
+        assert_eq!(parse_notification(&buf[..8]), None);
+    }
+}
@@ -227,6 +227,16 @@ impl SetupPacket {
         Self::get_interface_descriptor(HID_REPORT_DESCRIPTOR_TYPE, interface as u16, len)
     }
 
+    /// Build a GET_DESCRIPTOR(String) SETUP packet for the language `lang_id`.
+    ///
+    /// Index 0 returns the list of supported language IDs.
+    pub const fn get_string_descriptor(index: u8, lang_id: u16, max_len: u16) -> Self {
+        Self {
+            index: lang_id,
+            ..Self::get_descriptor(false, descriptor_type::STRING, index, max_len)
+        }
+    }
+
     /// Build a SET_ADDRESS SETUP packet.
     pub const fn set_address(address: u8) -> Self {
         Self {
@@ -257,6 +267,21 @@ impl SetupPacket {
         }
     }
 
+    /// Build a SET_INTERFACE SETUP packet selecting `alternate_setting` of `interface`.
+    pub const fn set_interface(interface: u16, alternate_setting: u8) -> Self {
+        Self {
+            request_type: RequestType {
+                direction: Direction::Out,
+                control_type: ControlType::Standard,
+                recipient: Recipient::Interface,
+            },
+            request: Request::SET_INTERFACE,
+            value: alternate_setting as u16,
+            index: interface,
+            length: 0,
+        }
+    }
+
     /// Build a GET_CONFIGURATION SETUP packet.
     pub const fn get_configuration() -> Self {
         Self {
@@ -304,6 +329,36 @@ impl SetupPacket {
         }
     }
 
+    /// Build a vendor-specific device request SETUP packet, host-to-device.
+    pub const fn vendor_device_out(request: u8, value: u16, index: u16, length: u16) -> Self {
+        Self {
+            request_type: RequestType {
+                direction: Direction::Out,
+                control_type: ControlType::Vendor,
+                recipient: Recipient::Device,
+            },
+            request,
+            value,
+            index,
+            length,
+        }
+    }
+
+    /// Build a vendor-specific device request SETUP packet, device-to-host.
+    pub const fn vendor_device_in(request: u8, value: u16, index: u16, length: u16) -> Self {
+        Self {
+            request_type: RequestType {
+                direction: Direction::In,
+                control_type: ControlType::Vendor,
+                recipient: Recipient::Device,
+            },
+            request,
+            value,
+            index,
+            length,
+        }
+    }
+
     /// Build a vendor-specific interface request SETUP packet, host-to-device.
     pub const fn vendor_interface_out(request: u8, value: u16, interface: u16, length: u16) -> Self {
         Self {
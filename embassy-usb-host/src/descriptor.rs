@@ -864,8 +864,9 @@ impl<'a> Iterator for RawDescriptorIterator<'a> {
 
     fn next(&mut self) -> Option<Self::Item> {
         let offset = self.offset;
+        // A descriptor is at least 2 bytes: bLength and bDescriptorType.
         if let Some(&len) = self.buf.get(offset)
-            && len > 0
+            && len >= 2
         {
             self.offset += len as usize;
             if let Some(bytes) = self.buf.get(offset..self.offset) {
@@ -892,6 +893,10 @@ impl Iterator for EndpointIterator<'_> {
         }
         while self.buffer_idx + 7 <= self.iface_desc.buffer.len() {
             let working = &self.iface_desc.buffer[self.buffer_idx..];
+            if working[0] < 2 {
+                // Malformed length, it would not advance to the next descriptor.
+                return None;
+            }
             self.buffer_idx += working[0] as usize;
             if let Ok(d) = EndpointDescriptor::try_from_bytes(working) {
                 self.index += 1;
@@ -23,6 +23,7 @@ pub mod class;
 pub mod control;
 pub mod descriptor;
 pub mod handler;
+pub mod manager;
 
 use core::cell::RefCell;
 use core::marker::PhantomData;
@@ -0,0 +1,621 @@
+//! Device manager driving hotplug, hub trees and class-driver dispatch.
+//!
+//! [`DeviceManager`] owns the [`BusController`] and every hub on the bus.
+//! It waits for root-port and hub-port changes, enumerates new devices
+//! (including devices behind nested hubs), registers hubs itself and
+//! matches every other device against a table of [`DriverEntry`]s. The
+//! application receives a [`ManagerEvent`] per attach/detach and hands the
+//! device off to the matching class driver, typically by spawning a task
+//! that builds the driver from the [`BusHandle`] and the configuration
+//! descriptor.
+//!
+//! Device addresses are released through [`BusState::free_address`]
+//! when the corresponding [`ManagerEvent::Detached`] is returned.
+//!
+//! [`BusState::free_address`]: crate::BusState::free_address
+
+use core::pin::{Pin, pin};
+
+use embassy_futures::select::{Either, select, select_slice};
+use embassy_usb_driver::Speed;
+use embassy_usb_driver::host::{DeviceEvent, HostError, UsbHostController};
+use heapless::{Deque, Vec};
+
+use crate::class::hub::{HubEvent, HubHandler};
+use crate::descriptor::{ConfigurationDescriptorChain, DeviceDescriptor};
+use crate::handler::{BusRoute, EnumerationInfo, HandlerEvent};
+use crate::{BusController, BusHandle, EnumerationError};
+
+/// Hub class code (USB 2.0 §11.23.1).
+const HUB_CLASS: u8 = 0x09;
+
+/// Maximum number of hubs between the root port and a device (USB 2.0 §4.1.1).
+const MAX_HUB_DEPTH: u8 = 5;
+
+/// Criteria a device must meet to be handed to a class driver.
+///
+/// Unset fields match anything. The class triple is compared against the
+/// device descriptor and, failing that, against every interface of the
+/// configuration, so a filter for an interface class also matches composite
+/// devices exposing that interface.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceFilter {
+    /// Required vendor ID.
+    pub vendor_id: Option<u16>,
+    /// Required product ID.
+    pub product_id: Option<u16>,
+    /// Required device or interface class.
+    pub class: Option<u8>,
+    /// Required device or interface subclass.
+    pub subclass: Option<u8>,
+    /// Required device or interface protocol.
+    pub protocol: Option<u8>,
+}
+
+impl DeviceFilter {
+    /// Filter matching every device.
+    pub const ANY: Self = Self {
+        vendor_id: None,
+        product_id: None,
+        class: None,
+        subclass: None,
+        protocol: None,
+    };
+
+    /// Match a specific vendor and product ID.
+    pub const fn vid_pid(vendor_id: u16, product_id: u16) -> Self {
+        Self {
+            vendor_id: Some(vendor_id),
+            product_id: Some(product_id),
+            ..Self::ANY
+        }
+    }
+
+    /// Match a device or interface class.
+    pub const fn class(class: u8) -> Self {
+        Self {
+            class: Some(class),
+            ..Self::ANY
+        }
+    }
+
+    /// Additionally require a subclass.
+    pub const fn subclass(mut self, subclass: u8) -> Self {
+        self.subclass = Some(subclass);
+        self
+    }
+
+    /// Additionally require a protocol.
+    pub const fn protocol(mut self, protocol: u8) -> Self {
+        self.protocol = Some(protocol);
+        self
+    }
+
+    /// Check the filter against a device and its configuration descriptor.
+    pub fn matches(&self, device_desc: &DeviceDescriptor, config_desc: &[u8]) -> bool {
+        if self.vendor_id.is_some_and(|v| v != device_desc.vendor_id)
+            || self.product_id.is_some_and(|p| p != device_desc.product_id)
+        {
+            return false;
+        }
+        if self.class.is_none() && self.subclass.is_none() && self.protocol.is_none() {
+            return true;
+        }
+
+        // Class 0 defers the class to the interfaces (USB 2.0 §9.6.1).
+        if device_desc.device_class != 0
+            && self.matches_class(
+                device_desc.device_class,
+                device_desc.device_subclass,
+                device_desc.device_protocol,
+            )
+        {
+            return true;
+        }
+
+        let Ok(cfg) = ConfigurationDescriptorChain::try_from_slice(config_desc) else {
+            return false;
+        };
+        cfg.iter_interface().any(|iface| {
+            self.matches_class(
+                iface.interface_class,
+                iface.interface_subclass,
+                iface.interface_protocol,
+            )
+        })
+    }
+
+    fn matches_class(&self, class: u8, subclass: u8, protocol: u8) -> bool {
+        self.class.is_none_or(|c| c == class)
+            && self.subclass.is_none_or(|s| s == subclass)
+            && self.protocol.is_none_or(|p| p == protocol)
+    }
+}
+
+/// Entry of the class-driver table given to [`DeviceManager::new`].
+///
+/// `driver` is an application-defined tag (usually a fieldless enum)
+/// identifying the class driver to hand the device to.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DriverEntry<K> {
+    /// Devices this driver accepts.
+    pub filter: DeviceFilter,
+    /// Driver tag reported in [`ManagerEvent::Attached`].
+    pub driver: K,
+}
+
+impl<K> DriverEntry<K> {
+    /// Create a new driver table entry.
+    pub const fn new(filter: DeviceFilter, driver: K) -> Self {
+        Self { filter, driver }
+    }
+}
+
+/// A device known to the [`DeviceManager`] and its place in the hub tree.
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AttachedDevice {
+    /// Enumeration result for the device.
+    pub info: EnumerationInfo,
+    /// Address of the hub the device is attached to, or `None` for the root port.
+    pub parent: Option<u8>,
+    /// 0-based port on the parent hub. Always 0 on the root port.
+    pub port: u8,
+    /// Number of hubs between the root port and the device.
+    pub depth: u8,
+}
+
+impl AttachedDevice {
+    /// Assigned device address.
+    pub const fn address(&self) -> u8 {
+        self.info.device_address
+    }
+}
+
+/// Event reported by [`DeviceManager::wait_for_event`].
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ManagerEvent<K> {
+    /// A device was enumerated and configured.
+    ///
+    /// `driver` is the first matching entry of the driver table, or `None`
+    /// if no entry matched. The configuration descriptor occupies the first
+    /// `config_len` bytes of the buffer passed to
+    /// [`wait_for_event`](DeviceManager::wait_for_event).
+    Attached {
+        /// The new device.
+        device: AttachedDevice,
+        /// Matching class driver.
+        driver: Option<K>,
+        /// Length of the configuration descriptor.
+        config_len: usize,
+    },
+    /// A hub was enumerated and is now managed by the [`DeviceManager`].
+    HubAttached(AttachedDevice),
+    /// A device was removed. Its address has been released.
+    ///
+    /// Removing a hub reports every device below it first.
+    Detached(AttachedDevice),
+    /// A device was detected on a port but could not be enumerated.
+    EnumerationFailed {
+        /// Address of the hub, or `None` for the root port.
+        parent: Option<u8>,
+        /// 0-based port on the parent hub.
+        port: u8,
+        /// Enumeration error.
+        error: EnumerationError,
+    },
+    /// A device was detected on a port but the device table is full.
+    DeviceLimitReached {
+        /// Address of the hub, or `None` for the root port.
+        parent: Option<u8>,
+        /// 0-based port on the parent hub.
+        port: u8,
+    },
+    /// The root port's overcurrent protection tripped.
+    Overcurrent,
+}
+
+/// Devices on the bus, plus devices removed but not yet reported.
+struct DeviceTable<const N: usize> {
+    devices: Vec<AttachedDevice, N>,
+    removed: Deque<AttachedDevice, N>,
+}
+
+impl<const N: usize> DeviceTable<N> {
+    const fn new() -> Self {
+        Self {
+            devices: Vec::new(),
+            removed: Deque::new(),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.devices.len() + self.removed.len() >= N
+    }
+
+    fn get(&self, address: u8) -> Option<&AttachedDevice> {
+        self.devices.iter().find(|d| d.address() == address)
+    }
+
+    fn at_port(&self, parent: Option<u8>, port: u8) -> Option<u8> {
+        self.devices
+            .iter()
+            .find(|d| d.parent == parent && d.port == port)
+            .map(|d| d.address())
+    }
+
+    fn insert(&mut self, device: AttachedDevice) -> Result<(), AttachedDevice> {
+        if self.is_full() {
+            return Err(device);
+        }
+        self.devices.push(device)
+    }
+
+    /// Move a device and everything below it to the removed queue,
+    /// children first.
+    fn remove(&mut self, address: u8) {
+        // Recursion is bounded by the hub depth limit.
+        while let Some(child) = self
+            .devices
+            .iter()
+            .find(|d| d.parent == Some(address))
+            .map(|d| d.address())
+        {
+            self.remove(child);
+        }
+        if let Some(idx) = self.devices.iter().position(|d| d.address() == address) {
+            let device = self.devices.remove(idx);
+            // Cannot fail: `devices` and `removed` share the capacity.
+            let _ = self.removed.push_back(device);
+        }
+    }
+
+    fn take_removed(&mut self) -> Option<AttachedDevice> {
+        self.removed.pop_front()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &AttachedDevice> {
+        self.devices.iter()
+    }
+}
+
+struct HubNode<'d, C: UsbHostController<'d>, const MAX_PORTS: usize> {
+    address: u8,
+    handler: HubHandler<'d, C::Allocator, MAX_PORTS>,
+    /// Set once the hub stops answering; it is no longer polled until removed.
+    failed: bool,
+}
+
+enum Change {
+    Root(DeviceEvent),
+    Hub(u8, Result<HandlerEvent<HubEvent>, HostError>),
+}
+
+/// Owns the bus and hub tree and dispatches devices to class drivers.
+///
+/// - `MAX_DEVICES` bounds the number of devices (hubs included) on the bus.
+/// - `MAX_HUBS` bounds the number of hubs managed at once. Further hubs are
+///   reported as regular devices.
+/// - `MAX_PORTS` is forwarded to [`HubHandler`].
+pub struct DeviceManager<
+    'd,
+    C: UsbHostController<'d>,
+    K: Copy,
+    const MAX_DEVICES: usize,
+    const MAX_HUBS: usize,
+    const MAX_PORTS: usize,
+> {
+    ctrl: BusController<'d, C>,
+    bus: BusHandle<'d, C::Allocator>,
+    drivers: &'d [DriverEntry<K>],
+    table: DeviceTable<MAX_DEVICES>,
+    hubs: Vec<HubNode<'d, C, MAX_PORTS>, MAX_HUBS>,
+    pending: Option<(Option<u8>, u8, Speed)>,
+}
+
+impl<'d, C: UsbHostController<'d>, K: Copy, const MAX_DEVICES: usize, const MAX_HUBS: usize, const MAX_PORTS: usize>
+    DeviceManager<'d, C, K, MAX_DEVICES, MAX_HUBS, MAX_PORTS>
+{
+    /// Create a device manager from the pair returned by [`bus`](crate::bus).
+    ///
+    /// Devices are matched against `drivers` in order; list VID/PID entries
+    /// before class entries so that they take precedence.
+    pub fn new(ctrl: BusController<'d, C>, bus: BusHandle<'d, C::Allocator>, drivers: &'d [DriverEntry<K>]) -> Self {
+        Self {
+            ctrl,
+            bus,
+            drivers,
+            table: DeviceTable::new(),
+            hubs: Vec::new(),
+            pending: None,
+        }
+    }
+
+    /// Bus handle to construct class drivers with.
+    pub fn bus(&self) -> &BusHandle<'d, C::Allocator> {
+        &self.bus
+    }
+
+    /// Look up an attached device by address.
+    pub fn device(&self, address: u8) -> Option<&AttachedDevice> {
+        self.table.get(address)
+    }
+
+    /// Iterate over all attached devices, hubs included.
+    pub fn devices(&self) -> impl Iterator<Item = &AttachedDevice> {
+        self.table.iter()
+    }
+
+    /// Wait for the next attach/detach on the bus.
+    ///
+    /// `config_buf` receives the configuration descriptor of newly attached
+    /// devices. The manager must be polled continuously for hub ports to be
+    /// serviced.
+    pub async fn wait_for_event(&mut self, config_buf: &mut [u8]) -> ManagerEvent<K> {
+        loop {
+            if let Some(device) = self.table.take_removed() {
+                let address = device.address();
+                self.hubs.retain(|h| h.address != address);
+                self.bus.free_address(address);
+                info!("Device {} detached", address);
+                return ManagerEvent::Detached(device);
+            }
+
+            if let Some((parent, port, speed)) = self.pending.take() {
+                if let Some(event) = self.attach(parent, port, speed, config_buf).await {
+                    return event;
+                }
+                continue;
+            }
+
+            match self.wait_for_change().await {
+                Change::Root(DeviceEvent::Connected(speed)) => self.port_connected(None, 0, speed),
+                Change::Root(DeviceEvent::Disconnected) => self.port_disconnected(None, 0),
+                Change::Root(DeviceEvent::Overcurrent) => return ManagerEvent::Overcurrent,
+                Change::Root(_) => {}
+                Change::Hub(hub, Ok(HandlerEvent::HandlerEvent(HubEvent::DeviceDetected { port, speed }))) => {
+                    self.port_connected(Some(hub), port, speed)
+                }
+                Change::Hub(hub, Ok(HandlerEvent::HandlerEvent(HubEvent::DeviceRemoved { port, .. }))) => {
+                    self.port_disconnected(Some(hub), port)
+                }
+                Change::Hub(_, Ok(HandlerEvent::NoChange)) => {}
+                Change::Hub(hub, result) => {
+                    if let Err(e) = result {
+                        warn!("HUB {}: {:?}, no longer polled", hub, e);
+                    }
+                    if let Some(node) = self.hubs.iter_mut().find(|h| h.address == hub) {
+                        node.failed = true;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn wait_for_change(&mut self) -> Change {
+        let Self { ctrl, hubs, .. } = self;
+        let futs: Vec<_, MAX_HUBS> = hubs
+            .iter_mut()
+            .filter(|h| !h.failed)
+            .map(|h| async { (h.address, h.handler.wait_for_event().await) })
+            .collect();
+        let mut futs = pin!(futs);
+        // SAFETY: the vector is pinned and never moved or resized while the
+        // slice is in use.
+        let futs = unsafe { Pin::map_unchecked_mut(futs.as_mut(), |v| v.as_mut_slice()) };
+
+        // Hub waits are cancel-safe: a port change stays latched in the hub
+        // until it is cleared, so it is reported again on the next poll.
+        match select(ctrl.wait_for_device_event(), select_slice(futs)).await {
+            Either::First(event) => Change::Root(event),
+            Either::Second(((hub, result), _)) => Change::Hub(hub, result),
+        }
+    }
+
+    fn port_connected(&mut self, parent: Option<u8>, port: u8, speed: Speed) {
+        // A connect without a prior disconnect replaces the old device.
+        self.port_disconnected(parent, port);
+        self.pending = Some((parent, port, speed));
+    }
+
+    fn port_disconnected(&mut self, parent: Option<u8>, port: u8) {
+        if let Some(address) = self.table.at_port(parent, port) {
+            self.table.remove(address);
+        }
+        // Drop an attach queued behind a hub that just went away.
+        if let Some((Some(hub), _, _)) = self.pending
+            && self.table.get(hub).is_none()
+        {
+            self.pending = None;
+        }
+    }
+
+    async fn attach(
+        &mut self,
+        parent: Option<u8>,
+        port: u8,
+        speed: Speed,
+        config_buf: &mut [u8],
+    ) -> Option<ManagerEvent<K>> {
+        if self.table.is_full() {
+            warn!("Device table full, ignoring device on port {}", port);
+            return Some(ManagerEvent::DeviceLimitReached { parent, port });
+        }
+
+        let (depth, result) = match parent {
+            None => (0, self.bus.enumerate(BusRoute::Direct(speed), config_buf).await),
+            Some(hub) => {
+                let depth = self.table.get(hub)?.depth + 1;
+                let node = self.hubs.iter_mut().find(|h| h.address == hub)?;
+                (depth, node.handler.enumerate_port(config_buf, port, speed).await)
+            }
+        };
+        let (info, config_len) = match result {
+            Ok(v) => v,
+            Err(error) => {
+                warn!("Enumeration on port {} failed: {:?}", port, error);
+                return Some(ManagerEvent::EnumerationFailed { parent, port, error });
+            }
+        };
+
+        let device = AttachedDevice {
+            info,
+            parent,
+            port,
+            depth,
+        };
+        // Cannot fail: checked above and nothing was inserted since.
+        let _ = self.table.insert(device);
+
+        if info.device_desc.device_class == HUB_CLASS && depth < MAX_HUB_DEPTH && !self.hubs.is_full() {
+            match HubHandler::try_register(&self.bus, &info).await {
+                Ok(handler) => {
+                    let address = info.device_address;
+                    let _ = self.hubs.push(HubNode {
+                        address,
+                        handler,
+                        failed: false,
+                    });
+                    info!("Hub {} attached", address);
+                    return Some(ManagerEvent::HubAttached(device));
+                }
+                Err(e) => warn!("Hub {} registration failed: {:?}", info.device_address, e),
+            }
+        }
+
+        let config = &config_buf[..config_len];
+        let driver = self
+            .drivers
+            .iter()
+            .find(|d| d.filter.matches(&info.device_desc, config))
+            .map(|d| d.driver);
+        Some(ManagerEvent::Attached {
+            device,
+            driver,
+            config_len,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Single HID boot keyboard interface.
+    #[rustfmt::skip]
+    const CFG_KEYBOARD: [u8; 25] = [
+        9, 0x02, 25, 0, 1, 1, 0, 0x80, 50,
+        9, 0x04, 0, 0, 1, 0x03, 0x01, 0x01, 0,
+        7, 0x05, 0x81, 0x03, 0x08, 0x00, 10,
+    ];
+
+    fn desc(device_class: u8, vendor_id: u16, product_id: u16) -> DeviceDescriptor {
+        DeviceDescriptor {
+            bcd_usb: 0x0200,
+            device_class,
+            device_subclass: 0,
+            device_protocol: 0,
+            max_packet_size0: 64,
+            vendor_id,
+            product_id,
+            bcd_device: 0x0100,
+            manufacturer: 0,
+            product: 0,
+            serial_number: 0,
+            num_configurations: 1,
+        }
+    }
+
+    fn device(address: u8, parent: Option<u8>, port: u8) -> AttachedDevice {
+        AttachedDevice {
+            info: EnumerationInfo {
+                device_address: address,
+                route: BusRoute::Direct(Speed::Full),
+                device_desc: desc(0, 0, 0),
+            },
+            parent,
+            port,
+            depth: 0,
+        }
+    }
+
+    #[test]
+    fn filter_vid_pid() {
+        let f = DeviceFilter::vid_pid(0x1234, 0x5678);
+        assert!(f.matches(&desc(0, 0x1234, 0x5678), &[]));
+        assert!(!f.matches(&desc(0, 0x1234, 0x0001), &[]));
+        assert!(!f.matches(&desc(0, 0x4321, 0x5678), &[]));
+        assert!(DeviceFilter::ANY.matches(&desc(0, 0x4321, 0x5678), &[]));
+    }
+
+    #[test]
+    fn filter_device_class() {
+        let f = DeviceFilter::class(0x02);
+        assert!(f.matches(&desc(0x02, 0, 0), &[]));
+        assert!(!f.matches(&desc(0x09, 0, 0), &[]));
+    }
+
+    #[test]
+    fn filter_interface_class() {
+        let kbd = DeviceFilter::class(0x03).subclass(0x01).protocol(0x01);
+        let mouse = DeviceFilter::class(0x03).subclass(0x01).protocol(0x02);
+        assert!(kbd.matches(&desc(0, 0, 0), &CFG_KEYBOARD));
+        assert!(!mouse.matches(&desc(0, 0, 0), &CFG_KEYBOARD));
+        // Malformed configuration never matches a class filter.
+        assert!(!kbd.matches(&desc(0, 0, 0), &CFG_KEYBOARD[..12]));
+    }
+
+    #[test]
+    fn filter_class_and_vid_pid() {
+        let f = DeviceFilter {
+            class: Some(0x03),
+            ..DeviceFilter::vid_pid(0x1234, 0x5678)
+        };
+        assert!(f.matches(&desc(0, 0x1234, 0x5678), &CFG_KEYBOARD));
+        assert!(!f.matches(&desc(0, 0x1234, 0x5679), &CFG_KEYBOARD));
+    }
+
+    #[test]
+    fn table_remove_nested_hubs() {
+        let mut t = DeviceTable::<8>::new();
+        // root hub 1 -> { dev 2 on port 0, hub 3 on port 1 -> { dev 4, dev 5 } }, dev 6 elsewhere
+        t.insert(device(1, None, 0)).unwrap();
+        t.insert(device(2, Some(1), 0)).unwrap();
+        t.insert(device(3, Some(1), 1)).unwrap();
+        t.insert(device(4, Some(3), 0)).unwrap();
+        t.insert(device(5, Some(3), 2)).unwrap();
+
+        assert_eq!(t.at_port(Some(3), 2), Some(5));
+        assert_eq!(t.at_port(Some(3), 1), None);
+
+        t.remove(3);
+        let mut removed = [0u8; 3];
+        for r in removed.iter_mut() {
+            *r = t.take_removed().unwrap().address();
+        }
+        assert_eq!(removed, [4, 5, 3]);
+        assert!(t.take_removed().is_none());
+        assert!(t.get(1).is_some() && t.get(2).is_some());
+
+        t.remove(1);
+        assert_eq!(t.take_removed().unwrap().address(), 2);
+        assert_eq!(t.take_removed().unwrap().address(), 1);
+        assert_eq!(t.iter().count(), 0);
+    }
+
+    #[test]
+    fn table_capacity_includes_unreported_removals() {
+        let mut t = DeviceTable::<2>::new();
+        t.insert(device(1, None, 0)).unwrap();
+        t.insert(device(2, Some(1), 0)).unwrap();
+        assert!(t.insert(device(3, Some(1), 1)).is_err());
+
+        t.remove(2);
+        assert!(t.is_full());
+        t.take_removed();
+        assert!(t.insert(device(3, Some(1), 1)).is_ok());
+    }
+}
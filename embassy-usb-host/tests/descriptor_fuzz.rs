@@ -0,0 +1,396 @@
+//! Robustness and conformance tests for the descriptor parsers.
+//!
+//! Every parser that sees bytes from a device is run against arbitrary input and
+//! against mutations of the descriptor dumps in `tests/corpus`. Parsers must reject
+//! malformed input with an error, never panic or loop forever. The same corpus
+//! seeds the `cargo fuzz` targets in `fuzz/`.
+
+use embassy_usb_host::class::hid::{HidInfo, find_hid, find_hid_interfaces};
+use embassy_usb_host::class::hid_input::{self, InputKind};
+use embassy_usb_host::class::hid_report::ReportDescriptor;
+use embassy_usb_host::class::hub::HubDescriptor;
+use embassy_usb_host::class::kbd::HIDDescriptor;
+use embassy_usb_host::class::uac::descriptors::*;
+use embassy_usb_host::class::uac::{Layout1ParameterBlock, Layout2ParameterBlock, Layout3ParameterBlock};
+use embassy_usb_host::class::{cdc_acm, cdc_net, dfu, gip, midi, msc, printer};
+use embassy_usb_host::descriptor::*;
+use proptest::prelude::*;
+
+const CORPUS: &[(&str, &[u8])] = &[
+    ("config_cdc_acm_iad", include_bytes!("corpus/config_cdc_acm_iad.bin")),
+    ("config_cdc_ecm", include_bytes!("corpus/config_cdc_ecm.bin")),
+    (
+        "config_hid_keyboard_mouse",
+        include_bytes!("corpus/config_hid_keyboard_mouse.bin"),
+    ),
+    ("config_hub_mtt", include_bytes!("corpus/config_hub_mtt.bin")),
+    ("config_msc_stick", include_bytes!("corpus/config_msc_stick.bin")),
+    ("config_uac2_headset", include_bytes!("corpus/config_uac2_headset.bin")),
+    ("device_hub", include_bytes!("corpus/device_hub.bin")),
+    ("device_vendor", include_bytes!("corpus/device_vendor.bin")),
+    ("hid_boot_keyboard", include_bytes!("corpus/hid_boot_keyboard.bin")),
+    ("hid_gamepad", include_bytes!("corpus/hid_gamepad.bin")),
+    (
+        "hid_keyboard_consumer_ids",
+        include_bytes!("corpus/hid_keyboard_consumer_ids.bin"),
+    ),
+    ("hid_mouse_wheel_pan", include_bytes!("corpus/hid_mouse_wheel_pan.bin")),
+    ("hid_touchscreen", include_bytes!("corpus/hid_touchscreen.bin")),
+    ("hub_4port", include_bytes!("corpus/hub_4port.bin")),
+    ("string_langids", include_bytes!("corpus/string_langids.bin")),
+    ("string_product", include_bytes!("corpus/string_product.bin")),
+];
+
+fn corpus(prefix: &str) -> impl Iterator<Item = (&'static str, &'static [u8])> + '_ {
+    CORPUS.iter().copied().filter(move |(name, _)| name.starts_with(prefix))
+}
+
+// ── Parser drivers ───────────────────────────────────────────────────────────
+
+#[derive(Default)]
+struct CountingVisitor {
+    interfaces: usize,
+    endpoints: usize,
+    others: usize,
+}
+
+impl<'a> DescriptorVisitor<'a> for CountingVisitor {
+    type Error = ();
+
+    fn on_interface(&mut self, _i: &InterfaceDescriptorChain<'a>) -> bool {
+        self.interfaces += 1;
+        true
+    }
+
+    fn on_endpoint(&mut self, _iface: &InterfaceDescriptorChain<'a>, _e: &EndpointDescriptor) -> bool {
+        self.endpoints += 1;
+        true
+    }
+
+    fn on_other(&mut self, _iface: Option<&InterfaceDescriptorChain<'a>>, raw: &[u8]) -> Result<bool, ()> {
+        // Raw descriptors always carry at least their length and type.
+        assert!(raw.len() >= 2 && raw[0] as usize == raw.len());
+        self.others += 1;
+        Ok(true)
+    }
+}
+
+/// Walk a configuration descriptor with every consumer of configuration descriptors.
+fn parse_configuration(bytes: &[u8]) {
+    if let Ok(cfg) = ConfigurationDescriptorChain::try_from_slice(bytes) {
+        for (offset, raw) in cfg.iter_descriptors() {
+            assert!(raw.len() >= 2 && offset + raw.len() <= cfg.buffer.len());
+        }
+        for iface in cfg.iter_interface() {
+            for _ in iface.iter_descriptors() {}
+            assert!(iface.iter_endpoints().count() <= iface.num_endpoints as usize);
+        }
+        let _ = cfg.visit_descriptors(&mut CountingVisitor::default());
+        let _ = AudioInterfaceCollection::try_from_configuration(&cfg);
+    }
+
+    let _ = find_hid(bytes);
+    let _ = find_hid_interfaces::<8>(bytes);
+    let _ = cdc_acm::find_cdc_acm(bytes);
+    let _ = cdc_net::find_cdc_net(bytes);
+    let _ = dfu::find_dfu(bytes);
+    let _ = gip::find_gip(bytes);
+    let _ = midi::find_midi(bytes);
+    let _ = msc::find_msc(bytes);
+    let _ = printer::find_printer(bytes);
+}
+
+/// Parse a single descriptor as every standard and class descriptor type.
+fn parse_descriptor(bytes: &[u8]) {
+    let _ = DeviceDescriptorPartial::try_from_bytes(bytes);
+    let _ = DeviceDescriptor::try_from_bytes(bytes);
+    let _ = ConfigurationDescriptor::try_from_bytes(bytes);
+    let _ = InterfaceDescriptor::try_from_bytes(bytes);
+    let _ = EndpointDescriptor::try_from_bytes(bytes);
+    let _ = StringDescriptorZero::try_from_bytes(bytes);
+    let _ = StringDescriptor::try_from_bytes(bytes);
+    let _ = StringDescriptorLossy::try_from_bytes(bytes);
+    let _ = HubDescriptor::try_from_bytes(bytes);
+    let _ = HIDDescriptor::try_from_bytes(bytes);
+
+    let _ = InterfaceAssociationDescriptor::try_from_bytes(bytes);
+    let _ = AudioControlHeaderDescriptor::try_from_bytes(bytes);
+    let _ = ClockDescriptor::try_from_bytes(bytes);
+    let _ = ClockSourceDescriptor::try_from_bytes(bytes);
+    let _ = ClockSelectorDescriptor::try_from_bytes(bytes);
+    let _ = ClockMultiplierDescriptor::try_from_bytes(bytes);
+    let _ = TerminalDescriptor::try_from_bytes(bytes);
+    let _ = InputTerminalDescriptor::try_from_bytes(bytes);
+    let _ = OutputTerminalDescriptor::try_from_bytes(bytes);
+    let _ = UnitDescriptor::try_from_bytes(bytes);
+    let _ = AudioStreamingClassDescriptor::try_from_bytes(bytes);
+    let _ = AudioEndpointDescriptor::try_from_bytes(bytes);
+    let _ = FormatTypeDescriptor::try_from_bytes(bytes);
+    let _ = Layout1ParameterBlock::try_from_bytes(bytes);
+    let _ = Layout2ParameterBlock::try_from_bytes(bytes);
+    let _ = Layout3ParameterBlock::try_from_bytes(bytes);
+}
+
+/// Parse a HID report descriptor and decode `report` with it.
+fn parse_hid_report(descriptor: &[u8], report: &[u8]) {
+    let desc: ReportDescriptor<32> = ReportDescriptor::parse(descriptor);
+    let _ = ReportDescriptor::<32>::try_parse(descriptor);
+    for field in desc.fields() {
+        for i in 0..(field.count as usize).min(64) {
+            let _ = field.extract_i32(report, i);
+        }
+    }
+    let _ = hid_input::decode(&desc, report);
+}
+
+// ── Conformance ──────────────────────────────────────────────────────────────
+
+#[test]
+fn corpus_configurations_are_consistent() {
+    for (name, bytes) in corpus("config_") {
+        let cfg = ConfigurationDescriptorChain::try_from_slice(bytes).unwrap_or_else(|_| panic!("{name}"));
+        assert_eq!(cfg.total_len as usize, bytes.len(), "{name}");
+
+        // Every byte of the configuration belongs to exactly one descriptor.
+        let covered: usize = cfg.iter_descriptors().map(|(_, raw)| raw.len()).sum();
+        assert_eq!(covered, cfg.buffer.len(), "{name}");
+
+        let mut numbers = heapless::Vec::<u8, 16>::new();
+        for iface in cfg.iter_interface() {
+            assert_eq!(
+                iface.iter_endpoints().count(),
+                iface.num_endpoints as usize,
+                "{name} interface {}",
+                iface.interface_number
+            );
+            if !numbers.contains(&iface.interface_number) {
+                numbers.push(iface.interface_number).unwrap();
+            }
+        }
+        assert_eq!(numbers.len(), cfg.num_interfaces as usize, "{name}");
+
+        let mut visitor = CountingVisitor::default();
+        cfg.visit_descriptors(&mut visitor).unwrap();
+        assert_eq!(visitor.interfaces, cfg.iter_interface().count(), "{name}");
+    }
+}
+
+#[test]
+fn corpus_classes_are_found() {
+    let get = |name: &str| corpus(name).next().unwrap().1;
+
+    assert!(cdc_acm::find_cdc_acm(get("config_cdc_acm_iad")).is_some());
+    assert!(cdc_net::find_cdc_net(get("config_cdc_ecm")).is_some());
+    assert!(msc::find_msc(get("config_msc_stick")).is_some());
+    assert!(midi::find_midi(get("config_uac2_headset")).is_some());
+    assert!(dfu::find_dfu(get("config_uac2_headset")).is_some());
+
+    let hids: heapless::Vec<HidInfo, 4> = find_hid_interfaces(get("config_hid_keyboard_mouse"));
+    assert_eq!(hids.len(), 2);
+    assert_eq!((hids[0].interrupt_in_ep, hids[0].report_descriptor_len), (0x81, 63));
+    assert_eq!((hids[1].interrupt_in_ep, hids[1].report_descriptor_len), (0x83, 39));
+
+    let cfg = ConfigurationDescriptorChain::try_from_slice(get("config_uac2_headset")).unwrap();
+    let audio = AudioInterfaceCollection::try_from_configuration(&cfg).unwrap();
+    assert_eq!(audio.audio_streaming_interfaces.len(), 2);
+}
+
+#[test]
+fn corpus_descriptors_parse() {
+    for (_, bytes) in corpus("device_") {
+        let desc = DeviceDescriptor::try_from_bytes(bytes).unwrap();
+        assert_eq!(desc.max_packet_size0, 64);
+    }
+    assert_eq!(HubDescriptor::try_from_bytes(get_one("hub_4port")).unwrap().port_num, 4);
+    assert_eq!(
+        StringDescriptorZero::try_from_bytes(get_one("string_langids"))
+            .unwrap()
+            .lang_ids,
+        [0x0409]
+    );
+    assert_eq!(
+        &*StringDescriptor::try_from_bytes(get_one("string_product")).unwrap(),
+        "Embassy"
+    );
+}
+
+fn get_one(name: &str) -> &'static [u8] {
+    corpus(name).next().unwrap().1
+}
+
+#[test]
+fn corpus_report_descriptors_classify() {
+    let expected = [
+        ("hid_boot_keyboard", 0, InputKind::Keyboard),
+        ("hid_gamepad", 0, InputKind::Gamepad),
+        ("hid_keyboard_consumer_ids", 1, InputKind::Keyboard),
+        ("hid_mouse_wheel_pan", 0, InputKind::Mouse),
+        ("hid_touchscreen", 4, InputKind::Touch),
+    ];
+    for (name, report_id, kind) in expected {
+        let desc = ReportDescriptor::<32>::try_parse(get_one(name)).unwrap_or_else(|e| panic!("{name}: {e:?}"));
+        assert_eq!(hid_input::report_kind(&desc, report_id), Some(kind), "{name}");
+    }
+}
+
+// ── Malformed input ──────────────────────────────────────────────────────────
+
+#[test]
+fn malformed_descriptors_are_rejected() {
+    // Zero-length descriptor inside a configuration.
+    let zero_len = [9, 2, 18, 0, 1, 1, 0, 0x80, 50, 9, 4, 0, 0, 1, 0xFF, 0, 0, 0];
+    let mut zero_len = zero_len.to_vec();
+    zero_len[9 + 4] = 2;
+    zero_len.extend_from_slice(&[0, 5, 0x81, 2, 64, 0, 0]);
+    zero_len[2] = zero_len.len() as u8;
+    parse_configuration(&zero_len);
+
+    // One-byte descriptor after the configuration header.
+    parse_configuration(&[9, 2, 11, 0, 1, 1, 0, 0x80, 50, 1, 4]);
+
+    // Truncated configuration and descriptors declaring more bytes than available.
+    assert!(ConfigurationDescriptorChain::try_from_slice(&[9, 2, 200, 0, 1, 1, 0, 0x80, 50]).is_err());
+    assert_eq!(
+        EndpointDescriptor::try_from_bytes(&[7, 5, 0x81, 3]),
+        Err(DescriptorError::UnexpectedEndOfBuffer)
+    );
+
+    // Range responses announcing more ranges than they contain.
+    assert!(Layout1ParameterBlock::try_from_bytes(&[2, 0, 1, 2, 3]).is_none());
+    assert!(Layout2ParameterBlock::try_from_bytes(&[2, 0, 1, 0, 2, 0, 3, 0]).is_none());
+    assert!(Layout1ParameterBlock::try_from_bytes(&[1, 0, 1, 2, 3]).is_some_and(|b| b.ranges.len() == 1));
+
+    // Unbalanced and truncated HID report descriptors.
+    assert_eq!(
+        ReportDescriptor::<8>::try_parse(&[0xC0]).err(),
+        Some(DescriptorError::BadDescriptorData)
+    );
+    assert_eq!(
+        ReportDescriptor::<8>::try_parse(&[0x05, 0x01, 0x09, 0x02, 0xA1, 0x01]).err(),
+        Some(DescriptorError::BadDescriptorData)
+    );
+    assert_eq!(
+        ReportDescriptor::<8>::try_parse(&[0x05, 0x01, 0x27, 0xFF]).err(),
+        Some(DescriptorError::UnexpectedEndOfBuffer)
+    );
+    assert_eq!(
+        ReportDescriptor::<8>::try_parse(&[0xFE, 0x10, 0x00, 0x01]).err(),
+        Some(DescriptorError::UnexpectedEndOfBuffer)
+    );
+
+    // Extreme logical ranges and report sizes.
+    let extreme = [
+        0x05, 0x01, 0x09, 0x05, 0xA1, 0x01, // Gamepad application
+        0x17, 0x00, 0x00, 0x00, 0x80, // Logical Minimum (i32::MIN)
+        0x27, 0xFF, 0xFF, 0xFF, 0x7F, // Logical Maximum (i32::MAX)
+        0x75, 0x20, 0x95, 0x02, 0x09, 0x39, 0x09, 0x30, 0x81, 0x02, // Hat + X, 32 bits each
+        0x09, 0x39, 0x95, 0x01, 0x81, 0x00, // Hat array
+        0xC0,
+    ];
+    parse_hid_report(&extreme, &[0xFF; 12]);
+    parse_hid_report(
+        &extreme,
+        &[0x7F, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x80, 0xFF, 0xFF, 0xFF, 0x7F],
+    );
+    parse_hid_report(
+        &extreme,
+        &[0x00, 0x00, 0x00, 0x80, 0xFF, 0xFF, 0xFF, 0x7F, 0x00, 0x00, 0x00, 0x80],
+    );
+
+    // Huge fields, with offsets beyond any report.
+    let huge = [
+        0x05, 0x01, 0x09, 0x06, 0xA1, 0x01, // Keyboard application
+        0x05, 0x07, 0x75, 0xFF, 0x96, 0xFF, 0xFF, 0x81, 0x00, // 255-bit array, 65535 elements
+        0x75, 0x08, 0x95, 0x01, 0x81, 0x02, // One more field after it
+        0xC0,
+    ];
+    let mut huge_repeated = Vec::new();
+    for _ in 0..600 {
+        huge_repeated.extend_from_slice(&huge);
+    }
+    parse_hid_report(&huge, &[0xFF; 64]);
+    parse_hid_report(&huge_repeated, &[0xFF; 64]);
+}
+
+// ── Property tests ───────────────────────────────────────────────────────────
+
+/// Mutations applied to corpus entries.
+#[derive(Clone, Debug)]
+enum Mutation {
+    Set(usize, u8),
+    Truncate(usize),
+    Insert(usize, u8),
+    Remove(usize),
+}
+
+fn mutate(bytes: &[u8], mutations: &[Mutation]) -> Vec<u8> {
+    let mut out = bytes.to_vec();
+    for m in mutations {
+        let len = out.len().max(1);
+        match *m {
+            Mutation::Set(i, v) => {
+                if let Some(b) = out.get_mut(i % len) {
+                    *b = v;
+                }
+            }
+            Mutation::Truncate(i) => out.truncate(i % len),
+            Mutation::Insert(i, v) => out.insert(i % (out.len() + 1), v),
+            Mutation::Remove(i) => {
+                if !out.is_empty() {
+                    out.remove(i % len);
+                }
+            }
+        }
+    }
+    out
+}
+
+fn mutations() -> impl Strategy<Value = Vec<Mutation>> {
+    let interesting = prop_oneof![
+        Just(0u8),
+        Just(1),
+        Just(2),
+        Just(0x7F),
+        Just(0x80),
+        Just(0xFF),
+        any::<u8>()
+    ];
+    let mutation = prop_oneof![
+        4 => (any::<usize>(), interesting.clone()).prop_map(|(i, v)| Mutation::Set(i, v)),
+        1 => any::<usize>().prop_map(Mutation::Truncate),
+        2 => (any::<usize>(), interesting).prop_map(|(i, v)| Mutation::Insert(i, v)),
+        2 => any::<usize>().prop_map(Mutation::Remove),
+    ];
+    prop::collection::vec(mutation, 1..8)
+}
+
+fn corpus_entry() -> impl Strategy<Value = &'static [u8]> {
+    prop::sample::select(CORPUS.iter().map(|(_, bytes)| *bytes).collect::<Vec<_>>())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(2048))]
+
+    #[test]
+    fn arbitrary_bytes(bytes in prop::collection::vec(any::<u8>(), 0..600)) {
+        parse_configuration(&bytes);
+        parse_descriptor(&bytes);
+        parse_hid_report(&bytes, &bytes);
+    }
+
+    #[test]
+    fn mutated_configuration(entry in corpus_entry(), mutations in mutations()) {
+        let bytes = mutate(entry, &mutations);
+        parse_configuration(&bytes);
+        parse_descriptor(&bytes);
+    }
+
+    #[test]
+    fn mutated_report_descriptor(
+        entry in corpus_entry(),
+        mutations in mutations(),
+        report in prop::collection::vec(any::<u8>(), 0..64),
+    ) {
+        parse_hid_report(&mutate(entry, &mutations), &report);
+    }
+}
@@ -0,0 +1,568 @@
+//! End-to-end DFU tests against `embassy-usb` DFU devices over an in-memory bus.
+//!
+//! The device side runs a real `UsbDevice` on a driver whose control pipe is
+//! wired through channels to a host-side `UsbPipe`, so every request goes
+//! through the same code paths as on hardware.
+
+use std::cell::{Cell, RefCell};
+use std::marker::PhantomData;
+
+use embassy_boot::{BlockingFirmwareUpdater, FirmwareUpdaterConfig};
+use embassy_futures::select::{Either, select};
+use embassy_futures::{block_on, yield_now};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::Duration;
+use embassy_usb::class::dfu::app_mode;
+use embassy_usb::driver::{
+    Bus, ControlPipe, Direction, Driver, Endpoint, EndpointAddress, EndpointAllocError, EndpointError, EndpointIn,
+    EndpointInfo, EndpointOut, EndpointType, Event, Speed, Unsupported,
+};
+use embassy_usb::{Builder, UsbDevice};
+use embassy_usb_driver::host::{HostError, PipeError, SplitInfo, TimeoutConfig, UsbHostAllocator, UsbPipe, pipe};
+use embassy_usb_host::BusRoute;
+use embassy_usb_host::class::dfu::{DfuAttributes, DfuError, DfuHost, DfuMode, ResetAction, State, Status};
+use embassy_usb_host::control::SetupPacket;
+use embassy_usb_host::descriptor::{DeviceDescriptor, USBDescriptor};
+use embassy_usb_host::handler::EnumerationInfo;
+use embedded_storage::nor_flash::{ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash};
+
+type M = CriticalSectionRawMutex;
+
+const MPS: usize = 64;
+const BLOCK_SIZE: usize = 256;
+const WRITE_SIZE: usize = 8;
+
+/// Device response to a control transfer stage.
+enum Response {
+    Data(Vec<u8>, bool),
+    Ack,
+    Stall,
+}
+
+/// Both ends of the in-memory control pipe.
+struct Wire {
+    setup: Channel<M, [u8; 8], 1>,
+    data_out: Channel<M, Vec<u8>, 64>,
+    response: Channel<M, Response, 64>,
+    bus_reset: Signal<M, ()>,
+}
+
+impl Wire {
+    fn new() -> &'static Self {
+        Box::leak(Box::new(Self {
+            setup: Channel::new(),
+            data_out: Channel::new(),
+            response: Channel::new(),
+            bus_reset: Signal::new(),
+        }))
+    }
+}
+
+struct MemDriver(&'static Wire);
+
+struct MemBus {
+    wire: &'static Wire,
+    powered: bool,
+    reset: bool,
+}
+
+struct MemControl(&'static Wire);
+
+/// DFU functions have no endpoints besides EP0.
+struct NoEndpoint(EndpointInfo);
+
+impl<'d> Driver<'d> for MemDriver {
+    type EndpointOut = NoEndpoint;
+    type EndpointIn = NoEndpoint;
+    type ControlPipe = MemControl;
+    type Bus = MemBus;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        _ep_type: EndpointType,
+        _ep_addr: Option<EndpointAddress>,
+        _max_packet_size: u16,
+        _interval_ms: u8,
+    ) -> Result<NoEndpoint, EndpointAllocError> {
+        Err(EndpointAllocError)
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        _ep_type: EndpointType,
+        _ep_addr: Option<EndpointAddress>,
+        _max_packet_size: u16,
+        _interval_ms: u8,
+    ) -> Result<NoEndpoint, EndpointAllocError> {
+        Err(EndpointAllocError)
+    }
+
+    fn start(self, _control_max_packet_size: u16) -> (MemBus, MemControl) {
+        let bus = MemBus {
+            wire: self.0,
+            powered: false,
+            reset: false,
+        };
+        (bus, MemControl(self.0))
+    }
+}
+
+impl Bus for MemBus {
+    async fn enable(&mut self) {}
+
+    async fn disable(&mut self) {}
+
+    async fn poll(&mut self) -> Event {
+        if !self.powered {
+            self.powered = true;
+            return Event::PowerDetected;
+        }
+        if !self.reset {
+            self.reset = true;
+            return Event::Reset;
+        }
+        self.wire.bus_reset.wait().await;
+        Event::Reset
+    }
+
+    fn endpoint_set_enabled(&mut self, _ep_addr: EndpointAddress, _enabled: bool) {}
+
+    fn endpoint_set_stalled(&mut self, _ep_addr: EndpointAddress, _stalled: bool) {}
+
+    fn endpoint_is_stalled(&mut self, _ep_addr: EndpointAddress) -> bool {
+        false
+    }
+
+    async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+        Err(Unsupported)
+    }
+}
+
+impl ControlPipe for MemControl {
+    fn max_packet_size(&self) -> usize {
+        MPS
+    }
+
+    async fn setup(&mut self) -> [u8; 8] {
+        self.0.setup.receive().await
+    }
+
+    async fn data_out(&mut self, buf: &mut [u8], _first: bool, _last: bool) -> Result<usize, EndpointError> {
+        let data = self.0.data_out.receive().await;
+        if data.len() > buf.len() {
+            return Err(EndpointError::BufferOverflow);
+        }
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    async fn data_in(&mut self, data: &[u8], _first: bool, last: bool) -> Result<(), EndpointError> {
+        self.0.response.send(Response::Data(data.to_vec(), last)).await;
+        Ok(())
+    }
+
+    async fn accept(&mut self) {
+        self.0.response.send(Response::Ack).await;
+    }
+
+    async fn reject(&mut self) {
+        self.0.response.send(Response::Stall).await;
+    }
+
+    async fn accept_set_address(&mut self, _addr: u8) {
+        self.0.response.send(Response::Ack).await;
+    }
+}
+
+impl Endpoint for NoEndpoint {
+    fn info(&self) -> &EndpointInfo {
+        &self.0
+    }
+
+    async fn wait_enabled(&mut self) {}
+}
+
+impl EndpointOut for NoEndpoint {
+    async fn read(&mut self, _buf: &mut [u8]) -> Result<usize, EndpointError> {
+        Err(EndpointError::Disabled)
+    }
+}
+
+impl EndpointIn for NoEndpoint {
+    async fn write(&mut self, _buf: &[u8]) -> Result<(), EndpointError> {
+        Err(EndpointError::Disabled)
+    }
+}
+
+#[derive(Clone)]
+struct MemHost(&'static Wire);
+
+struct MemPipe<T, D> {
+    wire: &'static Wire,
+    _phantom: PhantomData<(T, D)>,
+}
+
+impl<'d> UsbHostAllocator<'d> for MemHost {
+    type Pipe<T: pipe::Type, D: pipe::Direction> = MemPipe<T, D>;
+
+    fn alloc_pipe<T: pipe::Type, D: pipe::Direction>(
+        &self,
+        _addr: u8,
+        _endpoint: &EndpointInfo,
+        _split: Option<SplitInfo>,
+    ) -> Result<MemPipe<T, D>, HostError> {
+        Ok(MemPipe {
+            wire: self.0,
+            _phantom: PhantomData,
+        })
+    }
+}
+
+impl<T: pipe::Type, D: pipe::Direction> UsbPipe<T, D> for MemPipe<T, D> {
+    async fn control_in(&mut self, setup: &[u8; 8], buf: &mut [u8]) -> Result<usize, PipeError>
+    where
+        T: pipe::IsControl,
+        D: pipe::IsIn,
+    {
+        self.wire.setup.send(*setup).await;
+        let mut n = 0;
+        loop {
+            match self.wire.response.receive().await {
+                Response::Data(data, last) => {
+                    let len = data.len().min(buf.len() - n);
+                    buf[n..n + len].copy_from_slice(&data[..len]);
+                    n += len;
+                    if last {
+                        return Ok(n);
+                    }
+                }
+                Response::Ack => return Ok(n),
+                Response::Stall => return Err(PipeError::Stall),
+            }
+        }
+    }
+
+    async fn control_out(&mut self, setup: &[u8; 8], buf: &[u8]) -> Result<(), PipeError>
+    where
+        T: pipe::IsControl,
+        D: pipe::IsOut,
+    {
+        self.wire.setup.send(*setup).await;
+        for chunk in buf.chunks(MPS) {
+            self.wire.data_out.send(chunk.to_vec()).await;
+        }
+        loop {
+            match self.wire.response.receive().await {
+                Response::Data(..) => {}
+                Response::Ack => return Ok(()),
+                Response::Stall => {
+                    // Drop data the device did not read before rejecting.
+                    while self.wire.data_out.try_receive().is_ok() {}
+                    return Err(PipeError::Stall);
+                }
+            }
+        }
+    }
+
+    async fn request_in(&mut self, _buf: &mut [u8]) -> Result<usize, PipeError>
+    where
+        D: pipe::IsIn,
+    {
+        Err(PipeError::Stall)
+    }
+
+    async fn request_out(&mut self, _buf: &[u8], _ensure_transaction_end: bool) -> Result<(), PipeError>
+    where
+        D: pipe::IsOut,
+    {
+        Err(PipeError::Stall)
+    }
+
+    fn set_timeout(&mut self, _timeout: TimeoutConfig)
+    where
+        T: pipe::IsControl,
+    {
+    }
+
+    fn reset_data_toggle(&mut self)
+    where
+        T: pipe::IsBulkOrInterrupt,
+    {
+    }
+}
+
+struct InMemoryFlash<'a, const SIZE: usize> {
+    buffer: &'a RefCell<[u8; SIZE]>,
+}
+
+impl<const SIZE: usize> InMemoryFlash<'_, SIZE> {
+    fn check(offset: u32, len: usize) -> Result<core::ops::Range<usize>, NorFlashErrorKind> {
+        let range = offset as usize..offset as usize + len;
+        if range.end > SIZE {
+            return Err(NorFlashErrorKind::OutOfBounds);
+        }
+        Ok(range)
+    }
+}
+
+impl<const SIZE: usize> ErrorType for InMemoryFlash<'_, SIZE> {
+    type Error = NorFlashErrorKind;
+}
+
+impl<const SIZE: usize> ReadNorFlash for InMemoryFlash<'_, SIZE> {
+    const READ_SIZE: usize = WRITE_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        bytes.copy_from_slice(&self.buffer.borrow()[Self::check(offset, bytes.len())?]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        SIZE
+    }
+}
+
+impl<const SIZE: usize> NorFlash for InMemoryFlash<'_, SIZE> {
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const ERASE_SIZE: usize = WRITE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.buffer.borrow_mut()[Self::check(from, (to - from) as usize)?].fill(0xFF);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.buffer.borrow_mut()[Self::check(offset, bytes.len())?].copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+struct FlagReset<'a>(&'a Cell<bool>);
+
+impl embassy_usb_dfu::Reset for FlagReset<'_> {
+    fn sys_reset(&self) {
+        self.0.set(true);
+    }
+}
+
+impl app_mode::Handler for FlagReset<'_> {
+    fn enter_dfu(&mut self) {
+        self.0.set(true);
+    }
+}
+
+fn firmware(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + 3) as u8).collect()
+}
+
+/// Enumerate `usb` and run `host` against it while the device runs.
+fn with_device<'d, R>(
+    wire: &'static Wire,
+    usb: &mut UsbDevice<'d, MemDriver>,
+    host: impl AsyncFnOnce(MemHost, EnumerationInfo, Vec<u8>) -> R,
+) -> R {
+    let host_fut = async {
+        let alloc = MemHost(wire);
+        let ep0 = EndpointInfo {
+            addr: EndpointAddress::from_parts(0, Direction::In),
+            ep_type: EndpointType::Control,
+            max_packet_size: MPS as u16,
+            interval_ms: 0,
+        };
+        let mut ctrl = alloc.alloc_pipe::<pipe::Control, pipe::InOut>(0, &ep0, None).unwrap();
+        ctrl.control_out(&SetupPacket::set_address(1).to_bytes(), &[])
+            .await
+            .unwrap();
+        let mut buf = [0u8; 256];
+        let n = ctrl
+            .control_in(&SetupPacket::get_device_descriptor(18).to_bytes(), &mut buf)
+            .await
+            .unwrap();
+        let device_desc = DeviceDescriptor::try_from_bytes(&buf[..n]).unwrap();
+        let n = ctrl
+            .control_in(&SetupPacket::get_config_descriptor(0, 256).to_bytes(), &mut buf)
+            .await
+            .unwrap();
+        ctrl.control_out(&SetupPacket::set_configuration(1).to_bytes(), &[])
+            .await
+            .unwrap();
+        let enum_info = EnumerationInfo {
+            device_address: 1,
+            route: BusRoute::Direct(Speed::Full),
+            device_desc,
+        };
+        host(alloc, enum_info, buf[..n].to_vec()).await
+    };
+    match block_on(select(usb.run(), host_fut)) {
+        Either::First(never) => never,
+        Either::Second(r) => r,
+    }
+}
+
+/// Download `image` into an `embassy-usb-dfu` bootloader with `attrs`.
+///
+/// Returns the download result, the DFU partition, and whether the device reset.
+fn download(
+    attrs: DfuAttributes,
+    image: &[u8],
+    firmware_error: bool,
+) -> (Result<ResetAction, DfuError>, Vec<usize>, Vec<u8>, bool) {
+    let wire = Wire::new();
+    let reset = Cell::new(false);
+    let dfu_buffer = RefCell::new([0u8; 2048]);
+    let state_buffer = RefCell::new([0u8; WRITE_SIZE * 2]);
+    let mut aligned = [0u8; WRITE_SIZE];
+    let updater = BlockingFirmwareUpdater::new(
+        FirmwareUpdaterConfig {
+            dfu: InMemoryFlash { buffer: &dfu_buffer },
+            state: InMemoryFlash { buffer: &state_buffer },
+        },
+        &mut aligned,
+    );
+    let mut state = embassy_usb_dfu::new_state::<_, _, _, BLOCK_SIZE>(updater, attrs, FlagReset(&reset));
+    if firmware_error {
+        state.set_to_firmware_error();
+    }
+
+    let mut config_desc = [0u8; 256];
+    let mut bos_desc = [0u8; 256];
+    let mut msos_desc = [0u8; 256];
+    let mut control_buf = [0u8; BLOCK_SIZE];
+    let mut builder = Builder::new(
+        MemDriver(wire),
+        embassy_usb::Config::new(0xc0de, 0xcafe),
+        &mut config_desc,
+        &mut bos_desc,
+        &mut msos_desc,
+        &mut control_buf,
+    );
+    embassy_usb_dfu::usb_dfu(&mut builder, &mut state, |_| {});
+    let mut usb = builder.build();
+
+    let (result, progress) = with_device(wire, &mut usb, async |alloc, enum_info, cfg| {
+        let mut dfu = DfuHost::new(&alloc, &cfg, &enum_info).unwrap();
+        assert_eq!(dfu.info().mode, DfuMode::Dfu);
+        assert_eq!(dfu.info().transfer_size as usize, BLOCK_SIZE);
+        dfu.enable().await.unwrap();
+
+        let mut progress = Vec::new();
+        let result = dfu.download(image, |n| progress.push(n)).await;
+        match result {
+            Ok(ResetAction::None) => assert_eq!(dfu.get_state().await.unwrap(), State::DfuIdle),
+            Ok(ResetAction::BusReset) => {
+                wire.bus_reset.signal(());
+                while !reset.get() {
+                    yield_now().await;
+                }
+            }
+            Ok(ResetAction::Detached) => {}
+            // Errors are cleared, leaving the device ready for another attempt.
+            Err(_) => assert_eq!(dfu.get_state().await.unwrap(), State::DfuIdle),
+        }
+        (result, progress)
+    });
+    drop(usb);
+    let flash = dfu_buffer.borrow()[..image.len().min(2048)].to_vec();
+    (result, progress, flash, reset.get())
+}
+
+#[test]
+fn download_manifestation_tolerant() {
+    let image = firmware(1000);
+    let attrs = DfuAttributes::CAN_DOWNLOAD | DfuAttributes::MANIFESTATION_TOLERANT;
+    let (result, progress, flash, reset) = download(attrs, &image, false);
+    assert_eq!(result.unwrap(), ResetAction::None);
+    assert_eq!(progress, [256, 512, 768, 1000]);
+    assert_eq!(flash, image);
+    assert!(!reset);
+}
+
+#[test]
+fn download_will_detach() {
+    let image = firmware(2 * BLOCK_SIZE);
+    let attrs = DfuAttributes::CAN_DOWNLOAD | DfuAttributes::WILL_DETACH;
+    let (result, progress, flash, reset) = download(attrs, &image, false);
+    assert_eq!(result.unwrap(), ResetAction::Detached);
+    assert_eq!(progress, [256, 512]);
+    assert_eq!(flash, image);
+    assert!(reset);
+}
+
+#[test]
+fn download_bus_reset() {
+    let image = firmware(300);
+    let (result, _, flash, reset) = download(DfuAttributes::CAN_DOWNLOAD, &image, false);
+    assert_eq!(result.unwrap(), ResetAction::BusReset);
+    assert_eq!(flash, image);
+    assert!(reset);
+}
+
+#[test]
+fn download_recovers_from_error_state() {
+    let image = firmware(500);
+    let attrs = DfuAttributes::CAN_DOWNLOAD | DfuAttributes::MANIFESTATION_TOLERANT;
+    let (result, _, flash, _) = download(attrs, &image, true);
+    assert_eq!(result.unwrap(), ResetAction::None);
+    assert_eq!(flash, image);
+}
+
+#[test]
+fn download_reports_device_error() {
+    // Larger than the DFU partition.
+    let image = firmware(4096);
+    let attrs = DfuAttributes::CAN_DOWNLOAD | DfuAttributes::MANIFESTATION_TOLERANT;
+    let (result, progress, _, _) = download(attrs, &image[..], false);
+    assert!(matches!(
+        result,
+        Err(DfuError::Device {
+            status: Status::ErrAddress,
+            state: State::Error
+        })
+    ));
+    assert_eq!(progress, (1..=8).map(|i| i * BLOCK_SIZE).collect::<Vec<_>>());
+}
+
+#[test]
+fn detach_runtime_device() {
+    let wire = Wire::new();
+    let entered = Cell::new(false);
+    let mut state = app_mode::DfuState::new(
+        FlagReset(&entered),
+        DfuAttributes::CAN_DOWNLOAD,
+        Duration::from_millis(1000),
+    );
+
+    let mut config_desc = [0u8; 256];
+    let mut bos_desc = [0u8; 256];
+    let mut msos_desc = [0u8; 256];
+    let mut control_buf = [0u8; 64];
+    let mut builder = Builder::new(
+        MemDriver(wire),
+        embassy_usb::Config::new(0xc0de, 0xcafe),
+        &mut config_desc,
+        &mut bos_desc,
+        &mut msos_desc,
+        &mut control_buf,
+    );
+    app_mode::usb_dfu(&mut builder, &mut state, |_| {});
+    let mut usb = builder.build();
+
+    with_device(wire, &mut usb, async |alloc, enum_info, cfg| {
+        let mut dfu = DfuHost::new(&alloc, &cfg, &enum_info).unwrap();
+        assert_eq!(dfu.info().mode, DfuMode::Runtime);
+        assert_eq!(dfu.info().detach_timeout_ms, 1000);
+        assert!(matches!(
+            dfu.download(&[0; 16], |_| {}).await,
+            Err(DfuError::NotSupported)
+        ));
+
+        assert_eq!(dfu.detach().await.unwrap(), ResetAction::BusReset);
+        assert_eq!(dfu.get_status().await.unwrap().state, State::AppDetach);
+        wire.bus_reset.signal(());
+        while !entered.get() {
+            yield_now().await;
+        }
+    });
+}
@@ -13,9 +13,12 @@ use embassy_usb_driver::host::{
 use embassy_usb_driver::{EndpointInfo, EndpointType, Speed};
 use portable_atomic::{AtomicBool, AtomicU8, AtomicU16, Ordering};
 
+use self::schedule::{MAX_SPLIT_PAYLOAD, PeriodicSchedule, Reservation, Transaction};
 use crate::PhyType;
 use crate::otg_v1::{Otg, vals};
 
+mod schedule;
+
 // Per-channel event flags, OR'd into an AtomicU16 mailbox by the ISR.
 const EV_XFRC: u16 = 1 << 0;
 const EV_STALL: u16 = 1 << 1;
@@ -27,10 +30,29 @@ const EV_FRMOR: u16 = 1 << 6;
 const EV_DTERR: u16 = 1 << 7;
 const EV_CHH: u16 = 1 << 8;
 const EV_DISCONNECT: u16 = 1 << 9;
+const EV_ACK: u16 = 1 << 10;
 
 /// HCINT.NYET bit (not exposed by the PAC struct).
 const HCINT_NYET_MASK: u32 = 1 << 6;
 
+// HCSPLT fields (the PAC exposes the register as a raw u32).
+const HCSPLT_SPLITEN: u32 = 1 << 31;
+const HCSPLT_COMPLSPLT: u32 = 1 << 16;
+const HCSPLT_XACTPOS_SHIFT: u32 = 14;
+const HCSPLT_HUBADDR_SHIFT: u32 = 7;
+
+// HCSPLT.XACTPOS: which part of a full-speed payload a start-split carries.
+const XACTPOS_MID: u32 = 0b00;
+const XACTPOS_END: u32 = 0b01;
+const XACTPOS_BEGIN: u32 = 0b10;
+const XACTPOS_ALL: u32 = 0b11;
+
+/// HFNUM.FRNUM wraps at this value; in high-speed mode it counts microframes.
+const FRNUM_MODULUS: u16 = 0x4000;
+
+/// Consecutive transaction errors tolerated on a split transaction (USB 2.0 §11.17.1).
+const SPLIT_ERROR_RETRIES: u8 = 3;
+
 enum ChannelEvent {
     None,
     Complete,
@@ -68,8 +90,14 @@ struct HostStateFields {
     port_waker: AtomicWaker,
     port_event: AtomicU8,
     port_speed: AtomicU8,
+    /// Periodic split-transaction schedule, only accessed under the host mutex.
+    schedule: UnsafeCell<PeriodicSchedule>,
 }
 
+// SAFETY: `schedule` is only accessed with the host mutex held.
+unsafe impl Send for HostStateFields {}
+unsafe impl Sync for HostStateFields {}
+
 /// Storage object for USB host driver state. Create one per OTG instance.
 pub struct HostStateStorage<const CH_COUNT: usize, M = CriticalSectionRawMutex>
 where
@@ -101,6 +129,7 @@ where
                 port_waker: AtomicWaker::new(),
                 port_event: AtomicU8::new(0),
                 port_speed: AtomicU8::new(0),
+                schedule: UnsafeCell::new(PeriodicSchedule::new()),
             },
             mutex,
         }
@@ -383,6 +412,9 @@ where
                 if hcint.chh() {
                     events |= EV_CHH;
                 }
+                if hcint.ack() {
+                    events |= EV_ACK;
+                }
 
                 trace!(
                     "otg-host: hcint ch={} raw={:#010x} -> events={:#06x}",
@@ -627,19 +659,17 @@ where
         // Read root-port speed from port_speed atomic (stored by ISR)
         let speed_code = self.state.fields.port_speed.load(Ordering::Acquire);
 
+        // Behind a hub the root port reports the *hub's* speed, so the
+        // target device's speed has to come from the split metadata.
         let is_low_speed = match split {
-            // Behind a hub the root port reports the *hub's* speed, so the
-            // target device's speed has to come from the split metadata.
-            Some(_) if speed_code == 2 => {
-                // A high-speed root port reaches LS/FS devices only through
-                // real split transactions (HCSPLT plus start/complete-split
-                // scheduling), which this driver does not implement. Fail
-                // loudly instead of emitting tokens the device can't see.
-                return Err(HostError::Other("high-speed split transactions not supported"));
-            }
             Some(split) => split.device_speed() == SplitSpeed::Low,
             None => speed_code == 1,
         };
+        // A high-speed root port reaches LS/FS devices only through the hub's
+        // transaction translator, using start/complete-split transactions.
+        // Behind a full-speed root port the hub just repeats the tokens.
+        let split = split.filter(|_| speed_code == 2);
+        let periodic = matches!(T::ep_type(), EndpointType::Interrupt | EndpointType::Isochronous);
 
         let max_ch = self.state.channels.len();
         // Find a free channel using atomic CAS
@@ -651,6 +681,31 @@ where
             {
                 self.state.channels[i].result.store(0, Ordering::Release);
 
+                // Periodic split pipes need a slot in the TT's bus time budget.
+                let reservation = match split {
+                    Some(split) if periodic => {
+                        let tr = Transaction {
+                            ep_type: T::ep_type(),
+                            dir_in: endpoint.addr.is_in(),
+                            low_speed: is_low_speed,
+                            max_packet_size,
+                            interval: endpoint.interval_ms,
+                        };
+                        let reservation = self.state.mutex.lock(|| {
+                            // SAFETY: the schedule is only accessed with the mutex held.
+                            let schedule = unsafe { &mut *self.state.fields.schedule.get() };
+                            schedule.reserve(i, split.hub_addr(), &tr)
+                        });
+                        let Some(reservation) = reservation else {
+                            self.state.channels[i].allocated.store(false, Ordering::Release);
+                            return Err(HostError::InsufficientBandwidth);
+                        };
+                        trace!("otg-host: ch={} split schedule {:?}", i, reservation);
+                        Some(reservation)
+                    }
+                    _ => None,
+                };
+
                 return Ok(Channel {
                     regs: self.regs,
                     state: self.state,
@@ -660,6 +715,8 @@ where
                     max_packet_size,
                     is_low_speed,
                     data_toggle: false,
+                    split,
+                    reservation,
                     phantom: PhantomData,
                 });
             }
@@ -891,6 +948,10 @@ where
     max_packet_size: u16,
     is_low_speed: bool,
     data_toggle: bool,
+    /// Hub transaction translator, if transfers go through split transactions.
+    split: Option<SplitInfo>,
+    /// Periodic schedule slot of a split interrupt or isochronous pipe.
+    reservation: Option<Reservation>,
     phantom: PhantomData<(T, D)>,
 }
 
@@ -931,6 +992,10 @@ where
             r.haintmsk().modify(|w| {
                 w.set_haintm(w.haintm() & !(1 << ch));
             });
+            if self.reservation.is_some() {
+                // SAFETY: the schedule is only accessed with the mutex held.
+                unsafe { &mut *self.state.fields.schedule.get() }.release(ch);
+            }
         });
 
         // Clear any pending channel interrupts.
@@ -1006,6 +1071,9 @@ where
             }
         });
 
+        // Route through the hub's TT as a start-split carrying the whole payload.
+        self.set_split(XACTPOS_ALL, false);
+
         // Configure transfer size
         r.hctsiz(ch).write(|w| {
             w.set_xfrsiz(xfrsiz);
@@ -1026,6 +1094,8 @@ where
             w.set_bberrm(true);
             w.set_frmorm(true);
             w.set_dterrm(true);
+            // A start-split is answered with a bare ACK from the hub.
+            w.set_ackm(self.split.is_some());
         });
 
         // Enable this channel in HAINTMSK (critical section guards the RMW against concurrent alloc_pipe)
@@ -1150,6 +1220,261 @@ where
         }
     }
 
+    /// Program HCSPLT. Clears it unless the pipe uses split transactions.
+    fn set_split(&self, xactpos: u32, complete: bool) {
+        let value = match self.split {
+            Some(split) => {
+                let mut value = HCSPLT_SPLITEN
+                    | (xactpos << HCSPLT_XACTPOS_SHIFT)
+                    | ((split.hub_addr() as u32) << HCSPLT_HUBADDR_SHIFT)
+                    | split.port() as u32;
+                if complete {
+                    value |= HCSPLT_COMPLSPLT;
+                }
+                value
+            }
+            None => 0,
+        };
+        self.regs.hcsplt(self.index).write_value(value);
+    }
+
+    /// Current microframe number (the root port runs at high speed).
+    fn uframe_number(&self) -> u16 {
+        self.regs.hfnum().read().frnum() % FRNUM_MODULUS
+    }
+
+    /// Wait for the microframe before `target`, so that a channel enabled next
+    /// runs in `target`. Returns `false` if `target` has already started.
+    async fn wait_for_uframe(&self, target: u16) -> bool {
+        loop {
+            let ahead = target.wrapping_sub(self.uframe_number()) % FRNUM_MODULUS;
+            if ahead == 1 {
+                return true;
+            }
+            if ahead == 0 || ahead > FRNUM_MODULUS / 2 {
+                return false;
+            }
+            yield_now().await;
+        }
+    }
+
+    /// Wait until the next microframe is the scheduled start-split slot, and
+    /// return it. Non-periodic split transactions are not scheduled.
+    async fn wait_for_start_split(&self) -> Option<u16> {
+        let r = self.reservation?;
+        loop {
+            let next = (self.uframe_number() + 1) % FRNUM_MODULUS;
+            // The start-split goes out the microframe before the transaction starts.
+            let start = (next + 1) % FRNUM_MODULUS;
+            let frame = (start >> 3) as usize;
+            if frame % r.period as usize == r.phase as usize && start & 7 == r.start_uframe as u16 {
+                return Some(next);
+            }
+            yield_now().await;
+        }
+    }
+
+    /// Issue a start-split. Returns `false` if the hub NAKed it, e.g. because
+    /// its transaction buffers are full.
+    async fn start_split(
+        &mut self,
+        ep_type: EndpointType,
+        dir_in: bool,
+        data: &[u8],
+        len: usize,
+        dpid: u8,
+    ) -> Result<bool, PipeError> {
+        self.configure_channel(dir_in, ep_type, 1, len as u32, dpid);
+        self.enable_channel();
+        if !dir_in {
+            self.write_fifo(data);
+        }
+
+        let events = self.wait_for_result().await;
+        // In slave mode the channel is not halted on ACK.
+        self.halt_channel();
+        match Self::classify_events(events) {
+            ChannelEvent::Error(e) => Err(e),
+            _ => Ok(events & EV_ACK != 0),
+        }
+    }
+
+    /// Collect the result of a split transaction with complete-splits.
+    ///
+    /// Returns `false` if the transaction has to be restarted with a new
+    /// start-split: the device NAKed, or a periodic transaction did not finish
+    /// within its scheduled complete-split microframes.
+    async fn complete_split(
+        &mut self,
+        ep_type: EndpointType,
+        dir_in: bool,
+        len: usize,
+        dpid: u8,
+        start: Option<u16>,
+    ) -> Result<bool, PipeError> {
+        // Periodic complete-splits start two microframes after the start-split,
+        // one after the transaction starts on the full-speed bus.
+        let window = start.zip(self.reservation).map(|(start, r)| {
+            let last = r.last_csplit_uframe.map_or(0, |u| u - r.start_uframe) + 1;
+            (start, last as u16)
+        });
+        let mut offset = 2;
+        loop {
+            if let Some((start, last)) = window {
+                if offset > last {
+                    return Ok(false);
+                }
+                let target = (start + offset) % FRNUM_MODULUS;
+                offset += 1;
+                if !self.wait_for_uframe(target).await {
+                    continue;
+                }
+            }
+
+            self.configure_channel(dir_in, ep_type, 1, len as u32, dpid);
+            self.set_split(XACTPOS_ALL, true);
+            self.enable_channel();
+
+            let events = self.wait_for_result().await;
+            match Self::classify_events(events) {
+                ChannelEvent::Complete => return Ok(true),
+                ChannelEvent::Nyet => {
+                    // The TT has not finished the transaction yet.
+                    self.halt_channel();
+                    if window.is_none() {
+                        yield_now().await;
+                    }
+                }
+                ChannelEvent::Error(e) => {
+                    self.halt_channel();
+                    return Err(e);
+                }
+                ChannelEvent::Nak | ChannelEvent::Halted | ChannelEvent::None => {
+                    self.halt_channel();
+                    return Ok(false);
+                }
+            }
+        }
+    }
+
+    /// Run one IN packet as a split transaction through the hub's TT (USB 2.0 §11.14).
+    async fn split_in(&mut self, ep_type: EndpointType, buf: &mut [u8], dpid: u8) -> Result<usize, PipeError> {
+        let len = buf.len().min(self.max_packet_size as usize);
+        let mut errors = 0;
+        loop {
+            let start = self.wait_for_start_split().await;
+            let result = match self.start_split(ep_type, true, &[], len, dpid).await {
+                Ok(true) => {
+                    self.setup_rx_buffer(&mut buf[..len]);
+                    let result = self.complete_split(ep_type, true, len, dpid, start).await;
+                    let count = self.rx_count();
+                    self.clear_rx_buffer();
+                    result.map(|done| done.then_some(count))
+                }
+                Ok(false) => Ok(None),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(Some(count)) => return Ok(count),
+                Ok(None) => yield_now().await,
+                Err(PipeError::BadResponse) if errors < SPLIT_ERROR_RETRIES => errors += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Run one OUT packet as a split transaction through the hub's TT (USB 2.0 §11.14).
+    async fn split_out(&mut self, ep_type: EndpointType, data: &[u8], dpid: u8) -> Result<(), PipeError> {
+        let mut errors = 0;
+        loop {
+            let start = self.wait_for_start_split().await;
+            if ep_type == EndpointType::Isochronous {
+                return self.split_iso_out(data, dpid, start).await;
+            }
+            let result = match self.start_split(ep_type, false, data, data.len(), dpid).await {
+                Ok(true) => self.complete_split(ep_type, false, data.len(), dpid, start).await,
+                other => other,
+            };
+            match result {
+                Ok(true) => return Ok(()),
+                Ok(false) => yield_now().await,
+                Err(PipeError::BadResponse) if errors < SPLIT_ERROR_RETRIES => errors += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Send an isochronous OUT packet through the hub's TT. There is no
+    /// handshake, and payloads above 188 bytes take one start-split per
+    /// microframe (USB 2.0 §11.18.4).
+    async fn split_iso_out(&mut self, data: &[u8], dpid: u8, start: Option<u16>) -> Result<(), PipeError> {
+        let count = data.len().div_ceil(MAX_SPLIT_PAYLOAD).max(1);
+        for i in 0..count {
+            let chunk = &data[i * MAX_SPLIT_PAYLOAD..data.len().min((i + 1) * MAX_SPLIT_PAYLOAD)];
+            let xactpos = match i {
+                _ if count == 1 => XACTPOS_ALL,
+                0 => XACTPOS_BEGIN,
+                _ if i == count - 1 => XACTPOS_END,
+                _ => XACTPOS_MID,
+            };
+            if let Some(start) = start.filter(|_| i > 0) {
+                // The TT drops a payload whose parts don't arrive in consecutive microframes.
+                if !self.wait_for_uframe((start + i as u16) % FRNUM_MODULUS).await {
+                    return Err(PipeError::BadResponse);
+                }
+            }
+
+            self.configure_channel(false, EndpointType::Isochronous, 1, chunk.len() as u32, dpid);
+            self.set_split(xactpos, false);
+            self.enable_channel();
+            self.write_fifo(chunk);
+
+            let events = self.wait_for_result().await;
+            match Self::classify_events(events) {
+                ChannelEvent::Complete => {}
+                ChannelEvent::Error(e) => {
+                    self.halt_channel();
+                    return Err(e);
+                }
+                _ => {
+                    self.halt_channel();
+                    return Err(PipeError::BadResponse);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute an IN transfer as one split transaction per packet.
+    async fn split_in_transfer(&mut self, ep_type: EndpointType, buf: &mut [u8], dpid: u8) -> Result<usize, PipeError> {
+        let is_periodic = matches!(ep_type, EndpointType::Interrupt | EndpointType::Isochronous);
+        let mps = self.max_packet_size as usize;
+        let mut offset = 0;
+        let mut dpid = dpid;
+        loop {
+            let n = self.split_in(ep_type, &mut buf[offset..], dpid).await?;
+            offset += n;
+            if is_periodic || n < mps || offset >= buf.len() {
+                return Ok(offset);
+            }
+            dpid = next_dpid(dpid);
+        }
+    }
+
+    /// Execute an OUT transfer as one split transaction per packet.
+    async fn split_out_transfer(&mut self, ep_type: EndpointType, data: &[u8], dpid: u8) -> Result<(), PipeError> {
+        let mps = self.max_packet_size as usize;
+        if ep_type == EndpointType::Isochronous || data.len() <= mps {
+            return self.split_out(ep_type, data, dpid).await;
+        }
+        let mut dpid = dpid;
+        for chunk in data.chunks(mps) {
+            self.split_out(ep_type, chunk, dpid).await?;
+            dpid = next_dpid(dpid);
+        }
+        Ok(())
+    }
+
     /// Execute an OUT transfer on this channel, retrying indefinitely on NAK/NYET.
     ///
     /// Per USB spec, NAK is a legitimate "try again" response with no
@@ -1157,6 +1482,10 @@ where
     /// deadline if one is needed. Disconnect is still surfaced via
     /// [`PipeError::Disconnected`].
     async fn do_out_transfer(&mut self, ep_type: EndpointType, data: &[u8], dpid: u8) -> Result<(), PipeError> {
+        if self.split.is_some() {
+            return self.split_out_transfer(ep_type, data, dpid).await;
+        }
+
         let pktcnt = if data.is_empty() {
             1
         } else {
@@ -1200,6 +1529,10 @@ where
     /// deadline if one is needed. Disconnect is still surfaced via
     /// [`PipeError::Disconnected`].
     async fn do_in_transfer(&mut self, ep_type: EndpointType, buf: &mut [u8], dpid: u8) -> Result<usize, PipeError> {
+        if self.split.is_some() {
+            return self.split_in_transfer(ep_type, buf, dpid).await;
+        }
+
         // For interrupt/isochronous endpoints, only request one packet per transfer.
         // The device sends at most one packet per (micro)frame.
         let is_periodic = matches!(ep_type, EndpointType::Interrupt | EndpointType::Isochronous);
@@ -1395,6 +1728,15 @@ impl vals::Dpid {
     const SETUP: Self = Self::MDATA;
 }
 
+/// The data PID following `dpid` in a DATA0/DATA1 sequence.
+fn next_dpid(dpid: u8) -> u8 {
+    if dpid == vals::Dpid::DATA0.to_bits() {
+        vals::Dpid::DATA1.to_bits()
+    } else {
+        vals::Dpid::DATA0.to_bits()
+    }
+}
+
 /// Yield to the executor, allowing other tasks to run.
 async fn yield_now() {
     let mut yielded = false;
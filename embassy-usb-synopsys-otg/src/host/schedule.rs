@@ -0,0 +1,345 @@
+//! Periodic bandwidth budgeting for split transactions.
+//!
+//! Interrupt and isochronous transfers to a full- or low-speed device behind a
+//! high-speed hub go through the hub's Transaction Translator (TT): the host sends
+//! a start-split (SSPLIT) in one microframe, the TT runs the transaction on the
+//! full-speed bus from the next microframe on, and the host collects the result
+//! with complete-splits (CSPLIT) (USB 2.0 §11.14, §11.18).
+//!
+//! A TT moves a limited amount of full-speed data per microframe, so every
+//! periodic split pipe reserves full-speed bus time in a fixed slot of a repeating
+//! schedule of [`SCHEDULE_FRAMES`] frames, and allocation fails when no slot has
+//! room. The per-microframe budget adds up to less than the 90% of a frame that
+//! periodic transfers may use (USB 2.0 §5.6.4), so that limit needs no check of
+//! its own.
+//!
+//! Microframes are numbered as the spec's Y0..Y7: the full-speed frame starts
+//! in Y0, and the start-split for a transaction beginning in Y0 is sent in the
+//! last microframe of the previous frame.
+//!
+//! Each hub is assumed to have a single TT, which is exact for single-TT hubs and
+//! conservative for multi-TT hubs. High-speed bus time of the split tokens is not
+//! budgeted.
+
+use core::iter;
+
+use embassy_usb_driver::EndpointType;
+
+/// Frames in the schedule. Longer polling intervals are shortened to this.
+pub(crate) const SCHEDULE_FRAMES: usize = 32;
+
+/// Maximum number of reservations, one per host channel.
+const MAX_RESERVATIONS: usize = 16;
+
+/// Full-speed bus time a TT can use in each microframe, in microseconds.
+const MAX_TT_USECS: [u16; 8] = [125, 125, 125, 125, 125, 125, 30, 0];
+
+/// Largest full-speed payload a TT sends in one microframe (USB 2.0 §11.18.4).
+pub(crate) const MAX_SPLIT_PAYLOAD: usize = 188;
+
+/// A periodic full- or low-speed transaction to schedule.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Transaction {
+    pub ep_type: EndpointType,
+    pub dir_in: bool,
+    pub low_speed: bool,
+    pub max_packet_size: u16,
+    /// `bInterval` of the endpoint descriptor.
+    pub interval: u8,
+}
+
+impl Transaction {
+    /// Full-speed bus time of the transaction in microseconds, from the bus
+    /// time formulas of USB 2.0 §5.11.3.
+    pub fn usecs(&self) -> u16 {
+        const HUB_LS_SETUP_NS: u64 = 333;
+        // Data bits after worst case bit stuffing, plus sync and EOP, in 1/1000 bit.
+        let mbits = 3167 + 56_000 * self.max_packet_size as u64 / 6;
+        let ns = match (self.low_speed, self.ep_type, self.dir_in) {
+            (true, _, true) => 64_060 + 2 * HUB_LS_SETUP_NS + 67_667 * mbits / 100_000,
+            (true, _, false) => 64_107 + 2 * HUB_LS_SETUP_NS + 66_700 * mbits / 100_000,
+            (false, EndpointType::Isochronous, true) => 7_268 + 8_354 * mbits / 100_000,
+            (false, EndpointType::Isochronous, false) => 6_265 + 8_354 * mbits / 100_000,
+            (false, _, _) => 9_107 + 8_354 * mbits / 100_000,
+        };
+        ns.div_ceil(1000) as u16
+    }
+
+    /// Period in frames: a power of two no longer than the polling interval.
+    pub fn period(&self) -> u8 {
+        let frames = match self.ep_type {
+            // Full-speed isochronous intervals are 2^(bInterval-1) frames.
+            EndpointType::Isochronous => 1u32 << (self.interval.clamp(1, 16) - 1),
+            _ => self.interval.max(1) as u32,
+        };
+        let frames = frames.min(SCHEDULE_FRAMES as u32);
+        (1u32 << frames.ilog2()) as u8
+    }
+
+    /// Latest microframe the transaction can start in, and the last microframe
+    /// it may end in when starting in `start`.
+    fn window(&self, start: usize) -> (usize, usize) {
+        match (self.ep_type, self.dir_in) {
+            // No handshake. One start-split per 188 bytes, the last one sent no
+            // later than Y7.
+            (EndpointType::Isochronous, false) => {
+                let start_splits = (self.max_packet_size as usize).div_ceil(MAX_SPLIT_PAYLOAD).max(1);
+                ((9 - start_splits).min(6), 6)
+            }
+            // Complete-splits up to Y7 collect the data.
+            (EndpointType::Isochronous, true) => (6, 6),
+            // Three complete-splits in Y+1..Y+3 collect the result (USB 2.0 §11.18.8).
+            _ => (4, start + 2),
+        }
+    }
+}
+
+/// The schedule slot reserved for a periodic split pipe.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) struct Reservation {
+    /// Address of the hub owning the TT.
+    pub hub_addr: u8,
+    /// Period in frames, a power of two.
+    pub period: u8,
+    /// Frame within the period.
+    pub phase: u8,
+    /// Microframe the transaction starts in on the full-speed bus. The
+    /// start-split is sent in the microframe before.
+    pub start_uframe: u8,
+    /// Last microframe the transaction may end in.
+    pub deadline: u8,
+    /// Microframe of the last complete-split, if the transaction has any.
+    pub last_csplit_uframe: Option<u8>,
+    /// Full-speed bus time in microseconds.
+    pub usecs: u16,
+}
+
+impl Reservation {
+    fn is_active(&self, frame: usize) -> bool {
+        frame % self.period as usize == self.phase as usize
+    }
+}
+
+/// Places `usecs` of full-speed bus time from microframe `start` on, behind what
+/// `load` already holds. Returns the last microframe used.
+fn place(load: &mut [u16; 8], start: usize, usecs: u16) -> Option<usize> {
+    let mut remaining = usecs;
+    let mut uframe = start;
+    loop {
+        let free = MAX_TT_USECS.get(uframe)?.saturating_sub(load[uframe]);
+        let used = free.min(remaining);
+        load[uframe] += used;
+        remaining -= used;
+        if remaining == 0 {
+            return Some(uframe);
+        }
+        uframe += 1;
+    }
+}
+
+/// Full-speed bus time reserved on each TT.
+pub(crate) struct PeriodicSchedule {
+    reservations: [Option<Reservation>; MAX_RESERVATIONS],
+}
+
+impl PeriodicSchedule {
+    pub const fn new() -> Self {
+        Self {
+            reservations: [None; MAX_RESERVATIONS],
+        }
+    }
+
+    /// Checks that every transaction on the TT of `candidate` still meets its
+    /// deadline once `candidate` is added.
+    ///
+    /// The TT runs transactions in start-split order, so each one is placed
+    /// behind those starting earlier, and behind existing ones starting in the
+    /// same microframe.
+    fn fits(&self, candidate: &Reservation) -> bool {
+        let phase = candidate.phase as usize;
+        (phase..SCHEDULE_FRAMES)
+            .step_by(candidate.period as usize)
+            .all(|frame| {
+                let active = self
+                    .reservations
+                    .iter()
+                    .flatten()
+                    .filter(|r| r.hub_addr == candidate.hub_addr && r.is_active(frame))
+                    .chain(iter::once(candidate));
+                let mut load = [0; 8];
+                (0..8).all(|start| {
+                    active
+                        .clone()
+                        .filter(|r| r.start_uframe as usize == start)
+                        .all(|r| place(&mut load, start, r.usecs).is_some_and(|end| end <= r.deadline as usize))
+                })
+            })
+    }
+
+    /// Reserves a slot for `tr` on the TT of `hub_addr`, on behalf of channel `owner`.
+    ///
+    /// Returns `None` if the TT budget is exhausted in every slot.
+    pub fn reserve(&mut self, owner: usize, hub_addr: u8, tr: &Transaction) -> Option<Reservation> {
+        let period = tr.period();
+        let (last_start, _) = tr.window(0);
+        for phase in 0..period {
+            for start in 0..=last_start {
+                let (_, deadline) = tr.window(start);
+                let last_csplit_uframe = match (tr.ep_type, tr.dir_in) {
+                    (EndpointType::Isochronous, false) => None,
+                    _ => Some(deadline as u8 + 1),
+                };
+                let candidate = Reservation {
+                    hub_addr,
+                    period,
+                    phase,
+                    start_uframe: start as u8,
+                    deadline: deadline as u8,
+                    last_csplit_uframe,
+                    usecs: tr.usecs(),
+                };
+                if self.fits(&candidate) {
+                    self.reservations[owner] = Some(candidate);
+                    return Some(candidate);
+                }
+            }
+        }
+        None
+    }
+
+    /// Frees the slot reserved by channel `owner`.
+    pub fn release(&mut self, owner: usize) {
+        self.reservations[owner] = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interrupt(low_speed: bool, max_packet_size: u16, interval: u8) -> Transaction {
+        Transaction {
+            ep_type: EndpointType::Interrupt,
+            dir_in: true,
+            low_speed,
+            max_packet_size,
+            interval,
+        }
+    }
+
+    fn iso(dir_in: bool, max_packet_size: u16, interval: u8) -> Transaction {
+        Transaction {
+            ep_type: EndpointType::Isochronous,
+            dir_in,
+            low_speed: false,
+            max_packet_size,
+            interval,
+        }
+    }
+
+    #[test]
+    fn bus_time() {
+        assert_eq!(interrupt(false, 64, 1).usecs(), 60);
+        assert_eq!(interrupt(false, 8, 1).usecs(), 16);
+        assert_eq!(interrupt(true, 8, 10).usecs(), 118);
+        assert_eq!(iso(true, 1023, 1).usecs(), 806);
+        assert_eq!(iso(false, 192, 1).usecs(), 157);
+    }
+
+    #[test]
+    fn period() {
+        assert_eq!(interrupt(false, 8, 1).period(), 1);
+        assert_eq!(interrupt(false, 8, 10).period(), 8);
+        assert_eq!(interrupt(false, 8, 255).period(), 32);
+        assert_eq!(interrupt(false, 8, 0).period(), 1);
+        assert_eq!(iso(true, 8, 4).period(), 8);
+        assert_eq!(iso(true, 8, 16).period(), 32);
+    }
+
+    #[test]
+    fn tt_budget_exhausted() {
+        let mut schedule = PeriodicSchedule::new();
+        let tr = interrupt(false, 64, 1);
+
+        // 60 µs each, 780 µs per frame.
+        let mut reserved = 0;
+        while schedule.reserve(reserved, 1, &tr).is_some() {
+            reserved += 1;
+        }
+        assert_eq!(reserved, 13);
+        for r in schedule.reservations.iter().flatten() {
+            assert_eq!(r.phase, 0);
+            assert!(r.start_uframe <= 4);
+            assert_eq!(r.last_csplit_uframe, Some(r.start_uframe + 3));
+        }
+
+        // Another hub has its own TT.
+        assert!(schedule.reserve(13, 2, &tr).is_some());
+
+        // Releasing a reservation makes room again.
+        schedule.release(3);
+        assert!(schedule.reserve(3, 1, &tr).is_some());
+        assert!(schedule.reserve(14, 1, &tr).is_none());
+    }
+
+    #[test]
+    fn spread_over_phases() {
+        let mut schedule = PeriodicSchedule::new();
+        // Low-speed keyboards polled every 8 ms, 118 µs each.
+        let tr = interrupt(true, 8, 10);
+        let phases: [u8; 8] = core::array::from_fn(|i| schedule.reserve(i, 1, &tr).unwrap().phase);
+        assert_eq!(phases, [0, 0, 0, 0, 0, 0, 1, 1]);
+        assert_eq!(schedule.reservations[5].unwrap().start_uframe, 3);
+    }
+
+    #[test]
+    fn insertion_keeps_deadlines() {
+        let mut schedule = PeriodicSchedule::new();
+        let tr = interrupt(true, 8, 1);
+        for i in 0..4 {
+            schedule.reserve(i, 1, &tr).unwrap();
+        }
+        for i in 0..3 {
+            schedule.release(i);
+        }
+        assert_eq!(schedule.reservations[3].unwrap().start_uframe, 1);
+
+        // Starting in Y0 would push the remaining transaction past Y3.
+        let r = schedule.reserve(0, 1, &iso(true, 600, 1)).unwrap();
+        assert_eq!(r.start_uframe, 1);
+    }
+
+    #[test]
+    fn isochronous_in() {
+        let mut schedule = PeriodicSchedule::new();
+        // 806 µs exceeds what a TT can carry in one frame.
+        assert!(schedule.reserve(0, 1, &iso(true, 1023, 1)).is_none());
+
+        let r = schedule.reserve(0, 1, &iso(true, 900, 1)).unwrap();
+        assert_eq!((r.start_uframe, r.last_csplit_uframe), (0, Some(7)));
+        let r = schedule.reserve(1, 1, &interrupt(false, 64, 1)).unwrap();
+        assert_eq!(r.start_uframe, 4);
+        assert!(schedule.reserve(2, 1, &interrupt(false, 8, 1)).is_none());
+        schedule.release(0);
+        schedule.release(1);
+
+        let tr = iso(true, 600, 2);
+        assert_eq!(schedule.reserve(0, 1, &tr).unwrap().phase, 0);
+        assert_eq!(schedule.reserve(1, 1, &tr).unwrap().phase, 1);
+        assert!(schedule.reserve(2, 1, &tr).is_none());
+        assert!(schedule.reserve(2, 1, &interrupt(false, 64, 1)).is_some());
+    }
+
+    #[test]
+    fn isochronous_out() {
+        let mut schedule = PeriodicSchedule::new();
+        assert!(schedule.reserve(0, 1, &iso(false, 1023, 1)).is_none());
+
+        // 752 bytes take four start-splits of at most 188 bytes.
+        let r = schedule.reserve(0, 1, &iso(false, 752, 1)).unwrap();
+        assert_eq!((r.start_uframe, r.last_csplit_uframe, r.usecs), (0, None, 593));
+        assert!(schedule.reserve(1, 1, &iso(false, 64, 1)).is_some());
+        assert!(schedule.reserve(2, 1, &iso(false, 752, 1)).is_none());
+    }
+}
@@ -1,13 +1,14 @@
 use heapless::Vec;
 
-use crate::config::MAX_HANDLER_COUNT;
+use crate::config::{MAX_HANDLER_COUNT, MAX_INTERFACE_COUNT};
 use crate::descriptor::{
     self, BosWriter, DescriptorWriter, SynchronizationType, UsageType, rewrite_config_descriptor_for_high_speed,
 };
-use crate::driver::{Driver, Endpoint, EndpointAddress, EndpointInfo, EndpointType};
+use crate::descriptor_reader::foreach_endpoint;
+use crate::driver::{Driver, Endpoint, EndpointAddress, EndpointAllocError, EndpointInfo, EndpointType};
 use crate::msos::{DeviceLevelDescriptor, FunctionLevelDescriptor, MsOsDescriptorWriter};
 use crate::types::{InterfaceNumber, StringIndex};
-use crate::{Handler, Inner, Interface, MAX_INTERFACE_COUNT, STRING_INDEX_CUSTOM_START, UsbDevice, UsbDeviceState};
+use crate::{Handler, Inner, Interface, STRING_INDEX_CUSTOM_START, UsbDevice, UsbDeviceState};
 
 #[derive(Debug, Copy, Clone)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -160,16 +161,77 @@ impl<'a> Config<'a> {
     }
 }
 
+/// Error returned by [`Builder::finish`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum BuildError {
+    /// The configuration descriptor does not fit in `config_descriptor_buf`.
+    ConfigDescriptorOverflow,
+    /// The BOS descriptor does not fit in `bos_descriptor_buf`.
+    BosDescriptorOverflow,
+    /// The MS OS 2.0 descriptor set does not fit in `msos_descriptor_buf`.
+    MsOsDescriptorOverflow,
+    /// More interfaces were added than the `MAX_INTERFACES` parameter of the [`Builder`] allows.
+    TooManyInterfaces {
+        /// Number of interfaces added.
+        count: usize,
+        /// Maximum number of interfaces.
+        max: usize,
+    },
+    /// More handlers were added than the `MAX_HANDLERS` parameter of the [`Builder`] allows.
+    TooManyHandlers {
+        /// Number of handlers added.
+        count: usize,
+        /// Maximum number of handlers.
+        max: usize,
+    },
+    /// More string descriptors were allocated than string indices exist.
+    TooManyStrings,
+    /// An endpoint address is used by more than one interface, or twice in the same alternate setting.
+    EndpointConflict(EndpointAddress),
+    /// A function has more than one interface, but [`Config::composite_with_iads`] is not set,
+    /// so no interface association descriptor groups them. Only reported for devices with more
+    /// than one function, or whose device class announces IADs.
+    MissingIad {
+        /// First interface of the function.
+        first_interface: InterfaceNumber,
+    },
+    /// The driver could not allocate an endpoint requested with
+    /// [`InterfaceAltBuilder::try_alloc_endpoint_in`] or
+    /// [`InterfaceAltBuilder::try_alloc_endpoint_out`].
+    EndpointAlloc {
+        /// Interface the endpoint was requested for.
+        interface: InterfaceNumber,
+    },
+}
+
 /// [`UsbDevice`] builder.
-pub struct Builder<'d, D: Driver<'d>> {
+///
+/// `MAX_INTERFACES` and `MAX_HANDLERS` bound the number of interfaces and handlers the device
+/// can have. They default to the values selected with the `max-interface-count-*` and
+/// `max-handler-count-*` cargo features.
+pub struct Builder<
+    'd,
+    D: Driver<'d>,
+    const MAX_INTERFACES: usize = MAX_INTERFACE_COUNT,
+    const MAX_HANDLERS: usize = MAX_HANDLER_COUNT,
+> {
     config: Config<'d>,
-    handlers: Vec<&'d mut dyn Handler, MAX_HANDLER_COUNT>,
-    interfaces: Vec<Interface, MAX_INTERFACE_COUNT>,
+    handlers: Vec<&'d mut dyn Handler, MAX_HANDLERS>,
+    interfaces: Vec<Interface, MAX_INTERFACES>,
     control_buf: &'d mut [u8],
 
     driver: D,
     next_string_index: u8,
 
+    interface_count: usize,
+    handler_count: usize,
+    strings_exhausted: bool,
+    function_count: usize,
+    missing_iad: Option<InterfaceNumber>,
+    endpoint_alloc_failed: Option<InterfaceNumber>,
+
     config_descriptor: DescriptorWriter<'d>,
     bos_descriptor: BosWriter<'d>,
 
@@ -182,6 +244,10 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
     /// `control_buf` is a buffer used for USB control request data. It should be sized
     /// large enough for the length of the largest control request (in or out)
     /// anticipated by any class added to the device.
+    ///
+    /// The interface and handler limits are taken from the `max-interface-count-*` and
+    /// `max-handler-count-*` features, use [`with_limits`](Self::with_limits) to choose
+    /// them per device.
     pub fn new(
         driver: D,
         config: Config<'d>,
@@ -189,6 +255,36 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
         bos_descriptor_buf: &'d mut [u8],
         msos_descriptor_buf: &'d mut [u8],
         control_buf: &'d mut [u8],
+    ) -> Self {
+        Self::with_limits(
+            driver,
+            config,
+            config_descriptor_buf,
+            bos_descriptor_buf,
+            msos_descriptor_buf,
+            control_buf,
+        )
+    }
+}
+
+impl<'d, D: Driver<'d>, const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>
+    Builder<'d, D, MAX_INTERFACES, MAX_HANDLERS>
+{
+    /// Creates a builder for a [`UsbDevice`] with at most `MAX_INTERFACES` interfaces and
+    /// `MAX_HANDLERS` handlers.
+    ///
+    /// ```ignore
+    /// let mut builder = Builder::<_, 8, 6>::with_limits(driver, config, &mut config_descriptor, ...);
+    /// ```
+    ///
+    /// See [`new`](Builder::new) for the buffers.
+    pub fn with_limits(
+        driver: D,
+        config: Config<'d>,
+        config_descriptor_buf: &'d mut [u8],
+        bos_descriptor_buf: &'d mut [u8],
+        msos_descriptor_buf: &'d mut [u8],
+        control_buf: &'d mut [u8],
     ) -> Self {
         // Magic values specified in USB-IF ECN on IADs.
         if config.composite_with_iads
@@ -223,6 +319,13 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
             control_buf,
             next_string_index: STRING_INDEX_CUSTOM_START,
 
+            interface_count: 0,
+            handler_count: 0,
+            strings_exhausted: false,
+            function_count: 0,
+            missing_iad: None,
+            endpoint_alloc_failed: None,
+
             config_descriptor,
             bos_descriptor,
 
@@ -231,7 +334,25 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
     }
 
     /// Creates the [`UsbDevice`] instance with the configuration in this builder.
-    pub fn build(self) -> UsbDevice<'d, D> {
+    ///
+    /// # Panics
+    ///
+    /// Panics if the configuration is invalid, see [`finish`](Self::finish) for a
+    /// non-panicking variant.
+    pub fn build(self) -> UsbDevice<'d, D, MAX_INTERFACES, MAX_HANDLERS> {
+        match self.finish() {
+            Ok(device) => device,
+            Err(e) => panic!("embassy-usb: invalid device configuration: {:?}", e),
+        }
+    }
+
+    /// Validates the configuration in this builder and creates the [`UsbDevice`] instance.
+    ///
+    /// Returns an error if a descriptor buffer is too small, if more interfaces or
+    /// handlers were added than the const parameters of the builder allow, if an
+    /// endpoint could not be allocated or its address is claimed twice, or if a
+    /// multi-interface function lacks an interface association descriptor.
+    pub fn finish(self) -> Result<UsbDevice<'d, D, MAX_INTERFACES, MAX_HANDLERS>, BuildError> {
         let Self {
             config,
             handlers,
@@ -239,17 +360,60 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
             control_buf,
             driver,
             next_string_index: _,
+            interface_count,
+            handler_count,
+            strings_exhausted,
+            function_count,
+            missing_iad,
+            endpoint_alloc_failed,
             mut config_descriptor,
             mut bos_descriptor,
             msos_descriptor,
         } = self;
 
+        if interface_count > MAX_INTERFACES {
+            return Err(BuildError::TooManyInterfaces {
+                count: interface_count,
+                max: MAX_INTERFACES,
+            });
+        }
+        if handler_count > MAX_HANDLERS {
+            return Err(BuildError::TooManyHandlers {
+                count: handler_count,
+                max: MAX_HANDLERS,
+            });
+        }
+        if strings_exhausted {
+            return Err(BuildError::TooManyStrings);
+        }
+        if let Some(interface) = endpoint_alloc_failed {
+            return Err(BuildError::EndpointAlloc { interface });
+        }
+        // A single function without IADs is fine, as long as the device class describes it,
+        // like CDC-ACM with `device_class = 0x02`.
+        let iad_class = (config.device_class, config.device_sub_class, config.device_protocol) == (0xEF, 0x02, 0x01);
+        if let Some(first_interface) = missing_iad
+            && (function_count > 1 || iad_class)
+        {
+            return Err(BuildError::MissingIad { first_interface });
+        }
+
+        if msos_descriptor.overflowed() {
+            return Err(BuildError::MsOsDescriptorOverflow);
+        }
         let msos_descriptor = msos_descriptor.build(&mut bos_descriptor);
 
         config_descriptor.end_configuration();
+        if config_descriptor.overflowed() {
+            return Err(BuildError::ConfigDescriptorOverflow);
+        }
         let config_descriptor = config_descriptor.into_buf();
+        check_endpoints(config_descriptor)?;
 
         bos_descriptor.end_bos();
+        if bos_descriptor.writer.overflowed() {
+            return Err(BuildError::BosDescriptorOverflow);
+        }
         let bos_descriptor = bos_descriptor.writer.into_buf();
 
         if config.max_speed == UsbDeviceSpeed::High {
@@ -272,7 +436,7 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
         let device_descriptor = descriptor::device_descriptor(&config);
         let device_qualifier_descriptor = descriptor::device_qualifier_descriptor(&config);
 
-        UsbDevice {
+        Ok(UsbDevice {
             control_buf,
             control,
             inner: Inner {
@@ -293,7 +457,7 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
                 interfaces,
                 handlers,
             },
-        }
+        })
     }
 
     /// Returns the size of the control request data buffer. Can be used by
@@ -308,13 +472,20 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
     /// with the given class/subclass/protocol, associating all the child interfaces.
     ///
     /// If it's not set, no IAD descriptor is added.
-    pub fn function(&mut self, class: u8, subclass: u8, protocol: u8) -> FunctionBuilder<'_, 'd, D> {
-        let first_interface = InterfaceNumber::new(self.interfaces.len() as u8);
+    pub fn function(
+        &mut self,
+        class: u8,
+        subclass: u8,
+        protocol: u8,
+    ) -> FunctionBuilder<'_, 'd, D, MAX_INTERFACES, MAX_HANDLERS> {
+        let first_interface = InterfaceNumber::new(self.interface_count as u8);
+        self.function_count += 1;
         let iface_count_index = if self.config.composite_with_iads {
             self.config_descriptor
                 .iad(first_interface, 0, class, subclass, protocol);
 
-            Some(self.config_descriptor.position() - 5)
+            // The IAD is not written if the descriptor buffer is full.
+            (!self.config_descriptor.overflowed()).then(|| self.config_descriptor.position() - 5)
         } else {
             None
         };
@@ -322,6 +493,7 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
         FunctionBuilder {
             builder: self,
             iface_count_index,
+            iface_count: 0,
 
             first_interface,
         }
@@ -331,18 +503,27 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
     ///
     /// The Handler is called on some USB bus events, and to handle all control requests not already
     /// handled by the USB stack.
+    ///
+    /// Handlers beyond `MAX_HANDLERS` are dropped and reported by [`finish`](Self::finish).
     pub fn handler(&mut self, handler: &'d mut dyn Handler) {
-        assert!(
-            self.handlers.push(handler).is_ok(),
-            "embassy-usb: handler list full. Increase the `max_handler_count` compile-time setting. Current value: {}",
-            MAX_HANDLER_COUNT
-        );
+        self.handler_count += 1;
+        if self.handlers.push(handler).is_err() {
+            warn!(
+                "embassy-usb: handler list full. Increase the `MAX_HANDLERS` parameter of the builder. Current value: {}",
+                MAX_HANDLERS
+            );
+        }
     }
 
     /// Allocates a new string index.
+    ///
+    /// Running out of string indices is reported by [`finish`](Self::finish).
     pub fn string(&mut self) -> StringIndex {
         let index = self.next_string_index;
-        self.next_string_index += 1;
+        match self.next_string_index.checked_add(1) {
+            Some(next) => self.next_string_index = next,
+            None => self.strings_exhausted = true,
+        }
         StringIndex::new(index)
     }
 
@@ -370,39 +551,60 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
 /// A function is a logical grouping of interfaces that perform a given USB function.
 /// If [`Config::composite_with_iads`] is set, each function will have an IAD descriptor.
 /// If not, functions will not be visible as descriptors.
-pub struct FunctionBuilder<'a, 'd, D: Driver<'d>> {
-    builder: &'a mut Builder<'d, D>,
+pub struct FunctionBuilder<
+    'a,
+    'd,
+    D: Driver<'d>,
+    const MAX_INTERFACES: usize = MAX_INTERFACE_COUNT,
+    const MAX_HANDLERS: usize = MAX_HANDLER_COUNT,
+> {
+    builder: &'a mut Builder<'d, D, MAX_INTERFACES, MAX_HANDLERS>,
     iface_count_index: Option<usize>,
+    iface_count: usize,
 
     first_interface: InterfaceNumber,
 }
 
-impl<'a, 'd, D: Driver<'d>> Drop for FunctionBuilder<'a, 'd, D> {
+impl<'a, 'd, D: Driver<'d>, const MAX_INTERFACES: usize, const MAX_HANDLERS: usize> Drop
+    for FunctionBuilder<'a, 'd, D, MAX_INTERFACES, MAX_HANDLERS>
+{
     fn drop(&mut self) {
         self.builder.msos_descriptor.end_function();
+
+        // Without an IAD the host has no way to tell the interfaces of this function belong together.
+        if self.iface_count > 1 && !self.builder.config.composite_with_iads && self.builder.missing_iad.is_none() {
+            self.builder.missing_iad = Some(self.first_interface);
+        }
     }
 }
 
-impl<'a, 'd, D: Driver<'d>> FunctionBuilder<'a, 'd, D> {
+impl<'a, 'd, D: Driver<'d>, const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>
+    FunctionBuilder<'a, 'd, D, MAX_INTERFACES, MAX_HANDLERS>
+{
     /// Add an interface to the function.
     ///
     /// Interface numbers are guaranteed to be allocated consecutively, starting from 0.
-    pub fn interface(&mut self) -> InterfaceBuilder<'_, 'd, D> {
+    ///
+    /// Interfaces beyond `MAX_INTERFACES` are reported by [`Builder::finish`].
+    pub fn interface(&mut self) -> InterfaceBuilder<'_, 'd, D, MAX_INTERFACES, MAX_HANDLERS> {
         if let Some(i) = self.iface_count_index {
             self.builder.config_descriptor.buf[i] += 1;
         }
+        self.iface_count += 1;
 
-        let number = self.builder.interfaces.len() as _;
+        let number = self.builder.interface_count as _;
+        self.builder.interface_count += 1;
         let iface = Interface {
             current_alt_setting: 0,
             num_alt_settings: 0,
         };
 
-        assert!(
-            self.builder.interfaces.push(iface).is_ok(),
-            "embassy-usb: interface list full. Increase the `max_interface_count` compile-time setting. Current value: {}",
-            MAX_INTERFACE_COUNT
-        );
+        if self.builder.interfaces.push(iface).is_err() {
+            warn!(
+                "embassy-usb: interface list full. Increase the `MAX_INTERFACES` parameter of the builder. Current value: {}",
+                MAX_INTERFACES
+            );
+        }
 
         InterfaceBuilder {
             builder: self.builder,
@@ -426,13 +628,21 @@ impl<'a, 'd, D: Driver<'d>> FunctionBuilder<'a, 'd, D> {
 }
 
 /// Interface builder.
-pub struct InterfaceBuilder<'a, 'd, D: Driver<'d>> {
-    builder: &'a mut Builder<'d, D>,
+pub struct InterfaceBuilder<
+    'a,
+    'd,
+    D: Driver<'d>,
+    const MAX_INTERFACES: usize = MAX_INTERFACE_COUNT,
+    const MAX_HANDLERS: usize = MAX_HANDLER_COUNT,
+> {
+    builder: &'a mut Builder<'d, D, MAX_INTERFACES, MAX_HANDLERS>,
     interface_number: InterfaceNumber,
     next_alt_setting_number: u8,
 }
 
-impl<'a, 'd, D: Driver<'d>> InterfaceBuilder<'a, 'd, D> {
+impl<'a, 'd, D: Driver<'d>, const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>
+    InterfaceBuilder<'a, 'd, D, MAX_INTERFACES, MAX_HANDLERS>
+{
     /// Get the interface number.
     pub const fn interface_number(&self) -> InterfaceNumber {
         self.interface_number
@@ -454,10 +664,12 @@ impl<'a, 'd, D: Driver<'d>> InterfaceBuilder<'a, 'd, D> {
         subclass: u8,
         protocol: u8,
         interface_string: Option<StringIndex>,
-    ) -> InterfaceAltBuilder<'_, 'd, D> {
+    ) -> InterfaceAltBuilder<'_, 'd, D, MAX_INTERFACES, MAX_HANDLERS> {
         let number = self.next_alt_setting_number;
         self.next_alt_setting_number += 1;
-        self.builder.interfaces[self.interface_number.0 as usize].num_alt_settings += 1;
+        if let Some(iface) = self.builder.interfaces.get_mut(self.interface_number.0 as usize) {
+            iface.num_alt_settings += 1;
+        }
 
         self.builder.config_descriptor.interface_alt(
             self.interface_number,
@@ -477,13 +689,21 @@ impl<'a, 'd, D: Driver<'d>> InterfaceBuilder<'a, 'd, D> {
 }
 
 /// Interface alternate setting builder.
-pub struct InterfaceAltBuilder<'a, 'd, D: Driver<'d>> {
-    builder: &'a mut Builder<'d, D>,
+pub struct InterfaceAltBuilder<
+    'a,
+    'd,
+    D: Driver<'d>,
+    const MAX_INTERFACES: usize = MAX_INTERFACE_COUNT,
+    const MAX_HANDLERS: usize = MAX_HANDLER_COUNT,
+> {
+    builder: &'a mut Builder<'d, D, MAX_INTERFACES, MAX_HANDLERS>,
     interface_number: InterfaceNumber,
     alt_setting_number: u8,
 }
 
-impl<'a, 'd, D: Driver<'d>> InterfaceAltBuilder<'a, 'd, D> {
+impl<'a, 'd, D: Driver<'d>, const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>
+    InterfaceAltBuilder<'a, 'd, D, MAX_INTERFACES, MAX_HANDLERS>
+{
     /// Get the interface number.
     pub const fn interface_number(&self) -> InterfaceNumber {
         self.interface_number
@@ -527,6 +747,11 @@ impl<'a, 'd, D: Driver<'d>> InterfaceAltBuilder<'a, 'd, D> {
     /// Allocate an IN endpoint, without writing its descriptor.
     ///
     /// Used for granular control over the order of endpoint and descriptor creation.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the driver cannot allocate the endpoint. Use [`Self::try_alloc_endpoint_in`]
+    /// to have [`Builder::finish`] report it instead.
     pub fn alloc_endpoint_in(
         &mut self,
         ep_type: EndpointType,
@@ -534,12 +759,26 @@ impl<'a, 'd, D: Driver<'d>> InterfaceAltBuilder<'a, 'd, D> {
         max_packet_size: u16,
         interval_ms: u8,
     ) -> D::EndpointIn {
-        self.builder
-            .driver
-            .alloc_endpoint_in(ep_type, ep_addr, max_packet_size, interval_ms)
+        self.try_alloc_endpoint_in(ep_type, ep_addr, max_packet_size, interval_ms)
             .expect("alloc_endpoint_in failed")
     }
 
+    /// Allocate an IN endpoint, without writing its descriptor, or return `None` if the driver
+    /// cannot allocate it. [`Builder::finish`] then returns [`BuildError::EndpointAlloc`].
+    pub fn try_alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Option<D::EndpointIn> {
+        let ep = self
+            .builder
+            .driver
+            .alloc_endpoint_in(ep_type, ep_addr, max_packet_size, interval_ms);
+        self.alloc_result(ep)
+    }
+
     fn endpoint_in(
         &mut self,
         ep_type: EndpointType,
@@ -559,6 +798,11 @@ impl<'a, 'd, D: Driver<'d>> InterfaceAltBuilder<'a, 'd, D> {
     /// Allocate an OUT endpoint, without writing its descriptor.
     ///
     /// Use for granular control over the order of endpoint and descriptor creation.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the driver cannot allocate the endpoint. Use [`Self::try_alloc_endpoint_out`]
+    /// to have [`Builder::finish`] report it instead.
     pub fn alloc_endpoint_out(
         &mut self,
         ep_type: EndpointType,
@@ -566,12 +810,34 @@ impl<'a, 'd, D: Driver<'d>> InterfaceAltBuilder<'a, 'd, D> {
         max_packet_size: u16,
         interval_ms: u8,
     ) -> D::EndpointOut {
-        self.builder
-            .driver
-            .alloc_endpoint_out(ep_type, ep_addr, max_packet_size, interval_ms)
+        self.try_alloc_endpoint_out(ep_type, ep_addr, max_packet_size, interval_ms)
             .expect("alloc_endpoint_out failed")
     }
 
+    /// Allocate an OUT endpoint, without writing its descriptor, or return `None` if the
+    /// driver cannot allocate it. [`Builder::finish`] then returns [`BuildError::EndpointAlloc`].
+    pub fn try_alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Option<D::EndpointOut> {
+        let ep = self
+            .builder
+            .driver
+            .alloc_endpoint_out(ep_type, ep_addr, max_packet_size, interval_ms);
+        self.alloc_result(ep)
+    }
+
+    /// Record a failed allocation, to be reported by [`Builder::finish`].
+    fn alloc_result<E>(&mut self, ep: Result<E, EndpointAllocError>) -> Option<E> {
+        if ep.is_err() && self.builder.endpoint_alloc_failed.is_none() {
+            self.builder.endpoint_alloc_failed = Some(self.interface_number);
+        }
+        ep.ok()
+    }
+
     fn endpoint_out(
         &mut self,
         ep_type: EndpointType,
@@ -704,3 +970,388 @@ impl<'a, 'd, D: Driver<'d>> InterfaceAltBuilder<'a, 'd, D> {
         )
     }
 }
+
+/// Checks that no endpoint address is claimed by more than one interface, or twice by
+/// the same alternate setting.
+fn check_endpoints(config_descriptor: &[u8]) -> Result<(), BuildError> {
+    // Owner of each endpoint address, indexed by `index + 16 * is_in`.
+    let mut owners: [Option<(InterfaceNumber, u8)>; 32] = [None; 32];
+    let mut conflict = None;
+    foreach_endpoint(config_descriptor, |ep| {
+        let slot = &mut owners[ep.ep_address.index() % 16 + 16 * ep.ep_address.is_in() as usize];
+        match *slot {
+            Some((iface, alt)) if iface != ep.interface || alt == ep.interface_alt => {
+                conflict.get_or_insert(ep.ep_address);
+            }
+            _ => *slot = Some((ep.interface, ep.interface_alt)),
+        }
+    })
+    .map_err(|_| BuildError::ConfigDescriptorOverflow)?;
+
+    match conflict {
+        Some(addr) => Err(BuildError::EndpointConflict(addr)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_usb_driver::{Bus, ControlPipe, Direction, EndpointError, EndpointIn, EndpointOut, Event, Unsupported};
+
+    use super::*;
+
+    struct TestDriver {
+        next_index: usize,
+    }
+
+    struct TestEndpoint(EndpointInfo);
+
+    impl TestDriver {
+        /// Allocate endpoints 1 to 15 in order, like a driver with 16 endpoints.
+        fn alloc(
+            &mut self,
+            ep_type: EndpointType,
+            ep_addr: Option<EndpointAddress>,
+            dir: Direction,
+        ) -> Result<TestEndpoint, EndpointAllocError> {
+            let addr = match ep_addr {
+                Some(addr) => addr,
+                None if self.next_index < 15 => {
+                    self.next_index += 1;
+                    EndpointAddress::from_parts(self.next_index, dir)
+                }
+                None => return Err(EndpointAllocError),
+            };
+            Ok(TestEndpoint(EndpointInfo {
+                addr,
+                ep_type,
+                max_packet_size: 64,
+                interval_ms: 0,
+            }))
+        }
+    }
+
+    impl<'d> Driver<'d> for TestDriver {
+        type EndpointOut = TestEndpoint;
+        type EndpointIn = TestEndpoint;
+        type ControlPipe = TestEndpoint;
+        type Bus = TestEndpoint;
+
+        fn alloc_endpoint_out(
+            &mut self,
+            ep_type: EndpointType,
+            ep_addr: Option<EndpointAddress>,
+            _max_packet_size: u16,
+            _interval_ms: u8,
+        ) -> Result<TestEndpoint, EndpointAllocError> {
+            self.alloc(ep_type, ep_addr, Direction::Out)
+        }
+
+        fn alloc_endpoint_in(
+            &mut self,
+            ep_type: EndpointType,
+            ep_addr: Option<EndpointAddress>,
+            _max_packet_size: u16,
+            _interval_ms: u8,
+        ) -> Result<TestEndpoint, EndpointAllocError> {
+            self.alloc(ep_type, ep_addr, Direction::In)
+        }
+
+        fn start(self, _control_max_packet_size: u16) -> (TestEndpoint, TestEndpoint) {
+            let ep = TestEndpoint(EndpointInfo {
+                addr: EndpointAddress::from_parts(0, Direction::Out),
+                ep_type: EndpointType::Control,
+                max_packet_size: 64,
+                interval_ms: 0,
+            });
+            (TestEndpoint(ep.0), ep)
+        }
+    }
+
+    impl Endpoint for TestEndpoint {
+        fn info(&self) -> &EndpointInfo {
+            &self.0
+        }
+
+        async fn wait_enabled(&mut self) {}
+    }
+
+    impl EndpointOut for TestEndpoint {
+        async fn read(&mut self, _buf: &mut [u8]) -> Result<usize, EndpointError> {
+            Err(EndpointError::Disabled)
+        }
+    }
+
+    impl EndpointIn for TestEndpoint {
+        async fn write(&mut self, _buf: &[u8]) -> Result<(), EndpointError> {
+            Err(EndpointError::Disabled)
+        }
+    }
+
+    impl ControlPipe for TestEndpoint {
+        fn max_packet_size(&self) -> usize {
+            64
+        }
+
+        async fn setup(&mut self) -> [u8; 8] {
+            [0; 8]
+        }
+
+        async fn data_out(&mut self, _buf: &mut [u8], _first: bool, _last: bool) -> Result<usize, EndpointError> {
+            Err(EndpointError::Disabled)
+        }
+
+        async fn data_in(&mut self, _data: &[u8], _first: bool, _last: bool) -> Result<(), EndpointError> {
+            Err(EndpointError::Disabled)
+        }
+
+        async fn accept(&mut self) {}
+
+        async fn reject(&mut self) {}
+
+        async fn accept_set_address(&mut self, _addr: u8) {}
+    }
+
+    impl Bus for TestEndpoint {
+        async fn enable(&mut self) {}
+
+        async fn disable(&mut self) {}
+
+        async fn poll(&mut self) -> Event {
+            Event::PowerRemoved
+        }
+
+        fn endpoint_set_enabled(&mut self, _ep_addr: EndpointAddress, _enabled: bool) {}
+
+        fn endpoint_set_stalled(&mut self, _ep_addr: EndpointAddress, _stalled: bool) {}
+
+        fn endpoint_is_stalled(&mut self, _ep_addr: EndpointAddress) -> bool {
+            false
+        }
+
+        async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+            Err(Unsupported)
+        }
+    }
+
+    struct NoopHandler;
+
+    impl Handler for NoopHandler {}
+
+    struct Bufs {
+        config: [u8; 256],
+        bos: [u8; 64],
+        msos: [u8; 64],
+        control: [u8; 64],
+    }
+
+    impl Bufs {
+        fn new() -> Self {
+            Self {
+                config: [0; 256],
+                bos: [0; 64],
+                msos: [0; 64],
+                control: [0; 64],
+            }
+        }
+
+        fn builder<const I: usize, const H: usize>(
+            &mut self,
+            config: Config<'static>,
+        ) -> Builder<'_, TestDriver, I, H> {
+            Builder::with_limits(
+                TestDriver { next_index: 0 },
+                config,
+                &mut self.config,
+                &mut self.bos,
+                &mut self.msos,
+                &mut self.control,
+            )
+        }
+    }
+
+    fn add_function<const I: usize, const H: usize>(builder: &mut Builder<'_, TestDriver, I, H>, interfaces: usize) {
+        let mut func = builder.function(0xFF, 0, 0);
+        for _ in 0..interfaces {
+            let mut iface = func.interface();
+            let mut alt = iface.alt_setting(0xFF, 0, 0, None);
+            alt.endpoint_bulk_in(None, 64);
+        }
+    }
+
+    #[test]
+    fn finish_ok() {
+        let mut bufs = Bufs::new();
+        let mut builder = bufs.builder::<2, 1>(Config::new(0x1234, 0x5678));
+        add_function(&mut builder, 2);
+        let mut handler = NoopHandler;
+        builder.handler(&mut handler);
+        let device = builder.finish().unwrap();
+        assert_eq!(device.inner.interfaces.len(), 2);
+        assert_eq!(device.inner.handlers.len(), 1);
+    }
+
+    #[test]
+    fn too_many_interfaces_and_handlers() {
+        let mut bufs = Bufs::new();
+        let mut builder = bufs.builder::<1, 4>(Config::new(0x1234, 0x5678));
+        add_function(&mut builder, 3);
+        assert_eq!(
+            builder.finish().err(),
+            Some(BuildError::TooManyInterfaces { count: 3, max: 1 })
+        );
+
+        let mut bufs = Bufs::new();
+        let mut builder = bufs.builder::<4, 1>(Config::new(0x1234, 0x5678));
+        let (mut a, mut b) = (NoopHandler, NoopHandler);
+        builder.handler(&mut a);
+        builder.handler(&mut b);
+        assert_eq!(
+            builder.finish().err(),
+            Some(BuildError::TooManyHandlers { count: 2, max: 1 })
+        );
+    }
+
+    #[test]
+    fn config_descriptor_overflow() {
+        let mut config_buf = [0; 40];
+        let mut bufs = Bufs::new();
+        let mut builder: Builder<'_, TestDriver> = Builder::new(
+            TestDriver { next_index: 0 },
+            Config::new(0x1234, 0x5678),
+            &mut config_buf,
+            &mut bufs.bos,
+            &mut bufs.msos,
+            &mut bufs.control,
+        );
+        add_function(&mut builder, 2);
+        assert_eq!(builder.finish().err(), Some(BuildError::ConfigDescriptorOverflow));
+    }
+
+    #[test]
+    fn bos_descriptor_overflow() {
+        let mut bos_buf = [0; 16];
+        let mut bufs = Bufs::new();
+        let mut builder: Builder<'_, TestDriver> = Builder::new(
+            TestDriver { next_index: 0 },
+            Config::new(0x1234, 0x5678),
+            &mut bufs.config,
+            &mut bos_buf,
+            &mut bufs.msos,
+            &mut bufs.control,
+        );
+        let mut func = builder.function(0xFF, 0, 0);
+        let mut iface = func.interface();
+        let mut alt = iface.alt_setting(0xFF, 0, 0, None);
+        alt.bos_capability(0x05, &[0; 20]);
+        drop(func);
+        assert_eq!(builder.finish().err(), Some(BuildError::BosDescriptorOverflow));
+    }
+
+    #[test]
+    fn endpoint_conflict() {
+        let addr = EndpointAddress::from_parts(1, Direction::In);
+        let mut bufs = Bufs::new();
+        let mut builder = bufs.builder::<4, 4>(Config::new(0x1234, 0x5678));
+        let mut func = builder.function(0xFF, 0, 0);
+        for _ in 0..2 {
+            let mut iface = func.interface();
+            let mut alt = iface.alt_setting(0xFF, 0, 0, None);
+            alt.endpoint_bulk_in(Some(addr), 64);
+        }
+        drop(func);
+        assert_eq!(builder.finish().err(), Some(BuildError::EndpointConflict(addr)));
+    }
+
+    #[test]
+    fn endpoint_shared_between_alt_settings() {
+        let mut bufs = Bufs::new();
+        let mut builder = bufs.builder::<4, 4>(Config::new(0x1234, 0x5678));
+        let mut func = builder.function(0xFF, 0, 0);
+        let mut iface = func.interface();
+        let mut alt = iface.alt_setting(0xFF, 0, 0, None);
+        let ep = alt.alloc_endpoint_in(EndpointType::Bulk, None, 64, 0);
+        alt.endpoint_descriptor(
+            ep.info(),
+            SynchronizationType::NoSynchronization,
+            UsageType::DataEndpoint,
+            &[],
+        );
+        let mut alt = iface.alt_setting(0xFF, 0, 0, None);
+        alt.endpoint_descriptor(
+            ep.info(),
+            SynchronizationType::NoSynchronization,
+            UsageType::DataEndpoint,
+            &[],
+        );
+        drop(func);
+        assert!(builder.finish().is_ok());
+    }
+
+    #[test]
+    fn missing_iad() {
+        let mut config = Config::new(0x1234, 0x5678);
+        config.composite_with_iads = false;
+        config.device_class = 0xFF;
+        let mut bufs = Bufs::new();
+        let mut builder = bufs.builder::<4, 4>(config);
+        add_function(&mut builder, 1);
+        add_function(&mut builder, 2);
+        assert_eq!(
+            builder.finish().err(),
+            Some(BuildError::MissingIad {
+                first_interface: InterfaceNumber::new(1)
+            })
+        );
+    }
+
+    #[test]
+    fn single_function_without_iad() {
+        let mut config = Config::new(0x1234, 0x5678);
+        config.composite_with_iads = false;
+        config.device_class = 0x02;
+        config.device_sub_class = 0x00;
+        config.device_protocol = 0x00;
+        let mut bufs = Bufs::new();
+        let mut builder = bufs.builder::<4, 4>(config);
+        add_function(&mut builder, 2);
+        assert!(builder.finish().is_ok());
+
+        // With the IAD device class, the host still expects the interfaces to be associated.
+        let mut config = Config::new(0x1234, 0x5678);
+        config.composite_with_iads = false;
+        let mut bufs = Bufs::new();
+        let mut builder = bufs.builder::<4, 4>(config);
+        add_function(&mut builder, 2);
+        assert_eq!(
+            builder.finish().err(),
+            Some(BuildError::MissingIad {
+                first_interface: InterfaceNumber::new(0)
+            })
+        );
+    }
+
+    #[test]
+    fn endpoint_alloc() {
+        let mut bufs = Bufs::new();
+        let mut builder = bufs.builder::<2, 1>(Config::new(0x1234, 0x5678));
+        add_function(&mut builder, 1);
+        {
+            let mut func = builder.function(0xFF, 0, 0);
+            let mut iface = func.interface();
+            let mut alt = iface.alt_setting(0xFF, 0, 0, None);
+            // The first function left 14 of the 15 endpoints.
+            for _ in 0..14 {
+                assert!(alt.try_alloc_endpoint_in(EndpointType::Bulk, None, 64, 0).is_some());
+            }
+            assert!(alt.try_alloc_endpoint_out(EndpointType::Bulk, None, 64, 0).is_none());
+            assert!(alt.try_alloc_endpoint_in(EndpointType::Bulk, None, 64, 0).is_none());
+        }
+        assert_eq!(
+            builder.finish().err(),
+            Some(BuildError::EndpointAlloc {
+                interface: InterfaceNumber::new(1)
+            })
+        );
+    }
+}
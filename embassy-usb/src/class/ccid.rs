@@ -0,0 +1,817 @@
+//! USB Chip/Smart Card Interface Device (CCID) class implementation.
+//!
+//! Implements a single-slot reader using short APDU level exchange, which is what
+//! security tokens emulating a smart card usually do. The card itself is provided
+//! by the application through the [`Card`] trait: the class answers the bulk
+//! command messages sent by the host, calls into the card to power it and
+//! exchange APDUs, and notifies the host of card insertion and removal on the
+//! interrupt endpoint.
+
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+
+use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+/// This should be used as `device_class` when building the `UsbDevice`.
+pub const USB_CLASS_CCID: u8 = 0x0B;
+
+const CCID_SUBCLASS: u8 = 0x00;
+const CCID_PROTOCOL_BULK: u8 = 0x00;
+
+const CS_CCID: u8 = 0x21;
+
+const REQ_ABORT: u8 = 0x01;
+const REQ_GET_CLOCK_FREQUENCIES: u8 = 0x02;
+const REQ_GET_DATA_RATES: u8 = 0x03;
+
+/// Length of the header of every bulk message.
+pub const HEADER_LEN: usize = 10;
+
+/// Smallest message buffer allowed for short APDU level exchange: the header,
+/// a 5 byte command header and 256 bytes of data.
+pub const MIN_MESSAGE_LEN: usize = HEADER_LEN + 261;
+
+const PC_TO_RDR_SET_PARAMETERS: u8 = 0x61;
+const PC_TO_RDR_ICC_POWER_ON: u8 = 0x62;
+const PC_TO_RDR_ICC_POWER_OFF: u8 = 0x63;
+const PC_TO_RDR_GET_SLOT_STATUS: u8 = 0x65;
+const PC_TO_RDR_SECURE: u8 = 0x69;
+const PC_TO_RDR_T0_APDU: u8 = 0x6A;
+const PC_TO_RDR_ESCAPE: u8 = 0x6B;
+const PC_TO_RDR_GET_PARAMETERS: u8 = 0x6C;
+const PC_TO_RDR_RESET_PARAMETERS: u8 = 0x6D;
+const PC_TO_RDR_ICC_CLOCK: u8 = 0x6E;
+const PC_TO_RDR_XFR_BLOCK: u8 = 0x6F;
+const PC_TO_RDR_MECHANICAL: u8 = 0x71;
+const PC_TO_RDR_ABORT: u8 = 0x72;
+const PC_TO_RDR_SET_DATA_RATE_AND_CLOCK_FREQUENCY: u8 = 0x73;
+
+const RDR_TO_PC_DATA_BLOCK: u8 = 0x80;
+const RDR_TO_PC_SLOT_STATUS: u8 = 0x81;
+const RDR_TO_PC_PARAMETERS: u8 = 0x82;
+const RDR_TO_PC_ESCAPE: u8 = 0x83;
+const RDR_TO_PC_DATA_RATE_AND_CLOCK_FREQUENCY: u8 = 0x84;
+
+const RDR_TO_PC_NOTIFY_SLOT_CHANGE: u8 = 0x50;
+
+const COMMAND_STATUS_FAILED: u8 = 0x40;
+
+const ERROR_CMD_NOT_SUPPORTED: u8 = 0x00;
+/// Bad parameter at offset 1, `dwLength`.
+const ERROR_BAD_LENGTH: u8 = 0x01;
+const ERROR_BAD_SLOT: u8 = 0x05;
+const ERROR_CMD_ABORTED: u8 = 0xFF;
+const ERROR_ICC_MUTE: u8 = 0xFE;
+const ERROR_HW_ERROR: u8 = 0xFB;
+const ERROR_XFR_OVERRUN: u8 = 0xFC;
+
+/// Automatic parameter configuration based on ATR data, automatic activation,
+/// voltage selection, clock frequency, baud rate and PPS, short APDU level exchange.
+const FEATURES: u32 = 0x0002_00BE;
+
+/// Protocol T=1.
+const PROTOCOL_T1: u8 = 0x01;
+
+/// Default T=1 protocol data returned by `GetParameters`: Fi/Di = 372/1, LRC,
+/// no extra guard time, BWI = 4, CWI = 13, clock stop not allowed, IFSC = 254.
+const T1_PARAMETERS: [u8; 7] = [0x11, 0x10, 0x00, 0x4D, 0x00, 0xFE, 0x00];
+
+/// Configuration for the CCID class.
+pub struct Config {
+    /// Max packet size for the bulk endpoints.
+    pub max_packet_size: u16,
+
+    /// Default ICC clock frequency in kHz, reported in the class descriptor.
+    pub clock_khz: u32,
+
+    /// Default ICC data rate in bps, reported in the class descriptor.
+    pub data_rate: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_packet_size: 64,
+            clock_khz: 4000,
+            data_rate: 10752,
+        }
+    }
+}
+
+/// Error reported by a [`Card`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CardError {
+    /// The card did not answer.
+    Mute,
+    /// A hardware error occurred.
+    HardwareError,
+}
+
+impl CardError {
+    fn code(self) -> u8 {
+        match self {
+            CardError::Mute => ERROR_ICC_MUTE,
+            CardError::HardwareError => ERROR_HW_ERROR,
+        }
+    }
+}
+
+/// Smart card behind the reader slot.
+///
+/// The class calls into the card when the host powers the slot and exchanges
+/// APDUs with it.
+#[allow(async_fn_in_trait)]
+pub trait Card {
+    /// Activates the card and writes its Answer-To-Reset into `atr`.
+    ///
+    /// Returns the length of the ATR.
+    async fn power_on(&mut self, atr: &mut [u8]) -> Result<usize, CardError>;
+
+    /// Deactivates the card.
+    async fn power_off(&mut self) {}
+
+    /// Processes a command APDU and writes the response APDU, including the
+    /// trailing status word, into `response`.
+    ///
+    /// Returns the length of the response.
+    async fn transmit(&mut self, command: &[u8], response: &mut [u8]) -> Result<usize, CardError>;
+}
+
+/// State of the card in the slot.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum SlotState {
+    /// A card is present and powered.
+    Active = 0,
+    /// A card is present but not powered.
+    Inactive = 1,
+    /// No card is present.
+    Absent = 2,
+}
+
+/// Header of a CCID bulk message.
+///
+/// The header is the same in both directions; the meaning of the three last
+/// bytes depends on the message type.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MessageHeader {
+    /// Message type.
+    pub message_type: u8,
+    /// Length of the data following the header.
+    pub length: u32,
+    /// Slot index.
+    pub slot: u8,
+    /// Sequence number, echoed in the response.
+    pub seq: u8,
+    /// Message specific bytes.
+    pub specific: [u8; 3],
+}
+
+impl MessageHeader {
+    /// Parses a header from the start of `buf`.
+    ///
+    /// Returns `None` if `buf` is shorter than [`HEADER_LEN`].
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < HEADER_LEN {
+            return None;
+        }
+        Some(Self {
+            message_type: buf[0],
+            length: u32::from_le_bytes([buf[1], buf[2], buf[3], buf[4]]),
+            slot: buf[5],
+            seq: buf[6],
+            specific: [buf[7], buf[8], buf[9]],
+        })
+    }
+
+    /// Writes the header into the start of `buf`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buf` is shorter than [`HEADER_LEN`].
+    pub fn write(&self, buf: &mut [u8]) {
+        buf[0] = self.message_type;
+        buf[1..5].copy_from_slice(&self.length.to_le_bytes());
+        buf[5] = self.slot;
+        buf[6] = self.seq;
+        buf[7..10].copy_from_slice(&self.specific);
+    }
+}
+
+/// Internal state for the CCID class.
+pub struct State<'d> {
+    control: MaybeUninit<Control<'d>>,
+    shared: ControlShared,
+}
+
+impl<'d> Default for State<'d> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'d> State<'d> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+            shared: ControlShared::new(),
+        }
+    }
+}
+
+/// Shared data between Control, CcidClass and SlotControl
+struct ControlShared {
+    /// `bSeq << 8 | bSlot` of the last ABORT request, valid if `abort_pending`.
+    abort: AtomicU16,
+    abort_pending: AtomicBool,
+    present: AtomicBool,
+    present_changed: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl ControlShared {
+    const fn new() -> Self {
+        Self {
+            abort: AtomicU16::new(0),
+            abort_pending: AtomicBool::new(false),
+            present: AtomicBool::new(true),
+            present_changed: Signal::new(),
+        }
+    }
+}
+
+struct Control<'d> {
+    if_num: InterfaceNumber,
+    clock_khz: u32,
+    data_rate: u32,
+    shared: &'d ControlShared,
+}
+
+impl<'d> Handler for Control<'d> {
+    fn reset(&mut self) {
+        self.shared.abort_pending.store(false, Ordering::Relaxed);
+    }
+
+    fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.if_num.0 as u16)
+        {
+            return None;
+        }
+
+        match req.request {
+            REQ_ABORT => {
+                debug!("CCID abort slot {} seq {}", req.value & 0xFF, req.value >> 8);
+                self.shared.abort.store(req.value, Ordering::Relaxed);
+                self.shared.abort_pending.store(true, Ordering::Relaxed);
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.if_num.0 as u16)
+        {
+            return None;
+        }
+
+        let value = match req.request {
+            REQ_GET_CLOCK_FREQUENCIES => self.clock_khz,
+            REQ_GET_DATA_RATES => self.data_rate,
+            _ => return Some(InResponse::Rejected),
+        };
+        buf[..4].copy_from_slice(&value.to_le_bytes());
+        Some(InResponse::Accepted(&buf[..4]))
+    }
+}
+
+/// Handle to change the card presence while [`CcidClass::run`] is running.
+#[derive(Clone, Copy)]
+pub struct SlotControl<'d> {
+    shared: &'d ControlShared,
+}
+
+impl<'d> SlotControl<'d> {
+    /// Returns whether a card is present in the slot.
+    pub fn card_present(&self) -> bool {
+        self.shared.present.load(Ordering::Relaxed)
+    }
+
+    /// Sets whether a card is present in the slot.
+    ///
+    /// The host is notified of the change on the interrupt endpoint.
+    pub fn set_card_present(&self, present: bool) {
+        if self.shared.present.swap(present, Ordering::Relaxed) != present {
+            self.shared.present_changed.signal(());
+        }
+    }
+}
+
+/// Single-slot CCID reader.
+///
+/// `N` is the size of the message buffers, reported to the host as the maximum
+/// message length. It must be at least [`MIN_MESSAGE_LEN`].
+pub struct CcidClass<'d, D: Driver<'d>, const N: usize> {
+    read_ep: D::EndpointOut,
+    write_ep: D::EndpointIn,
+    int_ep: D::EndpointIn,
+    control: &'d ControlShared,
+    slot: SlotState,
+}
+
+impl<'d, D: Driver<'d>, const N: usize> CcidClass<'d, D, N> {
+    /// Creates a new `CcidClass`.
+    pub fn new<const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>(
+        builder: &mut Builder<'d, D, MAX_INTERFACES, MAX_HANDLERS>,
+        state: &'d mut State<'d>,
+        config: Config,
+    ) -> Self {
+        assert!(N >= MIN_MESSAGE_LEN);
+
+        let mut func = builder.function(USB_CLASS_CCID, CCID_SUBCLASS, CCID_PROTOCOL_BULK);
+        let mut iface = func.interface();
+        let if_num = iface.interface_number();
+        let mut alt = iface.alt_setting(USB_CLASS_CCID, CCID_SUBCLASS, CCID_PROTOCOL_BULK, None);
+
+        let clock = config.clock_khz.to_le_bytes();
+        let rate = config.data_rate.to_le_bytes();
+        let features = FEATURES.to_le_bytes();
+        let max_message_len = (N as u32).to_le_bytes();
+        alt.descriptor(
+            CS_CCID,
+            &[
+                0x10,
+                0x01, // bcdCCID 1.10
+                0x00, // bMaxSlotIndex
+                0x07, // bVoltageSupport: 5V, 3V, 1.8V
+                0x02,
+                0x00,
+                0x00,
+                0x00, // dwProtocols: T=1
+                clock[0],
+                clock[1],
+                clock[2],
+                clock[3], // dwDefaultClock
+                clock[0],
+                clock[1],
+                clock[2],
+                clock[3], // dwMaximumClock
+                0x00,     // bNumClockSupported
+                rate[0],
+                rate[1],
+                rate[2],
+                rate[3], // dwDataRate
+                rate[0],
+                rate[1],
+                rate[2],
+                rate[3], // dwMaxDataRate
+                0x00,    // bNumDataRatesSupported
+                0xFE,
+                0x00,
+                0x00,
+                0x00, // dwMaxIFSD
+                0x00,
+                0x00,
+                0x00,
+                0x00, // dwSynchProtocols
+                0x00,
+                0x00,
+                0x00,
+                0x00, // dwMechanical
+                features[0],
+                features[1],
+                features[2],
+                features[3], // dwFeatures
+                max_message_len[0],
+                max_message_len[1],
+                max_message_len[2],
+                max_message_len[3], // dwMaxCCIDMessageLength
+                0xFF,               // bClassGetResponse: echo
+                0xFF,               // bClassEnvelope: echo
+                0x00,
+                0x00, // wLcdLayout: no LCD
+                0x00, // bPINSupport: none
+                0x01, // bMaxCCIDBusySlots
+            ],
+        );
+
+        let read_ep = alt.endpoint_bulk_out(None, config.max_packet_size);
+        let write_ep = alt.endpoint_bulk_in(None, config.max_packet_size);
+        let int_ep = alt.endpoint_interrupt_in(None, 8, 255);
+        drop(func);
+
+        let control = state.control.write(Control {
+            if_num,
+            clock_khz: config.clock_khz,
+            data_rate: config.data_rate,
+            shared: &state.shared,
+        });
+        builder.handler(control);
+
+        Self {
+            read_ep,
+            write_ep,
+            int_ep,
+            control: &state.shared,
+            slot: SlotState::Inactive,
+        }
+    }
+
+    /// Returns a handle to change the card presence.
+    pub fn slot_control(&self) -> SlotControl<'d> {
+        SlotControl { shared: self.control }
+    }
+
+    /// Returns the current state of the slot.
+    pub fn slot_state(&self) -> SlotState {
+        self.slot
+    }
+
+    /// Waits for the USB host to enable this interface
+    pub async fn wait_connection(&mut self) {
+        self.read_ep.wait_enabled().await;
+    }
+
+    /// Answers host messages using `card`, and notifies the host of card presence changes.
+    ///
+    /// Returns when the interface is disabled.
+    pub async fn run<C: Card>(&mut self, card: &mut C) -> Result<(), EndpointError> {
+        let mut rx = [0; N];
+        let mut tx = [0; N];
+
+        // Report the initial presence once after enumeration.
+        self.control.present_changed.signal(());
+
+        loop {
+            let (n, received) =
+                match select(receive(&mut self.read_ep, &mut rx), self.control.present_changed.wait()).await {
+                    Either::First(n) => n?,
+                    Either::Second(()) => {
+                        self.notify_slot_change(card).await?;
+                        continue;
+                    }
+                };
+
+            let Some(header) = MessageHeader::parse(&rx[..n]) else {
+                warn!("CCID: short message ({} bytes)", n);
+                continue;
+            };
+            // A truncated command must never reach the card, it is answered with an error instead.
+            let data = data_len(&header, received, N).map(|len| &rx[HEADER_LEN..HEADER_LEN + len]);
+
+            let len = self.process(card, &header, data, &mut tx).await;
+            self.write_ep.write_transfer(&tx[..len], true).await?;
+        }
+    }
+
+    async fn notify_slot_change<C: Card>(&mut self, card: &mut C) -> Result<(), EndpointError> {
+        let present = self.control.present.load(Ordering::Relaxed);
+        match (present, self.slot) {
+            (true, SlotState::Absent) => self.slot = SlotState::Inactive,
+            (false, SlotState::Active) => {
+                card.power_off().await;
+                self.slot = SlotState::Absent;
+            }
+            (false, _) => self.slot = SlotState::Absent,
+            _ => {}
+        }
+        self.int_ep.write(&slot_change(present)).await
+    }
+
+    /// Handles one command message and writes the response into `tx`, returning its length.
+    ///
+    /// `data` is the error to answer with if the message was not received whole.
+    async fn process<C: Card>(
+        &mut self,
+        card: &mut C,
+        cmd: &MessageHeader,
+        data: Result<&[u8], u8>,
+        tx: &mut [u8; N],
+    ) -> usize {
+        let result = if cmd.slot != 0 {
+            Err(ERROR_BAD_SLOT)
+        } else if self.is_aborted(cmd) {
+            Err(ERROR_CMD_ABORTED)
+        } else {
+            match data {
+                Ok(data) => self.execute(card, cmd, data, &mut tx[HEADER_LEN..]).await,
+                Err(error) => Err(error),
+            }
+        };
+
+        let slot = if cmd.slot != 0 { SlotState::Absent } else { self.slot };
+        write_response(cmd, slot, result, tx)
+    }
+
+    /// Returns whether `cmd` was aborted by an ABORT request on the control pipe.
+    ///
+    /// The bulk `Abort` message that completes the sequence clears the pending abort.
+    fn is_aborted(&self, cmd: &MessageHeader) -> bool {
+        if !self.control.abort_pending.load(Ordering::Relaxed) {
+            return false;
+        }
+        if cmd.message_type == PC_TO_RDR_ABORT {
+            self.control.abort_pending.store(false, Ordering::Relaxed);
+            return false;
+        }
+        self.control.abort.load(Ordering::Relaxed) == (cmd.seq as u16) << 8 | cmd.slot as u16
+    }
+
+    /// Executes `cmd`, returning the response data length and the last header byte.
+    async fn execute<C: Card>(
+        &mut self,
+        card: &mut C,
+        cmd: &MessageHeader,
+        data: &[u8],
+        out: &mut [u8],
+    ) -> Result<(usize, u8), u8> {
+        match cmd.message_type {
+            PC_TO_RDR_ICC_POWER_ON => {
+                if self.slot == SlotState::Absent {
+                    return Err(ERROR_ICC_MUTE);
+                }
+                let len = card.power_on(out).await.map_err(CardError::code)?;
+                self.slot = SlotState::Active;
+                Ok((len, 0))
+            }
+            PC_TO_RDR_ICC_POWER_OFF => {
+                if self.slot == SlotState::Active {
+                    card.power_off().await;
+                    self.slot = SlotState::Inactive;
+                }
+                // bClockStatus: clock stopped.
+                Ok((0, 0x01))
+            }
+            PC_TO_RDR_GET_SLOT_STATUS | PC_TO_RDR_ABORT => {
+                let clock = if self.slot == SlotState::Active { 0x00 } else { 0x01 };
+                Ok((0, clock))
+            }
+            PC_TO_RDR_XFR_BLOCK => {
+                if self.slot != SlotState::Active {
+                    return Err(ERROR_ICC_MUTE);
+                }
+                let len = card.transmit(data, out).await.map_err(CardError::code)?;
+                // bChainParameter: the response is complete.
+                Ok((len, 0))
+            }
+            PC_TO_RDR_GET_PARAMETERS | PC_TO_RDR_RESET_PARAMETERS | PC_TO_RDR_SET_PARAMETERS => {
+                if self.slot == SlotState::Absent {
+                    return Err(ERROR_ICC_MUTE);
+                }
+                out[..T1_PARAMETERS.len()].copy_from_slice(&T1_PARAMETERS);
+                Ok((T1_PARAMETERS.len(), PROTOCOL_T1))
+            }
+            PC_TO_RDR_SECURE
+            | PC_TO_RDR_T0_APDU
+            | PC_TO_RDR_ESCAPE
+            | PC_TO_RDR_ICC_CLOCK
+            | PC_TO_RDR_MECHANICAL
+            | PC_TO_RDR_SET_DATA_RATE_AND_CLOCK_FREQUENCY => Err(ERROR_CMD_NOT_SUPPORTED),
+            _ => {
+                warn!("CCID: unknown message type {:02x}", cmd.message_type);
+                Err(ERROR_CMD_NOT_SUPPORTED)
+            }
+        }
+    }
+}
+
+/// Reads one bulk message into `buf`, discarding whatever does not fit.
+///
+/// Returns the number of bytes in `buf` and the length of the whole message.
+async fn receive<E: EndpointOut>(ep: &mut E, buf: &mut [u8]) -> Result<(usize, usize), EndpointError> {
+    let n = ep.read_transfer(buf).await?;
+    let mut received = n;
+    if n == buf.len() {
+        let mut discard = [0; 64];
+        let expected = MessageHeader::parse(buf).map_or(0, |h| h.length as usize + HEADER_LEN);
+        while received < expected {
+            let i = ep.read(&mut discard).await?;
+            received += i;
+            if i < ep.info().max_packet_size as usize {
+                break;
+            }
+        }
+    }
+    Ok((n, received))
+}
+
+/// Returns the data length of a message of `received` bytes, or the error to answer it with
+/// if it did not fit in a buffer of `capacity` bytes or does not match its `dwLength`.
+fn data_len(header: &MessageHeader, received: usize, capacity: usize) -> Result<usize, u8> {
+    if received > capacity {
+        warn!("CCID: message of {} bytes overruns the buffer", received);
+        Err(ERROR_XFR_OVERRUN)
+    } else if received - HEADER_LEN != header.length as usize {
+        warn!("CCID: message of {} bytes with dwLength {}", received, header.length);
+        Err(ERROR_BAD_LENGTH)
+    } else {
+        Ok(header.length as usize)
+    }
+}
+
+/// Writes the header of the response to `cmd` into `tx`, returning the length of the response.
+///
+/// The response data, if any, must already be in `tx` after the header.
+fn write_response(cmd: &MessageHeader, slot: SlotState, result: Result<(usize, u8), u8>, tx: &mut [u8]) -> usize {
+    let response_type = match cmd.message_type {
+        PC_TO_RDR_ICC_POWER_ON | PC_TO_RDR_XFR_BLOCK | PC_TO_RDR_SECURE => RDR_TO_PC_DATA_BLOCK,
+        PC_TO_RDR_GET_PARAMETERS | PC_TO_RDR_RESET_PARAMETERS | PC_TO_RDR_SET_PARAMETERS => RDR_TO_PC_PARAMETERS,
+        PC_TO_RDR_ESCAPE => RDR_TO_PC_ESCAPE,
+        PC_TO_RDR_SET_DATA_RATE_AND_CLOCK_FREQUENCY => RDR_TO_PC_DATA_RATE_AND_CLOCK_FREQUENCY,
+        _ => RDR_TO_PC_SLOT_STATUS,
+    };
+    let (status, error, len, specific) = match result {
+        Ok((len, specific)) => (slot as u8, 0, len, specific),
+        Err(error) => (COMMAND_STATUS_FAILED | slot as u8, error, 0, 0),
+    };
+
+    MessageHeader {
+        message_type: response_type,
+        length: len as u32,
+        slot: cmd.slot,
+        seq: cmd.seq,
+        specific: [status, error, specific],
+    }
+    .write(tx);
+    HEADER_LEN + len
+}
+
+/// `RDR_to_PC_NotifySlotChange` message for a slot whose card became `present` or absent.
+const fn slot_change(present: bool) -> [u8; 2] {
+    // bmSlotICCState: bit 0 = present, bit 1 = changed.
+    [RDR_TO_PC_NOTIFY_SLOT_CHANGE, 0b10 | present as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use embassy_usb_driver::{EndpointAddress, EndpointInfo, EndpointType};
+
+    use super::*;
+
+    /// Bulk OUT endpoint returning the given packets.
+    struct Packets<'a> {
+        info: EndpointInfo,
+        packets: core::slice::Iter<'a, &'a [u8]>,
+    }
+
+    impl<'a> Packets<'a> {
+        fn new(packets: &'a [&'a [u8]]) -> Self {
+            Self {
+                info: EndpointInfo {
+                    addr: EndpointAddress::from_parts(1, embassy_usb_driver::Direction::Out),
+                    ep_type: EndpointType::Bulk,
+                    max_packet_size: 64,
+                    interval_ms: 0,
+                },
+                packets: packets.iter(),
+            }
+        }
+    }
+
+    impl Endpoint for Packets<'_> {
+        fn info(&self) -> &EndpointInfo {
+            &self.info
+        }
+
+        async fn wait_enabled(&mut self) {}
+    }
+
+    impl EndpointOut for Packets<'_> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+            let packet = self.packets.next().ok_or(EndpointError::Disabled)?;
+            buf.get_mut(..packet.len())
+                .ok_or(EndpointError::BufferOverflow)?
+                .copy_from_slice(packet);
+            Ok(packet.len())
+        }
+    }
+
+    fn message(message_type: u8, seq: u8, data_len: usize, buf: &mut [u8]) -> &[u8] {
+        MessageHeader {
+            message_type,
+            length: data_len as u32,
+            slot: 0,
+            seq,
+            specific: [0; 3],
+        }
+        .write(buf);
+        for (i, b) in buf[HEADER_LEN..HEADER_LEN + data_len].iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        &buf[..HEADER_LEN + data_len]
+    }
+
+    #[test]
+    fn header() {
+        let bytes = [0x6F, 0x05, 0x00, 0x00, 0x00, 0x00, 0x2A, 0x01, 0x02, 0x03];
+        let header = MessageHeader::parse(&bytes).unwrap();
+        assert_eq!(
+            header,
+            MessageHeader {
+                message_type: PC_TO_RDR_XFR_BLOCK,
+                length: 5,
+                slot: 0,
+                seq: 0x2A,
+                specific: [1, 2, 3],
+            }
+        );
+        let mut buf = [0; HEADER_LEN];
+        header.write(&mut buf);
+        assert_eq!(buf, bytes);
+        assert_eq!(MessageHeader::parse(&bytes[..HEADER_LEN - 1]), None);
+    }
+
+    #[test]
+    fn receive_across_packets() {
+        let mut a = [0; 128];
+        let a = message(PC_TO_RDR_XFR_BLOCK, 1, 100, &mut a);
+        // A message filling whole packets ends with a zero length packet.
+        let mut b = [0; 128];
+        let b = message(PC_TO_RDR_XFR_BLOCK, 2, 118, &mut b);
+        let packets = [&a[..64], &a[64..], &b[..64], &b[64..], &[]];
+        let mut ep = Packets::new(&packets);
+        let mut buf = [0; MIN_MESSAGE_LEN];
+
+        let (n, received) = block_on(receive(&mut ep, &mut buf)).unwrap();
+        assert_eq!((n, received), (110, 110));
+        assert_eq!(&buf[..n], a);
+        let header = MessageHeader::parse(&buf[..n]).unwrap();
+        assert_eq!(data_len(&header, received, buf.len()), Ok(100));
+
+        let (n, received) = block_on(receive(&mut ep, &mut buf)).unwrap();
+        assert_eq!((n, received), (128, 128));
+        assert_eq!(&buf[..n], b);
+    }
+
+    #[test]
+    fn receive_overrun() {
+        let mut a = [0; 200];
+        let a = message(PC_TO_RDR_XFR_BLOCK, 1, 190, &mut a);
+        let mut b = [0; 16];
+        let b = message(PC_TO_RDR_GET_SLOT_STATUS, 2, 0, &mut b);
+        let packets = [&a[..64], &a[64..128], &a[128..192], &a[192..], b];
+        let mut ep = Packets::new(&packets);
+        let mut buf = [0; 128];
+
+        // The rest of the message is discarded, and it is not executed.
+        let (n, received) = block_on(receive(&mut ep, &mut buf)).unwrap();
+        assert_eq!((n, received), (128, 200));
+        let header = MessageHeader::parse(&buf[..n]).unwrap();
+        assert_eq!(data_len(&header, received, buf.len()), Err(ERROR_XFR_OVERRUN));
+
+        // The next message is read whole.
+        let (n, received) = block_on(receive(&mut ep, &mut buf)).unwrap();
+        assert_eq!(&buf[..n], b);
+        let header = MessageHeader::parse(&buf[..n]).unwrap();
+        assert_eq!(data_len(&header, received, buf.len()), Ok(0));
+    }
+
+    #[test]
+    fn bad_length() {
+        let mut buf = [0; 32];
+        message(PC_TO_RDR_XFR_BLOCK, 1, 20, &mut buf);
+        let header = MessageHeader::parse(&buf).unwrap();
+        assert_eq!(data_len(&header, HEADER_LEN + 15, buf.len()), Err(ERROR_BAD_LENGTH));
+        assert_eq!(data_len(&header, HEADER_LEN + 21, buf.len()), Err(ERROR_BAD_LENGTH));
+        assert_eq!(data_len(&header, HEADER_LEN + 20, buf.len()), Ok(20));
+    }
+
+    #[test]
+    fn slot_status() {
+        let mut cmd = MessageHeader::parse(&[PC_TO_RDR_GET_SLOT_STATUS, 0, 0, 0, 0, 0, 7, 0, 0, 0]).unwrap();
+        let mut tx = [0; HEADER_LEN];
+
+        // Card present but not powered, clock stopped.
+        let len = write_response(&cmd, SlotState::Inactive, Ok((0, 0x01)), &mut tx);
+        assert_eq!(&tx[..len], [0x81, 0, 0, 0, 0, 0, 7, 0x01, 0x00, 0x01]);
+
+        // Failed transfer with an active card.
+        cmd.message_type = PC_TO_RDR_XFR_BLOCK;
+        let len = write_response(&cmd, SlotState::Active, Err(ERROR_XFR_OVERRUN), &mut tx);
+        assert_eq!(&tx[..len], [0x80, 0, 0, 0, 0, 0, 7, 0x40, 0xFC, 0x00]);
+
+        // Command for a slot that does not exist.
+        cmd.slot = 1;
+        let len = write_response(&cmd, SlotState::Absent, Err(ERROR_BAD_SLOT), &mut tx);
+        assert_eq!(&tx[..len], [0x80, 0, 0, 0, 0, 1, 7, 0x42, 0x05, 0x00]);
+    }
+
+    #[test]
+    fn notify_slot_change() {
+        assert_eq!(slot_change(true), [0x50, 0x03]);
+        assert_eq!(slot_change(false), [0x50, 0x02]);
+    }
+}
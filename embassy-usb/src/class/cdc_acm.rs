@@ -240,7 +240,11 @@ impl<'d> Handler for Control<'d> {
 impl<'d, D: Driver<'d>> CdcAcmClass<'d, D> {
     /// Creates a new CdcAcmClass with the provided UsbBus and `max_packet_size` in bytes. For
     /// full-speed devices, `max_packet_size` has to be one of 8, 16, 32 or 64.
-    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, max_packet_size: u16) -> Self {
+    pub fn new<const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>(
+        builder: &mut Builder<'d, D, MAX_INTERFACES, MAX_HANDLERS>,
+        state: &'d mut State<'d>,
+        max_packet_size: u16,
+    ) -> Self {
         assert!(builder.control_buf_len() >= 7);
 
         let mut func = builder.function(USB_CLASS_CDC, CDC_SUBCLASS_ACM, CDC_PROTOCOL_NONE);
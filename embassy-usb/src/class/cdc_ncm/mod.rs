@@ -255,8 +255,8 @@ pub struct CdcNcmClass<'d, D: Driver<'d>> {
 
 impl<'d, D: Driver<'d>> CdcNcmClass<'d, D> {
     /// Create a new CDC NCM class.
-    pub fn new(
-        builder: &mut Builder<'d, D>,
+    pub fn new<const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>(
+        builder: &mut Builder<'d, D, MAX_INTERFACES, MAX_HANDLERS>,
         state: &'d mut State<'d>,
         mac_address: [u8; 6],
         max_packet_size: u16,
@@ -54,7 +54,12 @@ impl<'d, D: Driver<'d>> CmsisDapV2Class<'d, D> {
     ///
     /// The `trace` parameter enables the trace output endpoint. This is optional and can be
     /// disabled if the probe does not support trace output.
-    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State, max_packet_size: u16, trace: bool) -> Self {
+    pub fn new<const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>(
+        builder: &mut Builder<'d, D, MAX_INTERFACES, MAX_HANDLERS>,
+        state: &'d mut State,
+        max_packet_size: u16,
+        trace: bool,
+    ) -> Self {
         // DAP - Custom Class 0
         let iface_string = builder.string();
         let mut function = builder.function(0xFF, 0, 0);
@@ -120,10 +120,10 @@ impl<H: Handler> crate::Handler for DfuState<H> {
 /// it should expose a DFU device, and a software reset will be issued.
 ///
 /// To apply USB DFU updates, the bootloader must be capable of recognizing the DFU magic and exposing a device to handle the full DFU transaction with the host.
-pub fn usb_dfu<'d, D: Driver<'d>, H: Handler>(
-    builder: &mut Builder<'d, D>,
+pub fn usb_dfu<'d, D: Driver<'d>, H: Handler, const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>(
+    builder: &mut Builder<'d, D, MAX_INTERFACES, MAX_HANDLERS>,
     state: &'d mut DfuState<H>,
-    func_modifier: impl Fn(&mut FunctionBuilder<'_, 'd, D>),
+    func_modifier: impl Fn(&mut FunctionBuilder<'_, 'd, D, MAX_INTERFACES, MAX_HANDLERS>),
 ) {
     let mut func = builder.function(0x00, 0x00, 0x00);
 
@@ -1,12 +1,15 @@
 //! USB DFU constants and types.
 
-pub(crate) const USB_CLASS_APPN_SPEC: u8 = 0xFE;
-pub(crate) const APPN_SPEC_SUBCLASS_DFU: u8 = 0x01;
-#[allow(unused)]
-pub(crate) const DFU_PROTOCOL_DFU: u8 = 0x02;
-#[allow(unused)]
-pub(crate) const DFU_PROTOCOL_RT: u8 = 0x01;
-pub(crate) const DESC_DFU_FUNCTIONAL: u8 = 0x21;
+/// Application-specific interface class code.
+pub const USB_CLASS_APPN_SPEC: u8 = 0xFE;
+/// DFU interface subclass code.
+pub const APPN_SPEC_SUBCLASS_DFU: u8 = 0x01;
+/// Interface protocol of a device in DFU mode.
+pub const DFU_PROTOCOL_DFU: u8 = 0x02;
+/// Interface protocol of a device in runtime mode.
+pub const DFU_PROTOCOL_RT: u8 = 0x01;
+/// DFU functional descriptor type.
+pub const DESC_DFU_FUNCTIONAL: u8 = 0x21;
 
 #[cfg(feature = "defmt")]
 defmt::bitflags! {
@@ -26,6 +29,7 @@ defmt::bitflags! {
 #[cfg(not(feature = "defmt"))]
 bitflags::bitflags! {
     /// Attributes supported by the DFU controller.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
     pub struct DfuAttributes: u8 {
         /// Generate WillDetach sequence on bus.
         const WILL_DETACH = 0b0000_1000;
@@ -38,25 +42,59 @@ bitflags::bitflags! {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+/// DFU device states, as reported by DFU_GETSTATUS and DFU_GETSTATE.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
-#[allow(unused)]
-pub(crate) enum State {
+pub enum State {
+    /// Device is running its normal application.
     AppIdle = 0,
+    /// Device has received DFU_DETACH and is waiting for a USB reset.
     AppDetach = 1,
+    /// Device is in DFU mode, waiting for requests.
     DfuIdle = 2,
+    /// Device has received a block and is waiting for DFU_GETSTATUS.
     DlSync = 3,
+    /// Device is programming a block.
     DlBusy = 4,
+    /// Device is waiting for the next DFU_DNLOAD block.
     Download = 5,
+    /// Device has received the final block and is waiting for DFU_GETSTATUS.
     ManifestSync = 6,
+    /// Device is in the manifestation phase.
     Manifest = 7,
+    /// Device has programmed its memories and is waiting for a USB reset.
     ManifestWaitReset = 8,
+    /// Device is processing a DFU_UPLOAD.
     UploadIdle = 9,
+    /// An error has occurred; awaiting DFU_CLRSTATUS.
     Error = 10,
 }
 
+impl TryFrom<u8> for State {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, ()> {
+        Ok(match value {
+            0 => State::AppIdle,
+            1 => State::AppDetach,
+            2 => State::DfuIdle,
+            3 => State::DlSync,
+            4 => State::DlBusy,
+            5 => State::Download,
+            6 => State::ManifestSync,
+            7 => State::Manifest,
+            8 => State::ManifestWaitReset,
+            9 => State::UploadIdle,
+            10 => State::Error,
+            _ => return Err(()),
+        })
+    }
+}
+
 /// DFU status codes indicating the result of the most recent request.
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 #[allow(unused)]
 pub enum Status {
@@ -94,15 +132,50 @@ pub enum Status {
     ErrStalledPkt = 0x0F,
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+impl TryFrom<u8> for Status {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, ()> {
+        Ok(match value {
+            0x00 => Status::Ok,
+            0x01 => Status::ErrTarget,
+            0x02 => Status::ErrFile,
+            0x03 => Status::ErrWrite,
+            0x04 => Status::ErrErase,
+            0x05 => Status::ErrCheckErased,
+            0x06 => Status::ErrProg,
+            0x07 => Status::ErrVerify,
+            0x08 => Status::ErrAddress,
+            0x09 => Status::ErrNotDone,
+            0x0A => Status::ErrFirmware,
+            0x0B => Status::ErrVendor,
+            0x0C => Status::ErrUsbr,
+            0x0D => Status::ErrPor,
+            0x0E => Status::ErrUnknown,
+            0x0F => Status::ErrStalledPkt,
+            _ => return Err(()),
+        })
+    }
+}
+
+/// DFU class-specific requests.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
-pub(crate) enum Request {
+pub enum Request {
+    /// Switch from runtime to DFU mode.
     Detach = 0,
+    /// Download a block of firmware to the device.
     Dnload = 1,
+    /// Upload a block of firmware from the device.
     Upload = 2,
+    /// Read the device status.
     GetStatus = 3,
+    /// Clear an error status.
     ClrStatus = 4,
+    /// Read the device state.
     GetState = 5,
+    /// Abort the current operation and return to dfuIDLE.
     Abort = 6,
 }
 
@@ -122,3 +195,35 @@ impl TryFrom<u8> for Request {
         }
     }
 }
+
+/// `bcdDFUVersion` of a device supporting the DfuSe extensions of STMicroelectronics.
+pub const DFUSE_VERSION: u16 = 0x011A;
+
+/// DfuSe commands, sent in DFU_DNLOAD block 0 and listed by DFU_UPLOAD block 0.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum DfuSeCommand {
+    /// List the supported commands.
+    GetCommands = 0x00,
+    /// Set the address of the following DFU_DNLOAD and DFU_UPLOAD blocks.
+    SetAddressPointer = 0x21,
+    /// Erase the page at an address, or the whole memory without an address.
+    Erase = 0x41,
+    /// Remove the read protection of the memory.
+    ReadUnprotect = 0x92,
+}
+
+impl TryFrom<u8> for DfuSeCommand {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(DfuSeCommand::GetCommands),
+            0x21 => Ok(DfuSeCommand::SetAddressPointer),
+            0x41 => Ok(DfuSeCommand::Erase),
+            0x92 => Ok(DfuSeCommand::ReadUnprotect),
+            _ => Err(()),
+        }
+    }
+}
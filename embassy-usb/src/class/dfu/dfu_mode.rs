@@ -1,12 +1,16 @@
 use embassy_usb_driver::Driver;
 
 use super::consts::{
-    APPN_SPEC_SUBCLASS_DFU, DESC_DFU_FUNCTIONAL, DFU_PROTOCOL_DFU, DfuAttributes, Request, State, Status,
-    USB_CLASS_APPN_SPEC,
+    APPN_SPEC_SUBCLASS_DFU, DESC_DFU_FUNCTIONAL, DFU_PROTOCOL_DFU, DFUSE_VERSION, DfuAttributes, DfuSeCommand, Request,
+    State, Status, USB_CLASS_APPN_SPEC,
 };
 use crate::control::{InResponse, OutResponse, Recipient, Request as ControlRequest, RequestType};
+use crate::types::StringIndex;
 use crate::{Builder, FunctionBuilder};
 
+/// `bcdDFUVersion` of a plain DFU 1.1 device.
+const DFU_VERSION: u16 = 0x0110;
+
 /// Handler trait for DFU bootloader mode.
 ///
 /// Implement this trait to handle firmware download operations.
@@ -37,6 +41,46 @@ pub trait Handler {
     /// This is typically where you would perform a system reset to boot
     /// the new firmware after a successful download.
     fn system_reset(&mut self);
+
+    /// Called to read a chunk of memory for a DFU_UPLOAD, at `offset` from the start of the
+    /// upload, or from the base address with DfuSe.
+    ///
+    /// Returns the number of bytes read. The upload ends with the first chunk shorter than
+    /// `buf`. Only called if [`DfuAttributes::CAN_UPLOAD`] is set.
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<usize, Status> {
+        let _ = (offset, buf);
+        Err(Status::ErrStalledPkt)
+    }
+
+    /// Called with DfuSe to write a chunk of firmware data at `offset` from the base address,
+    /// instead of [`Handler::write`].
+    fn write_at(&mut self, offset: usize, data: &[u8]) -> Result<(), Status> {
+        let _ = (offset, data);
+        Err(Status::ErrStalledPkt)
+    }
+
+    /// Called with DfuSe to erase the page at `offset` from the base address, or the whole
+    /// memory if `offset` is `None`.
+    fn erase(&mut self, offset: Option<usize>) -> Result<(), Status> {
+        let _ = offset;
+        Err(Status::ErrStalledPkt)
+    }
+}
+
+/// Configuration of the DfuSe extensions of STMicroelectronics, used by `dfu-util -s` and
+/// STM32CubeProgrammer.
+///
+/// With DfuSe, the host addresses the memory through the set address pointer command, erases
+/// it with the erase command, and reads the memory layout from the interface string.
+#[derive(Copy, Clone, Debug)]
+pub struct DfuSe {
+    /// Memory layout string, such as `"@Internal Flash  /0x08008000/48*002Kg"`: the memory
+    /// name, its start address and its groups of pages, as `count*size` followed by a size
+    /// unit (` `, `K` or `M`) and the readable (`a`), erasable (`b`) and writable (`c`) bits
+    /// as a letter from `a` to `g`.
+    pub layout: &'static str,
+    /// Address of offset zero of the [`Handler`] memory. Addresses below it are rejected.
+    pub base_address: u32,
 }
 
 /// Internal state for USB DFU
@@ -46,6 +90,11 @@ pub struct DfuState<H: Handler> {
     state: State,
     status: Status,
     next_block_num: usize,
+    upload_offset: usize,
+    dfuse: Option<DfuSe>,
+    address: u32,
+    transfer_size: usize,
+    layout_string: Option<StringIndex>,
 }
 
 impl<H: Handler> DfuState<H> {
@@ -57,6 +106,20 @@ impl<H: Handler> DfuState<H> {
             state: State::DfuIdle,
             status: Status::Ok,
             next_block_num: 0,
+            upload_offset: 0,
+            dfuse: None,
+            address: 0,
+            transfer_size: 0,
+            layout_string: None,
+        }
+    }
+
+    /// Create a new DFU instance handling the DfuSe extensions.
+    pub fn new_dfuse(handler: H, attrs: DfuAttributes, dfuse: DfuSe) -> Self {
+        Self {
+            dfuse: Some(dfuse),
+            address: dfuse.base_address,
+            ..Self::new(handler, attrs)
         }
     }
 
@@ -75,6 +138,127 @@ impl<H: Handler> DfuState<H> {
         self.state = State::DfuIdle;
         self.status = Status::Ok;
     }
+
+    fn fail(&mut self, status: Status) {
+        self.state = State::Error;
+        self.status = status;
+    }
+
+    /// Handle a DFU_DNLOAD with DfuSe, where block 0 holds a command and blocks from 2 are
+    /// written from the address pointer.
+    fn dfuse_dnload(&mut self, base_address: u32, req: ControlRequest, data: &[u8]) -> OutResponse {
+        if !matches!(self.state, State::DfuIdle | State::Download) {
+            error!("Unexpected DNLOAD while chip is waiting for a GETSTATUS");
+            self.fail(Status::ErrUnknown);
+            return OutResponse::Rejected;
+        }
+
+        if self.state == State::DfuIdle
+            && let Err(e) = self.handler.start()
+        {
+            self.fail(e);
+            return OutResponse::Rejected;
+        }
+
+        let result = if req.length == 0 {
+            self.handler.finish().map(|_| State::ManifestSync)
+        } else if req.value == 0 {
+            self.dfuse_command(base_address, data).map(|_| State::DlSync)
+        } else {
+            block_address(self.address, req.value, self.transfer_size)
+                .and_then(|address| offset(base_address, address))
+                .and_then(|offset| self.handler.write_at(offset, data))
+                .map(|_| State::DlSync)
+        };
+        match result {
+            Ok(state) => {
+                self.status = Status::Ok;
+                self.state = state;
+            }
+            Err(e) => self.fail(e),
+        }
+        OutResponse::Accepted
+    }
+
+    fn dfuse_command(&mut self, base_address: u32, data: &[u8]) -> Result<(), Status> {
+        let (&command, args) = data.split_first().ok_or(Status::ErrStalledPkt)?;
+        let address = args.try_into().map(u32::from_le_bytes);
+        match (DfuSeCommand::try_from(command), address) {
+            (Ok(DfuSeCommand::SetAddressPointer), Ok(address)) => {
+                debug!("Address pointer set to {:x}", address);
+                self.address = address;
+                Ok(())
+            }
+            (Ok(DfuSeCommand::Erase), Ok(address)) => self.handler.erase(Some(offset(base_address, address)?)),
+            (Ok(DfuSeCommand::Erase), Err(_)) if args.is_empty() => self.handler.erase(None),
+            _ => {
+                error!("Unsupported DfuSe command {:x}", command);
+                Err(Status::ErrStalledPkt)
+            }
+        }
+    }
+
+    /// Handle a DFU_UPLOAD. With DfuSe, block 0 lists the supported commands and blocks from 2
+    /// are read from the address pointer.
+    fn upload<'a>(&mut self, req: ControlRequest, buf: &'a mut [u8]) -> InResponse<'a> {
+        if !matches!(self.state, State::DfuIdle | State::UploadIdle) {
+            error!("Unexpected UPLOAD in state {:?}", self.state);
+            self.fail(Status::ErrUnknown);
+            return InResponse::Rejected;
+        }
+
+        let len = buf.len().min(req.length as usize);
+        let offset = match self.dfuse {
+            Some(_) if req.value == 0 => {
+                let commands = [
+                    DfuSeCommand::GetCommands,
+                    DfuSeCommand::SetAddressPointer,
+                    DfuSeCommand::Erase,
+                ];
+                let len = len.min(commands.len());
+                for (byte, command) in buf[..len].iter_mut().zip(commands) {
+                    *byte = command as u8;
+                }
+                self.state = State::DfuIdle;
+                return InResponse::Accepted(&buf[..len]);
+            }
+            Some(dfuse) => block_address(self.address, req.value, self.transfer_size)
+                .and_then(|address| offset(dfuse.base_address, address)),
+            None if self.state == State::DfuIdle => Ok(0),
+            None => Ok(self.upload_offset),
+        };
+
+        match offset.and_then(|offset| Ok((offset, self.handler.read(offset, &mut buf[..len])?))) {
+            Ok((offset, n)) => {
+                self.upload_offset = offset + n;
+                self.state = if n < len { State::DfuIdle } else { State::UploadIdle };
+                InResponse::Accepted(&buf[..n])
+            }
+            Err(e) => {
+                self.fail(e);
+                InResponse::Rejected
+            }
+        }
+    }
+}
+
+/// Address of DfuSe block `block`, which must be 2 or more. Blocks are `transfer_size` bytes
+/// apart, whatever the length of the request, so the last block of a transfer may be short.
+fn block_address(address: u32, block: u16, transfer_size: usize) -> Result<u32, Status> {
+    let index = block.checked_sub(2).ok_or(Status::ErrStalledPkt)?;
+    u32::try_from(transfer_size)
+        .ok()
+        .and_then(|size| (index as u32).checked_mul(size))
+        .and_then(|delta| address.checked_add(delta))
+        .ok_or(Status::ErrAddress)
+}
+
+/// Offset of `address` from `base_address`.
+fn offset(base_address: u32, address: u32) -> Result<usize, Status> {
+    address
+        .checked_sub(base_address)
+        .map(|offset| offset as usize)
+        .ok_or(Status::ErrAddress)
 }
 
 impl<H: Handler> crate::Handler for DfuState<H> {
@@ -98,6 +282,10 @@ impl<H: Handler> crate::Handler for DfuState<H> {
                 Some(OutResponse::Accepted)
             }
             Ok(Request::Dnload) if self.attrs.contains(DfuAttributes::CAN_DOWNLOAD) => {
+                if let Some(dfuse) = self.dfuse {
+                    return Some(self.dfuse_dnload(dfuse.base_address, req, data));
+                }
+
                 if req.value as usize != self.next_block_num {
                     error!("expected next block num {}, got {}", self.next_block_num, req.value);
                     self.state = State::Error;
@@ -171,7 +359,9 @@ impl<H: Handler> crate::Handler for DfuState<H> {
         match Request::try_from(req.request) {
             Ok(Request::GetStatus) => {
                 match self.state {
-                    State::DlSync => self.state = State::Download,
+                    // DfuSe hosts expect the device to report being busy once after each block.
+                    State::DlSync if self.dfuse.is_some() => self.state = State::DlBusy,
+                    State::DlSync | State::DlBusy => self.state = State::Download,
                     State::ManifestSync if self.attrs.contains(DfuAttributes::MANIFESTATION_TOLERANT) => {
                         self.state = State::DfuIdle
                     }
@@ -197,30 +387,35 @@ impl<H: Handler> crate::Handler for DfuState<H> {
                 buf[0] = self.state as u8;
                 Some(InResponse::Accepted(&buf[0..1]))
             }
-            Ok(Request::Upload) if self.attrs.contains(DfuAttributes::CAN_UPLOAD) => {
-                //TODO: FirmwareUpdater does not provide a way of reading the active partition, can't upload.
-                Some(InResponse::Rejected)
-            }
+            Ok(Request::Upload) if self.attrs.contains(DfuAttributes::CAN_UPLOAD) => Some(self.upload(req, buf)),
             _ => {
                 debug!("Unknown IN request {:?}", req);
                 None
             }
         }
     }
+
+    fn get_string(&mut self, index: StringIndex, _lang_id: u16) -> Option<&str> {
+        let dfuse = self.dfuse.as_ref()?;
+        (self.layout_string == Some(index)).then_some(dfuse.layout)
+    }
 }
 
 /// An implementation of the USB DFU 1.1 protocol
 ///
 /// This function will add a DFU interface descriptor to the provided Builder, and register the provided Control as a handler for the USB device
-/// The handler is responsive to DFU GetState, GetStatus, Abort, and ClrStatus commands, as well as Download if configured by the user.
+/// The handler is responsive to DFU GetState, GetStatus, Abort, and ClrStatus commands, as well as Download and Upload if configured by the user.
 ///
 /// Once the host has initiated a DFU download operation, the chunks sent by the host will be written to the DFU partition.
 /// Once the final sync in the manifestation phase has been received, the handler will trigger a system reset to swap the new firmware.
-pub fn usb_dfu<'d, D: Driver<'d>, H: Handler>(
-    builder: &mut Builder<'d, D>,
+///
+/// A state created with [`DfuState::new_dfuse`] also handles the DfuSe commands, and reports the memory layout as the
+/// interface string.
+pub fn usb_dfu<'d, D: Driver<'d>, H: Handler, const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>(
+    builder: &mut Builder<'d, D, MAX_INTERFACES, MAX_HANDLERS>,
     state: &'d mut DfuState<H>,
     max_write_size: usize,
-    func_modifier: impl Fn(&mut FunctionBuilder<'_, 'd, D>),
+    func_modifier: impl Fn(&mut FunctionBuilder<'_, 'd, D, MAX_INTERFACES, MAX_HANDLERS>),
 ) {
     let mut func = builder.function(0x00, 0x00, 0x00);
 
@@ -229,7 +424,18 @@ pub fn usb_dfu<'d, D: Driver<'d>, H: Handler>(
     func_modifier(&mut func);
 
     let mut iface = func.interface();
-    let mut alt = iface.alt_setting(USB_CLASS_APPN_SPEC, APPN_SPEC_SUBCLASS_DFU, DFU_PROTOCOL_DFU, None);
+    state.transfer_size = max_write_size;
+    state.layout_string = state.dfuse.map(|_| iface.string());
+    let version = match state.dfuse {
+        Some(_) => DFUSE_VERSION,
+        None => DFU_VERSION,
+    };
+    let mut alt = iface.alt_setting(
+        USB_CLASS_APPN_SPEC,
+        APPN_SPEC_SUBCLASS_DFU,
+        DFU_PROTOCOL_DFU,
+        state.layout_string,
+    );
     alt.descriptor(
         DESC_DFU_FUNCTIONAL,
         &[
@@ -238,11 +444,215 @@ pub fn usb_dfu<'d, D: Driver<'d>, H: Handler>(
             0x09, // 2500ms timeout, doesn't affect operation as DETACH not necessary in bootloader code
             (max_write_size & 0xff) as u8,
             ((max_write_size & 0xff00) >> 8) as u8,
-            0x10,
-            0x01, // DFU 1.1
+            version as u8,
+            (version >> 8) as u8,
         ],
     );
 
     drop(func);
     builder.handler(state);
 }
+
+#[cfg(test)]
+mod tests {
+    use embassy_usb_driver::Direction;
+
+    use super::*;
+    use crate::Handler as _;
+
+    const BASE: u32 = 0x0800_8000;
+
+    struct Memory {
+        data: [u8; 16],
+        erased: Option<Option<usize>>,
+    }
+
+    impl Handler for Memory {
+        fn start(&mut self) -> Result<(), Status> {
+            Ok(())
+        }
+
+        fn write(&mut self, _data: &[u8]) -> Result<(), Status> {
+            Err(Status::ErrWrite)
+        }
+
+        fn finish(&mut self) -> Result<(), Status> {
+            Ok(())
+        }
+
+        fn system_reset(&mut self) {}
+
+        fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<usize, Status> {
+            let data = self.data.get(offset..).ok_or(Status::ErrAddress)?;
+            let n = data.len().min(buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            Ok(n)
+        }
+
+        fn write_at(&mut self, offset: usize, data: &[u8]) -> Result<(), Status> {
+            self.data
+                .get_mut(offset..offset + data.len())
+                .ok_or(Status::ErrAddress)?
+                .copy_from_slice(data);
+            Ok(())
+        }
+
+        fn erase(&mut self, offset: Option<usize>) -> Result<(), Status> {
+            self.erased = Some(offset);
+            Ok(())
+        }
+    }
+
+    fn request(direction: Direction, request: Request, value: u16, length: u16) -> ControlRequest {
+        ControlRequest {
+            direction,
+            request_type: RequestType::Class,
+            recipient: Recipient::Interface,
+            request: request as u8,
+            value,
+            index: 0,
+            length,
+        }
+    }
+
+    fn dnload(dfu: &mut DfuState<Memory>, block: u16, data: &[u8]) -> Option<OutResponse> {
+        dfu.control_out(request(Direction::Out, Request::Dnload, block, data.len() as u16), data)
+    }
+
+    fn upload<'a>(dfu: &'a mut DfuState<Memory>, block: u16, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        let len = buf.len() as u16;
+        dfu.control_in(request(Direction::In, Request::Upload, block, len), buf)
+    }
+
+    fn get_status(dfu: &mut DfuState<Memory>) -> (Status, State) {
+        let mut buf = [0; 6];
+        match dfu.control_in(request(Direction::In, Request::GetStatus, 0, 6), &mut buf) {
+            Some(InResponse::Accepted(status)) => (
+                Status::try_from(status[0]).unwrap(),
+                State::try_from(status[4]).unwrap(),
+            ),
+            _ => panic!("GETSTATUS rejected"),
+        }
+    }
+
+    fn memory() -> Memory {
+        Memory {
+            data: core::array::from_fn(|i| i as u8),
+            erased: None,
+        }
+    }
+
+    #[test]
+    fn upload_until_short_block() {
+        let mut dfu = DfuState::new(memory(), DfuAttributes::CAN_UPLOAD);
+        let mut buf = [0; 6];
+
+        for (block, expected) in [&[0, 1, 2, 3, 4, 5][..], &[6, 7, 8, 9, 10, 11], &[12, 13, 14, 15]]
+            .into_iter()
+            .enumerate()
+        {
+            match upload(&mut dfu, block as u16, &mut buf) {
+                Some(InResponse::Accepted(data)) => assert_eq!(expected, data),
+                _ => panic!("UPLOAD rejected"),
+            }
+        }
+        assert_eq!((Status::Ok, State::DfuIdle), get_status(&mut dfu));
+
+        // Without the attribute, uploads are not handled.
+        let mut dfu = DfuState::new(memory(), DfuAttributes::CAN_DOWNLOAD);
+        assert!(upload(&mut dfu, 0, &mut buf).is_none());
+    }
+
+    #[test]
+    fn dfuse() {
+        let dfuse = DfuSe {
+            layout: "@Flash/0x08008000/01*016 g",
+            base_address: BASE,
+        };
+        let mut dfu = DfuState::new_dfuse(memory(), DfuAttributes::CAN_DOWNLOAD | DfuAttributes::CAN_UPLOAD, dfuse);
+        dfu.transfer_size = 4;
+        let mut buf = [0; 4];
+
+        match upload(&mut dfu, 0, &mut buf) {
+            Some(InResponse::Accepted(commands)) => assert_eq!([0x00, 0x21, 0x41], commands),
+            _ => panic!("UPLOAD rejected"),
+        }
+
+        // Set the address pointer, then write a block and a short last block from it.
+        let mut command = [0x21, 0, 0, 0, 0];
+        command[1..].copy_from_slice(&(BASE + 8).to_le_bytes());
+        assert!(matches!(dnload(&mut dfu, 0, &command), Some(OutResponse::Accepted)));
+        assert_eq!((Status::Ok, State::DlBusy), get_status(&mut dfu));
+        assert_eq!((Status::Ok, State::Download), get_status(&mut dfu));
+        assert!(matches!(
+            dnload(&mut dfu, 2, &[0xA0, 0xA1, 0xA2, 0xA3]),
+            Some(OutResponse::Accepted)
+        ));
+        get_status(&mut dfu);
+        get_status(&mut dfu);
+        assert!(matches!(
+            dnload(&mut dfu, 3, &[0xA4, 0xA5]),
+            Some(OutResponse::Accepted)
+        ));
+        get_status(&mut dfu);
+        assert_eq!((Status::Ok, State::Download), get_status(&mut dfu));
+        assert_eq!([0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5], dfu.handler.data[8..14]);
+
+        // Erase a page, and the whole memory.
+        command[0] = 0x41;
+        assert!(matches!(dnload(&mut dfu, 0, &command), Some(OutResponse::Accepted)));
+        assert_eq!(Some(Some(8)), dfu.handler.erased);
+        get_status(&mut dfu);
+        get_status(&mut dfu);
+        assert!(matches!(dnload(&mut dfu, 0, &[0x41]), Some(OutResponse::Accepted)));
+        assert_eq!(Some(None), dfu.handler.erased);
+        get_status(&mut dfu);
+        get_status(&mut dfu);
+
+        // Addresses below the base address are rejected.
+        command[1..].copy_from_slice(&(BASE - 4).to_le_bytes());
+        assert!(matches!(dnload(&mut dfu, 0, &command), Some(OutResponse::Accepted)));
+        assert_eq!((Status::ErrAddress, State::Error), get_status(&mut dfu));
+        dfu.control_out(request(Direction::Out, Request::ClrStatus, 0, 0), &[]);
+
+        // Read back from the address pointer, after an abort as dfu-util does.
+        command[0] = 0x21;
+        command[1..].copy_from_slice(&(BASE + 10).to_le_bytes());
+        assert!(matches!(dnload(&mut dfu, 0, &command), Some(OutResponse::Accepted)));
+        dfu.control_out(request(Direction::Out, Request::Abort, 0, 0), &[]);
+        match upload(&mut dfu, 2, &mut buf) {
+            Some(InResponse::Accepted(data)) => assert_eq!([0xA2, 0xA3, 0xA4, 0xA5], data),
+            _ => panic!("UPLOAD rejected"),
+        }
+        match upload(&mut dfu, 3, &mut buf) {
+            Some(InResponse::Accepted(data)) => assert_eq!([14, 15], data),
+            _ => panic!("UPLOAD rejected"),
+        }
+        assert_eq!((Status::Ok, State::DfuIdle), get_status(&mut dfu));
+    }
+
+    #[test]
+    fn dfuse_short_block() {
+        let dfuse = DfuSe {
+            layout: "@Flash/0x08008000/01*016 g",
+            base_address: BASE,
+        };
+        let mut dfu = DfuState::new_dfuse(memory(), DfuAttributes::CAN_DOWNLOAD | DfuAttributes::CAN_UPLOAD, dfuse);
+        dfu.transfer_size = 4;
+
+        // Block 4 is two transfer sizes from the address pointer, even when it is shorter.
+        assert!(matches!(
+            dnload(&mut dfu, 4, &[0xB0, 0xB1]),
+            Some(OutResponse::Accepted)
+        ));
+        assert_eq!((Status::Ok, State::DlBusy), get_status(&mut dfu));
+        assert_eq!([0xB0, 0xB1, 10, 11], dfu.handler.data[8..12]);
+        dfu.control_out(request(Direction::Out, Request::Abort, 0, 0), &[]);
+
+        let mut buf = [0; 2];
+        match upload(&mut dfu, 4, &mut buf) {
+            Some(InResponse::Accepted(data)) => assert_eq!([0xB0, 0xB1], data),
+            _ => panic!("UPLOAD rejected"),
+        }
+    }
+}
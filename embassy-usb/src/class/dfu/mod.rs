@@ -2,7 +2,8 @@
 //!
 //! This module provides USB DFU 1.1 protocol support, split into two modes:
 //! - `app_mode`: Runtime mode for applications to support detach requests
-//! - `dfu_mode`: Bootloader mode for handling firmware downloads
+//! - `dfu_mode`: Bootloader mode for handling firmware downloads and uploads, optionally with
+//!   the DfuSe extensions of STMicroelectronics
 
 pub mod consts;
 
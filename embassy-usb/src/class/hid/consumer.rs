@@ -0,0 +1,152 @@
+//! Ready-made HID consumer control (media keys).
+//!
+//! Reports up to two simultaneously pressed Consumer page usages. Consumer
+//! controls have no boot protocol, so `SET_PROTOCOL(Boot)` is rejected.
+
+use super::HidBootProtocol;
+use super::descriptor::{Collection, ItemFlags, ReportDescriptorBuilder, usage_page};
+use super::preset::{IDLE_INDEFINITE, PresetConfig, PresetState, PresetWriter};
+use crate::Builder;
+use crate::driver::{Driver, EndpointError};
+
+/// Consumer Control application collection usage.
+const CONSUMER_CONTROL: u16 = 0x01;
+
+/// Length of the input report.
+const CONSUMER_REPORT_LEN: usize = 4;
+
+/// Common Consumer page usages.
+pub mod usage {
+    /// Play.
+    pub const PLAY: u16 = 0xB0;
+    /// Pause.
+    pub const PAUSE: u16 = 0xB1;
+    /// Fast forward.
+    pub const FAST_FORWARD: u16 = 0xB3;
+    /// Rewind.
+    pub const REWIND: u16 = 0xB4;
+    /// Next track.
+    pub const SCAN_NEXT_TRACK: u16 = 0xB5;
+    /// Previous track.
+    pub const SCAN_PREVIOUS_TRACK: u16 = 0xB6;
+    /// Stop.
+    pub const STOP: u16 = 0xB7;
+    /// Eject.
+    pub const EJECT: u16 = 0xB8;
+    /// Play/Pause toggle.
+    pub const PLAY_PAUSE: u16 = 0xCD;
+    /// Mute.
+    pub const MUTE: u16 = 0xE2;
+    /// Volume increment.
+    pub const VOLUME_INCREMENT: u16 = 0xE9;
+    /// Volume decrement.
+    pub const VOLUME_DECREMENT: u16 = 0xEA;
+    /// Launch the calculator application.
+    pub const AL_CALCULATOR: u16 = 0x192;
+    /// Open the browser home page.
+    pub const AC_HOME: u16 = 0x223;
+}
+
+static CONSUMER_DESCRIPTOR: ReportDescriptorBuilder<32> = ReportDescriptorBuilder::new()
+    .usage_page(usage_page::CONSUMER)
+    .usage(CONSUMER_CONTROL)
+    .collection(Collection::Application)
+    .usage_range(0x000, 0x3FF)
+    .logical_range(0, 0x3FF)
+    .input_field(16, 2, ItemFlags::DATA)
+    .end_collection();
+
+/// Consumer control report.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConsumerReport {
+    /// Usages of up to two pressed controls, 0 for unused slots. See the [`usage`] module.
+    pub usages: [u16; 2],
+}
+
+impl ConsumerReport {
+    fn to_bytes(self) -> [u8; CONSUMER_REPORT_LEN] {
+        let a = self.usages[0].to_le_bytes();
+        let b = self.usages[1].to_le_bytes();
+        [a[0], a[1], b[0], b[1]]
+    }
+}
+
+/// Internal state for [`ConsumerControl`].
+pub struct State<'d> {
+    inner: PresetState<'d>,
+}
+
+impl<'d> Default for State<'d> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'d> State<'d> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            inner: PresetState::new(),
+        }
+    }
+}
+
+/// Consumer control (media keys) function.
+pub struct ConsumerControl<'d, D: Driver<'d>> {
+    writer: PresetWriter<'d, D, CONSUMER_REPORT_LEN>,
+}
+
+impl<'d, D: Driver<'d>> ConsumerControl<'d, D> {
+    /// Creates a new `ConsumerControl` interface.
+    ///
+    /// `poll_ms` is the interrupt endpoint polling interval, see [`Config::poll_ms`](super::Config::poll_ms).
+    pub fn new<const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>(
+        builder: &mut Builder<'d, D, MAX_INTERFACES, MAX_HANDLERS>,
+        state: &'d mut State<'d>,
+        poll_ms: u8,
+    ) -> Self {
+        let config = PresetConfig {
+            report_descriptor: CONSUMER_DESCRIPTOR.as_bytes(),
+            boot_protocol: HidBootProtocol::None,
+            default_idle_ms: IDLE_INDEFINITE,
+            accepts_output: false,
+            max_packet_size: 8,
+        };
+        Self {
+            writer: PresetWriter::new(builder, &mut state.inner, config, poll_ms),
+        }
+    }
+
+    /// Waits for the interrupt IN endpoint to be enabled.
+    pub async fn ready(&mut self) {
+        self.writer.ready().await
+    }
+
+    /// Sends `report` and remembers it for idle repeats and `GET_REPORT`.
+    pub async fn write(&mut self, report: &ConsumerReport) -> Result<(), EndpointError> {
+        self.writer.write(&report.to_bytes(), &[]).await
+    }
+
+    /// Presses a single control, releasing all others.
+    pub async fn press(&mut self, usage: u16) -> Result<(), EndpointError> {
+        self.write(&ConsumerReport { usages: [usage, 0] }).await
+    }
+
+    /// Releases all controls.
+    pub async fn release(&mut self) -> Result<(), EndpointError> {
+        self.write(&ConsumerReport::default()).await
+    }
+
+    /// Waits until the idle period set by the host has elapsed since the last report.
+    ///
+    /// Call [`resend`](Self::resend) when this completes. This future is cancel-safe.
+    pub async fn wait_idle(&mut self) {
+        self.writer.wait_idle().await
+    }
+
+    /// Sends the last report again.
+    pub async fn resend(&mut self) -> Result<(), EndpointError> {
+        self.writer.resend().await
+    }
+}
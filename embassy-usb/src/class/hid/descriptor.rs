@@ -0,0 +1,479 @@
+//! HID report descriptor builder.
+//!
+//! Builds report descriptors item by item, without `usbd-hid` and without
+//! heap allocation. All builder methods are `const fn`, so a descriptor can be
+//! assembled at compile time and handed to [`Config::report_descriptor`]:
+//!
+//! ```
+//! use embassy_usb::class::hid::descriptor::{usage, usage_page, Collection, ItemFlags, ReportDescriptorBuilder};
+//!
+//! const MOUSE: ReportDescriptorBuilder<64> = ReportDescriptorBuilder::new()
+//!     .usage_page(usage_page::GENERIC_DESKTOP)
+//!     .usage(usage::MOUSE)
+//!     .collection(Collection::Application)
+//!     .usage(usage::POINTER)
+//!     .collection(Collection::Physical)
+//!     // Three buttons followed by five bits of padding.
+//!     .usage_page(usage_page::BUTTON)
+//!     .usage_range(1, 3)
+//!     .logical_range(0, 1)
+//!     .input_field(1, 3, ItemFlags::VARIABLE)
+//!     .input_field(5, 1, ItemFlags::CONSTANT)
+//!     // Relative X/Y motion.
+//!     .usage_page(usage_page::GENERIC_DESKTOP)
+//!     .usage(usage::X)
+//!     .usage(usage::Y)
+//!     .logical_range(-127, 127)
+//!     .input_field(8, 2, ItemFlags::VARIABLE.union(ItemFlags::RELATIVE))
+//!     .end_collection()
+//!     .end_collection();
+//!
+//! let report_descriptor: &[u8] = MOUSE.as_bytes();
+//! # assert_eq!(report_descriptor.len(), 50);
+//! ```
+//!
+//! Exceeding the buffer size `N` or unbalanced collections panic, which turns
+//! into a compile error when the builder is evaluated in a `const` context.
+//!
+//! [`Config::report_descriptor`]: super::Config::report_descriptor
+
+/// Common HID usage page identifiers.
+pub mod usage_page {
+    /// Generic Desktop Controls (pointers, mice, joysticks, gamepads, keyboards).
+    pub const GENERIC_DESKTOP: u16 = 0x01;
+    /// Simulation Controls.
+    pub const SIMULATION: u16 = 0x02;
+    /// Generic Device Controls (battery strength, etc.).
+    pub const GENERIC_DEVICE: u16 = 0x06;
+    /// Keyboard / Keypad.
+    pub const KEYBOARD: u16 = 0x07;
+    /// LEDs.
+    pub const LED: u16 = 0x08;
+    /// Buttons. Usage 1 = Button 1, usage 2 = Button 2, etc.
+    pub const BUTTON: u16 = 0x09;
+    /// Ordinals.
+    pub const ORDINAL: u16 = 0x0A;
+    /// Consumer Controls (media keys, volume, etc.).
+    pub const CONSUMER: u16 = 0x0C;
+    /// Digitizers (pens, touchscreens, touchpads).
+    pub const DIGITIZER: u16 = 0x0D;
+    /// Start of the vendor-defined usage page range.
+    pub const VENDOR_DEFINED_START: u16 = 0xFF00;
+}
+
+/// Generic Desktop usages (usage page [`usage_page::GENERIC_DESKTOP`]).
+pub mod usage {
+    /// Pointer collection.
+    pub const POINTER: u16 = 0x01;
+    /// Mouse collection.
+    pub const MOUSE: u16 = 0x02;
+    /// Joystick collection.
+    pub const JOYSTICK: u16 = 0x04;
+    /// Gamepad collection.
+    pub const GAMEPAD: u16 = 0x05;
+    /// Keyboard collection.
+    pub const KEYBOARD: u16 = 0x06;
+    /// Keypad collection.
+    pub const KEYPAD: u16 = 0x07;
+    /// X axis.
+    pub const X: u16 = 0x30;
+    /// Y axis.
+    pub const Y: u16 = 0x31;
+    /// Z axis.
+    pub const Z: u16 = 0x32;
+    /// X rotation axis.
+    pub const RX: u16 = 0x33;
+    /// Y rotation axis.
+    pub const RY: u16 = 0x34;
+    /// Z rotation axis.
+    pub const RZ: u16 = 0x35;
+    /// Slider control.
+    pub const SLIDER: u16 = 0x36;
+    /// Dial / rotary control.
+    pub const DIAL: u16 = 0x37;
+    /// Scroll wheel.
+    pub const WHEEL: u16 = 0x38;
+    /// Hat switch (POV).
+    pub const HAT_SWITCH: u16 = 0x39;
+    /// System control collection.
+    pub const SYSTEM_CONTROL: u16 = 0x80;
+    /// D-pad up.
+    pub const DPAD_UP: u16 = 0x90;
+    /// D-pad down.
+    pub const DPAD_DOWN: u16 = 0x91;
+    /// D-pad right.
+    pub const DPAD_RIGHT: u16 = 0x92;
+    /// D-pad left.
+    pub const DPAD_LEFT: u16 = 0x93;
+}
+
+/// Collection types, see section 6.2.2.6 of the HID 1.11 specification.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Collection {
+    /// A group of axes, e.g. the sensors of a pointer.
+    Physical = 0x00,
+    /// A group of controls that is meaningful to an application, e.g. a mouse or keyboard.
+    Application = 0x01,
+    /// Interrelated data items.
+    Logical = 0x02,
+    /// Wraps all the fields of one report.
+    Report = 0x03,
+    /// An array of selector usages.
+    NamedArray = 0x04,
+    /// Modifies the meaning of the usage it contains.
+    UsageSwitch = 0x05,
+    /// Modifies the meaning of the usage attached to the encompassing collection.
+    UsageModifier = 0x06,
+}
+
+/// Flags of an Input, Output or Feature main item.
+///
+/// The default value (all bits clear) is `Data, Array, Absolute`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ItemFlags(u16);
+
+impl ItemFlags {
+    /// Data, Array, Absolute, ...
+    pub const DATA: Self = Self(0);
+    /// The field carries no meaningful data (padding / filler).
+    pub const CONSTANT: Self = Self(1 << 0);
+    /// Each element represents one control. When clear, the field is an array
+    /// whose elements contain usage codes.
+    pub const VARIABLE: Self = Self(1 << 1);
+    /// Values are relative to the previous report. When clear, absolute.
+    pub const RELATIVE: Self = Self(1 << 2);
+    /// Values wrap around at the logical extents.
+    pub const WRAP: Self = Self(1 << 3);
+    /// Raw data was processed in some non-linear way.
+    pub const NON_LINEAR: Self = Self(1 << 4);
+    /// The control has no preferred state to return to.
+    pub const NO_PREFERRED_STATE: Self = Self(1 << 5);
+    /// The control has a null state (out-of-range value means "not engaged").
+    pub const NULL_STATE: Self = Self(1 << 6);
+    /// The value can change without host interaction. Only valid for Output and Feature items.
+    pub const VOLATILE: Self = Self(1 << 7);
+    /// The field is a fixed-size stream of bytes rather than a bit field.
+    pub const BUFFERED_BYTES: Self = Self(1 << 8);
+
+    /// Returns the raw flag bits.
+    pub const fn bits(self) -> u16 {
+        self.0
+    }
+
+    /// Returns the union of `self` and `other`.
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// Returns `true` if all flags set in `other` are also set in `self`.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for ItemFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        self.union(rhs)
+    }
+}
+
+// Item types (bits 2..3 of the prefix).
+const TYPE_MAIN: u8 = 0;
+const TYPE_GLOBAL: u8 = 1;
+const TYPE_LOCAL: u8 = 2;
+
+// Main item tags.
+const MAIN_INPUT: u8 = 0x8;
+const MAIN_OUTPUT: u8 = 0x9;
+const MAIN_COLLECTION: u8 = 0xA;
+const MAIN_FEATURE: u8 = 0xB;
+const MAIN_END_COLLECTION: u8 = 0xC;
+
+// Global item tags.
+const GLOBAL_USAGE_PAGE: u8 = 0x0;
+const GLOBAL_LOGICAL_MINIMUM: u8 = 0x1;
+const GLOBAL_LOGICAL_MAXIMUM: u8 = 0x2;
+const GLOBAL_PHYSICAL_MINIMUM: u8 = 0x3;
+const GLOBAL_PHYSICAL_MAXIMUM: u8 = 0x4;
+const GLOBAL_UNIT_EXPONENT: u8 = 0x5;
+const GLOBAL_UNIT: u8 = 0x6;
+const GLOBAL_REPORT_SIZE: u8 = 0x7;
+const GLOBAL_REPORT_ID: u8 = 0x8;
+const GLOBAL_REPORT_COUNT: u8 = 0x9;
+const GLOBAL_PUSH: u8 = 0xA;
+const GLOBAL_POP: u8 = 0xB;
+
+// Local item tags.
+const LOCAL_USAGE: u8 = 0x0;
+const LOCAL_USAGE_MINIMUM: u8 = 0x1;
+const LOCAL_USAGE_MAXIMUM: u8 = 0x2;
+const LOCAL_STRING_INDEX: u8 = 0x7;
+
+/// Const-friendly HID report descriptor builder.
+///
+/// `N` is the capacity of the descriptor buffer in bytes. Use
+/// [`as_bytes`](Self::as_bytes) to get the encoded descriptor.
+#[derive(Clone, Debug)]
+pub struct ReportDescriptorBuilder<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    depth: u8,
+}
+
+impl<const N: usize> Default for ReportDescriptorBuilder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> ReportDescriptorBuilder<N> {
+    /// Create an empty builder.
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            depth: 0,
+        }
+    }
+
+    /// Returns the encoded report descriptor.
+    ///
+    /// Panics if a collection is still open.
+    pub const fn as_bytes(&self) -> &[u8] {
+        core::assert!(self.depth == 0, "HID report descriptor has unterminated collections");
+        self.buf.split_at(self.len).0
+    }
+
+    /// Returns the number of bytes written so far.
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if no item has been written yet.
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Append a raw, already encoded item.
+    pub const fn raw(mut self, bytes: &[u8]) -> Self {
+        core::assert!(self.len + bytes.len() <= N, "HID report descriptor buffer too small");
+        let mut i = 0;
+        while i < bytes.len() {
+            self.buf[self.len] = bytes[i];
+            self.len += 1;
+            i += 1;
+        }
+        self
+    }
+
+    /// Append a short item with the given type, tag and little-endian data of `size` bytes (0, 1, 2 or 4).
+    const fn item(mut self, item_type: u8, tag: u8, data: u32, size: usize) -> Self {
+        let size_code = match size {
+            0 => 0,
+            1 => 1,
+            2 => 2,
+            4 => 3,
+            _ => core::panic!("invalid HID item size"),
+        };
+        core::assert!(self.len + 1 + size <= N, "HID report descriptor buffer too small");
+        self.buf[self.len] = (tag << 4) | (item_type << 2) | size_code;
+        self.len += 1;
+        let data = data.to_le_bytes();
+        let mut i = 0;
+        while i < size {
+            self.buf[self.len] = data[i];
+            self.len += 1;
+            i += 1;
+        }
+        self
+    }
+
+    /// Append an item whose data is interpreted as unsigned, using the shortest encoding (at least one byte).
+    const fn item_unsigned(self, item_type: u8, tag: u8, data: u32) -> Self {
+        let size = if data <= 0xFF {
+            1
+        } else if data <= 0xFFFF {
+            2
+        } else {
+            4
+        };
+        self.item(item_type, tag, data, size)
+    }
+
+    /// Append an item whose data is interpreted as signed, using the shortest encoding.
+    const fn item_signed(self, item_type: u8, tag: u8, data: i32) -> Self {
+        let size = if data >= i8::MIN as i32 && data <= i8::MAX as i32 {
+            1
+        } else if data >= i16::MIN as i32 && data <= i16::MAX as i32 {
+            2
+        } else {
+            4
+        };
+        self.item(item_type, tag, data as u32, size)
+    }
+
+    // ── Global items ──────────────────────────────────────────────────────────
+
+    /// Usage Page global item.
+    pub const fn usage_page(self, page: u16) -> Self {
+        self.item_unsigned(TYPE_GLOBAL, GLOBAL_USAGE_PAGE, page as u32)
+    }
+
+    /// Logical Minimum global item.
+    pub const fn logical_minimum(self, min: i32) -> Self {
+        self.item_signed(TYPE_GLOBAL, GLOBAL_LOGICAL_MINIMUM, min)
+    }
+
+    /// Logical Maximum global item.
+    pub const fn logical_maximum(self, max: i32) -> Self {
+        self.item_signed(TYPE_GLOBAL, GLOBAL_LOGICAL_MAXIMUM, max)
+    }
+
+    /// Logical Minimum and Logical Maximum global items.
+    pub const fn logical_range(self, min: i32, max: i32) -> Self {
+        self.logical_minimum(min).logical_maximum(max)
+    }
+
+    /// Physical Minimum global item.
+    pub const fn physical_minimum(self, min: i32) -> Self {
+        self.item_signed(TYPE_GLOBAL, GLOBAL_PHYSICAL_MINIMUM, min)
+    }
+
+    /// Physical Maximum global item.
+    pub const fn physical_maximum(self, max: i32) -> Self {
+        self.item_signed(TYPE_GLOBAL, GLOBAL_PHYSICAL_MAXIMUM, max)
+    }
+
+    /// Physical Minimum and Physical Maximum global items.
+    pub const fn physical_range(self, min: i32, max: i32) -> Self {
+        self.physical_minimum(min).physical_maximum(max)
+    }
+
+    /// Unit Exponent global item. `exponent` must be in `-8..=7`.
+    pub const fn unit_exponent(self, exponent: i8) -> Self {
+        core::assert!(exponent >= -8 && exponent <= 7, "HID unit exponent out of range");
+        self.item(TYPE_GLOBAL, GLOBAL_UNIT_EXPONENT, (exponent as u32) & 0x0F, 1)
+    }
+
+    /// Unit global item, as the raw nibble-encoded unit value.
+    pub const fn unit(self, unit: u32) -> Self {
+        self.item_unsigned(TYPE_GLOBAL, GLOBAL_UNIT, unit)
+    }
+
+    /// Report Size global item, in bits per element.
+    pub const fn report_size(self, bits: u8) -> Self {
+        core::assert!(bits > 0 && bits <= 32, "HID report size must be 1..=32 bits");
+        self.item_unsigned(TYPE_GLOBAL, GLOBAL_REPORT_SIZE, bits as u32)
+    }
+
+    /// Report Count global item.
+    pub const fn report_count(self, count: u16) -> Self {
+        self.item_unsigned(TYPE_GLOBAL, GLOBAL_REPORT_COUNT, count as u32)
+    }
+
+    /// Report ID global item. Report ID 0 is reserved.
+    pub const fn report_id(self, id: u8) -> Self {
+        core::assert!(id != 0, "HID report ID 0 is reserved");
+        self.item_unsigned(TYPE_GLOBAL, GLOBAL_REPORT_ID, id as u32)
+    }
+
+    /// Push global item: saves the current global state.
+    pub const fn push(self) -> Self {
+        self.item(TYPE_GLOBAL, GLOBAL_PUSH, 0, 0)
+    }
+
+    /// Pop global item: restores the last pushed global state.
+    pub const fn pop(self) -> Self {
+        self.item(TYPE_GLOBAL, GLOBAL_POP, 0, 0)
+    }
+
+    // ── Local items ───────────────────────────────────────────────────────────
+
+    /// Usage local item, relative to the current usage page.
+    pub const fn usage(self, usage: u16) -> Self {
+        self.item_unsigned(TYPE_LOCAL, LOCAL_USAGE, usage as u32)
+    }
+
+    /// Extended Usage local item, carrying its own usage page.
+    pub const fn usage_extended(self, page: u16, usage: u16) -> Self {
+        self.item(TYPE_LOCAL, LOCAL_USAGE, ((page as u32) << 16) | usage as u32, 4)
+    }
+
+    /// Usage Minimum local item.
+    pub const fn usage_minimum(self, min: u16) -> Self {
+        self.item_unsigned(TYPE_LOCAL, LOCAL_USAGE_MINIMUM, min as u32)
+    }
+
+    /// Usage Maximum local item.
+    pub const fn usage_maximum(self, max: u16) -> Self {
+        self.item_unsigned(TYPE_LOCAL, LOCAL_USAGE_MAXIMUM, max as u32)
+    }
+
+    /// Usage Minimum and Usage Maximum local items.
+    pub const fn usage_range(self, min: u16, max: u16) -> Self {
+        self.usage_minimum(min).usage_maximum(max)
+    }
+
+    /// String Index local item.
+    pub const fn string_index(self, index: u8) -> Self {
+        self.item_unsigned(TYPE_LOCAL, LOCAL_STRING_INDEX, index as u32)
+    }
+
+    // ── Main items ────────────────────────────────────────────────────────────
+
+    /// Collection main item. Must be matched by [`end_collection`](Self::end_collection).
+    pub const fn collection(mut self, kind: Collection) -> Self {
+        self.depth += 1;
+        self.item(TYPE_MAIN, MAIN_COLLECTION, kind as u32, 1)
+    }
+
+    /// End Collection main item.
+    pub const fn end_collection(mut self) -> Self {
+        core::assert!(self.depth > 0, "HID end collection without matching collection");
+        self.depth -= 1;
+        self.item(TYPE_MAIN, MAIN_END_COLLECTION, 0, 0)
+    }
+
+    /// Input main item.
+    pub const fn input(self, flags: ItemFlags) -> Self {
+        self.item_unsigned(TYPE_MAIN, MAIN_INPUT, flags.bits() as u32)
+    }
+
+    /// Output main item.
+    pub const fn output(self, flags: ItemFlags) -> Self {
+        self.item_unsigned(TYPE_MAIN, MAIN_OUTPUT, flags.bits() as u32)
+    }
+
+    /// Feature main item.
+    pub const fn feature(self, flags: ItemFlags) -> Self {
+        self.item_unsigned(TYPE_MAIN, MAIN_FEATURE, flags.bits() as u32)
+    }
+
+    // ── Fields ────────────────────────────────────────────────────────────────
+
+    /// Input field of `count` elements of `size` bits each.
+    ///
+    /// Shorthand for Report Size, Report Count and Input items.
+    pub const fn input_field(self, size: u8, count: u16, flags: ItemFlags) -> Self {
+        self.report_size(size).report_count(count).input(flags)
+    }
+
+    /// Output field of `count` elements of `size` bits each.
+    ///
+    /// Shorthand for Report Size, Report Count and Output items.
+    pub const fn output_field(self, size: u8, count: u16, flags: ItemFlags) -> Self {
+        self.report_size(size).report_count(count).output(flags)
+    }
+
+    /// Feature field of `count` elements of `size` bits each.
+    ///
+    /// Shorthand for Report Size, Report Count and Feature items.
+    pub const fn feature_field(self, size: u8, count: u16, flags: ItemFlags) -> Self {
+        self.report_size(size).report_count(count).feature(flags)
+    }
+}
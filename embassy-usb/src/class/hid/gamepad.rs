@@ -0,0 +1,171 @@
+//! Ready-made HID gamepad.
+//!
+//! Reports 16 buttons, an 8-way hat switch and four 16-bit absolute axes
+//! (X/Y for the left stick, Z/Rz for the right stick). Gamepads have no boot
+//! protocol, so `SET_PROTOCOL(Boot)` is rejected.
+
+use super::HidBootProtocol;
+use super::descriptor::{Collection, ItemFlags, ReportDescriptorBuilder, usage, usage_page};
+use super::preset::{IDLE_INDEFINITE, PresetConfig, PresetState, PresetWriter};
+use crate::Builder;
+use crate::driver::{Driver, EndpointError};
+
+/// Angular unit (English rotation, degrees) for the hat switch.
+const UNIT_DEGREES: u32 = 0x14;
+
+/// Length of the input report.
+const GAMEPAD_REPORT_LEN: usize = 11;
+
+static GAMEPAD_DESCRIPTOR: ReportDescriptorBuilder<96> = ReportDescriptorBuilder::new()
+    .usage_page(usage_page::GENERIC_DESKTOP)
+    .usage(usage::GAMEPAD)
+    .collection(Collection::Application)
+    // Buttons 1-16.
+    .usage_page(usage_page::BUTTON)
+    .usage_range(1, 16)
+    .logical_range(0, 1)
+    .input_field(1, 16, ItemFlags::VARIABLE)
+    // Hat switch, 0 = up, clockwise in 45° steps, out of range = centered.
+    .usage_page(usage_page::GENERIC_DESKTOP)
+    .usage(usage::HAT_SWITCH)
+    .logical_range(0, 7)
+    .physical_range(0, 315)
+    .unit(UNIT_DEGREES)
+    .input_field(4, 1, ItemFlags::VARIABLE.union(ItemFlags::NULL_STATE))
+    .unit(0)
+    .input_field(4, 1, ItemFlags::CONSTANT)
+    // Sticks.
+    .usage(usage::X)
+    .usage(usage::Y)
+    .usage(usage::Z)
+    .usage(usage::RZ)
+    .logical_range(-32767, 32767)
+    .physical_range(-32767, 32767)
+    .input_field(16, 4, ItemFlags::VARIABLE)
+    .end_collection();
+
+/// Hat switch (D-pad) position.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Hat {
+    /// Up.
+    Up = 0,
+    /// Up and right.
+    UpRight = 1,
+    /// Right.
+    Right = 2,
+    /// Down and right.
+    DownRight = 3,
+    /// Down.
+    Down = 4,
+    /// Down and left.
+    DownLeft = 5,
+    /// Left.
+    Left = 6,
+    /// Up and left.
+    UpLeft = 7,
+    /// Not pressed (null state).
+    #[default]
+    Centered = 8,
+}
+
+/// Gamepad report.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct GamepadReport {
+    /// Pressed buttons, bit 0 = button 1.
+    pub buttons: u16,
+    /// Hat switch position.
+    pub hat: Hat,
+    /// Left stick X axis.
+    pub x: i16,
+    /// Left stick Y axis.
+    pub y: i16,
+    /// Right stick X axis.
+    pub z: i16,
+    /// Right stick Y axis.
+    pub rz: i16,
+}
+
+impl GamepadReport {
+    fn to_bytes(self) -> [u8; GAMEPAD_REPORT_LEN] {
+        let mut buf = [0; GAMEPAD_REPORT_LEN];
+        buf[0..2].copy_from_slice(&self.buttons.to_le_bytes());
+        buf[2] = self.hat as u8;
+        buf[3..5].copy_from_slice(&self.x.to_le_bytes());
+        buf[5..7].copy_from_slice(&self.y.to_le_bytes());
+        buf[7..9].copy_from_slice(&self.z.to_le_bytes());
+        buf[9..11].copy_from_slice(&self.rz.to_le_bytes());
+        buf
+    }
+}
+
+/// Internal state for [`Gamepad`].
+pub struct State<'d> {
+    inner: PresetState<'d>,
+}
+
+impl<'d> Default for State<'d> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'d> State<'d> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            inner: PresetState::new(),
+        }
+    }
+}
+
+/// Generic gamepad function.
+pub struct Gamepad<'d, D: Driver<'d>> {
+    writer: PresetWriter<'d, D, GAMEPAD_REPORT_LEN>,
+}
+
+impl<'d, D: Driver<'d>> Gamepad<'d, D> {
+    /// Creates a new `Gamepad` interface.
+    ///
+    /// `poll_ms` is the interrupt endpoint polling interval, see [`Config::poll_ms`](super::Config::poll_ms).
+    pub fn new<const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>(
+        builder: &mut Builder<'d, D, MAX_INTERFACES, MAX_HANDLERS>,
+        state: &'d mut State<'d>,
+        poll_ms: u8,
+    ) -> Self {
+        let config = PresetConfig {
+            report_descriptor: GAMEPAD_DESCRIPTOR.as_bytes(),
+            boot_protocol: HidBootProtocol::None,
+            default_idle_ms: IDLE_INDEFINITE,
+            accepts_output: false,
+            max_packet_size: 16,
+        };
+        Self {
+            writer: PresetWriter::new(builder, &mut state.inner, config, poll_ms),
+        }
+    }
+
+    /// Waits for the interrupt IN endpoint to be enabled.
+    pub async fn ready(&mut self) {
+        self.writer.ready().await
+    }
+
+    /// Sends `report` and remembers it for idle repeats and `GET_REPORT`.
+    pub async fn write(&mut self, report: &GamepadReport) -> Result<(), EndpointError> {
+        self.writer.write(&report.to_bytes(), &[]).await
+    }
+
+    /// Waits until the idle period set by the host has elapsed since the last report.
+    ///
+    /// Call [`resend`](Self::resend) when this completes. This future is cancel-safe.
+    pub async fn wait_idle(&mut self) {
+        self.writer.wait_idle().await
+    }
+
+    /// Sends the last report again.
+    pub async fn resend(&mut self) -> Result<(), EndpointError> {
+        self.writer.resend().await
+    }
+}
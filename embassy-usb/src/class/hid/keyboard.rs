@@ -0,0 +1,449 @@
+//! Ready-made HID keyboards.
+//!
+//! - [`BootKeyboard`]: a 6-key rollover keyboard whose report protocol layout is
+//!   identical to the boot protocol layout.
+//! - [`NkroKeyboard`]: an N-key rollover keyboard that reports a key bitmap in
+//!   report protocol, and falls back to 6-key boot reports when the host (e.g. a
+//!   BIOS) selects the boot protocol.
+//!
+//! Both handle `SET_PROTOCOL`/`GET_PROTOCOL`, `SET_IDLE`/`GET_IDLE` (with a default
+//! idle rate of 500 ms as recommended for keyboards) and the LED output report.
+
+use super::descriptor::{Collection, ItemFlags, ReportDescriptorBuilder, usage, usage_page};
+use super::preset::{BOOT_REPORT_LEN, PresetConfig, PresetState, PresetWriter};
+use super::{HidBootProtocol, HidProtocolMode};
+use crate::Builder;
+use crate::driver::{Driver, EndpointError};
+
+/// Recommended default idle rate for keyboards (HID 1.11, 7.2.4).
+const DEFAULT_IDLE_MS: u32 = 500;
+
+/// Keyboard usage reported in every key slot when too many keys are pressed.
+const KEY_ERROR_ROLL_OVER: u8 = 0x01;
+
+/// Number of key usages covered by the N-key rollover bitmap (`0x00..=0xDF`).
+const NKRO_KEYS: usize = 0xE0;
+const NKRO_BITMAP_LEN: usize = NKRO_KEYS / 8;
+const NKRO_REPORT_LEN: usize = 1 + NKRO_BITMAP_LEN;
+
+static BOOT_KEYBOARD_DESCRIPTOR: ReportDescriptorBuilder<80> = leds(modifiers(
+    ReportDescriptorBuilder::new()
+        .usage_page(usage_page::GENERIC_DESKTOP)
+        .usage(usage::KEYBOARD)
+        .collection(Collection::Application),
+))
+.input_field(8, 1, ItemFlags::CONSTANT)
+.usage_page(usage_page::KEYBOARD)
+.usage_range(0x00, 0xFF)
+.logical_range(0, 255)
+.input_field(8, 6, ItemFlags::DATA)
+.end_collection();
+
+static NKRO_KEYBOARD_DESCRIPTOR: ReportDescriptorBuilder<80> = leds(modifiers(
+    ReportDescriptorBuilder::new()
+        .usage_page(usage_page::GENERIC_DESKTOP)
+        .usage(usage::KEYBOARD)
+        .collection(Collection::Application),
+))
+.usage_page(usage_page::KEYBOARD)
+.usage_range(0x00, NKRO_KEYS as u16 - 1)
+.logical_range(0, 1)
+.input_field(1, NKRO_KEYS as u16, ItemFlags::VARIABLE)
+.end_collection();
+
+/// Modifier byte: one bit per modifier key (usages `0xE0..=0xE7`).
+const fn modifiers(b: ReportDescriptorBuilder<80>) -> ReportDescriptorBuilder<80> {
+    b.usage_page(usage_page::KEYBOARD)
+        .usage_range(0xE0, 0xE7)
+        .logical_range(0, 1)
+        .input_field(1, 8, ItemFlags::VARIABLE)
+}
+
+/// LED output report: five LEDs and three bits of padding.
+const fn leds(b: ReportDescriptorBuilder<80>) -> ReportDescriptorBuilder<80> {
+    b.usage_page(usage_page::LED)
+        .usage_range(1, 5)
+        .logical_range(0, 1)
+        .output_field(1, 5, ItemFlags::VARIABLE)
+        .output_field(3, 1, ItemFlags::CONSTANT)
+}
+
+/// Keyboard modifier bits.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Modifiers(pub u8);
+
+impl Modifiers {
+    /// Left Control.
+    pub const LEFT_CTRL: Self = Self(1 << 0);
+    /// Left Shift.
+    pub const LEFT_SHIFT: Self = Self(1 << 1);
+    /// Left Alt.
+    pub const LEFT_ALT: Self = Self(1 << 2);
+    /// Left GUI (Windows / Command key).
+    pub const LEFT_GUI: Self = Self(1 << 3);
+    /// Right Control.
+    pub const RIGHT_CTRL: Self = Self(1 << 4);
+    /// Right Shift.
+    pub const RIGHT_SHIFT: Self = Self(1 << 5);
+    /// Right Alt.
+    pub const RIGHT_ALT: Self = Self(1 << 6);
+    /// Right GUI (Windows / Command key).
+    pub const RIGHT_GUI: Self = Self(1 << 7);
+
+    /// Returns the union of `self` and `other`.
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// Returns `true` if all modifiers set in `other` are also set in `self`.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// Keyboard LED states, as set by the host.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Leds(pub u8);
+
+impl Leds {
+    /// Returns `true` if Num Lock is on.
+    pub const fn num_lock(self) -> bool {
+        self.0 & (1 << 0) != 0
+    }
+
+    /// Returns `true` if Caps Lock is on.
+    pub const fn caps_lock(self) -> bool {
+        self.0 & (1 << 1) != 0
+    }
+
+    /// Returns `true` if Scroll Lock is on.
+    pub const fn scroll_lock(self) -> bool {
+        self.0 & (1 << 2) != 0
+    }
+
+    /// Returns `true` if Compose is on.
+    pub const fn compose(self) -> bool {
+        self.0 & (1 << 3) != 0
+    }
+
+    /// Returns `true` if Kana is on.
+    pub const fn kana(self) -> bool {
+        self.0 & (1 << 4) != 0
+    }
+}
+
+/// 6-key rollover keyboard report, identical in boot and report protocol.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeyboardReport {
+    /// Pressed modifier keys.
+    pub modifiers: Modifiers,
+    /// Usage IDs of up to six pressed keys, 0 for unused slots.
+    pub keycodes: [u8; 6],
+}
+
+impl KeyboardReport {
+    fn to_bytes(self) -> [u8; BOOT_REPORT_LEN] {
+        let k = self.keycodes;
+        [self.modifiers.0, 0, k[0], k[1], k[2], k[3], k[4], k[5]]
+    }
+}
+
+/// N-key rollover keyboard report.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NkroReport {
+    /// Pressed modifier keys.
+    pub modifiers: Modifiers,
+    keys: [u8; NKRO_BITMAP_LEN],
+}
+
+impl Default for NkroReport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NkroReport {
+    /// Create a report with no key pressed.
+    pub const fn new() -> Self {
+        Self {
+            modifiers: Modifiers(0),
+            keys: [0; NKRO_BITMAP_LEN],
+        }
+    }
+
+    /// Mark the key with usage ID `keycode` as pressed.
+    ///
+    /// Modifier usages (`0xE0..=0xE7`) set the corresponding modifier bit.
+    pub fn press(&mut self, keycode: u8) {
+        self.set(keycode, true)
+    }
+
+    /// Mark the key with usage ID `keycode` as released.
+    pub fn release(&mut self, keycode: u8) {
+        self.set(keycode, false)
+    }
+
+    /// Set the pressed state of the key with usage ID `keycode`.
+    pub fn set(&mut self, keycode: u8, pressed: bool) {
+        let (byte, mask) = if keycode >= 0xE0 {
+            (&mut self.modifiers.0, 1 << (keycode - 0xE0))
+        } else {
+            (&mut self.keys[keycode as usize / 8], 1 << (keycode % 8))
+        };
+        if pressed {
+            *byte |= mask;
+        } else {
+            *byte &= !mask;
+        }
+    }
+
+    /// Returns `true` if the key with usage ID `keycode` is pressed.
+    pub fn is_pressed(&self, keycode: u8) -> bool {
+        if keycode >= 0xE0 {
+            self.modifiers.0 & (1 << (keycode - 0xE0)) != 0
+        } else {
+            self.keys[keycode as usize / 8] & (1 << (keycode % 8)) != 0
+        }
+    }
+
+    /// Iterate over the usage IDs of all pressed non-modifier keys.
+    pub fn pressed(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..NKRO_KEYS as u8).filter(|&k| self.is_pressed(k))
+    }
+
+    /// Convert to a 6-key rollover report.
+    ///
+    /// If more than six keys are pressed, all slots report `ErrorRollOver`, as
+    /// required by the boot protocol.
+    pub fn to_boot(&self) -> KeyboardReport {
+        let mut report = KeyboardReport {
+            modifiers: self.modifiers,
+            keycodes: [0; 6],
+        };
+        for (i, key) in self.pressed().enumerate() {
+            if i >= report.keycodes.len() {
+                report.keycodes = [KEY_ERROR_ROLL_OVER; 6];
+                break;
+            }
+            report.keycodes[i] = key;
+        }
+        report
+    }
+
+    fn to_bytes(self) -> [u8; NKRO_REPORT_LEN] {
+        let mut buf = [0; NKRO_REPORT_LEN];
+        buf[0] = self.modifiers.0;
+        buf[1..].copy_from_slice(&self.keys);
+        buf
+    }
+}
+
+/// Internal state for the keyboard presets.
+pub struct State<'d> {
+    inner: PresetState<'d>,
+}
+
+impl<'d> Default for State<'d> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'d> State<'d> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            inner: PresetState::new(),
+        }
+    }
+}
+
+/// Boot-compatible 6-key rollover keyboard.
+pub struct BootKeyboard<'d, D: Driver<'d>> {
+    writer: PresetWriter<'d, D, BOOT_REPORT_LEN>,
+}
+
+impl<'d, D: Driver<'d>> BootKeyboard<'d, D> {
+    /// Creates a new `BootKeyboard` interface.
+    ///
+    /// `poll_ms` is the interrupt endpoint polling interval, see [`Config::poll_ms`](super::Config::poll_ms).
+    pub fn new<const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>(
+        builder: &mut Builder<'d, D, MAX_INTERFACES, MAX_HANDLERS>,
+        state: &'d mut State<'d>,
+        poll_ms: u8,
+    ) -> Self {
+        let config = PresetConfig {
+            report_descriptor: BOOT_KEYBOARD_DESCRIPTOR.as_bytes(),
+            boot_protocol: HidBootProtocol::Keyboard,
+            default_idle_ms: DEFAULT_IDLE_MS,
+            accepts_output: true,
+            max_packet_size: 8,
+        };
+        Self {
+            writer: PresetWriter::new(builder, &mut state.inner, config, poll_ms),
+        }
+    }
+
+    /// Waits for the interrupt IN endpoint to be enabled.
+    pub async fn ready(&mut self) {
+        self.writer.ready().await
+    }
+
+    /// Returns the protocol currently selected by the host.
+    pub fn protocol(&self) -> HidProtocolMode {
+        self.writer.protocol()
+    }
+
+    /// Returns the LED states last set by the host.
+    pub fn leds(&self) -> Leds {
+        Leds(self.writer.output())
+    }
+
+    /// Waits until the host sets the LEDs, and returns the new states.
+    pub async fn wait_leds_changed(&self) -> Leds {
+        Leds(self.writer.wait_output_changed().await)
+    }
+
+    /// Sends `report` and remembers it for idle repeats and `GET_REPORT`.
+    pub async fn write(&mut self, report: &KeyboardReport) -> Result<(), EndpointError> {
+        let bytes = report.to_bytes();
+        self.writer.write(&bytes, &bytes).await
+    }
+
+    /// Waits until the idle period set by the host has elapsed since the last report.
+    ///
+    /// Call [`resend`](Self::resend) when this completes. This future is cancel-safe,
+    /// so it can be raced against the application's key events.
+    pub async fn wait_idle(&mut self) {
+        self.writer.wait_idle().await
+    }
+
+    /// Sends the last report again.
+    pub async fn resend(&mut self) -> Result<(), EndpointError> {
+        self.writer.resend().await
+    }
+}
+
+/// N-key rollover keyboard with boot protocol fallback.
+pub struct NkroKeyboard<'d, D: Driver<'d>> {
+    writer: PresetWriter<'d, D, NKRO_REPORT_LEN>,
+}
+
+impl<'d, D: Driver<'d>> NkroKeyboard<'d, D> {
+    /// Creates a new `NkroKeyboard` interface.
+    ///
+    /// `poll_ms` is the interrupt endpoint polling interval, see [`Config::poll_ms`](super::Config::poll_ms).
+    pub fn new<const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>(
+        builder: &mut Builder<'d, D, MAX_INTERFACES, MAX_HANDLERS>,
+        state: &'d mut State<'d>,
+        poll_ms: u8,
+    ) -> Self {
+        let config = PresetConfig {
+            report_descriptor: NKRO_KEYBOARD_DESCRIPTOR.as_bytes(),
+            boot_protocol: HidBootProtocol::Keyboard,
+            default_idle_ms: DEFAULT_IDLE_MS,
+            accepts_output: true,
+            max_packet_size: 32,
+        };
+        Self {
+            writer: PresetWriter::new(builder, &mut state.inner, config, poll_ms),
+        }
+    }
+
+    /// Waits for the interrupt IN endpoint to be enabled.
+    pub async fn ready(&mut self) {
+        self.writer.ready().await
+    }
+
+    /// Returns the protocol currently selected by the host.
+    pub fn protocol(&self) -> HidProtocolMode {
+        self.writer.protocol()
+    }
+
+    /// Returns the LED states last set by the host.
+    pub fn leds(&self) -> Leds {
+        Leds(self.writer.output())
+    }
+
+    /// Waits until the host sets the LEDs, and returns the new states.
+    pub async fn wait_leds_changed(&self) -> Leds {
+        Leds(self.writer.wait_output_changed().await)
+    }
+
+    /// Sends `report` in the format of the current protocol, and remembers it
+    /// for idle repeats and `GET_REPORT`.
+    pub async fn write(&mut self, report: &NkroReport) -> Result<(), EndpointError> {
+        self.writer
+            .write(&report.to_bytes(), &report.to_boot().to_bytes())
+            .await
+    }
+
+    /// Waits until the idle period set by the host has elapsed since the last report.
+    ///
+    /// Call [`resend`](Self::resend) when this completes. This future is cancel-safe,
+    /// so it can be raced against the application's key events.
+    pub async fn wait_idle(&mut self) {
+        self.writer.wait_idle().await
+    }
+
+    /// Sends the last report again, in the format of the current protocol.
+    pub async fn resend(&mut self) -> Result<(), EndpointError> {
+        self.writer.resend().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nkro_report_bytes() {
+        let mut report = NkroReport::new();
+        report.press(0x04);
+        report.press(0x1D);
+        report.press(0xE1);
+        let bytes = report.to_bytes();
+        assert_eq!(bytes[0], Modifiers::LEFT_SHIFT.0);
+        assert_eq!(bytes[1], 1 << 4);
+        assert_eq!(bytes[4], 1 << 5);
+        assert!(bytes[5..].iter().all(|&b| b == 0));
+
+        report.release(0x04);
+        assert!(!report.is_pressed(0x04));
+        assert_eq!(report.to_bytes()[1], 0);
+    }
+
+    #[test]
+    fn nkro_to_boot() {
+        let mut report = NkroReport::new();
+        report.press(0xE0);
+        for key in 0x04..0x0A {
+            report.press(key);
+        }
+        assert_eq!(
+            report.to_boot().to_bytes(),
+            [0x01, 0, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09]
+        );
+
+        // More than six keys: every slot reports ErrorRollOver, modifiers are kept.
+        report.press(0x0A);
+        assert_eq!(
+            report.to_boot(),
+            KeyboardReport {
+                modifiers: Modifiers::LEFT_CTRL,
+                keycodes: [KEY_ERROR_ROLL_OVER; 6],
+            }
+        );
+    }
+
+    #[test]
+    fn leds() {
+        let leds = Leds(0b00011);
+        assert!(leds.num_lock() && leds.caps_lock());
+        assert!(!leds.scroll_lock() && !leds.compose() && !leds.kana());
+    }
+}
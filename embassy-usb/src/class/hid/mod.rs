@@ -1,4 +1,21 @@
 //! USB HID (Human Interface Device) class implementation.
+//!
+//! Report descriptors can be built with [`descriptor::ReportDescriptorBuilder`] and
+//! reports serialized with the [`report`] codec, without depending on `usbd-hid`.
+//!
+//! Ready-made device functions that handle protocol switching and idle rates are
+//! available in [`keyboard`], [`mouse`], [`consumer`] and [`gamepad`]. Each one is
+//! a separate interface, so several of them can be combined in one composite device:
+//!
+//! ```ignore
+//! let mut kbd_state = keyboard::State::new();
+//! let mut mouse_state = mouse::State::new();
+//! let mut media_state = consumer::State::new();
+//!
+//! let mut kbd = keyboard::BootKeyboard::new(&mut builder, &mut kbd_state, 10);
+//! let mut mouse = mouse::BootMouse::new(&mut builder, &mut mouse_state, 10);
+//! let mut media = consumer::ConsumerControl::new(&mut builder, &mut media_state, 10);
+//! ```
 
 use core::mem::MaybeUninit;
 use core::ops::Range;
@@ -7,11 +24,20 @@ use core::sync::atomic::{AtomicUsize, Ordering};
 #[cfg(feature = "usbd-hid")]
 use usbd_hid::descriptor::AsInputReport;
 
+use self::report::{EncodeReport, encode_report};
 use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
 use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
 use crate::types::InterfaceNumber;
 use crate::{Builder, Handler};
 
+pub mod consumer;
+pub mod descriptor;
+pub mod gamepad;
+pub mod keyboard;
+pub mod mouse;
+mod preset;
+pub mod report;
+
 const USB_CLASS_HID: u8 = 0x03;
 
 // HID
@@ -150,8 +176,8 @@ pub struct HidReaderWriter<'d, D: Driver<'d>, const READ_N: usize, const WRITE_N
     interface_number: InterfaceNumber,
 }
 
-fn build<'d, D: Driver<'d>>(
-    builder: &mut Builder<'d, D>,
+fn build<'d, D: Driver<'d>, const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>(
+    builder: &mut Builder<'d, D, MAX_INTERFACES, MAX_HANDLERS>,
     state: &'d mut State<'d>,
     config: Config<'d>,
     with_out_endpoint: bool,
@@ -213,7 +239,11 @@ impl<'d, D: Driver<'d>, const READ_N: usize, const WRITE_N: usize> HidReaderWrit
     /// This will allocate one IN and one OUT endpoints. If you only need writing (sending)
     /// HID reports, consider using [`HidWriter::new`] instead, which allocates an IN endpoint only.
     ///
-    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config<'d>) -> Self {
+    pub fn new<const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>(
+        builder: &mut Builder<'d, D, MAX_INTERFACES, MAX_HANDLERS>,
+        state: &'d mut State<'d>,
+        config: Config<'d>,
+    ) -> Self {
         let (ep_out, ep_in, offset, if_num) = build(builder, state, config, true);
 
         Self {
@@ -243,6 +273,11 @@ impl<'d, D: Driver<'d>, const READ_N: usize, const WRITE_N: usize> HidReaderWrit
         self.writer.write_serialize(r).await
     }
 
+    /// Writes an input report by encoding the given report structure.
+    pub async fn write_report<R: EncodeReport + ?Sized>(&mut self, r: &R) -> Result<(), EndpointError> {
+        self.writer.write_report(r).await
+    }
+
     /// Writes `report` to its interrupt endpoint.
     pub async fn write(&mut self, report: &[u8]) -> Result<(), EndpointError> {
         self.writer.write(report).await
@@ -308,7 +343,11 @@ impl<'d, D: Driver<'d>, const N: usize> HidWriter<'d, D, N> {
     /// HID reports. A lower value means better throughput & latency, at the expense
     /// of CPU on the device & bandwidth on the bus. A value of 10 is reasonable for
     /// high performance uses, and a value of 255 is good for best-effort usecases.
-    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config<'d>) -> Self {
+    pub fn new<const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>(
+        builder: &mut Builder<'d, D, MAX_INTERFACES, MAX_HANDLERS>,
+        state: &'d mut State<'d>,
+        config: Config<'d>,
+    ) -> Self {
         let (ep_out, ep_in, _offset, _) = build(builder, state, config, false);
 
         assert!(ep_out.is_none());
@@ -331,6 +370,15 @@ impl<'d, D: Driver<'d>, const N: usize> HidWriter<'d, D, N> {
         self.write(&buf[0..size]).await
     }
 
+    /// Writes an input report by encoding the given report structure.
+    pub async fn write_report<R: EncodeReport + ?Sized>(&mut self, r: &R) -> Result<(), EndpointError> {
+        let mut buf: [u8; N] = [0; N];
+        let Ok(size) = encode_report(r, &mut buf) else {
+            return Err(EndpointError::BufferOverflow);
+        };
+        self.write(&buf[0..size]).await
+    }
+
     /// Writes `report` to its interrupt endpoint.
     pub async fn write(&mut self, report: &[u8]) -> Result<(), EndpointError> {
         assert!(report.len() <= N);
@@ -472,6 +520,11 @@ pub trait RequestHandler {
     fn set_idle_ms(&mut self, id: Option<ReportId>, duration_ms: u32) {
         let _ = (id, duration_ms);
     }
+
+    /// Called after a USB reset.
+    ///
+    /// Implementations should restore the report protocol and their default idle rate.
+    fn reset(&mut self) {}
 }
 
 struct Control<'d> {
@@ -519,6 +572,9 @@ impl<'d> Control<'d> {
 impl<'d> Handler for Control<'d> {
     fn reset(&mut self) {
         self.out_report_offset.store(0, Ordering::Release);
+        if let Some(handler) = self.request_handler.as_mut() {
+            handler.reset();
+        }
     }
 
     fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
@@ -623,3 +679,82 @@ impl<'d> Handler for Control<'d> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use embassy_usb_driver::Direction;
+
+    use super::*;
+
+    /// Keeps an idle rate per input report ID, and a global one for ID 0.
+    #[derive(Default)]
+    struct IdleRates {
+        ms: [Option<u32>; 4],
+    }
+
+    impl RequestHandler for IdleRates {
+        fn get_idle_ms(&mut self, id: Option<ReportId>) -> Option<u32> {
+            match id {
+                None => self.ms[0],
+                Some(ReportId::In(id)) => self.ms[id as usize],
+                Some(_) => None,
+            }
+        }
+
+        fn set_idle_ms(&mut self, id: Option<ReportId>, duration_ms: u32) {
+            match id {
+                None => self.ms = [Some(duration_ms); 4],
+                Some(ReportId::In(id)) => self.ms[id as usize] = Some(duration_ms),
+                Some(_) => unreachable!(),
+            }
+        }
+    }
+
+    fn request(direction: Direction, request: u8, value: u16) -> Request {
+        Request {
+            direction,
+            request_type: RequestType::Class,
+            recipient: Recipient::Interface,
+            request,
+            value,
+            index: 0,
+            length: 1,
+        }
+    }
+
+    fn get_idle(control: &mut Control<'_>, id: u8) -> Option<u8> {
+        let mut buf = [0; 1];
+        match control.control_in(request(Direction::In, HID_REQ_GET_IDLE, id.into()), &mut buf) {
+            Some(InResponse::Accepted(&[dur])) => Some(dur),
+            _ => None,
+        }
+    }
+
+    fn set_idle(control: &mut Control<'_>, id: u8, dur: u8) {
+        let value = u16::from(dur) << 8 | u16::from(id);
+        control.control_out(request(Direction::Out, HID_REQ_SET_IDLE, value), &[]);
+    }
+
+    #[test]
+    fn idle_per_report_id() {
+        let mut rates = IdleRates::default();
+        let offset = AtomicUsize::new(0);
+        let mut control = Control::new(InterfaceNumber::new(0), &[], Some(&mut rates), &offset);
+
+        // No idle rate known yet.
+        assert_eq!(get_idle(&mut control, 0), None);
+
+        // Report ID 0 sets all reports, in units of 4 ms.
+        set_idle(&mut control, 0, 25);
+        assert_eq!(get_idle(&mut control, 2), Some(25));
+
+        set_idle(&mut control, 2, 5);
+        assert_eq!(get_idle(&mut control, 1), Some(25));
+        assert_eq!(get_idle(&mut control, 2), Some(5));
+
+        // A duration of 0 is indefinite, reported as 0.
+        set_idle(&mut control, 3, 0);
+        assert_eq!(get_idle(&mut control, 3), Some(0));
+        assert_eq!(rates.ms, [Some(100), Some(100), Some(20), Some(u32::MAX)]);
+    }
+}
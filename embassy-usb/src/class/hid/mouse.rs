@@ -0,0 +1,186 @@
+//! Ready-made boot-compatible HID mouse.
+//!
+//! In report protocol the mouse reports five buttons, 16-bit relative X/Y motion,
+//! a vertical wheel and a horizontal pan axis. When the host selects the boot
+//! protocol, reports are reduced to the 3-byte boot layout with motion clamped
+//! to 8 bits.
+
+use super::descriptor::{Collection, ItemFlags, ReportDescriptorBuilder, usage, usage_page};
+use super::preset::{IDLE_INDEFINITE, PresetConfig, PresetState, PresetWriter};
+use super::{HidBootProtocol, HidProtocolMode};
+use crate::Builder;
+use crate::driver::{Driver, EndpointError};
+
+/// AC Pan usage on the Consumer page, used for horizontal scrolling.
+const CONSUMER_AC_PAN: u16 = 0x238;
+
+/// Length of the input report.
+const MOUSE_REPORT_LEN: usize = 7;
+
+static MOUSE_DESCRIPTOR: ReportDescriptorBuilder<96> = ReportDescriptorBuilder::new()
+    .usage_page(usage_page::GENERIC_DESKTOP)
+    .usage(usage::MOUSE)
+    .collection(Collection::Application)
+    .usage(usage::POINTER)
+    .collection(Collection::Physical)
+    // Buttons 1-5 and padding.
+    .usage_page(usage_page::BUTTON)
+    .usage_range(1, 5)
+    .logical_range(0, 1)
+    .input_field(1, 5, ItemFlags::VARIABLE)
+    .input_field(3, 1, ItemFlags::CONSTANT)
+    // Relative X/Y.
+    .usage_page(usage_page::GENERIC_DESKTOP)
+    .usage(usage::X)
+    .usage(usage::Y)
+    .logical_range(-32767, 32767)
+    .input_field(16, 2, ItemFlags::VARIABLE.union(ItemFlags::RELATIVE))
+    // Vertical wheel.
+    .usage(usage::WHEEL)
+    .logical_range(-127, 127)
+    .input_field(8, 1, ItemFlags::VARIABLE.union(ItemFlags::RELATIVE))
+    // Horizontal pan.
+    .usage_page(usage_page::CONSUMER)
+    .usage(CONSUMER_AC_PAN)
+    .input_field(8, 1, ItemFlags::VARIABLE.union(ItemFlags::RELATIVE))
+    .end_collection()
+    .end_collection();
+
+/// Mouse report.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MouseReport {
+    /// Pressed buttons, bit 0 = button 1 (primary). Only the low five bits are used.
+    pub buttons: u8,
+    /// Relative horizontal motion.
+    pub x: i16,
+    /// Relative vertical motion.
+    pub y: i16,
+    /// Vertical wheel motion.
+    pub wheel: i8,
+    /// Horizontal scroll motion.
+    pub pan: i8,
+}
+
+impl MouseReport {
+    fn to_bytes(self) -> [u8; MOUSE_REPORT_LEN] {
+        let x = self.x.to_le_bytes();
+        let y = self.y.to_le_bytes();
+        [
+            self.buttons & 0x1F,
+            x[0],
+            x[1],
+            y[0],
+            y[1],
+            self.wheel as u8,
+            self.pan as u8,
+        ]
+    }
+
+    fn to_boot_bytes(self) -> [u8; 3] {
+        let clamp = |v: i16| v.clamp(-127, 127) as i8 as u8;
+        [self.buttons & 0x07, clamp(self.x), clamp(self.y)]
+    }
+}
+
+/// Internal state for [`BootMouse`].
+pub struct State<'d> {
+    inner: PresetState<'d>,
+}
+
+impl<'d> Default for State<'d> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'d> State<'d> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            inner: PresetState::new(),
+        }
+    }
+}
+
+/// Boot-compatible mouse with wheel and horizontal scroll.
+pub struct BootMouse<'d, D: Driver<'d>> {
+    writer: PresetWriter<'d, D, MOUSE_REPORT_LEN>,
+}
+
+impl<'d, D: Driver<'d>> BootMouse<'d, D> {
+    /// Creates a new `BootMouse` interface.
+    ///
+    /// `poll_ms` is the interrupt endpoint polling interval, see [`Config::poll_ms`](super::Config::poll_ms).
+    pub fn new<const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>(
+        builder: &mut Builder<'d, D, MAX_INTERFACES, MAX_HANDLERS>,
+        state: &'d mut State<'d>,
+        poll_ms: u8,
+    ) -> Self {
+        let config = PresetConfig {
+            report_descriptor: MOUSE_DESCRIPTOR.as_bytes(),
+            boot_protocol: HidBootProtocol::Mouse,
+            default_idle_ms: IDLE_INDEFINITE,
+            accepts_output: false,
+            max_packet_size: 8,
+        };
+        Self {
+            writer: PresetWriter::new(builder, &mut state.inner, config, poll_ms),
+        }
+    }
+
+    /// Waits for the interrupt IN endpoint to be enabled.
+    pub async fn ready(&mut self) {
+        self.writer.ready().await
+    }
+
+    /// Returns the protocol currently selected by the host.
+    pub fn protocol(&self) -> HidProtocolMode {
+        self.writer.protocol()
+    }
+
+    /// Sends `report` in the format of the current protocol.
+    ///
+    /// Motion is relative, so the report is remembered for `GET_REPORT` and idle
+    /// repeats with its motion fields cleared.
+    pub async fn write(&mut self, report: &MouseReport) -> Result<(), EndpointError> {
+        self.writer.write(&report.to_bytes(), &report.to_boot_bytes()).await?;
+        let idle = MouseReport {
+            buttons: report.buttons,
+            ..Default::default()
+        };
+        self.writer.remember(&idle.to_bytes(), &idle.to_boot_bytes());
+        Ok(())
+    }
+
+    /// Waits until the idle period set by the host has elapsed since the last report.
+    ///
+    /// Call [`resend`](Self::resend) when this completes. This future is cancel-safe.
+    pub async fn wait_idle(&mut self) {
+        self.writer.wait_idle().await
+    }
+
+    /// Sends the button state of the last report again, without motion.
+    pub async fn resend(&mut self) -> Result<(), EndpointError> {
+        self.writer.resend().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_and_boot_bytes() {
+        let report = MouseReport {
+            buttons: 0xFF,
+            x: 300,
+            y: -2,
+            wheel: -1,
+            pan: 1,
+        };
+        assert_eq!(report.to_bytes(), [0x1F, 0x2C, 0x01, 0xFE, 0xFF, 0xFF, 0x01]);
+        // Boot reports keep three buttons and clamp the motion to 8 bits.
+        assert_eq!(report.to_boot_bytes(), [0x07, 127, 0xFE]);
+    }
+}
@@ -0,0 +1,474 @@
+//! Shared plumbing for the ready-made HID device functions.
+//!
+//! Every preset is a single HID interface with one interrupt IN endpoint. Output
+//! reports (keyboard LEDs) are received through `SET_REPORT` on the control pipe,
+//! which saves an endpoint per function in composite devices.
+
+use core::cell::RefCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU8, AtomicU32, Ordering};
+
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::CriticalSectionMutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+
+use super::{Config, HidBootProtocol, HidProtocolMode, HidSubclass, HidWriter, ReportId, RequestHandler};
+use crate::Builder;
+use crate::control::OutResponse;
+use crate::driver::{Driver, EndpointError};
+
+/// Largest input report of any preset, in bytes.
+pub(super) const MAX_REPORT_LEN: usize = 32;
+/// Length of the boot protocol input reports.
+pub(super) const BOOT_REPORT_LEN: usize = 8;
+
+/// Idle rate meaning "only report on change".
+pub(super) const IDLE_INDEFINITE: u32 = u32::MAX;
+
+/// Last input report, in both report and boot protocol formats.
+struct LastInput {
+    report: [u8; MAX_REPORT_LEN],
+    report_len: usize,
+    boot: [u8; BOOT_REPORT_LEN],
+    boot_len: usize,
+}
+
+impl LastInput {
+    const fn new() -> Self {
+        Self {
+            report: [0; MAX_REPORT_LEN],
+            report_len: 0,
+            boot: [0; BOOT_REPORT_LEN],
+            boot_len: 0,
+        }
+    }
+
+    fn get(&self, protocol: HidProtocolMode) -> &[u8] {
+        match protocol {
+            HidProtocolMode::Report => &self.report[..self.report_len],
+            HidProtocolMode::Boot => &self.boot[..self.boot_len],
+        }
+    }
+}
+
+/// State shared between the request handler and the writer.
+struct Shared {
+    protocol: AtomicU8,
+    idle_ms: AtomicU32,
+    output: AtomicU8,
+    output_changed: Signal<CriticalSectionRawMutex, ()>,
+    idle_changed: Signal<CriticalSectionRawMutex, ()>,
+    input: CriticalSectionMutex<RefCell<LastInput>>,
+}
+
+impl Shared {
+    const fn new() -> Self {
+        Self {
+            protocol: AtomicU8::new(HidProtocolMode::Report as u8),
+            idle_ms: AtomicU32::new(IDLE_INDEFINITE),
+            output: AtomicU8::new(0),
+            output_changed: Signal::new(),
+            idle_changed: Signal::new(),
+            input: CriticalSectionMutex::new(RefCell::new(LastInput::new())),
+        }
+    }
+
+    fn protocol(&self) -> HidProtocolMode {
+        HidProtocolMode::from(self.protocol.load(Ordering::Relaxed))
+    }
+}
+
+/// Static description of a preset.
+pub(super) struct PresetConfig {
+    pub report_descriptor: &'static [u8],
+    pub boot_protocol: HidBootProtocol,
+    pub default_idle_ms: u32,
+    pub accepts_output: bool,
+    pub max_packet_size: u16,
+}
+
+/// Request handler implementing protocol switching, idle rate and `GET_REPORT`/`SET_REPORT`.
+struct PresetHandler<'d> {
+    shared: &'d Shared,
+    boot_supported: bool,
+    default_idle_ms: u32,
+    accepts_output: bool,
+}
+
+impl<'d> RequestHandler for PresetHandler<'d> {
+    fn get_report(&mut self, id: ReportId, buf: &mut [u8]) -> Option<usize> {
+        match id {
+            ReportId::In(_) => self.shared.input.lock(|input| {
+                let input = input.borrow();
+                let report = input.get(self.shared.protocol());
+                let len = report.len().min(buf.len());
+                buf[..len].copy_from_slice(&report[..len]);
+                Some(len)
+            }),
+            ReportId::Out(_) if self.accepts_output && !buf.is_empty() => {
+                buf[0] = self.shared.output.load(Ordering::Relaxed);
+                Some(1)
+            }
+            _ => None,
+        }
+    }
+
+    fn set_report(&mut self, id: ReportId, data: &[u8]) -> OutResponse {
+        match (id, data.first()) {
+            (ReportId::Out(_), Some(&value)) if self.accepts_output => {
+                self.shared.output.store(value, Ordering::Relaxed);
+                self.shared.output_changed.signal(());
+                OutResponse::Accepted
+            }
+            _ => OutResponse::Rejected,
+        }
+    }
+
+    fn get_protocol(&self) -> HidProtocolMode {
+        self.shared.protocol()
+    }
+
+    fn set_protocol(&mut self, protocol: HidProtocolMode) -> OutResponse {
+        if protocol == HidProtocolMode::Boot && !self.boot_supported {
+            return OutResponse::Rejected;
+        }
+        debug!("HID protocol set to {:?}", protocol);
+        self.shared.protocol.store(protocol as u8, Ordering::Relaxed);
+        OutResponse::Accepted
+    }
+
+    fn get_idle_ms(&mut self, _id: Option<ReportId>) -> Option<u32> {
+        Some(self.shared.idle_ms.load(Ordering::Relaxed))
+    }
+
+    fn set_idle_ms(&mut self, _id: Option<ReportId>, duration_ms: u32) {
+        // Every preset has a single input report, so per-report and global idle rates are the same.
+        self.shared.idle_ms.store(duration_ms, Ordering::Relaxed);
+        self.shared.idle_changed.signal(());
+    }
+
+    fn reset(&mut self) {
+        // HID 1.11, 7.2.6: devices default to the report protocol when initialized.
+        self.shared
+            .protocol
+            .store(HidProtocolMode::Report as u8, Ordering::Relaxed);
+        self.shared.idle_ms.store(self.default_idle_ms, Ordering::Relaxed);
+        self.shared.idle_changed.signal(());
+        if self.accepts_output {
+            self.shared.output.store(0, Ordering::Relaxed);
+            self.shared.output_changed.signal(());
+        }
+    }
+}
+
+/// Internal state of a preset.
+pub(super) struct PresetState<'d> {
+    hid: super::State<'d>,
+    handler: MaybeUninit<PresetHandler<'d>>,
+    shared: Shared,
+}
+
+impl<'d> PresetState<'d> {
+    pub const fn new() -> Self {
+        Self {
+            hid: super::State::new(),
+            handler: MaybeUninit::uninit(),
+            shared: Shared::new(),
+        }
+    }
+}
+
+/// Writer for a preset, taking care of protocol selection and idle repeats.
+///
+/// `N` is the length of the report protocol input report.
+pub(super) struct PresetWriter<'d, D: Driver<'d>, const N: usize> {
+    writer: HidWriter<'d, D, N>,
+    shared: &'d Shared,
+    last_write: Instant,
+}
+
+impl<'d, D: Driver<'d>, const N: usize> PresetWriter<'d, D, N> {
+    pub fn new<const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>(
+        builder: &mut Builder<'d, D, MAX_INTERFACES, MAX_HANDLERS>,
+        state: &'d mut PresetState<'d>,
+        config: PresetConfig,
+        poll_ms: u8,
+    ) -> Self {
+        let PresetState { hid, handler, shared } = state;
+
+        let boot_supported = config.boot_protocol != HidBootProtocol::None;
+        shared.idle_ms.store(config.default_idle_ms, Ordering::Relaxed);
+        let shared = &*shared;
+        let handler = handler.write(PresetHandler {
+            shared,
+            boot_supported,
+            default_idle_ms: config.default_idle_ms,
+            accepts_output: config.accepts_output,
+        });
+
+        let writer = HidWriter::new(
+            builder,
+            hid,
+            Config {
+                report_descriptor: config.report_descriptor,
+                request_handler: Some(handler),
+                poll_ms,
+                max_packet_size: config.max_packet_size,
+                hid_subclass: if boot_supported {
+                    HidSubclass::Boot
+                } else {
+                    HidSubclass::No
+                },
+                hid_boot_protocol: config.boot_protocol,
+            },
+        );
+
+        Self {
+            writer,
+            shared,
+            last_write: Instant::MIN,
+        }
+    }
+
+    pub async fn ready(&mut self) {
+        self.writer.ready().await
+    }
+
+    pub fn protocol(&self) -> HidProtocolMode {
+        self.shared.protocol()
+    }
+
+    pub fn idle_ms(&self) -> Option<u32> {
+        let idle_ms = self.shared.idle_ms.load(Ordering::Relaxed);
+        (idle_ms != IDLE_INDEFINITE).then_some(idle_ms)
+    }
+
+    pub fn output(&self) -> u8 {
+        self.shared.output.load(Ordering::Relaxed)
+    }
+
+    pub async fn wait_output_changed(&self) -> u8 {
+        self.shared.output_changed.wait().await;
+        self.output()
+    }
+
+    /// Store both encodings of a new report and send the one matching the current protocol.
+    pub async fn write(&mut self, report: &[u8; N], boot: &[u8]) -> Result<(), EndpointError> {
+        self.remember(report, boot);
+        self.resend().await
+    }
+
+    /// Store both encodings of a report for `GET_REPORT` and idle repeats, without sending it.
+    pub fn remember(&mut self, report: &[u8; N], boot: &[u8]) {
+        self.shared.input.lock(|input| {
+            let mut input = input.borrow_mut();
+            input.report[..report.len()].copy_from_slice(report);
+            input.report_len = report.len();
+            input.boot[..boot.len()].copy_from_slice(boot);
+            input.boot_len = boot.len();
+        });
+    }
+
+    /// Send the last report again, in the current protocol's format.
+    pub async fn resend(&mut self) -> Result<(), EndpointError> {
+        let mut buf = [0; MAX_REPORT_LEN];
+        let len = self.shared.input.lock(|input| {
+            let input = input.borrow();
+            let report = input.get(self.shared.protocol());
+            buf[..report.len()].copy_from_slice(report);
+            report.len()
+        });
+        self.last_write = Instant::now();
+        self.writer.write(&buf[..len]).await
+    }
+
+    /// Wait until the idle period has elapsed since the last report was sent.
+    ///
+    /// Never completes while the idle rate is indefinite. This future is cancel-safe.
+    pub async fn wait_idle(&mut self) {
+        loop {
+            self.shared.idle_changed.reset();
+            let Some(idle_ms) = self.idle_ms() else {
+                self.shared.idle_changed.wait().await;
+                continue;
+            };
+            let deadline = self.last_write.saturating_add(Duration::from_millis(idle_ms.into()));
+            match select(Timer::at(deadline), self.shared.idle_changed.wait()).await {
+                Either::First(()) => return,
+                Either::Second(()) => continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicUsize;
+
+    use embassy_usb_driver::Direction;
+
+    use super::*;
+    use crate::Handler as _;
+    use crate::class::hid::Control;
+    use crate::control::{InResponse, Recipient, Request, RequestType};
+    use crate::types::InterfaceNumber;
+
+    const SET_IDLE: u8 = 0x0a;
+    const GET_IDLE: u8 = 0x02;
+    const GET_REPORT: u8 = 0x01;
+    const SET_REPORT: u8 = 0x09;
+    const GET_PROTOCOL: u8 = 0x03;
+    const SET_PROTOCOL: u8 = 0x0b;
+
+    fn request(direction: Direction, request: u8, value: u16, length: u16) -> Request {
+        Request {
+            direction,
+            request_type: RequestType::Class,
+            recipient: Recipient::Interface,
+            request,
+            value,
+            index: 0,
+            length,
+        }
+    }
+
+    fn preset_handler(shared: &Shared, boot_supported: bool, accepts_output: bool) -> PresetHandler<'_> {
+        PresetHandler {
+            shared,
+            boot_supported,
+            default_idle_ms: 500,
+            accepts_output,
+        }
+    }
+
+    fn preset_control<'d>(handler: &'d mut PresetHandler<'d>, offset: &'d AtomicUsize) -> Control<'d> {
+        Control::new(InterfaceNumber::new(0), &[], Some(handler), offset)
+    }
+
+    fn control_out(control: &mut Control<'_>, req: u8, value: u16, data: &[u8]) -> Option<OutResponse> {
+        control.control_out(request(Direction::Out, req, value, data.len() as u16), data)
+    }
+
+    fn control_in(control: &mut Control<'_>, req: u8, value: u16) -> Option<heapless::Vec<u8, MAX_REPORT_LEN>> {
+        let mut buf = [0; MAX_REPORT_LEN];
+        match control.control_in(request(Direction::In, req, value, buf.len() as u16), &mut buf) {
+            Some(InResponse::Accepted(data)) => Some(heapless::Vec::from_slice(data).unwrap()),
+            _ => None,
+        }
+    }
+
+    fn remember(shared: &Shared, report: &[u8], boot: &[u8]) {
+        shared.input.lock(|input| {
+            let mut input = input.borrow_mut();
+            input.report[..report.len()].copy_from_slice(report);
+            input.report_len = report.len();
+            input.boot[..boot.len()].copy_from_slice(boot);
+            input.boot_len = boot.len();
+        });
+    }
+
+    #[test]
+    fn protocol_switching() {
+        let shared = Shared::new();
+        remember(&shared, &[1, 2, 3, 4, 5, 6, 7, 8, 9], &[1, 0, 4, 0, 0, 0, 0, 0]);
+        let offset = AtomicUsize::new(0);
+        let mut handler = preset_handler(&shared, true, true);
+        let mut control = preset_control(&mut handler, &offset);
+
+        assert_eq!(control_in(&mut control, GET_PROTOCOL, 0).unwrap(), [1]);
+        assert_eq!(
+            control_in(&mut control, GET_REPORT, 0x0100).unwrap(),
+            [1, 2, 3, 4, 5, 6, 7, 8, 9]
+        );
+
+        assert!(matches!(
+            control_out(&mut control, SET_PROTOCOL, 0, &[]),
+            Some(OutResponse::Accepted)
+        ));
+        assert_eq!(control_in(&mut control, GET_PROTOCOL, 0).unwrap(), [0]);
+        assert_eq!(
+            control_in(&mut control, GET_REPORT, 0x0100).unwrap(),
+            [1, 0, 4, 0, 0, 0, 0, 0]
+        );
+
+        // A USB reset restores the report protocol.
+        control.reset();
+        assert_eq!(control_in(&mut control, GET_PROTOCOL, 0).unwrap(), [1]);
+        assert_eq!(
+            control_in(&mut control, GET_REPORT, 0x0100).unwrap(),
+            [1, 2, 3, 4, 5, 6, 7, 8, 9]
+        );
+    }
+
+    #[test]
+    fn boot_protocol_unsupported() {
+        let shared = Shared::new();
+        let offset = AtomicUsize::new(0);
+        let mut handler = preset_handler(&shared, false, false);
+        let mut control = preset_control(&mut handler, &offset);
+
+        assert!(matches!(
+            control_out(&mut control, SET_PROTOCOL, 0, &[]),
+            Some(OutResponse::Rejected)
+        ));
+        assert_eq!(control_in(&mut control, GET_PROTOCOL, 0).unwrap(), [1]);
+    }
+
+    #[test]
+    fn idle_rate() {
+        let shared = Shared::new();
+        shared.idle_ms.store(500, Ordering::Relaxed);
+        let offset = AtomicUsize::new(0);
+        let mut handler = preset_handler(&shared, true, true);
+        let mut control = preset_control(&mut handler, &offset);
+
+        // 500 ms is reported in units of 4 ms.
+        assert_eq!(control_in(&mut control, GET_IDLE, 0).unwrap(), [125]);
+
+        control_out(&mut control, SET_IDLE, 24 << 8, &[]);
+        assert_eq!(shared.idle_ms.load(Ordering::Relaxed), 96);
+        assert!(shared.idle_changed.signaled());
+        assert_eq!(control_in(&mut control, GET_IDLE, 0).unwrap(), [24]);
+
+        // The idle rate of the single input report is also its per-report rate.
+        control_out(&mut control, SET_IDLE, 10 << 8, &[]);
+        assert_eq!(control_in(&mut control, GET_IDLE, 0).unwrap(), [10]);
+
+        // Zero means indefinite, only reporting on change.
+        control_out(&mut control, SET_IDLE, 0, &[]);
+        assert_eq!(shared.idle_ms.load(Ordering::Relaxed), IDLE_INDEFINITE);
+        assert_eq!(control_in(&mut control, GET_IDLE, 0).unwrap(), [0]);
+
+        control.reset();
+        assert_eq!(shared.idle_ms.load(Ordering::Relaxed), 500);
+    }
+
+    #[test]
+    fn output_report() {
+        let shared = Shared::new();
+        let offset = AtomicUsize::new(0);
+        let mut handler = preset_handler(&shared, true, true);
+        let mut control = preset_control(&mut handler, &offset);
+
+        assert!(!shared.output_changed.signaled());
+        assert!(matches!(
+            control_out(&mut control, SET_REPORT, 0x0200, &[0x02]),
+            Some(OutResponse::Accepted)
+        ));
+        assert_eq!(shared.output.load(Ordering::Relaxed), 0x02);
+        assert!(shared.output_changed.signaled());
+        assert_eq!(control_in(&mut control, GET_REPORT, 0x0200).unwrap(), [0x02]);
+
+        // Presets without an output report reject it.
+        let shared = Shared::new();
+        let mut handler = preset_handler(&shared, false, false);
+        let mut control = preset_control(&mut handler, &offset);
+        assert!(matches!(
+            control_out(&mut control, SET_REPORT, 0x0200, &[0x02]),
+            Some(OutResponse::Rejected)
+        ));
+        assert!(!shared.output_changed.signaled());
+    }
+}
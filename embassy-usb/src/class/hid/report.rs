@@ -0,0 +1,223 @@
+//! Typed HID report encoding and decoding.
+//!
+//! [`ReportWriter`] and [`ReportReader`] pack and unpack report fields in
+//! the bit order mandated by the HID specification (least significant bit
+//! first), so a report laid out with the
+//! [`ReportDescriptorBuilder`](super::descriptor::ReportDescriptorBuilder)
+//! can be serialized field by field in the same order it was declared.
+//!
+//! Types implementing [`EncodeReport`] can be sent with
+//! [`HidWriter::write_report`](super::HidWriter::write_report), and output or
+//! feature reports received from the host can be turned into types
+//! implementing [`DecodeReport`] with [`decode_report`].
+
+/// Error when encoding or decoding a HID report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ReportError {
+    /// The buffer is too small for the report.
+    BufferOverflow,
+    /// A field size is not in `1..=32` bits.
+    InvalidFieldSize,
+    /// The report ID prefix does not match the expected report ID.
+    WrongReportId,
+}
+
+/// Bit-level writer for HID report payloads.
+///
+/// Fields are appended least significant bit first. The buffer is cleared on
+/// creation, so padding fields can simply be skipped with [`pad`](Self::pad).
+pub struct ReportWriter<'a> {
+    buf: &'a mut [u8],
+    bit_pos: usize,
+}
+
+impl<'a> ReportWriter<'a> {
+    /// Create a writer for a report without report ID.
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        buf.fill(0);
+        Self { buf, bit_pos: 0 }
+    }
+
+    /// Create a writer that first emits the one-byte `report_id` prefix.
+    ///
+    /// A `report_id` of 0 means the report has no ID prefix.
+    pub fn with_report_id(buf: &'a mut [u8], report_id: u8) -> Result<Self, ReportError> {
+        let mut w = Self::new(buf);
+        if report_id != 0 {
+            w.write_unsigned(report_id as u32, 8)?;
+        }
+        Ok(w)
+    }
+
+    /// Write the low `bits` bits of `value`.
+    pub fn write_unsigned(&mut self, value: u32, bits: u8) -> Result<(), ReportError> {
+        if bits == 0 || bits > 32 {
+            return Err(ReportError::InvalidFieldSize);
+        }
+        let bits = bits as usize;
+        if self.bit_pos + bits > self.buf.len() * 8 {
+            return Err(ReportError::BufferOverflow);
+        }
+        let value = if bits == 32 { value } else { value & ((1 << bits) - 1) };
+        for i in 0..bits {
+            if value & (1 << i) != 0 {
+                let pos = self.bit_pos + i;
+                self.buf[pos / 8] |= 1 << (pos % 8);
+            }
+        }
+        self.bit_pos += bits;
+        Ok(())
+    }
+
+    /// Write `value` as a two's-complement field of `bits` bits.
+    pub fn write_signed(&mut self, value: i32, bits: u8) -> Result<(), ReportError> {
+        self.write_unsigned(value as u32, bits)
+    }
+
+    /// Write a single-bit field.
+    pub fn write_bool(&mut self, value: bool) -> Result<(), ReportError> {
+        self.write_unsigned(value as u32, 1)
+    }
+
+    /// Write whole bytes, e.g. an array field of 8-bit elements.
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), ReportError> {
+        for &b in bytes {
+            self.write_unsigned(b as u32, 8)?;
+        }
+        Ok(())
+    }
+
+    /// Skip `bits` bits of constant padding (left at zero).
+    pub fn pad(&mut self, bits: usize) -> Result<(), ReportError> {
+        if self.bit_pos + bits > self.buf.len() * 8 {
+            return Err(ReportError::BufferOverflow);
+        }
+        self.bit_pos += bits;
+        Ok(())
+    }
+
+    /// Returns the number of bits written so far, including the report ID prefix.
+    pub fn bit_position(&self) -> usize {
+        self.bit_pos
+    }
+
+    /// Finish writing and return the report length in bytes.
+    pub fn finish(self) -> usize {
+        self.bit_pos.div_ceil(8)
+    }
+}
+
+/// Bit-level reader for HID report payloads.
+pub struct ReportReader<'a> {
+    buf: &'a [u8],
+    bit_pos: usize,
+}
+
+impl<'a> ReportReader<'a> {
+    /// Create a reader for a report without report ID.
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, bit_pos: 0 }
+    }
+
+    /// Create a reader that first checks and consumes the one-byte `report_id` prefix.
+    ///
+    /// A `report_id` of 0 means the report has no ID prefix.
+    pub fn with_report_id(buf: &'a [u8], report_id: u8) -> Result<Self, ReportError> {
+        let mut r = Self::new(buf);
+        if report_id != 0 && r.read_unsigned(8)? != report_id as u32 {
+            return Err(ReportError::WrongReportId);
+        }
+        Ok(r)
+    }
+
+    /// Read an unsigned field of `bits` bits.
+    pub fn read_unsigned(&mut self, bits: u8) -> Result<u32, ReportError> {
+        if bits == 0 || bits > 32 {
+            return Err(ReportError::InvalidFieldSize);
+        }
+        let bits = bits as usize;
+        if self.bit_pos + bits > self.buf.len() * 8 {
+            return Err(ReportError::BufferOverflow);
+        }
+        let mut value = 0u32;
+        for i in 0..bits {
+            let pos = self.bit_pos + i;
+            if self.buf[pos / 8] & (1 << (pos % 8)) != 0 {
+                value |= 1 << i;
+            }
+        }
+        self.bit_pos += bits;
+        Ok(value)
+    }
+
+    /// Read a two's-complement field of `bits` bits, sign-extending it.
+    pub fn read_signed(&mut self, bits: u8) -> Result<i32, ReportError> {
+        let raw = self.read_unsigned(bits)?;
+        if bits < 32 && raw & (1 << (bits - 1)) != 0 {
+            Ok((raw | (u32::MAX << bits)) as i32)
+        } else {
+            Ok(raw as i32)
+        }
+    }
+
+    /// Read a single-bit field.
+    pub fn read_bool(&mut self) -> Result<bool, ReportError> {
+        Ok(self.read_unsigned(1)? != 0)
+    }
+
+    /// Read whole bytes, e.g. an array field of 8-bit elements.
+    pub fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<(), ReportError> {
+        for b in bytes {
+            *b = self.read_unsigned(8)? as u8;
+        }
+        Ok(())
+    }
+
+    /// Skip `bits` bits of padding.
+    pub fn skip(&mut self, bits: usize) -> Result<(), ReportError> {
+        if self.bit_pos + bits > self.buf.len() * 8 {
+            return Err(ReportError::BufferOverflow);
+        }
+        self.bit_pos += bits;
+        Ok(())
+    }
+
+    /// Returns the number of bits consumed so far, including the report ID prefix.
+    pub fn bit_position(&self) -> usize {
+        self.bit_pos
+    }
+}
+
+/// A report that can be serialized into its wire format.
+pub trait EncodeReport {
+    /// Report ID of this report, or 0 if the descriptor does not use report IDs.
+    const REPORT_ID: u8 = 0;
+
+    /// Write the report fields, in descriptor order, excluding the report ID prefix.
+    fn encode(&self, writer: &mut ReportWriter<'_>) -> Result<(), ReportError>;
+}
+
+/// A report that can be deserialized from its wire format.
+pub trait DecodeReport: Sized {
+    /// Report ID of this report, or 0 if the descriptor does not use report IDs.
+    const REPORT_ID: u8 = 0;
+
+    /// Read the report fields, in descriptor order, excluding the report ID prefix.
+    fn decode(reader: &mut ReportReader<'_>) -> Result<Self, ReportError>;
+}
+
+/// Encode `report` into `buf`, including the report ID prefix if any.
+///
+/// Returns the number of bytes written.
+pub fn encode_report<R: EncodeReport + ?Sized>(report: &R, buf: &mut [u8]) -> Result<usize, ReportError> {
+    let mut writer = ReportWriter::with_report_id(buf, R::REPORT_ID)?;
+    report.encode(&mut writer)?;
+    Ok(writer.finish())
+}
+
+/// Decode a report from `buf`, checking the report ID prefix if any.
+pub fn decode_report<R: DecodeReport>(buf: &[u8]) -> Result<R, ReportError> {
+    let mut reader = ReportReader::with_report_id(buf, R::REPORT_ID)?;
+    R::decode(&mut reader)
+}
@@ -1,9 +1,12 @@
 //! Implementations of well-known USB classes.
+pub mod ccid;
 pub mod cdc_acm;
 pub mod cdc_ncm;
 pub mod cmsis_dap_v2;
 pub mod dfu;
 pub mod hid;
 pub mod midi;
+pub mod printer;
 pub mod uac1;
+pub mod uvc;
 pub mod web_usb;
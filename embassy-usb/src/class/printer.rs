@@ -0,0 +1,411 @@
+//! USB Printer class implementation.
+//!
+//! Implements the device side of the USB Printing Devices class 1.1: the
+//! `GET_DEVICE_ID`, `GET_PORT_STATUS` and `SOFT_RESET` class requests, a bulk OUT
+//! endpoint for print data and, for bidirectional printers, a bulk IN endpoint
+//! for status data sent back to the host.
+
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::task::Poll;
+
+use embassy_sync::waitqueue::WakerRegistration;
+
+use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+/// This should be used as `device_class` when building the `UsbDevice`.
+pub const USB_CLASS_PRINTER: u8 = 0x07;
+
+const PRINTER_SUBCLASS: u8 = 0x01;
+const PRINTER_PROTOCOL_UNIDIRECTIONAL: u8 = 0x01;
+const PRINTER_PROTOCOL_BIDIRECTIONAL: u8 = 0x02;
+
+const REQ_GET_DEVICE_ID: u8 = 0x00;
+const REQ_GET_PORT_STATUS: u8 = 0x01;
+const REQ_SOFT_RESET: u8 = 0x02;
+
+/// Configuration for the printer class.
+pub struct Config<'d> {
+    /// IEEE 1284 device ID string, without the length prefix.
+    ///
+    /// For example `"MFG:Embassy;MDL:Label Printer;CMD:ZPL;CLS:PRINTER;"`.
+    pub device_id: &'d str,
+
+    /// Max packet size for the bulk endpoints.
+    pub max_packet_size: u16,
+
+    /// Whether the printer sends data back to the host over a bulk IN endpoint.
+    pub bidirectional: bool,
+}
+
+/// Printer port status, as returned by `GET_PORT_STATUS`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PortStatus {
+    /// The printer is out of paper.
+    pub paper_empty: bool,
+    /// The printer is selected (online).
+    pub selected: bool,
+    /// The printer is in an error state.
+    pub error: bool,
+}
+
+impl Default for PortStatus {
+    fn default() -> Self {
+        Self {
+            paper_empty: false,
+            selected: true,
+            error: false,
+        }
+    }
+}
+
+impl PortStatus {
+    const PAPER_EMPTY: u8 = 1 << 5;
+    const SELECT: u8 = 1 << 4;
+    const NOT_ERROR: u8 = 1 << 3;
+
+    const fn to_bits(self) -> u8 {
+        let mut bits = 0;
+        if self.paper_empty {
+            bits |= Self::PAPER_EMPTY;
+        }
+        if self.selected {
+            bits |= Self::SELECT;
+        }
+        if !self.error {
+            bits |= Self::NOT_ERROR;
+        }
+        bits
+    }
+
+    const fn from_bits(bits: u8) -> Self {
+        Self {
+            paper_empty: bits & Self::PAPER_EMPTY != 0,
+            selected: bits & Self::SELECT != 0,
+            error: bits & Self::NOT_ERROR == 0,
+        }
+    }
+}
+
+/// Internal state for the printer class.
+pub struct State<'d> {
+    control: MaybeUninit<Control<'d>>,
+    shared: ControlShared,
+}
+
+impl<'d> Default for State<'d> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'d> State<'d> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+            shared: ControlShared::new(),
+        }
+    }
+}
+
+/// Shared data between Control and PrinterClass
+struct ControlShared {
+    port_status: AtomicU8,
+    soft_reset: AtomicBool,
+    waker: RefCell<WakerRegistration>,
+}
+
+impl ControlShared {
+    const fn new() -> Self {
+        Self {
+            port_status: AtomicU8::new(
+                PortStatus {
+                    paper_empty: false,
+                    selected: true,
+                    error: false,
+                }
+                .to_bits(),
+            ),
+            soft_reset: AtomicBool::new(false),
+            waker: RefCell::new(WakerRegistration::new()),
+        }
+    }
+}
+
+struct Control<'d> {
+    if_num: InterfaceNumber,
+    device_id: &'d str,
+    shared: &'d ControlShared,
+}
+
+impl<'d> Handler for Control<'d> {
+    fn reset(&mut self) {
+        self.shared.soft_reset.store(false, Ordering::Relaxed);
+    }
+
+    fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.if_num.0 as u16)
+        {
+            return None;
+        }
+
+        match req.request {
+            REQ_SOFT_RESET => {
+                debug!("Printer soft reset");
+                self.shared.soft_reset.store(true, Ordering::Relaxed);
+                self.shared.waker.borrow_mut().wake();
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient) != (RequestType::Class, Recipient::Interface) {
+            return None;
+        }
+
+        match req.request {
+            // GET_DEVICE_ID carries the interface number in the high byte of wIndex,
+            // and the alternate setting in the low byte.
+            REQ_GET_DEVICE_ID if req.index >> 8 == self.if_num.0 as u16 => {
+                let id = self.device_id.as_bytes();
+                let len = id.len() + 2;
+                // The length prefix is big-endian and includes itself.
+                buf[..2].copy_from_slice(&(len as u16).to_be_bytes());
+                buf[2..len].copy_from_slice(id);
+                Some(InResponse::Accepted(&buf[..len]))
+            }
+            REQ_GET_PORT_STATUS if req.index == self.if_num.0 as u16 => {
+                buf[0] = self.shared.port_status.load(Ordering::Relaxed);
+                Some(InResponse::Accepted(&buf[..1]))
+            }
+            _ if req.index == self.if_num.0 as u16 => Some(InResponse::Rejected),
+            _ => None,
+        }
+    }
+}
+
+/// Packet level implementation of a USB printer.
+pub struct PrinterClass<'d, D: Driver<'d>> {
+    read_ep: D::EndpointOut,
+    write_ep: Option<D::EndpointIn>,
+    control: &'d ControlShared,
+}
+
+impl<'d, D: Driver<'d>> PrinterClass<'d, D> {
+    /// Creates a new `PrinterClass`.
+    ///
+    /// The control buffer of the `builder` must be large enough for the device ID
+    /// string plus its two-byte length prefix.
+    pub fn new<const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>(
+        builder: &mut Builder<'d, D, MAX_INTERFACES, MAX_HANDLERS>,
+        state: &'d mut State<'d>,
+        config: Config<'d>,
+    ) -> Self {
+        assert!(config.device_id.len() + 2 <= builder.control_buf_len());
+        assert!(config.device_id.len() + 2 <= u16::MAX as usize);
+
+        let protocol = if config.bidirectional {
+            PRINTER_PROTOCOL_BIDIRECTIONAL
+        } else {
+            PRINTER_PROTOCOL_UNIDIRECTIONAL
+        };
+
+        let mut func = builder.function(USB_CLASS_PRINTER, PRINTER_SUBCLASS, protocol);
+        let mut iface = func.interface();
+        let if_num = iface.interface_number();
+        let mut alt = iface.alt_setting(USB_CLASS_PRINTER, PRINTER_SUBCLASS, protocol, None);
+        let read_ep = alt.endpoint_bulk_out(None, config.max_packet_size);
+        let write_ep = config
+            .bidirectional
+            .then(|| alt.endpoint_bulk_in(None, config.max_packet_size));
+        drop(func);
+
+        let control = state.control.write(Control {
+            if_num,
+            device_id: config.device_id,
+            shared: &state.shared,
+        });
+        builder.handler(control);
+
+        Self {
+            read_ep,
+            write_ep,
+            control: &state.shared,
+        }
+    }
+
+    /// Gets the maximum packet size in bytes.
+    pub fn max_packet_size(&self) -> u16 {
+        self.read_ep.info().max_packet_size
+    }
+
+    /// Gets the port status reported to the host.
+    pub fn port_status(&self) -> PortStatus {
+        PortStatus::from_bits(self.control.port_status.load(Ordering::Relaxed))
+    }
+
+    /// Sets the port status reported to the host.
+    pub fn set_port_status(&self, status: PortStatus) {
+        self.control.port_status.store(status.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Waits until the host issues a `SOFT_RESET` request.
+    ///
+    /// The application should then discard any buffered print data.
+    pub async fn wait_soft_reset(&self) {
+        poll_fn(|cx| {
+            if self.control.soft_reset.swap(false, Ordering::Relaxed) {
+                Poll::Ready(())
+            } else {
+                self.control.waker.borrow_mut().register(cx.waker());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Reads a single packet of print data from the OUT endpoint.
+    ///
+    /// Must be called with a buffer large enough to hold `max_packet_size` bytes.
+    pub async fn read_packet(&mut self, data: &mut [u8]) -> Result<usize, EndpointError> {
+        self.read_ep.read(data).await
+    }
+
+    /// Writes a single packet of status data into the IN endpoint.
+    ///
+    /// Returns [`EndpointError::Disabled`] if the printer is not bidirectional.
+    pub async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        match self.write_ep.as_mut() {
+            Some(ep) => ep.write(data).await,
+            None => Err(EndpointError::Disabled),
+        }
+    }
+
+    /// Waits for the USB host to enable this interface
+    pub async fn wait_connection(&mut self) {
+        self.read_ep.wait_enabled().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_usb_driver::Direction;
+
+    use super::*;
+
+    fn request(direction: Direction, request: u8, index: u16, length: u16) -> Request {
+        Request {
+            direction,
+            request_type: RequestType::Class,
+            recipient: Recipient::Interface,
+            request,
+            value: 0,
+            index,
+            length,
+        }
+    }
+
+    fn control<'d>(shared: &'d ControlShared) -> Control<'d> {
+        Control {
+            if_num: InterfaceNumber::new(1),
+            device_id: "MFG:Embassy;MDL:Test;CLS:PRINTER;",
+            shared,
+        }
+    }
+
+    #[test]
+    fn device_id() {
+        let shared = ControlShared::new();
+        let mut control = control(&shared);
+        let mut buf = [0; 64];
+
+        // Interface 1, alternate setting 0.
+        match control.control_in(request(Direction::In, REQ_GET_DEVICE_ID, 0x0100, 64), &mut buf) {
+            Some(InResponse::Accepted(data)) => {
+                assert_eq!(data[..2], [0x00, 35]);
+                assert_eq!(&data[2..], b"MFG:Embassy;MDL:Test;CLS:PRINTER;");
+            }
+            _ => panic!("GET_DEVICE_ID rejected"),
+        }
+
+        // Another interface.
+        assert!(
+            control
+                .control_in(request(Direction::In, REQ_GET_DEVICE_ID, 0x0000, 64), &mut buf)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn device_id_length_big_endian() {
+        let id = [b'x'; 300];
+        let shared = ControlShared::new();
+        let mut control = Control {
+            if_num: InterfaceNumber::new(0),
+            device_id: core::str::from_utf8(&id).unwrap(),
+            shared: &shared,
+        };
+        let mut buf = [0; 512];
+        match control.control_in(request(Direction::In, REQ_GET_DEVICE_ID, 0, 512), &mut buf) {
+            Some(InResponse::Accepted(data)) => {
+                assert_eq!(data.len(), 302);
+                assert_eq!(data[..2], [0x01, 0x2E]);
+            }
+            _ => panic!("GET_DEVICE_ID rejected"),
+        }
+    }
+
+    #[test]
+    fn port_status() {
+        assert_eq!(PortStatus::default().to_bits(), 0x18);
+        let status = PortStatus {
+            paper_empty: true,
+            selected: false,
+            error: true,
+        };
+        assert_eq!(status.to_bits(), 0x20);
+        assert_eq!(PortStatus::from_bits(0x20), status);
+
+        let shared = ControlShared::new();
+        shared.port_status.store(status.to_bits(), Ordering::Relaxed);
+        let mut control = control(&shared);
+        let mut buf = [0; 1];
+        match control.control_in(request(Direction::In, REQ_GET_PORT_STATUS, 1, 1), &mut buf) {
+            Some(InResponse::Accepted(data)) => assert_eq!(data, [0x20]),
+            _ => panic!("GET_PORT_STATUS rejected"),
+        }
+    }
+
+    #[test]
+    fn soft_reset() {
+        let shared = ControlShared::new();
+        let mut control = control(&shared);
+
+        assert!(matches!(
+            control.control_out(request(Direction::Out, REQ_SOFT_RESET, 1, 0), &[]),
+            Some(OutResponse::Accepted)
+        ));
+        assert!(shared.soft_reset.load(Ordering::Relaxed));
+
+        // A USB reset forgets a pending soft reset.
+        control.reset();
+        assert!(!shared.soft_reset.load(Ordering::Relaxed));
+
+        // Requests for other interfaces are not handled.
+        assert!(
+            control
+                .control_out(request(Direction::Out, REQ_SOFT_RESET, 0, 0), &[])
+                .is_none()
+        );
+    }
+}
@@ -110,8 +110,8 @@ pub struct AudioSource<'d, D: Driver<'d>> {
 
 impl<'d, D: Driver<'d>> AudioSource<'d, D> {
     /// Create the Audio Control interface descriptors
-    fn create_control_function(
-        b: &mut InterfaceAltBuilder<'_, 'd, D>,
+    fn create_control_function<const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>(
+        b: &mut InterfaceAltBuilder<'_, 'd, D, MAX_INTERFACES, MAX_HANDLERS>,
         streaming_interface: u8,
         terminal_type: Option<TerminalType>,
     ) {
@@ -197,8 +197,8 @@ impl<'d, D: Driver<'d>> AudioSource<'d, D> {
         b.descriptor(CS_INTERFACE, &output_terminal_descriptor);
     }
 
-    fn create_streaming_iface_active(
-        b: &mut InterfaceAltBuilder<'_, 'd, D>,
+    fn create_streaming_iface_active<const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>(
+        b: &mut InterfaceAltBuilder<'_, 'd, D, MAX_INTERFACES, MAX_HANDLERS>,
         sample_rates: &[u32],
         sample_width: SampleWidth,
         feedback_refresh_period_ms: u8,
@@ -305,8 +305,8 @@ impl<'d, D: Driver<'d>> AudioSource<'d, D> {
     }
 
     /// Create a new Audio Source interface with control and streaming endpoints    
-    pub fn new(
-        b: &mut Builder<'d, D>,
+    pub fn new<const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>(
+        b: &mut Builder<'d, D, MAX_INTERFACES, MAX_HANDLERS>,
         sample_rates: &'static [u32],
         sample_width: SampleWidth,
         fedback_refresh_period_ms: u8,
@@ -108,8 +108,8 @@ impl<'d, D: Driver<'d>> Speaker<'d, D> {
     /// * `sample_rates_hz` - The supported sample rates in Hz.
     /// * `channels` - The advertised audio channels (up to 12). Entries must be unique, or this function panics.
     /// * `feedback_refresh_period` - The refresh period for the feedback value.
-    pub fn new(
-        builder: &mut Builder<'d, D>,
+    pub fn new<const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>(
+        builder: &mut Builder<'d, D, MAX_INTERFACES, MAX_HANDLERS>,
         state: &'d mut State<'d>,
         max_packet_size: u16,
         resolution: SampleWidth,
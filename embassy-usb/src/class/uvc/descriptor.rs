@@ -0,0 +1,326 @@
+//! Class-specific descriptors of the video control and video streaming interfaces.
+//!
+//! Descriptors are produced without their `bLength` and `bDescriptorType` fields, as
+//! expected by [`InterfaceAltBuilder::descriptor`](crate::builder::InterfaceAltBuilder::descriptor).
+
+use heapless::Vec;
+
+use super::class_codes::*;
+use super::{Encoding, Format, Frame, MAX_FORMATS, MAX_FRAME_INTERVALS};
+
+/// Arbitrary unique identifier for the camera terminal.
+pub(super) const CAMERA_TERMINAL_ID: u8 = 0x01;
+
+/// Arbitrary unique identifier for the streaming output terminal.
+pub(super) const OUTPUT_TERMINAL_ID: u8 = 0x02;
+
+/// `bLength` and `bDescriptorType`, not part of the bodies below.
+const DESCRIPTOR_HEADER_LEN: usize = 2;
+
+/// Largest descriptor body, a frame descriptor with all intervals.
+const MAX_BODY_LEN: usize = 24 + 4 * MAX_FRAME_INTERVALS;
+
+pub(super) type Body = Vec<u8, MAX_BODY_LEN>;
+
+fn body(bytes: &[u8]) -> Body {
+    Vec::from_slice(bytes).unwrap()
+}
+
+/// Calls `f` with the descriptors of the video control interface.
+pub(super) fn vc_descriptors(streaming_interface: u8, mut f: impl FnMut(&[u8])) {
+    let camera_terminal = camera_terminal();
+    let output_terminal = output_terminal();
+
+    // USB Device Class Definition for Video Devices
+    // 3.7.2 Class-Specific VC Interface Header Descriptor
+    const VC_HEADER_LEN: usize = 11;
+    let total_len = (VC_HEADER_LEN + camera_terminal.len() + output_terminal.len() + 3 * DESCRIPTOR_HEADER_LEN) as u16;
+    let clock = 48_000_000u32.to_le_bytes();
+    let header: [u8; VC_HEADER_LEN] = [
+        VC_HEADER,                // bDescriptorSubtype
+        UVC_VERSION as u8,        // bcdUVC[0]
+        (UVC_VERSION >> 8) as u8, // bcdUVC[1]
+        total_len as u8,          // wTotalLength[0]
+        (total_len >> 8) as u8,   // wTotalLength[1]
+        clock[0],                 // dwClockFrequency
+        clock[1],
+        clock[2],
+        clock[3],
+        0x01,                // bInCollection (1 streaming interface)
+        streaming_interface, // baInterfaceNr
+    ];
+
+    f(&header);
+    f(&camera_terminal);
+    f(&output_terminal);
+}
+
+/// 3.7.2.3 Camera Terminal Descriptor
+fn camera_terminal() -> Body {
+    body(&[
+        VC_INPUT_TERMINAL,       // bDescriptorSubtype
+        CAMERA_TERMINAL_ID,      // bTerminalID
+        ITT_CAMERA as u8,        // wTerminalType[0]
+        (ITT_CAMERA >> 8) as u8, // wTerminalType[1]
+        0x00,                    // bAssocTerminal (none)
+        0x00,                    // iTerminal (none)
+        0x00,                    // wObjectiveFocalLengthMin[0]
+        0x00,                    // wObjectiveFocalLengthMin[1]
+        0x00,                    // wObjectiveFocalLengthMax[0]
+        0x00,                    // wObjectiveFocalLengthMax[1]
+        0x00,                    // wOcularFocalLength[0]
+        0x00,                    // wOcularFocalLength[1]
+        0x03,                    // bControlSize
+        0x00,                    // bmControls (none)
+        0x00,
+        0x00,
+    ])
+}
+
+/// 3.7.2.2 Output Terminal Descriptor
+fn output_terminal() -> Body {
+    body(&[
+        VC_OUTPUT_TERMINAL,        // bDescriptorSubtype
+        OUTPUT_TERMINAL_ID,        // bTerminalID
+        TT_STREAMING as u8,        // wTerminalType[0]
+        (TT_STREAMING >> 8) as u8, // wTerminalType[1]
+        0x00,                      // bAssocTerminal (none)
+        CAMERA_TERMINAL_ID,        // bSourceID
+        0x00,                      // iTerminal (none)
+    ])
+}
+
+/// Calls `f` with the descriptors of the video streaming interface, starting with the input header.
+pub(super) fn vs_descriptors(formats: &[Format<'_>], endpoint_address: u8, mut f: impl FnMut(&[u8])) {
+    let mut total_len = 0;
+    vs_format_descriptors(formats, |d| total_len += d.len() + DESCRIPTOR_HEADER_LEN);
+
+    // 3.9.2.1 Input Header Descriptor
+    let mut header = body(&[
+        VS_INPUT_HEADER,     // bDescriptorSubtype
+        formats.len() as u8, // bNumFormats
+        0x00,                // wTotalLength, filled in below
+        0x00,
+        endpoint_address,   // bEndpointAddress
+        0x00,               // bmInfo (no dynamic format change)
+        OUTPUT_TERMINAL_ID, // bTerminalLink
+        0x00,               // bStillCaptureMethod (none)
+        0x00,               // bTriggerSupport (none)
+        0x00,               // bTriggerUsage
+        0x01,               // bControlSize
+    ]);
+    for _ in formats {
+        header.push(0x00).unwrap(); // bmaControls (none)
+    }
+    let total_len = (total_len + header.len() + DESCRIPTOR_HEADER_LEN) as u16;
+    header[2..4].copy_from_slice(&total_len.to_le_bytes());
+
+    f(&header);
+    vs_format_descriptors(formats, f);
+}
+
+fn vs_format_descriptors(formats: &[Format<'_>], mut f: impl FnMut(&[u8])) {
+    for (i, format) in formats.iter().enumerate() {
+        f(&format_descriptor(format, i as u8 + 1));
+        for (j, frame) in format.frames.iter().enumerate() {
+            f(&frame_descriptor(format.encoding, j as u8 + 1, frame));
+        }
+        f(&color_matching());
+    }
+}
+
+/// MJPEG 3.1.1 Motion-JPEG Video Format Descriptor, and
+/// Uncompressed 3.1.1 Uncompressed Video Format Descriptor.
+fn format_descriptor(format: &Format<'_>, index: u8) -> Body {
+    let num_frames = format.frames.len() as u8;
+    match format.encoding {
+        Encoding::Mjpeg => body(&[
+            VS_FORMAT_MJPEG, // bDescriptorSubtype
+            index,           // bFormatIndex
+            num_frames,      // bNumFrameDescriptors
+            0x01,            // bmFlags (fixed size samples)
+            0x01,            // bDefaultFrameIndex
+            0x00,            // bAspectRatioX
+            0x00,            // bAspectRatioY
+            0x00,            // bmInterlaceFlags
+            0x00,            // bCopyProtect
+        ]),
+        Encoding::Yuy2 => {
+            let mut d = body(&[
+                VS_FORMAT_UNCOMPRESSED, // bDescriptorSubtype
+                index,                  // bFormatIndex
+                num_frames,             // bNumFrameDescriptors
+            ]);
+            d.extend_from_slice(&GUID_YUY2).unwrap(); // guidFormat
+            d.extend_from_slice(&[
+                Encoding::Yuy2.bits_per_pixel(), // bBitsPerPixel
+                0x01,                            // bDefaultFrameIndex
+                0x00,                            // bAspectRatioX
+                0x00,                            // bAspectRatioY
+                0x00,                            // bmInterlaceFlags
+                0x00,                            // bCopyProtect
+            ])
+            .unwrap();
+            d
+        }
+    }
+}
+
+/// MJPEG 3.1.2 Motion-JPEG Video Frame Descriptor, and
+/// Uncompressed 3.1.2 Uncompressed Video Frame Descriptor, with discrete frame intervals.
+fn frame_descriptor(encoding: Encoding, index: u8, frame: &Frame<'_>) -> Body {
+    let subtype = match encoding {
+        Encoding::Mjpeg => VS_FRAME_MJPEG,
+        Encoding::Yuy2 => VS_FRAME_UNCOMPRESSED,
+    };
+    let size = frame.max_frame_size(encoding);
+    let min_interval = frame.intervals.iter().copied().min().unwrap();
+    let max_interval = frame.intervals.iter().copied().max().unwrap();
+
+    let mut d = body(&[
+        subtype, // bDescriptorSubtype
+        index,   // bFrameIndex
+        0x00,    // bmCapabilities
+    ]);
+    d.extend_from_slice(&frame.width.to_le_bytes()).unwrap(); // wWidth
+    d.extend_from_slice(&frame.height.to_le_bytes()).unwrap(); // wHeight
+    d.extend_from_slice(&bit_rate(size, max_interval).to_le_bytes())
+        .unwrap(); // dwMinBitRate
+    d.extend_from_slice(&bit_rate(size, min_interval).to_le_bytes())
+        .unwrap(); // dwMaxBitRate
+    d.extend_from_slice(&size.to_le_bytes()).unwrap(); // dwMaxVideoFrameBufferSize
+    d.extend_from_slice(&frame.intervals[0].to_le_bytes()).unwrap(); // dwDefaultFrameInterval
+    d.push(frame.intervals.len() as u8).unwrap(); // bFrameIntervalType
+    for interval in frame.intervals {
+        d.extend_from_slice(&interval.to_le_bytes()).unwrap(); // dwFrameInterval
+    }
+    d
+}
+
+/// 3.9.2.6 Color Matching Descriptor, with the sRGB / BT.709 defaults.
+fn color_matching() -> Body {
+    body(&[
+        VS_COLORFORMAT, // bDescriptorSubtype
+        0x01,           // bColorPrimaries (BT.709, sRGB)
+        0x01,           // bTransferCharacteristics (BT.709)
+        0x04,           // bMatrixCoefficients (SMPTE 170M)
+    ])
+}
+
+/// Bit rate in bits per second of frames of `size` bytes every `interval` (in 100 ns units).
+fn bit_rate(size: u32, interval: u32) -> u32 {
+    let rate = size as u64 * 8 * 10_000_000 / interval.max(1) as u64;
+    rate.min(u32::MAX as u64) as u32
+}
+
+/// Checks the limits of the descriptors above.
+pub(super) fn validate(formats: &[Format<'_>]) {
+    assert!(!formats.is_empty() && formats.len() <= MAX_FORMATS);
+    for format in formats {
+        assert!(!format.frames.is_empty() && format.frames.len() <= u8::MAX as usize);
+        for frame in format.frames {
+            assert!(!frame.intervals.is_empty() && frame.intervals.len() <= MAX_FRAME_INTERVALS);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    const INTERVALS: [u32; 2] = [333_333, 666_666];
+    const FRAMES: [Frame<'static>; 2] = [
+        Frame {
+            width: 640,
+            height: 480,
+            intervals: &INTERVALS,
+        },
+        Frame {
+            width: 320,
+            height: 240,
+            intervals: INTERVALS.split_at(1).0,
+        },
+    ];
+    const FORMATS: [Format<'static>; 2] = [
+        Format {
+            encoding: Encoding::Mjpeg,
+            frames: &FRAMES,
+        },
+        Format {
+            encoding: Encoding::Yuy2,
+            frames: FRAMES.split_at(1).1,
+        },
+    ];
+
+    fn collect(f: impl FnOnce(&mut dyn FnMut(&[u8]))) -> Vec<Vec<u8>> {
+        let mut out = Vec::new();
+        f(&mut |d| out.push(d.to_vec()));
+        out
+    }
+
+    #[test]
+    fn vc_total_length() {
+        let descriptors = collect(|f| vc_descriptors(1, f));
+        assert_eq!(descriptors.len(), 3);
+        let total: usize = descriptors.iter().map(|d| d.len() + 2).sum();
+        assert_eq!(total, 13 + 18 + 9);
+        assert_eq!(
+            u16::from_le_bytes([descriptors[0][3], descriptors[0][4]]) as usize,
+            total
+        );
+        assert_eq!(descriptors[0][10], 1);
+    }
+
+    #[test]
+    fn vs_layout() {
+        let descriptors = collect(|f| vs_descriptors(&FORMATS, 0x81, f));
+        let subtypes: Vec<u8> = descriptors.iter().map(|d| d[0]).collect();
+        assert_eq!(
+            subtypes,
+            [
+                VS_INPUT_HEADER,
+                VS_FORMAT_MJPEG,
+                VS_FRAME_MJPEG,
+                VS_FRAME_MJPEG,
+                VS_COLORFORMAT,
+                VS_FORMAT_UNCOMPRESSED,
+                VS_FRAME_UNCOMPRESSED,
+                VS_COLORFORMAT,
+            ]
+        );
+
+        let header = &descriptors[0];
+        assert_eq!(header.len() + 2, 13 + FORMATS.len());
+        assert_eq!(header[1], 2);
+        assert_eq!(header[4], 0x81);
+        let total: usize = descriptors.iter().map(|d| d.len() + 2).sum();
+        assert_eq!(u16::from_le_bytes([header[2], header[3]]) as usize, total);
+
+        // Uncompressed format: GUID and bits per pixel.
+        assert_eq!(descriptors[5].len() + 2, 27);
+        assert_eq!(&descriptors[5][3..19], &GUID_YUY2);
+        assert_eq!(descriptors[5][19], 16);
+    }
+
+    #[test]
+    fn frame_fields() {
+        let d = frame_descriptor(Encoding::Mjpeg, 1, &FRAMES[0]);
+        assert_eq!(d.len() + 2, 26 + 4 * INTERVALS.len());
+        let u32_at = |i: usize| u32::from_le_bytes([d[i], d[i + 1], d[i + 2], d[i + 3]]);
+        assert_eq!(u16::from_le_bytes([d[3], d[4]]), 640);
+        assert_eq!(u16::from_le_bytes([d[5], d[6]]), 480);
+        let size = 640 * 480 * 2;
+        // 15 fps minimum, 30 fps maximum.
+        assert_eq!(u32_at(7), bit_rate(size, 666_666));
+        assert_eq!(u32_at(11), bit_rate(size, 333_333));
+        assert_eq!(u32_at(15), size);
+        assert_eq!(u32_at(19), 333_333);
+        assert_eq!(d[23], 2);
+        assert_eq!(u32_at(24), 333_333);
+        assert_eq!(u32_at(28), 666_666);
+    }
+}
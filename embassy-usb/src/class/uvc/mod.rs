@@ -0,0 +1,494 @@
+//! USB Video Class 1.1 camera function.
+//!
+//! Implements a video control interface with a camera terminal, and a video streaming
+//! interface offering MJPEG and uncompressed YUY2 formats. The host negotiates the
+//! format, frame size and frame interval with the probe and commit controls; frames
+//! are then sent with [`UvcClass::write_frame`], split into payloads with a payload
+//! header, over an isochronous or a bulk endpoint.
+//!
+//! The function uses an interface association, so the device must be built with
+//! [`Config::composite_with_iads`](crate::Config::composite_with_iads) set.
+
+use core::cell::Cell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_sync::blocking_mutex::CriticalSectionMutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+
+use self::class_codes::*;
+use self::payload::Payloads;
+pub use self::probe::ProbeCommit;
+use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::descriptor::{SynchronizationType, UsageType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointType};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+mod class_codes;
+mod descriptor;
+mod payload;
+mod probe;
+
+/// The maximum number of formats of a function.
+pub const MAX_FORMATS: usize = 4;
+
+/// The maximum number of discrete frame intervals of a frame size.
+pub const MAX_FRAME_INTERVALS: usize = 8;
+
+/// The largest supported endpoint max packet size.
+pub const MAX_PACKET_SIZE: usize = 1024;
+
+/// Video encoding of a format.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Encoding {
+    /// Motion-JPEG, every frame is a JPEG image.
+    Mjpeg,
+    /// Uncompressed packed YUV 4:2:2, in Y0 U Y1 V byte order.
+    Yuy2,
+}
+
+impl Encoding {
+    const fn bits_per_pixel(self) -> u8 {
+        match self {
+            Encoding::Mjpeg | Encoding::Yuy2 => 16,
+        }
+    }
+}
+
+/// A frame size and the frame intervals supported for it.
+#[derive(Copy, Clone, Debug)]
+pub struct Frame<'d> {
+    /// Width in pixels.
+    pub width: u16,
+    /// Height in pixels.
+    pub height: u16,
+    /// Supported frame intervals in 100 ns units, e.g. 333_333 for 30 fps.
+    ///
+    /// The first interval is the default.
+    pub intervals: &'d [u32],
+}
+
+impl<'d> Frame<'d> {
+    /// The largest frame in bytes.
+    ///
+    /// For MJPEG this is the size of the frame uncompressed, as an upper bound.
+    pub const fn max_frame_size(&self, encoding: Encoding) -> u32 {
+        self.width as u32 * self.height as u32 * encoding.bits_per_pixel() as u32 / 8
+    }
+}
+
+/// A video format and the frame sizes supported for it.
+#[derive(Copy, Clone, Debug)]
+pub struct Format<'d> {
+    /// Encoding of the frames.
+    pub encoding: Encoding,
+    /// Supported frame sizes. The first one is the default.
+    pub frames: &'d [Frame<'d>],
+}
+
+/// How video payloads are transferred.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TransferMode {
+    /// Isochronous endpoint in alternate setting 1. Streaming starts when the host
+    /// selects it after committing the parameters.
+    Isochronous,
+    /// Bulk endpoint in alternate setting 0. Streaming starts with the commit.
+    Bulk,
+}
+
+/// Configuration for the video function.
+pub struct Config<'d> {
+    /// Offered formats, at most [`MAX_FORMATS`]. The first one is the default.
+    pub formats: &'d [Format<'d>],
+
+    /// Endpoint type for the video data.
+    pub transfer_mode: TransferMode,
+
+    /// Max packet size for the video endpoint, at most [`MAX_PACKET_SIZE`].
+    ///
+    /// Every packet carries one payload, so this is also the maximum payload size.
+    pub max_packet_size: u16,
+}
+
+/// Streaming parameters committed by the host.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StreamConfig {
+    /// 0-based index into [`Config::formats`].
+    pub format: usize,
+    /// 0-based index into [`Format::frames`].
+    pub frame: usize,
+    /// Encoding of the selected format.
+    pub encoding: Encoding,
+    /// Frame width in pixels.
+    pub width: u16,
+    /// Frame height in pixels.
+    pub height: u16,
+    /// Frame interval in 100 ns units.
+    pub interval: u32,
+}
+
+/// Internal state for the video function.
+pub struct State<'d> {
+    control: MaybeUninit<Control<'d>>,
+    shared: ControlShared,
+}
+
+impl<'d> Default for State<'d> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'d> State<'d> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+            shared: ControlShared::new(),
+        }
+    }
+}
+
+/// Shared data between Control and UvcClass
+struct ControlShared {
+    commit: CriticalSectionMutex<Cell<ProbeCommit>>,
+    committed: AtomicBool,
+    alt_active: AtomicBool,
+    changed: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl ControlShared {
+    const fn new() -> Self {
+        Self {
+            commit: CriticalSectionMutex::new(Cell::new(ProbeCommit::EMPTY)),
+            committed: AtomicBool::new(false),
+            alt_active: AtomicBool::new(false),
+            changed: Signal::new(),
+        }
+    }
+
+    fn stop(&self) {
+        self.committed.store(false, Ordering::Relaxed);
+        self.alt_active.store(false, Ordering::Relaxed);
+        self.changed.signal(());
+    }
+}
+
+struct Control<'d> {
+    vc_if: InterfaceNumber,
+    vs_if: InterfaceNumber,
+    formats: &'d [Format<'d>],
+    max_packet_size: u16,
+    probe: ProbeCommit,
+    error_code: u8,
+    shared: &'d ControlShared,
+}
+
+impl<'d> Control<'d> {
+    fn default_probe(&self) -> ProbeCommit {
+        probe::negotiate(self.formats, &ProbeCommit::default(), self.max_packet_size)
+    }
+
+    fn vs_control_out(&mut self, req: Request, data: &[u8]) -> Result<(), u8> {
+        let selector = (req.value >> 8) as u8;
+        if !matches!(selector, VS_PROBE_CONTROL | VS_COMMIT_CONTROL) {
+            return Err(ERROR_INVALID_CONTROL);
+        }
+        if req.request != SET_CUR {
+            return Err(ERROR_INVALID_REQUEST);
+        }
+        let Some(proposal) = ProbeCommit::parse(data) else {
+            return Err(ERROR_INVALID_REQUEST);
+        };
+
+        let negotiated = probe::negotiate(self.formats, &proposal, self.max_packet_size);
+        if selector == VS_PROBE_CONTROL {
+            self.probe = negotiated;
+        } else {
+            debug!(
+                "uvc: commit format {} frame {} interval {}",
+                negotiated.format_index, negotiated.frame_index, negotiated.frame_interval
+            );
+            self.shared.commit.lock(|c| c.set(negotiated));
+            self.shared.committed.store(true, Ordering::Relaxed);
+            self.shared.changed.signal(());
+        }
+        Ok(())
+    }
+
+    fn vs_control_in<'a>(&mut self, req: Request, buf: &'a mut [u8]) -> Result<&'a [u8], u8> {
+        let selector = (req.value >> 8) as u8;
+        if !matches!(selector, VS_PROBE_CONTROL | VS_COMMIT_CONTROL) {
+            return Err(ERROR_INVALID_CONTROL);
+        }
+
+        let value = match req.request {
+            GET_CUR if selector == VS_PROBE_CONTROL => self.probe,
+            GET_CUR => self.shared.commit.lock(|c| c.get()),
+            GET_MIN | GET_MAX | GET_DEF => self.default_probe(),
+            GET_RES => ProbeCommit::default(),
+            GET_LEN => {
+                buf[..2].copy_from_slice(&(ProbeCommit::LEN as u16).to_le_bytes());
+                return Ok(&buf[..2]);
+            }
+            GET_INFO => {
+                buf[0] = INFO_GET_SET;
+                return Ok(&buf[..1]);
+            }
+            _ => return Err(ERROR_INVALID_REQUEST),
+        };
+        buf[..ProbeCommit::LEN].copy_from_slice(&value.to_bytes());
+        Ok(&buf[..ProbeCommit::LEN])
+    }
+
+    fn vc_control_in<'a>(&mut self, req: Request, buf: &'a mut [u8]) -> Result<&'a [u8], u8> {
+        // Only the interface itself (entity 0) has a control.
+        if req.index >> 8 != 0 || (req.value >> 8) as u8 != VC_REQUEST_ERROR_CODE_CONTROL {
+            return Err(ERROR_INVALID_CONTROL);
+        }
+        match req.request {
+            GET_CUR => {
+                // Reading the error code does not overwrite it.
+                buf[0] = self.error_code;
+                Ok(&buf[..1])
+            }
+            GET_INFO => {
+                buf[0] = INFO_GET;
+                Ok(&buf[..1])
+            }
+            _ => Err(ERROR_INVALID_REQUEST),
+        }
+    }
+
+    fn interface(&self, req: &Request) -> Option<InterfaceNumber> {
+        if (req.request_type, req.recipient) != (RequestType::Class, Recipient::Interface) {
+            return None;
+        }
+        let iface = InterfaceNumber::new(req.index as u8);
+        (iface == self.vc_if || iface == self.vs_if).then_some(iface)
+    }
+}
+
+impl<'d> Handler for Control<'d> {
+    fn reset(&mut self) {
+        self.probe = self.default_probe();
+        self.error_code = ERROR_NONE;
+        self.shared.stop();
+    }
+
+    fn configured(&mut self, configured: bool) {
+        if !configured {
+            self.shared.stop();
+        }
+    }
+
+    fn set_alternate_setting(&mut self, iface: InterfaceNumber, alternate_setting: u8) {
+        if iface == self.vs_if {
+            debug!("uvc: streaming interface alt setting {}", alternate_setting);
+            self.shared.alt_active.store(alternate_setting != 0, Ordering::Relaxed);
+            if alternate_setting == 0 {
+                self.shared.committed.store(false, Ordering::Relaxed);
+            }
+            self.shared.changed.signal(());
+        }
+    }
+
+    fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
+        let iface = self.interface(&req)?;
+        let result = if iface == self.vs_if {
+            self.vs_control_out(req, data)
+        } else {
+            Err(ERROR_INVALID_CONTROL)
+        };
+
+        match result {
+            Ok(()) => {
+                self.error_code = ERROR_NONE;
+                Some(OutResponse::Accepted)
+            }
+            Err(code) => {
+                self.error_code = code;
+                Some(OutResponse::Rejected)
+            }
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        let iface = self.interface(&req)?;
+        let result = if iface == self.vs_if {
+            self.vs_control_in(req, buf)
+        } else {
+            self.vc_control_in(req, buf)
+        };
+
+        match result {
+            Ok(data) => {
+                if iface == self.vs_if {
+                    self.error_code = ERROR_NONE;
+                }
+                Some(InResponse::Accepted(data))
+            }
+            Err(code) => {
+                self.error_code = code;
+                Some(InResponse::Rejected)
+            }
+        }
+    }
+}
+
+/// USB Video Class camera function.
+pub struct UvcClass<'d, D: Driver<'d>> {
+    ep: D::EndpointIn,
+    formats: &'d [Format<'d>],
+    transfer_mode: TransferMode,
+    control: &'d ControlShared,
+    fid: bool,
+}
+
+impl<'d, D: Driver<'d>> UvcClass<'d, D> {
+    /// Creates a new `UvcClass` with the provided UsbBus and `config`.
+    pub fn new<const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>(
+        builder: &mut Builder<'d, D, MAX_INTERFACES, MAX_HANDLERS>,
+        state: &'d mut State<'d>,
+        config: Config<'d>,
+    ) -> Self {
+        descriptor::validate(config.formats);
+        assert!(config.max_packet_size as usize <= MAX_PACKET_SIZE);
+        assert!(builder.control_buf_len() >= ProbeCommit::LEN);
+
+        let mut func = builder.function(CC_VIDEO, SC_VIDEO_INTERFACE_COLLECTION, PC_PROTOCOL_UNDEFINED);
+
+        // Video control interface.
+        let mut iface = func.interface();
+        let vc_if = iface.interface_number();
+        let vs_if = InterfaceNumber::new(vc_if.0 + 1);
+        {
+            let mut alt = iface.alt_setting(CC_VIDEO, SC_VIDEOCONTROL, PC_PROTOCOL_UNDEFINED, None);
+            descriptor::vc_descriptors(vs_if.0, |d| alt.descriptor(CS_INTERFACE, d));
+        }
+
+        // Video streaming interface.
+        let mut iface = func.interface();
+        assert_eq!(iface.interface_number(), vs_if);
+        let mut alt = iface.alt_setting(CC_VIDEO, SC_VIDEOSTREAMING, PC_PROTOCOL_UNDEFINED, None);
+        let ep = match config.transfer_mode {
+            TransferMode::Isochronous => {
+                // The input header of alt setting 0 refers to the endpoint of alt setting 1.
+                let ep = alt.alloc_endpoint_in(EndpointType::Isochronous, None, config.max_packet_size, 1);
+                descriptor::vs_descriptors(config.formats, ep.info().addr.into(), |d| {
+                    alt.descriptor(CS_INTERFACE, d)
+                });
+
+                let mut alt = iface.alt_setting(CC_VIDEO, SC_VIDEOSTREAMING, PC_PROTOCOL_UNDEFINED, None);
+                alt.endpoint_descriptor(
+                    ep.info(),
+                    SynchronizationType::Asynchronous,
+                    UsageType::DataEndpoint,
+                    &[],
+                );
+                ep
+            }
+            TransferMode::Bulk => {
+                let ep = alt.alloc_endpoint_in(EndpointType::Bulk, None, config.max_packet_size, 0);
+                descriptor::vs_descriptors(config.formats, ep.info().addr.into(), |d| {
+                    alt.descriptor(CS_INTERFACE, d)
+                });
+                alt.endpoint_descriptor(
+                    ep.info(),
+                    SynchronizationType::NoSynchronization,
+                    UsageType::DataEndpoint,
+                    &[],
+                );
+                ep
+            }
+        };
+        drop(func);
+
+        let control = state.control.write(Control {
+            vc_if,
+            vs_if,
+            formats: config.formats,
+            max_packet_size: config.max_packet_size,
+            probe: ProbeCommit::default(),
+            error_code: ERROR_NONE,
+            shared: &state.shared,
+        });
+        control.probe = control.default_probe();
+        builder.handler(control);
+
+        Self {
+            ep,
+            formats: config.formats,
+            transfer_mode: config.transfer_mode,
+            control: &state.shared,
+            fid: false,
+        }
+    }
+
+    /// Returns the committed streaming parameters, if the host is streaming.
+    pub fn stream_config(&self) -> Option<StreamConfig> {
+        let streaming = self.control.committed.load(Ordering::Relaxed)
+            && match self.transfer_mode {
+                TransferMode::Isochronous => self.control.alt_active.load(Ordering::Relaxed),
+                TransferMode::Bulk => true,
+            };
+        if !streaming {
+            return None;
+        }
+
+        let commit = self.control.commit.lock(|c| c.get());
+        let format = commit.format_index as usize - 1;
+        let frame = commit.frame_index as usize - 1;
+        let f = &self.formats[format].frames[frame];
+        Some(StreamConfig {
+            format,
+            frame,
+            encoding: self.formats[format].encoding,
+            width: f.width,
+            height: f.height,
+            interval: commit.frame_interval,
+        })
+    }
+
+    /// Waits until the host starts streaming, or changes the streaming parameters.
+    ///
+    /// Returns the parameters the frames must be produced with.
+    pub async fn wait_streaming(&mut self) -> StreamConfig {
+        loop {
+            if let Some(config) = self.stream_config() {
+                self.control.changed.reset();
+                return config;
+            }
+            self.control.changed.wait().await;
+        }
+    }
+
+    /// Waits until the host stops streaming or changes the streaming parameters.
+    pub async fn wait_changed(&mut self) {
+        self.control.changed.wait().await
+    }
+
+    /// Sends a complete video frame.
+    ///
+    /// The frame must be encoded according to the current [`StreamConfig`]. Returns
+    /// [`EndpointError::Disabled`] if the host is not streaming.
+    pub async fn write_frame(&mut self, frame: &[u8]) -> Result<(), EndpointError> {
+        if self.stream_config().is_none() {
+            return Err(EndpointError::Disabled);
+        }
+
+        let mut buf = [0; MAX_PACKET_SIZE];
+        let mut payloads = Payloads::new(frame, self.ep.info().max_packet_size as usize, self.fid);
+        // Toggle the frame ID even on error, so the host never merges two frames.
+        self.fid = !self.fid;
+        while let Some(n) = payloads.next(&mut buf) {
+            self.ep.write(&buf[..n]).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+//! Payload header generation, UVC 1.1 2.4.3.3.
+
+use super::class_codes::{HEADER_EOF, HEADER_EOH, HEADER_FID};
+
+/// Length of the payload header written before the data of every payload.
+///
+/// The optional presentation time stamp and source clock reference are not sent.
+pub(super) const HEADER_LEN: usize = 2;
+
+/// Splits a video frame into payloads of at most `payload_size` bytes, header included.
+pub(super) struct Payloads<'a> {
+    remaining: &'a [u8],
+    chunk_len: usize,
+    fid: bool,
+    done: bool,
+}
+
+impl<'a> Payloads<'a> {
+    pub(super) fn new(frame: &'a [u8], payload_size: usize, fid: bool) -> Self {
+        assert!(payload_size > HEADER_LEN);
+        Self {
+            remaining: frame,
+            chunk_len: payload_size - HEADER_LEN,
+            fid,
+            done: false,
+        }
+    }
+
+    /// Writes the next payload into `buf`, returning its length, or `None` once the
+    /// whole frame was written.
+    ///
+    /// The last payload has the end of frame bit set; an empty frame produces a
+    /// single header-only payload.
+    pub(super) fn next(&mut self, buf: &mut [u8]) -> Option<usize> {
+        if self.done {
+            return None;
+        }
+
+        let n = self.remaining.len().min(self.chunk_len);
+        let (data, rest) = self.remaining.split_at(n);
+        self.remaining = rest;
+        self.done = rest.is_empty();
+
+        let mut info = HEADER_EOH;
+        if self.fid {
+            info |= HEADER_FID;
+        }
+        if self.done {
+            info |= HEADER_EOF;
+        }
+        buf[0] = HEADER_LEN as u8;
+        buf[1] = info;
+        buf[HEADER_LEN..HEADER_LEN + n].copy_from_slice(data);
+        Some(HEADER_LEN + n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split() {
+        let frame: [u8; 10] = core::array::from_fn(|i| i as u8);
+        let mut payloads = Payloads::new(&frame, 6, true);
+        let mut buf = [0; 6];
+
+        assert_eq!(payloads.next(&mut buf), Some(6));
+        assert_eq!(buf, [2, HEADER_EOH | HEADER_FID, 0, 1, 2, 3]);
+        assert_eq!(payloads.next(&mut buf), Some(6));
+        assert_eq!(buf, [2, HEADER_EOH | HEADER_FID, 4, 5, 6, 7]);
+        assert_eq!(payloads.next(&mut buf), Some(4));
+        assert_eq!(buf[..4], [2, HEADER_EOH | HEADER_FID | HEADER_EOF, 8, 9]);
+        assert_eq!(payloads.next(&mut buf), None);
+    }
+
+    #[test]
+    fn exact_multiple() {
+        let frame = [0xAA; 8];
+        let mut payloads = Payloads::new(&frame, 6, false);
+        let mut buf = [0; 6];
+
+        assert_eq!(payloads.next(&mut buf), Some(6));
+        assert_eq!(buf[1], HEADER_EOH);
+        assert_eq!(payloads.next(&mut buf), Some(6));
+        assert_eq!(buf[1], HEADER_EOH | HEADER_EOF);
+        assert_eq!(payloads.next(&mut buf), None);
+    }
+
+    #[test]
+    fn empty_frame() {
+        let mut payloads = Payloads::new(&[], 6, false);
+        let mut buf = [0; 6];
+        assert_eq!(payloads.next(&mut buf), Some(2));
+        assert_eq!(buf[..2], [2, HEADER_EOH | HEADER_EOF]);
+        assert_eq!(payloads.next(&mut buf), None);
+    }
+}
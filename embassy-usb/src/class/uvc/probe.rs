@@ -0,0 +1,279 @@
+//! Video probe and commit controls.
+
+use super::Format;
+
+/// Video probe and commit control, UVC 1.1 4.3.1.1.
+///
+/// The host proposes streaming parameters with `SET_CUR(PROBE)`, reads back what the
+/// device can do with `GET_CUR(PROBE)`, and starts streaming with `SET_CUR(COMMIT)`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ProbeCommit {
+    /// Parameters the host wants to be kept fixed during negotiation.
+    pub hint: u16,
+    /// 1-based index of the format descriptor.
+    pub format_index: u8,
+    /// 1-based index of the frame descriptor within the format.
+    pub frame_index: u8,
+    /// Frame interval in 100 ns units.
+    pub frame_interval: u32,
+    /// Key frame rate in key frames per video frame.
+    pub key_frame_rate: u16,
+    /// P-frame rate in P-frames per key frame.
+    pub p_frame_rate: u16,
+    /// Compression quality, 0 to 10000.
+    pub comp_quality: u16,
+    /// Compression window size in frames.
+    pub comp_window_size: u16,
+    /// Internal video streaming interface latency in ms.
+    pub delay: u16,
+    /// Maximum video frame size in bytes.
+    pub max_video_frame_size: u32,
+    /// Maximum number of bytes the device transmits in a single payload.
+    pub max_payload_transfer_size: u32,
+    /// Device clock frequency in Hz, used by the presentation timestamps.
+    pub clock_frequency: u32,
+    /// Framing information bits.
+    pub framing_info: u8,
+    /// Preferred payload format version.
+    pub preferred_version: u8,
+    /// Minimum payload format version.
+    pub min_version: u8,
+    /// Maximum payload format version.
+    pub max_version: u8,
+}
+
+impl ProbeCommit {
+    /// Length of the control in UVC 1.1.
+    pub const LEN: usize = 34;
+
+    /// Length of the control in UVC 1.0, which hosts may still send.
+    pub const LEN_1_0: usize = 26;
+
+    /// All fields zero, as `Default` but usable in const contexts.
+    pub(super) const EMPTY: Self = Self {
+        hint: 0,
+        format_index: 0,
+        frame_index: 0,
+        frame_interval: 0,
+        key_frame_rate: 0,
+        p_frame_rate: 0,
+        comp_quality: 0,
+        comp_window_size: 0,
+        delay: 0,
+        max_video_frame_size: 0,
+        max_payload_transfer_size: 0,
+        clock_frequency: 0,
+        framing_info: 0,
+        preferred_version: 0,
+        min_version: 0,
+        max_version: 0,
+    };
+
+    /// Parses a probe or commit control.
+    ///
+    /// Fields missing from a short UVC 1.0 control are zero. Returns `None` if `buf`
+    /// is shorter than [`LEN_1_0`](Self::LEN_1_0).
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < Self::LEN_1_0 {
+            return None;
+        }
+        let mut b = [0; Self::LEN];
+        let n = buf.len().min(Self::LEN);
+        b[..n].copy_from_slice(&buf[..n]);
+
+        let u16_at = |i: usize| u16::from_le_bytes([b[i], b[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+        Some(Self {
+            hint: u16_at(0),
+            format_index: b[2],
+            frame_index: b[3],
+            frame_interval: u32_at(4),
+            key_frame_rate: u16_at(8),
+            p_frame_rate: u16_at(10),
+            comp_quality: u16_at(12),
+            comp_window_size: u16_at(14),
+            delay: u16_at(16),
+            max_video_frame_size: u32_at(18),
+            max_payload_transfer_size: u32_at(22),
+            clock_frequency: u32_at(26),
+            framing_info: b[30],
+            preferred_version: b[31],
+            min_version: b[32],
+            max_version: b[33],
+        })
+    }
+
+    /// Serializes the control.
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut b = [0; Self::LEN];
+        b[0..2].copy_from_slice(&self.hint.to_le_bytes());
+        b[2] = self.format_index;
+        b[3] = self.frame_index;
+        b[4..8].copy_from_slice(&self.frame_interval.to_le_bytes());
+        b[8..10].copy_from_slice(&self.key_frame_rate.to_le_bytes());
+        b[10..12].copy_from_slice(&self.p_frame_rate.to_le_bytes());
+        b[12..14].copy_from_slice(&self.comp_quality.to_le_bytes());
+        b[14..16].copy_from_slice(&self.comp_window_size.to_le_bytes());
+        b[16..18].copy_from_slice(&self.delay.to_le_bytes());
+        b[18..22].copy_from_slice(&self.max_video_frame_size.to_le_bytes());
+        b[22..26].copy_from_slice(&self.max_payload_transfer_size.to_le_bytes());
+        b[26..30].copy_from_slice(&self.clock_frequency.to_le_bytes());
+        b[30] = self.framing_info;
+        b[31] = self.preferred_version;
+        b[32] = self.min_version;
+        b[33] = self.max_version;
+        b
+    }
+}
+
+/// `bmFramingInfo`: the FID bit is required and the EOF bit may be present.
+const FRAMING_FID_EOF: u8 = 0x03;
+
+/// Device clock reported in the probe control, matching the VC header.
+const CLOCK_FREQUENCY: u32 = 48_000_000;
+
+/// Returns the parameters the device will use for the host proposal `req`.
+///
+/// Out of range format and frame indices fall back to the first descriptor, and the
+/// frame interval is rounded to the nearest supported one.
+pub(super) fn negotiate(formats: &[Format<'_>], req: &ProbeCommit, max_payload_size: u16) -> ProbeCommit {
+    let format_index = match req.format_index as usize {
+        i @ 1.. if i <= formats.len() => i,
+        _ => 1,
+    };
+    let format = &formats[format_index - 1];
+
+    let frame_index = match req.frame_index as usize {
+        i @ 1.. if i <= format.frames.len() => i,
+        _ => 1,
+    };
+    let frame = &format.frames[frame_index - 1];
+
+    let frame_interval = match req.frame_interval {
+        0 => frame.intervals[0],
+        wanted => frame
+            .intervals
+            .iter()
+            .copied()
+            .min_by_key(|i| i.abs_diff(wanted))
+            .unwrap(),
+    };
+
+    ProbeCommit {
+        hint: req.hint,
+        format_index: format_index as u8,
+        frame_index: frame_index as u8,
+        frame_interval,
+        max_video_frame_size: frame.max_frame_size(format.encoding),
+        max_payload_transfer_size: max_payload_size as u32,
+        clock_frequency: CLOCK_FREQUENCY,
+        framing_info: FRAMING_FID_EOF,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Encoding, Frame};
+    use super::*;
+
+    const FRAMES: [Frame<'static>; 2] = [
+        Frame {
+            width: 640,
+            height: 480,
+            intervals: &[333_333, 666_666, 1_000_000],
+        },
+        Frame {
+            width: 160,
+            height: 120,
+            intervals: &[500_000],
+        },
+    ];
+    const FORMATS: [Format<'static>; 2] = [
+        Format {
+            encoding: Encoding::Mjpeg,
+            frames: &FRAMES,
+        },
+        Format {
+            encoding: Encoding::Yuy2,
+            frames: FRAMES.split_at(1).1,
+        },
+    ];
+
+    #[test]
+    fn roundtrip() {
+        let p = ProbeCommit {
+            hint: 1,
+            format_index: 2,
+            frame_index: 1,
+            frame_interval: 500_000,
+            max_video_frame_size: 38400,
+            max_payload_transfer_size: 1024,
+            clock_frequency: CLOCK_FREQUENCY,
+            framing_info: 3,
+            max_version: 1,
+            ..Default::default()
+        };
+        assert_eq!(ProbeCommit::parse(&p.to_bytes()), Some(p));
+    }
+
+    #[test]
+    fn parse_uvc_1_0() {
+        let mut b = [0u8; ProbeCommit::LEN_1_0];
+        b[2] = 1;
+        b[3] = 2;
+        b[22..26].copy_from_slice(&512u32.to_le_bytes());
+        let p = ProbeCommit::parse(&b).unwrap();
+        assert_eq!(
+            (p.format_index, p.frame_index, p.max_payload_transfer_size),
+            (1, 2, 512)
+        );
+        assert_eq!(p.framing_info, 0);
+        assert_eq!(ProbeCommit::parse(&b[..25]), None);
+    }
+
+    #[test]
+    fn negotiate_valid() {
+        let req = ProbeCommit {
+            format_index: 2,
+            frame_index: 1,
+            frame_interval: 500_000,
+            ..Default::default()
+        };
+        let p = negotiate(&FORMATS, &req, 1023);
+        assert_eq!((p.format_index, p.frame_index, p.frame_interval), (2, 1, 500_000));
+        assert_eq!(p.max_video_frame_size, 160 * 120 * 2);
+        assert_eq!(p.max_payload_transfer_size, 1023);
+        assert_eq!(p.framing_info, FRAMING_FID_EOF);
+    }
+
+    #[test]
+    fn negotiate_out_of_range() {
+        let req = ProbeCommit {
+            format_index: 3,
+            frame_index: 9,
+            ..Default::default()
+        };
+        let p = negotiate(&FORMATS, &req, 512);
+        assert_eq!((p.format_index, p.frame_index, p.frame_interval), (1, 1, 333_333));
+        assert_eq!(p.max_video_frame_size, 640 * 480 * 2);
+    }
+
+    #[test]
+    fn negotiate_nearest_interval() {
+        let req = ProbeCommit {
+            format_index: 1,
+            frame_index: 1,
+            frame_interval: 700_000,
+            ..Default::default()
+        };
+        assert_eq!(negotiate(&FORMATS, &req, 512).frame_interval, 666_666);
+
+        let req = ProbeCommit {
+            frame_interval: 5_000_000,
+            ..req
+        };
+        assert_eq!(negotiate(&FORMATS, &req, 512).frame_interval, 1_000_000);
+    }
+}
@@ -82,6 +82,7 @@ pub(crate) struct DescriptorWriter<'a> {
     position: usize,
     num_interfaces_mark: Option<usize>,
     num_endpoints_mark: Option<usize>,
+    overflowed: bool,
 }
 
 impl<'a> DescriptorWriter<'a> {
@@ -91,6 +92,7 @@ impl<'a> DescriptorWriter<'a> {
             position: 0,
             num_interfaces_mark: None,
             num_endpoints_mark: None,
+            overflowed: false,
         }
     }
 
@@ -103,16 +105,25 @@ impl<'a> DescriptorWriter<'a> {
         self.position
     }
 
+    /// Returns whether a descriptor was dropped because the buffer was full.
+    pub const fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+
     /// Writes an arbitrary (usually class-specific) descriptor with optional extra fields.
+    ///
+    /// If the buffer is full, the descriptor is dropped and the writer is marked as
+    /// [`overflowed`](Self::overflowed).
     pub fn write(&mut self, descriptor_type: u8, descriptor: &[u8], extra_fields: &[u8]) {
         let descriptor_length = descriptor.len();
         let extra_fields_length = extra_fields.len();
         let total_length = descriptor_length + extra_fields_length;
 
-        assert!(
-            (self.position + 2 + total_length) <= self.buf.len() && (total_length + 2) <= 255,
-            "Descriptor buffer full"
-        );
+        assert!((total_length + 2) <= 255, "Descriptor too long");
+        if self.overflowed || (self.position + 2 + total_length) > self.buf.len() {
+            self.overflowed = true;
+            return;
+        }
 
         self.buf[self.position] = (total_length + 2) as u8;
         self.buf[self.position + 1] = descriptor_type;
@@ -126,7 +137,7 @@ impl<'a> DescriptorWriter<'a> {
     }
 
     pub(crate) fn configuration(&mut self, config: &Config) {
-        self.num_interfaces_mark = Some(self.position + 4);
+        let mark = self.position + 4;
 
         self.write(
             descriptor_type::CONFIGURATION,
@@ -142,6 +153,10 @@ impl<'a> DescriptorWriter<'a> {
             ],
             &[],
         );
+
+        if !self.overflowed {
+            self.num_interfaces_mark = Some(mark);
+        }
     }
 
     #[allow(unused)]
@@ -150,6 +165,9 @@ impl<'a> DescriptorWriter<'a> {
     }
 
     pub(crate) fn end_configuration(&mut self) {
+        if self.overflowed {
+            return;
+        }
         let position = self.position as u16;
         self.buf[2..4].copy_from_slice(&position.to_le_bytes());
     }
@@ -213,6 +231,9 @@ impl<'a> DescriptorWriter<'a> {
         interface_protocol: u8,
         interface_string: Option<StringIndex>,
     ) {
+        if self.overflowed {
+            return;
+        }
         if alternate_setting == 0 {
             match self.num_interfaces_mark {
                 Some(mark) => self.buf[mark] += 1,
@@ -257,6 +278,9 @@ impl<'a> DescriptorWriter<'a> {
         usage_type: UsageType,
         extra_fields: &[u8],
     ) {
+        if self.overflowed {
+            return;
+        }
         match self.num_endpoints_mark {
             Some(mark) => self.buf[mark] += 1,
             None => panic!("you can only call `endpoint` after `interface/interface_alt`."),
@@ -299,7 +323,10 @@ impl<'a> DescriptorWriter<'a> {
     pub(crate) fn string(&mut self, string: &str) {
         let mut pos = self.position;
 
-        assert!(pos + 2 <= self.buf.len(), "Descriptor buffer full");
+        if self.overflowed || pos + 2 + 2 * string.encode_utf16().count() > self.buf.len() {
+            self.overflowed = true;
+            return;
+        }
 
         self.buf[pos] = 0; // length placeholder
         self.buf[pos + 1] = descriptor_type::STRING;
@@ -307,8 +334,6 @@ impl<'a> DescriptorWriter<'a> {
         pos += 2;
 
         for c in string.encode_utf16() {
-            assert!(pos < self.buf.len(), "Descriptor buffer full");
-
             self.buf[pos..pos + 2].copy_from_slice(&c.to_le_bytes());
             pos += 2;
         }
@@ -426,19 +451,22 @@ impl<'a> BosWriter<'a> {
     ///
     /// * `capability_type` - Type of a capability
     /// * `data` - Binary data of the descriptor
+    ///
+    /// If the buffer is full, or the BOS descriptor could not be written at all, the
+    /// capability is dropped and the writer is marked as overflowed.
     pub fn capability(&mut self, capability_type: u8, data: &[u8]) {
-        match self.num_caps_mark {
-            Some(mark) => self.writer.buf[mark] += 1,
-            None => panic!("called `capability` not between `bos` and `end_bos`."),
-        }
-
         let mut start = self.writer.position;
         let blen = data.len();
 
-        assert!(
-            (start + blen + 3) <= self.writer.buf.len() && (blen + 3) <= 255,
-            "Descriptor buffer full"
-        );
+        assert!((blen + 3) <= 255, "Descriptor too long");
+        let mark = match self.num_caps_mark {
+            Some(mark) if !self.writer.overflowed && (start + blen + 3) <= self.writer.buf.len() => mark,
+            _ => {
+                self.writer.overflowed = true;
+                return;
+            }
+        };
+        self.writer.buf[mark] += 1;
 
         self.writer.buf[start] = (blen + 3) as u8;
         self.writer.buf[start + 1] = descriptor_type::CAPABILITY;
@@ -450,7 +478,7 @@ impl<'a> BosWriter<'a> {
     }
 
     pub(crate) fn end_bos(&mut self) {
-        if self.writer.position == 0 {
+        if self.writer.position == 0 || self.writer.overflowed {
             return;
         }
         self.num_caps_mark = None;
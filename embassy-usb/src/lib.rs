@@ -25,7 +25,7 @@ use embassy_futures::select::{Either, select};
 use heapless::Vec;
 
 pub use crate::builder::{
-    Builder, Config, FunctionBuilder, InterfaceAltBuilder, InterfaceBuilder, UsbDeviceSpeed, UsbVersion,
+    BuildError, Builder, Config, FunctionBuilder, InterfaceAltBuilder, InterfaceBuilder, UsbDeviceSpeed, UsbVersion,
 };
 use crate::config::{MAX_HANDLER_COUNT, MAX_INTERFACE_COUNT};
 use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
@@ -206,13 +206,20 @@ pub struct UsbBufferReport {
 }
 
 /// Main struct for the USB device stack.
-pub struct UsbDevice<'d, D: Driver<'d>> {
+///
+/// `MAX_INTERFACES` and `MAX_HANDLERS` are the limits of the [`Builder`] the device was built with.
+pub struct UsbDevice<
+    'd,
+    D: Driver<'d>,
+    const MAX_INTERFACES: usize = MAX_INTERFACE_COUNT,
+    const MAX_HANDLERS: usize = MAX_HANDLER_COUNT,
+> {
     control_buf: &'d mut [u8],
     control: D::ControlPipe,
-    inner: Inner<'d, D>,
+    inner: Inner<'d, D, MAX_INTERFACES, MAX_HANDLERS>,
 }
 
-struct Inner<'d, D: Driver<'d>> {
+struct Inner<'d, D: Driver<'d>, const MAX_INTERFACES: usize, const MAX_HANDLERS: usize> {
     bus: D::Bus,
 
     config: Config<'d>,
@@ -234,11 +241,13 @@ struct Inner<'d, D: Driver<'d>> {
     /// instead of regular `accept()`.
     set_address_pending: bool,
 
-    interfaces: Vec<Interface, MAX_INTERFACE_COUNT>,
-    handlers: Vec<&'d mut dyn Handler, MAX_HANDLER_COUNT>,
+    interfaces: Vec<Interface, MAX_INTERFACES>,
+    handlers: Vec<&'d mut dyn Handler, MAX_HANDLERS>,
 }
 
-impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
+impl<'d, D: Driver<'d>, const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>
+    UsbDevice<'d, D, MAX_INTERFACES, MAX_HANDLERS>
+{
     /// Returns a report of the consumed buffers
     ///
     /// Useful for tuning buffer sizes for actual usage
@@ -412,7 +421,9 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
     }
 }
 
-impl<'d, D: Driver<'d>> Inner<'d, D> {
+impl<'d, D: Driver<'d>, const MAX_INTERFACES: usize, const MAX_HANDLERS: usize>
+    Inner<'d, D, MAX_INTERFACES, MAX_HANDLERS>
+{
     async fn handle_bus_event(&mut self, evt: Event) {
         match evt {
             Event::Reset => {
@@ -45,6 +45,7 @@ pub struct MsOsDescriptorWriter<'d> {
     config_mark: Option<usize>,
     function_mark: Option<usize>,
     vendor_code: u8,
+    overflowed: bool,
 }
 
 impl<'d> MsOsDescriptorWriter<'d> {
@@ -55,6 +56,7 @@ impl<'d> MsOsDescriptorWriter<'d> {
             config_mark: None,
             function_mark: None,
             vendor_code: 0,
+            overflowed: false,
         }
     }
 
@@ -80,6 +82,11 @@ impl<'d> MsOsDescriptorWriter<'d> {
         self.position == 0
     }
 
+    /// Returns `true` if a descriptor was dropped because the buffer was full
+    pub fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+
     /// Returns `true` if a configuration subset header has been started
     pub fn is_in_config_subset(&self) -> bool {
         self.config_mark.is_some()
@@ -106,6 +113,9 @@ impl<'d> MsOsDescriptorWriter<'d> {
     /// Note that some feature descriptors may only be used at the device level in non-composite devices.
     /// Those features must be written before the first call to [`Self::configuration`].
     pub fn device_feature<T: DeviceLevelDescriptor>(&mut self, desc: T) {
+        if self.overflowed {
+            return;
+        }
         assert!(
             !self.is_empty(),
             "device features may only be added after the header is written"
@@ -115,6 +125,9 @@ impl<'d> MsOsDescriptorWriter<'d> {
 
     /// Add a configuration subset.
     pub fn configuration(&mut self, config: u8) {
+        if self.overflowed {
+            return;
+        }
         assert!(
             !self.is_empty(),
             "MsOsDescriptorWriter: configuration must be called after header"
@@ -126,6 +139,9 @@ impl<'d> MsOsDescriptorWriter<'d> {
 
     /// Add a function subset.
     pub fn function(&mut self, first_interface: InterfaceNumber) {
+        if self.overflowed {
+            return;
+        }
         assert!(
             self.config_mark.is_some(),
             "MsOsDescriptorWriter: function subset requires a configuration subset"
@@ -140,6 +156,9 @@ impl<'d> MsOsDescriptorWriter<'d> {
     /// Note that some features may only be used at the function level. Those features must be written after a call
     /// to [`Self::function`].
     pub fn function_feature<T: FunctionLevelDescriptor>(&mut self, desc: T) {
+        if self.overflowed {
+            return;
+        }
         assert!(
             self.function_mark.is_some(),
             "function features may only be added to a function subset"
@@ -149,10 +168,18 @@ impl<'d> MsOsDescriptorWriter<'d> {
 
     /// Ends the current function subset (if any)
     pub fn end_function(&mut self) {
+        if self.overflowed {
+            self.function_mark = None;
+            return;
+        }
         Self::end_subset::<FunctionSubsetHeader>(self.buf, self.position, &mut self.function_mark);
     }
 
     fn write<T: Descriptor>(&mut self, desc: T) {
+        if self.overflowed || self.position + desc.size() > self.buf.len() {
+            self.overflowed = true;
+            return;
+        }
         desc.write_to(&mut self.buf[self.position..]);
         self.position += desc.size();
     }
@@ -166,7 +193,7 @@ impl<'d> MsOsDescriptorWriter<'d> {
     }
 
     fn end(&mut self) {
-        if self.position > 0 {
+        if self.position > 0 && !self.overflowed {
             Self::end_subset::<FunctionSubsetHeader>(self.buf, self.position, &mut self.function_mark);
             Self::end_subset::<ConfigurationSubsetHeader>(self.buf, self.position, &mut self.config_mark);
             Self::end_subset::<DescriptorSetHeader>(self.buf, self.position, &mut Some(0));
@@ -92,7 +92,7 @@ fn main() -> ! {
             msos::PropertyData::RegMultiSz(DEVICE_INTERFACE_GUIDS),
         ));
 
-        usb_dfu::<_, _, _, _, 4096>(&mut builder, &mut state, |func| {
+        usb_dfu::<_, _, _, _, _, 4096>(&mut builder, &mut state, |func| {
             // You likely don't have to add these function level headers if your USB device is not composite
             // (i.e. if your device does not expose another interface in addition to DFU)
             func.msos_feature(msos::CompatibleIdFeatureDescriptor::new("WINUSB", ""));